@@ -0,0 +1,5 @@
+fn main() {
+    // Generates the gRPC service from its definition (see `server::grpc`).
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/payments.proto").expect("Failed to compile protos");
+}
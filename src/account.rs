@@ -1,28 +1,191 @@
-use rust_decimal::Decimal;
-use serde::Serialize;
+use crate::arena::{self, CHUNK_RECORDS};
+use crate::clock::format_date;
+use crate::interest::{Accrual, InterestPolicy, InterestRecord};
+use crate::intern::Interned;
+use crate::money::{Money, RoundingMode, OUTPUT_SCALE};
+use crate::notes::AccountNote;
+use crate::system_accounts::SystemAccounts;
+use crate::transaction::{DisputeReason, TransactionInfo};
+use crate::transaction_engine::TransactionNotApplied;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU64;
 
-#[derive(Debug, Default)]
+/// How funds are held against disputed deposits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HoldPolicy {
+    /// The full disputed amount is held, but only out of the funds the
+    /// account actually has: available funds never go below zero and held
+    /// funds never exceed the total. Holds grow to cover outstanding disputes
+    /// as further funds are deposited.
+    #[default]
+    Capped,
+    /// The full disputed amount is held, taking available funds negative if
+    /// the account doesn't have enough.
+    NegativeAvailable,
+    /// Funds are moved into a separate held bucket at dispute time, up to
+    /// the funds available then. Later deposits aren't held.
+    HeldBucket,
+}
+
+impl std::str::FromStr for HoldPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "capped" => Ok(HoldPolicy::Capped),
+            "negative-available" => Ok(HoldPolicy::NegativeAvailable),
+            "held-bucket" => Ok(HoldPolicy::HeldBucket),
+            _ => Err(format!("Unknown hold policy {:?}", s)),
+        }
+    }
+}
+
+/// What a locked account is still allowed to do.
+///
+/// Ordered from least to most restrictive, each scope blocking everything the
+/// previous one does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockScope {
+    /// Withdrawals are blocked. Deposits and dispute operations are allowed.
+    BlockDebits,
+    /// Deposits and withdrawals are blocked. Dispute operations are allowed.
+    AllowDisputes,
+    /// Every transaction is blocked.
+    BlockAll,
+}
+
+impl LockScope {
+    /// Checks whether a transaction may be applied under this scope.
+    pub fn allows(&self, info: &TransactionInfo) -> bool {
+        if matches!(info, TransactionInfo::Unlock(_)) {
+            // Always allowed, being how the lock is lifted.
+            return true;
+        }
+        match self {
+            LockScope::BlockDebits => !matches!(
+                info,
+                TransactionInfo::Withdrawal(_) | TransactionInfo::Convert(_)
+            ),
+            LockScope::AllowDisputes => !matches!(
+                info,
+                TransactionInfo::Deposit(_)
+                    | TransactionInfo::Withdrawal(_)
+                    | TransactionInfo::Convert(_)
+            ),
+            LockScope::BlockAll => false,
+        }
+    }
+}
+
+impl std::str::FromStr for LockScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block-debits" => Ok(LockScope::BlockDebits),
+            "allow-disputes" => Ok(LockScope::AllowDisputes),
+            "block-all" => Ok(LockScope::BlockAll),
+            _ => Err(format!("Unknown lock scope {:?}", s)),
+        }
+    }
+}
+
+/// A client's account.
+///
+/// Balances only change through the methods applying each kind of
+/// transaction, which keep the account's totals and records consistent with
+/// each other and post the other side of every movement to the engine's
+/// [`SystemAccounts`].
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Account {
     /// Client ID associated with this account.
-    pub client: u16,
+    client: u16,
 
     /// Account raw funds, may be negative if account is overdrawn.
-    pub total_funds: Decimal,
+    total_funds: Money,
 
-    /// Total of all current disputes.
+    /// Total held for all current disputes (see [`DepositRecord::held`] and
+    /// [`DebitRecord`]).
     ///
     /// Actively disputed funds may exceed total funds in the case where an
     /// account has accrued disputes exceeding its remaining balance. For held
     /// funds, use [`Account::held_funds`] instead.
-    pub active_dispute_total: Decimal,
+    active_dispute_total: Money,
+
+    /// How disputed funds are held for this account.
+    hold_policy: HoldPolicy,
+
+    /// What the account is restricted to, if it's locked.
+    lock: Option<LockScope>,
+
+    /// Records of all deposits applied to this account.
+    transactions: DepositRecords,
+
+    /// Records of all withdrawals applied to this account, by transaction
+    /// ID.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
+    withdrawals: HashMap<u32, DebitRecord>,
+
+    /// Sequence number of the last transaction applied to the account, or
+    /// zero if none have been.
+    last_activity: u64,
+
+    /// Operators' notes on the account and its transactions, in the order
+    /// they were attached.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    notes: Vec<AccountNote>,
 
-    /// Whether or not the account is frozen.
-    pub locked: bool,
+    /// Withdrawals tagged with a destination, in applied order. Untagged
+    /// withdrawals aren't recorded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tagged_withdrawals: Vec<WithdrawalRecord>,
 
-    /// Map of all transactions related to this account.
-    pub transactions: HashMap<u32, DepositRecord>,
+    /// Currency of the account's primary balance, if any transaction has
+    /// named one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Interned>,
+
+    /// Balances in currencies other than the primary one, each kept as an
+    /// account of its own with its own records (see
+    /// [`Account::balance_mut`]). Locks, notes and activity are the
+    /// client's, so are kept on the primary balance.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Interned, Account>,
+
+    /// Conversions between the account's balances, by transaction ID. Kept
+    /// on the primary balance, as they span two.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
+    conversions: HashMap<u32, ConversionRecord>,
+
+    /// Fees charged on the balance's deposits and withdrawals, by
+    /// transaction ID. Transactions charged no fee aren't recorded.
+    #[serde(
+        default,
+        skip_serializing_if = "HashMap::is_empty",
+        serialize_with = "serialize_sorted"
+    )]
+    fees: HashMap<u32, Money>,
+
+    /// Interest accrued on the balance and not yet posted, once it's begun
+    /// accruing (see [`crate::interest`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accrual: Option<Accrual>,
+
+    /// Interest posted to the balance, in posted order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    interest: Vec<InterestRecord>,
 }
 
 impl Account {
@@ -34,31 +197,1033 @@ impl Account {
         }
     }
 
-    /// Returns the funds available for withdrawal.
-    pub fn available_funds(&self) -> Decimal {
-        max(self.total_funds - self.active_dispute_total, Decimal::ZERO)
+    /// Client ID associated with this account.
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Account raw funds, may be negative if account is overdrawn.
+    pub fn total_funds(&self) -> &Money {
+        &self.total_funds
+    }
+
+    /// Total held for all current disputes. May exceed the total funds; for
+    /// the funds actually held, use [`Account::held_funds`].
+    pub fn active_dispute_total(&self) -> &Money {
+        &self.active_dispute_total
+    }
+
+    /// How disputed funds are held for this account.
+    pub fn hold_policy(&self) -> HoldPolicy {
+        self.hold_policy
+    }
+
+    /// Changes how disputed funds are held. Existing holds are kept as they
+    /// are.
+    pub fn set_hold_policy(&mut self, policy: HoldPolicy) {
+        self.hold_policy = policy;
+        for balance in self.currencies.values_mut() {
+            balance.hold_policy = policy;
+        }
+    }
+
+    /// Currency of this balance, if any of the account's transactions has
+    /// named one.
+    pub fn currency(&self) -> Option<&Interned> {
+        self.currency.as_ref()
+    }
+
+    /// The account's balances: the primary balance (the account itself),
+    /// then one per further currency, in currency order.
+    pub fn balances(&self) -> impl Iterator<Item = &Account> {
+        std::iter::once(self).chain(self.currencies.values())
+    }
+
+    /// The balance transactions in `currency` apply to, created if need be.
+    ///
+    /// The primary balance takes the currency of the first transaction
+    /// applied to the account, and transactions that don't name a currency
+    /// apply to it. Other currencies each get a balance of their own, so
+    /// withdrawals and disputes only ever draw on funds in the same
+    /// currency.
+    pub fn balance_mut(&mut self, currency: Option<&Interned>) -> &mut Account {
+        let Some(currency) = currency else {
+            return self;
+        };
+        if self.currency.is_none() && self.last_activity == 0 {
+            self.currency = Some(currency.clone());
+        }
+        if self.currency.as_ref() == Some(currency) {
+            return self;
+        }
+        let (client, hold_policy) = (self.client, self.hold_policy);
+        self.currencies
+            .entry(currency.clone())
+            .or_insert_with(|| Account {
+                client,
+                hold_policy,
+                currency: Some(currency.clone()),
+                ..Account::default()
+            })
+    }
+
+    /// The balance deposit or withdrawal `tx` was applied to, if any.
+    pub fn balance_with(&self, tx: u32) -> Option<&Account> {
+        self.balances().find(|balance| balance.recorded(tx))
+    }
+
+    /// The balance deposit or withdrawal `tx` was applied to, or the
+    /// primary balance if neither was, for dispute steps and corrections.
+    pub fn balance_of_mut(&mut self, tx: u32) -> &mut Account {
+        let currency = self
+            .currencies
+            .iter()
+            .find(|(_, balance)| balance.recorded(tx))
+            .map(|(currency, _)| currency.clone());
+        match currency {
+            Some(currency) => self.currencies.get_mut(&currency).expect("Found above"),
+            None => self,
+        }
+    }
+
+    /// Statements of the account's balances, one per currency it's used.
+    /// Other currencies' balances are as locked as the account.
+    pub fn statements(&self) -> impl Iterator<Item = AccountStatement> + '_ {
+        self.balances()
+            .filter(|balance| balance.currency == self.currency || balance.last_activity != 0)
+            .map(|balance| AccountStatement {
+                locked: self.locked(),
+                ..AccountStatement::from(balance)
+            })
+    }
+
+    /// Records that a transaction was applied to one of the account's other
+    /// balances, as activity on the account.
+    pub fn touch(&mut self, sequence: u64) {
+        self.last_activity = max(self.last_activity, sequence);
+    }
+
+    /// What the account is restricted to, if it's locked.
+    pub fn lock_scope(&self) -> Option<LockScope> {
+        self.lock
+    }
+
+    /// Whether or not the account is locked, in any scope.
+    pub fn locked(&self) -> bool {
+        self.lock.is_some()
+    }
+
+    /// Sequence number of the last transaction applied to the account, or
+    /// zero if none have been.
+    pub fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+
+    /// The record of deposit `tx`, if it was applied to this account, in
+    /// any currency.
+    pub fn transaction(&self, tx: u32) -> Option<&DepositRecord> {
+        self.balance_with(tx)?.transactions.get(tx)
+    }
+
+    /// The record of withdrawal `tx`, if it was applied to this account, in
+    /// any currency.
+    pub fn withdrawal(&self, tx: u32) -> Option<&DebitRecord> {
+        self.balance_with(tx)?.withdrawals.get(&tx)
+    }
+
+    /// The dispute status of deposit or withdrawal `tx`, if either was
+    /// applied to this account.
+    pub fn dispute_status(&self, tx: u32) -> Option<DisputeStatus> {
+        let balance = self.balance_with(tx)?;
+        balance
+            .transactions
+            .get(tx)
+            .map(DepositRecord::dispute_status)
+            .or_else(|| {
+                balance
+                    .withdrawals
+                    .get(&tx)
+                    .map(DebitRecord::dispute_status)
+            })
+    }
+
+    /// Funds held for the dispute of deposit or withdrawal `tx`. Zero unless
+    /// it's disputed; `None` if neither was applied to this account.
+    pub fn held_for(&self, tx: u32) -> Option<Money> {
+        let balance = self.balance_with(tx)?;
+        match balance.transactions.get(tx) {
+            Some(record) => Some(record.held()),
+            None => balance.withdrawals.get(&tx).map(DebitRecord::held),
+        }
+    }
+
+    /// Operators' notes on the account and its transactions, in the order
+    /// they were attached.
+    pub fn notes(&self) -> &[AccountNote] {
+        &self.notes
+    }
+
+    /// Attaches an operator's note. Notes don't change the account's
+    /// balances, so don't count as activity.
+    pub fn add_note(&mut self, note: AccountNote) {
+        self.notes.push(note);
+    }
+
+    /// Withdrawals tagged with a destination, in applied order.
+    pub fn tagged_withdrawals(&self) -> &[WithdrawalRecord] {
+        &self.tagged_withdrawals
+    }
+
+    /// Records an applied withdrawal's destination.
+    pub fn record_withdrawal(&mut self, record: WithdrawalRecord) {
+        self.tagged_withdrawals.push(record);
+    }
+
+    /// Locks the account with the given scope. An account already locked
+    /// more restrictively stays that way.
+    pub fn lock(&mut self, scope: LockScope) {
+        self.lock = max(self.lock, Some(scope));
+    }
+
+    /// Lifts the account's lock, as an applied transaction. Returns the
+    /// sequence number it was applied with.
+    pub fn unlock(&mut self, sequence: impl FnOnce() -> u64) -> u64 {
+        self.lock = None;
+        self.applied(sequence)
+    }
+
+    /// The account's deposit records, with their transaction IDs, in the
+    /// order they were applied. Withdrawals are in
+    /// [`Account::withdrawal_history`].
+    pub fn transaction_history(&self) -> Vec<(u32, &DepositRecord)> {
+        self.transactions
+            .iter()
+            .map(|record| (record.tx, record))
+            .collect()
+    }
+
+    /// The account's withdrawal records, with their transaction IDs, in the
+    /// order they were applied.
+    pub fn withdrawal_history(&self) -> Vec<(u32, &DebitRecord)> {
+        let mut history: Vec<(u32, &DebitRecord)> = self
+            .withdrawals
+            .iter()
+            .map(|(tx, record)| (*tx, record))
+            .collect();
+        history.sort_unstable_by_key(|(_, record)| record.sequence);
+        history
+    }
+
+    /// The deposits, withdrawals and conversions applied to the account, in
+    /// every currency, in the order they were applied, for auditors to
+    /// reconstruct its activity. Dispute steps are reflected in each entry's
+    /// dispute status, and interest in [`Account::interest_history`].
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        let mut history = vec![];
+        for balance in self.balances() {
+            let currency = balance.currency();
+            history.extend(balance.transactions.iter().map(|record| HistoryEntry {
+                sequence: record.sequence,
+                tx: record.tx,
+                kind: "deposit",
+                currency: currency.cloned(),
+                amount: record.amount.clone(),
+                dispute_status: Some(record.dispute_status),
+                timestamp: record.timestamp(),
+            }));
+            history.extend(balance.withdrawals.iter().map(|(tx, record)| HistoryEntry {
+                sequence: record.sequence,
+                tx: *tx,
+                kind: "withdrawal",
+                currency: currency.cloned(),
+                amount: record.amount.clone(),
+                dispute_status: Some(record.dispute_status),
+                timestamp: record.timestamp,
+            }));
+        }
+        history.extend(self.conversions.iter().map(|(tx, record)| HistoryEntry {
+            sequence: record.sequence,
+            tx: *tx,
+            kind: "convert",
+            currency: Some(record.from.clone()),
+            amount: record.amount.clone(),
+            dispute_status: None,
+            timestamp: None,
+        }));
+        history.sort_unstable_by_key(|entry| entry.sequence);
+        history
+    }
+
+    /// Transaction IDs of the deposits, withdrawals and conversions applied
+    /// to the account, in any currency, in no particular order.
+    pub fn transaction_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.balances()
+            .flat_map(|balance| {
+                let deposits = balance.transactions.iter().map(|record| record.tx);
+                deposits.chain(balance.withdrawals.keys().copied())
+            })
+            .chain(self.conversions.keys().copied())
+    }
+
+    /// The record of conversion `tx`, if it was applied to this account.
+    pub fn conversion(&self, tx: u32) -> Option<&ConversionRecord> {
+        self.conversions.get(&tx)
+    }
+
+    /// The account's conversion records, with their transaction IDs, in the
+    /// order they were applied.
+    pub fn conversion_history(&self) -> Vec<(u32, &ConversionRecord)> {
+        let mut history: Vec<(u32, &ConversionRecord)> = self
+            .conversions
+            .iter()
+            .map(|(tx, record)| (*tx, record))
+            .collect();
+        history.sort_unstable_by_key(|(_, record)| record.sequence);
+        history
+    }
+
+    /// The fee charged on deposit or withdrawal `tx`, if it was applied to
+    /// this account and charged one.
+    pub fn fee(&self, tx: u32) -> Option<&Money> {
+        self.balance_with(tx)?.fees.get(&tx)
+    }
+
+    /// Total fees charged on this balance's deposits and withdrawals.
+    pub fn fees_charged(&self) -> Money {
+        self.fees
+            .values()
+            .fold(Money::zero(), |total, fee| &total + fee)
+    }
+
+    /// Interest accrued on the balance and not yet posted, if it's begun
+    /// accruing.
+    pub fn accrual(&self) -> Option<&Accrual> {
+        self.accrual.as_ref()
+    }
+
+    /// Interest posted to the balance, in posted order.
+    pub fn interest_history(&self) -> &[InterestRecord] {
+        &self.interest
+    }
+
+    /// Total interest posted to the balance.
+    pub fn interest_paid(&self) -> Money {
+        self.interest
+            .iter()
+            .fold(Money::zero(), |total, record| &total + &record.amount)
+    }
+
+    /// Records when deposit or withdrawal `tx` happened, if known, for
+    /// [`crate::EngineConfig::dispute_window_days`].
+    pub fn record_time(&mut self, tx: u32, time: Option<u64>) {
+        if let Some(record) = self.transactions.get_mut(tx) {
+            record.timestamp = time.and_then(NonZeroU64::new);
+        } else if let Some(record) = self.withdrawals.get_mut(&tx) {
+            record.timestamp = time;
+        }
+    }
+
+    /// When deposit or withdrawal `tx` happened, if it was applied to this
+    /// account and the time is known.
+    pub fn time_of(&self, tx: u32) -> Option<u64> {
+        let balance = self.balance_with(tx)?;
+        match balance.transactions.get(tx) {
+            Some(record) => record.timestamp(),
+            None => balance.withdrawals.get(&tx)?.timestamp,
+        }
+    }
+
+    /// Whether deposit or withdrawal `tx` was applied to this account.
+    fn recorded(&self, tx: u32) -> bool {
+        self.transactions.get(tx).is_some() || self.withdrawals.contains_key(&tx)
+    }
+
+    /// Deposits `amount` as transaction `tx`, charging `fee` out of it.
+    ///
+    /// Like the other methods applying transactions, nothing is changed
+    /// unless the whole transaction can be applied. `sequence` is only called
+    /// once it can, to number the transaction; the number is returned.
+    pub fn credit(
+        &mut self,
+        tx: u32,
+        amount: &Money,
+        fee: &Money,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        if self.recorded(tx) {
+            return Err(TransactionNotApplied::RepeatTransaction(tx));
+        }
+        let total_funds = amount
+            .checked_sub(fee)
+            .and_then(|credit| self.total_funds.checked_add(&credit))
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        *system = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                amount,
+                fee,
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        self.total_funds = total_funds;
+        let sequence = self.applied(sequence);
+        self.transactions
+            .push(tx, DepositRecord::new(amount.clone(), sequence));
+        if fee.is_positive() {
+            self.fees.insert(tx, fee.clone());
+        }
+        Ok(sequence)
+    }
+
+    /// Converts `amount` of the balance in `from` into `converted` in `to`,
+    /// as transaction `tx` at `rate`, if that much is available in `from`.
+    /// Called on the primary balance, which keeps the record.
+    #[allow(clippy::too_many_arguments)]
+    pub fn convert(
+        &mut self,
+        tx: u32,
+        from: &Interned,
+        to: &Interned,
+        amount: &Money,
+        converted: &Money,
+        rate: &Money,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        if self.conversions.contains_key(&tx) || self.balance_with(tx).is_some() {
+            return Err(TransactionNotApplied::RepeatTransaction(tx));
+        }
+        let in_currency = |currency: &Interned| {
+            self.balances()
+                .find(|balance| balance.currency.as_ref() == Some(currency))
+        };
+        let Some(source) = in_currency(from) else {
+            return Err(TransactionNotApplied::InsufficientFunds(amount.clone()));
+        };
+        let available = source.available_funds();
+        if &available < amount {
+            return Err(TransactionNotApplied::InsufficientFunds(
+                amount.saturating_sub(&available),
+            ));
+        }
+        let source_before = source.total_funds.clone();
+        let target_before =
+            in_currency(to).map_or_else(Money::zero, |target| target.total_funds.clone());
+        let overflow = TransactionNotApplied::ArithmeticOverflow;
+        let source_after = source_before.checked_sub(amount).ok_or(overflow.clone())?;
+        let target_after = target_before
+            .checked_add(converted)
+            .ok_or(overflow.clone())?;
+        // Posted as funds leaving one balance and arriving in the other.
+        *system = system
+            .checked_post(
+                Some(from),
+                &source_before,
+                &source_after,
+                &-amount.clone(),
+                &Money::zero(),
+            )
+            .and_then(|system| {
+                system.checked_post(
+                    Some(to),
+                    &target_before,
+                    &target_after,
+                    converted,
+                    &Money::zero(),
+                )
+            })
+            .ok_or(overflow)?;
+        let sequence = sequence();
+        for (currency, total_funds) in [(from, source_after), (to, target_after)] {
+            let balance = self.balance_mut(Some(currency));
+            balance.total_funds = total_funds;
+            balance.applied(|| sequence);
+        }
+        self.conversions.insert(
+            tx,
+            ConversionRecord {
+                from: from.clone(),
+                to: to.clone(),
+                amount: amount.clone(),
+                converted: converted.clone(),
+                rate: rate.clone(),
+                sequence,
+            },
+        );
+        Ok(sequence)
+    }
+
+    /// Withdraws `amount` plus `fee` as transaction `tx`, if that much is
+    /// available, overdrawing by up to `overdraft` (see
+    /// [`Account::spendable`]).
+    pub fn debit(
+        &mut self,
+        tx: u32,
+        amount: &Money,
+        fee: &Money,
+        overdraft: &Money,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        if self.recorded(tx) {
+            return Err(TransactionNotApplied::RepeatTransaction(tx));
+        }
+        let debit = fee
+            .checked_add(amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let spendable = self.spendable(overdraft);
+        if spendable < debit {
+            return Err(TransactionNotApplied::InsufficientFunds(
+                debit.saturating_sub(&spendable),
+            ));
+        }
+        let total_funds = self
+            .total_funds
+            .checked_sub(&debit)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        *system = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &-amount.clone(),
+                fee,
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        self.total_funds = total_funds;
+        let sequence = self.applied(sequence);
+        self.withdrawals
+            .insert(tx, DebitRecord::new(amount.clone(), sequence));
+        if fee.is_positive() {
+            self.fees.insert(tx, fee.clone());
+        }
+        Ok(sequence)
+    }
+
+    /// Disputes `amount` of deposit `tx`, or all that's left to dispute of
+    /// it if `None`, holding funds for it according to the account's
+    /// [`HoldPolicy`]. Or disputes withdrawal `tx` (see
+    /// [`Account::hold_withdrawal`]).
+    pub fn hold(
+        &mut self,
+        tx: u32,
+        reason: Option<DisputeReason>,
+        amount: Option<&Money>,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let Some(record) = self.transactions.get(tx) else {
+            return self.hold_withdrawal(tx, reason, amount, system, sequence);
+        };
+        // Transition a copy, so a failure below leaves the record untouched.
+        let mut status = record.dispute_status;
+        status
+            .disputed()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        let disputable = record.disputable();
+        let disputed = amount.cloned().unwrap_or_else(|| disputable.clone());
+        if disputed > disputable {
+            return Err(TransactionNotApplied::InvalidDisputeState(format!(
+                "Cannot dispute {} with only {} left to dispute",
+                disputed, disputable
+            )));
+        }
+        let held = self.hold_for_dispute(&disputed);
+        let dispute_total = self
+            .active_dispute_total
+            .checked_add(&held)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let record = self.transactions.get_mut(tx).expect("record found above");
+        record.dispute_status = status;
+        record.set_disputed(disputed);
+        record.set_held(held);
+        record.reason = reason;
+        self.active_dispute_total = dispute_total;
+        Ok(self.applied(sequence))
+    }
+
+    /// Resolves the dispute of deposit `tx`, releasing the funds held for it,
+    /// or of withdrawal `tx` (see [`Account::release_withdrawal`]).
+    pub fn release(
+        &mut self,
+        tx: u32,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let Some(record) = self.transactions.get_mut(tx) else {
+            return self.release_withdrawal(tx, system, sequence);
+        };
+        record
+            .resolved()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        record.set_disputed(Money::zero());
+        let held = record.take_held();
+        self.release_held(&held);
+        Ok(self.applied(sequence))
+    }
+
+    /// Charges back the disputed deposit `tx`, removing the amount disputed
+    /// from the account along with the funds held for it, or the disputed
+    /// withdrawal `tx` (see [`Account::charge_back_withdrawal`]). Locking the
+    /// account is left to the caller.
+    ///
+    /// A deposit with more left to dispute afterwards is only
+    /// [`DisputeStatus::PartiallyRefunded`], and may be disputed again.
+    pub fn charge_back(
+        &mut self,
+        tx: u32,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let Some(record) = self.transactions.get(tx) else {
+            return self.charge_back_withdrawal(tx, sequence);
+        };
+        let amount = record.disputed_amount();
+        let charged_back = record
+            .charged_back()
+            .checked_add(&amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let total_funds = self
+            .total_funds
+            .checked_sub(&amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let posted = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &-amount,
+                &Money::zero(),
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let record = self.transactions.get_mut(tx).expect("record found above");
+        if charged_back < record.net_amount() {
+            record.dispute_status.partially_refunded()
+        } else {
+            record.refunded()
+        }
+        .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        record.set_disputed(Money::zero());
+        record.set_charged_back(charged_back);
+        let held = record.take_held();
+        self.release_held(&held);
+        self.total_funds = total_funds;
+        *system = posted;
+        Ok(self.applied(sequence))
+    }
+
+    /// Disputes withdrawal `tx`. The withdrawn funds are returned to the
+    /// account while the dispute is open, but held in full, so its available
+    /// funds don't change. Withdrawals can only be disputed in full.
+    fn hold_withdrawal(
+        &mut self,
+        tx: u32,
+        reason: Option<DisputeReason>,
+        amount: Option<&Money>,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let record = self
+            .withdrawals
+            .get(&tx)
+            .ok_or(TransactionNotApplied::DisputedTransactionNotFound(tx))?;
+        if amount.is_some_and(|amount| amount != &record.amount) {
+            return Err(TransactionNotApplied::InvalidDisputeState(
+                "Withdrawals can only be disputed in full".to_owned(),
+            ));
+        }
+        let amount = record.amount.clone();
+        let total_funds = self
+            .total_funds
+            .checked_add(&amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let dispute_total = self
+            .active_dispute_total
+            .checked_add(&amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let posted = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &amount,
+                &Money::zero(),
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let record = self.withdrawals.get_mut(&tx).expect("record found above");
+        record
+            .dispute_status
+            .disputed()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        record.reason = reason;
+        self.total_funds = total_funds;
+        self.active_dispute_total = dispute_total;
+        *system = posted;
+        Ok(self.applied(sequence))
+    }
+
+    /// Resolves the dispute of withdrawal `tx` in the withdrawal's favour,
+    /// taking back the funds returned for the dispute.
+    fn release_withdrawal(
+        &mut self,
+        tx: u32,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let record = self
+            .withdrawals
+            .get(&tx)
+            .ok_or(TransactionNotApplied::DisputedTransactionNotFound(tx))?;
+        let amount = record.amount.clone();
+        let total_funds = self
+            .total_funds
+            .checked_sub(&amount)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let posted = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &-amount.clone(),
+                &Money::zero(),
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        self.withdrawals
+            .get_mut(&tx)
+            .expect("record found above")
+            .dispute_status
+            .resolved()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        self.release_held(&amount);
+        self.total_funds = total_funds;
+        *system = posted;
+        Ok(self.applied(sequence))
+    }
+
+    /// Charges back the disputed withdrawal `tx`, crediting the funds
+    /// returned for the dispute back to the client.
+    fn charge_back_withdrawal(
+        &mut self,
+        tx: u32,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<u64, TransactionNotApplied> {
+        let record = self
+            .withdrawals
+            .get_mut(&tx)
+            .ok_or(TransactionNotApplied::DisputedTransactionNotFound(tx))?;
+        record
+            .dispute_status
+            .refunded()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        let amount = record.amount.clone();
+        self.release_held(&amount);
+        Ok(self.applied(sequence))
+    }
+
+    /// Reverses deposit or withdrawal `tx`, e.g. a bank-initiated
+    /// correction, posting the opposite entry and marking it
+    /// [`DisputeStatus::Reversed`]. Unlike a chargeback nothing is held and
+    /// the account isn't locked; reversing a deposit may overdraw the
+    /// account. Fees charged on the transaction are kept. Returns the
+    /// sequence number and the amount posted, negative for a deposit.
+    ///
+    /// Transactions under dispute, or charged back, can't be reversed.
+    pub fn reverse(
+        &mut self,
+        tx: u32,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<(u64, Money), TransactionNotApplied> {
+        let (mut status, posted) = match self.transactions.get(tx) {
+            Some(record) => (record.dispute_status, -record.net_amount()),
+            None => {
+                let record = self
+                    .withdrawals
+                    .get(&tx)
+                    .ok_or(TransactionNotApplied::DisputedTransactionNotFound(tx))?;
+                (record.dispute_status, record.amount.clone())
+            }
+        };
+        // Transition a copy, so a failure below leaves the record untouched.
+        status
+            .reversed()
+            .map_err(TransactionNotApplied::InvalidDisputeState)?;
+        let total_funds = self
+            .total_funds
+            .checked_add(&posted)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        *system = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &posted,
+                &Money::zero(),
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        self.total_funds = total_funds;
+        match self.transactions.get_mut(tx) {
+            Some(record) => record.dispute_status = status,
+            None => {
+                self.withdrawals
+                    .get_mut(&tx)
+                    .expect("record found above")
+                    .dispute_status = status
+            }
+        }
+        Ok((self.applied(sequence), posted))
+    }
+
+    /// Corrects deposit `tx` to `amount`, e.g. when a late fix arrives for a
+    /// deposit whose statements were already published. Rather than
+    /// rewriting the deposit, the difference is posted as a compensating
+    /// entry, numbered like any other transaction; a correction reducing the
+    /// deposit may overdraw the account. A deposit the account has no record
+    /// of is applied as a late deposit. Returns the sequence number and the
+    /// amount posted.
+    ///
+    /// Deposits under dispute, charged back or reversed, can't be
+    /// corrected. A correction to the deposit's current amount is
+    /// [`TransactionNotApplied::AlreadyApplied`].
+    pub fn correct(
+        &mut self,
+        tx: u32,
+        amount: &Money,
+        system: &mut SystemAccounts,
+        sequence: impl FnOnce() -> u64,
+    ) -> Result<(u64, Money), TransactionNotApplied> {
+        let Some(record) = self.transactions.get(tx) else {
+            let sequence = self.credit(tx, amount, &Money::zero(), system, sequence)?;
+            return Ok((sequence, amount.clone()));
+        };
+        if matches!(
+            record.dispute_status,
+            DisputeStatus::Disputed
+                | DisputeStatus::Refunded
+                | DisputeStatus::PartiallyRefunded
+                | DisputeStatus::Reversed
+        ) {
+            return Err(TransactionNotApplied::InvalidDisputeState(format!(
+                "Cannot correct from current transaction state {:?}",
+                record.dispute_status
+            )));
+        }
+        let adjustment = amount
+            .checked_sub(&record.net_amount())
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        if adjustment == Money::zero() {
+            return Err(TransactionNotApplied::AlreadyApplied(tx));
+        }
+        let correction = record
+            .correction()
+            .checked_add(&adjustment)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        let total_funds = self
+            .total_funds
+            .checked_add(&adjustment)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        *system = system
+            .checked_post(
+                self.currency.as_ref(),
+                &self.total_funds,
+                &total_funds,
+                &adjustment,
+                &Money::zero(),
+            )
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+        self.total_funds = total_funds;
+        self.transactions
+            .get_mut(tx)
+            .expect("record found above")
+            .set_correction(correction);
+        Ok((self.applied(sequence), adjustment))
+    }
+
+    /// Accrues interest under `policy` up to the start of `day` (in days
+    /// since 1970-01-01), posting the interest of each period that ended by
+    /// then, numbered with `sequence`. Returns the interest posted.
+    ///
+    /// The balance begins accruing on the first day this is called for.
+    /// Days it has already accrued for are ignored, so times going
+    /// backwards accrue nothing. Posting interest isn't activity of the
+    /// client's, so doesn't reactivate a dormant account.
+    pub fn accrue_interest(
+        &mut self,
+        policy: &InterestPolicy,
+        day: i64,
+        system: &mut SystemAccounts,
+        sequence: impl Fn() -> u64,
+    ) -> Result<Vec<InterestRecord>, TransactionNotApplied> {
+        let overflow = TransactionNotApplied::ArithmeticOverflow;
+        let mut posted = vec![];
+        let Some(mut accrual) = self.accrual.clone() else {
+            self.accrual = Some(Accrual {
+                since: day,
+                accrued: Money::zero(),
+            });
+            return Ok(posted);
+        };
+        while accrual.since < day {
+            let period_end = policy.period.next_start(accrual.since);
+            let until = min(period_end, day);
+            let balance = max(self.available_funds(), Money::zero());
+            accrual.accrued = policy
+                .accrue(&balance, accrual.since, until)
+                .and_then(|interest| accrual.accrued.checked_add(&interest))
+                .ok_or(overflow.clone())?;
+            accrual.since = until;
+            if until == period_end {
+                let amount = accrual
+                    .accrued
+                    .round_with(OUTPUT_SCALE, RoundingMode::HalfEven);
+                accrual.accrued = Money::zero();
+                if amount.is_positive() {
+                    let total_funds = self
+                        .total_funds
+                        .checked_add(&amount)
+                        .ok_or(overflow.clone())?;
+                    *system = system
+                        .checked_pay_interest(
+                            self.currency.as_ref(),
+                            &self.total_funds,
+                            &total_funds,
+                            &amount,
+                        )
+                        .ok_or(overflow.clone())?;
+                    self.total_funds = total_funds;
+                    let record = InterestRecord {
+                        period_end: format_date(period_end - 1),
+                        amount,
+                        sequence: sequence(),
+                    };
+                    self.interest.push(record.clone());
+                    posted.push(record);
+                }
+            }
+            // Kept as it goes, so a later period overflowing doesn't undo
+            // the accrual of those already posted.
+            self.accrual = Some(accrual.clone());
+        }
+        Ok(posted)
+    }
+
+    /// Frees the funds held for a settled dispute.
+    fn release_held(&mut self, held: &Money) {
+        // Every hold is added to the dispute total when made, so more can
+        // never be freed than is held.
+        let over_freed = self.free_disputed_amount(held);
+        debug_assert!(!over_freed, "freed more than held for disputes");
+    }
+
+    /// Numbers a transaction that's been applied to the account.
+    fn applied(&mut self, sequence: impl FnOnce() -> u64) -> u64 {
+        self.last_activity = sequence();
+        self.last_activity
+    }
+
+    /// Number of transactions applied (to any account) since this account's
+    /// last activity, as of sequence number `now`. `None` if the account has
+    /// never been active.
+    pub fn idle_for(&self, now: u64) -> Option<u64> {
+        (self.last_activity != 0).then(|| now.saturating_sub(self.last_activity))
+    }
+
+    /// Whether the account has been idle for at least `after` transactions.
+    /// Accounts that have never been active aren't dormant.
+    pub fn dormant(&self, now: u64, after: u64) -> bool {
+        self.idle_for(now).is_some_and(|idle| idle >= after)
+    }
+
+    /// Returns the funds available for withdrawal. Only negative under
+    /// [`HoldPolicy::NegativeAvailable`], or once overdrawn under
+    /// [`HoldPolicy::HeldBucket`].
+    pub fn available_funds(&self) -> Money {
+        match self.hold_policy {
+            HoldPolicy::Capped => {
+                // Disputes are never negative, so overflow can only mean the
+                // result is below zero.
+                let available = self
+                    .total_funds
+                    .checked_sub(&self.active_dispute_total)
+                    .unwrap_or_else(Money::zero);
+                max(available, Money::zero())
+            }
+            HoldPolicy::NegativeAvailable | HoldPolicy::HeldBucket => {
+                self.total_funds.saturating_sub(&self.active_dispute_total)
+            }
+        }
+    }
+
+    /// Returns the funds that may be withdrawn with an arranged overdraft
+    /// of `overdraft`: the available funds, or if more, what's left after
+    /// held funds of the overdraft. With no overdraft, the available funds.
+    pub fn spendable(&self, overdraft: &Money) -> Money {
+        let headroom = self
+            .total_funds
+            .saturating_sub(&self.active_dispute_total)
+            .checked_add(overdraft)
+            // Only overflows if both are vast, and the available funds too.
+            .unwrap_or_else(|| overdraft.clone());
+        max(self.available_funds(), headroom)
     }
 
     /// Returns the calculated held funds due to disputes.
     ///
     /// This is the amount of the account's total funds held back to cover
     /// disputed payments.
-    pub fn held_funds(&self) -> Decimal {
-        min(
-            self.active_dispute_total,
-            max(self.total_funds, Decimal::ZERO),
-        )
+    pub fn held_funds(&self) -> Money {
+        match self.hold_policy {
+            HoldPolicy::Capped => min(
+                self.active_dispute_total.clone(),
+                max(self.total_funds.clone(), Money::zero()),
+            ),
+            HoldPolicy::NegativeAvailable | HoldPolicy::HeldBucket => {
+                self.active_dispute_total.clone()
+            }
+        }
+    }
+
+    /// Returns the amount to hold for a newly disputed deposit of `amount`.
+    pub fn hold_for_dispute(&self, amount: &Money) -> Money {
+        match self.hold_policy {
+            HoldPolicy::Capped | HoldPolicy::NegativeAvailable => amount.clone(),
+            HoldPolicy::HeldBucket => {
+                min(amount.clone(), max(self.available_funds(), Money::zero()))
+            }
+        }
+    }
+
+    /// Returns the disputed funds the account's balance can't cover, i.e.
+    /// the amount by which disputes exceed the account's (non-negative)
+    /// total funds. Non-zero when disputed deposits were already withdrawn.
+    pub fn dispute_shortfall(&self) -> Money {
+        let disputed = match self.hold_policy {
+            HoldPolicy::Capped | HoldPolicy::NegativeAvailable => self.active_dispute_total.clone(),
+            // Holds are capped at dispute time, so only the deposits know
+            // the full amounts disputed.
+            HoldPolicy::HeldBucket => self
+                .transactions
+                .iter()
+                .filter(|record| record.dispute_status == DisputeStatus::Disputed)
+                .map(DepositRecord::disputed_amount)
+                .chain(self.withdrawals.values().map(DebitRecord::held))
+                .sum(),
+        };
+        let covered = max(self.total_funds.clone(), Money::zero());
+        max(disputed.saturating_sub(&covered), Money::zero())
     }
 
     /// Frees the requested disputed amount to be available for use.
     ///
     /// Returns `true` if the requested amount is greater than the current
-    /// total disputed funds. This represents an error to be handled by
-    /// the caller.
-    pub fn free_disputed_amount(&mut self, amount: &Decimal) -> bool {
-        let new_disputed = self.active_dispute_total - amount;
-        if new_disputed < Decimal::ZERO {
-            self.active_dispute_total = Decimal::ZERO;
+    /// total disputed funds, freeing only what's held.
+    fn free_disputed_amount(&mut self, amount: &Money) -> bool {
+        // Both are non-negative, so this can't overflow.
+        let new_disputed = &self.active_dispute_total - amount;
+        if new_disputed.is_negative() {
+            self.active_dispute_total = Money::zero();
             true
         } else {
             self.active_dispute_total = new_disputed;
@@ -67,166 +1232,1181 @@ impl Account {
     }
 }
 
+/// An account's deposit records, in the order they were applied, indexed by
+/// transaction ID. Stored in chunks from the [`crate::arena`].
+///
+/// Serialized as a map of transaction ID to record, ordered by transaction
+/// ID.
+#[derive(Debug, Default)]
+struct DepositRecords {
+    /// Full chunks of [`CHUNK_RECORDS`] records, then any partly filled.
+    chunks: Vec<Vec<DepositRecord>>,
+    /// Position of each transaction's record, counting through the chunks.
+    index: HashMap<u32, u32>,
+}
+
+impl DepositRecords {
+    fn get(&self, tx: u32) -> Option<&DepositRecord> {
+        let position = *self.index.get(&tx)? as usize;
+        Some(&self.chunks[position / CHUNK_RECORDS][position % CHUNK_RECORDS])
+    }
+
+    fn get_mut(&mut self, tx: u32) -> Option<&mut DepositRecord> {
+        let position = *self.index.get(&tx)? as usize;
+        Some(&mut self.chunks[position / CHUNK_RECORDS][position % CHUNK_RECORDS])
+    }
+
+    /// Adds the record of deposit `tx`, which must be the most recently
+    /// applied of the account's deposits.
+    fn push(&mut self, tx: u32, mut record: DepositRecord) {
+        debug_assert!(
+            !self.index.contains_key(&tx),
+            "deposit {} recorded twice",
+            tx
+        );
+        record.tx = tx;
+        let position = u32::try_from(self.index.len()).expect("at most one record per tx");
+        if self.index.capacity() == 0 {
+            self.index = arena::take_index();
+        }
+        self.index.insert(tx, position);
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() < CHUNK_RECORDS => chunk.push(record),
+            // The first chunk grows as usual, so accounts with few deposits
+            // don't take a whole chunk.
+            None => self.chunks.push(vec![record]),
+            Some(_) => {
+                let mut chunk = arena::take_chunk();
+                chunk.push(record);
+                self.chunks.push(chunk);
+            }
+        }
+    }
+
+    /// The records in the order they were applied.
+    fn iter(&self) -> impl Iterator<Item = &DepositRecord> {
+        self.chunks.iter().flatten()
+    }
+}
+
+impl Drop for DepositRecords {
+    fn drop(&mut self) {
+        arena::give_back(
+            std::mem::take(&mut self.chunks),
+            std::mem::take(&mut self.index),
+        );
+    }
+}
+
+impl Serialize for DepositRecords {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut records: Vec<&DepositRecord> = self.iter().collect();
+        records.sort_unstable_by_key(|record| record.tx);
+        serializer.collect_map(records.into_iter().map(|record| (record.tx, record)))
+    }
+}
+
+impl<'de> Deserialize<'de> for DepositRecords {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = BTreeMap::<u32, DepositRecord>::deserialize(deserializer)?;
+        let mut records: Vec<(u32, DepositRecord)> = map.into_iter().collect();
+        records.sort_by_key(|(tx, record)| (record.sequence, *tx));
+        let mut deposits = DepositRecords::default();
+        for (tx, record) in records {
+            deposits.push(tx, record);
+        }
+        Ok(deposits)
+    }
+}
+
+/// Serializes a map ordered by key, so serialized state is deterministic.
+pub(crate) fn serialize_sorted<K, V, S>(
+    map: &HashMap<K, V>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+#[cfg(test)]
+impl Account {
+    /// Sets the account's balances directly, for tests of code reading them.
+    pub(crate) fn set_funds(&mut self, total_funds: Money, active_dispute_total: Money) {
+        self.total_funds = total_funds;
+        self.active_dispute_total = active_dispute_total;
+    }
+
+    /// Sets the account's last activity directly, for tests of code reading
+    /// it.
+    pub(crate) fn set_last_activity(&mut self, sequence: u64) {
+        self.last_activity = sequence;
+    }
+}
+
+/// How an overdrawn account's negative total is reported in statements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TotalPolicy {
+    /// Report the total as-is, which may be negative.
+    #[default]
+    Signed,
+    /// Clamp the total to zero and report the shortfall in an additional
+    /// `owed` column (zero for accounts that aren't overdrawn).
+    #[serde(rename = "owed")]
+    ClampWithOwed,
+}
+
+impl std::str::FromStr for TotalPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "signed" => Ok(TotalPolicy::Signed),
+            "owed" => Ok(TotalPolicy::ClampWithOwed),
+            _ => Err(format!("Unknown total policy {:?}", s)),
+        }
+    }
+}
+
+/// Order to output account statements in. Ties are always broken by client
+/// ID, so every order is deterministic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StatementOrder {
+    /// Ascending client ID.
+    Client,
+    /// Largest total first.
+    TotalDesc,
+    /// Largest held funds first.
+    HeldDesc,
+    /// Locked accounts first.
+    LockedFirst,
+}
+
+impl StatementOrder {
+    /// Sorts `statements` into this order.
+    pub fn sort(&self, statements: &mut [AccountStatement]) {
+        statements.sort_by(|a, b| {
+            let ordering = match self {
+                StatementOrder::Client => std::cmp::Ordering::Equal,
+                StatementOrder::TotalDesc => b.total.cmp(&a.total),
+                StatementOrder::HeldDesc => b.held.cmp(&a.held),
+                StatementOrder::LockedFirst => b.locked.cmp(&a.locked),
+            };
+            ordering
+                .then(a.client.cmp(&b.client))
+                .then_with(|| a.currency.cmp(&b.currency))
+        });
+    }
+}
+
+impl std::str::FromStr for StatementOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(StatementOrder::Client),
+            "total-desc" => Ok(StatementOrder::TotalDesc),
+            "held-desc" => Ok(StatementOrder::HeldDesc),
+            "locked-first" => Ok(StatementOrder::LockedFirst),
+            _ => Err(format!("Unknown statement order {:?}", s)),
+        }
+    }
+}
+
+/// Output options for account statements.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StatementOptions {
+    /// How overdrawn accounts' totals are reported.
+    pub total_policy: TotalPolicy,
+    /// Include a `shortfall` column, holding the disputed funds the
+    /// account's balance can't cover (see [`Account::dispute_shortfall`]).
+    pub shortfall: bool,
+    /// Include a `fees` column, holding the fees charged to the balance
+    /// (see [`Account::fees_charged`]).
+    pub fees: bool,
+    /// Order to output statements in. Unordered if not set.
+    pub order: Option<StatementOrder>,
+}
+
 /// Serializable summary of an account's state intended for reporting.
 ///
 /// Note: when constructing an [`AccountStatement`] from an [`Account`], all
-/// values of funds are rounded to 4 decimal places.
-#[derive(Debug, Serialize)]
+/// values of funds are rounded to [`OUTPUT_SCALE`] decimal places.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 pub struct AccountStatement {
     client: u16,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Interned>,
+    available: Money,
+    held: Money,
+    total: Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    owed: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shortfall: Option<Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fees: Option<Money>,
     locked: bool,
 }
 
+impl AccountStatement {
+    /// Client ID the statement is for.
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+
+    /// Currency of the balance the statement is for, if named. Only
+    /// output when statements are in more than one currency (see
+    /// [`crate::statements::label_currencies`]).
+    pub fn currency(&self) -> Option<&Interned> {
+        self.currency.as_ref()
+    }
+
+    /// Labels the statement with another currency, or none.
+    pub(crate) fn set_currency(&mut self, currency: Option<Interned>) {
+        self.currency = currency;
+    }
+
+    pub fn available(&self) -> &Money {
+        &self.available
+    }
+
+    pub fn held(&self) -> &Money {
+        &self.held
+    }
+
+    pub fn total(&self) -> &Money {
+        &self.total
+    }
+
+    /// Amount owed by an overdrawn account, only present when the statement
+    /// follows [`TotalPolicy::ClampWithOwed`].
+    pub fn owed(&self) -> Option<&Money> {
+        self.owed.as_ref()
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Disputed funds the account's balance can't cover. Present unless
+    /// dropped by [`AccountStatement::with_options`].
+    pub fn shortfall(&self) -> Option<&Money> {
+        self.shortfall.as_ref()
+    }
+
+    /// Fees charged to the balance. Present unless dropped by
+    /// [`AccountStatement::with_options`].
+    pub fn fees(&self) -> Option<&Money> {
+        self.fees.as_ref()
+    }
+
+    /// Applies the given [`StatementOptions`] to a statement built from an
+    /// [`Account`], which is always [`TotalPolicy::Signed`] and includes the
+    /// shortfall and fees.
+    pub fn with_options(mut self, options: &StatementOptions) -> Self {
+        if options.total_policy == TotalPolicy::ClampWithOwed {
+            self.owed = Some(max(-self.total.clone(), Money::zero()));
+            self.total = max(self.total, Money::zero());
+        }
+        if !options.shortfall {
+            self.shortfall = None;
+        }
+        if !options.fees {
+            self.fees = None;
+        }
+        self
+    }
+
+    /// The statement, labelled with another client ID (e.g. a pseudonym).
+    pub(crate) fn with_client(mut self, client: u16) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Displays every amount with exactly [`OUTPUT_SCALE`] decimal places.
+    pub fn to_fixed_scale(mut self) -> Self {
+        for amount in [&mut self.available, &mut self.held, &mut self.total]
+            .into_iter()
+            .chain(self.owed.as_mut())
+            .chain(self.shortfall.as_mut())
+            .chain(self.fees.as_mut())
+        {
+            *amount = amount.to_fixed_scale(OUTPUT_SCALE);
+        }
+        self
+    }
+
+    /// Column names of statements following `options`, in serialized order.
+    /// Kept in step with the struct's fields, for writers that need a header
+    /// even when there are no statements. Statements labelled with their
+    /// currency also have a `currency` column, after `client`.
+    pub fn header(options: &StatementOptions) -> Vec<&'static str> {
+        let mut header = vec!["client", "available", "held", "total"];
+        if options.total_policy == TotalPolicy::ClampWithOwed {
+            header.push("owed");
+        }
+        if options.shortfall {
+            header.push("shortfall");
+        }
+        if options.fees {
+            header.push("fees");
+        }
+        header.push("locked");
+        header
+    }
+}
+
 impl std::convert::From<&Account> for AccountStatement {
+    /// The statement of the account's primary balance (or of the balance
+    /// given). [`Account::statements`] has one per balance.
     fn from(src: &Account) -> Self {
         Self {
             client: src.client,
-            available: src.available_funds().round_dp(4),
-            held: src.held_funds().round_dp(4),
-            total: src.total_funds.round_dp(4),
-            locked: src.locked,
+            currency: src.currency.clone(),
+            available: src.available_funds().round_dp(OUTPUT_SCALE),
+            held: src.held_funds().round_dp(OUTPUT_SCALE),
+            total: src.total_funds.round_dp(OUTPUT_SCALE),
+            owed: None,
+            shortfall: Some(src.dispute_shortfall().round_dp(OUTPUT_SCALE)),
+            fees: Some(src.fees_charged().round_dp(OUTPUT_SCALE)),
+            locked: src.locked(),
+        }
+    }
+}
+
+/// A withdrawal tagged with where it was paid to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WithdrawalRecord {
+    pub tx: u32,
+    /// E.g. a bank reference or wallet ID.
+    pub destination: Interned,
+    pub amount: Money,
+    /// Sequence number the withdrawal was applied with.
+    pub sequence: u64,
+}
+
+/// A deposit, withdrawal or conversion in an account's history (see
+/// [`Account::history`]).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// Sequence number the transaction was applied with.
+    pub sequence: u64,
+    pub tx: u32,
+    /// `deposit`, `withdrawal` or `convert`, as in input files.
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    /// Currency of the balance the transaction applied to, or converted
+    /// from, if any transaction has named one.
+    pub currency: Option<Interned>,
+    /// Amount as applied, before any corrections; for a conversion, the
+    /// amount taken.
+    pub amount: Money,
+    /// Where a deposit or withdrawal is in the dispute process.
+    pub dispute_status: Option<DisputeStatus>,
+    /// When the transaction happened, in seconds since the Unix epoch, if
+    /// known.
+    pub timestamp: Option<u64>,
+}
+
+/// A conversion between two of an account's currency balances.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversionRecord {
+    pub from: Interned,
+    pub to: Interned,
+    /// Amount taken from the `from` balance.
+    pub amount: Money,
+    /// Amount credited to the `to` balance, once rounded.
+    pub converted: Money,
+    pub rate: Money,
+    /// Sequence number the conversion was applied with.
+    pub sequence: u64,
+}
+
+/// A withdrawal that was successfully processed for an account.
+///
+/// Disputing a withdrawal works the other way round to a deposit: rather
+/// than holding back funds the client was given, the funds they withdrew are
+/// returned to the account and held until the dispute is settled. Resolving
+/// the dispute takes them back out; a chargeback credits them to the client.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DebitRecord {
+    pub amount: Money,
+    /// Sequence number the withdrawal was applied with.
+    pub sequence: u64,
+    /// Reason given for the most recent dispute, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<DisputeReason>,
+    #[serde(default = "not_disputed")]
+    dispute_status: DisputeStatus,
+    /// When the withdrawal happened, in seconds since the Unix epoch, if
+    /// known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+fn not_disputed() -> DisputeStatus {
+    DisputeStatus::NotDisputed
+}
+
+impl DebitRecord {
+    pub fn new(amount: Money, sequence: u64) -> Self {
+        Self {
+            amount,
+            sequence,
+            reason: None,
+            dispute_status: DisputeStatus::NotDisputed,
+            timestamp: None,
+        }
+    }
+
+    /// Amount held while the withdrawal is disputed, which is always all of
+    /// it. Zero otherwise.
+    pub fn held(&self) -> Money {
+        match self.dispute_status {
+            DisputeStatus::Disputed => self.amount.clone(),
+            _ => Money::zero(),
         }
     }
+
+    pub fn dispute_status(&self) -> DisputeStatus {
+        self.dispute_status
+    }
 }
 
 /// A deposit that was successfully processed for an account.
+///
+/// Accounts may retain a great many deposits, so records are kept small.
+/// Amounts held for disputes, and corrections, are rare, so are kept out of
+/// line (see [`Adjustments`]) and only while non-zero.
 #[derive(Debug)]
 pub struct DepositRecord {
-    pub amount: Decimal,
+    pub amount: Money,
+    /// Sequence number the deposit was applied with.
+    pub sequence: u64,
+    adjustments: Option<Box<Adjustments>>,
+    /// The deposit's transaction ID. Set once the record is added to an
+    /// account.
+    tx: u32,
+    /// Reason given for the most recent dispute, if any.
+    pub reason: Option<DisputeReason>,
     // Private, so we can enforce transitions via methods instead.
     dispute_status: DisputeStatus,
+    /// When the deposit happened, if known. Non-zero so it packs into
+    /// 8 bytes, at the cost of treating the epoch itself as unknown.
+    timestamp: Option<NonZeroU64>,
+}
+
+/// The parts of a [`DepositRecord`] that are usually zero.
+#[derive(Debug, Default)]
+struct Adjustments {
+    held: Money,
+    correction: Money,
+    disputed: Money,
+    charged_back: Money,
+}
+
+/// A [`DepositRecord`] as serialized.
+#[derive(Serialize)]
+struct DepositRecordRef<'a> {
+    amount: &'a Money,
+    held: &'a Money,
+    sequence: u64,
+    reason: Option<DisputeReason>,
+    correction: &'a Money,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disputed: Option<&'a Money>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charged_back: Option<&'a Money>,
+    dispute_status: DisputeStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+}
+
+/// A [`DepositRecord`] as deserialized.
+#[derive(Deserialize)]
+struct DepositRecordState {
+    amount: Money,
+    held: Money,
+    sequence: u64,
+    reason: Option<DisputeReason>,
+    #[serde(default)]
+    correction: Money,
+    /// Missing from records written before partial disputes, when the whole
+    /// deposit was always disputed.
+    #[serde(default)]
+    disputed: Option<Money>,
+    #[serde(default)]
+    charged_back: Option<Money>,
+    dispute_status: DisputeStatus,
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+impl Serialize for DepositRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let zero = Money::zero();
+        let adjustments = self.adjustments.as_deref();
+        DepositRecordRef {
+            amount: &self.amount,
+            held: adjustments.map_or(&zero, |adjustments| &adjustments.held),
+            sequence: self.sequence,
+            reason: self.reason,
+            correction: adjustments.map_or(&zero, |adjustments| &adjustments.correction),
+            disputed: adjustments
+                .map(|adjustments| &adjustments.disputed)
+                .filter(|disputed| **disputed != zero),
+            charged_back: adjustments
+                .map(|adjustments| &adjustments.charged_back)
+                .filter(|charged_back| **charged_back != zero),
+            dispute_status: self.dispute_status,
+            timestamp: self.timestamp(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DepositRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let state = DepositRecordState::deserialize(deserializer)?;
+        let mut record = DepositRecord::new(state.amount, state.sequence);
+        record.set_held(state.held);
+        record.set_correction(state.correction);
+        let whole = |status| {
+            if state.dispute_status == status {
+                record.net_amount()
+            } else {
+                Money::zero()
+            }
+        };
+        let disputed = state
+            .disputed
+            .unwrap_or_else(|| whole(DisputeStatus::Disputed));
+        let charged_back = state
+            .charged_back
+            .unwrap_or_else(|| whole(DisputeStatus::Refunded));
+        record.set_disputed(disputed);
+        record.set_charged_back(charged_back);
+        record.reason = state.reason;
+        record.dispute_status = state.dispute_status;
+        record.timestamp = state.timestamp.and_then(NonZeroU64::new);
+        Ok(record)
+    }
 }
 
 impl DepositRecord {
-    pub fn new(amount: Decimal) -> Self {
+    pub fn new(amount: Money, sequence: u64) -> Self {
         Self {
             dispute_status: DisputeStatus::NotDisputed,
             amount,
+            sequence,
+            adjustments: None,
+            tx: 0,
+            reason: None,
+            timestamp: None,
+        }
+    }
+
+    /// When the deposit happened, in seconds since the Unix epoch, if known.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp.map(NonZeroU64::get)
+    }
+
+    /// Amount held while the deposit is disputed. Zero otherwise.
+    pub fn held(&self) -> Money {
+        self.adjustments
+            .as_ref()
+            .map_or_else(Money::zero, |adjustments| adjustments.held.clone())
+    }
+
+    /// Net of the compensating entries posted by corrections (see
+    /// [`Account::correct`]). The deposit's corrected amount is `amount` plus
+    /// this.
+    pub fn correction(&self) -> Money {
+        self.adjustments
+            .as_ref()
+            .map_or_else(Money::zero, |adjustments| adjustments.correction.clone())
+    }
+
+    /// Amount disputed while the deposit is disputed, which is what's
+    /// charged back. Zero otherwise.
+    pub fn disputed_amount(&self) -> Money {
+        self.adjustments
+            .as_ref()
+            .map_or_else(Money::zero, |adjustments| adjustments.disputed.clone())
+    }
+
+    /// Total charged back by the deposit's chargebacks.
+    pub fn charged_back(&self) -> Money {
+        self.adjustments
+            .as_ref()
+            .map_or_else(Money::zero, |adjustments| adjustments.charged_back.clone())
+    }
+
+    /// What's left to dispute of the deposit: its amount after any
+    /// corrections, less what's been charged back. Disputes without an
+    /// amount dispute all of it.
+    pub fn disputable(&self) -> Money {
+        match &self.adjustments {
+            Some(adjustments) => &self.net_amount() - &adjustments.charged_back,
+            None => self.amount.clone(),
+        }
+    }
+
+    /// The deposit's amount after any corrections.
+    pub fn net_amount(&self) -> Money {
+        match &self.adjustments {
+            Some(adjustments) => &self.amount + &adjustments.correction,
+            None => self.amount.clone(),
+        }
+    }
+
+    fn set_held(&mut self, held: Money) {
+        self.adjust(|adjustments| adjustments.held = held);
+    }
+
+    fn take_held(&mut self) -> Money {
+        let mut held = Money::zero();
+        self.adjust(|adjustments| held = std::mem::take(&mut adjustments.held));
+        held
+    }
+
+    fn set_correction(&mut self, correction: Money) {
+        self.adjust(|adjustments| adjustments.correction = correction);
+    }
+
+    fn set_disputed(&mut self, disputed: Money) {
+        self.adjust(|adjustments| adjustments.disputed = disputed);
+    }
+
+    fn set_charged_back(&mut self, charged_back: Money) {
+        self.adjust(|adjustments| adjustments.charged_back = charged_back);
+    }
+
+    /// Changes the adjustments, dropping them once they're all zero.
+    fn adjust(&mut self, f: impl FnOnce(&mut Adjustments)) {
+        let adjustments = self.adjustments.get_or_insert_default();
+        f(adjustments);
+        let zero = Money::zero();
+        if [
+            &adjustments.held,
+            &adjustments.correction,
+            &adjustments.disputed,
+            &adjustments.charged_back,
+        ]
+        .iter()
+        .all(|amount| **amount == zero)
+        {
+            self.adjustments = None;
         }
     }
 
-    #[cfg(test)]
     pub fn dispute_status(&self) -> DisputeStatus {
         self.dispute_status
     }
 
-    pub fn disputed(&mut self) -> Result<(), String> {
-        if self.dispute_status == DisputeStatus::Disputed
-            || self.dispute_status == DisputeStatus::Refunded
-        {
+    fn resolved(&mut self) -> Result<(), String> {
+        self.dispute_status.resolved()
+    }
+
+    fn refunded(&mut self) -> Result<(), String> {
+        self.dispute_status.refunded()
+    }
+}
+
+/// Dispute status denote's a transaction's position in the dispute process.
+///
+/// Valid transitions are:
+/// NotDisputed -> Disputed
+/// Disputed -> {Resolved, Refunded, PartiallyRefunded}
+/// {Resolved, PartiallyRefunded} -> Disputed
+/// {NotDisputed, Resolved} -> Reversed
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeStatus {
+    NotDisputed,
+    Disputed,
+    Resolved,
+    Refunded,
+    /// Charged back in part, with more left to dispute.
+    PartiallyRefunded,
+    /// Undone by a reversal (see [`Account::reverse`]). Final.
+    Reversed,
+}
+
+impl DisputeStatus {
+    fn disputed(&mut self) -> Result<(), String> {
+        if matches!(
+            *self,
+            DisputeStatus::Disputed | DisputeStatus::Refunded | DisputeStatus::Reversed
+        ) {
             return Err(format!(
                 "Cannot begin dispute from current transaciton state {:?}",
-                self.dispute_status
+                *self
             ));
         }
-        self.dispute_status = DisputeStatus::Disputed;
+        *self = DisputeStatus::Disputed;
         Ok(())
     }
 
-    pub fn resolved(&mut self) -> Result<(), String> {
-        if self.dispute_status != DisputeStatus::Disputed {
+    fn resolved(&mut self) -> Result<(), String> {
+        if *self != DisputeStatus::Disputed {
             return Err(format!(
                 "Cannot resolve dispute from current transaction state {:?}",
-                self.dispute_status
+                *self
             ));
         }
-        self.dispute_status = DisputeStatus::Resolved;
+        *self = DisputeStatus::Resolved;
         Ok(())
     }
 
-    pub fn refunded(&mut self) -> Result<(), String> {
-        if self.dispute_status != DisputeStatus::Disputed {
+    fn refunded(&mut self) -> Result<(), String> {
+        if *self != DisputeStatus::Disputed {
             return Err(format!(
                 "Cannot chargeback from current transaction state {:?}",
-                self.dispute_status
+                *self
             ));
         }
-        self.dispute_status = DisputeStatus::Refunded;
+        *self = DisputeStatus::Refunded;
         Ok(())
     }
-}
 
-/// Dispute status denote's a transaction's position in the dispute process.
-///
-/// Valid transitions are:
-/// NotDisputed -> Disputed
-/// Disputed -> {Resolved, Refunded}
-/// Resolved -> Disputed
-#[derive(Debug, PartialEq, Clone, Copy)]
-pub enum DisputeStatus {
-    NotDisputed,
-    Disputed,
-    Resolved,
-    Refunded,
+    fn partially_refunded(&mut self) -> Result<(), String> {
+        self.refunded()?;
+        *self = DisputeStatus::PartiallyRefunded;
+        Ok(())
+    }
+
+    fn reversed(&mut self) -> Result<(), String> {
+        if !matches!(*self, DisputeStatus::NotDisputed | DisputeStatus::Resolved) {
+            return Err(format!(
+                "Cannot reverse from current transaction state {:?}",
+                *self
+            ));
+        }
+        *self = DisputeStatus::Reversed;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use rust_decimal_macros::dec;
+    use crate::money::money;
+
+    #[test]
+    fn lock_scopes() {
+        let deposit = TransactionInfo::Deposit(money!(1));
+        let withdrawal = TransactionInfo::Withdrawal(money!(1));
+        let allowed = |scope: LockScope| {
+            [&deposit, &withdrawal, &TransactionInfo::Dispute(None)].map(|info| scope.allows(info))
+        };
+        assert_eq!(allowed(LockScope::BlockDebits), [true, false, true]);
+        assert_eq!(allowed(LockScope::AllowDisputes), [false, false, true]);
+        assert_eq!(allowed(LockScope::BlockAll), [false, false, false]);
+
+        let mut acc = Account::new(1);
+        assert!(!acc.locked());
+        acc.lock(LockScope::AllowDisputes);
+        acc.lock(LockScope::BlockDebits);
+        assert_eq!(acc.lock, Some(LockScope::AllowDisputes));
+        acc.lock(LockScope::BlockAll);
+        assert_eq!(acc.lock, Some(LockScope::BlockAll));
+    }
+
+    #[test]
+    fn hold_policies() {
+        let mut acc = Account::new(1);
+        acc.total_funds = money!(100);
+        acc.active_dispute_total = money!(125);
+        assert_eq!(acc.available_funds(), money!(0));
+        assert_eq!(acc.held_funds(), money!(100));
+        assert_eq!(acc.hold_for_dispute(&money!(30)), money!(30));
+
+        acc.hold_policy = HoldPolicy::NegativeAvailable;
+        assert_eq!(acc.available_funds(), money!(-25));
+        assert_eq!(acc.held_funds(), money!(125));
+        assert_eq!(acc.hold_for_dispute(&money!(30)), money!(30));
+
+        acc.hold_policy = HoldPolicy::HeldBucket;
+        acc.active_dispute_total = money!(80);
+        assert_eq!(acc.available_funds(), money!(20));
+        assert_eq!(acc.held_funds(), money!(80));
+        // Only what's available can be moved into the bucket.
+        assert_eq!(acc.hold_for_dispute(&money!(30)), money!(20));
+        assert_eq!(acc.hold_for_dispute(&money!(10)), money!(10));
+    }
+
+    #[test]
+    fn statement_total_policy() {
+        let clamp = StatementOptions {
+            total_policy: TotalPolicy::ClampWithOwed,
+            ..StatementOptions::default()
+        };
+        let mut acc = Account::new(1);
+        acc.total_funds = money!(-12.5);
+        let statement = AccountStatement::from(&acc).with_options(&StatementOptions::default());
+        assert_eq!(statement.total(), &money!(-12.5));
+        assert_eq!(statement.owed(), None);
+
+        let statement = AccountStatement::from(&acc).with_options(&clamp);
+        assert_eq!(statement.total(), &money!(0));
+        assert_eq!(statement.owed(), Some(&money!(12.5)));
+
+        acc.total_funds = money!(3);
+        let statement = AccountStatement::from(&acc).with_options(&clamp);
+        assert_eq!(statement.total(), &money!(3));
+        assert_eq!(statement.owed(), Some(&money!(0)));
+    }
+
+    #[test]
+    fn header_matches_serialized_fields() {
+        for total_policy in [TotalPolicy::Signed, TotalPolicy::ClampWithOwed] {
+            for (shortfall, fees) in [(false, false), (true, false), (false, true), (true, true)] {
+                let options = StatementOptions {
+                    total_policy,
+                    shortfall,
+                    fees,
+                    order: None,
+                };
+                let statement = AccountStatement::from(&Account::new(1)).with_options(&options);
+                let mut writer = csv::Writer::from_writer(vec![]);
+                writer.serialize(statement).unwrap();
+                let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+                let header = output.lines().next().unwrap();
+                assert_eq!(header, AccountStatement::header(&options).join(","));
+            }
+        }
+    }
+
+    #[test]
+    fn statement_orders() {
+        let statements = || {
+            [
+                (1, 5, 0, false),
+                (2, 9, 3, true),
+                (3, 9, 0, false),
+                (4, -2, 0, true),
+            ]
+            .map(|(client, total, held, locked)| {
+                let mut acc = Account::new(client);
+                acc.total_funds = Money::from(total);
+                acc.active_dispute_total = Money::from(held);
+                if locked {
+                    acc.lock(LockScope::BlockAll);
+                }
+                AccountStatement::from(&acc)
+            })
+        };
+        let order = |order: StatementOrder| {
+            let mut statements = statements();
+            order.sort(&mut statements);
+            statements.map(|statement| statement.client())
+        };
+        assert_eq!(order(StatementOrder::Client), [1, 2, 3, 4]);
+        assert_eq!(order(StatementOrder::TotalDesc), [2, 3, 1, 4]);
+        assert_eq!(order(StatementOrder::HeldDesc), [2, 1, 3, 4]);
+        assert_eq!(order(StatementOrder::LockedFirst), [2, 4, 1, 3]);
+    }
+
+    #[test]
+    fn fixed_scale_statement() {
+        let mut acc = Account::new(1);
+        acc.total_funds = money!(2.5);
+        let statement = AccountStatement::from(&acc).to_fixed_scale();
+        assert_eq!(statement.total().to_string(), "2.5000");
+        assert_eq!(statement.held().to_string(), "0.0000");
+        assert_eq!(statement.shortfall().unwrap().to_string(), "0.0000");
+    }
+
+    #[test]
+    fn transaction_history_in_applied_order() {
+        let mut acc = Account::new(1);
+        for (tx, sequence) in [(5, 1), (7, 3), (2, 9)] {
+            acc.transactions
+                .push(tx, DepositRecord::new(money!(10), sequence));
+        }
+        acc.transactions
+            .get_mut(2)
+            .unwrap()
+            .dispute_status
+            .disputed()
+            .unwrap();
+        acc.transactions.get_mut(2).unwrap().set_held(money!(10));
+        let expected = vec![
+            (5, 1, DisputeStatus::NotDisputed, money!(0)),
+            (7, 3, DisputeStatus::NotDisputed, money!(0)),
+            (2, 9, DisputeStatus::Disputed, money!(10)),
+        ];
+        let history = |acc: &Account| -> Vec<(u32, u64, DisputeStatus, Money)> {
+            acc.transaction_history()
+                .into_iter()
+                .map(|(tx, record)| (tx, record.sequence, record.dispute_status(), record.held()))
+                .collect()
+        };
+        assert_eq!(history(&acc), expected);
+
+        // Saved ordered by transaction ID, and restored in applied order.
+        let state = serde_json::to_value(&acc).unwrap();
+        let txs: Vec<&String> = state["transactions"].as_object().unwrap().keys().collect();
+        assert_eq!(txs, ["2", "5", "7"]);
+        assert_eq!(
+            state["transactions"]["2"],
+            serde_json::json!({
+                "amount": "10",
+                "held": "10",
+                "sequence": 9,
+                "reason": null,
+                "correction": "0",
+                "dispute_status": "disputed",
+            })
+        );
+        let restored: Account = serde_json::from_value(state).unwrap();
+        assert_eq!(history(&restored), expected);
+        assert_eq!(restored.transaction(7).unwrap().sequence, 3);
+        // Records without the amount disputed, as saved before partial
+        // disputes, were disputed in full.
+        assert_eq!(
+            restored.transaction(2).unwrap().disputed_amount(),
+            money!(10)
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigdecimal"))]
+    fn deposit_records_compact() {
+        // An amount, a sequence number, a pointer to any adjustments, a
+        // timestamp, and the transaction ID, reason and status packed
+        // alongside.
+        assert_eq!(std::mem::size_of::<DepositRecord>(), 48);
+        let mut record = DepositRecord::new(money!(10), 1);
+        record.set_correction(money!(-2));
+        assert_eq!(record.net_amount(), money!(8));
+        record.set_correction(money!(0));
+        // Freed once back to zero.
+        assert!(record.adjustments.is_none());
+        record.set_held(money!(10));
+        assert_eq!(record.take_held(), money!(10));
+        assert!(record.adjustments.is_none());
+    }
+
+    #[test]
+    fn transactions_applied_atomically() {
+        let mut acc = Account::new(1);
+        let mut system = SystemAccounts::default();
+        let mut next = 0;
+        let mut sequence = || {
+            next += 1;
+            next
+        };
+        assert_eq!(
+            acc.credit(1, &money!(100), &money!(1), &mut system, &mut sequence),
+            Ok(1)
+        );
+        assert_eq!(
+            acc.credit(1, &money!(5), &money!(0), &mut system, &mut sequence),
+            Err(TransactionNotApplied::RepeatTransaction(1))
+        );
+        assert_eq!(
+            acc.debit(
+                2,
+                &money!(99),
+                &money!(1),
+                &money!(0),
+                &mut system,
+                &mut sequence
+            ),
+            Err(TransactionNotApplied::InsufficientFunds(money!(1)))
+        );
+        assert_eq!(acc.hold(1, None, None, &mut system, &mut sequence), Ok(2));
+        assert_eq!(acc.held_funds(), money!(99));
+        assert!(matches!(
+            acc.hold(1, None, None, &mut system, &mut sequence),
+            Err(TransactionNotApplied::InvalidDisputeState(_))
+        ));
+        assert_eq!(acc.release(1, &mut system, &mut sequence), Ok(3));
+        assert_eq!(acc.active_dispute_total(), &money!(0));
+        assert_eq!(
+            acc.debit(
+                2,
+                &money!(60),
+                &money!(0),
+                &money!(0),
+                &mut system,
+                &mut sequence
+            ),
+            Ok(4)
+        );
+        acc.hold(
+            1,
+            Some(DisputeReason::Fraud),
+            None,
+            &mut system,
+            &mut sequence,
+        )
+        .unwrap();
+        assert_eq!(acc.charge_back(1, &mut system, &mut sequence), Ok(6));
+        assert_eq!(acc.total_funds(), &money!(-61));
+        assert_eq!(acc.active_dispute_total(), &money!(0));
+        assert_eq!(acc.last_activity(), 6);
+        // Failed transactions weren't numbered.
+        assert_eq!(next, 6);
+        assert_eq!(acc.total_funds() + &system.total(), money!(0));
+    }
+
+    #[test]
+    fn corrections_post_compensating_entries() {
+        let mut acc = Account::new(1);
+        let mut system = SystemAccounts::default();
+        let mut next = 0;
+        let mut sequence = || {
+            next += 1;
+            next
+        };
+        acc.credit(1, &money!(10), &money!(0), &mut system, &mut sequence)
+            .unwrap();
+        acc.debit(
+            3,
+            &money!(8),
+            &money!(0),
+            &money!(0),
+            &mut system,
+            &mut sequence,
+        )
+        .unwrap();
+
+        // Corrected down, overdrawing the account.
+        assert_eq!(
+            acc.correct(1, &money!(6), &mut system, &mut sequence),
+            Ok((3, money!(-4)))
+        );
+        assert_eq!(acc.total_funds(), &money!(-2));
+        assert_eq!(
+            acc.correct(1, &money!(6), &mut system, &mut sequence),
+            Err(TransactionNotApplied::AlreadyApplied(1))
+        );
+        // The deposit itself is left as it was applied.
+        let record = acc.transaction(1).unwrap();
+        assert_eq!((&record.amount, record.sequence), (&money!(10), 1));
+        assert_eq!(record.net_amount(), money!(6));
+
+        // Disputes are for the corrected amount.
+        acc.hold(1, None, None, &mut system, &mut sequence).unwrap();
+        assert_eq!(acc.active_dispute_total(), &money!(6));
+        assert!(matches!(
+            acc.correct(1, &money!(10), &mut system, &mut sequence),
+            Err(TransactionNotApplied::InvalidDisputeState(_))
+        ));
+        acc.release(1, &mut system, &mut sequence).unwrap();
+
+        // Unknown deposits arrived late.
+        assert_eq!(
+            acc.correct(2, &money!(5), &mut system, &mut sequence),
+            Ok((6, money!(5)))
+        );
+        assert_eq!(acc.total_funds(), &money!(3));
+        assert_eq!(acc.total_funds() + &system.total(), money!(0));
+    }
+
+    #[test]
+    fn dispute_shortfall() {
+        let mut acc = Account::new(1);
+        acc.total_funds = money!(30);
+        acc.active_dispute_total = money!(100);
+        assert_eq!(acc.dispute_shortfall(), money!(70));
+        acc.total_funds = money!(-10);
+        assert_eq!(acc.dispute_shortfall(), money!(100));
+        acc.total_funds = money!(150);
+        assert_eq!(acc.dispute_shortfall(), money!(0));
+
+        // Held bucket holds may be less than the disputed amounts.
+        acc.hold_policy = HoldPolicy::HeldBucket;
+        acc.total_funds = money!(30);
+        acc.active_dispute_total = money!(20);
+        let mut record = DepositRecord::new(money!(100), 1);
+        record.dispute_status.disputed().unwrap();
+        record.set_disputed(money!(100));
+        record.set_held(money!(20));
+        acc.transactions.push(1, record);
+        acc.transactions.push(2, DepositRecord::new(money!(50), 2));
+        assert_eq!(acc.dispute_shortfall(), money!(70));
+
+        let statement = AccountStatement::from(&acc);
+        assert_eq!(statement.shortfall(), Some(&money!(70)));
+        let statement = statement.with_options(&StatementOptions::default());
+        assert_eq!(statement.shortfall(), None);
+        let options = StatementOptions {
+            shortfall: true,
+            ..StatementOptions::default()
+        };
+        let statement = AccountStatement::from(&acc).with_options(&options);
+        assert_eq!(statement.shortfall(), Some(&money!(70)));
+    }
 
     #[test]
     fn hold_funds_for_disputed_transactions() {
         let mut acc = Account::new(1);
-        acc.total_funds = dec!(100);
-        assert_eq!(acc.available_funds(), dec!(100));
-        assert_eq!(acc.held_funds(), dec!(0));
+        acc.total_funds = money!(100);
+        assert_eq!(acc.available_funds(), money!(100));
+        assert_eq!(acc.held_funds(), money!(0));
 
-        acc.active_dispute_total = dec!(50);
-        assert_eq!(acc.available_funds(), dec!(50));
-        assert_eq!(acc.held_funds(), dec!(50));
-        assert_eq!(acc.total_funds, dec!(100));
+        acc.active_dispute_total = money!(50);
+        assert_eq!(acc.available_funds(), money!(50));
+        assert_eq!(acc.held_funds(), money!(50));
+        assert_eq!(acc.total_funds, money!(100));
 
-        acc.active_dispute_total = dec!(100);
-        assert_eq!(acc.available_funds(), dec!(0));
-        assert_eq!(acc.held_funds(), dec!(100));
-        assert_eq!(acc.total_funds, dec!(100));
+        acc.active_dispute_total = money!(100);
+        assert_eq!(acc.available_funds(), money!(0));
+        assert_eq!(acc.held_funds(), money!(100));
+        assert_eq!(acc.total_funds, money!(100));
 
         // Start another dispute pushing the total disputed funds
         // past what's available in total_funds.
         // We should still get sensible values in "available" and "held"
         // compared to the total available funds (i.e. available >= 0
         // and held <= total).
-        acc.active_dispute_total = dec!(125);
-        assert_eq!(acc.available_funds(), dec!(0));
-        assert_eq!(acc.held_funds(), dec!(100));
-        assert_eq!(acc.total_funds, dec!(100));
+        acc.active_dispute_total = money!(125);
+        assert_eq!(acc.available_funds(), money!(0));
+        assert_eq!(acc.held_funds(), money!(100));
+        assert_eq!(acc.total_funds, money!(100));
 
         // Resolve a dispute, bringing the disputed funds back below the
         // total available. Ensure we didn't spontaneously gain some available
         // funds due to the ceiling imposed by total_funds.
-        acc.free_disputed_amount(&dec!(50));
-        assert_eq!(acc.available_funds(), dec!(25));
-        assert_eq!(acc.held_funds(), dec!(75));
-        assert_eq!(acc.total_funds, dec!(100));
+        acc.free_disputed_amount(&money!(50));
+        assert_eq!(acc.available_funds(), money!(25));
+        assert_eq!(acc.held_funds(), money!(75));
+        assert_eq!(acc.total_funds, money!(100));
     }
 
     #[test]
     fn prevent_negative_dispute_total() {
         // Ensure we never "free" more disputed funds than we're aware of.
         let mut acc = Account::new(1);
-        acc.total_funds = dec!(100);
-        acc.active_dispute_total = dec!(50);
-        assert_eq!(acc.available_funds(), dec!(50));
-        assert_eq!(acc.held_funds(), dec!(50));
+        acc.total_funds = money!(100);
+        acc.active_dispute_total = money!(50);
+        assert_eq!(acc.available_funds(), money!(50));
+        assert_eq!(acc.held_funds(), money!(50));
 
         // Free most of what's currently disputed
-        assert!(!acc.free_disputed_amount(&dec!(45)));
-        assert_eq!(acc.available_funds(), dec!(95));
-        assert_eq!(acc.held_funds(), dec!(5));
-        assert_eq!(acc.total_funds, dec!(100));
+        assert!(!acc.free_disputed_amount(&money!(45)));
+        assert_eq!(acc.available_funds(), money!(95));
+        assert_eq!(acc.held_funds(), money!(5));
+        assert_eq!(acc.total_funds, money!(100));
 
         // Then go over - shouldn't happen unless we've miscalculated elsewhere
         // or are trying to free/chargeback an incorrect/missed transaction.
         // Check we notice (boolean true response to free_disputed_amount)
         // and don't magically gain some more available funds.
-        assert!(acc.free_disputed_amount(&dec!(10)));
-        assert_eq!(acc.available_funds(), dec!(100));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert_eq!(acc.total_funds, dec!(100));
+        assert!(acc.free_disputed_amount(&money!(10)));
+        assert_eq!(acc.available_funds(), money!(100));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert_eq!(acc.total_funds, money!(100));
     }
 
     #[test]
@@ -234,13 +2414,29 @@ mod test {
         fn tx_rec(initial: DisputeStatus) -> DepositRecord {
             DepositRecord {
                 dispute_status: initial,
-                amount: dec!(100),
+                ..DepositRecord::new(money!(100), 1)
             }
         }
-        assert!(tx_rec(DisputeStatus::NotDisputed).disputed().is_ok());
-        assert!(tx_rec(DisputeStatus::Disputed).disputed().is_err());
-        assert!(tx_rec(DisputeStatus::Resolved).disputed().is_ok());
-        assert!(tx_rec(DisputeStatus::Refunded).disputed().is_err());
+        assert!(tx_rec(DisputeStatus::NotDisputed)
+            .dispute_status
+            .disputed()
+            .is_ok());
+        assert!(tx_rec(DisputeStatus::Disputed)
+            .dispute_status
+            .disputed()
+            .is_err());
+        assert!(tx_rec(DisputeStatus::Resolved)
+            .dispute_status
+            .disputed()
+            .is_ok());
+        assert!(tx_rec(DisputeStatus::Refunded)
+            .dispute_status
+            .disputed()
+            .is_err());
+        assert!(tx_rec(DisputeStatus::PartiallyRefunded)
+            .dispute_status
+            .disputed()
+            .is_ok());
 
         assert!(tx_rec(DisputeStatus::NotDisputed).resolved().is_err());
         assert!(tx_rec(DisputeStatus::Disputed).resolved().is_ok());
@@ -251,5 +2447,6 @@ mod test {
         assert!(tx_rec(DisputeStatus::Disputed).refunded().is_ok());
         assert!(tx_rec(DisputeStatus::Resolved).refunded().is_err());
         assert!(tx_rec(DisputeStatus::Refunded).refunded().is_err());
+        assert!(tx_rec(DisputeStatus::PartiallyRefunded).refunded().is_err());
     }
 }
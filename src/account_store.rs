@@ -1,4 +1,6 @@
-use crate::account::{Account, AccountStatement};
+use crate::account::{serialize_sorted, Account, AccountStatement};
+use crate::tx_index::TxIndex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::HashMap;
 
 /// Trait for accessing Account and transaction state from a state store.
@@ -12,46 +14,358 @@ pub trait AccountStore {
     /// created and a mutable reference returned.
     fn get_account_mut(&mut self, client_id: u16) -> &mut Account;
 
-    /// Generate account statements for all contained accounts.
+    /// Iterates over all contained accounts.
+    fn accounts(&self) -> impl Iterator<Item = &Account>;
+
+    /// Iterates over the accounts with client IDs after `after` (from the
+    /// start if `None`), in ascending client order, so large stores can be
+    /// paged through without visiting every account.
+    ///
+    /// Client IDs are 16 bit, so this looks each one up in turn by default.
+    fn accounts_after(&self, after: Option<u16>) -> impl Iterator<Item = &Account> {
+        let start = after.map_or(Some(0), |after| after.checked_add(1));
+        start
+            .into_iter()
+            .flat_map(|start| start..=u16::MAX)
+            .filter_map(|client_id| self.get_account(client_id))
+    }
+
+    /// Generate account statements for all contained accounts, one per
+    /// balance (see [`Account::statements`]).
     fn account_statements(&self) -> impl Iterator<Item = AccountStatement>;
+
+    /// The client whose account transaction ID `tx` was taken by, with a
+    /// deposit, withdrawal or conversion, if any. Transaction IDs are
+    /// globally unique, so a taken ID can't be reused, even by another
+    /// client.
+    ///
+    /// Looks through every account by default. Stores holding many accounts
+    /// should keep an index instead (see [`TxIndex`]), updated by
+    /// [`AccountStore::take_tx`].
+    fn tx_owner(&self, tx: u32) -> Option<u16> {
+        self.accounts()
+            .find(|account| account.balance_with(tx).is_some() || account.conversion(tx).is_some())
+            .map(Account::client)
+    }
+
+    /// Records that transaction ID `tx` was taken by a deposit, withdrawal
+    /// or conversion just applied to `client`'s account. Stores looking
+    /// through their accounts have nothing to record.
+    fn take_tx(&mut self, _tx: u32, _client: u16) {}
+
+    /// Loads the clients' accounts into memory ahead of their transactions,
+    /// e.g. those expected to be busy when a server starts, most important
+    /// first. Returns the number loaded.
+    ///
+    /// Stores holding every account in memory have nothing to load.
+    fn preload(&mut self, _clients: &[u16]) -> usize {
+        0
+    }
+}
+
+/// How an [`InMemoryStore`] lays out its accounts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StoreLayout {
+    /// A hash map from client ID to account, suiting any client IDs.
+    #[default]
+    Hashed,
+    /// Accounts in a dense vector, found through a table of 4 bytes per
+    /// client ID up to the highest seen, so at most 256KiB. Lookups skip
+    /// hashing and iterating (e.g. for statements) walks contiguous
+    /// accounts, so it's faster where client IDs are small or the store
+    /// holds many accounts.
+    Dense,
+}
+
+impl std::str::FromStr for StoreLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hashed" => Ok(StoreLayout::Hashed),
+            "dense" => Ok(StoreLayout::Dense),
+            _ => Err(format!("Unknown store layout {:?}", s)),
+        }
+    }
 }
 
 /// In-memory implementation of the [`AccountStore`] trait.
+///
+/// Serialized as its accounts alone, in client order whatever its layout;
+/// the index of taken transaction IDs is rebuilt from them when
+/// deserialized, into the hashed layout.
+#[derive(Default)]
 pub struct InMemoryStore {
-    data: HashMap<u16, Account>,
+    data: Accounts,
+    tx_ids: TxIndex,
+}
+
+impl Serialize for InMemoryStore {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.data {
+            Accounts::Hashed(data) => serialize_sorted(data, serializer),
+            Accounts::Dense(data) => serializer.collect_map(
+                data.accounts_after(None)
+                    .map(|account| (account.client(), account)),
+            ),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for InMemoryStore {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = HashMap::<u16, Account>::deserialize(deserializer)?;
+        let tx_ids = data.values().flat_map(taken_ids).collect();
+        Ok(Self {
+            data: Accounts::Hashed(data),
+            tx_ids,
+        })
+    }
 }
 
 impl InMemoryStore {
     /// Returns a new empty instance of [`InMemoryStore`]
     pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a new empty instance with the given layout.
+    pub fn with_layout(layout: StoreLayout) -> Self {
+        let data = match layout {
+            StoreLayout::Hashed => Accounts::Hashed(HashMap::new()),
+            StoreLayout::Dense => Accounts::Dense(DenseAccounts::default()),
+        };
         Self {
-            data: HashMap::new(),
+            data,
+            tx_ids: TxIndex::new(),
+        }
+    }
+
+    /// The store's layout.
+    pub fn layout(&self) -> StoreLayout {
+        match self.data {
+            Accounts::Hashed(_) => StoreLayout::Hashed,
+            Accounts::Dense(_) => StoreLayout::Dense,
         }
     }
 
     #[cfg(test)]
     /// Returns a new instance of [`InMemoryStore`] with the provided accounts.
     pub fn new_with_data(accounts: Vec<Account>) -> Self {
-        let data = accounts
-            .into_iter()
-            .map(|acc| (acc.client, acc))
-            .collect::<HashMap<u16, Account>>();
-        Self { data }
+        let mut store = Self::new();
+        accounts.into_iter().for_each(|acc| store.insert(acc));
+        store
+    }
+
+    /// Adds an account, replacing any for the same client.
+    pub(crate) fn insert(&mut self, account: Account) {
+        self.tx_ids.extend(taken_ids(&account));
+        self.data.insert(account);
+    }
+
+    /// As [`InMemoryStore::insert`], for an account lent to the store for a
+    /// single transaction (see [`crate::async_store`]). Its transaction IDs
+    /// aren't indexed, as that would take time in proportion to its
+    /// records; only the IDs taken while it's in the store are.
+    pub(crate) fn lend(&mut self, account: Account) {
+        self.data.insert(account);
+    }
+
+    /// Takes the client's account out of the store.
+    pub(crate) fn remove(&mut self, client_id: u16) -> Option<Account> {
+        match &mut self.data {
+            Accounts::Hashed(data) => data.remove(&client_id),
+            Accounts::Dense(data) => data.remove(client_id),
+        }
     }
 }
 
 impl AccountStore for InMemoryStore {
     fn get_account(&self, client_id: u16) -> Option<&Account> {
-        self.data.get(&client_id)
+        match &self.data {
+            Accounts::Hashed(data) => data.get(&client_id),
+            Accounts::Dense(data) => data.get(client_id),
+        }
     }
 
     fn get_account_mut(&mut self, client_id: u16) -> &mut Account {
-        self.data
-            .entry(client_id)
-            .or_insert_with(|| Account::new(client_id))
+        match &mut self.data {
+            Accounts::Hashed(data) => data
+                .entry(client_id)
+                .or_insert_with(|| Account::new(client_id)),
+            Accounts::Dense(data) => data.get_or_create(client_id),
+        }
+    }
+
+    fn accounts(&self) -> impl Iterator<Item = &Account> {
+        // One of the two is empty. Chained rather than boxed, so iterating
+        // isn't dispatched dynamically per account.
+        let (hashed, dense) = match &self.data {
+            Accounts::Hashed(data) => (Some(data.values()), None),
+            Accounts::Dense(data) => (None, Some(data.accounts.iter())),
+        };
+        hashed
+            .into_iter()
+            .flatten()
+            .chain(dense.into_iter().flatten())
+    }
+
+    fn accounts_after(&self, after: Option<u16>) -> impl Iterator<Item = &Account> {
+        let (hashed, dense) = match &self.data {
+            Accounts::Hashed(_) => {
+                let start = after.map_or(Some(0), |after| after.checked_add(1));
+                let accounts = start
+                    .into_iter()
+                    .flat_map(|start| start..=u16::MAX)
+                    .filter_map(|client_id| self.get_account(client_id));
+                (Some(accounts), None)
+            }
+            Accounts::Dense(data) => (None, Some(data.accounts_after(after))),
+        };
+        hashed
+            .into_iter()
+            .flatten()
+            .chain(dense.into_iter().flatten())
     }
 
     fn account_statements(&self) -> impl Iterator<Item = AccountStatement> {
-        self.data.values().map(|account| account.into())
+        self.accounts().flat_map(Account::statements)
+    }
+
+    fn tx_owner(&self, tx: u32) -> Option<u16> {
+        self.tx_ids.owner(tx)
+    }
+
+    fn take_tx(&mut self, tx: u32, client: u16) {
+        self.tx_ids.insert(tx, client);
+    }
+}
+
+/// An [`InMemoryStore`]'s accounts, in its layout.
+enum Accounts {
+    Hashed(HashMap<u16, Account>),
+    Dense(DenseAccounts),
+}
+
+impl Default for Accounts {
+    fn default() -> Self {
+        Accounts::Hashed(HashMap::new())
+    }
+}
+
+impl Accounts {
+    fn insert(&mut self, account: Account) {
+        match self {
+            Accounts::Hashed(data) => {
+                data.insert(account.client(), account);
+            }
+            Accounts::Dense(data) => {
+                let client_id = account.client();
+                *data.get_or_create(client_id) = account;
+            }
+        }
+    }
+}
+
+/// Accounts for [`StoreLayout::Dense`].
+#[derive(Default)]
+struct DenseAccounts {
+    /// One more than the position in `accounts` of each client's account,
+    /// or 0 if it has none, indexed by client ID up to the highest seen.
+    slots: Vec<u32>,
+    /// In the order they were created, bar removals.
+    accounts: Vec<Account>,
+}
+
+impl DenseAccounts {
+    fn position(&self, client_id: u16) -> Option<usize> {
+        match self.slots.get(usize::from(client_id)) {
+            Some(&slot) if slot != 0 => Some(slot as usize - 1),
+            _ => None,
+        }
+    }
+
+    fn get(&self, client_id: u16) -> Option<&Account> {
+        self.position(client_id)
+            .map(|position| &self.accounts[position])
+    }
+
+    fn get_or_create(&mut self, client_id: u16) -> &mut Account {
+        let position = match self.position(client_id) {
+            Some(position) => position,
+            None => {
+                let index = usize::from(client_id);
+                if self.slots.len() <= index {
+                    self.slots.resize(index + 1, 0);
+                }
+                self.accounts.push(Account::new(client_id));
+                self.slots[index] = self.accounts.len() as u32;
+                self.accounts.len() - 1
+            }
+        };
+        &mut self.accounts[position]
+    }
+
+    fn remove(&mut self, client_id: u16) -> Option<Account> {
+        let position = self.position(client_id)?;
+        self.slots[usize::from(client_id)] = 0;
+        let account = self.accounts.swap_remove(position);
+        // The last account moved into the removed one's place.
+        if let Some(moved) = self.accounts.get(position) {
+            self.slots[usize::from(moved.client())] = position as u32 + 1;
+        }
+        Some(account)
+    }
+
+    /// Accounts with client IDs after `after`, in client order, found by
+    /// walking the slots rather than looking up every client ID.
+    fn accounts_after(&self, after: Option<u16>) -> impl Iterator<Item = &Account> {
+        let start = after.map_or(0, |after| usize::from(after) + 1);
+        self.slots
+            .iter()
+            .skip(start)
+            .filter(|&&slot| slot != 0)
+            .map(|&slot| &self.accounts[slot as usize - 1])
+    }
+}
+
+/// The transaction IDs `account` has taken, each with its client, for a
+/// [`TxIndex`].
+pub(crate) fn taken_ids(account: &Account) -> impl Iterator<Item = (u32, u16)> + '_ {
+    let client = account.client();
+    account.transaction_ids().map(move |tx| (tx, client))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dense_layout() {
+        let mut store = InMemoryStore::with_layout(StoreLayout::Dense);
+        for client_id in [9, 2, 40_000, 5] {
+            store.get_account_mut(client_id);
+        }
+        let clients = |store: &InMemoryStore, after| -> Vec<u16> {
+            store.accounts_after(after).map(Account::client).collect()
+        };
+        assert_eq!(clients(&store, None), [2, 5, 9, 40_000]);
+        assert_eq!(clients(&store, Some(5)), [9, 40_000]);
+        assert!(clients(&store, Some(u16::MAX)).is_empty());
+
+        // The last account moves into the removed one's place.
+        assert_eq!(store.remove(9).map(|account| account.client()), Some(9));
+        assert_eq!(store.remove(9).map(|account| account.client()), None);
+        assert_eq!(store.get_account(5).map(Account::client), Some(5));
+        assert_eq!(clients(&store, None), [2, 5, 40_000]);
+
+        let mut hashed = InMemoryStore::new();
+        for client_id in [40_000, 5, 2] {
+            hashed.get_account_mut(client_id);
+        }
+        assert_eq!(
+            serde_json::to_string(&store).unwrap(),
+            serde_json::to_string(&hashed).unwrap()
+        );
     }
 }
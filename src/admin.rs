@@ -0,0 +1,233 @@
+//! Client for a running service's admin API (see [`crate::server`]), as used
+//! by the `admin` command.
+//!
+//! Calls are plain HTTP/1.1 over a fresh connection each, closed once the
+//! response is read, so no HTTP client library is needed. Responses are
+//! returned as their JSON bodies; a response with an error status is
+//! returned as an [`AdminError`] with the service's error body.
+
+use crate::account::LockScope;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long to wait for the service to connect or respond.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct AdminClient {
+    /// Host and port, as in the request's `Host` header.
+    host: String,
+}
+
+/// A response with an error status.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminError {
+    pub status: u16,
+    pub body: String,
+}
+
+impl fmt::Display for AdminError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Admin call failed with status {}: {}",
+            self.status, self.body
+        )
+    }
+}
+
+impl Error for AdminError {}
+
+impl AdminClient {
+    /// A client for the admin API at `endpoint`, e.g.
+    /// `http://127.0.0.1:9090`. The scheme may be left out; only `http` is
+    /// supported.
+    pub fn new(endpoint: &str) -> Result<Self, String> {
+        let host = match endpoint.split_once("://") {
+            Some(("http", host)) => host,
+            Some((scheme, _)) => return Err(format!("Unsupported admin scheme {:?}", scheme)),
+            None => endpoint,
+        };
+        let host = host.trim_end_matches('/');
+        if host.is_empty() || host.contains('/') {
+            return Err(format!("Invalid admin endpoint {:?}", endpoint));
+        }
+        Ok(Self {
+            host: host.to_owned(),
+        })
+    }
+
+    pub fn lock(&self, client: u16, scope: LockScope) -> Result<String, Box<dyn Error>> {
+        let body = serde_json::json!({ "scope": scope }).to_string();
+        let path = format!("/admin/accounts/{}/lock", client);
+        self.call("POST", &path, Some(&body))
+    }
+
+    pub fn unlock(&self, client: u16, operator: &str) -> Result<String, Box<dyn Error>> {
+        let body = serde_json::json!({ "operator": operator }).to_string();
+        let path = format!("/admin/accounts/{}/unlock", client);
+        self.call("POST", &path, Some(&body))
+    }
+
+    pub fn statement(&self, client: u16) -> Result<String, Box<dyn Error>> {
+        self.call("GET", &format!("/admin/accounts/{}", client), None)
+    }
+
+    pub fn snapshot(&self) -> Result<String, Box<dyn Error>> {
+        self.call("POST", "/admin/snapshot", None)
+    }
+
+    pub fn flush_dlq(&self) -> Result<String, Box<dyn Error>> {
+        self.call("POST", "/admin/dlq/flush", None)
+    }
+
+    pub fn reload_config(&self) -> Result<String, Box<dyn Error>> {
+        self.call("POST", "/admin/config/reload", None)
+    }
+
+    fn call(&self, method: &str, path: &str, body: Option<&str>) -> Result<String, Box<dyn Error>> {
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let body = body.unwrap_or_default();
+        // Written in one go, so the request isn't split over several
+        // packets.
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            self.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = vec![];
+        stream.read_to_end(&mut response)?;
+        let (status, body) = parse_response(&response)?;
+        if !(200..300).contains(&status) {
+            return Err(AdminError { status, body }.into());
+        }
+        Ok(body)
+    }
+}
+
+/// The status and body of a whole HTTP/1.1 response.
+fn parse_response(response: &[u8]) -> Result<(u16, String), Box<dyn Error>> {
+    let response = std::str::from_utf8(response)?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("Incomplete response from the service")?;
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .ok_or("Malformed status line from the service")?;
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding")
+                && value.trim().eq_ignore_ascii_case("chunked")
+        })
+    });
+    let body = if chunked {
+        dechunk(body)?
+    } else {
+        body.to_owned()
+    };
+    Ok((status, body))
+}
+
+/// Decodes a body sent with chunked transfer encoding.
+fn dechunk(mut body: &str) -> Result<String, Box<dyn Error>> {
+    let mut decoded = String::new();
+    loop {
+        let (size, rest) = body.split_once("\r\n").ok_or("Truncated chunk")?;
+        // Chunk extensions, after a `;`, are ignored.
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)?;
+        if size == 0 {
+            return Ok(decoded);
+        }
+        let chunk = rest.get(..size).ok_or("Truncated chunk")?;
+        decoded.push_str(chunk);
+        body = rest[size..].strip_prefix("\r\n").ok_or("Malformed chunk")?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serves one connection with `response`, returning the request.
+    fn serve_once(response: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request = String::new();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if let Some(value) = line.strip_prefix("Content-Length: ") {
+                    length = value.trim().parse().unwrap();
+                }
+                request.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            request.push_str(std::str::from_utf8(&body).unwrap());
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            request
+        });
+        (endpoint, server)
+    }
+
+    #[test]
+    fn calls_admin_api() {
+        let (endpoint, server) = serve_once(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\n\
+             transfer-encoding: chunked\r\n\r\n5\r\n{\"a\":\r\n2\r\n1}\r\n0\r\n\r\n",
+        );
+        let client = AdminClient::new(&endpoint).unwrap();
+        assert_eq!(
+            client.lock(7, LockScope::BlockDebits).unwrap(),
+            r#"{"a":1}"#
+        );
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /admin/accounts/7/lock HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\n{\"scope\":\"block-debits\"}"));
+
+        let (endpoint, server) = serve_once(
+            "HTTP/1.1 404 Not Found\r\ncontent-length: 26\r\n\r\n{\"code\":\"AccountNotFound\"}",
+        );
+        let err = AdminClient::new(&endpoint)
+            .unwrap()
+            .statement(9)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AdminError>(),
+            Some(&AdminError {
+                status: 404,
+                body: r#"{"code":"AccountNotFound"}"#.to_owned(),
+            })
+        );
+        assert!(server
+            .join()
+            .unwrap()
+            .starts_with("GET /admin/accounts/9 HTTP/1.1\r\n"));
+
+        assert!(AdminClient::new("https://example.com").is_err());
+        assert!(AdminClient::new("localhost:9090/admin").is_err());
+    }
+}
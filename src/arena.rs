@@ -0,0 +1,133 @@
+//! A pool of the storage accounts keep deposit records in, so big runs
+//! don't churn the allocator.
+//!
+//! An account's records are kept in chunks of [`CHUNK_RECORDS`]. Only the
+//! first chunk grows by reallocating; later ones are taken from the pool at
+//! full size, so an account's records are never moved once it has many.
+//! When an account is dropped, e.g. as a [`crate::tiered_store::TieredStore`]
+//! pages it out, its full chunks and its index are returned to the pool, for
+//! the next account loaded or grown to reuse.
+//!
+//! The pool is per thread, and bounded. [`reclaim`] frees it, e.g. once a
+//! store has been compacted.
+
+use crate::account::DepositRecord;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Records per chunk.
+pub(crate) const CHUNK_RECORDS: usize = 256;
+
+/// Most chunks pooled per thread, around 10 MB of records.
+const MAX_POOLED_CHUNKS: usize = 1024;
+
+/// Most indexes pooled per thread.
+const MAX_POOLED_INDEXES: usize = 1024;
+
+#[derive(Default)]
+struct Pool {
+    /// Empty chunks, each with room for [`CHUNK_RECORDS`] records.
+    chunks: Vec<Vec<DepositRecord>>,
+    /// Empty indexes, with whatever room they had.
+    indexes: Vec<HashMap<u32, u32>>,
+}
+
+thread_local! {
+    static POOL: RefCell<Pool> = RefCell::default();
+}
+
+/// An empty chunk with room for [`CHUNK_RECORDS`] records.
+pub(crate) fn take_chunk() -> Vec<DepositRecord> {
+    POOL.with_borrow_mut(|pool| pool.chunks.pop())
+        .unwrap_or_else(|| Vec::with_capacity(CHUNK_RECORDS))
+}
+
+/// An empty index, with room for some records if one was pooled.
+pub(crate) fn take_index() -> HashMap<u32, u32> {
+    POOL.with_borrow_mut(|pool| pool.indexes.pop())
+        .unwrap_or_default()
+}
+
+/// Returns an account's storage to the pool. Chunks that were never full
+/// size are freed instead, as is everything once the pool is full or the
+/// thread is exiting.
+pub(crate) fn give_back(chunks: Vec<Vec<DepositRecord>>, mut index: HashMap<u32, u32>) {
+    if chunks.is_empty() && index.capacity() == 0 {
+        return;
+    }
+    let _ = POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        for mut chunk in chunks {
+            if chunk.capacity() == CHUNK_RECORDS && pool.chunks.len() < MAX_POOLED_CHUNKS {
+                chunk.clear();
+                pool.chunks.push(chunk);
+            }
+        }
+        if index.capacity() > 0 && pool.indexes.len() < MAX_POOLED_INDEXES {
+            index.clear();
+            pool.indexes.push(index);
+        }
+    });
+}
+
+/// Frees the storage pooled on this thread.
+pub fn reclaim() {
+    POOL.take();
+}
+
+/// Chunks and indexes pooled on this thread.
+#[cfg(test)]
+fn pooled() -> (usize, usize) {
+    POOL.with_borrow(|pool| (pool.chunks.len(), pool.indexes.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::Account;
+    use crate::money::Money;
+    use crate::system_accounts::SystemAccounts;
+
+    fn account(client: u16, deposits: u32) -> Account {
+        let mut account = Account::new(client);
+        let mut system = SystemAccounts::default();
+        for tx in 0..deposits {
+            account
+                .credit(tx, &Money::from(1), &Money::zero(), &mut system, || {
+                    u64::from(tx) + 1
+                })
+                .unwrap();
+        }
+        account
+    }
+
+    #[test]
+    fn storage_reused_then_reclaimed() {
+        reclaim();
+        let deposits = (CHUNK_RECORDS * 3) as u32;
+        drop(account(1, deposits));
+        // The first chunk grew to full size, so is pooled too.
+        assert_eq!(pooled(), (3, 1));
+
+        let account = account(2, deposits + 1);
+        assert_eq!(pooled(), (0, 0));
+        let history = account.transaction_history();
+        assert_eq!(history.len(), deposits as usize + 1);
+        assert!(history
+            .iter()
+            .enumerate()
+            .all(|(position, (tx, _))| *tx == position as u32));
+        assert_eq!(
+            account.transaction(deposits).unwrap().sequence,
+            u64::from(deposits) + 1
+        );
+
+        // Small accounts reuse indexes, but their chunks are freed, not
+        // pooled.
+        drop(account);
+        drop(self::account(3, 2));
+        assert_eq!(pooled(), (4, 1));
+        reclaim();
+        assert_eq!(pooled(), (0, 0));
+    }
+}
@@ -0,0 +1,177 @@
+//! Runs the engine inside a long-running async service, applying
+//! transactions as they arrive on a Tokio channel. Requires the `tokio`
+//! feature.
+//!
+//! ```ignore
+//! let (sender, receiver) = tokio::sync::mpsc::channel(1024);
+//! let mut runner = EngineRunner::new(TxEngine::new(InMemoryStore::new()), receiver);
+//! tokio::select! {
+//!     _ = runner.run() => {}
+//!     _ = shutdown_signal => {}
+//! }
+//! let flushed = runner.shutdown().await;
+//! ```
+
+use crate::account::AccountStatement;
+use crate::account_store::AccountStore;
+use crate::event::EngineEvent;
+use crate::transaction::Transaction;
+use crate::transaction_engine::TxEngine;
+use std::collections::BTreeMap;
+use tokio::sync::mpsc::Receiver;
+
+/// Applies transactions received on a channel to an engine, until the
+/// channel closes or [`shutdown`](Self::shutdown) is called.
+///
+/// The engine is set up (plugins, quarantined clients, etc.) before it's
+/// handed over. Transactions are applied in the order they're received.
+/// Callers that need each transaction's result should use a
+/// [`crate::shared_engine::SharedTxEngine`] instead; the runner only counts
+/// outcomes.
+pub struct EngineRunner<T> {
+    engine: TxEngine<T>,
+    receiver: Receiver<Transaction>,
+    outcomes: RunnerOutcomes,
+}
+
+/// Counts of the transactions a runner handled.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunnerOutcomes {
+    pub applied: u64,
+    /// Transactions not applied, by
+    /// [`TransactionNotApplied::name`](crate::TransactionNotApplied::name).
+    pub not_applied: BTreeMap<&'static str, u64>,
+}
+
+/// What's left once a runner has shut down.
+pub struct Flushed<T> {
+    /// Statements for all accounts, ordered by client.
+    pub statements: Vec<AccountStatement>,
+    /// Events raised since they were last drained.
+    pub events: Vec<EngineEvent>,
+    pub outcomes: RunnerOutcomes,
+    /// The engine, e.g. to snapshot its final state.
+    pub engine: TxEngine<T>,
+}
+
+impl<T: AccountStore> EngineRunner<T> {
+    pub fn new(engine: TxEngine<T>, receiver: Receiver<Transaction>) -> Self {
+        Self {
+            engine,
+            receiver,
+            outcomes: RunnerOutcomes::default(),
+        }
+    }
+
+    /// Applies transactions as they arrive, returning once every sender has
+    /// been dropped.
+    ///
+    /// Cancel safe: no transaction is lost if this is dropped while waiting,
+    /// e.g. when selected against a shutdown signal.
+    pub async fn run(&mut self) {
+        while let Some(transaction) = self.receiver.recv().await {
+            self.apply(&transaction);
+        }
+    }
+
+    /// Stops accepting transactions, applies those already queued, and
+    /// returns the final statements.
+    pub async fn shutdown(mut self) -> Flushed<T> {
+        self.receiver.close();
+        while let Some(transaction) = self.receiver.recv().await {
+            self.apply(&transaction);
+        }
+        let mut statements: Vec<AccountStatement> =
+            self.engine.store().account_statements().collect();
+        statements.sort_by_key(AccountStatement::client);
+        Flushed {
+            statements,
+            events: self.engine.drain_events().collect(),
+            outcomes: self.outcomes,
+            engine: self.engine,
+        }
+    }
+
+    /// As [`TxEngine::drain_events`], e.g. to publish them while running.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = EngineEvent> + '_ {
+        self.engine.drain_events()
+    }
+
+    pub fn outcomes(&self) -> &RunnerOutcomes {
+        &self.outcomes
+    }
+
+    pub fn engine(&self) -> &TxEngine<T> {
+        &self.engine
+    }
+
+    fn apply(&mut self, transaction: &Transaction) {
+        match self.engine.handle(transaction) {
+            Ok(_) => self.outcomes.applied += 1,
+            Err(err) => *self.outcomes.not_applied.entry(err.name()).or_default() += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+    use crate::transaction::TransactionInfo;
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    fn poll<F: Future>(future: F) -> Poll<F::Output> {
+        pin!(future).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn transaction(client_id: u16, transaction_id: u32, info: TransactionInfo) -> Transaction {
+        Transaction {
+            client_id,
+            transaction_id,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn runs_until_shutdown() {
+        let (sender, receiver) = tokio::sync::mpsc::channel(8);
+        let mut runner = EngineRunner::new(TxEngine::new(InMemoryStore::new()), receiver);
+        sender
+            .try_send(transaction(1, 1, TransactionInfo::Deposit(money!(10))))
+            .unwrap();
+        sender
+            .try_send(transaction(1, 2, TransactionInfo::Withdrawal(money!(20))))
+            .unwrap();
+        // Waits for more, having applied what's queued.
+        assert!(poll(runner.run()).is_pending());
+        assert_eq!(runner.outcomes().applied, 1);
+
+        sender
+            .try_send(transaction(2, 3, TransactionInfo::Deposit(money!(5))))
+            .unwrap();
+        let Poll::Ready(flushed) = poll(runner.shutdown()) else {
+            panic!("Shutdown waited");
+        };
+        assert!(sender
+            .try_send(transaction(2, 4, TransactionInfo::Deposit(money!(1))))
+            .is_err());
+        assert_eq!(flushed.outcomes.applied, 2);
+        assert_eq!(
+            flushed.outcomes.not_applied,
+            BTreeMap::from([("InsufficientFunds", 1)])
+        );
+        let totals: Vec<_> = flushed
+            .statements
+            .iter()
+            .map(|statement| (statement.client(), statement.total().clone()))
+            .collect();
+        assert_eq!(totals, [(1, money!(10)), (2, money!(5))]);
+    }
+}
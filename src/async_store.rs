@@ -0,0 +1,268 @@
+//! Async account stores, for running the engine as a service against state
+//! shared between instances (e.g. [`crate::postgres_store::PostgresStore`]).
+//!
+//! Unlike an [`AccountStore`](crate::AccountStore), an async store doesn't
+//! hand out references to accounts it holds. It lends out one account at a
+//! time, for as long as it takes to apply a transaction, and keeps others
+//! (in other instances) from changing the account in the meantime.
+//!
+//! Stores don't depend on a particular async runtime.
+//!
+//! Only accounts are shared. Each [`AsyncTxEngine`] numbers the transactions
+//! it applies, and keeps its own system accounts, quarantine queues and
+//! withdrawal destination totals.
+//!
+//! Stores with an outbox (see [`crate::outbox`]) save the events raised with
+//! the accounts, to be published reliably.
+
+use crate::account::{Account, AccountStatement};
+use crate::account_store::InMemoryStore;
+use crate::event::EngineEvent;
+use crate::outbox::{OutboxEntry, OutboxStore};
+use crate::plugin::TransactionPlugin;
+use crate::transaction::Transaction;
+use crate::transaction_engine::{EngineConfig, TransactionNotApplied, TxEngine};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::future::Future;
+use std::sync::Mutex;
+
+/// An error from an async store, e.g. a lost database connection.
+pub type StoreError = Box<dyn Error + Send + Sync>;
+
+pub trait AsyncAccountStore: Send + Sync {
+    /// Calls `f` with the client's account, creating it if there isn't one,
+    /// and saves the account as `f` leaves it. No other update to the same
+    /// account may happen in between.
+    fn update<R: Send>(
+        &self,
+        client_id: u16,
+        f: impl FnOnce(&mut Account) -> R + Send,
+    ) -> impl Future<Output = Result<R, StoreError>> + Send;
+
+    /// Generates statements for all accounts, ordered by client.
+    fn account_statements(
+        &self,
+    ) -> impl Future<Output = Result<Vec<AccountStatement>, StoreError>> + Send;
+}
+
+/// An in-memory [`AsyncAccountStore`], e.g. for tests, or a single instance.
+#[derive(Default)]
+pub struct InMemoryAsyncStore {
+    data: Mutex<HashMap<u16, Account>>,
+    /// Entries not yet published, and the last ID given out.
+    outbox: Mutex<(VecDeque<OutboxEntry>, u64)>,
+}
+
+impl InMemoryAsyncStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AsyncAccountStore for InMemoryAsyncStore {
+    fn update<R: Send>(
+        &self,
+        client_id: u16,
+        f: impl FnOnce(&mut Account) -> R + Send,
+    ) -> impl Future<Output = Result<R, StoreError>> + Send {
+        let mut data = self.data.lock().expect("Store lock poisoned");
+        let account = data
+            .entry(client_id)
+            .or_insert_with(|| Account::new(client_id));
+        std::future::ready(Ok(f(account)))
+    }
+
+    fn account_statements(
+        &self,
+    ) -> impl Future<Output = Result<Vec<AccountStatement>, StoreError>> + Send {
+        let data = self.data.lock().expect("Store lock poisoned");
+        let mut statements: Vec<AccountStatement> =
+            data.values().flat_map(Account::statements).collect();
+        statements.sort_by_key(AccountStatement::client);
+        std::future::ready(Ok(statements))
+    }
+}
+
+impl OutboxStore for InMemoryAsyncStore {
+    fn update_with_events<R: Send>(
+        &self,
+        client_id: u16,
+        f: impl FnOnce(&mut Account) -> (R, Vec<EngineEvent>) + Send,
+    ) -> impl Future<Output = Result<R, StoreError>> + Send {
+        let mut data = self.data.lock().expect("Store lock poisoned");
+        let account = data
+            .entry(client_id)
+            .or_insert_with(|| Account::new(client_id));
+        let (result, events) = f(account);
+        // Added before the account is released, as if in one commit.
+        let mut outbox = self.outbox.lock().expect("Outbox lock poisoned");
+        let (entries, last_id) = &mut *outbox;
+        for event in events {
+            *last_id += 1;
+            entries.push_back(OutboxEntry {
+                id: *last_id,
+                event,
+            });
+        }
+        std::future::ready(Ok(result))
+    }
+
+    fn pending(
+        &self,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<OutboxEntry>, StoreError>> + Send {
+        let outbox = self.outbox.lock().expect("Outbox lock poisoned");
+        std::future::ready(Ok(outbox.0.iter().take(limit).cloned().collect()))
+    }
+
+    fn published(&self, ids: &[u64]) -> impl Future<Output = Result<(), StoreError>> + Send {
+        let mut outbox = self.outbox.lock().expect("Outbox lock poisoned");
+        outbox.0.retain(|entry| !ids.contains(&entry.id));
+        std::future::ready(Ok(()))
+    }
+}
+
+/// A [`TxEngine`] over an [`AsyncAccountStore`].
+pub struct AsyncTxEngine<S> {
+    store: S,
+    /// Applies transactions to the account lent out by the store, so holds
+    /// no accounts of its own between transactions.
+    engine: TxEngine<InMemoryStore>,
+}
+
+impl<S: AsyncAccountStore> AsyncTxEngine<S> {
+    pub fn new(store: S, config: EngineConfig) -> Self {
+        Self {
+            store,
+            engine: TxEngine::with_config(InMemoryStore::new(), config),
+        }
+    }
+
+    /// As [`TxEngine::add_plugin`].
+    pub fn add_plugin(&mut self, plugin: Box<dyn TransactionPlugin>) {
+        self.engine.add_plugin(plugin);
+    }
+
+    /// As [`TxEngine::handle`]. The outer error is the store's, in which case
+    /// the transaction wasn't applied.
+    pub async fn handle(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Result<u64, TransactionNotApplied>, StoreError> {
+        let engine = &mut self.engine;
+        self.store
+            .update(transaction.client_id, |account| {
+                engine.handle_on(account, transaction)
+            })
+            .await
+    }
+
+    /// As [`AsyncTxEngine::handle`], saving the events raised to the store's
+    /// outbox along with the account, rather than leaving them to be
+    /// drained. Events still undrained from before are saved with them.
+    pub async fn handle_to_outbox(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Result<u64, TransactionNotApplied>, StoreError>
+    where
+        S: OutboxStore,
+    {
+        let engine = &mut self.engine;
+        self.store
+            .update_with_events(transaction.client_id, |account| {
+                let result = engine.handle_on(account, transaction);
+                (result, engine.drain_events().collect())
+            })
+            .await
+    }
+
+    /// Applies each transaction in turn, returning their results in order.
+    /// Stops at the first store error.
+    pub async fn handle_all(
+        &mut self,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> Result<Vec<Result<u64, TransactionNotApplied>>, StoreError> {
+        let mut results = vec![];
+        for transaction in transactions {
+            results.push(self.handle(&transaction).await?);
+        }
+        Ok(results)
+    }
+
+    /// As [`TxEngine::drain_events`].
+    pub fn drain_events(&mut self) -> impl Iterator<Item = EngineEvent> + '_ {
+        self.engine.drain_events()
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+    use crate::transaction::TransactionInfo;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    /// Runs a future that never waits, as the in-memory store's don't.
+    fn ready<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Future waited"),
+        }
+    }
+
+    fn transaction(client_id: u16, transaction_id: u32, info: TransactionInfo) -> Transaction {
+        Transaction {
+            client_id,
+            transaction_id,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn engine_over_async_store() {
+        let mut engine = AsyncTxEngine::new(InMemoryAsyncStore::new(), EngineConfig::default());
+        let results = ready(engine.handle_all([
+            transaction(1, 1, TransactionInfo::Deposit(money!(10))),
+            transaction(2, 2, TransactionInfo::Deposit(money!(5))),
+            transaction(1, 3, TransactionInfo::Withdrawal(money!(20))),
+            transaction(1, 1, TransactionInfo::Dispute(None)),
+            transaction(1, 1, TransactionInfo::Chargeback),
+            transaction(1, 4, TransactionInfo::Deposit(money!(1))),
+        ]))
+        .unwrap();
+        assert_eq!(
+            results,
+            [
+                Ok(1),
+                Ok(2),
+                Err(TransactionNotApplied::InsufficientFunds(money!(10))),
+                Ok(3),
+                Ok(4),
+                Err(TransactionNotApplied::AccountLocked),
+            ]
+        );
+
+        let statements = ready(engine.store().account_statements()).unwrap();
+        let summary: Vec<_> = statements
+            .iter()
+            .map(|statement| {
+                (
+                    statement.client(),
+                    statement.total().clone(),
+                    statement.locked(),
+                )
+            })
+            .collect();
+        assert_eq!(summary, [(1, money!(0), true), (2, money!(5), false)]);
+    }
+}
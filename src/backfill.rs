@@ -0,0 +1,138 @@
+//! Backfills of late-arriving corrections against a saved snapshot (see
+//! [`crate::snapshot::read_snapshot`]).
+//!
+//! Statements published from the snapshot stay valid: corrections never
+//! rewrite a deposit, but post compensating entries after everything already
+//! applied.
+
+use crate::money::{Money, OUTPUT_SCALE};
+use crate::transaction_engine::TransactionNotApplied;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// The corrected amount of a client's deposit.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Correction {
+    pub client: u16,
+    pub tx: u32,
+    /// What the deposit should have been. Zero voids it.
+    pub amount: Money,
+}
+
+/// Outcome of a single correction, as written to the entries file.
+#[derive(Debug, Serialize)]
+struct CorrectionEntry {
+    client: u16,
+    tx: u32,
+    /// `applied`, or the name of the reason it wasn't.
+    outcome: &'static str,
+    /// Sequence number of the compensating entry, if applied.
+    sequence: Option<u64>,
+    /// Amount credited (positive) or debited (negative), if applied.
+    adjustment: Option<Money>,
+    reason: Option<String>,
+}
+
+/// Reads a corrections file: a CSV with `client`, `tx` and `amount` columns.
+pub fn read_corrections<R: Read>(reader: R) -> Result<Vec<Correction>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut corrections = vec![];
+    for correction in csv_reader.deserialize() {
+        let correction: Correction = correction?;
+        if correction.amount.is_negative() {
+            return Err(format!(
+                "Correction for client {} tx {} must not be negative",
+                correction.client, correction.tx
+            )
+            .into());
+        }
+        corrections.push(correction);
+    }
+    Ok(corrections)
+}
+
+/// Writes the compensating entry posted for each correction as CSV, in the
+/// order given.
+pub fn write_entries<W: Write>(
+    writer: W,
+    corrections: &[Correction],
+    results: &[Result<(u64, Money), TransactionNotApplied>],
+) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be no corrections.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record([
+        "client",
+        "tx",
+        "outcome",
+        "sequence",
+        "adjustment",
+        "reason",
+    ])?;
+    for (correction, result) in corrections.iter().zip(results) {
+        let (outcome, sequence, adjustment, reason) = match result {
+            Ok((sequence, adjustment)) => (
+                "applied",
+                Some(*sequence),
+                Some(adjustment.round_dp(OUTPUT_SCALE)),
+                None,
+            ),
+            Err(err) => (err.name(), None, None, Some(err.to_string())),
+        };
+        csv_writer.serialize(CorrectionEntry {
+            client: correction.client,
+            tx: correction.tx,
+            outcome,
+            sequence,
+            adjustment,
+            reason,
+        })?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn read_and_write() {
+        let corrections =
+            read_corrections("client, tx, amount\n1, 2, 7.5\n3, 4, 0\n".as_bytes()).unwrap();
+        assert_eq!(
+            corrections,
+            vec![
+                Correction {
+                    client: 1,
+                    tx: 2,
+                    amount: money!(7.5),
+                },
+                Correction {
+                    client: 3,
+                    tx: 4,
+                    amount: money!(0),
+                },
+            ]
+        );
+        assert!(read_corrections("client,tx,amount\n1,2,-1\n".as_bytes()).is_err());
+
+        let results = [
+            Ok((9, money!(-2.5))),
+            Err(TransactionNotApplied::AlreadyApplied(4)),
+        ];
+        let mut output = vec![];
+        write_entries(&mut output, &corrections, &results).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,outcome,sequence,adjustment,reason\n\
+             1,2,applied,9,-2.5,\n\
+             3,4,AlreadyApplied,,,Already Applied: 4\n"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use crate::account_store::{AccountStore, InMemoryStore, StoreLayout};
+use crate::generator::{self, WorkloadConfig};
+use crate::tiered_store::{DirBackend, TieredStore};
+use crate::transaction_engine::TxEngine;
+use std::time::{Duration, Instant};
+
+/// Account store implementations available to benchmark.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoreBackend {
+    Memory,
+    /// An [`InMemoryStore`] with the dense layout (see [`StoreLayout`]).
+    Dense,
+    /// A [`TieredStore`] keeping a tenth of the clients in memory, and the
+    /// rest in a temporary directory.
+    Tiered,
+}
+
+impl std::str::FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StoreBackend::Memory),
+            "dense" => Ok(StoreBackend::Dense),
+            "tiered" => Ok(StoreBackend::Tiered),
+            _ => Err(format!("Unknown store backend {:?}", s)),
+        }
+    }
+}
+
+/// Results of a benchmark run.
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub transactions: usize,
+    pub applied: usize,
+    pub elapsed: Duration,
+    pub p50_latency: Duration,
+    pub p99_latency: Duration,
+    pub max_latency: Duration,
+    /// Time to generate every account's statements once the workload has
+    /// run.
+    pub statements_elapsed: Duration,
+    /// Peak resident set size of the process, where the platform reports it.
+    pub peak_memory_bytes: Option<u64>,
+}
+
+impl BenchReport {
+    pub fn rows_per_sec(&self) -> f64 {
+        self.transactions as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "transactions:  {}", self.transactions)?;
+        writeln!(f, "applied:       {}", self.applied)?;
+        writeln!(f, "elapsed:       {:.3?}", self.elapsed)?;
+        writeln!(f, "rows/sec:      {:.0}", self.rows_per_sec())?;
+        writeln!(f, "p50 latency:   {:?}", self.p50_latency)?;
+        writeln!(f, "p99 latency:   {:?}", self.p99_latency)?;
+        writeln!(f, "max latency:   {:?}", self.max_latency)?;
+        writeln!(f, "statements:    {:.3?}", self.statements_elapsed)?;
+        match self.peak_memory_bytes {
+            Some(bytes) => writeln!(f, "peak memory:   {:.1} MiB", bytes as f64 / 1048576.0),
+            None => writeln!(f, "peak memory:   unavailable"),
+        }
+    }
+}
+
+/// Generates a synthetic workload and times running it through the engine
+/// with the selected store backend.
+///
+/// Generation happens up front and isn't included in the timings.
+pub fn run(config: &WorkloadConfig, backend: StoreBackend) -> BenchReport {
+    let transactions = generator::generate(config);
+    match backend {
+        StoreBackend::Memory => run_with_store(&transactions, InMemoryStore::new()),
+        StoreBackend::Dense => run_with_store(
+            &transactions,
+            InMemoryStore::with_layout(StoreLayout::Dense),
+        ),
+        StoreBackend::Tiered => {
+            let dir =
+                std::env::temp_dir().join(format!("payments_engine_bench_{}", std::process::id()));
+            let backend = DirBackend::new(&dir).expect("Failed to create tiered store directory");
+            let hot_capacity = usize::from(config.clients / 10);
+            let report = run_with_store(&transactions, TieredStore::new(backend, hot_capacity));
+            // Best effort, the directory is only scratch space.
+            let _ = std::fs::remove_dir_all(&dir);
+            report
+        }
+    }
+}
+
+fn run_with_store<T: AccountStore>(
+    transactions: &[crate::transaction::Transaction],
+    store: T,
+) -> BenchReport {
+    let mut engine = TxEngine::new(store);
+    let mut latencies = Vec::with_capacity(transactions.len());
+    let mut applied = 0;
+    let start = Instant::now();
+    for transaction in transactions {
+        let tx_start = Instant::now();
+        if engine.handle(transaction).is_ok() {
+            applied += 1;
+        }
+        // Nothing consumes events, but they'd otherwise accumulate.
+        engine.drain_events().for_each(drop);
+        latencies.push(tx_start.elapsed());
+    }
+    let elapsed = start.elapsed();
+    let statements_start = Instant::now();
+    engine.store().account_statements().for_each(drop);
+    let statements_elapsed = statements_start.elapsed();
+    latencies.sort_unstable();
+    BenchReport {
+        transactions: transactions.len(),
+        applied,
+        elapsed,
+        p50_latency: percentile(&latencies, 0.5),
+        p99_latency: percentile(&latencies, 0.99),
+        max_latency: latencies.last().copied().unwrap_or_default(),
+        statements_elapsed,
+        peak_memory_bytes: peak_memory_bytes(),
+    }
+}
+
+/// Nearest-rank percentile of already sorted values.
+fn percentile(sorted: &[Duration], q: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (q * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Peak resident set size of this process, read from `/proc` on Linux.
+pub fn peak_memory_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let values: Vec<Duration> = (1..=100).map(Duration::from_micros).collect();
+        assert_eq!(percentile(&values, 0.5), Duration::from_micros(50));
+        assert_eq!(percentile(&values, 0.99), Duration::from_micros(99));
+        assert_eq!(percentile(&values, 1.0), Duration::from_micros(100));
+        assert_eq!(percentile(&values, 0.0), Duration::from_micros(1));
+        assert_eq!(percentile(&[], 0.5), Duration::ZERO);
+    }
+
+    #[test]
+    fn small_bench_run() {
+        let config = WorkloadConfig {
+            transactions: 1_000,
+            clients: 10,
+            seed: 7,
+        };
+        let report = run(&config, StoreBackend::Memory);
+        assert_eq!(report.transactions, 1_000);
+        assert!(report.applied > 0 && report.applied <= 1_000);
+        assert!(report.p50_latency <= report.p99_latency);
+        assert!(report.p99_latency <= report.max_latency);
+
+        // Paging accounts out doesn't change the outcome, nor does the
+        // store's layout.
+        let tiered = run(&config, StoreBackend::Tiered);
+        assert_eq!(tiered.applied, report.applied);
+        let dense = run(&config, StoreBackend::Dense);
+        assert_eq!(dense.applied, report.applied);
+    }
+}
@@ -0,0 +1,153 @@
+//! Bulk dispute operations, e.g. from a card network's batch dispute file.
+
+use crate::transaction::{DisputeReason, TransactionInfo};
+use crate::transaction_engine::TransactionNotApplied;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+/// The dispute step to apply to every item of a bulk operation.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeAction {
+    Open,
+    Resolve,
+    Chargeback,
+}
+
+impl DisputeAction {
+    /// The transaction applied for an item. `reason` is only used when
+    /// opening disputes.
+    pub fn info(self, reason: Option<DisputeReason>) -> TransactionInfo {
+        match self {
+            DisputeAction::Open => TransactionInfo::Dispute(reason),
+            DisputeAction::Resolve => TransactionInfo::Resolve,
+            DisputeAction::Chargeback => TransactionInfo::Chargeback,
+        }
+    }
+}
+
+impl std::str::FromStr for DisputeAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(DisputeAction::Open),
+            "resolve" => Ok(DisputeAction::Resolve),
+            "chargeback" => Ok(DisputeAction::Chargeback),
+            _ => Err(format!("Unknown dispute action {:?}", s)),
+        }
+    }
+}
+
+/// A disputed deposit, identified by its client and transaction ID.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct DisputeItem {
+    pub client: u16,
+    pub tx: u32,
+    /// Reason code for opening the dispute, if given.
+    #[serde(default)]
+    pub reason: Option<DisputeReason>,
+}
+
+/// Where to read a bulk dispute file from, and write its results to.
+#[derive(Debug, Clone)]
+pub struct BulkDisputeOptions {
+    /// CSV of `client,tx` pairs, with an optional `reason` column.
+    pub input: PathBuf,
+    pub action: DisputeAction,
+    /// CSV with the outcome of each item, in input order.
+    pub results: PathBuf,
+}
+
+/// Outcome of a single item, as written to the results file.
+#[derive(Debug, Serialize)]
+struct ItemResult {
+    client: u16,
+    tx: u32,
+    /// `applied`, or the name of the reason it wasn't.
+    outcome: &'static str,
+    /// Sequence number applied with, if applied.
+    sequence: Option<u64>,
+    reason: Option<String>,
+}
+
+/// Reads the items of a bulk dispute file: a CSV with `client` and `tx`
+/// columns.
+pub fn read_items<R: Read>(reader: R) -> Result<Vec<DisputeItem>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut items = vec![];
+    for item in csv_reader.deserialize() {
+        items.push(item?);
+    }
+    Ok(items)
+}
+
+/// Writes the outcome of each item as CSV, in the order given.
+pub fn write_results<W: Write>(
+    writer: W,
+    items: &[DisputeItem],
+    results: &[Result<u64, TransactionNotApplied>],
+) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be no items.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["client", "tx", "outcome", "sequence", "reason"])?;
+    for (item, result) in items.iter().zip(results) {
+        let (outcome, sequence, reason) = match result {
+            Ok(sequence) => ("applied", Some(*sequence), None),
+            Err(err) => (err.name(), None, Some(err.to_string())),
+        };
+        csv_writer.serialize(ItemResult {
+            client: item.client,
+            tx: item.tx,
+            outcome,
+            sequence,
+            reason,
+        })?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_and_write() {
+        let items = read_items("client, tx, reason\n1, 2, fraud\n3, 4,\n".as_bytes()).unwrap();
+        assert_eq!(
+            items,
+            vec![
+                DisputeItem {
+                    client: 1,
+                    tx: 2,
+                    reason: Some(DisputeReason::Fraud)
+                },
+                DisputeItem {
+                    client: 3,
+                    tx: 4,
+                    reason: None
+                }
+            ]
+        );
+
+        let results = [
+            Ok(7),
+            Err(TransactionNotApplied::DisputedTransactionNotFound(4)),
+        ];
+        let mut output = vec![];
+        write_results(&mut output, &items, &results).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,tx,outcome,sequence,reason\n\
+             1,2,applied,7,\n\
+             3,4,DisputedTransactionNotFound,,Transaction Not Found: 4\n"
+        );
+    }
+}
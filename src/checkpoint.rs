@@ -0,0 +1,159 @@
+//! Checkpoints of a run in progress, so an interrupted run can be resumed
+//! (see [`crate::RunOptions::checkpoint`] and [`crate::RunOptions::resume`]).
+//!
+//! A checkpoint holds the engine's whole state (as in a snapshot, see
+//! [`crate::snapshot`]), how far through the input the run had got, and the
+//! transactions rejected or failed so far. It's written to a temporary file
+//! and renamed over the previous checkpoint, so an interruption while it's
+//! being written leaves the previous one intact.
+
+use crate::account_store::InMemoryStore;
+use crate::transaction_engine::TxEngine;
+use crate::{FailedTransactions, RejectedTransactions};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+/// Checkpoint every this many input rows, by default.
+pub const DEFAULT_INTERVAL: u64 = 100_000;
+
+/// Where and how often to checkpoint a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckpointOptions {
+    pub path: PathBuf,
+    /// Input rows read between checkpoints.
+    pub interval: u64,
+}
+
+impl CheckpointOptions {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+}
+
+/// How far through the input a run had got: records (including a CSV
+/// header) and bytes read.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct InputPosition {
+    pub rows: u64,
+    pub bytes: u64,
+}
+
+/// A run's state at a checkpoint.
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    pub position: InputPosition,
+    pub rejected: RejectedTransactions,
+    pub failed: FailedTransactions,
+    pub engine: TxEngine<InMemoryStore>,
+}
+
+#[derive(Serialize)]
+struct CheckpointRef<'a> {
+    position: InputPosition,
+    rejected: &'a RejectedTransactions,
+    failed: &'a FailedTransactions,
+    engine: &'a TxEngine<InMemoryStore>,
+}
+
+/// Writes a checkpoint to `path`, replacing any there once it's complete.
+pub fn write_checkpoint(
+    path: &Path,
+    position: InputPosition,
+    rejected: &RejectedTransactions,
+    failed: &FailedTransactions,
+    engine: &TxEngine<InMemoryStore>,
+) -> Result<(), Box<dyn Error>> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut file = BufWriter::new(File::create(&partial)?);
+    serde_json::to_writer(
+        &mut file,
+        &CheckpointRef {
+            position,
+            rejected,
+            failed,
+            engine,
+        },
+    )?;
+    file.into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+pub fn read_checkpoint(path: &Path) -> Result<Checkpoint, Box<dyn Error>> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Skips the input a checkpoint covers: the first rows up to its
+/// position. Fails if the input doesn't reach the same position, as then it
+/// isn't the input the checkpoint was taken of.
+pub(crate) struct Resume {
+    position: Option<InputPosition>,
+}
+
+impl Resume {
+    pub(crate) fn new(position: Option<InputPosition>) -> Self {
+        Self { position }
+    }
+
+    /// Whether the row read, leaving the input at `position`, was already
+    /// processed.
+    pub(crate) fn skip(&mut self, position: InputPosition) -> Result<bool, Box<dyn Error>> {
+        let Some(resume_at) = self.position else {
+            return Ok(false);
+        };
+        if position.rows < resume_at.rows {
+            return Ok(true);
+        }
+        if position != resume_at {
+            return Err(mismatch());
+        }
+        self.position = None;
+        Ok(true)
+    }
+
+    /// Fails if the input ended before the checkpoint's position.
+    pub(crate) fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self.position {
+            Some(_) => Err(mismatch()),
+            None => Ok(()),
+        }
+    }
+}
+
+fn mismatch() -> Box<dyn Error> {
+    "The input doesn't match the checkpoint being resumed.".into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resume_checks_position() {
+        let at = |rows, bytes| InputPosition { rows, bytes };
+        let mut resume = Resume::new(Some(at(3, 30)));
+        assert!(resume.skip(at(1, 10)).unwrap());
+        assert!(resume.skip(at(2, 20)).unwrap());
+        assert!(resume.skip(at(3, 30)).unwrap());
+        assert!(!resume.skip(at(4, 40)).unwrap());
+        resume.finish().unwrap();
+
+        // A longer third row: a different input.
+        let mut resume = Resume::new(Some(at(3, 30)));
+        resume.skip(at(2, 20)).unwrap();
+        assert!(resume.skip(at(3, 31)).is_err());
+
+        let mut resume = Resume::new(Some(at(3, 30)));
+        resume.skip(at(2, 20)).unwrap();
+        assert!(resume.finish().is_err());
+    }
+}
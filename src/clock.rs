@@ -0,0 +1,215 @@
+//! When transactions happened, for policies that depend on time (e.g.
+//! [`crate::EngineConfig::dispute_window_days`]).
+//!
+//! Inputs may give each transaction a `timestamp`, either as seconds since
+//! the Unix epoch or in RFC 3339 form (`2024-03-01T12:30:00Z`, with an
+//! optional fraction of a second, ignored, and a `Z` or `±HH:MM` offset). A
+//! date alone (`2024-03-01`) is midnight UTC on that date.
+//!
+//! A [`Clock`] decides the time of each transaction the engine applies.
+//! Batch runs replay history, so use the time the input gives
+//! ([`InputClock`]); a live service stamps transactions arriving without
+//! one with the time they're applied ([`SystemClock`]).
+
+use crate::transaction::Transaction;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Where the engine gets the time a transaction happened.
+pub trait Clock: Send {
+    /// When `transaction` happened, in seconds since the Unix epoch, or
+    /// `None` if that isn't known.
+    fn time_of(&self, transaction: &Transaction) -> Option<u64>;
+}
+
+/// The time given in the input, if any. The default, for batch runs.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct InputClock;
+
+impl Clock for InputClock {
+    fn time_of(&self, transaction: &Transaction) -> Option<u64> {
+        transaction.timestamp
+    }
+}
+
+/// The time given in the input, or else the current time, for live
+/// services.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn time_of(&self, transaction: &Transaction) -> Option<u64> {
+        transaction.timestamp.or_else(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+            Some(now.as_secs())
+        })
+    }
+}
+
+/// Any function of the transaction, e.g. a fixed time in tests.
+impl<F: Fn(&Transaction) -> Option<u64> + Send> Clock for F {
+    fn time_of(&self, transaction: &Transaction) -> Option<u64> {
+        self(transaction)
+    }
+}
+
+/// Parses a timestamp as written in input files, to seconds since the Unix
+/// epoch.
+pub fn parse_timestamp(s: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "Timestamp {:?} isn't seconds since the epoch or RFC 3339",
+            s
+        )
+    };
+    let s = s.trim();
+    if s.bytes().all(|byte| byte.is_ascii_digit()) {
+        return s.parse().map_err(|_| invalid());
+    }
+    let (date, time) = s.split_once(['T', 't', ' ']).unwrap_or((s, ""));
+    let days = parse_date(date).ok_or_else(invalid)?;
+    let seconds = if time.is_empty() {
+        0
+    } else {
+        parse_time(time).ok_or_else(invalid)?
+    };
+    u64::try_from(days * SECONDS_PER_DAY as i64 + seconds).map_err(|_| invalid())
+}
+
+/// Days since the epoch of a `YYYY-MM-DD` date.
+pub(crate) fn parse_date(date: &str) -> Option<i64> {
+    let mut parts = date.splitn(3, '-');
+    let (year, month, day) = (parts.next()?, parts.next()?, parts.next()?);
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return None;
+    }
+    let year: i64 = year.parse().ok()?;
+    let month: u32 = month.parse().ok()?;
+    let day: u32 = day.parse().ok()?;
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return None,
+    };
+    if day == 0 || day > days_in_month {
+        return None;
+    }
+    Some(days_from_civil(year, month, day))
+}
+
+/// Seconds since midnight UTC of an `HH:MM:SS[.fff](Z|±HH:MM)` time.
+fn parse_time(time: &str) -> Option<i64> {
+    let (time, offset) = if let Some(time) = time.strip_suffix(['Z', 'z']) {
+        (time, 0)
+    } else {
+        let at = time.rfind(['+', '-'])?;
+        let (time, offset) = time.split_at(at);
+        let sign = if offset.starts_with('-') { -1 } else { 1 };
+        let (hours, minutes) = offset[1..].split_once(':')?;
+        (time, sign * hms(hours, minutes, "00")?)
+    };
+    let time = time.split_once('.').map_or(time, |(time, fraction)| {
+        match fraction.bytes().all(|byte| byte.is_ascii_digit()) {
+            true => time,
+            false => "",
+        }
+    });
+    let mut parts = time.splitn(3, ':');
+    let seconds = hms(parts.next()?, parts.next()?, parts.next()?)?;
+    Some(seconds - offset)
+}
+
+fn hms(hours: &str, minutes: &str, seconds: &str) -> Option<i64> {
+    let two_digits = |part: &str| part.len() == 2 && part.bytes().all(|b| b.is_ascii_digit());
+    if !(two_digits(hours) && two_digits(minutes) && two_digits(seconds)) {
+        return None;
+    }
+    let (hours, minutes, seconds): (i64, i64, i64) = (
+        hours.parse().ok()?,
+        minutes.parse().ok()?,
+        seconds.parse().ok()?,
+    );
+    // Seconds up to 60, for leap seconds.
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+    Some(hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+/// (Howard Hinnant's `days_from_civil`).
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_from_march = (i64::from(month) + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The year, month and day of a number of days since 1970-01-01, the
+/// inverse of [`days_from_civil`] (Howard Hinnant's `civil_from_days`).
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month_from_march + 2) / 5 + 1) as u32;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// A number of days since 1970-01-01 as a `YYYY-MM-DD` date.
+pub fn format_date(days: i64) -> String {
+    let (year, month, day) = civil_from_days(days);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        for (timestamp, expected) in [
+            ("1709296200", 1_709_296_200),
+            ("2024-03-01T12:30:00Z", 1_709_296_200),
+            ("2024-03-01T12:30:00.250Z", 1_709_296_200),
+            ("2024-03-01T14:30:00+02:00", 1_709_296_200),
+            ("2024-03-01 07:30:00-05:00", 1_709_296_200),
+            ("2024-03-01", 1_709_251_200),
+            ("1970-01-01T00:00:00Z", 0),
+            ("2000-02-29T00:00:00Z", 951_782_400),
+        ] {
+            assert_eq!(parse_timestamp(timestamp), Ok(expected), "{}", timestamp);
+        }
+        for timestamp in [
+            "",
+            "yesterday",
+            "2023-02-29",
+            "2024-13-01",
+            "2024-03-01T25:00:00Z",
+            "2024-03-01T12:30Z",
+            "2024-3-1",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert!(parse_timestamp(timestamp).is_err(), "{}", timestamp);
+        }
+        for date in ["1970-01-01", "2000-02-29", "2023-12-31", "2024-03-01"] {
+            assert_eq!(format_date(parse_date(date).unwrap()), date);
+        }
+    }
+}
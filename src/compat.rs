@@ -0,0 +1,59 @@
+//! Stable facades over the engine's API, for services pinning this crate.
+//!
+//! The crate root's API changes as the engine grows: [`crate::RunOptions`]
+//! gains options, and [`crate::process`] reports more of what a run did in
+//! its [`crate::ProcessingReport`]. Each module here instead freezes the API
+//! as it was at a version, so code written against it keeps compiling and
+//! behaving the same as the crate is upgraded.
+//!
+//! Facades follow semver: a facade is only ever removed in a breaking
+//! release (a new major version, or while the crate is below 1.0, a new
+//! minor one), and only after it has been `#[deprecated]` for at least one
+//! release, with `since` naming the release that deprecated it. Items at the
+//! crate root replaced by a facade are deprecated the same way.
+
+/// The API of version 0.1: runs returning the rejected and failed
+/// transactions, writing statements with the default options.
+pub mod v1 {
+    use crate::input::InputFormat;
+    use crate::{process, RunOptions};
+    use std::error::Error;
+    use std::io::{Read, Write};
+
+    pub use crate::FailedTransactions;
+
+    /// Transactions that were rejected due to account state or invalid input.
+    /// Transaction ID + description of rejection cause.
+    pub type RejectedTransactions = Vec<(u32, String)>;
+
+    /// Runs the engine to completion, parsing all rows in the input CSV and
+    /// writing the resulting account state for all clients.
+    pub fn run_with_csv<R: Read, W: Write>(
+        reader: R,
+        writer: W,
+    ) -> Result<(RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+        let report = process(reader, writer, RunOptions::default())?;
+        Ok((rejected(report.rejected), report.failed))
+    }
+
+    /// As [`run_with_csv`], reading transactions as JSON Lines (see
+    /// [`crate::input`]) rather than CSV.
+    pub fn run_with_json_lines<R: Read, W: Write>(
+        reader: R,
+        writer: W,
+    ) -> Result<(RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+        let options = RunOptions {
+            input_format: InputFormat::JsonLines,
+            ..RunOptions::default()
+        };
+        let report = process(reader, writer, options)?;
+        Ok((rejected(report.rejected), report.failed))
+    }
+
+    fn rejected(rejected: crate::RejectedTransactions) -> RejectedTransactions {
+        rejected
+            .into_iter()
+            .map(|rejection| (rejection.tx, rejection.reason))
+            .collect()
+    }
+}
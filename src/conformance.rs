@@ -0,0 +1,266 @@
+//! Conformance checks for [`AccountStore`] implementations, the executable
+//! form of the trait's contract. Requires the `conformance` feature.
+//!
+//! Each check takes a function returning a new, empty store and panics with
+//! a description of the first way the store breaks the contract, so they can
+//! be called straight from a store's tests:
+//!
+//! ```ignore
+//! #[test]
+//! fn conforms() {
+//!     payments_engine::conformance::check_store(MyStore::new);
+//!     payments_engine::conformance::check_round_trip(MyStore::new, |store| {
+//!         store.close().unwrap();
+//!         MyStore::open().unwrap()
+//!     });
+//! }
+//! ```
+//!
+//! Accounts are compared by their serialized form, so every part of an
+//! account (deposit records, dispute state, locks, notes) must be kept.
+
+use crate::account::{Account, AccountStatement, LockScope};
+use crate::account_store::AccountStore;
+use crate::money::Money;
+use crate::system_accounts::SystemAccounts;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Clients given accounts by [`populate`], spread over the ID space.
+const CLIENTS: [u16; 6] = [0, 1, 2, 300, 4_000, u16::MAX];
+
+/// Runs every check not involving persistence.
+pub fn check_store<S: AccountStore>(mut new_store: impl FnMut() -> S) {
+    check_creates_on_get_mut(new_store());
+    check_statements_complete(new_store());
+    check_accounts_after(new_store());
+}
+
+/// `get_account_mut` creates an empty account for an unknown client, which
+/// `get_account` then finds, and returns the same account from then on.
+pub fn check_creates_on_get_mut<S: AccountStore>(mut store: S) {
+    assert!(
+        store.get_account(7).is_none(),
+        "A new store shouldn't have accounts"
+    );
+    assert_eq!(store.accounts().count(), 0, "A new store should be empty");
+
+    let account = store.get_account_mut(7);
+    assert_eq!(account.client(), 7, "Created the wrong client's account");
+    assert_eq!(
+        snapshot(account),
+        snapshot(&Account::new(7)),
+        "Created accounts should be empty"
+    );
+    let mut system = SystemAccounts::default();
+    account
+        .credit(1, &Money::from(5), &Money::default(), &mut system, || 1)
+        .expect("Deposit into a new account");
+
+    assert_eq!(
+        store.get_account(7).map(Account::total_funds),
+        Some(&Money::from(5)),
+        "get_account should find accounts created by get_account_mut"
+    );
+    assert_eq!(
+        store.get_account_mut(7).total_funds(),
+        &Money::from(5),
+        "get_account_mut should return existing accounts"
+    );
+    assert!(
+        store.get_account(8).is_none(),
+        "Only requested accounts should be created"
+    );
+    assert_eq!(store.accounts().count(), 1, "Created one account");
+}
+
+/// `accounts` and `account_statements` cover every account, once each,
+/// with statements matching the accounts.
+pub fn check_statements_complete<S: AccountStore>(mut store: S) {
+    populate(&mut store);
+    let accounts = accounts(&store);
+    assert_eq!(
+        accounts.keys().copied().collect::<Vec<_>>(),
+        CLIENTS,
+        "accounts should visit every account once"
+    );
+    for (client_id, account) in &accounts {
+        assert_eq!(
+            store.get_account(*client_id).map(snapshot).as_ref(),
+            Some(account),
+            "accounts and get_account disagree for client {}",
+            client_id
+        );
+    }
+
+    let mut statements: Vec<AccountStatement> = store.account_statements().collect();
+    statements.sort_by_key(AccountStatement::client);
+    let expected: Vec<AccountStatement> = CLIENTS
+        .iter()
+        .map(|client_id| store.get_account(*client_id).expect("account").into())
+        .collect();
+    assert_eq!(
+        statements, expected,
+        "account_statements should have one statement per account"
+    );
+}
+
+/// `accounts_after` pages through accounts in ascending client order.
+pub fn check_accounts_after<S: AccountStore>(mut store: S) {
+    populate(&mut store);
+    let clients = |after| {
+        store
+            .accounts_after(after)
+            .map(Account::client)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(clients(None), CLIENTS, "Paging from the start");
+    assert_eq!(clients(Some(2)), [300, 4_000, u16::MAX], "Paging after 2");
+    assert_eq!(clients(Some(3)), [300, 4_000, u16::MAX], "Paging after 3");
+    assert!(
+        clients(Some(u16::MAX)).is_empty(),
+        "Paging after the last client"
+    );
+}
+
+/// Accounts survive being persisted and loaded back by `round_trip`, e.g.
+/// closing a store and opening another over the same storage.
+pub fn check_round_trip<S: AccountStore>(
+    mut new_store: impl FnMut() -> S,
+    round_trip: impl FnOnce(S) -> S,
+) {
+    let mut store = new_store();
+    populate(&mut store);
+    let before = accounts(&store);
+    let restored = round_trip(store);
+    assert_eq!(
+        accounts(&restored),
+        before,
+        "Accounts changed persisting the store"
+    );
+}
+
+/// As [`check_round_trip`], for stores persisted by serializing them, e.g.
+/// in snapshots.
+pub fn check_serde_round_trip<S: AccountStore + Serialize + DeserializeOwned>(
+    new_store: impl FnMut() -> S,
+) {
+    check_round_trip(new_store, |store| {
+        let json = serde_json::to_string(&store).expect("Serialize store");
+        serde_json::from_str(&json).expect("Deserialize store")
+    });
+}
+
+/// Gives each of [`CLIENTS`] an account, with deposits, withdrawals and
+/// disputes in every state between them.
+fn populate<S: AccountStore>(store: &mut S) {
+    let mut system = SystemAccounts::default();
+    let no_fee = Money::default();
+    let mut sequence = 0;
+    let mut next = || {
+        sequence += 1;
+        sequence
+    };
+    for (index, client_id) in CLIENTS.into_iter().enumerate() {
+        let tx = u32::from(client_id) * 10;
+        let account = store.get_account_mut(client_id);
+        for offset in 0..3 {
+            let amount = Money::from(index as i64 + offset + 1);
+            account
+                .credit(tx + offset as u32, &amount, &no_fee, &mut system, &mut next)
+                .expect("Deposit");
+        }
+        match index % 3 {
+            0 => {
+                account
+                    .hold(tx, None, None, &mut system, &mut next)
+                    .expect("Dispute");
+            }
+            1 => {
+                account
+                    .debit(
+                        tx + 3,
+                        &Money::from(1),
+                        &no_fee,
+                        &no_fee,
+                        &mut system,
+                        &mut next,
+                    )
+                    .expect("Withdrawal");
+                account
+                    .hold(tx + 1, None, None, &mut system, &mut next)
+                    .expect("Dispute");
+                account
+                    .release(tx + 1, &mut system, &mut next)
+                    .expect("Resolve");
+            }
+            _ => {
+                account
+                    .hold(tx + 2, None, None, &mut system, &mut next)
+                    .expect("Dispute");
+                account
+                    .charge_back(tx + 2, &mut system, &mut next)
+                    .expect("Chargeback");
+                account.lock(LockScope::BlockAll);
+            }
+        }
+    }
+}
+
+/// Every account in the store, serialized, by client. Panics if a client
+/// has more than one.
+fn accounts<S: AccountStore>(store: &S) -> BTreeMap<u16, serde_json::Value> {
+    let mut accounts = BTreeMap::new();
+    for account in store.accounts() {
+        let previous = accounts.insert(account.client(), snapshot(account));
+        assert!(
+            previous.is_none(),
+            "Client {} has more than one account",
+            account.client()
+        );
+    }
+    accounts
+}
+
+fn snapshot(account: &Account) -> serde_json::Value {
+    serde_json::to_value(account).expect("Serialize account")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::{InMemoryStore, StoreLayout};
+    use crate::tiered_store::{ColdBackend, TieredStore};
+    use std::collections::HashMap;
+    use std::io;
+
+    #[derive(Default)]
+    struct MemoryBackend(HashMap<u16, Vec<u8>>);
+
+    impl ColdBackend for MemoryBackend {
+        fn read(&self, client_id: u16) -> io::Result<Option<Account>> {
+            Ok(self
+                .0
+                .get(&client_id)
+                .map(|bytes| serde_json::from_slice(bytes).unwrap()))
+        }
+
+        fn write(&mut self, account: &Account) -> io::Result<()> {
+            self.0
+                .insert(account.client(), serde_json::to_vec(account).unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stores_conform() {
+        check_store(InMemoryStore::new);
+        check_serde_round_trip(InMemoryStore::new);
+        let dense = || InMemoryStore::with_layout(StoreLayout::Dense);
+        check_store(dense);
+        check_serde_round_trip(dense);
+        // Little enough room in memory that most accounts are paged out.
+        check_store(|| TieredStore::new(MemoryBackend::default(), 2));
+    }
+}
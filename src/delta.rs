@@ -0,0 +1,122 @@
+//! Delta statements, against the snapshot of a previous run (see
+//! [`crate::snapshot`]), so downstream consumers only ingest the accounts
+//! that changed rather than every account, every run.
+//!
+//! An account has changed if its statement differs from its statement in
+//! the previous snapshot, or it's new. Accounts in the previous snapshot
+//! but not this run's final state (e.g. merged away) are listed as
+//! tombstones, for consumers to delete.
+
+use crate::account::AccountStatement;
+use crate::account_store::AccountStore;
+use crate::intern::Interned;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Options for writing only the statements that changed.
+#[derive(Debug, Clone)]
+pub struct DeltaOptions {
+    /// Snapshot of the previous run, to compare against. Encrypted with
+    /// the run's key, if it has one.
+    pub previous: PathBuf,
+    /// Write the clients whose accounts are in the previous snapshot but
+    /// not the final state to this path, as CSV.
+    pub tombstones: Option<PathBuf>,
+}
+
+/// Statements from a previous run, to compare against.
+#[derive(Debug)]
+pub struct Delta {
+    /// By client and currency.
+    previous: BTreeMap<(u16, Option<Interned>), AccountStatement>,
+}
+
+impl Delta {
+    pub fn new<S: AccountStore>(previous: &S) -> Self {
+        Self {
+            previous: previous
+                .account_statements()
+                .map(|statement| {
+                    let key = (statement.client(), statement.currency().cloned());
+                    (key, statement.to_fixed_scale())
+                })
+                .collect(),
+        }
+    }
+
+    /// Whether the statement differs from the balance's previous one, if it
+    /// had one.
+    pub fn changed(&self, statement: &AccountStatement) -> bool {
+        let key = (statement.client(), statement.currency().cloned());
+        self.previous.get(&key) != Some(&statement.clone().to_fixed_scale())
+    }
+
+    /// Clients with a previous statement but no account in `current`, in
+    /// order.
+    pub fn tombstones<S: AccountStore>(&self, current: &S) -> Vec<u16> {
+        let mut clients: Vec<u16> = self
+            .previous
+            .keys()
+            .map(|(client, _)| *client)
+            .filter(|client| current.get_account(*client).is_none())
+            .collect();
+        clients.dedup();
+        clients
+    }
+}
+
+/// Writes tombstoned clients as CSV, with a single `client` column.
+pub fn write_tombstones<W: Write>(writer: W, clients: &[u16]) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there are usually no tombstones.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["client"])?;
+    for client in clients {
+        csv_writer.write_record([client.to_string()])?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::Account;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+
+    fn account(client: u16, total: &str) -> Account {
+        let mut account = Account::new(client);
+        account.set_funds(total.parse().unwrap(), money!(0));
+        account
+    }
+
+    #[test]
+    fn changed_and_tombstoned() {
+        let previous =
+            InMemoryStore::new_with_data(vec![account(1, "10"), account(2, "5"), account(3, "1")]);
+        // Client 1 unchanged, if at another scale, 2 changed, 3 gone and 4
+        // new.
+        let current = InMemoryStore::new_with_data(vec![
+            account(1, "10.00"),
+            account(2, "6"),
+            account(4, "2"),
+        ]);
+        let delta = Delta::new(&previous);
+        let mut changed: Vec<u16> = current
+            .account_statements()
+            .filter(|statement| delta.changed(statement))
+            .map(|statement| statement.client())
+            .collect();
+        changed.sort_unstable();
+        assert_eq!(changed, [2, 4]);
+        assert_eq!(delta.tombstones(&current), [3]);
+
+        let mut output = vec![];
+        write_tombstones(&mut output, &[3]).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "client\n3\n");
+    }
+}
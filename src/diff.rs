@@ -0,0 +1,277 @@
+//! Differences between two saved engine state snapshots, e.g. for
+//! day-over-day change reports.
+//!
+//! Balances in different currencies are compared separately, as statements
+//! list them, so amounts in different currencies are never summed.
+
+use crate::account::{Account, DisputeStatus};
+use crate::account_store::AccountStore;
+use crate::intern::Interned;
+use crate::money::{Money, OUTPUT_SCALE};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io::Write;
+
+/// How a single client's balance in one currency changed between two
+/// snapshots.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountDiff {
+    pub client: u16,
+    /// The balance's currency, only labelled if more than one is named, as
+    /// in statements.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Interned>,
+    /// Changes in each balance, from the first snapshot to the second.
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    /// Whether the account was locked since the first snapshot.
+    pub newly_locked: bool,
+    /// Deposits whose dispute status changed, as `tx:before->after`
+    /// separated by spaces, in transaction ID order. Deposits only in the
+    /// second snapshot are from `none`.
+    pub dispute_changes: String,
+}
+
+/// Compares every account in either store, returning the balances that
+/// changed, ordered by client then currency.
+pub fn diff<S: AccountStore>(before: &S, after: &S) -> Vec<AccountDiff> {
+    let accounts = || before.accounts().chain(after.accounts());
+    let clients: BTreeSet<u16> = accounts().map(Account::client).collect();
+    let named: BTreeSet<&Interned> = accounts()
+        .flat_map(Account::balances)
+        .filter_map(Account::currency)
+        .collect();
+    let labelled = named.len() > 1;
+    let mut diffs = vec![];
+    for client in clients {
+        let empty = Account::new(client);
+        let before = before.get_account(client).unwrap_or(&empty);
+        let after = after.get_account(client).unwrap_or(&empty);
+        let currencies: BTreeSet<Option<&Interned>> = before
+            .balances()
+            .chain(after.balances())
+            .map(Account::currency)
+            .collect();
+        for currency in currencies {
+            let mut account_diff = diff_balance(before, after, currency);
+            if labelled {
+                account_diff.currency = Some(currency.cloned().unwrap_or_else(|| "".into()));
+            }
+            let zero = Money::zero();
+            let unchanged = account_diff.available == zero
+                && account_diff.held == zero
+                && account_diff.total == zero
+                && !account_diff.newly_locked
+                && account_diff.dispute_changes.is_empty();
+            if !unchanged {
+                diffs.push(account_diff);
+            }
+        }
+    }
+    diffs
+}
+
+/// Compares the accounts' balances in `currency`, either of which may not
+/// have one yet.
+fn diff_balance(before: &Account, after: &Account, currency: Option<&Interned>) -> AccountDiff {
+    let empty = Account::new(after.client());
+    let [before_balance, after_balance] = [before, after].map(|account| {
+        account
+            .balances()
+            .find(|balance| balance.currency() == currency)
+            .unwrap_or(&empty)
+    });
+    let delta = |funds: fn(&Account) -> Money| {
+        funds(after_balance)
+            .saturating_sub(&funds(before_balance))
+            .round_dp(OUTPUT_SCALE)
+    };
+    let mut dispute_changes = vec![];
+    for (tx, record) in after_balance.transaction_history() {
+        let status_before = before_balance
+            .transaction(tx)
+            .map(|record| record.dispute_status());
+        if status_before != Some(record.dispute_status()) {
+            dispute_changes.push((tx, status_before, record.dispute_status()));
+        }
+    }
+    dispute_changes.sort_by_key(|(tx, _, _)| *tx);
+    AccountDiff {
+        client: after.client(),
+        currency: None,
+        available: delta(Account::available_funds),
+        held: delta(Account::held_funds),
+        total: delta(|account| account.total_funds().clone()),
+        newly_locked: !before.locked() && after.locked(),
+        dispute_changes: dispute_changes
+            .into_iter()
+            .map(|(tx, before, after)| {
+                format!(
+                    "{}:{}->{}",
+                    tx,
+                    status_name(before),
+                    status_name(Some(after))
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+fn status_name(status: Option<DisputeStatus>) -> &'static str {
+    match status {
+        None => "none",
+        Some(DisputeStatus::NotDisputed) => "not-disputed",
+        Some(DisputeStatus::Disputed) => "disputed",
+        Some(DisputeStatus::Resolved) => "resolved",
+        Some(DisputeStatus::Refunded) => "refunded",
+        Some(DisputeStatus::PartiallyRefunded) => "partially-refunded",
+        Some(DisputeStatus::Reversed) => "reversed",
+    }
+}
+
+/// Writes the differences as CSV, with a `currency` column after `client`
+/// if they're labelled with currencies.
+pub fn write_diff<W: Write>(writer: W, diffs: &[AccountDiff]) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be no differences.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    let labelled = diffs
+        .iter()
+        .any(|account_diff| account_diff.currency.is_some());
+    let header = [
+        "client",
+        "currency",
+        "available",
+        "held",
+        "total",
+        "newly_locked",
+        "dispute_changes",
+    ];
+    csv_writer.write_record(
+        header
+            .into_iter()
+            .filter(|column| labelled || *column != "currency"),
+    )?;
+    for account_diff in diffs {
+        csv_writer.serialize(account_diff)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+    use crate::snapshot::{read_snapshot, write_snapshot, Compression};
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+
+    fn apply(engine: &mut TxEngine<InMemoryStore>, client_id: u16, tx: u32, info: TransactionInfo) {
+        apply_in(engine, client_id, tx, info, None);
+    }
+
+    fn apply_in(
+        engine: &mut TxEngine<InMemoryStore>,
+        client_id: u16,
+        tx: u32,
+        info: TransactionInfo,
+        currency: Option<&str>,
+    ) {
+        engine
+            .handle(&Transaction {
+                client_id,
+                transaction_id: tx,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: currency.map(Into::into),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        apply(&mut engine, 1, 1, TransactionInfo::Deposit(money!(10)));
+        apply(&mut engine, 1, 2, TransactionInfo::Deposit(money!(5)));
+        apply(&mut engine, 2, 3, TransactionInfo::Deposit(money!(7)));
+        apply(&mut engine, 3, 4, TransactionInfo::Deposit(money!(1)));
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &engine, Compression::None).unwrap();
+
+        apply(&mut engine, 1, 1, TransactionInfo::Dispute(None));
+        apply(&mut engine, 1, 5, TransactionInfo::Deposit(money!(2.5)));
+        apply(&mut engine, 2, 3, TransactionInfo::Dispute(None));
+        apply(&mut engine, 2, 3, TransactionInfo::Chargeback);
+        apply(&mut engine, 4, 6, TransactionInfo::Deposit(money!(3)));
+
+        let before = read_snapshot(snapshot.as_slice()).unwrap();
+        let diffs = diff(before.store(), engine.store());
+        let mut output = vec![];
+        write_diff(&mut output, &diffs).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,newly_locked,dispute_changes\n\
+             1,-7.5,10,2.5,false,1:not-disputed->disputed 5:none->not-disputed\n\
+             2,-7,0,-7,true,3:not-disputed->refunded\n\
+             4,3,0,3,false,6:none->not-disputed\n"
+        );
+    }
+
+    #[test]
+    fn balances_compared_by_currency() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        apply_in(
+            &mut engine,
+            1,
+            1,
+            TransactionInfo::Deposit(money!(10)),
+            Some("USD"),
+        );
+        apply_in(
+            &mut engine,
+            2,
+            2,
+            TransactionInfo::Deposit(money!(5)),
+            Some("USD"),
+        );
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &engine, Compression::None).unwrap();
+
+        apply_in(
+            &mut engine,
+            1,
+            3,
+            TransactionInfo::Deposit(money!(4)),
+            Some("EUR"),
+        );
+        apply_in(
+            &mut engine,
+            1,
+            4,
+            TransactionInfo::Withdrawal(money!(4)),
+            Some("USD"),
+        );
+        apply_in(&mut engine, 2, 2, TransactionInfo::Dispute(None), None);
+
+        let before = read_snapshot(snapshot.as_slice()).unwrap();
+        let diffs = diff(before.store(), engine.store());
+        let mut output = vec![];
+        write_diff(&mut output, &diffs).unwrap();
+        // Client 1's total is unchanged across currencies, but not in either.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,currency,available,held,total,newly_locked,dispute_changes\n\
+             1,EUR,4,0,4,false,3:none->not-disputed\n\
+             1,USD,-4,0,-4,false,\n\
+             2,USD,-5,5,0,false,2:not-disputed->disputed\n"
+        );
+    }
+}
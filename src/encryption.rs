@@ -0,0 +1,312 @@
+//! At-rest encryption of the files the engine writes that hold account
+//! histories: snapshots (see [`crate::snapshot`]), the event log (see
+//! [`crate::RunOptions::events`]) and the service's receipt log (see
+//! [`crate::server::PaymentsService::with_encryption`]).
+//!
+//! Files are encrypted whole with AES-256-GCM, which also authenticates
+//! them, so a damaged file or the wrong key fails to decrypt rather than
+//! yielding garbage. Logs that are appended to are instead encrypted a line
+//! at a time (see [`encrypt_line`]). Encryption requires the `encryption`
+//! feature.
+
+use crate::sha256;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Starts every encrypted file, followed by the nonce and ciphertext.
+const MAGIC: &[u8] = b"payments-engine-encrypted v1\n";
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
+
+/// A 256-bit encryption key.
+#[derive(Clone)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Parses a key written as 64 hex digits, ignoring surrounding
+    /// whitespace.
+    pub fn from_hex(hex: &str) -> Result<Self, Box<dyn Error>> {
+        let hex = hex.trim();
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err("An encryption key must be 64 hex digits.".into());
+        }
+        let mut key = [0; 32];
+        for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits)?;
+            *byte = u8::from_str_radix(digits, 16)
+                .map_err(|_| "An encryption key must be 64 hex digits.")?;
+        }
+        Ok(Self(key))
+    }
+
+    /// A key from raw bytes, e.g. fetched from a key management service.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Never print the key itself.
+        f.write_str("Key(..)")
+    }
+}
+
+/// Where to get the encryption key from, e.g. a key management service.
+pub trait KeyProvider {
+    fn key(&self) -> Result<Key, Box<dyn Error>>;
+}
+
+/// A [`KeyProvider`] reading the key from a file, as 64 hex digits.
+pub struct KeyFile(pub PathBuf);
+
+impl KeyProvider for KeyFile {
+    fn key(&self) -> Result<Key, Box<dyn Error>> {
+        Key::from_hex(&std::fs::read_to_string(&self.0)?)
+    }
+}
+
+/// A file being written, encrypted as a whole when finished if there's a key.
+///
+/// Encrypted contents are kept in memory until then, as they're only
+/// authenticated once complete.
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Encrypted {
+        file: File,
+        key: Key,
+        contents: Vec<u8>,
+    },
+}
+
+impl OutputFile {
+    pub fn create(path: &Path, key: Option<&Key>) -> std::io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(match key {
+            Some(key) => OutputFile::Encrypted {
+                file,
+                key: key.clone(),
+                contents: vec![],
+            },
+            None => OutputFile::Plain(BufWriter::new(file)),
+        })
+    }
+
+    /// Finishes writing the file, encrypting it if necessary.
+    pub fn finish(self) -> Result<(), Box<dyn Error>> {
+        match self {
+            OutputFile::Plain(mut writer) => writer.flush()?,
+            OutputFile::Encrypted {
+                mut file,
+                key,
+                contents,
+            } => file.write_all(&encrypt(&key, &contents)?)?,
+        }
+        Ok(())
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Plain(writer) => writer.write(buf),
+            OutputFile::Encrypted { contents, .. } => contents.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(writer) => writer.flush(),
+            // Nothing is written until the file is finished.
+            OutputFile::Encrypted { .. } => Ok(()),
+        }
+    }
+}
+
+/// Reads a file, decrypting it with `key` if one is given. Without a key,
+/// only unencrypted files can be read. With one, unencrypted files are
+/// refused, as one could have been put in place of the encrypted file to be
+/// trusted without being authenticated. Files encrypted a line at a time are
+/// decrypted line by line.
+pub fn read_file(path: &Path, key: Option<&Key>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let bytes = std::fs::read(path)?;
+    let by_line = bytes.starts_with(sha256::to_hex(MAGIC).as_bytes());
+    let encrypted = bytes.starts_with(MAGIC) || by_line;
+    match key {
+        Some(key) if by_line => {
+            let mut contents = vec![];
+            for line in std::str::from_utf8(&bytes)?.lines() {
+                contents.extend(decrypt_line(key, line)?);
+                contents.push(b'\n');
+            }
+            Ok(contents)
+        }
+        Some(key) if encrypted => decrypt(key, &bytes),
+        Some(_) => Err(format!(
+            "{} isn't encrypted, though a key was given.",
+            path.display()
+        )
+        .into()),
+        None if encrypted => {
+            Err(format!("{} is encrypted, and needs a key.", path.display()).into())
+        }
+        None => Ok(bytes),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn cipher(key: &Key) -> aes_gcm::Aes256Gcm {
+    use aes_gcm::KeyInit;
+    aes_gcm::Aes256Gcm::new(aes_gcm::Key::<aes_gcm::Aes256Gcm>::from_slice(
+        key.as_bytes(),
+    ))
+}
+
+/// Encrypts `plaintext` with a random nonce, as written to an encrypted file.
+#[cfg(feature = "encryption")]
+pub fn encrypt(key: &Key, plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    use aes_gcm::aead::{Aead, AeadCore, OsRng};
+    let nonce = aes_gcm::Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(key)
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| "Encryption failed.")?;
+    let mut encrypted = MAGIC.to_vec();
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+/// Decrypts the contents of an encrypted file.
+#[cfg(feature = "encryption")]
+pub fn decrypt(key: &Key, encrypted: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    use aes_gcm::aead::Aead;
+    let body = encrypted
+        .strip_prefix(MAGIC)
+        .filter(|body| body.len() >= NONCE_LEN)
+        .ok_or("Not an encrypted file.")?;
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    Ok(cipher(key)
+        .decrypt(aes_gcm::Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Decryption failed: wrong key, or the file is damaged.")?)
+}
+
+/// Encrypts one line of a log as hex, without its line break, so lines can
+/// be appended to the log without rewriting it. Each line is authenticated
+/// on its own.
+pub fn encrypt_line(key: &Key, line: &[u8]) -> Result<String, Box<dyn Error>> {
+    Ok(sha256::to_hex(&encrypt(key, line)?))
+}
+
+/// Decrypts a line encrypted by [`encrypt_line`].
+pub fn decrypt_line(key: &Key, line: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let damaged = "Not an encrypted line: the log is damaged.";
+    if !line.len().is_multiple_of(2) || !line.is_ascii() {
+        return Err(damaged.into());
+    }
+    let encrypted = (0..line.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(&line[at..at + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| damaged)?;
+    decrypt(key, &encrypted)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn encrypt(_key: &Key, _plaintext: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("Encryption requires the `encryption` feature.".into())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn decrypt(_key: &Key, _encrypted: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("Encryption requires the `encryption` feature.".into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn parse_key() {
+        let key = Key::from_hex(&format!("{}\n", HEX)).unwrap();
+        assert_eq!(key.as_bytes()[31], 0x1f);
+        assert_eq!(format!("{:?}", key), "Key(..)");
+        assert!(Key::from_hex(&HEX[2..]).is_err());
+        assert!(Key::from_hex(&HEX.replace('a', "g")).is_err());
+    }
+
+    #[test]
+    fn plain_files_refused_with_key() {
+        let path = std::env::temp_dir().join(format!("pe-plain-{}", std::process::id()));
+        let mut file = OutputFile::create(&path, None).unwrap();
+        file.write_all(b"plaintext").unwrap();
+        file.finish().unwrap();
+        let key = Key::from_hex(HEX).unwrap();
+        let err = read_file(&path, Some(&key)).unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("isn't encrypted, though a key was given."));
+        assert_eq!(read_file(&path, None).unwrap(), b"plaintext");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn round_trip() {
+        let path = std::env::temp_dir().join(format!("pe-encrypted-{}", std::process::id()));
+        let key = Key::from_hex(HEX).unwrap();
+        let mut file = OutputFile::create(&path, Some(&key)).unwrap();
+        file.write_all(b"account history").unwrap();
+        file.finish().unwrap();
+        let encrypted = std::fs::read(&path).unwrap();
+        assert!(!encrypted
+            .windows(b"history".len())
+            .any(|window| window == b"history"));
+        assert_eq!(read_file(&path, Some(&key)).unwrap(), b"account history");
+        assert!(read_file(&path, None).is_err());
+
+        let other = Key::from_hex(&HEX.replace('0', "1")).unwrap();
+        assert!(read_file(&path, Some(&other)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn lines_round_trip() {
+        let path = std::env::temp_dir().join(format!("pe-encrypted-lines-{}", std::process::id()));
+        let key = Key::from_hex(HEX).unwrap();
+        let lines: Vec<_> = ["first receipt", "second receipt"]
+            .into_iter()
+            .map(|line| encrypt_line(&key, line.as_bytes()).unwrap() + "\n")
+            .collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+        assert_eq!(
+            read_file(&path, Some(&key)).unwrap(),
+            b"first receipt\nsecond receipt\n"
+        );
+        assert!(read_file(&path, None)
+            .unwrap_err()
+            .to_string()
+            .ends_with("is encrypted, and needs a key."));
+        assert!(decrypt_line(&key, &lines[0][1..]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    #[test]
+    fn encryption_unavailable() {
+        let key = Key::from_hex(HEX).unwrap();
+        let err = encrypt(&key, b"account history").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Encryption requires the `encryption` feature."
+        );
+    }
+}
@@ -0,0 +1,96 @@
+//! Notable events raised by the engine while applying transactions, for
+//! downstream systems (e.g. risk) to act on.
+
+use crate::account::LockScope;
+use crate::intern::Interned;
+use crate::money::Money;
+use crate::thresholds::ThresholdKind;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EngineEvent {
+    /// A dispute was applied for more than the account's balance can cover,
+    /// typically because the disputed deposit was already withdrawn.
+    DisputeShortfall {
+        /// Sequence number the dispute was applied with.
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// The part of this dispute that isn't covered.
+        shortfall: Money,
+        /// The account's total uncovered disputed funds, including this one.
+        account_shortfall: Money,
+    },
+    /// An operator attached a note to an account, or one of its
+    /// transactions (see [`crate::notes`]).
+    NoteAdded {
+        /// Sequence number of the last transaction applied when the note was
+        /// attached.
+        after_sequence: u64,
+        client: u16,
+        tx: Option<u32>,
+        author: String,
+        note: String,
+    },
+    /// A dispute would have held more than the account's cap (see
+    /// [`crate::EngineConfig::max_held`]), so the account was quarantined
+    /// with the dispute queued for review, rather than the funds held.
+    DisputeReferred {
+        /// Sequence number of the last transaction applied when the dispute
+        /// was referred.
+        after_sequence: u64,
+        client: u16,
+        tx: u32,
+        /// The amount the dispute would have held.
+        held: Money,
+        max_held: Money,
+    },
+    /// An operator unlocked an account (see
+    /// [`crate::TransactionInfo::Unlock`]), for the audit log.
+    AccountUnlocked {
+        /// Sequence number the unlock was applied with.
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        operator: String,
+        /// The lock lifted.
+        scope: LockScope,
+    },
+    /// An applied transaction took one of the client's balances across a
+    /// threshold (see [`crate::thresholds`]).
+    BalanceThresholdCrossed {
+        /// Sequence number of the transaction.
+        sequence: u64,
+        client: u16,
+        /// Currency of the balance, if it has one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        currency: Option<Interned>,
+        threshold: ThresholdKind,
+        /// The threshold's amount.
+        limit: Money,
+        /// The balance's available funds after the transaction.
+        available: Money,
+    },
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn serializes_tagged() {
+        let event = EngineEvent::DisputeShortfall {
+            sequence: 3,
+            client: 1,
+            tx: 2,
+            shortfall: money!(5),
+            account_shortfall: money!(7.5),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"dispute_shortfall","sequence":3,"client":1,"tx":2,"shortfall":"5","account_shortfall":"7.5"}"#
+        );
+    }
+}
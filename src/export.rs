@@ -0,0 +1,317 @@
+use crate::account::{AccountStatement, StatementOptions};
+use crate::account_store::AccountStore;
+use crate::money::Money;
+use crate::notes::AccountNote;
+use crate::transaction::Transaction;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+/// File format for per-account statement bundles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// A statement section followed by a blank line and a history section,
+    /// each with their own header row. Accounts with notes have a further
+    /// blank line and notes section.
+    Csv,
+    /// A single object with `statement` and `transactions` keys, and a
+    /// `notes` key if the account has notes.
+    Json,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ExportFormat::Csv),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(format!("Unknown export format {:?}", s)),
+        }
+    }
+}
+
+/// Where and how to write per-account statement bundles.
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// Directory to write one file per client into. Must already exist.
+    pub dir: PathBuf,
+    pub format: ExportFormat,
+    /// Clients to export. All clients are exported if `None`.
+    pub clients: Option<BTreeSet<u16>>,
+}
+
+impl ExportOptions {
+    /// Whether the given client's history needs to be kept for export.
+    pub fn includes(&self, client_id: u16) -> bool {
+        self.clients
+            .as_ref()
+            .is_none_or(|clients| clients.contains(&client_id))
+    }
+}
+
+/// A single applied transaction, as written to a bundle's history.
+#[derive(Debug, Serialize)]
+struct HistoryEntry<'a> {
+    seq: u64,
+    #[serde(rename = "type")]
+    transaction_type: &'static str,
+    tx: u32,
+    amount: Option<&'a Money>,
+}
+
+impl<'a> From<&'a (u64, Transaction)> for HistoryEntry<'a> {
+    fn from((sequence, src): &'a (u64, Transaction)) -> Self {
+        Self {
+            seq: *sequence,
+            transaction_type: src.info.kind(),
+            tx: src.transaction_id,
+            amount: src.amount(),
+        }
+    }
+}
+
+/// An operator's note, as written to a bundle.
+#[derive(Debug, Serialize)]
+struct NoteEntry<'a> {
+    after_seq: u64,
+    tx: Option<u32>,
+    author: &'a str,
+    note: &'a str,
+}
+
+impl<'a> From<&'a AccountNote> for NoteEntry<'a> {
+    fn from(src: &'a AccountNote) -> Self {
+        Self {
+            after_seq: src.after_sequence,
+            tx: src.tx,
+            author: &src.author,
+            note: &src.text,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct JsonBundle<'a> {
+    statement: &'a AccountStatement,
+    transactions: Vec<HistoryEntry<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    notes: Vec<NoteEntry<'a>>,
+}
+
+/// Writes a bundle for each exported client, containing its final statement,
+/// the transactions applied to it (in applied order) and any notes on it.
+/// Returns the paths written.
+///
+/// `history` holds the applied transactions for each client, with the
+/// sequence numbers they were applied with. Clients
+/// explicitly requested but without an account are skipped. Statements follow
+/// `statement_options`, as for the main output.
+pub fn write_bundles<S: AccountStore>(
+    store: &S,
+    history: &HashMap<u16, Vec<(u64, Transaction)>>,
+    options: &ExportOptions,
+    statement_options: &StatementOptions,
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let statements: Vec<AccountStatement> = match &options.clients {
+        Some(clients) => clients
+            .iter()
+            .filter_map(|client_id| store.get_account(*client_id))
+            .map(|account| account.into())
+            .collect(),
+        None => store.account_statements().collect(),
+    };
+    let mut written = vec![];
+    for statement in statements {
+        let statement = statement.with_options(statement_options);
+        let transactions = history
+            .get(&statement.client())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let notes = store
+            .get_account(statement.client())
+            .map(|account| account.notes())
+            .unwrap_or_default();
+        let extension = match options.format {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Json => "json",
+        };
+        let path = options
+            .dir
+            .join(format!("client_{}.{}", statement.client(), extension));
+        let file = BufWriter::new(File::create(&path)?);
+        match options.format {
+            ExportFormat::Csv => write_csv_bundle(file, &statement, transactions, notes)?,
+            ExportFormat::Json => write_json_bundle(file, &statement, transactions, notes)?,
+        }
+        written.push(path);
+    }
+    Ok(written)
+}
+
+fn write_csv_bundle<W: Write>(
+    mut writer: W,
+    statement: &AccountStatement,
+    transactions: &[(u64, Transaction)],
+    notes: &[AccountNote],
+) -> Result<(), Box<dyn Error>> {
+    let mut csv_writer = csv::Writer::from_writer(&mut writer);
+    csv_writer.serialize(statement)?;
+    csv_writer.flush()?;
+    drop(csv_writer);
+
+    writer.write_all(b"\n")?;
+
+    // Write the header explicitly, as there may be no rows to infer it from.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(&mut writer);
+    csv_writer.write_record(["seq", "type", "tx", "amount"])?;
+    for transaction in transactions {
+        csv_writer.serialize(HistoryEntry::from(transaction))?;
+    }
+    csv_writer.flush()?;
+    drop(csv_writer);
+
+    if notes.is_empty() {
+        return Ok(());
+    }
+    writer.write_all(b"\n")?;
+    let mut csv_writer = csv::Writer::from_writer(&mut writer);
+    for note in notes {
+        csv_writer.serialize(NoteEntry::from(note))?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+fn write_json_bundle<W: Write>(
+    mut writer: W,
+    statement: &AccountStatement,
+    transactions: &[(u64, Transaction)],
+    notes: &[AccountNote],
+) -> Result<(), Box<dyn Error>> {
+    let bundle = JsonBundle {
+        statement,
+        transactions: transactions.iter().map(HistoryEntry::from).collect(),
+        notes: notes.iter().map(NoteEntry::from).collect(),
+    };
+    serde_json::to_writer_pretty(&mut writer, &bundle)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::Account;
+    use crate::money::money;
+    use crate::transaction::TransactionInfo;
+
+    fn history() -> Vec<(u64, Transaction)> {
+        vec![
+            (
+                1,
+                Transaction {
+                    client_id: 1,
+                    transaction_id: 1,
+                    info: TransactionInfo::Deposit(money!(10)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                },
+            ),
+            (
+                3,
+                Transaction {
+                    client_id: 1,
+                    transaction_id: 1,
+                    info: TransactionInfo::Dispute(None),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                },
+            ),
+        ]
+    }
+
+    fn statement() -> AccountStatement {
+        let mut account = Account::new(1);
+        account.set_funds(money!(10), money!(10));
+        AccountStatement::from(&account).with_options(&StatementOptions::default())
+    }
+
+    #[test]
+    fn csv_bundle_layout() {
+        let mut output = vec![];
+        write_csv_bundle(&mut output, &statement(), &history(), &[]).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,0,10,10,false\n\
+             \n\
+             seq,type,tx,amount\n\
+             1,deposit,1,10\n\
+             3,dispute,1,\n"
+        );
+    }
+
+    #[test]
+    fn csv_bundle_without_history() {
+        let mut output = vec![];
+        write_csv_bundle(&mut output, &statement(), &[], &[]).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("\nseq,type,tx,amount\n"));
+    }
+
+    #[test]
+    fn json_bundle_layout() {
+        let mut output = vec![];
+        write_json_bundle(&mut output, &statement(), &history(), &[]).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(value["statement"]["client"], 1);
+        assert_eq!(value["statement"]["held"], "10");
+        assert_eq!(value["transactions"][0]["seq"], 1);
+        assert_eq!(value["transactions"][0]["type"], "deposit");
+        assert_eq!(value["transactions"][0]["amount"], "10");
+        assert_eq!(value["transactions"][1]["type"], "dispute");
+        assert!(value["transactions"][1]["amount"].is_null());
+        assert!(value.get("notes").is_none());
+    }
+
+    fn notes() -> Vec<AccountNote> {
+        vec![AccountNote {
+            tx: Some(1),
+            after_sequence: 3,
+            author: "alice".into(),
+            text: "Disputed by phone, see case 7".into(),
+        }]
+    }
+
+    #[test]
+    fn bundles_with_notes() {
+        let mut output = vec![];
+        write_csv_bundle(&mut output, &statement(), &history(), &notes()).unwrap();
+        assert!(String::from_utf8(output).unwrap().ends_with(
+            "3,dispute,1,\n\
+             \n\
+             after_seq,tx,author,note\n\
+             3,1,alice,\"Disputed by phone, see case 7\"\n"
+        ));
+
+        let mut output = vec![];
+        write_json_bundle(&mut output, &statement(), &history(), &notes()).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(value["notes"][0]["after_seq"], 3);
+        assert_eq!(value["notes"][0]["tx"], 1);
+        assert_eq!(value["notes"][0]["author"], "alice");
+        assert_eq!(value["notes"][0]["note"], "Disputed by phone, see case 7");
+    }
+}
@@ -0,0 +1,130 @@
+//! Fees charged on deposits and withdrawals.
+//!
+//! A [`FeePolicy`] sets a flat fee and a percentage of the amount for each
+//! type of transaction, e.g. in an engine config file:
+//!
+//! ```json
+//! {"fees": {"deposit": {"percent": "0.25"}, "withdrawal": {"flat": "0.5", "percent": "1"}}}
+//! ```
+//!
+//! Fees are charged as the transaction is applied, along with any plugins'
+//! (see [`crate::plugin::TransactionPlugin::fee`]): taken out of deposits,
+//! and withdrawn on top of withdrawals. They're credited to the fee income
+//! system account and recorded against the transaction (see
+//! [`crate::Account::fee`]).
+
+use crate::money::{Money, RoundingMode, OUTPUT_SCALE};
+use crate::transaction::TransactionInfo;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::min;
+
+/// A fee on one type of transaction. No fee by default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Fee {
+    #[serde(deserialize_with = "non_negative")]
+    pub flat: Money,
+    /// Percentage of the amount, e.g. `1.5` for 1.5%.
+    #[serde(deserialize_with = "non_negative")]
+    pub percent: Money,
+}
+
+impl Fee {
+    /// The fee on `amount`, the percentage rounded half to even to the
+    /// precision amounts are reported to. `None` on overflow.
+    pub fn on(&self, amount: &Money) -> Option<Money> {
+        amount
+            .checked_mul(&self.percent)?
+            .checked_mul(&Money::from_scaled(1, 2))?
+            .round_with(OUTPUT_SCALE, RoundingMode::HalfEven)
+            .checked_add(&self.flat)
+    }
+}
+
+impl std::str::FromStr for Fee {
+    type Err = String;
+
+    /// Parses a flat fee, a percentage or both, e.g. `1`, `1.5%` or
+    /// `1+1.5%`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fee = Fee::default();
+        for part in s.split('+') {
+            let (amount, field) = match part.trim().strip_suffix('%') {
+                Some(percent) => (percent, &mut fee.percent),
+                None => (part.trim(), &mut fee.flat),
+            };
+            *field = amount
+                .parse()
+                .ok()
+                .filter(|amount: &Money| !amount.is_negative())
+                .ok_or_else(|| format!("Invalid fee {:?}", s))?;
+        }
+        Ok(fee)
+    }
+}
+
+fn non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+    let amount = Money::deserialize(deserializer)?;
+    if amount.is_negative() {
+        return Err(serde::de::Error::custom("fees can't be negative"));
+    }
+    Ok(amount)
+}
+
+/// Fees by type of transaction (see [`crate::EngineConfig::fees`]).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeePolicy {
+    pub deposit: Fee,
+    pub withdrawal: Fee,
+}
+
+impl FeePolicy {
+    /// The fee to charge on a transaction, zero for other than deposits
+    /// and withdrawals. A deposit's fee is never more than the deposit, so
+    /// the fee can't take more than was paid in. `None` on overflow.
+    pub fn fee(&self, info: &TransactionInfo) -> Option<Money> {
+        match info {
+            TransactionInfo::Deposit(amount) => Some(min(self.deposit.on(amount)?, amount.clone())),
+            TransactionInfo::Withdrawal(amount) => self.withdrawal.on(amount),
+            _ => Some(Money::zero()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn flat_and_percentage_fees() {
+        let policy: FeePolicy = serde_json::from_str(
+            r#"{"deposit": {"flat": "0.1"}, "withdrawal": {"flat": 1, "percent": "1.5"}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            policy.fee(&TransactionInfo::Deposit(money!(10))),
+            Some(money!(0.1))
+        );
+        assert_eq!(
+            policy.fee(&TransactionInfo::Deposit(money!(0.05))),
+            Some(money!(0.05))
+        );
+        assert_eq!(
+            policy.fee(&TransactionInfo::Withdrawal(money!(10.01))),
+            Some(money!(1.1502))
+        );
+        assert_eq!(policy.fee(&TransactionInfo::Resolve), Some(Money::zero()));
+        assert!(serde_json::from_str::<FeePolicy>(r#"{"deposit": {"flat": "-1"}}"#).is_err());
+        assert_eq!("1+1.5%".parse(), Ok(policy.withdrawal));
+        assert_eq!(
+            "0.25%".parse(),
+            Ok(Fee {
+                flat: Money::zero(),
+                percent: money!(0.25)
+            })
+        );
+        assert!("-1".parse::<Fee>().is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Conversions between the currencies of a client's balances.
+//!
+//! A `convert` transaction moves `amount` from the client's balance in the
+//! transaction's `currency` to their balance in `to_currency`. It may supply
+//! the `rate`, in units of `to_currency` per unit of `currency`; otherwise
+//! the engine looks it up with its [`RateProvider`]. The converted amount is
+//! rounded as the target currency's [`Rounding`] says.
+
+use crate::money::{Money, RoundingMode, OUTPUT_SCALE};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+
+/// Where the engine looks up conversion rates that transactions don't
+/// supply, e.g. a live rates feed.
+pub trait RateProvider: Send {
+    /// Units of `to` per unit of `from`, or `None` if there's no rate.
+    fn rate(&self, from: &str, to: &str) -> Option<Money>;
+}
+
+/// Any function of the two currencies, e.g. fixed rates in tests.
+impl<F: Fn(&str, &str) -> Option<Money> + Send> RateProvider for F {
+    fn rate(&self, from: &str, to: &str) -> Option<Money> {
+        self(from, to)
+    }
+}
+
+/// Fixed rates, by currency converted from then currency converted to.
+/// Rates only apply in the direction given, so give both directions to
+/// convert both ways.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct RateTable(BTreeMap<String, BTreeMap<String, Money>>);
+
+impl RateTable {
+    /// Sets the rate from `from` to `to`. Currencies are upper-cased, as in
+    /// transactions.
+    pub fn insert(&mut self, from: &str, to: &str, rate: Money) {
+        self.0
+            .entry(from.to_ascii_uppercase())
+            .or_default()
+            .insert(to.to_ascii_uppercase(), rate);
+    }
+
+    /// Reads a rates file: a CSV with `from`, `to` and `rate` columns.
+    ///
+    /// Rates are configuration rather than input, so any invalid rate is an
+    /// error.
+    pub fn read_csv<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct Row {
+            from: String,
+            to: String,
+            rate: Money,
+        }
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut table = Self::default();
+        for row in csv_reader.deserialize() {
+            let Row { from, to, rate } = row?;
+            if !rate.is_positive() {
+                return Err(format!("Rate from {} to {} must be positive", from, to).into());
+            }
+            table.insert(&from, &to, rate);
+        }
+        Ok(table)
+    }
+}
+
+impl RateProvider for RateTable {
+    fn rate(&self, from: &str, to: &str) -> Option<Money> {
+        self.0.get(from)?.get(to).cloned()
+    }
+}
+
+/// How amounts converted into a currency are rounded.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Rounding {
+    pub decimal_places: u32,
+    #[serde(default)]
+    pub mode: RoundingMode,
+}
+
+/// To the precision amounts are reported to, half to even, as for
+/// currencies without a rule of their own.
+impl Default for Rounding {
+    fn default() -> Self {
+        Self {
+            decimal_places: OUTPUT_SCALE,
+            mode: RoundingMode::HalfEven,
+        }
+    }
+}
+
+impl std::str::FromStr for Rounding {
+    type Err = String;
+
+    /// Parses `<decimal places>[:<mode>]`, e.g. `2:half-up`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (decimal_places, mode) = match s.split_once(':') {
+            Some((decimal_places, mode)) => (decimal_places, mode.parse()?),
+            None => (s, RoundingMode::default()),
+        };
+        let decimal_places = decimal_places
+            .parse()
+            .map_err(|_| format!("Invalid number of decimal places {:?}", decimal_places))?;
+        Ok(Self {
+            decimal_places,
+            mode,
+        })
+    }
+}
+
+/// Rates and rounding rules for conversions (see
+/// [`crate::EngineConfig::fx`]).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FxPolicy {
+    /// Rates for conversions that don't supply their own, unless the
+    /// engine has been given another [`RateProvider`].
+    pub rates: RateTable,
+    /// Rounding of amounts converted into each currency. Others are
+    /// rounded as [`Rounding::default`].
+    pub rounding: BTreeMap<String, Rounding>,
+}
+
+impl FxPolicy {
+    /// How amounts converted into `currency` are rounded.
+    pub fn rounding(&self, currency: &str) -> Rounding {
+        self.rounding.get(currency).copied().unwrap_or_default()
+    }
+
+    /// `amount` converted into `to` at `rate`, rounded for `to`. `None` on
+    /// overflow.
+    pub fn convert(&self, amount: &Money, rate: &Money, to: &str) -> Option<Money> {
+        let Rounding {
+            decimal_places,
+            mode,
+        } = self.rounding(to);
+        Some(amount.checked_mul(rate)?.round_with(decimal_places, mode))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn rates_and_rounding() {
+        let rates =
+            RateTable::read_csv("from,to,rate\nusd, jpy, 151.237\nUSD,EUR,0.9\n".as_bytes())
+                .unwrap();
+        assert_eq!(rates.rate("USD", "JPY"), Some(money!(151.237)));
+        assert_eq!(rates.rate("JPY", "USD"), None);
+        assert!(RateTable::read_csv("from,to,rate\nUSD,EUR,0\n".as_bytes()).is_err());
+
+        let policy = FxPolicy {
+            rates,
+            rounding: BTreeMap::from([
+                ("JPY".to_owned(), "0".parse().unwrap()),
+                ("EUR".to_owned(), "2:down".parse().unwrap()),
+            ]),
+        };
+        assert_eq!(
+            policy.convert(&money!(10.5), &money!(151.237), "JPY"),
+            Some(money!(1588))
+        );
+        assert_eq!(
+            policy.convert(&money!(10.99), &money!(0.9), "EUR"),
+            Some(money!(9.89))
+        );
+        assert_eq!(
+            policy.convert(&money!(1.23456), &money!(1), "GBP"),
+            Some(money!(1.2346))
+        );
+        assert!("2:sideways".parse::<Rounding>().is_err());
+    }
+}
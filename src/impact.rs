@@ -0,0 +1,160 @@
+//! Impact analysis of a proposed policy change, e.g. a new risk limit,
+//! before enabling it: the input is replayed under the current and the
+//! proposed engine configuration side by side (see [`crate::shadow`]), and
+//! summarised as the outcomes that would change and the accounts affected.
+//!
+//! Both engines are discarded afterwards, so nothing is committed under the
+//! proposed configuration.
+
+use crate::shadow::{self, EngineKind, ShadowReport, ShadowSide};
+use crate::transaction_engine::EngineConfig;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// An account the proposed configuration would affect.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AffectedAccount {
+    pub client: u16,
+    /// The client's transactions with a different outcome.
+    pub changed_outcomes: usize,
+    /// Whether the client's final statement differs.
+    pub state_changed: bool,
+}
+
+#[derive(Debug)]
+pub struct ImpactReport {
+    /// Transactions replayed.
+    pub transactions: usize,
+    /// Rows that couldn't be parsed, so weren't replayed.
+    pub malformed: usize,
+    /// Count of transactions whose outcome changes, by their outcome under
+    /// the current and proposed configuration: `applied`, or the name of
+    /// the reason they weren't.
+    pub changes: BTreeMap<(&'static str, &'static str), usize>,
+    /// Accounts affected, ordered by client.
+    pub accounts: Vec<AffectedAccount>,
+}
+
+impl ImpactReport {
+    /// Transactions whose outcome changes.
+    pub fn changed_outcomes(&self) -> usize {
+        self.changes.values().sum()
+    }
+
+    /// Writes the affected accounts as CSV, ordered by client.
+    pub fn write_accounts<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        // Write the header explicitly, as there may be no affected accounts.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        csv_writer.write_record(["client", "changed_outcomes", "state_changed"])?;
+        for account in &self.accounts {
+            csv_writer.serialize(account)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+impl From<ShadowReport> for ImpactReport {
+    fn from(report: ShadowReport) -> Self {
+        let mut changes = BTreeMap::new();
+        let mut accounts: BTreeMap<u16, AffectedAccount> = BTreeMap::new();
+        let affected = |client| AffectedAccount {
+            client,
+            changed_outcomes: 0,
+            state_changed: false,
+        };
+        for divergence in &report.divergences {
+            *changes
+                .entry((divergence.primary, divergence.shadow))
+                .or_default() += 1;
+            accounts
+                .entry(divergence.client)
+                .or_insert_with(|| affected(divergence.client))
+                .changed_outcomes += 1;
+        }
+        for &client in &report.differing_clients {
+            accounts
+                .entry(client)
+                .or_insert_with(|| affected(client))
+                .state_changed = true;
+        }
+        Self {
+            transactions: report.transactions,
+            malformed: report.malformed,
+            changes,
+            accounts: accounts.into_values().collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ImpactReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "transactions:      {}", self.transactions)?;
+        writeln!(f, "malformed:         {}", self.malformed)?;
+        writeln!(f, "outcomes changed:  {}", self.changed_outcomes())?;
+        for ((current, proposed), count) in &self.changes {
+            writeln!(f, "  {} -> {}: {}", current, proposed, count)?;
+        }
+        writeln!(f, "accounts affected: {}", self.accounts.len())
+    }
+}
+
+/// Replays a transactions CSV under the `current` and `proposed`
+/// configurations, reporting the difference the proposed one would make.
+pub fn analyze<R: Read>(
+    input: R,
+    current: &EngineConfig,
+    proposed: &EngineConfig,
+) -> Result<ImpactReport, Box<dyn Error>> {
+    let side = |config: &EngineConfig| ShadowSide {
+        engine: EngineKind::Single,
+        config: config.clone(),
+    };
+    Ok(shadow::run(input, &side(current), &side(proposed))?.into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn limit_change_impact() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,100\n\
+                     withdrawal,1,2,60\n\
+                     withdrawal,1,3,30\n\
+                     deposit,2,4,100\n\
+                     withdrawal,2,5,10\n\
+                     deposit,3,6,100\n\
+                     withdrawal,3,7,80\n";
+        let current = EngineConfig::default();
+        let mut proposed = current.clone();
+        proposed.risk.max_withdrawal = Some(money!(50));
+        let report = analyze(input.as_bytes(), &current, &proposed).unwrap();
+        assert_eq!(report.transactions, 7);
+        assert_eq!(report.changed_outcomes(), 2);
+        assert_eq!(report.changes[&("applied", "LimitExceeded")], 2);
+        let affected = |client| AffectedAccount {
+            client,
+            changed_outcomes: 1,
+            state_changed: true,
+        };
+        assert_eq!(report.accounts, [affected(1), affected(3)]);
+
+        let mut output = vec![];
+        report.write_accounts(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,changed_outcomes,state_changed\n1,1,true\n3,1,true\n"
+        );
+
+        let report = analyze(input.as_bytes(), &current, &current).unwrap();
+        assert_eq!(report.changed_outcomes(), 0);
+        assert!(report.accounts.is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+//! Ingestion profiles: how each partner's feed is read.
+//!
+//! Partners' feeds differ in small ways: columns named their own way,
+//! amounts written with a decimal comma, rows that leave the currency out.
+//! Rather than rewrite each feed before it's run, a profile describes its
+//! quirks, e.g.
+//!
+//! ```json
+//! {
+//!   "partner-x": {
+//!     "paths": ["partner-x/*", "px_*.csv"],
+//!     "columns": {"kind": "type", "customer": "client", "ref": "tx", "value": "amount"},
+//!     "locale": "de-DE",
+//!     "currency": "EUR",
+//!     "strictness": "strict",
+//!     "dedup": "exact"
+//!   }
+//! }
+//! ```
+//!
+//! A run uses the profile it's told to, or else the first (by name) with a
+//! path pattern matching its input.
+
+use crate::input::{OPTIONAL_COLUMNS, REQUIRED_COLUMNS};
+use crate::transaction::TransactionRaw;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Profiles by name, as in a profiles file.
+pub type Profiles = BTreeMap<String, IngestionProfile>;
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestionProfile {
+    /// Patterns of the input paths the profile applies to, where `*`
+    /// matches anything. Patterns with a `/` are matched against the whole
+    /// path, others against the file name.
+    pub paths: Vec<String>,
+    /// The feed's names for columns (or JSON Lines fields), mapped to ours.
+    pub columns: BTreeMap<String, String>,
+    /// How the feed writes amounts and rates.
+    pub locale: Locale,
+    /// Currency of rows that don't name one.
+    pub currency: Option<String>,
+    pub strictness: Strictness,
+    pub dedup: DedupPolicy,
+}
+
+/// How much of a feed may be unreadable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strictness {
+    /// Unreadable rows are counted and malformed ones rejected, and
+    /// columns we don't read are ignored.
+    #[default]
+    Lenient,
+    /// Any unreadable or malformed row, or column we don't read, fails the
+    /// run: the feed isn't what the profile says it is.
+    Strict,
+}
+
+/// Which repeated transactions (same client, transaction ID, type and
+/// amount) are skipped rather than applied again. As a transaction may be
+/// disputed again once resolved, a dispute, resolve or chargeback is only a
+/// repeat of the last of those read for its transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupPolicy {
+    /// Repeats of transactions from earlier inputs, as inputs exported by
+    /// time window may overlap at their boundaries.
+    #[default]
+    AcrossInputs,
+    /// Any repeat, including within an input, for feeds known to resend
+    /// rows.
+    Exact,
+    /// None: every row is applied (or rejected) as usual.
+    Off,
+}
+
+/// How a feed writes numbers, from a language tag such as `de-DE`.
+///
+/// Amounts are written with a decimal comma in much of Europe and South
+/// America, and with a decimal point elsewhere; Swiss feeds use a point,
+/// grouping thousands with apostrophes. Thousands may also be grouped with
+/// spaces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Locale {
+    pub tag: String,
+    pub decimal: char,
+    pub grouping: char,
+}
+
+/// Languages written with a decimal comma.
+const DECIMAL_COMMA_LANGUAGES: &[&str] = &[
+    "bg", "ca", "cs", "da", "de", "el", "es", "et", "fi", "fr", "hr", "hu", "id", "it", "lt", "lv",
+    "nb", "nl", "nn", "no", "pl", "pt", "ro", "ru", "sk", "sl", "sr", "sv", "tr", "uk", "vi",
+];
+
+/// Regions written with a decimal point whatever the language.
+const DECIMAL_POINT_REGIONS: &[&str] = &["CH", "LI"];
+
+/// As in the engine's own input: `1234.5`.
+impl Default for Locale {
+    fn default() -> Self {
+        Self {
+            tag: "en".to_owned(),
+            decimal: '.',
+            grouping: ',',
+        }
+    }
+}
+
+impl std::str::FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut subtags = s.split(['-', '_']);
+        let language = subtags.next().unwrap_or_default().to_ascii_lowercase();
+        if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic())
+        {
+            return Err(format!("Invalid locale {:?}", s));
+        }
+        let region = subtags.next().map(str::to_ascii_uppercase);
+        let (decimal, grouping) = match region.as_deref() {
+            Some(region) if DECIMAL_POINT_REGIONS.contains(&region) => ('.', '\''),
+            _ if DECIMAL_COMMA_LANGUAGES.contains(&language.as_str()) => (',', '.'),
+            _ => ('.', ','),
+        };
+        Ok(Self {
+            tag: s.to_owned(),
+            decimal,
+            grouping,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Locale {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Locale {
+    /// `number` as the engine reads numbers, or `None` if its thousands
+    /// aren't grouped in threes, as then it's more likely in another
+    /// locale than grouped oddly: `1.5` in German is an error, not 15.
+    pub fn normalise(&self, number: &str) -> Option<String> {
+        let number: String = number
+            .chars()
+            .filter(|c| !matches!(c, ' ' | '\u{a0}' | '\u{202f}'))
+            .collect();
+        let (whole, fraction) = match number.split_once(self.decimal) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (number.as_str(), None),
+        };
+        let (sign, digits) = match whole.strip_prefix(['-', '+']) {
+            Some(digits) => (&whole[..1], digits),
+            None => ("", whole),
+        };
+        let mut groups = digits.split(self.grouping);
+        let mut normalised = sign.to_owned();
+        normalised.push_str(groups.next().unwrap_or_default());
+        for group in groups {
+            if normalised.len() == sign.len() || group.len() != 3 {
+                return None;
+            }
+            normalised.push_str(group);
+        }
+        if let Some(fraction) = fraction {
+            normalised.push('.');
+            normalised.push_str(fraction);
+        }
+        Some(normalised)
+    }
+
+    /// `number`, written as the engine writes numbers, in the locale's
+    /// notation: the inverse of [`Locale::normalise`].
+    pub fn format(&self, number: &str) -> String {
+        let (whole, fraction) = match number.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (number, None),
+        };
+        let digits = whole.trim_start_matches(['-', '+']);
+        let mut formatted = whole[..whole.len() - digits.len()].to_owned();
+        for (i, digit) in digits.chars().enumerate() {
+            if i > 0 && (digits.len() - i) % 3 == 0 {
+                formatted.push(self.grouping);
+            }
+            formatted.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            formatted.push(self.decimal);
+            formatted.push_str(fraction);
+        }
+        formatted
+    }
+}
+
+impl IngestionProfile {
+    /// Whether the profile applies to the input at `path`.
+    pub fn matches(&self, path: &Path) -> bool {
+        let whole = path.to_string_lossy();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy())
+            .unwrap_or_default();
+        self.paths.iter().any(|pattern| {
+            let text = if pattern.contains('/') { &whole } else { &name };
+            wildcard_match(pattern, text)
+        })
+    }
+
+    /// Reads a transaction as the engine would have been given it: amounts
+    /// in the engine's notation and the default currency filled in. Fails
+    /// if an amount or rate isn't a number in the profile's locale.
+    pub fn apply(&self, raw: &mut TransactionRaw) -> Result<(), String> {
+        for number in [&mut raw.amount, &mut raw.rate].into_iter().flatten() {
+            *number = self
+                .locale
+                .normalise(number)
+                .ok_or_else(|| format!("{:?} isn't a number in {}", number, self.locale.tag))?;
+        }
+        if let Some(currency) = &self.currency {
+            if raw.currency.as_deref().is_none_or(|c| c.trim().is_empty()) {
+                raw.currency = Some(currency.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of
+/// characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcards.
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// Reads a profiles file. Columns must map to ones the engine reads.
+pub fn read_profiles(path: &Path) -> Result<Profiles, Box<dyn Error>> {
+    let profiles: Profiles = serde_json::from_reader(BufReader::new(File::open(path)?))
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    for (name, profile) in &profiles {
+        for column in profile.columns.values() {
+            if !REQUIRED_COLUMNS.contains(&column.as_str())
+                && !OPTIONAL_COLUMNS.contains(&column.as_str())
+            {
+                return Err(
+                    format!("Profile {} maps a column to unknown {:?}", name, column).into(),
+                );
+            }
+        }
+    }
+    Ok(profiles)
+}
+
+/// The profile named, or failing that the first whose paths match `input`.
+pub fn select<'a>(
+    profiles: &'a Profiles,
+    name: Option<&str>,
+    input: &Path,
+) -> Result<Option<(&'a str, &'a IngestionProfile)>, Box<dyn Error>> {
+    match name {
+        Some(name) => match profiles.get_key_value(name) {
+            Some((name, profile)) => Ok(Some((name, profile))),
+            None => Err(format!("No ingestion profile named {}", name).into()),
+        },
+        None => Ok(profiles
+            .iter()
+            .find(|(_, profile)| profile.matches(input))
+            .map(|(name, profile)| (name.as_str(), profile))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn profiles_and_locales() {
+        let profiles: Profiles = serde_json::from_str(
+            r#"{
+                "partner-x": {"paths": ["px_*.csv"], "locale": "de-DE", "currency": "eur"},
+                "partner-y": {"paths": ["feeds/y/*"], "locale": "fr-CH"}
+            }"#,
+        )
+        .unwrap();
+        let select = |name, path: &str| {
+            select(&profiles, name, Path::new(path))
+                .unwrap()
+                .map(|(name, _)| name)
+        };
+        assert_eq!(select(None, "in/px_2024-01.csv"), Some("partner-x"));
+        assert_eq!(select(None, "in/px_2024-01.jsonl"), None);
+        assert_eq!(select(None, "feeds/y/today.csv"), Some("partner-y"));
+        assert_eq!(select(Some("partner-y"), "px_1.csv"), Some("partner-y"));
+        assert!(super::select(&profiles, Some("partner-z"), Path::new("x")).is_err());
+
+        let german = &profiles["partner-x"].locale;
+        assert_eq!(german.normalise("1.234,5").as_deref(), Some("1234.5"));
+        assert_eq!(german.normalise("-12 345,67").as_deref(), Some("-12345.67"));
+        assert_eq!(german.normalise("1.5"), None);
+        assert_eq!(german.normalise(".500"), None);
+        let swiss = &profiles["partner-y"].locale;
+        assert_eq!(swiss.normalise("1'234.5").as_deref(), Some("1234.5"));
+        assert_eq!(german.format("-1234567.89"), "-1.234.567,89");
+        assert_eq!(german.format("123"), "123");
+        assert_eq!(swiss.format("1234.5"), "1'234.5");
+        assert_eq!(
+            Locale::default().normalise("1,234.5").as_deref(),
+            Some("1234.5")
+        );
+        assert!("d3".parse::<Locale>().is_err());
+
+        let mut raw =
+            crate::input::parse_json(br#"{"type":"deposit","client":1,"tx":1,"amount":"2,5"}"#)
+                .unwrap();
+        profiles["partner-x"].apply(&mut raw).unwrap();
+        assert_eq!(raw.amount.as_deref(), Some("2.5"));
+        assert_eq!(raw.currency.as_deref(), Some("eur"));
+        raw.amount = Some("2.5".into());
+        assert!(profiles["partner-x"].apply(&mut raw).is_err());
+    }
+}
@@ -0,0 +1,600 @@
+//! Formats transactions can be read in.
+//!
+//! Besides CSV, transactions can be read as JSON Lines (newline-delimited
+//! JSON): one object per line, with the same fields as the CSV columns,
+//! e.g.
+//!
+//! ```json
+//! {"type":"deposit","client":1,"tx":1,"amount":"1.5"}
+//! {"type":"dispute","client":1,"tx":1,"reason":"fraud"}
+//! ```
+//!
+//! Amounts may be strings or numbers. Strings are taken digit for digit, as
+//! in CSV; numbers go through a binary float first, so strings are safer
+//! for amounts with many significant digits.
+//!
+//! [`detect`] works out an input's format, and for CSV its delimiter, from
+//! its first few kilobytes, so operators needn't say which each feed uses.
+//!
+//! Feeds naming columns their own way are read with their names mapped to
+//! ours (see [`crate::ingestion`]).
+
+use crate::transaction::TransactionRaw;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    /// CSV with a header row, its fields separated by `delimiter`.
+    Csv {
+        delimiter: u8,
+    },
+    JsonLines,
+}
+
+impl InputFormat {
+    /// Comma-separated CSV.
+    pub const CSV: InputFormat = InputFormat::Csv { delimiter: b',' };
+}
+
+impl Default for InputFormat {
+    fn default() -> Self {
+        InputFormat::CSV
+    }
+}
+
+impl std::str::FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::CSV),
+            "jsonl" | "ndjson" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("Unknown input format {:?}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for InputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InputFormat::Csv { delimiter: b'\t' } => write!(f, "csv, tab-delimited"),
+            InputFormat::Csv { delimiter } => {
+                write!(f, "csv, delimited by {:?}", char::from(*delimiter))
+            }
+            InputFormat::JsonLines => write!(f, "jsonl"),
+        }
+    }
+}
+
+/// Columns (or JSON Lines fields) every transaction has.
+pub const REQUIRED_COLUMNS: &[&str] = &["type", "client", "tx"];
+
+/// Columns (or fields) transactions may have.
+pub const OPTIONAL_COLUMNS: &[&str] = &[
+    "amount",
+    "reason",
+    "date",
+    "destination",
+    "operator",
+    "timestamp",
+    "currency",
+    "to_currency",
+    "rate",
+];
+
+/// Whether the engine reads `column`.
+fn is_known(column: &str) -> bool {
+    REQUIRED_COLUMNS.contains(&column) || OPTIONAL_COLUMNS.contains(&column)
+}
+
+/// Each of `names` mapped to ours by `renames`, the feed's names for them.
+fn map_names<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    renames: &'a BTreeMap<String, String>,
+) -> impl Iterator<Item = &'a str> {
+    names
+        .into_iter()
+        .map(|name| renames.get(name).map_or(name, String::as_str))
+}
+
+/// How much of an input [`detect`] is given to look at.
+pub const DETECTION_BYTES: usize = 16 * 1024;
+
+/// Delimiters CSV input is detected with, in order of preference.
+const DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// What [`detect`] made of an input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub format: InputFormat,
+    /// Optional columns the input has (for JSON Lines, that any object in
+    /// the bytes looked at has).
+    pub optional_columns: Vec<String>,
+    /// Columns the input has that aren't read, e.g. a misspelt one.
+    pub ignored_columns: Vec<String>,
+}
+
+impl std::fmt::Display for Detection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format)?;
+        if !self.optional_columns.is_empty() {
+            write!(f, ", with {}", self.optional_columns.join(", "))?;
+        }
+        if !self.ignored_columns.is_empty() {
+            write!(f, ", ignoring {}", self.ignored_columns.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Works out an input's format from (up to [`DETECTION_BYTES`] of) its
+/// first bytes: JSON Lines if the first line is an object, otherwise CSV
+/// with whichever delimiter splits the header into the most columns.
+/// Parquet (by its magic bytes) is recognised, but not supported.
+///
+/// Fails if the input is missing any [`REQUIRED_COLUMNS`], so nothing in it
+/// could be read.
+pub fn detect(prefix: &[u8]) -> Result<Detection, String> {
+    detect_with(prefix, &BTreeMap::new())
+}
+
+/// As [`detect`], for an input whose columns are renamed as in `renames`
+/// (the feed's names for them, mapped to ours).
+pub fn detect_with(prefix: &[u8], renames: &BTreeMap<String, String>) -> Result<Detection, String> {
+    if prefix.starts_with(b"PAR1") {
+        return Err("Input is Parquet, which isn't supported. \
+                    Convert it to CSV or JSON Lines."
+            .to_owned());
+    }
+    let prefix = prefix.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(prefix);
+    let mut lines = prefix
+        .split(|byte| *byte == b'\n')
+        .map(|line| String::from_utf8_lossy(line).trim().to_owned())
+        .filter(|line| !line.is_empty());
+    let Some(first) = lines.next() else {
+        // Nothing to read, in any format.
+        return Ok(Detection {
+            format: InputFormat::default(),
+            optional_columns: vec![],
+            ignored_columns: vec![],
+        });
+    };
+    let (format, columns) = if first.starts_with('{') {
+        // Fields may only appear on some rows, so take every object that
+        // was read whole.
+        let mut columns: Vec<String> = vec![];
+        for line in std::iter::once(first).chain(lines) {
+            let Ok(object) = serde_json::from_str::<serde_json::Map<_, _>>(&line) else {
+                continue;
+            };
+            for key in object.keys() {
+                if !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+        }
+        (InputFormat::JsonLines, columns)
+    } else {
+        let split = |delimiter: u8| -> Vec<String> {
+            first
+                .split(char::from(delimiter))
+                .map(|column| column.trim().to_owned())
+                .collect()
+        };
+        let delimiter = DELIMITERS
+            .into_iter()
+            .rev()
+            .max_by_key(|delimiter| split(*delimiter).len())
+            .expect("There are delimiters to choose from");
+        (InputFormat::Csv { delimiter }, split(delimiter))
+    };
+    let columns: Vec<String> = map_names(columns.iter().map(String::as_str), renames)
+        .map(str::to_owned)
+        .collect();
+    // JSON Lines objects that weren't read whole don't count against the
+    // input.
+    if !(format == InputFormat::JsonLines && columns.is_empty()) {
+        let missing: Vec<&str> = REQUIRED_COLUMNS
+            .iter()
+            .filter(|column| !columns.iter().any(|found| found == *column))
+            .copied()
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!(
+                "Input ({}) is missing {}",
+                format,
+                missing.join(", ")
+            ));
+        }
+    }
+    let (optional_columns, ignored_columns) = columns
+        .into_iter()
+        .filter(|column| !REQUIRED_COLUMNS.contains(&column.as_str()))
+        .partition(|column| OPTIONAL_COLUMNS.contains(&column.as_str()));
+    Ok(Detection {
+        format,
+        optional_columns,
+        ignored_columns,
+    })
+}
+
+/// Detects the format of the file at `path` (see [`detect`]).
+pub fn detect_file(path: &Path) -> Result<Detection, Box<dyn Error>> {
+    detect_file_with(path, &BTreeMap::new())
+}
+
+/// As [`detect_file`], with columns renamed as for [`detect_with`].
+pub fn detect_file_with(
+    path: &Path,
+    renames: &BTreeMap<String, String>,
+) -> Result<Detection, Box<dyn Error>> {
+    let mut prefix = Vec::with_capacity(DETECTION_BYTES);
+    File::open(path)?
+        .take(DETECTION_BYTES as u64)
+        .read_to_end(&mut prefix)?;
+    Ok(detect_with(&prefix, renames).map_err(|err| format!("{}: {}", path.display(), err))?)
+}
+
+/// A transaction as written in JSON Lines input.
+#[derive(Debug, Deserialize)]
+struct TransactionJson {
+    #[serde(rename = "type")]
+    transaction_type: String,
+    client: u16,
+    tx: u32,
+    #[serde(default, deserialize_with = "string_or_number")]
+    amount: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+    #[serde(default)]
+    date: Option<String>,
+    #[serde(default)]
+    destination: Option<String>,
+    #[serde(default)]
+    operator: Option<String>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    timestamp: Option<String>,
+    #[serde(default)]
+    currency: Option<String>,
+    #[serde(default)]
+    to_currency: Option<String>,
+    #[serde(default, deserialize_with = "string_or_number")]
+    rate: Option<String>,
+}
+
+impl From<TransactionJson> for TransactionRaw {
+    fn from(value: TransactionJson) -> Self {
+        TransactionRaw {
+            transaction_type: value.transaction_type,
+            client: value.client,
+            tx: value.tx,
+            amount: value.amount,
+            reason: value.reason,
+            date: value.date,
+            destination: value.destination,
+            operator: value.operator,
+            timestamp: value.timestamp,
+            currency: value.currency,
+            to_currency: value.to_currency,
+            rate: value.rate,
+        }
+    }
+}
+
+/// Takes an amount, rate or timestamp written as a string or a number.
+fn string_or_number<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Amount {
+        String(String),
+        Number(serde_json::Number),
+    }
+    Ok(
+        Option::<Amount>::deserialize(deserializer)?.map(|amount| match amount {
+            Amount::String(amount) => amount,
+            Amount::Number(amount) => amount.to_string(),
+        }),
+    )
+}
+
+/// Parses a transaction written as a single JSON object, as in JSON Lines
+/// input.
+pub(crate) fn parse_json(bytes: &[u8]) -> Result<TransactionRaw, Box<dyn Error>> {
+    Ok(serde_json::from_slice::<TransactionJson>(bytes)?.into())
+}
+
+/// As [`parse_json`], with fields renamed as in `renames`. If `strict`,
+/// fields the engine doesn't read are errors rather than ignored.
+fn parse_json_renamed(
+    bytes: &[u8],
+    renames: &BTreeMap<String, String>,
+    strict: bool,
+) -> Result<TransactionRaw, Box<dyn Error>> {
+    let mut object = Map::new();
+    for (field, value) in serde_json::from_slice::<Map<String, Value>>(bytes)? {
+        let field = renames.get(&field).cloned().unwrap_or(field);
+        if strict && !is_known(&field) {
+            return Err(format!("Unknown field {:?}", field).into());
+        }
+        object.insert(field, value);
+    }
+    Ok(serde_json::from_value::<TransactionJson>(Value::Object(object))?.into())
+}
+
+/// Reads transactions from JSON Lines input. Blank lines are skipped.
+pub(crate) struct JsonLinesReader<R> {
+    reader: BufReader<R>,
+    line: String,
+    /// Lines read so far, including blank ones.
+    lines: u64,
+    bytes: u64,
+    /// Set after a read error, which may well repeat.
+    failed: bool,
+    /// The feed's names for fields, mapped to ours.
+    renames: BTreeMap<String, String>,
+    /// Whether fields the engine doesn't read make a line unreadable.
+    strict: bool,
+}
+
+impl<R: Read> JsonLinesReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: String::new(),
+            lines: 0,
+            bytes: 0,
+            failed: false,
+            renames: BTreeMap::new(),
+            strict: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for JsonLinesReader<R> {
+    type Item = Result<TransactionRaw, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            self.line.clear();
+            let read = match self.reader.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(err) => {
+                    self.failed = true;
+                    return Some(Err(err.into()));
+                }
+            };
+            self.lines += 1;
+            self.bytes += read as u64;
+            if self.line.trim().is_empty() {
+                continue;
+            }
+            if self.renames.is_empty() && !self.strict {
+                return Some(parse_json(self.line.as_bytes()));
+            }
+            return Some(parse_json_renamed(
+                self.line.as_bytes(),
+                &self.renames,
+                self.strict,
+            ));
+        }
+        None
+    }
+}
+
+/// Reads transactions in either format, tracking how far it's read for the
+/// run manifest.
+pub(crate) enum TransactionReader<R: Read> {
+    Csv(csv::Reader<R>),
+    JsonLines(JsonLinesReader<R>),
+}
+
+impl<R: Read> TransactionReader<R> {
+    pub(crate) fn new(reader: R, format: InputFormat) -> Self {
+        match format {
+            InputFormat::Csv { delimiter } => TransactionReader::Csv(
+                csv::ReaderBuilder::new()
+                    .has_headers(true)
+                    .delimiter(delimiter)
+                    // allow missing fields
+                    .flexible(true)
+                    .trim(csv::Trim::All)
+                    .from_reader(reader),
+            ),
+            InputFormat::JsonLines => TransactionReader::JsonLines(JsonLinesReader::new(reader)),
+        }
+    }
+
+    /// Reads columns (or fields) by the feed's names for them, mapped to
+    /// ours in `renames`. If `strict`, columns the engine doesn't read are
+    /// errors: for CSV, failing here; for JSON Lines, making the lines with
+    /// them unreadable.
+    pub(crate) fn rename_columns(
+        &mut self,
+        renames: &BTreeMap<String, String>,
+        strict: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            TransactionReader::Csv(reader) => {
+                let headers: csv::StringRecord =
+                    map_names(reader.headers()?.iter(), renames).collect();
+                if strict {
+                    if let Some(unknown) = headers.iter().find(|column| !is_known(column)) {
+                        return Err(format!("Unknown column {:?}", unknown).into());
+                    }
+                }
+                reader.set_headers(headers);
+            }
+            TransactionReader::JsonLines(reader) => {
+                reader.renames = renames.clone();
+                reader.strict = strict;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with each transaction read, or the reason it couldn't be.
+    pub(crate) fn for_each(
+        &mut self,
+        mut f: impl FnMut(Result<TransactionRaw, Box<dyn Error>>) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.for_each_at(|transaction, _| f(transaction))
+    }
+
+    /// As [`TransactionReader::for_each`], also passing the position after
+    /// each row (see [`TransactionReader::position`]).
+    pub(crate) fn for_each_at(
+        &mut self,
+        mut f: impl FnMut(
+            Result<TransactionRaw, Box<dyn Error>>,
+            (u64, u64),
+        ) -> Result<(), Box<dyn Error>>,
+    ) -> Result<(), Box<dyn Error>> {
+        match self {
+            TransactionReader::Csv(reader) => {
+                let mut transactions = reader.deserialize::<TransactionRaw>();
+                while let Some(transaction) = transactions.next() {
+                    let position = transactions.reader().position();
+                    f(
+                        transaction.map_err(Into::into),
+                        (position.record(), position.byte()),
+                    )?;
+                }
+            }
+            TransactionReader::JsonLines(reader) => {
+                while let Some(transaction) = reader.next() {
+                    f(transaction, (reader.lines, reader.bytes))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records (including a CSV header) and bytes read so far.
+    pub(crate) fn position(&self) -> (u64, u64) {
+        match self {
+            TransactionReader::Csv(reader) => {
+                (reader.position().record(), reader.position().byte())
+            }
+            TransactionReader::JsonLines(reader) => (reader.lines, reader.bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_lines() {
+        let input = "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1.2500\"}\n\
+                     \n\
+                     {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"amount\":0.5,\"date\":\"2024-03-01\",\"destination\":\"wallet-9\"}\n\
+                     {\"type\":\"dispute\",\"client\":1,\"tx\":1,\"reason\":\"fraud\"}\n\
+                     {\"type\":\"deposit\",\"client\":\"one\",\"tx\":3}\n";
+        let mut reader = TransactionReader::new(input.as_bytes(), InputFormat::JsonLines);
+        let mut transactions = vec![];
+        let mut unreadable = 0;
+        reader
+            .for_each(|transaction| {
+                match transaction {
+                    Ok(transaction) => transactions.push(transaction),
+                    Err(_) => unreadable += 1,
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(unreadable, 1);
+        assert_eq!(
+            transactions,
+            vec![
+                TransactionRaw {
+                    transaction_type: "deposit".into(),
+                    client: 1,
+                    tx: 1,
+                    amount: Some("1.2500".into()),
+                    reason: None,
+                    date: None,
+                    destination: None,
+                    operator: None,
+                    timestamp: None,
+                    currency: None,
+                    to_currency: None,
+                    rate: None,
+                },
+                TransactionRaw {
+                    transaction_type: "withdrawal".into(),
+                    client: 1,
+                    tx: 2,
+                    amount: Some("0.5".into()),
+                    reason: None,
+                    date: Some("2024-03-01".into()),
+                    destination: Some("wallet-9".into()),
+                    operator: None,
+                    timestamp: None,
+                    currency: None,
+                    to_currency: None,
+                    rate: None,
+                },
+                TransactionRaw {
+                    transaction_type: "dispute".into(),
+                    client: 1,
+                    tx: 1,
+                    amount: None,
+                    reason: Some("fraud".into()),
+                    date: None,
+                    destination: None,
+                    operator: None,
+                    timestamp: None,
+                    currency: None,
+                    to_currency: None,
+                    rate: None,
+                },
+            ]
+        );
+        assert_eq!(reader.position(), (5, input.len() as u64));
+    }
+
+    #[test]
+    fn detects_formats() {
+        let detection = detect(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\n").unwrap();
+        assert_eq!(detection.format, InputFormat::CSV);
+        assert_eq!(detection.optional_columns, ["amount"]);
+
+        let detection = detect(b"\xEF\xBB\xBFtype;client;tx;amount;timestamp;note\n").unwrap();
+        assert_eq!(detection.format, InputFormat::Csv { delimiter: b';' });
+        assert_eq!(detection.optional_columns, ["amount", "timestamp"]);
+        assert_eq!(detection.ignored_columns, ["note"]);
+        assert_eq!(
+            detection.to_string(),
+            "csv, delimited by ';', with amount, timestamp, ignoring note"
+        );
+        let detection = detect(b"type\tclient\ttx\n").unwrap();
+        assert_eq!(detection.to_string(), "csv, tab-delimited");
+
+        // Fields on any row count, but a line cut off doesn't.
+        let detection = detect(
+            b"\n{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":\"1\"}\n\
+              {\"type\":\"dispute\",\"client\":1,\"tx\":1,\"reason\":\"fraud\"}\n\
+              {\"type\":\"withdrawal\",\"client\":1,\"tx\":2,\"destin",
+        )
+        .unwrap();
+        assert_eq!(detection.format, InputFormat::JsonLines);
+        assert_eq!(detection.optional_columns, ["amount", "reason"]);
+
+        assert_eq!(detect(b"").unwrap().format, InputFormat::CSV);
+        assert!(detect(b"PAR1\x15\x04").unwrap_err().contains("Parquet"));
+        assert_eq!(
+            detect(b"kind,client,id\n").unwrap_err(),
+            "Input (csv, delimited by ',') is missing type, tx"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Read-only queries against a saved engine state snapshot (see
+//! [`crate::snapshot::read_snapshot`]).
+
+use crate::account::{Account, AccountStatement, DisputeStatus, StatementOptions, StatementOrder};
+use crate::account_store::AccountStore;
+use serde::Serialize;
+use std::error::Error;
+use std::io::Write;
+
+/// Which accounts to select. Every filter given must match.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InspectQuery {
+    /// Only this client's account.
+    pub client: Option<u16>,
+    /// Only locked accounts.
+    pub locked: bool,
+    /// Only accounts with a deposit currently disputed.
+    pub disputed: bool,
+}
+
+impl InspectQuery {
+    pub fn matches(&self, account: &Account) -> bool {
+        self.client.is_none_or(|client| client == account.client())
+            && (!self.locked || account.locked())
+            && (!self.disputed
+                || account
+                    .transaction_history()
+                    .iter()
+                    .any(|(_, record)| record.dispute_status() == DisputeStatus::Disputed))
+    }
+}
+
+/// Statements for the accounts matching `query`, ordered by client.
+pub fn select<S: AccountStore>(store: &S, query: &InspectQuery) -> Vec<AccountStatement> {
+    let mut statements: Vec<AccountStatement> = store
+        .accounts()
+        .filter(|account| query.matches(account))
+        .map(AccountStatement::from)
+        .collect();
+    statements.sort_by_key(|statement| statement.client());
+    statements
+}
+
+/// The most statements a single [`page`] returns, so responses stay bounded
+/// however many accounts match.
+pub const MAX_PAGE_SIZE: usize = 1_000;
+
+/// A page of statements, with the cursor to fetch the next page from.
+#[derive(Debug, Serialize)]
+pub struct Page {
+    pub statements: Vec<AccountStatement>,
+    /// The client to fetch the next page after, or `None` on the last page.
+    pub next: Option<u16>,
+}
+
+/// Up to `limit` (at most [`MAX_PAGE_SIZE`]) statements for the accounts
+/// matching `query`, in `order`, following the account of client `after`
+/// if given.
+///
+/// In client order, pages are read from the store with
+/// [`AccountStore::accounts_after`], so only the accounts up to the end of
+/// the page are visited. Other orders sort every matching account, and fail
+/// if the `after` client no longer matches.
+pub fn page<S: AccountStore>(
+    store: &S,
+    query: &InspectQuery,
+    order: StatementOrder,
+    after: Option<u16>,
+    limit: usize,
+) -> Result<Page, String> {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE);
+    let mut statements: Vec<AccountStatement> = match order {
+        StatementOrder::Client => store
+            .accounts_after(after)
+            .filter(|account| query.matches(account))
+            .take(limit + 1)
+            .map(AccountStatement::from)
+            .collect(),
+        _ => {
+            let mut statements = select(store, query);
+            skip_to_cursor(&mut statements, order, after)?;
+            statements.truncate(limit + 1);
+            statements
+        }
+    };
+    let next = if statements.len() > limit {
+        statements.truncate(limit);
+        statements.last().map(AccountStatement::client)
+    } else {
+        None
+    };
+    Ok(Page { statements, next })
+}
+
+/// Sorts `statements` into `order` and drops those up to and including the `after` client's. Fails if the
+/// `after` client's isn't among them.
+pub(crate) fn skip_to_cursor(
+    statements: &mut Vec<AccountStatement>,
+    order: StatementOrder,
+    after: Option<u16>,
+) -> Result<(), String> {
+    order.sort(statements);
+    let start = match after {
+        Some(after) => {
+            statements
+                .iter()
+                .position(|statement| statement.client() == after)
+                .ok_or_else(|| format!("Cursor client {} no longer matches.", after))?
+                + 1
+        }
+        None => 0,
+    };
+    statements.drain(..start);
+    Ok(())
+}
+
+/// Writes the selected statements as CSV, with the default statement
+/// columns.
+pub fn write_statements<W: Write>(
+    writer: W,
+    statements: Vec<AccountStatement>,
+) -> Result<(), Box<dyn Error>> {
+    let options = StatementOptions::default();
+    // Write the header explicitly, as there may be no matches.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(AccountStatement::header(&options))?;
+    for statement in statements {
+        csv_writer.serialize(statement.with_options(&options))?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::{money, Money};
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+
+    #[test]
+    fn query_snapshot() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        for (client_id, transaction_id, info) in [
+            (1, 1, TransactionInfo::Deposit(money!(10))),
+            (2, 2, TransactionInfo::Deposit(money!(5))),
+            (2, 2, TransactionInfo::Dispute(None)),
+            (3, 3, TransactionInfo::Deposit(money!(7))),
+            (3, 3, TransactionInfo::Dispute(None)),
+            (3, 3, TransactionInfo::Chargeback),
+        ] {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id,
+                    info,
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let clients = |query: InspectQuery| {
+            select(engine.store(), &query)
+                .iter()
+                .map(AccountStatement::client)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(clients(InspectQuery::default()), [1, 2, 3]);
+        let client = |client| InspectQuery {
+            client: Some(client),
+            ..InspectQuery::default()
+        };
+        assert_eq!(clients(client(2)), [2]);
+        assert!(clients(client(42)).is_empty());
+        let locked = InspectQuery {
+            locked: true,
+            ..InspectQuery::default()
+        };
+        assert_eq!(clients(locked.clone()), [3]);
+        let disputed = InspectQuery {
+            disputed: true,
+            ..InspectQuery::default()
+        };
+        assert_eq!(clients(disputed), [2]);
+        // Filters combine.
+        assert!(clients(InspectQuery {
+            client: Some(1),
+            ..locked
+        })
+        .is_empty());
+
+        let mut output = vec![];
+        write_statements(&mut output, select(engine.store(), &client(2))).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n2,0,5,5,false\n"
+        );
+    }
+
+    #[test]
+    fn paged_listing() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        for client_id in 1..=5 {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    info: TransactionInfo::Deposit(Money::from_scaled(i64::from(client_id), 0)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let pages = |order: StatementOrder, query: &InspectQuery| {
+            let mut pages = vec![];
+            let mut after = None;
+            loop {
+                let page = page(engine.store(), query, order, after, 2).unwrap();
+                pages.push(
+                    page.statements
+                        .iter()
+                        .map(AccountStatement::client)
+                        .collect::<Vec<_>>(),
+                );
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => return pages,
+                }
+            }
+        };
+        let all = InspectQuery::default();
+        assert_eq!(
+            pages(StatementOrder::Client, &all),
+            [vec![1, 2], vec![3, 4], vec![5]]
+        );
+        assert_eq!(
+            pages(StatementOrder::TotalDesc, &all),
+            [vec![5, 4], vec![3, 2], vec![1]]
+        );
+        let one = InspectQuery {
+            client: Some(4),
+            ..all.clone()
+        };
+        assert_eq!(pages(StatementOrder::Client, &one), [vec![4]]);
+
+        // An exact last page has no next page.
+        let last = page(engine.store(), &all, StatementOrder::Client, Some(3), 2).unwrap();
+        assert_eq!(last.next, None);
+        assert!(page(engine.store(), &one, StatementOrder::TotalDesc, Some(1), 2).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+//! Interest accrued on clients' balances.
+//!
+//! With an [`InterestPolicy`] (see [`crate::EngineConfig::interest`]), each
+//! account accrues interest daily on its available funds at an annual rate,
+//! and the interest accrued over each period is posted to the account at
+//! the period's end, as a deposit generated by the engine. E.g. in an
+//! engine config file:
+//!
+//! ```json
+//! {"interest": {"annual_percent": "2.5", "day_count": "actual/365", "period": "monthly"}}
+//! ```
+//!
+//! Days come from the times of transactions (see [`crate::clock`]), in UTC.
+//! As each of a client's timed transactions arrives, their account first
+//! accrues interest for the whole days since it last did, on its balance
+//! at the end of each, posting the interest of any periods that ended in
+//! between; [`crate::TxEngine::accrue_interest`] brings every account up to
+//! a given time, e.g. at the end of a run. Interest posted at the end of
+//! one period earns interest in the next.
+//!
+//! Only the primary balance accrues interest, and only while its available
+//! funds are positive. Posted interest is paid from the `interest_expense`
+//! system account, rounded half to even to the precision amounts are
+//! reported to; what rounding leaves over isn't carried into the next
+//! period.
+
+use crate::clock::{civil_from_days, days_from_civil};
+use crate::money::{Money, RoundingMode};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cmp::min;
+
+/// Precision interest is accrued to before it's posted.
+pub const ACCRUAL_SCALE: u32 = 10;
+
+/// How a day's share of the annual rate is counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DayCount {
+    /// Every day is 1/365 of a year, leap years included.
+    #[default]
+    #[serde(rename = "actual/365")]
+    Actual365,
+    /// Every day is 1/360 of a year, as in money markets.
+    #[serde(rename = "actual/360")]
+    Actual360,
+    /// Every day is a share of the year it falls in: 1/366 in leap years.
+    #[serde(rename = "actual/actual")]
+    ActualActual,
+}
+
+impl DayCount {
+    /// Days in the year counted for `day` (in days since 1970-01-01).
+    fn year_length(self, day: i64) -> u64 {
+        match self {
+            DayCount::Actual365 => 365,
+            DayCount::Actual360 => 360,
+            DayCount::ActualActual => {
+                let (year, _, _) = civil_from_days(day);
+                (days_from_civil(year + 1, 1, 1) - days_from_civil(year, 1, 1)) as u64
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for DayCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "actual/365" => Ok(DayCount::Actual365),
+            "actual/360" => Ok(DayCount::Actual360),
+            "actual/actual" => Ok(DayCount::ActualActual),
+            _ => Err(format!(
+                "Invalid day count {:?}, expected actual/365, actual/360 or actual/actual",
+                s
+            )),
+        }
+    }
+}
+
+/// How often accrued interest is posted. Periods follow the calendar, in
+/// UTC.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InterestPeriod {
+    Daily,
+    #[default]
+    Monthly,
+    Quarterly,
+    Yearly,
+}
+
+impl InterestPeriod {
+    /// The first day of the period after the one `day` is in, in days
+    /// since 1970-01-01.
+    pub fn next_start(self, day: i64) -> i64 {
+        let (year, month, _) = civil_from_days(day);
+        let month = match self {
+            InterestPeriod::Daily => return day + 1,
+            InterestPeriod::Monthly => month + 1,
+            InterestPeriod::Quarterly => month - (month - 1) % 3 + 3,
+            InterestPeriod::Yearly => 13,
+        };
+        match month {
+            13.. => days_from_civil(year + 1, month - 12, 1),
+            _ => days_from_civil(year, month, 1),
+        }
+    }
+}
+
+impl std::str::FromStr for InterestPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(InterestPeriod::Daily),
+            "monthly" => Ok(InterestPeriod::Monthly),
+            "quarterly" => Ok(InterestPeriod::Quarterly),
+            "yearly" => Ok(InterestPeriod::Yearly),
+            _ => Err(format!(
+                "Invalid interest period {:?}, expected daily, monthly, quarterly or yearly",
+                s
+            )),
+        }
+    }
+}
+
+/// Interest paid on balances (see [`crate::EngineConfig::interest`]).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InterestPolicy {
+    /// Annual rate as a percentage, e.g. `2.5` for 2.5%.
+    #[serde(deserialize_with = "non_negative")]
+    pub annual_percent: Money,
+    pub day_count: DayCount,
+    pub period: InterestPeriod,
+}
+
+impl InterestPolicy {
+    /// Interest on `balance` for the days from `from` up to `until`, to
+    /// [`ACCRUAL_SCALE`] half to even. `None` on overflow.
+    pub fn accrue(&self, balance: &Money, from: i64, until: i64) -> Option<Money> {
+        let mut interest = Money::zero();
+        let mut day = from;
+        while day < until {
+            // Days within a year are all counted alike.
+            let year_end = min(until, InterestPeriod::Yearly.next_start(day));
+            let days = Money::from(year_end - day);
+            let year_interest = balance
+                .checked_mul(&self.annual_percent)?
+                .checked_mul(&days)?
+                .div_count(100 * self.day_count.year_length(day))
+                .round_with(ACCRUAL_SCALE, RoundingMode::HalfEven);
+            interest = interest.checked_add(&year_interest)?;
+            day = year_end;
+        }
+        Some(interest)
+    }
+}
+
+impl std::str::FromStr for InterestPolicy {
+    type Err = String;
+
+    /// Parses `<annual percent>[:<day count>[:<period>]]`, e.g.
+    /// `2.5:actual/360:quarterly`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let percent = parts.next().unwrap_or_default();
+        let annual_percent = percent
+            .strip_suffix('%')
+            .unwrap_or(percent)
+            .parse()
+            .ok()
+            .filter(|percent: &Money| !percent.is_negative())
+            .ok_or_else(|| format!("Invalid interest rate {:?}", percent))?;
+        let day_count = parts.next().map(str::parse).transpose()?;
+        let period = parts.next().map(str::parse).transpose()?;
+        if parts.next().is_some() {
+            return Err(format!("Invalid interest policy {:?}", s));
+        }
+        Ok(Self {
+            annual_percent,
+            day_count: day_count.unwrap_or_default(),
+            period: period.unwrap_or_default(),
+        })
+    }
+}
+
+fn non_negative<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+    let rate = Money::deserialize(deserializer)?;
+    if rate.is_negative() {
+        return Err(serde::de::Error::custom("interest rates can't be negative"));
+    }
+    Ok(rate)
+}
+
+/// Interest an account has accrued in the current period, not yet posted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Accrual {
+    /// The first day not yet accrued for, in days since 1970-01-01.
+    pub since: i64,
+    /// Interest accrued so far in the period, to [`ACCRUAL_SCALE`].
+    pub accrued: Money,
+}
+
+/// Interest posted to an account at the end of a period.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InterestRecord {
+    /// The last day of the period, as `YYYY-MM-DD`.
+    pub period_end: String,
+    pub amount: Money,
+    pub sequence: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::parse_date;
+    use crate::money::money;
+
+    #[test]
+    fn day_counts_and_periods() {
+        let day = |date| parse_date(date).unwrap();
+        // A leap day, and 2024's last day.
+        let (from, until) = (day("2024-02-29"), day("2025-01-01"));
+        assert_eq!(InterestPeriod::Daily.next_start(from), from + 1);
+        assert_eq!(InterestPeriod::Monthly.next_start(from), day("2024-03-01"));
+        assert_eq!(
+            InterestPeriod::Quarterly.next_start(from),
+            day("2024-04-01")
+        );
+        assert_eq!(InterestPeriod::Monthly.next_start(until - 1), until);
+        assert_eq!(InterestPeriod::Quarterly.next_start(until - 1), until);
+        assert_eq!(InterestPeriod::Yearly.next_start(from), until);
+
+        let policy: InterestPolicy = "3.65".parse().unwrap();
+        assert_eq!(policy.day_count, DayCount::Actual365);
+        assert_eq!(policy.period, InterestPeriod::Monthly);
+        // 10 days of 3.65% on 1000 is 1.
+        assert_eq!(
+            policy.accrue(&money!(1000), from, from + 10),
+            Some(money!(1))
+        );
+        let policy = InterestPolicy {
+            day_count: DayCount::Actual360,
+            ..policy
+        };
+        assert_eq!(
+            policy.accrue(&money!(1000), from, from + 10),
+            Some(money!(1.0138888889))
+        );
+        // Ten days either side of the new year, at 1/366 and 1/365.
+        let policy: InterestPolicy = "3.66%:actual/actual:yearly".parse().unwrap();
+        assert_eq!(
+            policy.accrue(&money!(1000), until - 10, until + 10),
+            Some(money!(2.0027397260))
+        );
+        assert_eq!(policy.accrue(&money!(1000), until, until), Some(money!(0)));
+
+        assert!("-1".parse::<InterestPolicy>().is_err());
+        assert!("1:30/360".parse::<InterestPolicy>().is_err());
+        assert!("1:actual/365:weekly".parse::<InterestPolicy>().is_err());
+        assert!(serde_json::from_str::<InterestPolicy>(r#"{"annual_percent": "-1"}"#).is_err());
+        assert_eq!(
+            serde_json::from_str::<InterestPolicy>(
+                r#"{"annual_percent": 2, "day_count": "actual/360", "period": "quarterly"}"#
+            )
+            .unwrap(),
+            "2:actual/360:quarterly".parse().unwrap()
+        );
+    }
+}
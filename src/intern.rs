@@ -0,0 +1,197 @@
+//! Compact storage for short strings repeated across many records, such as
+//! the destinations withdrawals are tagged with.
+//!
+//! A run may keep millions of records tagged from a handful of distinct
+//! values. An [`Interned`] string is the size of a `String`, but short ones
+//! are kept inline, without allocating, and longer ones are shared: each
+//! thread keeps a bounded set of the longer strings it has seen, so records
+//! tagged with the same value point at the same allocation.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// Longest string kept inline.
+const INLINE_LEN: usize = 22;
+
+/// Most distinct strings shared per thread. Beyond this, longer strings are
+/// still stored once per record, so a column of unique values can't grow
+/// the set without bound.
+const MAX_INTERNED: usize = 65_536;
+
+thread_local! {
+    static INTERNED: RefCell<HashSet<Arc<str>>> = RefCell::default();
+}
+
+/// An immutable string, stored inline or shared (see the [module docs](self)).
+#[derive(Clone)]
+pub struct Interned(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, bytes: [u8; INLINE_LEN] },
+    Shared(Arc<str>),
+}
+
+impl Interned {
+    pub fn new(s: &str) -> Self {
+        if s.len() <= INLINE_LEN {
+            let mut bytes = [0; INLINE_LEN];
+            bytes[..s.len()].copy_from_slice(s.as_bytes());
+            return Self(Repr::Inline {
+                len: s.len() as u8,
+                bytes,
+            });
+        }
+        let shared = INTERNED
+            .try_with(|interned| {
+                let mut interned = interned.borrow_mut();
+                if let Some(shared) = interned.get(s) {
+                    return Arc::clone(shared);
+                }
+                let shared: Arc<str> = Arc::from(s);
+                if interned.len() < MAX_INTERNED {
+                    interned.insert(Arc::clone(&shared));
+                }
+                shared
+            })
+            .unwrap_or_else(|_| Arc::from(s));
+        Self(Repr::Shared(shared))
+    }
+
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            Repr::Inline { len, bytes } => std::str::from_utf8(&bytes[..usize::from(*len)])
+                .expect("Inline strings are copied from a str"),
+            Repr::Shared(shared) => shared,
+        }
+    }
+}
+
+impl Deref for Interned {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for Interned {
+    fn from(s: &str) -> Self {
+        Self::new(s)
+    }
+}
+
+impl From<String> for Interned {
+    fn from(s: String) -> Self {
+        Self::new(&s)
+    }
+}
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Interned {}
+
+impl PartialEq<str> for Interned {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Interned {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // As a str, so lookups by str (see `Borrow`) find it.
+        self.as_str().hash(state);
+    }
+}
+
+impl PartialOrd for Interned {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Interned {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl fmt::Debug for Interned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Interned {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Interned {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Interned {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        Ok(Self::new(&s))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn inline_or_shared() {
+        assert_eq!(
+            std::mem::size_of::<Interned>(),
+            std::mem::size_of::<String>()
+        );
+
+        let short = Interned::new("wallet-9");
+        assert!(matches!(short.0, Repr::Inline { .. }));
+        assert_eq!(short, "wallet-9");
+
+        let long = "GB29 NWBK 6016 1331 9268 19";
+        let (first, second) = (Interned::new(long), Interned::from(long.to_owned()));
+        let (Repr::Shared(first_shared), Repr::Shared(second_shared)) = (&first.0, &second.0)
+        else {
+            panic!("Long strings should be shared");
+        };
+        assert!(Arc::ptr_eq(first_shared, second_shared));
+        assert_eq!(first, second);
+        assert_eq!(first.to_string(), long);
+
+        let json = serde_json::to_string(&first).unwrap();
+        assert_eq!(json, format!("{:?}", long));
+        let restored: Interned = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, first);
+    }
+}
@@ -0,0 +1,349 @@
+//! A stream of ledger events, one for every change the engine makes to an
+//! account, for downstream systems that follow the ledger itself rather than
+//! the final statements.
+//!
+//! Unlike [`crate::event::EngineEvent`]s, which flag notable conditions and
+//! are held until drained, ledger events are passed to each [`EventSink`]
+//! added to the engine (see
+//! [`TxEngine::add_event_sink`](crate::TxEngine::add_event_sink)) as the
+//! change is made, in the order of their sequence numbers. Replaying them
+//! in order rebuilds every account's balances.
+
+use crate::account::LockScope;
+use crate::intern::Interned;
+use crate::money::Money;
+use crate::queue::BoundedQueue;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event")]
+pub enum LedgerEvent {
+    DepositApplied {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        amount: Money,
+    },
+    WithdrawalApplied {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        amount: Money,
+    },
+    DisputeOpened {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds held for the dispute, less than the deposit if the account
+        /// couldn't cover it under a capped hold policy.
+        held: Money,
+    },
+    DisputeResolved {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds released back to the client.
+        released: Money,
+    },
+    ChargebackApplied {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds removed from the account.
+        amount: Money,
+    },
+    /// Withdrawn funds returned to the account, and held, for a dispute of
+    /// the withdrawal.
+    WithdrawalDisputeOpened {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        held: Money,
+    },
+    /// The dispute of a withdrawal was resolved, so the funds returned for
+    /// it were withdrawn again.
+    WithdrawalDisputeResolved {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        released: Money,
+    },
+    WithdrawalChargebackApplied {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds credited back to the client, no longer held.
+        amount: Money,
+    },
+    /// A deposit undone by a reversal (see
+    /// [`TransactionInfo::Reversal`](crate::TransactionInfo::Reversal)).
+    DepositReversed {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds removed from the account: the deposit after any
+        /// corrections.
+        amount: Money,
+    },
+    /// A withdrawal undone by a reversal.
+    WithdrawalReversed {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        /// Funds credited back to the client.
+        amount: Money,
+    },
+    /// Raised after the chargeback that locked the account, with its
+    /// sequence number.
+    AccountLocked {
+        sequence: u64,
+        client: u16,
+        scope: LockScope,
+    },
+    /// An operator lifted the account's lock (see
+    /// [`TransactionInfo::Unlock`](crate::TransactionInfo::Unlock)).
+    AccountUnlocked {
+        sequence: u64,
+        client: u16,
+        operator: String,
+    },
+    /// Funds converted from one of the client's currency balances to
+    /// another (see [`crate::fx`]).
+    ConversionApplied {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        from: Interned,
+        to: Interned,
+        /// Funds taken from the `from` balance.
+        amount: Money,
+        /// Funds credited to the `to` balance.
+        converted: Money,
+    },
+    /// A fee charged on a deposit or withdrawal (see [`crate::fees`]),
+    /// raised after the transaction's own event, with the same sequence
+    /// number.
+    FeeCharged {
+        sequence: u64,
+        client: u16,
+        tx: u32,
+        fee: Money,
+    },
+    /// Interest posted at the end of a period (see [`crate::interest`]).
+    InterestPosted {
+        sequence: u64,
+        client: u16,
+        /// The last day of the period, as `YYYY-MM-DD`.
+        period_end: String,
+        amount: Money,
+    },
+}
+
+impl LedgerEvent {
+    pub fn sequence(&self) -> u64 {
+        match self {
+            Self::DepositApplied { sequence, .. }
+            | Self::WithdrawalApplied { sequence, .. }
+            | Self::DisputeOpened { sequence, .. }
+            | Self::DisputeResolved { sequence, .. }
+            | Self::ChargebackApplied { sequence, .. }
+            | Self::WithdrawalDisputeOpened { sequence, .. }
+            | Self::WithdrawalDisputeResolved { sequence, .. }
+            | Self::WithdrawalChargebackApplied { sequence, .. }
+            | Self::DepositReversed { sequence, .. }
+            | Self::WithdrawalReversed { sequence, .. }
+            | Self::AccountLocked { sequence, .. }
+            | Self::AccountUnlocked { sequence, .. }
+            | Self::ConversionApplied { sequence, .. }
+            | Self::FeeCharged { sequence, .. }
+            | Self::InterestPosted { sequence, .. } => *sequence,
+        }
+    }
+
+    pub fn client(&self) -> u16 {
+        match self {
+            Self::DepositApplied { client, .. }
+            | Self::WithdrawalApplied { client, .. }
+            | Self::DisputeOpened { client, .. }
+            | Self::DisputeResolved { client, .. }
+            | Self::ChargebackApplied { client, .. }
+            | Self::WithdrawalDisputeOpened { client, .. }
+            | Self::WithdrawalDisputeResolved { client, .. }
+            | Self::WithdrawalChargebackApplied { client, .. }
+            | Self::DepositReversed { client, .. }
+            | Self::WithdrawalReversed { client, .. }
+            | Self::AccountLocked { client, .. }
+            | Self::AccountUnlocked { client, .. }
+            | Self::ConversionApplied { client, .. }
+            | Self::FeeCharged { client, .. }
+            | Self::InterestPosted { client, .. } => *client,
+        }
+    }
+}
+
+/// Receives ledger events as the engine raises them.
+///
+/// Sinks are called while the transaction is applied, so should hand events
+/// off (e.g. to a channel or buffer) rather than block on slow I/O.
+pub trait EventSink: Send {
+    fn emit(&mut self, event: &LedgerEvent);
+}
+
+impl<F: FnMut(&LedgerEvent) + Send> EventSink for F {
+    fn emit(&mut self, event: &LedgerEvent) {
+        self(event)
+    }
+}
+
+/// Sends events to a receiver, e.g. on a publishing thread, waiting for
+/// room if the channel's full, so a slow receiver slows the engine rather
+/// than queueing events without limit. Events are dropped once the receiver
+/// is.
+impl EventSink for SyncSender<LedgerEvent> {
+    fn emit(&mut self, event: &LedgerEvent) {
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Queues events, handled as the queue's overflow says when it's full:
+/// waiting for room, dropping the event (counted as shed in its stats) or
+/// spilling it to disk.
+impl EventSink for Arc<BoundedQueue<LedgerEvent>> {
+    fn emit(&mut self, event: &LedgerEvent) {
+        // Sinks can't fail the transaction, so a spill that fails loses the
+        // event, as a full queue that sheds does.
+        let _ = self.push(event.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+    use crate::queue::{Overflow, QueueConfig};
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+    use std::sync::mpsc;
+
+    #[test]
+    fn events_for_each_change() {
+        let (sender, receiver) = mpsc::sync_channel(16);
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        engine.add_event_sink(Box::new(sender));
+        for (transaction_id, info) in [
+            (1, TransactionInfo::Deposit(money!(10))),
+            (2, TransactionInfo::Deposit(money!(5))),
+            (3, TransactionInfo::Withdrawal(money!(3))),
+            (1, TransactionInfo::Dispute(None)),
+            (1, TransactionInfo::Resolve),
+            // Not applied, so raises nothing.
+            (1, TransactionInfo::Resolve),
+            (2, TransactionInfo::Dispute(None)),
+            (2, TransactionInfo::Chargeback),
+        ] {
+            let _ = engine.handle(&Transaction {
+                client_id: 1,
+                transaction_id,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            });
+        }
+        drop(engine);
+
+        let events: Vec<LedgerEvent> = receiver.iter().collect();
+        assert_eq!(
+            events,
+            [
+                LedgerEvent::DepositApplied {
+                    sequence: 1,
+                    client: 1,
+                    tx: 1,
+                    amount: money!(10),
+                },
+                LedgerEvent::DepositApplied {
+                    sequence: 2,
+                    client: 1,
+                    tx: 2,
+                    amount: money!(5),
+                },
+                LedgerEvent::WithdrawalApplied {
+                    sequence: 3,
+                    client: 1,
+                    tx: 3,
+                    amount: money!(3),
+                },
+                LedgerEvent::DisputeOpened {
+                    sequence: 4,
+                    client: 1,
+                    tx: 1,
+                    held: money!(10),
+                },
+                LedgerEvent::DisputeResolved {
+                    sequence: 5,
+                    client: 1,
+                    tx: 1,
+                    released: money!(10),
+                },
+                LedgerEvent::DisputeOpened {
+                    sequence: 6,
+                    client: 1,
+                    tx: 2,
+                    held: money!(5),
+                },
+                LedgerEvent::ChargebackApplied {
+                    sequence: 7,
+                    client: 1,
+                    tx: 2,
+                    amount: money!(5),
+                },
+                LedgerEvent::AccountLocked {
+                    sequence: 7,
+                    client: 1,
+                    scope: LockScope::BlockAll,
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&events[2]).unwrap(),
+            r#"{"event":"WithdrawalApplied","sequence":3,"client":1,"tx":3,"amount":"3"}"#
+        );
+    }
+
+    #[test]
+    fn full_queue_sheds_events() {
+        let queue = Arc::new(
+            BoundedQueue::new(QueueConfig {
+                capacity: 1,
+                overflow: Overflow::Shed,
+            })
+            .unwrap(),
+        );
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        engine.add_event_sink(Box::new(Arc::clone(&queue)));
+        for transaction_id in 1..=2 {
+            engine
+                .handle(&Transaction {
+                    client_id: 1,
+                    transaction_id,
+                    info: TransactionInfo::Deposit(money!(1)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let stats = queue.stats();
+        assert_eq!((stats.depth, stats.shed), (1, 1));
+        assert_eq!(
+            queue.try_pop().unwrap().map(|event| event.sequence()),
+            Some(1)
+        );
+    }
+}
@@ -1,34 +1,404 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::{Read, Write};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use sha2::{Digest, Sha256};
 
 mod account;
 mod account_store;
+pub mod admin;
+pub mod arena;
+#[cfg(feature = "tokio")]
+pub mod async_runner;
+pub mod async_store;
+pub mod backfill;
+pub mod bench;
+pub mod bulk;
+pub mod checkpoint;
+pub mod clock;
+pub mod compat;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod delta;
+pub mod diff;
+pub mod encryption;
+pub mod event;
+pub mod export;
+pub mod fees;
+pub mod fx;
+pub mod generator;
+pub mod impact;
+pub mod ingestion;
+pub mod input;
+pub mod inspect;
+pub mod interest;
+pub mod intern;
+pub mod ledger;
+pub mod localize;
+pub mod manifest;
+pub mod merkle;
+pub mod metrics;
+pub mod money;
+pub mod notes;
+pub mod opening;
+pub mod outbox;
+pub mod period;
+pub mod plugin;
+pub mod postgres_store;
+pub mod profile;
+pub mod pseudonym;
+pub mod quarantine;
+pub mod queue;
+pub mod receipt;
+pub mod rejection;
+pub mod report;
+pub mod repro;
+pub mod resources;
+pub mod risk;
+pub mod screening;
+pub mod self_check;
+pub mod server;
+mod sha256;
+pub mod shadow;
+pub mod sharded_engine;
+pub mod shared_engine;
+pub mod signing;
+pub mod snapshot;
+pub mod statements;
+pub mod stream;
+pub mod subscriptions;
+pub mod system_accounts;
+pub mod tenants;
+pub mod thresholds;
+pub mod tiered_store;
 mod transaction;
 mod transaction_engine;
+pub mod trial_balance;
+pub mod tx_index;
+
+use delta::Delta;
+use encryption::OutputFile;
+use event::EngineEvent;
+use export::ExportOptions;
+use ingestion::{DedupPolicy, IngestionProfile, Strictness};
+use input::{InputFormat, TransactionReader};
+use manifest::{
+    Checkpoint, HashingReader, HashingWriter, InputRecord, ManifestOptions, OutputRecord,
+    RunConfig, RunCounts, RunManifest,
+};
+use metrics::{Metrics, Outcome};
+use money::Money;
+use period::BusinessDates;
+use plugin::TransactionPlugin;
+use profile::ClientProfiles;
+use pseudonym::{ClientColumnWriter, Pseudonymizer};
+use report::RunSummary;
+use repro::ReproBundle;
+use resources::{ResourceUsage, Stages};
+use screening::Screening;
+use sharded_engine::ShardedTxEngine;
+use signing::StatementSigner;
+use transaction::TransactionRaw;
+use trial_balance::{Flows, TrialBalance};
 
-use account_store::{AccountStore, InMemoryStore};
-use transaction::{Transaction, TransactionRaw};
-use transaction_engine::TxEngine;
+pub use account::{
+    Account, AccountStatement, ConversionRecord, DebitRecord, DepositRecord, DisputeStatus,
+    HistoryEntry, HoldPolicy, LockScope, StatementOptions, StatementOrder, TotalPolicy,
+    WithdrawalRecord,
+};
+pub use account_store::{AccountStore, InMemoryStore, StoreLayout};
+pub use rejection::RejectedTransaction;
+pub use transaction::{Conversion, DisputeReason, Transaction, TransactionInfo};
+pub use transaction_engine::{
+    DestinationPolicy, DormancyPolicy, EngineConfig, OverdraftPolicy, ReasonPolicy,
+    TransactionNotApplied, TxEngine, STATE_VERSION,
+};
 
 /// Transactions that were rejected due to account state or invalid input.
-/// Transaction ID + description of rejection cause.
-pub type RejectedTransactions = Vec<(u32, String)>;
+pub type RejectedTransactions = Vec<RejectedTransaction>;
 /// Valid transactions that we failed to apply. Store these so they aren't lost.
 /// Transaction + description of failure cause.
 pub type FailedTransactions = Vec<(Transaction, String)>;
 
+/// Options controlling a single run of the engine.
+#[derive(Default)]
+pub struct RunOptions {
+    /// Plugins to validate and charge fees for each transaction, in the order
+    /// they should be called.
+    pub plugins: Vec<Box<dyn TransactionPlugin>>,
+    /// Sinks to receive a ledger event for every change to an account (see
+    /// [`ledger`]).
+    pub event_sinks: Vec<Box<dyn ledger::EventSink>>,
+    /// Write per-account statement bundles, including each account's applied
+    /// transactions, once processing is complete.
+    pub export: Option<ExportOptions>,
+    /// Write a self-contained HTML summary report of the run to this path.
+    pub html_report: Option<PathBuf>,
+    /// Write per-transaction-type metrics to this path, in the Prometheus
+    /// text exposition format (e.g. for the node exporter's textfile
+    /// collector).
+    pub metrics: Option<PathBuf>,
+    /// Output options for account statements.
+    pub statement: StatementOptions,
+    /// Policies controlling how transactions are applied.
+    pub engine: EngineConfig,
+    /// How the engine's store lays out accounts in memory. Runs with more
+    /// than one worker, and those resumed from a checkpoint, use the hashed
+    /// layout.
+    pub store_layout: StoreLayout,
+    /// Only write the statements that changed since a previous run's
+    /// snapshot (see [`delta`]).
+    pub delta: Option<delta::DeltaOptions>,
+    /// Write statements in the canonical format: always a header, clients in
+    /// ascending order, amounts with exactly 4 decimal places and `\n` line
+    /// endings. The same final state always gives the same bytes, and this
+    /// format is stable across releases.
+    pub canonical: bool,
+    /// Write the statements (unless canonical) and HTML report for people
+    /// in this locale (see [`localize`]).
+    pub locale: localize::OutputLocale,
+    /// Write events raised by the engine (see [`event::EngineEvent`]) to this
+    /// path, as newline-delimited JSON.
+    pub events: Option<PathBuf>,
+    /// Write a manifest of the run's inputs, configuration and outputs.
+    pub manifest: Option<ManifestOptions>,
+    /// Write a CSV report of the accounts dormant at the end of the run to
+    /// this path. Requires [`EngineConfig::dormancy`] to be set.
+    pub dormancy_report: Option<PathBuf>,
+    /// Write a trial balance of the run (see [`trial_balance`]) to this
+    /// path, failing the run before any statements are written if it
+    /// doesn't balance.
+    pub trial_balance: Option<PathBuf>,
+    /// Write a CSV statement of the engine's system accounts (see
+    /// [`system_accounts::SystemAccounts`]) to this path.
+    pub system_statement: Option<PathBuf>,
+    /// Apply a bulk dispute file once the input has been processed.
+    pub bulk_disputes: Option<bulk::BulkDisputeOptions>,
+    /// Assign applied transactions to business dates, writing movements
+    /// and statements per date.
+    pub business_dates: Option<period::BusinessDateOptions>,
+    /// Attach operators' notes from this file (see [`notes::read_notes`])
+    /// once the input has been processed.
+    pub notes: Option<PathBuf>,
+    /// Screen applied transactions for suspicious patterns, writing a flags
+    /// report once the run completes.
+    pub screening: Option<screening::ScreeningOptions>,
+    /// Write a CSV profile of each client's transactions to this path.
+    pub profile_report: Option<PathBuf>,
+    /// Establish opening balances from this file (see
+    /// [`opening::read_entries`]) before processing the input.
+    pub opening_balances: Option<PathBuf>,
+    /// Write a snapshot of the engine's final state to this path, as
+    /// versioned JSON (see [`snapshot::read_snapshot`]).
+    pub snapshot: Option<PathBuf>,
+    /// How to compress the snapshot, if one is written.
+    pub snapshot_compression: snapshot::Compression,
+    /// Encrypt the snapshot and events, which hold account histories, with
+    /// the key from this provider.
+    pub encryption: Option<Box<dyn encryption::KeyProvider>>,
+    /// Pseudonymize client IDs in the statements, events and reports.
+    pub pseudonymize: Option<PseudonymOptions>,
+    /// Sign the statements written (see [`signing`]), writing a detached
+    /// signature alongside them.
+    pub sign_statements: Option<signing::SigningOptions>,
+    /// Clients to quarantine before processing (see
+    /// [`TxEngine::quarantine`]), e.g. while they're investigated.
+    pub quarantine: Vec<u16>,
+    /// Write a CSV of the transactions queued for quarantined clients at the
+    /// end of the run to this path.
+    pub quarantine_report: Option<PathBuf>,
+    /// Write a CSV of the rejected transactions (see
+    /// [`rejection::write_rejected`]) to this path.
+    pub rejects: Option<PathBuf>,
+    /// Format of the input and any further inputs.
+    pub input_format: InputFormat,
+    /// Further inputs, processed in order after the main input. A
+    /// transaction exactly matching one in an earlier input (same client,
+    /// transaction ID, type and amount) is skipped rather than reapplied,
+    /// as inputs exported by time window may overlap at their boundaries.
+    /// [`RunOptions::ingestion`] may skip more or fewer repeats.
+    pub extra_inputs: Vec<PathBuf>,
+    /// Write a repro bundle (see [`repro`]) for each failed transaction to
+    /// this directory, so the failure can be replayed.
+    pub repro_dir: Option<PathBuf>,
+    /// Read the input and any further inputs as a partner's feed (see
+    /// [`ingestion`]), with its column names, locale, default currency,
+    /// strictness and dedup policy.
+    pub ingestion: Option<IngestionProfile>,
+    /// Once the inputs are processed, bring interest (see [`interest`]) up
+    /// to this time, in seconds since the Unix epoch, rather than the
+    /// latest time in the inputs, e.g. to post the interest of a period
+    /// that ended after the last transaction. Requires
+    /// [`EngineConfig::interest`] to be set.
+    pub interest_as_of: Option<u64>,
+    /// Apply transactions on this many worker threads (see
+    /// [`sharded_engine::ShardedTxEngine`]). With more than one, only the
+    /// statement, engine, canonical, input format and rejects options are
+    /// supported.
+    pub workers: usize,
+    /// Periodically checkpoint the run (see [`checkpoint`]), so it can be
+    /// resumed if interrupted. Only the statement, engine, canonical, input
+    /// format and rejects options are supported with checkpoints.
+    pub checkpoint: Option<checkpoint::CheckpointOptions>,
+    /// Resume from this checkpoint, skipping the input it covers. The
+    /// checkpoint's engine configuration is used rather than
+    /// [`RunOptions::engine`]. As with [`RunOptions::checkpoint`], only the
+    /// core options are supported.
+    pub resume: Option<PathBuf>,
+}
+
+/// Options for pseudonymizing client IDs (see [`pseudonym`]).
+pub struct PseudonymOptions {
+    /// Provides the key pseudonyms are derived with.
+    pub key: Box<dyn encryption::KeyProvider>,
+    /// Write the mapping from client IDs to pseudonyms to this path, to be
+    /// stored apart from the pseudonymized outputs.
+    pub mapping: PathBuf,
+}
+
+/// Appends events to the event log, if one is being written.
+fn write_events(
+    log: Option<&mut OutputFile>,
+    events: impl Iterator<Item = EngineEvent>,
+    pseudonyms: Option<&Pseudonymizer>,
+) -> Result<(), Box<dyn Error>> {
+    let Some(writer) = log else {
+        events.for_each(drop);
+        return Ok(());
+    };
+    for event in events {
+        let event = match pseudonyms {
+            Some(pseudonyms) => pseudonyms.event(event),
+            None => event,
+        };
+        serde_json::to_writer(&mut *writer, &event)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// What a run did with its transactions, besides writing statements. More
+/// may be reported as the engine grows, so it can only be read outside
+/// the crate.
+#[derive(Debug, Default)]
+#[non_exhaustive]
+pub struct ProcessingReport {
+    /// Transactions rejected due to account state or invalid input.
+    pub rejected: RejectedTransactions,
+    /// Valid transactions that failed to apply.
+    pub failed: FailedTransactions,
+    /// Resources the run used.
+    pub resources: ResourceUsage,
+}
+
+/// Runs the engine to completion with `options`, parsing all rows in the
+/// input and writing the resulting account state for all clients.
+pub fn process<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: RunOptions,
+) -> Result<ProcessingReport, Box<dyn Error>> {
+    let checkpointed = options.checkpoint.is_some() || options.resume.is_some();
+    if !checkpointed && options.workers <= 1 {
+        return run(reader, writer, options);
+    }
+    let rejects = options.rejects.clone();
+    // These modes read, apply and write in the one stage.
+    let mut stages = Stages::default();
+    stages.start(if checkpointed {
+        "checkpointed"
+    } else {
+        "sharded"
+    });
+    let mut input = HashingReader::new(reader, false);
+    let mut output = HashingWriter::new(writer, false);
+    let (rejected, failed) = if checkpointed {
+        run_checkpointed(&mut input, &mut output, options)?
+    } else {
+        run_sharded(&mut input, &mut output, options)?
+    };
+    let (_, bytes_read) = input.finish();
+    let (_, mut bytes_written) = output.finish();
+    if let Some(path) = &rejects {
+        // These modes don't pseudonymize, so clients are written as they are.
+        rejection::write_rejected(&rejected, BufWriter::new(std::fs::File::create(path)?))?;
+        bytes_written += std::fs::metadata(path)?.len();
+    }
+    Ok(ProcessingReport {
+        rejected,
+        failed,
+        resources: ResourceUsage::measure(stages, bytes_read, bytes_written),
+    })
+}
+
 /// Runs the engine to completion, parsing all rows in the input csv and
 /// printing the resulting account state for all clients.
+#[deprecated(
+    since = "0.2.0",
+    note = "use `compat::v1::run_with_csv`, which keeps this signature, or `process`"
+)]
 pub fn run_with_csv<R: Read, W: Write>(
     reader: R,
     writer: W,
+) -> Result<(compat::v1::RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+    compat::v1::run_with_csv(reader, writer)
+}
+
+/// As [`compat::v1::run_with_csv`], reading transactions as JSON Lines (see
+/// [`input`]) rather than CSV.
+#[deprecated(
+    since = "0.2.0",
+    note = "use `compat::v1::run_with_json_lines`, which keeps this signature, or `process`"
+)]
+pub fn run_with_json_lines<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+) -> Result<(compat::v1::RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+    compat::v1::run_with_json_lines(reader, writer)
+}
+
+/// As [`process`], returning the rejected and failed transactions.
+pub fn run_with_options<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: RunOptions,
 ) -> Result<(RejectedTransactions, FailedTransactions), Box<dyn Error>> {
-    let mut csv_reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        // allow missing fields
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_reader(reader);
+    let report = process(reader, writer, options)?;
+    Ok((report.rejected, report.failed))
+}
+
+/// Runs the engine on a single thread, from the start of the input.
+fn run<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: RunOptions,
+) -> Result<ProcessingReport, Box<dyn Error>> {
+    let started_at = manifest::unix_time();
+    let mut stages = Stages::default();
+    stages.start("setup");
+    // Inputs and outputs are only hashed when there's a manifest to record
+    // them in.
+    let hashing = options.manifest.is_some();
+    let mut input = HashingReader::new(reader, hashing);
+    let mut output = HashingWriter::new(writer, hashing);
+    let run_config = RunConfig {
+        engine: options.engine.clone(),
+        statement: options.statement,
+        canonical: options.canonical,
+        locale: options
+            .locale
+            .is_localized()
+            .then(|| options.locale.tag().to_owned()),
+        plugins: options.plugins.len(),
+    };
+    let ingestion = options.ingestion.as_ref();
+    let strict = ingestion.is_some_and(|profile| profile.strictness == Strictness::Strict);
+    let dedup = ingestion.map_or(DedupPolicy::AcrossInputs, |profile| profile.dedup);
+    let mut transaction_reader = open_input(&mut input, options.input_format, ingestion)?;
 
     // Rejected transactions. For a system taking inputs from some client
     // service (rather than a static file), we'd send an appropriate response
@@ -38,42 +408,974 @@ pub fn run_with_csv<R: Read, W: Write>(
     // service, we'd also retry and send notifications indicating we could not
     // apply the transaction.
     let mut dead_letter_queue: FailedTransactions = vec![];
+    // Applied transactions per client, only kept when they're to be exported.
+    let mut history: HashMap<u16, Vec<(u64, Transaction)>> = HashMap::new();
+    let mut summary = RunSummary::default();
+    let mut metrics = Metrics::default();
+    let mut flows = Flows::default();
+    let mut profiles = options
+        .profile_report
+        .as_ref()
+        .map(|_| ClientProfiles::default());
+    let mut screening = options
+        .screening
+        .as_ref()
+        .map(|screening_options| Screening::new(screening_options.config.clone()));
+    let mut dates = match &options.business_dates {
+        Some(date_options) => Some(BusinessDates::new(&date_options.opening_date)?),
+        None => None,
+    };
+    let statements_dir = options
+        .business_dates
+        .as_ref()
+        .and_then(|date_options| date_options.statements_dir.as_ref());
+    // Statements written at the close of each business date.
+    let mut date_statements: Vec<PathBuf> = vec![];
+
+    let key = match &options.encryption {
+        Some(provider) => Some(provider.key()?),
+        None => None,
+    };
+    let pseudonyms = match &options.pseudonymize {
+        Some(pseudonym_options) => Some(Pseudonymizer::new(pseudonym_options.key.key()?)),
+        None => None,
+    };
+    // Taken up front, so a missing key fails the run before it starts.
+    let signer = match &options.sign_statements {
+        Some(signing_options) => Some(StatementSigner::new(signing_options.key.key()?)),
+        None => None,
+    };
+    if pseudonyms.is_some() && options.export.is_some() {
+        return Err("Statement bundles are per client, so can't be pseudonymized.".into());
+    }
+    if pseudonyms.is_some() && options.repro_dir.is_some() {
+        return Err("Repro bundles are per client, so can't be pseudonymized.".into());
+    }
+    let mut events = match &options.events {
+        Some(path) => Some(OutputFile::create(path, key.as_ref())?),
+        None => None,
+    };
+    // Read up front, so a missing snapshot fails the run before it starts.
+    let delta = match &options.delta {
+        Some(delta_options) => {
+            let previous = encryption::read_file(&delta_options.previous, key.as_ref())?;
+            Some(Delta::new(
+                snapshot::read_snapshot(previous.as_slice())?.store(),
+            ))
+        }
+        None => None,
+    };
 
-    let mut handler = TxEngine::new(InMemoryStore::new());
-    for transaction in csv_reader.deserialize::<TransactionRaw>() {
-        let transaction_raw = match transaction {
+    let dormancy = options.engine.dormancy;
+    if options.dormancy_report.is_some() && dormancy.is_none() {
+        return Err("A dormancy report requires a dormancy period.".into());
+    }
+    if options.interest_as_of.is_some() && options.engine.interest.is_none() {
+        return Err("Accruing interest as of a time requires an interest policy.".into());
+    }
+    let mut handler = TxEngine::with_config(
+        InMemoryStore::with_layout(options.store_layout),
+        options.engine,
+    );
+    for plugin in options.plugins {
+        handler.add_plugin(plugin);
+    }
+    for sink in options.event_sinks {
+        handler.add_event_sink(sink);
+    }
+    for client in &options.quarantine {
+        handler.quarantine(*client);
+    }
+    stages.start("input");
+    // Applied before the input, so the input can go on to dispute them.
+    let mut opening_applied = 0;
+    let mut opening_input = None;
+    if let Some(path) = &options.opening_balances {
+        let mut reader = HashingReader::new(std::fs::File::open(path)?, hashing);
+        for entry in opening::read_entries(&mut reader)? {
+            let applied = handler.open_balance(&entry).map_err(|err| {
+                format!(
+                    "Opening balance for client {} tx {} not applied: {}",
+                    entry.client, entry.tx, err
+                )
+            })?;
+            opening_applied += applied.len();
+            for (_, transaction) in &applied {
+                flows.record(transaction, handler.store());
+            }
+            if let Some(export) = &options.export {
+                if export.includes(entry.client) {
+                    history.entry(entry.client).or_default().extend(applied);
+                }
+            }
+        }
+        write_events(events.as_mut(), handler.drain_events(), pseudonyms.as_ref())?;
+        let (sha256, bytes) = reader.finish();
+        opening_input = Some(InputRecord {
+            name: path.display().to_string(),
+            sha256: sha256.unwrap_or_default(),
+            bytes,
+            processed: manifest::ByteRange {
+                start: 0,
+                end: bytes,
+            },
+        });
+    }
+    // Where each transaction was first read, to skip exact duplicates, and
+    // the last dispute step read for each disputed transaction, with where.
+    // Only kept when the dedup policy could skip any.
+    let deduplicating = match dedup {
+        DedupPolicy::AcrossInputs => !options.extra_inputs.is_empty(),
+        DedupPolicy::Exact => true,
+        DedupPolicy::Off => false,
+    };
+    let mut first_seen: HashMap<(u16, u32, &'static str, Option<Money>), usize> = HashMap::new();
+    let mut last_step = HashMap::new();
+    let mut process = |transaction: Result<TransactionRaw, Box<dyn Error>>,
+                       input_index: usize|
+     -> Result<(), Box<dyn Error>> {
+        let mut transaction_raw = match transaction {
             Ok(tx) => tx,
+            Err(err) if strict => return Err(format!("Unreadable row: {}", err).into()),
             // Ideally we'd intervene before here, log the string that
             // couldn't be deserialized, and send a rejection response.
             // For now, just log it and move on.
             Err(_err) => {
-                continue;
+                summary.unreadable_rows += 1;
+                return Ok(());
             }
         };
+        let localised = match ingestion {
+            Some(profile) => profile.apply(&mut transaction_raw),
+            None => Ok(()),
+        };
         // Save the ID so we can use it for logging/failure handling.
         let tx_id = transaction_raw.tx;
-        let transaction_parsed = match Transaction::try_from(transaction_raw) {
-            Ok(tx) => tx,
-            Err(_err) => {
-                rejected_transactions.push((tx_id, "Malformed Transaction".into()));
-                continue;
+        let client_id = transaction_raw.client;
+        let transaction_type = transaction_raw.transaction_type.clone();
+        let timestamp = transaction_raw
+            .timestamp
+            .as_deref()
+            .map(str::trim)
+            .filter(|timestamp| !timestamp.is_empty());
+        let advanced = match (dates.as_mut(), transaction_raw.date.as_deref(), timestamp) {
+            (Some(dates), Some(date), _) => dates.advance(date),
+            // Without a date, rows move on to the day they happened. A
+            // malformed timestamp is rejected when the row's parsed.
+            (Some(dates), None, Some(timestamp)) => match clock::parse_timestamp(timestamp) {
+                Ok(timestamp) => dates.advance_to_time(timestamp),
+                Err(_) => Ok(None),
+            },
+            _ => Ok(None),
+        };
+        if let (Ok(Some(closed)), Some(dir)) = (&advanced, statements_dir) {
+            date_statements.push(write_date_statements(
+                dir,
+                closed,
+                handler.store(),
+                &options.statement,
+                pseudonyms.as_ref(),
+            )?);
+        }
+        // Kept for a repro bundle, should the transaction fail.
+        let row = options.repro_dir.as_ref().map(|_| transaction_raw.clone());
+        // A row with a malformed business date or amount in the feed's
+        // locale is malformed as a whole.
+        let parsed = Transaction::try_from(transaction_raw)
+            .ok()
+            .filter(|_| advanced.is_ok() && localised.is_ok());
+        let transaction_parsed = match parsed {
+            Some(tx) => tx,
+            None if strict => return Err(format!("Malformed transaction {}", tx_id).into()),
+            None => {
+                rejected_transactions.push(RejectedTransaction::malformed(
+                    tx_id,
+                    client_id,
+                    transaction_type,
+                ));
+                summary.record_rejected("MalformedTransaction");
+                if let Some(profiles) = profiles.as_mut() {
+                    profiles.record(client_id, None, Outcome::Rejected);
+                }
+                return Ok(());
             }
         };
+        if deduplicating {
+            let ids = (
+                transaction_parsed.client_id,
+                transaction_parsed.transaction_id,
+            );
+            let step = (
+                transaction_parsed.info.kind(),
+                transaction_parsed.amount().cloned(),
+            );
+            let repeat_of = |seen_in: usize| dedup == DedupPolicy::Exact || seen_in < input_index;
+            let repeat = match &transaction_parsed.info {
+                // A transaction may be disputed again once resolved, so a
+                // dispute step only repeats its transaction's last step.
+                TransactionInfo::Dispute(_)
+                | TransactionInfo::Resolve
+                | TransactionInfo::Chargeback => {
+                    let repeat = last_step
+                        .get(&ids)
+                        .is_some_and(|(last, seen_in)| *last == step && repeat_of(*seen_in));
+                    if !repeat {
+                        last_step.insert(ids, (step, input_index));
+                    }
+                    repeat
+                }
+                _ => match first_seen.entry((ids.0, ids.1, step.0, step.1)) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(input_index);
+                        false
+                    }
+                    Entry::Occupied(entry) => repeat_of(*entry.get()),
+                },
+            };
+            if repeat {
+                summary.duplicates_skipped += 1;
+                return Ok(());
+            }
+        }
+        let before = dates.as_ref().map(|_| balances(handler.store(), client_id));
+        let start = Instant::now();
         let res = handler.handle(&transaction_parsed);
-        if let Err(err) = res {
-            if err.is_failure() {
+        let outcome = match &res {
+            Ok(_) => Outcome::Applied,
+            Err(TransactionNotApplied::Quarantined) => Outcome::Quarantined,
+            Err(err) if err.is_failure() => Outcome::Failed,
+            Err(_) => Outcome::Rejected,
+        };
+        metrics.record(&transaction_parsed.info, start.elapsed(), outcome);
+        if let Some(profiles) = profiles.as_mut() {
+            profiles.record(client_id, Some(&transaction_parsed.info), outcome);
+        }
+        write_events(events.as_mut(), handler.drain_events(), pseudonyms.as_ref())?;
+        match res {
+            Ok(sequence) => {
+                summary.record_applied(&transaction_parsed);
+                flows.record(&transaction_parsed, handler.store());
+                if let (Some(dates), Some(before)) = (dates.as_mut(), before) {
+                    let after = balances(handler.store(), client_id);
+                    dates.record(client_id, &transaction_parsed.info, before, after);
+                }
+                if let Some(screening) = screening.as_mut() {
+                    screening.record(&transaction_parsed);
+                }
+                if let Some(export) = &options.export {
+                    if export.includes(transaction_parsed.client_id) {
+                        history
+                            .entry(transaction_parsed.client_id)
+                            .or_default()
+                            .push((sequence, transaction_parsed));
+                    }
+                }
+            }
+            // Neither applied nor rejected yet.
+            Err(TransactionNotApplied::Quarantined) => summary.quarantined += 1,
+            Err(err) if err.is_failure() => {
+                summary.record_failed(err.name());
+                if let (Some(dir), Some(row)) = (&options.repro_dir, row) {
+                    let path = dir.join(format!(
+                        "{:04}-client-{}-tx-{}.json",
+                        dead_letter_queue.len() + 1,
+                        client_id,
+                        tx_id
+                    ));
+                    let mut file = OutputFile::create(&path, key.as_ref())?;
+                    ReproBundle::capture(row, &err, &handler).write(&mut file)?;
+                    file.finish()?;
+                }
                 dead_letter_queue.push((transaction_parsed, err.to_string()));
-            } else {
-                rejected_transactions.push((tx_id, err.to_string()));
+            }
+            Err(err) => {
+                summary.record_rejected(err.name());
+                rejected_transactions.push(RejectedTransaction::new(&transaction_parsed, &err));
             }
         }
+        Ok(())
+    };
+    transaction_reader.for_each(|transaction| process(transaction, 0))?;
+    let (end_of_input_rows, end_of_input) = transaction_reader.position();
+    drop(transaction_reader);
+    let mut extra_input_records = vec![];
+    for (index, path) in options.extra_inputs.iter().enumerate() {
+        let mut reader = HashingReader::new(File::open(path)?, hashing);
+        let mut transaction_reader = open_input(&mut reader, options.input_format, ingestion)?;
+        transaction_reader.for_each(|transaction| process(transaction, index + 1))?;
+        let (_, end) = transaction_reader.position();
+        drop(transaction_reader);
+        let (sha256, bytes) = reader.finish();
+        extra_input_records.push(InputRecord {
+            name: path.display().to_string(),
+            sha256: sha256.unwrap_or_default(),
+            bytes,
+            processed: manifest::ByteRange { start: 0, end },
+        });
+    }
+
+    stages.start("post-processing");
+    if let Some(bulk_options) = &options.bulk_disputes {
+        let items = bulk::read_items(std::fs::File::open(&bulk_options.input)?)?;
+        let results = match dates.as_mut() {
+            None => handler.handle_bulk_disputes(bulk_options.action, &items),
+            // One at a time, to measure each item's movements.
+            Some(dates) => items
+                .iter()
+                .map(|item| {
+                    let before = balances(handler.store(), item.client);
+                    let result = handler
+                        .handle_bulk_disputes(bulk_options.action, std::slice::from_ref(item))
+                        .remove(0);
+                    if result.is_ok() {
+                        let after = balances(handler.store(), item.client);
+                        let info = bulk_options.action.info(item.reason);
+                        dates.record(item.client, &info, before, after);
+                    }
+                    result
+                })
+                .collect(),
+        };
+        write_events(events.as_mut(), handler.drain_events(), pseudonyms.as_ref())?;
+        let file = BufWriter::new(std::fs::File::create(&bulk_options.results)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        bulk::write_results(file, &items, &results)?;
+    }
+
+    if let Some(path) = &options.notes {
+        for note in notes::read_notes(std::fs::File::open(path)?)? {
+            handler.annotate(&note)?;
+        }
+        write_events(events.as_mut(), handler.drain_events(), pseudonyms.as_ref())?;
+    }
+
+    if let Some(time) = options.interest_as_of.or(handler.latest_time()) {
+        handler
+            .accrue_interest(time)
+            .map_err(|err| format!("Interest not posted: {}", err))?;
+    }
+
+    if let (Some(dates), Some(dir)) = (&dates, statements_dir) {
+        date_statements.push(write_date_statements(
+            dir,
+            dates.current(),
+            handler.store(),
+            &options.statement,
+            pseudonyms.as_ref(),
+        )?);
+    }
+
+    stages.start("output");
+    if let Some(path) = &options.trial_balance {
+        let trial = TrialBalance::new(handler.store(), handler.system_accounts(), &flows);
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let mut file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        trial.write_report(&mut file)?;
+        file.flush()?;
+        if !trial.balances() {
+            return Err(format!("Trial balance doesn't balance, see {}.", path.display()).into());
+        }
     }
 
     // Done processing. Write out our results.
-    let mut csv_writer = csv::Writer::from_writer(writer);
-    for account_statement in handler.store().account_statements() {
-        csv_writer.serialize(account_statement)?;
+    let statements = statements::account_statements(handler.store())
+        .into_iter()
+        .filter(|statement| delta.as_ref().is_none_or(|delta| delta.changed(statement)));
+    // Statements to sign are kept whole, as they're only signed once
+    // complete.
+    let mut signed = vec![];
+    let statements_output: &mut dyn Write = match signer {
+        Some(_) => &mut signed,
+        None => &mut output,
+    };
+    write_statements(
+        statements_output,
+        statements,
+        &options.statement,
+        options.canonical,
+        &options.locale,
+        pseudonyms.as_ref(),
+    )?;
+    if let (Some(signer), Some(signing_options)) = (&signer, &options.sign_statements) {
+        output.write_all(&signed)?;
+        let signature = signer.sign(&signed)?;
+        std::fs::write(&signing_options.signature, format!("{}\n", signature))?;
     }
-    csv_writer.flush()?;
+    if let Some(writer) = events {
+        writer.finish()?;
+    }
+    // Files written, for the manifest.
+    let mut output_files: Vec<PathBuf> = options.events.iter().cloned().collect();
+    output_files.extend(
+        options
+            .bulk_disputes
+            .iter()
+            .map(|bulk_options| bulk_options.results.clone()),
+    );
+    output_files.extend(date_statements);
+    output_files.extend(
+        options
+            .sign_statements
+            .iter()
+            .map(|signing_options| signing_options.signature.clone()),
+    );
+    if let (Some(path), Some(delta)) = (
+        options
+            .delta
+            .as_ref()
+            .and_then(|delta_options| delta_options.tombstones.as_ref()),
+        &delta,
+    ) {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        delta::write_tombstones(file, &delta.tombstones(handler.store()))?;
+        output_files.push(path.clone());
+    }
+    if let (Some(path), Some(dates)) = (
+        options
+            .business_dates
+            .as_ref()
+            .and_then(|date_options| date_options.movements.as_ref()),
+        &dates,
+    ) {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        dates.write_movements(file)?;
+        output_files.push(path.clone());
+    }
+    if let Some(export) = &options.export {
+        output_files.extend(export::write_bundles(
+            handler.store(),
+            &history,
+            export,
+            &options.statement,
+        )?);
+    }
+    if let Some(path) = &options.html_report {
+        let mut statements: Vec<_> = handler.store().account_statements().collect();
+        summary.record_totals(&statements);
+        if let Some(pseudonyms) = &pseudonyms {
+            statements = statements
+                .into_iter()
+                .map(|statement| pseudonyms.statement(statement))
+                .collect();
+            for movement in &mut summary.largest_movements {
+                movement.client = pseudonyms.pseudonym(movement.client);
+            }
+        }
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        report::render_html(file, &summary, &statements, &options.locale)?;
+        output_files.push(path.clone());
+    }
+    if let Some(path) = &options.metrics {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        metrics.write_prometheus(file)?;
+        output_files.push(path.clone());
+    }
+    if let (Some(path), Some(dormancy)) = (&options.dormancy_report, dormancy) {
+        let dormant = report::dormant_accounts(
+            handler.store().accounts(),
+            handler.last_sequence(),
+            dormancy.after,
+        );
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        report::write_dormancy_report(file, &dormant)?;
+        output_files.push(path.clone());
+    }
+    if let (Some(screening_options), Some(screening)) = (&options.screening, &screening) {
+        let file = BufWriter::new(std::fs::File::create(&screening_options.path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        screening.write_report(file)?;
+        output_files.push(screening_options.path.clone());
+    }
+    if let (Some(path), Some(profiles)) = (&options.profile_report, &profiles) {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        profiles.write_report(file)?;
+        output_files.push(path.clone());
+    }
+    if let Some(path) = &options.quarantine_report {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        quarantine::write_queue(file, handler.quarantined())?;
+        output_files.push(path.clone());
+    }
+    if let Some(path) = &options.rejects {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        let file = ClientColumnWriter::new(file, pseudonyms.as_ref());
+        rejection::write_rejected(&rejected_transactions, file)?;
+        output_files.push(path.clone());
+    }
+    if let Some(path) = &options.snapshot {
+        let mut file = OutputFile::create(path, key.as_ref())?;
+        snapshot::write_snapshot(&mut file, &handler, options.snapshot_compression)?;
+        file.finish()?;
+        output_files.push(path.clone());
+    }
+    if let (Some(pseudonym_options), Some(pseudonyms)) = (&options.pseudonymize, &pseudonyms) {
+        let file = BufWriter::new(std::fs::File::create(&pseudonym_options.mapping)?);
+        pseudonyms.write_mapping(file)?;
+    }
+    if let Some(path) = &options.system_statement {
+        let file = BufWriter::new(std::fs::File::create(path)?);
+        handler.system_accounts().write_statement(file)?;
+        output_files.push(path.clone());
+    }
+    output_files.extend(options.trial_balance.iter().cloned());
+
+    let (input_sha256, input_bytes) = input.finish();
+    let (output_sha256, output_bytes) = output.finish();
+    let bytes_read = input_bytes
+        + extra_input_records
+            .iter()
+            .chain(&opening_input)
+            .map(|record| record.bytes)
+            .sum::<u64>();
+    let mut bytes_written = output_bytes;
+    for path in &output_files {
+        bytes_written += std::fs::metadata(path)?.len();
+    }
+    let resources = ResourceUsage::measure(stages, bytes_read, bytes_written);
+
+    if let Some(manifest_options) = &options.manifest {
+        let mut outputs = vec![OutputRecord {
+            name: "statements".into(),
+            sha256: output_sha256.unwrap_or_default(),
+            bytes: output_bytes,
+        }];
+        for path in &output_files {
+            outputs.push(OutputRecord::from_file(path)?);
+        }
+        let mut state = vec![];
+        write_canonical_statements(
+            &mut state,
+            handler.store().account_statements(),
+            &StatementOptions::default(),
+            None,
+        )?;
+        let manifest = RunManifest {
+            version: env!("CARGO_PKG_VERSION"),
+            started_at,
+            finished_at: manifest::unix_time(),
+            inputs: std::iter::once(InputRecord {
+                name: manifest_options.input_name.clone(),
+                sha256: input_sha256.unwrap_or_default(),
+                bytes: input_bytes,
+                processed: manifest::ByteRange {
+                    start: 0,
+                    end: end_of_input,
+                },
+            })
+            .chain(extra_input_records)
+            .chain(opening_input)
+            .collect(),
+            checkpoints: vec![Checkpoint {
+                rows: end_of_input_rows,
+                byte_offset: end_of_input,
+            }],
+            config: run_config,
+            counts: RunCounts {
+                applied: summary.applied,
+                rejected: rejected_transactions.len(),
+                failed: dead_letter_queue.len(),
+                unreadable_rows: summary.unreadable_rows,
+                quarantined: summary.quarantined,
+                opening: opening_applied,
+                duplicates_skipped: summary.duplicates_skipped,
+            },
+            outputs,
+            state_digest: sha256::to_hex(&Sha256::digest(&state)),
+            metrics: metrics.summary(),
+            resources: resources.clone(),
+        };
+        let file = BufWriter::new(std::fs::File::create(&manifest_options.path)?);
+        manifest.write(file)?;
+    }
+    Ok(ProcessingReport {
+        rejected: rejected_transactions,
+        failed: dead_letter_queue,
+        resources,
+    })
+}
+
+/// Writes the statements at the close of a business date into `dir`, in the
+/// canonical format. Returns the path written.
+fn write_date_statements<S: AccountStore>(
+    dir: &std::path::Path,
+    date: &str,
+    store: &S,
+    options: &StatementOptions,
+    pseudonyms: Option<&Pseudonymizer>,
+) -> Result<PathBuf, Box<dyn Error>> {
+    let path = dir.join(format!("statements_{}.csv", date));
+    let mut file = BufWriter::new(std::fs::File::create(&path)?);
+    write_canonical_statements(&mut file, store.account_statements(), options, pseudonyms)?;
+    file.flush()?;
+    Ok(path)
+}
+
+/// A client's total and held funds, summed across currencies, for measuring
+/// their movements.
+fn balances<S: AccountStore>(store: &S, client: u16) -> (Money, Money) {
+    let Some(account) = store.get_account(client) else {
+        return Default::default();
+    };
+    account
+        .balances()
+        .fold(Default::default(), |(total, held), balance| {
+            (
+                &total + balance.total_funds(),
+                &held + &balance.held_funds(),
+            )
+        })
+}
+
+/// Reads transactions from `input`, as `profile`'s feed if there is one.
+fn open_input<R: Read>(
+    input: R,
+    format: InputFormat,
+    profile: Option<&IngestionProfile>,
+) -> Result<TransactionReader<R>, Box<dyn Error>> {
+    let mut reader = TransactionReader::new(input, format);
+    if let Some(profile) = profile {
+        let strict = profile.strictness == Strictness::Strict;
+        reader.rename_columns(&profile.columns, strict)?;
+    }
+    Ok(reader)
+}
+
+/// Fails naming the options set other than the core ones (statement output,
+/// engine policies, the input format and the rejects report), for run modes
+/// only supporting those. `mode`'s own options, named in `own`, are allowed.
+fn core_options_only(options: &RunOptions, mode: &str, own: &[&str]) -> Result<(), Box<dyn Error>> {
+    // Listed in full, so new options have to be considered here.
+    let RunOptions {
+        plugins,
+        event_sinks,
+        export,
+        html_report,
+        metrics,
+        statement: _,
+        engine: _,
+        store_layout: _,
+        delta,
+        canonical: _,
+        locale: _,
+        events,
+        manifest,
+        dormancy_report,
+        trial_balance,
+        system_statement,
+        bulk_disputes,
+        business_dates,
+        notes,
+        screening,
+        profile_report,
+        opening_balances,
+        snapshot,
+        snapshot_compression: _,
+        encryption,
+        pseudonymize,
+        sign_statements,
+        quarantine,
+        quarantine_report,
+        rejects: _,
+        input_format: _,
+        extra_inputs,
+        repro_dir,
+        ingestion,
+        interest_as_of,
+        workers,
+        checkpoint,
+        resume,
+    } = options;
+    let unsupported: Vec<&str> = [
+        ("plugins", !plugins.is_empty()),
+        ("event_sinks", !event_sinks.is_empty()),
+        ("export", export.is_some()),
+        ("html_report", html_report.is_some()),
+        ("metrics", metrics.is_some()),
+        ("delta", delta.is_some()),
+        ("events", events.is_some()),
+        ("manifest", manifest.is_some()),
+        ("dormancy_report", dormancy_report.is_some()),
+        ("trial_balance", trial_balance.is_some()),
+        ("system_statement", system_statement.is_some()),
+        ("bulk_disputes", bulk_disputes.is_some()),
+        ("business_dates", business_dates.is_some()),
+        ("notes", notes.is_some()),
+        ("screening", screening.is_some()),
+        ("profile_report", profile_report.is_some()),
+        ("opening_balances", opening_balances.is_some()),
+        ("snapshot", snapshot.is_some()),
+        ("encryption", encryption.is_some()),
+        ("pseudonymize", pseudonymize.is_some()),
+        ("sign_statements", sign_statements.is_some()),
+        ("quarantine", !quarantine.is_empty()),
+        ("quarantine_report", quarantine_report.is_some()),
+        ("extra_inputs", !extra_inputs.is_empty()),
+        ("repro_dir", repro_dir.is_some()),
+        ("ingestion", ingestion.is_some()),
+        ("interest_as_of", interest_as_of.is_some()),
+        ("workers", *workers > 1),
+        ("checkpoint", checkpoint.is_some()),
+        ("resume", resume.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(name, set)| (set && !own.contains(&name)).then_some(name))
+    .collect();
+    if !unsupported.is_empty() {
+        return Err(format!(
+            "Options not supported with {}: {}.",
+            mode,
+            unsupported.join(", ")
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// As [`run_with_options`], applying transactions on
+/// [`RunOptions::workers`] worker threads.
+fn run_sharded<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: RunOptions,
+) -> Result<(RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+    core_options_only(&options, "more than one worker", &["workers"])?;
+    let RunOptions {
+        statement,
+        engine,
+        canonical,
+        locale,
+        input_format,
+        workers,
+        ..
+    } = options;
+
+    let mut sharded = ShardedTxEngine::new(engine, workers);
+    // Rows that couldn't be parsed, at the position the next transaction
+    // would be submitted at, so rejections can be reported in input order.
+    let mut malformed: Vec<(usize, RejectedTransaction)> = vec![];
+    TransactionReader::new(reader, input_format).for_each(|transaction| {
+        // As in `run_with_options`, unreadable rows are skipped.
+        if let Ok(transaction_raw) = transaction {
+            let rejection = RejectedTransaction::malformed(
+                transaction_raw.tx,
+                transaction_raw.client,
+                transaction_raw.transaction_type.clone(),
+            );
+            match Transaction::try_from(transaction_raw) {
+                Ok(transaction) => sharded.submit(transaction)?,
+                Err(_) => malformed.push((sharded.submitted(), rejection)),
+            }
+        }
+        Ok(())
+    })?;
+    let run = sharded.finish()?;
+
+    let mut rejected_transactions: RejectedTransactions = vec![];
+    let mut dead_letter_queue: FailedTransactions = vec![];
+    let mut malformed = malformed.into_iter().peekable();
+    for (position, transaction, err) in run.not_applied {
+        while let Some((_, rejection)) = malformed.next_if(|(before, _)| *before <= position) {
+            rejected_transactions.push(rejection);
+        }
+        match err {
+            // Left queued, as in `run_with_options`.
+            TransactionNotApplied::Quarantined => {}
+            err if err.is_failure() => dead_letter_queue.push((transaction, err.to_string())),
+            err => rejected_transactions.push(RejectedTransaction::new(&transaction, &err)),
+        }
+    }
+    rejected_transactions.extend(malformed.map(|(_, rejection)| rejection));
+    write_statements(
+        writer,
+        run.statements.into_iter(),
+        &statement,
+        canonical,
+        &locale,
+        None,
+    )?;
+    Ok((rejected_transactions, dead_letter_queue))
+}
+
+/// As [`run_with_options`], checkpointing the run and/or resuming it from a
+/// checkpoint (see [`checkpoint`]).
+fn run_checkpointed<R: Read, W: Write>(
+    reader: R,
+    writer: W,
+    options: RunOptions,
+) -> Result<(RejectedTransactions, FailedTransactions), Box<dyn Error>> {
+    core_options_only(&options, "checkpoints", &["checkpoint", "resume"])?;
+    let RunOptions {
+        statement,
+        engine,
+        store_layout,
+        canonical,
+        locale,
+        input_format,
+        checkpoint,
+        resume,
+        ..
+    } = options;
+
+    let (mut handler, mut rejected_transactions, mut dead_letter_queue, resume_at) = match resume {
+        Some(path) => {
+            let checkpoint = checkpoint::read_checkpoint(&path)?;
+            (
+                checkpoint.engine,
+                checkpoint.rejected,
+                checkpoint.failed,
+                Some(checkpoint.position),
+            )
+        }
+        None => (
+            TxEngine::with_config(InMemoryStore::with_layout(store_layout), engine),
+            vec![],
+            vec![],
+            None,
+        ),
+    };
+    let mut resume = checkpoint::Resume::new(resume_at);
+    let mut rows_since_checkpoint = 0;
+    TransactionReader::new(reader, input_format).for_each_at(|transaction, (rows, bytes)| {
+        let position = checkpoint::InputPosition { rows, bytes };
+        if resume.skip(position)? {
+            return Ok(());
+        }
+        // As in `run_with_options`, unreadable rows are skipped.
+        if let Ok(transaction_raw) = transaction {
+            let (tx_id, client_id) = (transaction_raw.tx, transaction_raw.client);
+            let transaction_type = transaction_raw.transaction_type.clone();
+            match Transaction::try_from(transaction_raw) {
+                Ok(transaction) => match handler.handle(&transaction) {
+                    // Left queued, as in `run_with_options`.
+                    Ok(_) | Err(TransactionNotApplied::Quarantined) => {}
+                    Err(err) if err.is_failure() => {
+                        dead_letter_queue.push((transaction, err.to_string()))
+                    }
+                    Err(err) => {
+                        rejected_transactions.push(RejectedTransaction::new(&transaction, &err))
+                    }
+                },
+                Err(_) => rejected_transactions.push(RejectedTransaction::malformed(
+                    tx_id,
+                    client_id,
+                    transaction_type,
+                )),
+            }
+            // Events aren't written with checkpoints.
+            handler.drain_events().for_each(drop);
+        }
+        rows_since_checkpoint += 1;
+        if let Some(options) = &checkpoint {
+            if rows_since_checkpoint >= options.interval {
+                checkpoint::write_checkpoint(
+                    &options.path,
+                    position,
+                    &rejected_transactions,
+                    &dead_letter_queue,
+                    &handler,
+                )?;
+                rows_since_checkpoint = 0;
+            }
+        }
+        Ok(())
+    })?;
+    resume.finish()?;
+    write_statements(
+        writer,
+        statements::account_statements(handler.store()).into_iter(),
+        &statement,
+        canonical,
+        &locale,
+        None,
+    )?;
     Ok((rejected_transactions, dead_letter_queue))
 }
+
+/// Writes statements as CSV, in the canonical format if `canonical` is set.
+fn write_statements<W: Write>(
+    mut writer: W,
+    statements: impl Iterator<Item = AccountStatement>,
+    options: &StatementOptions,
+    canonical: bool,
+    locale: &localize::OutputLocale,
+    pseudonyms: Option<&Pseudonymizer>,
+) -> Result<(), Box<dyn Error>> {
+    if canonical {
+        return write_canonical_statements(writer, statements, options, pseudonyms);
+    }
+    let mut statements: Vec<AccountStatement> = statements
+        .map(|statement| match pseudonyms {
+            Some(pseudonyms) => pseudonyms.statement(statement),
+            None => statement,
+        })
+        .map(|statement| statement.with_options(options))
+        .collect();
+    statements::label_currencies(&mut statements);
+    if let Some(order) = options.order {
+        order.sort(&mut statements);
+    }
+    if locale.is_localized() {
+        return localize::write_statements(writer, &statements, options, locale);
+    }
+    statements::write_rows(&mut writer, &statements, csv::WriterBuilder::new)
+}
+
+/// Writes statements in the canonical format, see [`RunOptions::canonical`].
+/// Statements are in client order unless another order is set.
+fn write_canonical_statements<W: Write>(
+    writer: W,
+    statements: impl Iterator<Item = AccountStatement>,
+    options: &StatementOptions,
+    pseudonyms: Option<&Pseudonymizer>,
+) -> Result<(), Box<dyn Error>> {
+    let mut statements: Vec<AccountStatement> = statements
+        .map(|statement| match pseudonyms {
+            Some(pseudonyms) => pseudonyms.statement(statement),
+            None => statement,
+        })
+        .map(|statement| statement.with_options(options))
+        .collect();
+    statements::label_currencies(&mut statements);
+    options
+        .order
+        .unwrap_or(StatementOrder::Client)
+        .sort(&mut statements);
+    write_canonical_rows(writer, statements, options)
+}
+
+/// Writes already ordered statements in the canonical format.
+pub(crate) fn write_canonical_rows<W: Write>(
+    mut writer: W,
+    statements: Vec<AccountStatement>,
+    options: &StatementOptions,
+) -> Result<(), Box<dyn Error>> {
+    let builder = || {
+        let mut builder = csv::WriterBuilder::new();
+        builder
+            .has_headers(false)
+            .terminator(csv::Terminator::Any(b'\n'));
+        builder
+    };
+    // Write the header explicitly, so it's present even without accounts.
+    let mut header = AccountStatement::header(options);
+    if statements
+        .iter()
+        .any(|statement| statement.currency().is_some())
+    {
+        header.insert(1, "currency");
+    }
+    let mut csv_writer = builder().from_writer(&mut writer);
+    csv_writer.write_record(header)?;
+    csv_writer.flush()?;
+    drop(csv_writer);
+    let rows: Vec<AccountStatement> = statements
+        .into_iter()
+        .map(AccountStatement::to_fixed_scale)
+        .collect();
+    statements::write_rows(writer, &rows, builder)
+}
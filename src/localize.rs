@@ -0,0 +1,342 @@
+//! Output localized for the people reading it.
+//!
+//! Statements and reports are written for machines by default: amounts with
+//! a decimal point and no grouping, and English column names and labels.
+//! Back offices elsewhere read them too, so an [`OutputLocale`] (see
+//! [`crate::RunOptions::locale`]), from a language tag such as `de-DE`,
+//! writes amounts and counts in the locale's notation (as an
+//! [`IngestionProfile`](crate::ingestion::IngestionProfile) reads them; see
+//! [`Locale`]) and, for the languages below, labels and booleans in its
+//! language. Other languages keep English labels.
+//!
+//! Only the statements and the HTML report are localized. Canonical
+//! statements (see [`crate::RunOptions::canonical`]) and every other output
+//! are for machines, and stay as they are.
+
+use crate::account::{AccountStatement, StatementOptions};
+use crate::ingestion::Locale;
+use crate::money::Money;
+use std::error::Error;
+use std::io::Write;
+
+/// Labels in English, with their translations.
+type Labels = &'static [(&'static str, &'static str)];
+
+const GERMAN: Labels = &[
+    ("client", "Kunde"),
+    ("currency", "Währung"),
+    ("available", "verfügbar"),
+    ("held", "einbehalten"),
+    ("total", "gesamt"),
+    ("owed", "geschuldet"),
+    ("shortfall", "Fehlbetrag"),
+    ("fees", "Gebühren"),
+    ("locked", "gesperrt"),
+    ("true", "ja"),
+    ("false", "nein"),
+    (
+        "Payments engine run report",
+        "Bericht zum Lauf der Zahlungs-Engine",
+    ),
+    ("Summary", "Zusammenfassung"),
+    ("Accounts", "Konten"),
+    ("Locked accounts", "Gesperrte Konten"),
+    ("Applied transactions", "Ausgeführte Transaktionen"),
+    ("Rejected transactions", "Abgelehnte Transaktionen"),
+    ("Failed transactions", "Fehlgeschlagene Transaktionen"),
+    ("Unreadable rows", "Unlesbare Zeilen"),
+    ("Duplicates skipped", "Übersprungene Duplikate"),
+    ("Top locked accounts", "Größte gesperrte Konten"),
+    ("No locked accounts.", "Keine gesperrten Konten."),
+    ("Client", "Kunde"),
+    ("Available", "Verfügbar"),
+    ("Held", "Einbehalten"),
+    ("Total", "Gesamt"),
+    ("Totals by currency", "Summen nach Währung"),
+    ("Currency", "Währung"),
+    ("Balances", "Salden"),
+    ("Largest movements", "Größte Bewegungen"),
+    ("No movements.", "Keine Bewegungen."),
+    ("Transaction", "Transaktion"),
+    ("Type", "Art"),
+    ("Amount", "Betrag"),
+    ("Rejections", "Ablehnungen"),
+    ("Failures", "Fehlschläge"),
+    ("None.", "Keine."),
+    ("Reason", "Grund"),
+    ("Count", "Anzahl"),
+    ("deposit", "Einzahlung"),
+    ("withdrawal", "Auszahlung"),
+    ("convert", "Umtausch"),
+];
+
+const FRENCH: Labels = &[
+    ("client", "client"),
+    ("currency", "devise"),
+    ("available", "disponible"),
+    ("held", "bloqué"),
+    ("total", "total"),
+    ("owed", "dû"),
+    ("shortfall", "insuffisance"),
+    ("fees", "frais"),
+    ("locked", "verrouillé"),
+    ("true", "oui"),
+    ("false", "non"),
+    (
+        "Payments engine run report",
+        "Rapport d'exécution du moteur de paiements",
+    ),
+    ("Summary", "Résumé"),
+    ("Accounts", "Comptes"),
+    ("Locked accounts", "Comptes verrouillés"),
+    ("Applied transactions", "Transactions appliquées"),
+    ("Rejected transactions", "Transactions rejetées"),
+    ("Failed transactions", "Transactions en échec"),
+    ("Unreadable rows", "Lignes illisibles"),
+    ("Duplicates skipped", "Doublons ignorés"),
+    ("Top locked accounts", "Principaux comptes verrouillés"),
+    ("No locked accounts.", "Aucun compte verrouillé."),
+    ("Client", "Client"),
+    ("Available", "Disponible"),
+    ("Held", "Bloqué"),
+    ("Total", "Total"),
+    ("Totals by currency", "Totaux par devise"),
+    ("Currency", "Devise"),
+    ("Balances", "Soldes"),
+    ("Largest movements", "Plus gros mouvements"),
+    ("No movements.", "Aucun mouvement."),
+    ("Transaction", "Transaction"),
+    ("Type", "Type"),
+    ("Amount", "Montant"),
+    ("Rejections", "Rejets"),
+    ("Failures", "Échecs"),
+    ("None.", "Aucun."),
+    ("Reason", "Motif"),
+    ("Count", "Nombre"),
+    ("deposit", "dépôt"),
+    ("withdrawal", "retrait"),
+    ("convert", "conversion"),
+];
+
+const SPANISH: Labels = &[
+    ("client", "cliente"),
+    ("currency", "divisa"),
+    ("available", "disponible"),
+    ("held", "retenido"),
+    ("total", "total"),
+    ("owed", "adeudado"),
+    ("shortfall", "déficit"),
+    ("fees", "comisiones"),
+    ("locked", "bloqueado"),
+    ("true", "sí"),
+    ("false", "no"),
+    (
+        "Payments engine run report",
+        "Informe de ejecución del motor de pagos",
+    ),
+    ("Summary", "Resumen"),
+    ("Accounts", "Cuentas"),
+    ("Locked accounts", "Cuentas bloqueadas"),
+    ("Applied transactions", "Transacciones aplicadas"),
+    ("Rejected transactions", "Transacciones rechazadas"),
+    ("Failed transactions", "Transacciones fallidas"),
+    ("Unreadable rows", "Filas ilegibles"),
+    ("Duplicates skipped", "Duplicados omitidos"),
+    ("Top locked accounts", "Principales cuentas bloqueadas"),
+    ("No locked accounts.", "No hay cuentas bloqueadas."),
+    ("Client", "Cliente"),
+    ("Available", "Disponible"),
+    ("Held", "Retenido"),
+    ("Total", "Total"),
+    ("Totals by currency", "Totales por divisa"),
+    ("Currency", "Divisa"),
+    ("Balances", "Saldos"),
+    ("Largest movements", "Mayores movimientos"),
+    ("No movements.", "No hay movimientos."),
+    ("Transaction", "Transacción"),
+    ("Type", "Tipo"),
+    ("Amount", "Importe"),
+    ("Rejections", "Rechazos"),
+    ("Failures", "Fallos"),
+    ("None.", "Ninguno."),
+    ("Reason", "Motivo"),
+    ("Count", "Cantidad"),
+    ("deposit", "depósito"),
+    ("withdrawal", "retirada"),
+    ("convert", "conversión"),
+];
+
+/// Languages with translated labels, by language subtag.
+const LANGUAGES: &[(&str, Labels)] = &[("de", GERMAN), ("es", SPANISH), ("fr", FRENCH)];
+
+/// How to write output for people in a locale. By default, output isn't
+/// localized.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputLocale {
+    /// How numbers are written, or as the engine writes them if `None`.
+    numbers: Option<Locale>,
+    labels: Labels,
+}
+
+impl std::str::FromStr for OutputLocale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let numbers: Locale = s.parse()?;
+        let language = s
+            .split(['-', '_'])
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let labels = LANGUAGES
+            .iter()
+            .find(|(tag, _)| *tag == language)
+            .map_or(&[][..], |(_, labels)| labels);
+        Ok(Self {
+            numbers: Some(numbers),
+            labels,
+        })
+    }
+}
+
+impl OutputLocale {
+    /// Whether output is localized at all.
+    pub fn is_localized(&self) -> bool {
+        self.numbers.is_some()
+    }
+
+    /// The language tag, for HTML's `lang` attribute.
+    pub fn tag(&self) -> &str {
+        self.numbers.as_ref().map_or("en", |locale| &locale.tag)
+    }
+
+    /// Whether numbers are written with a decimal comma, where CSV fields
+    /// are separated by semicolons instead.
+    pub fn decimal_comma(&self) -> bool {
+        self.numbers
+            .as_ref()
+            .is_some_and(|locale| locale.decimal == ',')
+    }
+
+    pub fn amount(&self, amount: &Money) -> String {
+        self.number(&amount.to_string())
+    }
+
+    pub fn count(&self, count: usize) -> String {
+        self.number(&count.to_string())
+    }
+
+    fn number(&self, number: &str) -> String {
+        match &self.numbers {
+            Some(locale) => locale.format(number),
+            None => number.to_owned(),
+        }
+    }
+
+    /// The label in the locale's language, or as it is if there's no
+    /// translation.
+    pub fn label<'a>(&self, label: &'a str) -> &'a str {
+        self.labels
+            .iter()
+            .find(|(english, _)| *english == label)
+            .map_or(label, |(_, translated)| translated)
+    }
+
+    pub fn boolean(&self, value: bool) -> &str {
+        self.label(if value { "true" } else { "false" })
+    }
+}
+
+/// Writes statements (with options already applied) as CSV, localized:
+/// column names, amounts and the `locked` flag. A `currency` column is
+/// included if any statement is labelled with one.
+pub fn write_statements<W: Write>(
+    writer: W,
+    statements: &[AccountStatement],
+    options: &StatementOptions,
+    locale: &OutputLocale,
+) -> Result<(), Box<dyn Error>> {
+    let mut header = AccountStatement::header(options);
+    let labelled = statements
+        .iter()
+        .any(|statement| statement.currency().is_some());
+    if labelled {
+        header.insert(1, "currency");
+    }
+    let mut csv_writer = csv::WriterBuilder::new()
+        .delimiter(if locale.decimal_comma() { b';' } else { b',' })
+        .from_writer(writer);
+    csv_writer.write_record(header.iter().map(|column| locale.label(column)))?;
+    for statement in statements {
+        let mut row = vec![statement.client().to_string()];
+        if labelled {
+            row.push(
+                statement
+                    .currency()
+                    .map(|c| c.to_string())
+                    .unwrap_or_default(),
+            );
+        }
+        let amounts = [
+            Some(statement.available()),
+            Some(statement.held()),
+            Some(statement.total()),
+            statement.owed(),
+            statement.shortfall(),
+            statement.fees(),
+        ];
+        row.extend(amounts.into_iter().flatten().map(|a| locale.amount(a)));
+        row.push(locale.boolean(statement.locked()).to_owned());
+        csv_writer.write_record(row)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::{Account, TotalPolicy};
+    use crate::money::money;
+
+    #[test]
+    fn statements_localized() {
+        let german: OutputLocale = "de-DE".parse().unwrap();
+        assert_eq!(german.tag(), "de-DE");
+        assert_eq!(german.amount(&money!(-12345.5)), "-12.345,5");
+        assert_eq!(german.count(1000), "1.000");
+        assert_eq!(german.label("Summary"), "Zusammenfassung");
+        assert_eq!(german.label("InsufficientFunds"), "InsufficientFunds");
+        // Numbers localized, but no translations.
+        let dutch: OutputLocale = "nl".parse().unwrap();
+        assert_eq!(dutch.amount(&money!(2.5)), "2,5");
+        assert_eq!(dutch.boolean(true), "true");
+        let english = OutputLocale::default();
+        assert!(!english.is_localized());
+        assert_eq!(english.amount(&money!(12345.5)), "12345.5");
+        assert!("x".parse::<OutputLocale>().is_err());
+
+        let mut account = Account::new(1);
+        account.set_funds(money!(-1234.5), Money::zero());
+        let options = StatementOptions {
+            total_policy: TotalPolicy::ClampWithOwed,
+            ..StatementOptions::default()
+        };
+        let statements = [AccountStatement::from(&account).with_options(&options)];
+        let mut output = vec![];
+        write_statements(&mut output, &statements, &options, &german).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "Kunde;verfügbar;einbehalten;gesamt;geschuldet;gesperrt\n\
+             1;0;0;0;1.234,5;nein\n"
+        );
+        let french: OutputLocale = "fr-CH".parse().unwrap();
+        let mut output = vec![];
+        write_statements(&mut output, &statements, &options, &french).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,disponible,bloqué,total,dû,verrouillé\n\
+             1,0,0,0,1'234.5,non\n"
+        );
+    }
+}
@@ -1,11 +1,1286 @@
 use std::env;
 use std::error::Error;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: payments_engine::resources::TrackingAllocator =
+    payments_engine::resources::TrackingAllocator;
+
+use payments_engine::admin::AdminClient;
+use payments_engine::backfill;
+use payments_engine::bench::{self, StoreBackend};
+use payments_engine::bulk::{BulkDisputeOptions, DisputeAction};
+use payments_engine::checkpoint::CheckpointOptions;
+use payments_engine::clock::{self, SystemClock};
+use payments_engine::delta::DeltaOptions;
+use payments_engine::diff;
+use payments_engine::encryption::{self, Key, KeyFile, KeyProvider, OutputFile};
+use payments_engine::export::{ExportFormat, ExportOptions};
+use payments_engine::fx::RateTable;
+use payments_engine::generator::WorkloadConfig;
+use payments_engine::impact;
+use payments_engine::ingestion;
+use payments_engine::input::{self, InputFormat};
+use payments_engine::inspect::{self, InspectQuery};
+use payments_engine::manifest::ManifestOptions;
+use payments_engine::notes;
+use payments_engine::period::BusinessDateOptions;
+use payments_engine::quarantine;
+use payments_engine::receipt::{ReceiptLog, ReceiptSigner};
+use payments_engine::repro::ReproBundle;
+use payments_engine::screening::{ScreeningConfig, ScreeningOptions};
+use payments_engine::self_check::{self, SelfCheck};
+use payments_engine::server::PaymentsService;
+use payments_engine::shadow::{self, EngineKind, ShadowSide};
+use payments_engine::shared_engine::SharedTxEngine;
+use payments_engine::signing::{self, SigningOptions, StatementSigner};
+use payments_engine::snapshot::{self, Compression};
+use payments_engine::subscriptions::Grants;
+use payments_engine::{
+    AccountStore, DormancyPolicy, EngineConfig, InMemoryStore, LockScope, PseudonymOptions,
+    RunOptions, StatementOrder, TxEngine,
+};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let infile = env::args().nth(1).expect("No input CSV file given.");
-    let reader = std::fs::File::open(Path::new(&infile))?;
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("bench") => {
+            args.next();
+            run_bench(args)
+        }
+        Some("diff") => {
+            args.next();
+            run_diff(args)
+        }
+        Some("shadow") => {
+            args.next();
+            run_shadow(args)
+        }
+        Some("impact") => {
+            args.next();
+            run_impact(args)
+        }
+        Some("backfill") => {
+            args.next();
+            run_backfill(args)
+        }
+        Some("release") => {
+            args.next();
+            run_release(args)
+        }
+        Some("annotate") => {
+            args.next();
+            run_annotate(args)
+        }
+        Some("inspect") => {
+            args.next();
+            run_inspect(args)
+        }
+        Some("decrypt") => {
+            args.next();
+            run_decrypt(args)
+        }
+        Some("verify") => {
+            args.next();
+            run_verify(args)
+        }
+        Some("public-key") => {
+            args.next();
+            run_public_key(args)
+        }
+        Some("repro") => {
+            args.next();
+            run_repro(args)
+        }
+        Some("serve") => {
+            args.next();
+            run_serve(args)
+        }
+        Some("admin") => {
+            args.next();
+            run_admin(args)
+        }
+        _ => run(args),
+    }
+}
+
+/// Processes one or more input CSV files in order, printing account
+/// statements to stdout.
+fn run(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut infiles = vec![];
+    let mut options = RunOptions::default();
+    let mut export_format = ExportFormat::Csv;
+    let mut export_clients = None;
+    let mut manifest = None;
+    let mut block_dormant_withdrawals = false;
+    let mut bulk_disputes = None;
+    let mut flags_report = None;
+    let mut screening_config = ScreeningConfig::default();
+    let mut bulk_action = DisputeAction::Open;
+    let mut bulk_results = None;
+    let mut pseudonym_key = None;
+    let mut pseudonym_mapping = None;
+    let mut sign_key = None;
+    let mut signature = None;
+    let mut business_date = None;
+    let mut period_report = None;
+    let mut period_statements = None;
+    let mut delta_from = None;
+    let mut tombstones = None;
+    let mut checkpoint_every = None;
+    let mut check_only = false;
+    let mut resource_usage = false;
+    let mut input_format = None;
+    let mut csv_delimiter = None;
+    let mut ingestion_profiles = None;
+    let mut ingestion_profile = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--self-check" => check_only = true,
+            "--resource-usage" => resource_usage = true,
+            "--plugin" => {
+                let path = value()?;
+                options.plugins.push(load_plugin(&path)?);
+            }
+            "--export-dir" => {
+                let dir = value()?;
+                options.export = Some(ExportOptions {
+                    dir: dir.into(),
+                    format: ExportFormat::Csv,
+                    clients: None,
+                });
+            }
+            "--html-report" => {
+                let path = value()?;
+                options.html_report = Some(path.into());
+            }
+            "--metrics" => {
+                let path = value()?;
+                options.metrics = Some(path.into());
+            }
+            "--hold-policy" => {
+                let policy = value()?;
+                options.engine.hold_policy = policy.parse()?;
+            }
+            "--chargeback-lock" => {
+                let scope = value()?;
+                options.engine.chargeback_lock_scope = scope.parse()?;
+            }
+            "--reason-policy" => {
+                let policy = value()?;
+                let (reason, policy) = policy
+                    .split_once('=')
+                    .ok_or("--reason-policy requires <reason>=<lock scope or none>.")?;
+                options
+                    .engine
+                    .reason_policies
+                    .insert(reason.parse()?, policy.parse()?);
+            }
+            "--idempotent-settlement" => options.engine.idempotent_settlement = true,
+            "--disputes-on-locked" => options.engine.disputes_on_locked_accounts = true,
+            "--canonical" => options.canonical = true,
+            "--output-locale" => {
+                let tag = value()?;
+                options.locale = tag.parse()?;
+            }
+            "--sort" => {
+                let order = value()?;
+                options.statement.order = Some(order.parse()?);
+            }
+            "--manifest" => {
+                let path = value()?;
+                manifest = Some(path);
+            }
+            "--events" => {
+                let path = value()?;
+                options.events = Some(path.into());
+            }
+            "--dormant-after" => {
+                let after = value()?;
+                options.engine.dormancy = Some(DormancyPolicy {
+                    after: after.parse()?,
+                    block_withdrawals: false,
+                });
+            }
+            "--block-dormant-withdrawals" => block_dormant_withdrawals = true,
+            "--dormancy-report" => {
+                let path = value()?;
+                options.dormancy_report = Some(path.into());
+            }
+            "--system-statement" => {
+                let path = value()?;
+                options.system_statement = Some(path.into());
+            }
+            "--bulk-disputes" => {
+                let path = value()?;
+                bulk_disputes = Some(path);
+            }
+            "--bulk-action" => {
+                let action = value()?;
+                bulk_action = action.parse()?;
+            }
+            "--bulk-results" => {
+                let path = value()?;
+                bulk_results = Some(path);
+            }
+            "--notes" => {
+                let path = value()?;
+                options.notes = Some(path.into());
+            }
+            "--destination-blocklist" => {
+                let path = value()?;
+                for line in std::fs::read_to_string(path)?.lines() {
+                    let destination = line.trim();
+                    if !destination.is_empty() {
+                        options
+                            .engine
+                            .destinations
+                            .blocklist
+                            .insert(destination.to_string());
+                    }
+                }
+            }
+            "--destination-limit" => {
+                let limit = value()?;
+                options.engine.destinations.limit = Some(limit.parse()?);
+            }
+            "--destination-limit-for" => {
+                let limit = value()?;
+                let (destination, amount) = limit
+                    .split_once('=')
+                    .ok_or("--destination-limit-for requires <destination>=<amount>.")?;
+                options
+                    .engine
+                    .destinations
+                    .limits
+                    .insert(destination.to_string(), amount.parse()?);
+            }
+            "--opening-balances" => {
+                let path = value()?;
+                options.opening_balances = Some(path.into());
+            }
+            "--quarantine" => {
+                let client = value()?;
+                options.quarantine.push(client.parse()?);
+            }
+            "--max-held" => {
+                let max_held = value()?;
+                options.engine.max_held = Some(max_held.parse()?);
+            }
+            "--dispute-window" => {
+                let days = value()?;
+                options.engine.dispute_window_days = Some(days.parse()?);
+            }
+            "--fx-rates" => {
+                let path = value()?;
+                options.engine.fx.rates = RateTable::read_csv(File::open(path)?)?;
+            }
+            "--fx-rounding" => {
+                let rounding = value()?;
+                let (currency, rounding) = rounding
+                    .split_once('=')
+                    .ok_or("--fx-rounding requires <currency>=<decimal places>[:<mode>].")?;
+                options
+                    .engine
+                    .fx
+                    .rounding
+                    .insert(currency.to_ascii_uppercase(), rounding.parse()?);
+            }
+            "--fee" => {
+                let fee = value()?;
+                let (kind, fee) = fee
+                    .split_once('=')
+                    .ok_or("--fee requires <deposit|withdrawal>=<flat>[+<percent>%].")?;
+                match kind {
+                    "deposit" => options.engine.fees.deposit = fee.parse()?,
+                    "withdrawal" => options.engine.fees.withdrawal = fee.parse()?,
+                    _ => {
+                        return Err(format!(
+                            "Fees only apply to deposits and withdrawals, not {:?}.",
+                            kind
+                        )
+                        .into())
+                    }
+                }
+            }
+            "--interest" => {
+                let policy = value()?;
+                options.engine.interest = Some(policy.parse()?);
+            }
+            "--interest-as-of" => {
+                let time = value()?;
+                options.interest_as_of = Some(clock::parse_timestamp(&time)?);
+            }
+            "--low-balance" => {
+                let low = value()?;
+                options.engine.thresholds.default.low = Some(low.parse()?);
+            }
+            "--high-balance" => {
+                let high = value()?;
+                options.engine.thresholds.default.high = Some(high.parse()?);
+            }
+            "--thresholds" => {
+                let path = value()?;
+                options
+                    .engine
+                    .thresholds
+                    .read_csv(BufReader::new(File::open(path)?))?;
+            }
+            "--overdraft-limit" => {
+                let limit = value()?;
+                options.engine.overdraft.limit = limit.parse()?;
+                if options.engine.overdraft.limit.is_negative() {
+                    return Err("--overdraft-limit can't be negative".into());
+                }
+            }
+            "--overdraft-limits" => {
+                let path = value()?;
+                options
+                    .engine
+                    .overdraft
+                    .read_csv(BufReader::new(File::open(path)?))?;
+            }
+            "--max-withdrawal" => {
+                let max = value()?;
+                options.engine.risk.max_withdrawal = Some(max.parse()?);
+            }
+            "--max-daily-withdrawals" => {
+                let max = value()?;
+                options.engine.risk.max_daily_withdrawals = Some(max.parse()?);
+            }
+            "--max-transactions" => {
+                let max = value()?;
+                options.engine.risk.max_transactions = Some(max.parse()?);
+            }
+            "--workers" => {
+                let workers = value()?;
+                options.workers = workers.parse()?;
+            }
+            "--checkpoint" => {
+                let path = value()?;
+                options.checkpoint = Some(CheckpointOptions::new(path));
+            }
+            "--checkpoint-every" => {
+                let rows = value()?;
+                checkpoint_every = Some(rows.parse()?);
+            }
+            "--resume" => {
+                let path = value()?;
+                options.resume = Some(path.into());
+            }
+            "--quarantine-report" => {
+                let path = value()?;
+                options.quarantine_report = Some(path.into());
+            }
+            "--snapshot" => {
+                let path = value()?;
+                options.snapshot = Some(path.into());
+            }
+            "--key-file" => {
+                let path = value()?;
+                options.encryption = Some(Box::new(KeyFile(path.into())));
+            }
+            "--pseudonym-key" => {
+                let path = value()?;
+                pseudonym_key = Some(path);
+            }
+            "--pseudonym-mapping" => {
+                let path = value()?;
+                pseudonym_mapping = Some(path);
+            }
+            "--rejects" => {
+                let path = value()?;
+                options.rejects = Some(path.into());
+            }
+            "--sign-key" => {
+                let path = value()?;
+                sign_key = Some(path);
+            }
+            "--signature" => {
+                let path = value()?;
+                signature = Some(path);
+            }
+            "--input-format" => {
+                let format = value()?;
+                input_format = match format.as_str() {
+                    "auto" => None,
+                    format => Some(format.parse()?),
+                };
+            }
+            "--delimiter" => {
+                let delimiter = value()?;
+                match delimiter.as_str() {
+                    "tab" | "\\t" => csv_delimiter = Some(b'\t'),
+                    delimiter if delimiter.len() == 1 => {
+                        csv_delimiter = Some(delimiter.as_bytes()[0])
+                    }
+                    _ => return Err("--delimiter requires a single ASCII character or tab.".into()),
+                }
+            }
+            "--profiles" => {
+                let path = value()?;
+                ingestion_profiles = Some(ingestion::read_profiles(Path::new(&path))?);
+            }
+            "--profile" => {
+                let name = value()?;
+                ingestion_profile = Some(name);
+            }
+            "--delta-from" => {
+                let path = value()?;
+                delta_from = Some(path.into());
+            }
+            "--tombstones" => {
+                let path = value()?;
+                tombstones = Some(path.into());
+            }
+            "--snapshot-compression" => {
+                let compression = value()?;
+                options.snapshot_compression = compression.parse()?;
+            }
+            "--store-layout" => {
+                let layout = value()?;
+                options.store_layout = layout.parse()?;
+            }
+            "--business-date" => {
+                let date = value()?;
+                business_date = Some(date);
+            }
+            "--period-report" => {
+                let path = value()?;
+                period_report = Some(path.into());
+            }
+            "--period-statements" => {
+                let dir = value()?;
+                period_statements = Some(dir.into());
+            }
+            "--trial-balance" => {
+                let path = value()?;
+                options.trial_balance = Some(path.into());
+            }
+            "--profile-report" => {
+                let path = value()?;
+                options.profile_report = Some(path.into());
+            }
+            "--flags-report" => {
+                let path = value()?;
+                flags_report = Some(path);
+            }
+            "--structuring-threshold" => {
+                let threshold = value()?;
+                screening_config.threshold = threshold.parse()?;
+            }
+            "--statement-shortfall" => options.statement.shortfall = true,
+            "--statement-fees" => options.statement.fees = true,
+            "--repro-dir" => {
+                let dir = value()?;
+                options.repro_dir = Some(dir.into());
+            }
+            "--total-policy" => {
+                let policy = value()?;
+                options.statement.total_policy = policy.parse()?;
+            }
+            "--export-format" => {
+                let format = value()?;
+                export_format = format.parse()?;
+            }
+            "--export-clients" => {
+                let clients = value()?;
+                export_clients = Some(
+                    clients
+                        .split(',')
+                        .map(|id| id.trim().parse())
+                        .collect::<Result<_, _>>()?,
+                );
+            }
+            _ => infiles.push(arg),
+        }
+    }
+    if let Some(export) = options.export.as_mut() {
+        export.format = export_format;
+        export.clients = export_clients;
+    }
+    if let (Some(checkpoint), Some(rows)) = (options.checkpoint.as_mut(), checkpoint_every) {
+        checkpoint.interval = rows;
+    }
+    if let Some(dormancy) = options.engine.dormancy.as_mut() {
+        dormancy.block_withdrawals = block_dormant_withdrawals;
+    }
+    if let Some(input) = bulk_disputes {
+        options.bulk_disputes = Some(BulkDisputeOptions {
+            input: input.into(),
+            action: bulk_action,
+            results: bulk_results
+                .ok_or("--bulk-disputes requires --bulk-results.")?
+                .into(),
+        });
+    }
+    options.pseudonymize = match (pseudonym_key, pseudonym_mapping) {
+        (Some(key), Some(mapping)) => Some(PseudonymOptions {
+            key: Box::new(KeyFile(key.into())),
+            mapping: mapping.into(),
+        }),
+        (None, None) => None,
+        _ => return Err("--pseudonym-key and --pseudonym-mapping must be given together.".into()),
+    };
+    options.sign_statements = match (sign_key, signature) {
+        (Some(key), Some(signature)) => Some(SigningOptions {
+            key: Box::new(KeyFile(key.into())),
+            signature: signature.into(),
+        }),
+        (None, None) => None,
+        _ => return Err("--sign-key and --signature must be given together.".into()),
+    };
+    options.business_dates = match business_date {
+        Some(opening_date) => Some(BusinessDateOptions {
+            opening_date,
+            movements: period_report,
+            statements_dir: period_statements,
+        }),
+        None if period_report.is_some() || period_statements.is_some() => {
+            return Err("Period reports require --business-date.".into())
+        }
+        None => None,
+    };
+    options.delta = match delta_from {
+        Some(previous) => Some(DeltaOptions {
+            previous,
+            tombstones,
+        }),
+        None if tombstones.is_some() => return Err("Tombstones require --delta-from.".into()),
+        None => None,
+    };
+    options.screening = flags_report.map(|path| ScreeningOptions {
+        path: path.into(),
+        config: screening_config,
+    });
+    let mut infiles = infiles.into_iter();
+    let infile = infiles.next().expect("No input CSV file given.");
+    options.extra_inputs = infiles.map(Into::into).collect();
+    // A profile named, or else the first whose paths match the input.
+    options.ingestion = match (&ingestion_profiles, ingestion_profile) {
+        (Some(profiles), name) => {
+            match ingestion::select(profiles, name.as_deref(), Path::new(&infile))? {
+                Some((name, profile)) => {
+                    eprintln!("Using ingestion profile {}", name);
+                    Some(profile.clone())
+                }
+                None => None,
+            }
+        }
+        (None, Some(_)) => return Err("--profile requires --profiles.".into()),
+        (None, None) => None,
+    };
+    options.manifest = manifest.map(|path| ManifestOptions {
+        path: path.into(),
+        input_name: infile.clone(),
+    });
+    let self_check = SelfCheck::for_run(Path::new(&infile), &options);
+    if check_only || !self_check.passed() {
+        eprint!("{}", self_check);
+    }
+    self_check.into_result()?;
+    // Further inputs are read in the same format. A delimiter implies CSV.
+    options.input_format = match (input_format, csv_delimiter) {
+        (Some(InputFormat::JsonLines), Some(_)) => {
+            return Err("--delimiter only applies to CSV input.".into())
+        }
+        (_, Some(delimiter)) => InputFormat::Csv { delimiter },
+        (Some(format), None) => format,
+        (None, None) => {
+            let renames = options
+                .ingestion
+                .as_ref()
+                .map(|profile| profile.columns.clone())
+                .unwrap_or_default();
+            let detection = input::detect_file_with(Path::new(&infile), &renames)?;
+            eprintln!("Detected input format: {}", detection);
+            detection.format
+        }
+    };
+    if check_only {
+        return Ok(());
+    }
+    let reader = File::open(Path::new(&infile))?;
     let writer = std::io::stdout();
-    payments_engine::run_with_csv(reader, writer)?;
+    let report = payments_engine::process(reader, writer, options)?;
+    if resource_usage {
+        eprint!("{}", report.resources);
+    }
+    Ok(())
+}
+
+/// `bench [--transactions N] [--clients N] [--seed N] [--store memory|dense|tiered]`
+///
+/// Runs a synthetic workload through the engine and reports throughput,
+/// latency and memory usage.
+fn run_bench(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut config = WorkloadConfig::default();
+    let mut backend = StoreBackend::Memory;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--transactions" => config.transactions = value()?.parse()?,
+            "--clients" => config.clients = value()?.parse()?,
+            "--seed" => config.seed = value()?.parse()?,
+            "--store" => backend = value()?.parse()?,
+            _ => return Err(format!("Unknown bench argument {:?}", arg).into()),
+        }
+    }
+    let report = bench::run(&config, backend);
+    print!("{}", report);
+    Ok(())
+}
+
+/// Replays an input through two engines side by side, printing where they
+/// diverged. Fails if they did.
+fn run_shadow(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut input = None;
+    let mut primary = ShadowSide {
+        engine: EngineKind::Single,
+        config: EngineConfig::default(),
+    };
+    let mut shadow = primary.clone();
+    let mut divergences = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--primary" => primary.engine = value()?.parse()?,
+            "--shadow" => shadow.engine = value()?.parse()?,
+            "--primary-config" => primary.config = read_engine_config(&value()?)?,
+            "--shadow-config" => shadow.config = read_engine_config(&value()?)?,
+            "--divergences" => divergences = Some(value()?),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unknown shadow argument {:?}", arg).into()),
+        }
+    }
+    let input = input.ok_or("shadow requires an input path.")?;
+    let report = shadow::run(BufReader::new(File::open(input)?), &primary, &shadow)?;
+    print!("{}", report);
+    if let Some(path) = divergences {
+        report.write_divergences(BufWriter::new(File::create(path)?))?;
+    }
+    if !report.agrees() {
+        return Err("Engines diverged.".into());
+    }
+    Ok(())
+}
+
+/// Replays an input under the current and a proposed engine configuration,
+/// printing the outcomes that would change and how many accounts that
+/// affects.
+fn run_impact(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut input = None;
+    let mut current = EngineConfig::default();
+    let mut proposed = None;
+    let mut accounts = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--current" => current = read_engine_config(&value()?)?,
+            "--proposed" => proposed = Some(read_engine_config(&value()?)?),
+            "--accounts" => accounts = Some(value()?),
+            _ if input.is_none() => input = Some(arg),
+            _ => return Err(format!("Unknown impact argument {:?}", arg).into()),
+        }
+    }
+    let input = input.ok_or("impact requires an input path.")?;
+    let proposed = proposed.ok_or("impact requires --proposed.")?;
+    let report = impact::analyze(BufReader::new(File::open(input)?), &current, &proposed)?;
+    print!("{}", report);
+    if let Some(path) = accounts {
+        report.write_accounts(BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
+
+/// Reads an engine configuration from a JSON file, as recorded in a run
+/// manifest. Unset policies take their defaults.
+fn read_engine_config(path: &str) -> Result<EngineConfig, Box<dyn Error>> {
+    self_check::read_config(Path::new(path)).map_err(|err| format!("{}: {}", path, err).into())
+}
+
+/// Compares two engine state snapshots, printing the accounts that changed.
+fn run_diff(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [before, after] =
+        <[String; 2]>::try_from(paths).map_err(|_| "diff requires exactly two snapshot paths.")?;
+    let before = read_snapshot_file(&before, key.as_ref())?;
+    let after = read_snapshot_file(&after, key.as_ref())?;
+    let diffs = diff::diff(before.store(), after.store());
+    diff::write_diff(std::io::stdout().lock(), &diffs)
+}
+
+/// Applies a corrections file to a saved snapshot, printing the compensating
+/// entries posted and writing the corrected state to a new snapshot.
+fn run_backfill(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    let mut output_snapshot = None;
+    let mut compression = Compression::None;
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            "--output-snapshot" => {
+                let path = value()?;
+                output_snapshot = Some(path);
+            }
+            "--snapshot-compression" => {
+                let value = value()?;
+                compression = value.parse()?;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [snapshot, corrections] = <[String; 2]>::try_from(paths)
+        .map_err(|_| "backfill requires a snapshot path and a corrections path.")?;
+    let output_snapshot = output_snapshot.ok_or("backfill requires --output-snapshot.")?;
+    let mut engine = read_snapshot_file(&snapshot, key.as_ref())?;
+    let corrections = backfill::read_corrections(File::open(corrections)?)?;
+    let results: Vec<_> = corrections
+        .iter()
+        .map(|correction| engine.correct(correction))
+        .collect();
+    let mut file = OutputFile::create(Path::new(&output_snapshot), key.as_ref())?;
+    snapshot::write_snapshot(&mut file, &engine, compression)?;
+    file.finish()?;
+    backfill::write_entries(std::io::stdout().lock(), &corrections, &results)
+}
+
+/// Releases a client's quarantine in a saved snapshot, applying the
+/// transactions queued for it and writing the resulting state to a new
+/// snapshot.
+fn run_release(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut snapshot = None;
+    let mut client = None;
+    let mut output_snapshot = None;
+    let mut compression = Compression::None;
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            "--client" => {
+                let id = value()?;
+                client = Some(id.parse()?);
+            }
+            "--output-snapshot" => {
+                let path = value()?;
+                output_snapshot = Some(path);
+            }
+            "--snapshot-compression" => {
+                let value = value()?;
+                compression = value.parse()?;
+            }
+            _ if snapshot.is_none() => snapshot = Some(arg),
+            _ => return Err(format!("Unknown release argument {:?}", arg).into()),
+        }
+    }
+    let snapshot = snapshot.ok_or("release requires a snapshot path.")?;
+    let client = client.ok_or("release requires --client.")?;
+    let output_snapshot = output_snapshot.ok_or("release requires --output-snapshot.")?;
+    let mut engine = read_snapshot_file(&snapshot, key.as_ref())?;
+    if !engine.is_quarantined(client) {
+        return Err(format!("Client {} isn't quarantined.", client).into());
+    }
+    let released = engine.release_quarantine(client);
+    let mut file = OutputFile::create(Path::new(&output_snapshot), key.as_ref())?;
+    snapshot::write_snapshot(&mut file, &engine, compression)?;
+    file.finish()?;
+    quarantine::write_released(std::io::stdout().lock(), &released)
+}
+
+/// Attaches a notes file to a saved snapshot, writing the annotated state to
+/// a new snapshot. The audit events raised, after any the snapshot still
+/// held, are printed as newline-delimited JSON.
+fn run_annotate(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    let mut output_snapshot = None;
+    let mut compression = Compression::None;
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            "--output-snapshot" => {
+                let path = value()?;
+                output_snapshot = Some(path);
+            }
+            "--snapshot-compression" => {
+                let value = value()?;
+                compression = value.parse()?;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [snapshot, notes] = <[String; 2]>::try_from(paths)
+        .map_err(|_| "annotate requires a snapshot path and a notes path.")?;
+    let output_snapshot = output_snapshot.ok_or("annotate requires --output-snapshot.")?;
+    let mut engine = read_snapshot_file(&snapshot, key.as_ref())?;
+    for note in notes::read_notes(File::open(notes)?)? {
+        engine.annotate(&note)?;
+    }
+    let mut stdout = std::io::stdout().lock();
+    for event in engine.drain_events() {
+        serde_json::to_writer(&mut stdout, &event)?;
+        stdout.write_all(b"\n")?;
+    }
+    let mut file = OutputFile::create(Path::new(&output_snapshot), key.as_ref())?;
+    snapshot::write_snapshot(&mut file, &engine, compression)?;
+    file.finish()
+}
+
+/// Queries a saved snapshot, printing the statements of matching accounts.
+/// With `--limit` or `--after`, prints a page of them, and the cursor for the
+/// next page to stderr.
+fn run_inspect(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut snapshot = None;
+    let mut query = InspectQuery::default();
+    let mut key = None;
+    let mut order = StatementOrder::Client;
+    let mut limit = None;
+    let mut after = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            "--client" => {
+                let client = value()?;
+                query.client = Some(client.parse()?);
+            }
+            "--locked" => query.locked = true,
+            "--disputed" => query.disputed = true,
+            "--order" => {
+                let value = value()?;
+                order = value.parse()?;
+            }
+            "--limit" => {
+                let value = value()?;
+                limit = Some(value.parse()?);
+            }
+            "--after" => {
+                let client = value()?;
+                after = Some(client.parse()?);
+            }
+            _ if snapshot.is_none() => snapshot = Some(arg),
+            _ => return Err(format!("Unknown inspect argument {:?}", arg).into()),
+        }
+    }
+    let snapshot = snapshot.ok_or("inspect requires a snapshot path.")?;
+    let engine = read_snapshot_file(&snapshot, key.as_ref())?;
+    if limit.is_none() && after.is_none() {
+        let mut statements = inspect::select(engine.store(), &query);
+        order.sort(&mut statements);
+        return inspect::write_statements(std::io::stdout().lock(), statements);
+    }
+    let limit = limit.unwrap_or(inspect::MAX_PAGE_SIZE);
+    let page = inspect::page(engine.store(), &query, order, after, limit)?;
+    inspect::write_statements(std::io::stdout().lock(), page.statements)?;
+    if let Some(next) = page.next {
+        eprintln!("Next page: --after {}", next);
+    }
+    Ok(())
+}
+
+/// `repro <bundle> [--key-file <path>]`
+///
+/// Replays a failed transaction from its repro bundle, printing the
+/// client's statement as the transaction found it and whether it fails the
+/// same way again.
+fn run_repro(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let key_path = value()?;
+                key = Some(KeyFile(key_path.into()).key()?);
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unknown repro argument {:?}", arg).into()),
+        }
+    }
+    let path = path.ok_or("repro requires a bundle path.")?;
+    let bundle = ReproBundle::read(&encryption::read_file(Path::new(&path), key.as_ref())?)?;
+    let statements: Vec<_> = bundle
+        .engine
+        .store()
+        .accounts()
+        .flat_map(|account| account.statements())
+        .collect();
+    inspect::write_statements(std::io::stdout().lock(), statements)?;
+    println!("{}", bundle.replay());
+    Ok(())
+}
+
+/// Decrypts an encrypted snapshot or event log, printing its contents.
+fn run_decrypt(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unknown decrypt argument {:?}", arg).into()),
+        }
+    }
+    let path = path.ok_or("decrypt requires a path.")?;
+    let key = key.ok_or("decrypt requires --key-file.")?;
+    let contents = encryption::read_file(Path::new(&path), Some(&key))?;
+    std::io::stdout().lock().write_all(&contents)?;
+    Ok(())
+}
+
+/// Checks a statement file against its detached signature. Fails if it
+/// doesn't match.
+fn run_verify(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut path = None;
+    let mut public_key = None;
+    let mut signature = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a path.", arg));
+        match arg.as_str() {
+            "--public-key" => public_key = Some(std::fs::read_to_string(value()?)?),
+            "--signature" => signature = Some(std::fs::read_to_string(value()?)?),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("Unknown verify argument {:?}", arg).into()),
+        }
+    }
+    let path = path.ok_or("verify requires a statements path.")?;
+    let public_key = public_key.ok_or("verify requires --public-key.")?;
+    let signature = signature.ok_or("verify requires --signature.")?;
+    signing::verify(&public_key, &std::fs::read(&path)?, &signature)?;
+    println!("{}: signature verified", path);
+    Ok(())
+}
+
+/// Prints the public key verifying statements signed with a signing key, to
+/// share with consumers.
+fn run_public_key(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut key = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--key-file" => {
+                let path = value()?;
+                key = Some(KeyFile(path.into()).key()?);
+            }
+            _ => return Err(format!("Unknown public-key argument {:?}", arg).into()),
+        }
+    }
+    let key = key.ok_or("public-key requires --key-file.")?;
+    println!("{}", StatementSigner::new(key).public_key()?);
     Ok(())
 }
+
+/// Serves the engine over HTTP and/or gRPC until a server fails.
+fn run_serve(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut http = None;
+    let mut grpc = None;
+    let mut admin = None;
+    let mut config = EngineConfig::default();
+    let mut config_path = None;
+    let mut snapshot_path = None;
+    let mut receipt_key = None;
+    let mut receipt_log = None;
+    let mut commit_every = None;
+    let mut cutover_every = None;
+    let mut period_dir = None;
+    let mut max_in_flight = None;
+    let mut ack_retention = None;
+    let mut key_file = None;
+    let mut subscribers = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--ack-retention" => {
+                let seconds: u64 = value()?.parse()?;
+                ack_retention = Some(std::time::Duration::from_secs(seconds));
+            }
+            "--max-in-flight" => {
+                let limit: usize = value()?.parse()?;
+                max_in_flight = Some(limit);
+            }
+            "--receipt-key" => receipt_key = Some(value()?),
+            "--commit-every" => {
+                let seconds: u64 = value()?.parse()?;
+                commit_every = Some(std::time::Duration::from_secs(seconds.max(1)));
+            }
+            "--cutover-every" => {
+                let seconds: u64 = value()?.parse()?;
+                cutover_every = Some(std::time::Duration::from_secs(seconds.max(1)));
+            }
+            "--period-dir" => period_dir = Some(PathBuf::from(value()?)),
+            "--receipts" => receipt_log = Some(value()?),
+            "--addr" => http = Some(value()?.parse()?),
+            "--grpc" => grpc = Some(value()?.parse()?),
+            "--admin" => admin = Some(value()?.parse()?),
+            "--snapshot" => snapshot_path = Some(value()?),
+            "--config" => config_path = Some(value()?),
+            "--key-file" => key_file = Some(value()?),
+            "--subscribers" => subscribers = Some(value()?),
+            _ => return Err(format!("Unknown serve argument {:?}", arg).into()),
+        }
+    }
+    if http.is_none() && grpc.is_none() {
+        return Err("serve requires --addr or --grpc.".into());
+    }
+    let cutover = match (cutover_every, period_dir) {
+        (Some(interval), Some(dir)) => Some((interval, dir)),
+        (None, None) => None,
+        _ => return Err("--cutover-every and --period-dir must be given together.".into()),
+    };
+    // Reported in full, as servers' logs are where problems are looked for.
+    let mut self_check = SelfCheck::new();
+    if let Some(path) = &config_path {
+        config = self_check.config(Path::new(path)).unwrap_or_default();
+    }
+    for path in [&receipt_key, &key_file, &subscribers]
+        .into_iter()
+        .flatten()
+    {
+        self_check.input(Path::new(path));
+    }
+    for path in [&receipt_log, &snapshot_path].into_iter().flatten() {
+        self_check.output(Path::new(path));
+    }
+    if let Some((_, dir)) = &cutover {
+        self_check.output_dir(dir);
+    }
+    let mut engine = SharedTxEngine::new(config);
+    if let Some(retention) = ack_retention {
+        engine = engine.with_ack_retention(retention);
+    }
+    self_check.store(|| {
+        engine
+            .statement_page(&InspectQuery::default(), StatementOrder::Client, None, 1)
+            .map(|page| page.statements.len())
+    });
+    eprint!("{}", self_check);
+    self_check.into_result()?;
+    // Submissions are stamped with the time they arrive, unless they say
+    // otherwise.
+    engine.set_clock(SystemClock);
+    let mut service = PaymentsService::new(engine);
+    match (receipt_key, receipt_log) {
+        (Some(key), log) => {
+            let signer = ReceiptSigner::new(KeyFile(key.into()).key()?)?;
+            eprintln!("Receipts verify with public key {}", signer.public_key());
+            let log = match log {
+                Some(path) => Some(ReceiptLog::open(Path::new(&path))?),
+                None => None,
+            };
+            service = service.with_receipts(signer, log);
+        }
+        (None, Some(_)) => return Err("--receipts requires --receipt-key.".into()),
+        (None, None) => {}
+    }
+    if let Some(path) = snapshot_path {
+        service = service.with_snapshots(path.into());
+    }
+    if let Some(path) = config_path {
+        service = service.with_config_file(path.into());
+    }
+    if let Some(limit) = max_in_flight {
+        service = service.with_max_in_flight(limit);
+    }
+    if let Some(path) = key_file {
+        service = service.with_encryption(&KeyFile(path.into()))?;
+    }
+    if let Some(path) = subscribers {
+        let grants =
+            Grants::read_csv(File::open(&path)?).map_err(|err| format!("{}: {}", path, err))?;
+        service = service.with_subscriptions(grants);
+    }
+    serve(http, grpc, admin, service, commit_every, cutover)
+}
+
+/// Calls the admin API of a running server, printing the response.
+fn run_admin(mut args: impl Iterator<Item = String>) -> Result<(), Box<dyn Error>> {
+    let mut endpoint = None;
+    let mut command = vec![];
+    let mut scope = LockScope::BlockAll;
+    let mut operator = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value.", arg));
+        match arg.as_str() {
+            "--endpoint" => endpoint = Some(value()?),
+            "--scope" => scope = value()?.parse()?,
+            "--operator" => operator = Some(value()?),
+            _ => command.push(arg),
+        }
+    }
+    let client = AdminClient::new(&endpoint.ok_or("admin requires --endpoint.")?)?;
+    let client_id = |id: Option<&String>| -> Result<u16, Box<dyn Error>> {
+        Ok(id.ok_or("admin command requires a client id.")?.parse()?)
+    };
+    let response = match command.first().map(String::as_str) {
+        Some("lock") => client.lock(client_id(command.get(1))?, scope)?,
+        Some("unlock") => {
+            let operator = operator.ok_or("admin unlock requires --operator.")?;
+            client.unlock(client_id(command.get(1))?, &operator)?
+        }
+        Some("statement") => client.statement(client_id(command.get(1))?)?,
+        Some("snapshot") => client.snapshot()?,
+        Some("flush-dlq") => client.flush_dlq()?,
+        Some("reload-config") => client.reload_config()?,
+        _ => {
+            return Err("admin requires one of lock, unlock, statement, snapshot, \
+                        flush-dlq or reload-config."
+                .into())
+        }
+    };
+    println!("{}", response);
+    Ok(())
+}
+
+#[cfg(any(feature = "http", feature = "grpc"))]
+fn serve(
+    http: Option<std::net::SocketAddr>,
+    grpc: Option<std::net::SocketAddr>,
+    admin: Option<std::net::SocketAddr>,
+    service: PaymentsService,
+    commit_every: Option<std::time::Duration>,
+    cutover: Option<(std::time::Duration, PathBuf)>,
+) -> Result<(), Box<dyn Error>> {
+    use payments_engine::StatementOptions;
+    use std::sync::Arc;
+    let service = Arc::new(service);
+    if let Some(interval) = commit_every {
+        let service = Arc::clone(&service);
+        // Commitments are published as lines of JSON on stdout.
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let commitment = service.commit_state();
+            println!(
+                "{}",
+                serde_json::to_string(&commitment).expect("Commitments always serialize")
+            );
+        });
+    }
+    if let Some((interval, dir)) = cutover {
+        let service = Arc::clone(&service);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let close = service.engine().cutover();
+            // A period that can't be written is reported, and the server
+            // carries on into the next.
+            match close.write_reports(&dir, &StatementOptions::default()) {
+                Ok(_) => eprintln!(
+                    "Closed period {} after sequence {}",
+                    close.summary.period, close.summary.last_sequence
+                ),
+                Err(err) => eprintln!("Period {} not written: {}", close.summary.period, err),
+            }
+        });
+    }
+    let runtime = tokio::runtime::Runtime::new()?;
+    let mut servers = vec![];
+    if let Some(addr) = http {
+        #[cfg(feature = "http")]
+        servers.push(runtime.spawn(payments_engine::server::http::serve(
+            Arc::clone(&service),
+            addr,
+        )));
+        #[cfg(not(feature = "http"))]
+        return Err(format!("Serving HTTP on {} requires the `http` feature.", addr).into());
+    }
+    if let Some(addr) = grpc {
+        #[cfg(feature = "grpc")]
+        servers.push(runtime.spawn(payments_engine::server::grpc::serve(
+            Arc::clone(&service),
+            addr,
+        )));
+        #[cfg(not(feature = "grpc"))]
+        return Err(format!("Serving gRPC on {} requires the `grpc` feature.", addr).into());
+    }
+    if let Some(addr) = admin {
+        #[cfg(feature = "http")]
+        servers.push(runtime.spawn(payments_engine::server::http::serve_admin(
+            Arc::clone(&service),
+            addr,
+        )));
+        #[cfg(not(feature = "http"))]
+        return Err(format!(
+            "Serving the admin API on {} requires the `http` feature.",
+            addr
+        )
+        .into());
+    }
+    for server in servers {
+        // The servers' errors are `Send + Sync`, to cross the runtime's
+        // threads, so convert them explicitly.
+        runtime
+            .block_on(server)?
+            .map_err(|err| -> Box<dyn Error> { err })?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(feature = "http", feature = "grpc")))]
+fn serve(
+    _http: Option<std::net::SocketAddr>,
+    _grpc: Option<std::net::SocketAddr>,
+    _admin: Option<std::net::SocketAddr>,
+    _service: PaymentsService,
+    _commit_every: Option<std::time::Duration>,
+    _cutover: Option<(std::time::Duration, PathBuf)>,
+) -> Result<(), Box<dyn Error>> {
+    Err("Serving requires the `http` or `grpc` feature.".into())
+}
+
+/// Reads a snapshot, decrypting it with the key if one is given.
+fn read_snapshot_file(
+    path: &str,
+    key: Option<&Key>,
+) -> Result<TxEngine<InMemoryStore>, Box<dyn Error>> {
+    snapshot::read_snapshot(encryption::read_file(Path::new(path), key)?.as_slice())
+}
+
+#[cfg(feature = "wasm")]
+fn load_plugin(
+    path: &str,
+) -> Result<Box<dyn payments_engine::plugin::TransactionPlugin>, Box<dyn Error>> {
+    Ok(Box::new(
+        payments_engine::plugin::wasm::WasmPlugin::from_file(path)?,
+    ))
+}
+
+#[cfg(not(feature = "wasm"))]
+fn load_plugin(
+    _path: &str,
+) -> Result<Box<dyn payments_engine::plugin::TransactionPlugin>, Box<dyn Error>> {
+    Err("Plugins require the `wasm` feature.".into())
+}
@@ -0,0 +1,220 @@
+//! Run manifests, recording exactly which inputs and configuration produced
+//! a run's outputs, for audit.
+
+use crate::account::StatementOptions;
+use crate::metrics::TypeSummary;
+use crate::resources::ResourceUsage;
+use crate::sha256::to_hex;
+use crate::transaction_engine::EngineConfig;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Where to write a run's manifest.
+#[derive(Debug, Clone)]
+pub struct ManifestOptions {
+    /// Path to write the manifest to, as JSON.
+    pub path: PathBuf,
+    /// Name recorded for the input, e.g. its path.
+    pub input_name: String,
+}
+
+/// Record of a single run.
+#[derive(Debug, Serialize)]
+pub struct RunManifest {
+    /// Version of the engine that produced the run.
+    pub version: &'static str,
+    /// Start and end of the run, in seconds since the Unix epoch.
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub inputs: Vec<InputRecord>,
+    /// Points in the input that processing can be resumed from. Currently
+    /// only ever the end of the input.
+    pub checkpoints: Vec<Checkpoint>,
+    pub config: RunConfig,
+    pub counts: RunCounts,
+    pub outputs: Vec<OutputRecord>,
+    /// SHA-256 of the final state: the canonical statements (see
+    /// [`crate::RunOptions::canonical`]) with the default statement options,
+    /// so it's comparable between runs whatever the output options.
+    pub state_digest: String,
+    pub metrics: BTreeMap<&'static str, TypeSummary>,
+    pub resources: ResourceUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputRecord {
+    pub name: String,
+    /// SHA-256 of every byte read from the input.
+    pub sha256: String,
+    pub bytes: u64,
+    /// Byte range of the input processed as transactions, `[start, end)`.
+    pub processed: ByteRange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Checkpoint {
+    /// Input rows (including the header) before this point.
+    pub rows: u64,
+    pub byte_offset: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunConfig {
+    pub engine: EngineConfig,
+    pub statement: StatementOptions,
+    pub canonical: bool,
+    /// The output locale's tag, if statements and reports were localized.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    pub plugins: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RunCounts {
+    pub applied: usize,
+    pub rejected: usize,
+    pub failed: usize,
+    pub unreadable_rows: usize,
+    pub quarantined: usize,
+    /// Synthetic transactions applied to establish opening balances.
+    pub opening: usize,
+    /// Transactions skipped as exact duplicates across inputs.
+    pub duplicates_skipped: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OutputRecord {
+    pub name: String,
+    pub sha256: String,
+    pub bytes: u64,
+}
+
+impl OutputRecord {
+    /// Hashes an output already written to `path`.
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let mut reader = HashingReader::new(std::fs::File::open(path)?, true);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        let (digest, bytes) = reader.finish();
+        Ok(Self {
+            name: path.display().to_string(),
+            sha256: digest.unwrap_or_default(),
+            bytes,
+        })
+    }
+}
+
+impl RunManifest {
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), serde_json::Error> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.write_all(b"\n").map_err(serde_json::Error::io)
+    }
+}
+
+/// Seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Passes reads through, counting and (optionally) hashing the bytes read.
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Option<Sha256>,
+    bytes: u64,
+}
+
+impl<R> HashingReader<R> {
+    pub fn new(inner: R, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: hash.then(Sha256::new),
+            bytes: 0,
+        }
+    }
+
+    /// Returns the hex SHA-256 of everything read, if hashing, and the
+    /// number of bytes read.
+    pub fn finish(self) -> (Option<String>, u64) {
+        (self.hasher.map(|h| to_hex(&h.finalize())), self.bytes)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..read]);
+        }
+        self.bytes += read as u64;
+        Ok(read)
+    }
+}
+
+/// Passes writes through, counting and (optionally) hashing the bytes
+/// written.
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+    bytes: u64,
+}
+
+impl<W> HashingWriter<W> {
+    pub fn new(inner: W, hash: bool) -> Self {
+        Self {
+            inner,
+            hasher: hash.then(Sha256::new),
+            bytes: 0,
+        }
+    }
+
+    /// Returns the hex SHA-256 of everything written, if hashing, and the
+    /// number of bytes written.
+    pub fn finish(self) -> (Option<String>, u64) {
+        (self.hasher.map(|h| to_hex(&h.finalize())), self.bytes)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        if let Some(hasher) = self.hasher.as_mut() {
+            hasher.update(&buf[..written]);
+        }
+        self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hashing_reader_and_writer() {
+        let mut reader = HashingReader::new(&b"abc"[..], true);
+        let mut writer = HashingWriter::new(vec![], true);
+        std::io::copy(&mut reader, &mut writer).unwrap();
+        let abc = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(reader.finish(), (Some(abc.to_string()), 3));
+        assert_eq!(writer.finish(), (Some(abc.to_string()), 3));
+
+        let mut reader = HashingReader::new(&b"abc"[..], false);
+        std::io::copy(&mut reader, &mut std::io::sink()).unwrap();
+        assert_eq!(reader.finish(), (None, 3));
+    }
+}
@@ -0,0 +1,247 @@
+//! Merkle-tree commitments to account balances, so a single account's
+//! balance can be proven against a published root without sharing the rest
+//! of the ledger.
+//!
+//! A [`StateCommitment`] is built from every account's statement, ordered by
+//! client. Each leaf is the SHA-256 of `0x00` followed by the statement's
+//! JSON; each node is the SHA-256 of `0x01` followed by its children's
+//! hashes. A node without a sibling at its level is carried up unchanged, so
+//! the tree needn't be padded. The prefixes keep a leaf from ever being
+//! passed off as a node.
+//!
+//! A [`BalanceProof`] holds the statement as hashed and the sibling hashes
+//! on the way to the root, and can be checked by anyone with the root.
+
+use crate::sha256;
+use crate::statements::StatementView;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+type Digest = [u8; 32];
+
+/// A commitment to the balances of every account at one point.
+#[derive(Debug, Clone)]
+pub struct StateCommitment {
+    /// Sequence number of the last transaction applied before the
+    /// accounts were read.
+    sequence: u64,
+    /// Clients and their statements' JSON, in client order.
+    leaves: Vec<(u16, String)>,
+    /// Every level of the tree, from the leaves' hashes up to the root.
+    levels: Vec<Vec<Digest>>,
+}
+
+/// Summary of a [`StateCommitment`], for publishing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Commitment {
+    pub root: String,
+    pub sequence: u64,
+    pub accounts: usize,
+}
+
+/// Proof that an account's statement is part of a commitment.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BalanceProof {
+    /// The account's statement, as the JSON hashed into the leaf.
+    pub statement: String,
+    /// Sibling hashes from the leaf up to the root, in hex.
+    pub path: Vec<ProofStep>,
+    /// The root the proof leads to, in hex.
+    pub root: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProofStep {
+    /// Whether the sibling is on the left.
+    pub left: bool,
+    pub hash: String,
+}
+
+impl StateCommitment {
+    /// Commits to every statement in `view`, as of `sequence`.
+    pub fn new(view: &StatementView, sequence: u64) -> Self {
+        let leaves: Vec<(u16, String)> = view
+            .statements()
+            .iter()
+            .map(|statement| {
+                let json = serde_json::to_string(statement).expect("Statements always serialize");
+                (statement.client(), json)
+            })
+            .collect();
+        let mut levels = vec![leaves
+            .iter()
+            .map(|(_, json)| hash(LEAF, &[json.as_bytes()]))
+            .collect::<Vec<_>>()];
+        while levels.last().expect("At least the leaves").len() > 1 {
+            let level = levels.last().expect("At least the leaves");
+            let parents = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash(NODE, &[left, right]),
+                    [only] => *only,
+                    _ => unreachable!("Chunks of at most two"),
+                })
+                .collect();
+            levels.push(parents);
+        }
+        Self {
+            sequence,
+            leaves,
+            levels,
+        }
+    }
+
+    /// The root hash, or the hash of nothing if there are no accounts.
+    pub fn root(&self) -> Digest {
+        match self.levels.last().and_then(|level| level.first()) {
+            Some(root) => *root,
+            None => Sha256::digest([]).into(),
+        }
+    }
+
+    pub fn summary(&self) -> Commitment {
+        Commitment {
+            root: sha256::to_hex(&self.root()),
+            sequence: self.sequence,
+            accounts: self.leaves.len(),
+        }
+    }
+
+    /// Proof of the client's statement, if they had an account.
+    pub fn proof(&self, client_id: u16) -> Option<BalanceProof> {
+        let leaf = self
+            .leaves
+            .binary_search_by_key(&client_id, |(client, _)| *client)
+            .ok()?;
+        let mut index = leaf;
+        let mut path = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                path.push(ProofStep {
+                    left: sibling < index,
+                    hash: sha256::to_hex(hash),
+                });
+            }
+            index /= 2;
+        }
+        Some(BalanceProof {
+            statement: self.leaves[leaf].1.clone(),
+            path,
+            root: sha256::to_hex(&self.root()),
+        })
+    }
+}
+
+impl BalanceProof {
+    /// Whether the path leads from the statement to the root.
+    pub fn verify(&self) -> bool {
+        let mut digest = hash(LEAF, &[self.statement.as_bytes()]);
+        for step in &self.path {
+            let Some(sibling) = from_hex(&step.hash) else {
+                return false;
+            };
+            digest = if step.left {
+                hash(NODE, &[&sibling, &digest])
+            } else {
+                hash(NODE, &[&digest, &sibling])
+            };
+        }
+        sha256::to_hex(&digest) == self.root
+    }
+
+    /// The client whose statement is proven.
+    pub fn client(&self) -> Option<u16> {
+        let statement: serde_json::Value = serde_json::from_str(&self.statement).ok()?;
+        u16::try_from(statement.get("client")?.as_u64()?).ok()
+    }
+}
+
+fn hash(prefix: u8, parts: &[&[u8]]) -> Digest {
+    let mut hasher = Sha256::new();
+    hasher.update([prefix]);
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn from_hex(hex: &str) -> Option<Digest> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (byte, digits) in digest.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::Money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+
+    #[test]
+    fn proofs_verify_against_root() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        // An odd number of accounts, so a node is carried up unpaired.
+        for client_id in 1..=5u16 {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    info: TransactionInfo::Deposit(Money::from(i64::from(client_id) * 10)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let view = StatementView::of(engine.store());
+        let commitment = StateCommitment::new(&view, engine.last_sequence());
+        let summary = commitment.summary();
+        assert_eq!((summary.sequence, summary.accounts), (5, 5));
+
+        for client_id in 1..=5 {
+            let proof = commitment.proof(client_id).unwrap();
+            assert!(proof.verify(), "Proof for client {}", client_id);
+            assert_eq!(proof.root, summary.root);
+            assert_eq!(proof.client(), Some(client_id));
+            assert_eq!(
+                proof.statement,
+                serde_json::to_string(view.get(client_id).unwrap()).unwrap()
+            );
+        }
+        assert!(commitment.proof(6).is_none());
+
+        // A proof for a different balance doesn't verify.
+        let mut forged = commitment.proof(3).unwrap();
+        forged.statement = forged.statement.replace("30", "300");
+        assert!(!forged.verify());
+
+        // Nor does one against another commitment's root.
+        engine
+            .handle(&Transaction {
+                client_id: 1,
+                transaction_id: 6,
+                info: TransactionInfo::Deposit(Money::from(1)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            })
+            .unwrap();
+        let later = StateCommitment::new(&StatementView::of(engine.store()), 6);
+        let mut stale = commitment.proof(3).unwrap();
+        stale.root = later.summary().root;
+        assert!(!stale.verify());
+    }
+}
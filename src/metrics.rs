@@ -0,0 +1,349 @@
+use crate::transaction::TransactionInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.000_001,
+    0.000_002_5,
+    0.000_005,
+    0.000_01,
+    0.000_025,
+    0.000_05,
+    0.000_1,
+    0.000_25,
+    0.000_5,
+    0.001,
+    0.01,
+    0.1,
+    1.0,
+];
+
+/// Upper bounds of the amount histogram buckets.
+const AMOUNT_BUCKETS: &[f64] = &[1.0, 10.0, 100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0];
+
+/// Cumulative-style histogram with fixed bucket bounds, matching Prometheus'
+/// histogram type.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    bounds: &'static [f64],
+    /// Non-cumulative count per bucket, plus a final overflow (+Inf) bucket.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+    max: f64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.0,
+            count: 0,
+            max: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket] += 1;
+        self.sum += value;
+        self.count += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Estimates the given quantile (0.0 - 1.0) as the upper bound of the
+    /// bucket it falls in. Values in the overflow bucket are reported as the
+    /// maximum observed value.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return self.bounds.get(bucket).copied().unwrap_or(self.max);
+            }
+        }
+        self.max
+    }
+
+    pub fn summary(&self) -> HistogramSummary {
+        HistogramSummary {
+            count: self.count,
+            mean: if self.count == 0 {
+                0.0
+            } else {
+                self.sum / self.count as f64
+            },
+            p50: self.quantile(0.5),
+            p99: self.quantile(0.99),
+            max: self.max,
+        }
+    }
+
+    fn write_prometheus<W: Write>(
+        &self,
+        writer: &mut W,
+        name: &str,
+        labels: &str,
+    ) -> std::io::Result<()> {
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            writeln!(
+                writer,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            )?;
+        }
+        writeln!(
+            writer,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )?;
+        writeln!(writer, "{}_sum{{{}}} {}", name, labels, self.sum)?;
+        writeln!(writer, "{}_count{{{}}} {}", name, labels, self.count)
+    }
+}
+
+/// Condensed view of a [`Histogram`], for run summaries.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HistogramSummary {
+    pub count: u64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+/// Outcome of handling a transaction, for counting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    Applied,
+    Rejected,
+    Failed,
+    /// Queued for a quarantined account rather than applied or rejected.
+    Quarantined,
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Applied => "applied",
+            Outcome::Rejected => "rejected",
+            Outcome::Failed => "failed",
+            Outcome::Quarantined => "quarantined",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TypeMetrics {
+    latency: Histogram,
+    amount: Histogram,
+    outcomes: BTreeMap<Outcome, u64>,
+}
+
+impl Default for TypeMetrics {
+    fn default() -> Self {
+        Self {
+            latency: Histogram::new(LATENCY_BUCKETS),
+            amount: Histogram::new(AMOUNT_BUCKETS),
+            outcomes: BTreeMap::new(),
+        }
+    }
+}
+
+/// Processing metrics, broken down by transaction type.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    by_type: BTreeMap<&'static str, TypeMetrics>,
+}
+
+/// Per-type summary of [`Metrics`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TypeSummary {
+    pub latency_seconds: HistogramSummary,
+    pub amount: HistogramSummary,
+    pub applied: u64,
+    pub rejected: u64,
+    pub failed: u64,
+    pub quarantined: u64,
+}
+
+impl Metrics {
+    /// Records the handling of a single transaction.
+    ///
+    /// Amounts are only recorded for applied transactions.
+    pub fn record(&mut self, info: &TransactionInfo, latency: Duration, outcome: Outcome) {
+        let metrics = self.by_type.entry(info.kind()).or_default();
+        metrics.latency.observe(latency.as_secs_f64());
+        *metrics.outcomes.entry(outcome).or_default() += 1;
+        if outcome == Outcome::Applied {
+            if let Some(amount) = info.amount() {
+                metrics.amount.observe(amount.to_f64());
+            }
+        }
+    }
+
+    /// Summarizes the metrics for each transaction type seen.
+    pub fn summary(&self) -> BTreeMap<&'static str, TypeSummary> {
+        self.by_type
+            .iter()
+            .map(|(kind, metrics)| {
+                let outcome = |o| metrics.outcomes.get(&o).copied().unwrap_or_default();
+                let summary = TypeSummary {
+                    latency_seconds: metrics.latency.summary(),
+                    amount: metrics.amount.summary(),
+                    applied: outcome(Outcome::Applied),
+                    rejected: outcome(Outcome::Rejected),
+                    failed: outcome(Outcome::Failed),
+                    quarantined: outcome(Outcome::Quarantined),
+                };
+                (*kind, summary)
+            })
+            .collect()
+    }
+
+    /// Writes all metrics in the Prometheus text exposition format.
+    pub fn write_prometheus<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writeln!(
+            writer,
+            "# HELP payments_engine_transactions_total Transactions handled, by type and outcome."
+        )?;
+        writeln!(writer, "# TYPE payments_engine_transactions_total counter")?;
+        for (kind, metrics) in &self.by_type {
+            for (outcome, count) in &metrics.outcomes {
+                writeln!(
+                    writer,
+                    "payments_engine_transactions_total{{type=\"{}\",outcome=\"{}\"}} {}",
+                    kind,
+                    outcome.name(),
+                    count
+                )?;
+            }
+        }
+
+        let name = "payments_engine_transaction_latency_seconds";
+        writeln!(
+            writer,
+            "# HELP {} Time taken to handle a transaction, by type.",
+            name
+        )?;
+        writeln!(writer, "# TYPE {} histogram", name)?;
+        for (kind, metrics) in &self.by_type {
+            let labels = format!("type=\"{}\"", kind);
+            metrics
+                .latency
+                .write_prometheus(&mut writer, name, &labels)?;
+        }
+
+        let name = "payments_engine_transaction_amount";
+        writeln!(
+            writer,
+            "# HELP {} Amounts of applied transactions, by type.",
+            name
+        )?;
+        writeln!(writer, "# TYPE {} histogram", name)?;
+        for (kind, metrics) in &self.by_type {
+            if metrics.amount.count() == 0 {
+                continue;
+            }
+            let labels = format!("type=\"{}\"", kind);
+            metrics
+                .amount
+                .write_prometheus(&mut writer, name, &labels)?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut hist = Histogram::new(AMOUNT_BUCKETS);
+        assert_eq!(hist.quantile(0.5), 0.0);
+        for _ in 0..98 {
+            hist.observe(5.0);
+        }
+        hist.observe(500.0);
+        hist.observe(2_000_000.0);
+        assert_eq!(hist.quantile(0.5), 10.0);
+        assert_eq!(hist.quantile(0.99), 1_000.0);
+        assert_eq!(hist.quantile(1.0), 2_000_000.0);
+        // Values on a bucket boundary fall in that bucket.
+        let mut hist = Histogram::new(AMOUNT_BUCKETS);
+        hist.observe(10.0);
+        assert_eq!(hist.quantile(1.0), 10.0);
+    }
+
+    #[test]
+    fn per_type_summary() {
+        let mut metrics = Metrics::default();
+        let latency = Duration::from_micros(3);
+        metrics.record(
+            &TransactionInfo::Deposit(money!(50)),
+            latency,
+            Outcome::Applied,
+        );
+        metrics.record(
+            &TransactionInfo::Deposit(money!(5)),
+            latency,
+            Outcome::Rejected,
+        );
+        metrics.record(&TransactionInfo::Dispute(None), latency, Outcome::Failed);
+
+        let summary = metrics.summary();
+        let deposit = &summary["deposit"];
+        assert_eq!(deposit.applied, 1);
+        assert_eq!(deposit.rejected, 1);
+        assert_eq!(deposit.latency_seconds.count, 2);
+        assert_eq!(deposit.latency_seconds.p99, 0.000_005);
+        // Rejected amounts aren't recorded.
+        assert_eq!(deposit.amount.count, 1);
+        assert_eq!(deposit.amount.max, 50.0);
+        assert_eq!(summary["dispute"].failed, 1);
+        assert_eq!(summary["dispute"].amount.count, 0);
+    }
+
+    #[test]
+    fn prometheus_exposition() {
+        let mut metrics = Metrics::default();
+        metrics.record(
+            &TransactionInfo::Withdrawal(money!(20)),
+            Duration::from_micros(30),
+            Outcome::Applied,
+        );
+        let mut output = vec![];
+        metrics.write_prometheus(&mut output).unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.contains(
+            "payments_engine_transactions_total{type=\"withdrawal\",outcome=\"applied\"} 1\n"
+        ));
+        assert!(text.contains(
+            "payments_engine_transaction_latency_seconds_bucket{type=\"withdrawal\",le=\"0.000025\"} 0\n"
+        ));
+        assert!(text.contains(
+            "payments_engine_transaction_latency_seconds_bucket{type=\"withdrawal\",le=\"0.00005\"} 1\n"
+        ));
+        assert!(text.contains(
+            "payments_engine_transaction_amount_bucket{type=\"withdrawal\",le=\"100\"} 1\n"
+        ));
+        assert!(text.contains("payments_engine_transaction_amount_sum{type=\"withdrawal\"} 20\n"));
+        assert!(text.contains("payments_engine_transaction_amount_count{type=\"withdrawal\"} 1\n"));
+    }
+}
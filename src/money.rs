@@ -0,0 +1,568 @@
+//! Monetary amounts.
+//!
+//! By default amounts are fixed precision [`rust_decimal::Decimal`]s, rounded
+//! to 4 decimal places on input. With the `bigdecimal` feature they are
+//! arbitrary precision `bigdecimal::BigDecimal`s, kept at full precision
+//! internally and only rounded to 4 decimal places on output.
+//!
+//! [`Money`] deliberately isn't `Copy` in either configuration, so the rest of
+//! the engine builds the same way whichever backend is selected.
+
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+#[cfg(not(feature = "bigdecimal"))]
+type Inner = rust_decimal::Decimal;
+#[cfg(feature = "bigdecimal")]
+type Inner = bigdecimal::BigDecimal;
+
+/// Decimal places amounts are reported to.
+pub const OUTPUT_SCALE: u32 = 4;
+
+/// A monetary amount. May be negative.
+///
+/// The arithmetic operators panic on overflow (with the fixed precision
+/// backend), so should only be used for derived values known to be in range.
+/// Updates to balances must use [`Money::checked_add`] and
+/// [`Money::checked_sub`].
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(Inner);
+
+impl Money {
+    pub fn zero() -> Self {
+        Self::default()
+    }
+
+    pub fn is_positive(&self) -> bool {
+        self.0 > Inner::default()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0 < Inner::default()
+    }
+
+    pub fn abs(&self) -> Self {
+        Self(self.0.abs())
+    }
+
+    /// Adds `rhs`, returning `None` on overflow.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn checked_add(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_add(rhs.0).map(Self)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn checked_add(&self, rhs: &Money) -> Option<Money> {
+        Some(Self(&self.0 + &rhs.0))
+    }
+
+    /// Subtracts `rhs`, returning `None` on overflow.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn checked_sub(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_sub(rhs.0).map(Self)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn checked_sub(&self, rhs: &Money) -> Option<Money> {
+        Some(Self(&self.0 - &rhs.0))
+    }
+
+    /// Multiplies by `rhs`, returning `None` on overflow.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn checked_mul(&self, rhs: &Money) -> Option<Money> {
+        self.0.checked_mul(rhs.0).map(Self)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn checked_mul(&self, rhs: &Money) -> Option<Money> {
+        Some(Self(&self.0 * &rhs.0))
+    }
+
+    /// Subtracts `rhs`, saturating at the bounds of the fixed precision
+    /// backend. Only for derived values that are compared or reported.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn saturating_sub(&self, rhs: &Money) -> Money {
+        Self(self.0.saturating_sub(rhs.0))
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn saturating_sub(&self, rhs: &Money) -> Money {
+        Self(&self.0 - &rhs.0)
+    }
+
+    /// Constructs an amount from a fixed-point integer, e.g.
+    /// `from_scaled(15, 1)` is 1.5.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn from_scaled(value: i64, scale: u32) -> Self {
+        Self(Inner::new(value, scale))
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn from_scaled(value: i64, scale: u32) -> Self {
+        Self(Inner::new(value.into(), i64::from(scale)))
+    }
+
+    /// Converts to a fixed-point integer with the given scale, truncating
+    /// any further digits. Returns `None` if the result doesn't fit.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn to_scaled_i64(&self, scale: u32) -> Option<i64> {
+        use rust_decimal::prelude::ToPrimitive;
+        self.0
+            .checked_mul(Inner::from(10i64.checked_pow(scale)?))?
+            .trunc()
+            .to_i64()
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn to_scaled_i64(&self, scale: u32) -> Option<i64> {
+        use bigdecimal::ToPrimitive;
+        (&self.0 * Inner::from(10i64.checked_pow(scale)?))
+            .with_scale(0)
+            .to_i64()
+    }
+
+    /// Divides by a count, e.g. to average a sum. Zero for a count of zero.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn div_count(&self, count: u64) -> Money {
+        if count == 0 {
+            return Money::zero();
+        }
+        Self((self.0 / Inner::from(count)).normalize())
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn div_count(&self, count: u64) -> Money {
+        if count == 0 {
+            return Money::zero();
+        }
+        Self((&self.0 / Inner::from(count)).normalized())
+    }
+
+    /// Lossy conversion to a float, e.g. for metrics.
+    pub fn to_f64(&self) -> f64 {
+        #[cfg(feature = "bigdecimal")]
+        use bigdecimal::ToPrimitive;
+        #[cfg(not(feature = "bigdecimal"))]
+        use rust_decimal::prelude::ToPrimitive;
+        self.0.to_f64().unwrap_or(f64::MAX)
+    }
+
+    /// Rounds to `dp` decimal places, rounding half to even.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn round_dp(&self, dp: u32) -> Self {
+        Self(self.0.round_dp(dp))
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn round_dp(&self, dp: u32) -> Self {
+        Self(self.0.round(i64::from(dp)).normalized())
+    }
+
+    /// Rounds to `dp` decimal places, as `mode` says.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn round_with(&self, dp: u32, mode: RoundingMode) -> Self {
+        use rust_decimal::RoundingStrategy;
+        let strategy = match mode {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Down => RoundingStrategy::ToZero,
+        };
+        Self(self.0.round_dp_with_strategy(dp, strategy).normalize())
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn round_with(&self, dp: u32, mode: RoundingMode) -> Self {
+        let mode = match mode {
+            RoundingMode::HalfEven => bigdecimal::RoundingMode::HalfEven,
+            RoundingMode::HalfUp => bigdecimal::RoundingMode::HalfUp,
+            RoundingMode::Down => bigdecimal::RoundingMode::Down,
+        };
+        Self(self.0.with_scale_round(i64::from(dp), mode).normalized())
+    }
+
+    /// Rounds to exactly `dp` decimal places, so it's always displayed with
+    /// that many digits after the point (e.g. "1.5000"), and never as
+    /// negative zero.
+    #[cfg(not(feature = "bigdecimal"))]
+    pub fn to_fixed_scale(&self, dp: u32) -> Self {
+        let mut value = self.0.round_dp(dp);
+        if value.is_zero() {
+            value = Inner::ZERO;
+        }
+        value.rescale(dp);
+        Self(value)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    pub fn to_fixed_scale(&self, dp: u32) -> Self {
+        use bigdecimal::Zero;
+        let value = self.0.round(i64::from(dp));
+        if value.is_zero() {
+            return Self(Inner::zero().with_scale(i64::from(dp)));
+        }
+        Self(value.with_scale(i64::from(dp)))
+    }
+
+    /// Applies the backend's input precision to a newly received amount.
+    ///
+    /// The fixed precision backend rounds to [`OUTPUT_SCALE`] on input to
+    /// avoid compounding rounding errors on output. E.g. erroneous deposits
+    /// of 1.00003 + 1.00003 => 2.0000, not 2.0001. The arbitrary precision
+    /// backend keeps amounts as they are.
+    pub fn round_input(self) -> Self {
+        if cfg!(feature = "bigdecimal") {
+            self
+        } else {
+            self.round_dp(OUTPUT_SCALE)
+        }
+    }
+}
+
+/// How [`Money::round_with`] rounds the digits it drops.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RoundingMode {
+    /// Half way cases to the nearest even digit (banker's rounding).
+    #[default]
+    HalfEven,
+    /// Half way cases away from zero.
+    HalfUp,
+    /// Towards zero, truncating.
+    Down,
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "half-even" => Ok(RoundingMode::HalfEven),
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "down" => Ok(RoundingMode::Down),
+            _ => Err(format!("Unknown rounding mode {:?}", s)),
+        }
+    }
+}
+
+impl From<rust_decimal::Decimal> for Money {
+    #[cfg(not(feature = "bigdecimal"))]
+    fn from(value: rust_decimal::Decimal) -> Self {
+        Self(value)
+    }
+
+    #[cfg(feature = "bigdecimal")]
+    fn from(value: rust_decimal::Decimal) -> Self {
+        // Every Decimal has an exact decimal string representation.
+        Self(
+            value
+                .to_string()
+                .parse()
+                .expect("Decimal is a valid BigDecimal"),
+        )
+    }
+}
+
+impl From<i64> for Money {
+    fn from(value: i64) -> Self {
+        Self(Inner::from(value))
+    }
+}
+
+impl FromStr for Money {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(amount) = parse_simple(s) {
+            return Ok(amount);
+        }
+        Inner::from_str(s)
+            .map(Self)
+            .map_err(|err| format!("Invalid amount {:?}: {}", s, err))
+    }
+}
+
+/// Most digits a simple amount may have, so its unscaled value fits an
+/// `i64`.
+const SIMPLE_MAX_DIGITS: usize = 18;
+
+/// Parses the common shape of amount, unsigned digits with an optional
+/// fraction (e.g. `12.5000`), straight to a fixed-point value, keeping its
+/// scale as the general parser would. Returns `None` for any other shape,
+/// to be left to the general parser.
+fn parse_simple(s: &str) -> Option<Money> {
+    let (whole, fraction) = match s.split_once('.') {
+        Some((whole, fraction)) if !fraction.is_empty() => (whole, fraction),
+        Some(_) => return None,
+        None => (s, ""),
+    };
+    if whole.is_empty() || whole.len() + fraction.len() > SIMPLE_MAX_DIGITS {
+        return None;
+    }
+    let mut value: i64 = 0;
+    for digit in whole.bytes().chain(fraction.bytes()) {
+        if !digit.is_ascii_digit() {
+            return None;
+        }
+        value = value * 10 + i64::from(digit - b'0');
+    }
+    Some(Money::from_scaled(value, fraction.len() as u32))
+}
+
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // `BigDecimal` displays zero as "0" whatever its scale, which would
+        // break fixed scale output (see `Money::to_fixed_scale`).
+        #[cfg(feature = "bigdecimal")]
+        {
+            use bigdecimal::Zero;
+            let scale = self.0.fractional_digit_count();
+            if self.0.is_zero() && scale > 0 {
+                return write!(f, "0.{:0<1$}", "", scale as usize);
+            }
+        }
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for Money {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+macro_rules! impl_op {
+    ($op:ident, $method:ident) => {
+        impl std::ops::$op for Money {
+            type Output = Money;
+            fn $method(self, rhs: Money) -> Money {
+                Money(std::ops::$op::$method(self.0, rhs.0))
+            }
+        }
+
+        impl std::ops::$op<&Money> for Money {
+            type Output = Money;
+            fn $method(self, rhs: &Money) -> Money {
+                Money(std::ops::$op::$method(self.0, &rhs.0))
+            }
+        }
+
+        impl std::ops::$op<&Money> for &Money {
+            type Output = Money;
+            fn $method(self, rhs: &Money) -> Money {
+                Money(std::ops::$op::$method(&self.0, &rhs.0))
+            }
+        }
+    };
+}
+
+impl_op!(Add, add);
+impl_op!(Sub, sub);
+
+impl std::ops::Neg for Money {
+    type Output = Money;
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl std::iter::Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::zero(), |acc, amount| acc + amount)
+    }
+}
+
+impl<'a> std::iter::Sum<&'a Money> for Money {
+    fn sum<I: Iterator<Item = &'a Money>>(iter: I) -> Money {
+        iter.fold(Money::zero(), |acc, amount| acc + amount)
+    }
+}
+
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(MoneyVisitor)
+    }
+}
+
+struct MoneyVisitor;
+
+impl Visitor<'_> for MoneyVisitor {
+    type Value = Money;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a decimal amount")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Money, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Money, E> {
+        Ok(Money::from(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Money, E> {
+        i64::try_from(v).map(Money::from).map_err(E::custom)
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Money, E> {
+        // Go via the shortest string representation, so e.g. 0.1 is read as
+        // exactly 0.1.
+        v.to_string().parse().map_err(E::custom)
+    }
+}
+
+/// Constructs a [`Money`] from a decimal literal, e.g. `money!(1.5)`.
+#[cfg(test)]
+macro_rules! money {
+    ($($t:tt)*) => {
+        $crate::money::Money::from(rust_decimal_macros::dec!($($t)*))
+    };
+}
+
+#[cfg(test)]
+pub(crate) use money;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn arithmetic_and_ordering() {
+        let amount = money!(10.5) + money!(0.25) - &money!(1);
+        assert_eq!(amount, money!(9.75));
+        assert_eq!(&amount - &money!(10), money!(-0.25));
+        assert!((&amount - &money!(10)).is_negative());
+        assert!(amount.is_positive());
+        assert!(!Money::zero().is_positive());
+        assert!(money!(-1) < Money::zero());
+        assert_eq!(money!(-1.5).abs(), money!(1.5));
+        let total: Money = [money!(1), money!(2.5)].iter().sum();
+        assert_eq!(total, money!(3.5));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        assert_eq!(money!(1.5).checked_add(&money!(1)), Some(money!(2.5)));
+        assert_eq!(money!(1.5).checked_sub(&money!(2)), Some(money!(-0.5)));
+        assert_eq!(money!(1.5).checked_mul(&money!(-2)), Some(money!(-3)));
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigdecimal"))]
+    fn checked_arithmetic_overflow() {
+        let max = Money::from(rust_decimal::Decimal::MAX);
+        let min = Money::from(rust_decimal::Decimal::MIN);
+        assert_eq!(max.checked_add(&money!(1)), None);
+        assert_eq!(min.checked_sub(&money!(1)), None);
+        assert_eq!(min.saturating_sub(&money!(1)), min);
+    }
+
+    #[test]
+    fn scaled_conversions() {
+        assert_eq!(Money::from_scaled(15, 1), money!(1.5));
+        assert_eq!(money!(1.23456).to_scaled_i64(4), Some(12345));
+        assert_eq!(money!(-1.23456).to_scaled_i64(4), Some(-12345));
+        assert_eq!(money!(100000000000000000000).to_scaled_i64(4), None);
+    }
+
+    #[test]
+    fn fixed_scale() {
+        assert_eq!(money!(1.5).to_fixed_scale(4).to_string(), "1.5000");
+        assert_eq!(money!(2).to_fixed_scale(4).to_string(), "2.0000");
+        assert_eq!(money!(1.00005).to_fixed_scale(4).to_string(), "1.0000");
+        assert_eq!(money!(-0.00001).to_fixed_scale(4).to_string(), "0.0000");
+        assert_eq!(money!(-3.25).to_fixed_scale(4).to_string(), "-3.2500");
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(money!(1.00005).round_dp(4), money!(1.0000));
+        assert_eq!(money!(1.00015).round_dp(4), money!(1.0002));
+        assert_eq!(money!(2.5).to_string(), "2.5");
+        for (mode, expected) in [
+            (RoundingMode::HalfEven, [money!(2), money!(2), money!(-2)]),
+            (RoundingMode::HalfUp, [money!(3), money!(2), money!(-3)]),
+            (RoundingMode::Down, [money!(2), money!(1), money!(-2)]),
+        ] {
+            let rounded = [money!(2.5), money!(1.5), money!(-2.5)].map(|m| m.round_with(0, mode));
+            assert_eq!(rounded, expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn div_count() {
+        assert_eq!(money!(10).div_count(4), money!(2.5));
+        assert_eq!(money!(10).div_count(3).round_dp(4), money!(3.3333));
+        assert_eq!(money!(10).div_count(0), money!(0));
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigdecimal"))]
+    fn fixed_precision_rounds_on_input() {
+        let amount: Money = "1.00003".parse().unwrap();
+        assert_eq!(amount.round_input(), money!(1.0000));
+    }
+
+    #[test]
+    #[cfg(feature = "bigdecimal")]
+    fn arbitrary_precision_rounds_on_output_only() {
+        let amount: Money = "0.000000000000000001".parse().unwrap();
+        let total = amount.clone().round_input() + amount.round_input();
+        assert_eq!(total, "0.000000000000000002".parse().unwrap());
+        assert_eq!(total.round_dp(OUTPUT_SCALE), Money::zero());
+    }
+
+    #[test]
+    fn simple_parse_matches_general() {
+        for s in [
+            "0",
+            "7",
+            "1.5",
+            "1.50",
+            "0001.2500",
+            "12345.6789",
+            "0.0000",
+            "999999999999999999",
+            "99999999999999.9999",
+        ] {
+            let amount = parse_simple(s).unwrap_or_else(|| panic!("{:?} not simple", s));
+            let general = Money(Inner::from_str(s).unwrap());
+            assert_eq!(amount, general, "{:?}", s);
+            assert_eq!(amount.to_string(), general.to_string(), "{:?}", s);
+        }
+        for s in [
+            "",
+            "-1.5",
+            "+1",
+            "1.",
+            ".5",
+            "1e3",
+            "1.2.3",
+            "1,5",
+            " 1",
+            "1_000",
+            "9999999999999999999",
+        ] {
+            assert_eq!(parse_simple(s), None, "{:?}", s);
+        }
+        assert_eq!("-1.5".parse::<Money>().unwrap(), money!(-1.5));
+        assert_eq!(".5".parse::<Money>().unwrap(), money!(0.5));
+        assert!("1,5".parse::<Money>().is_err());
+    }
+
+    #[test]
+    fn deserialize_from_strings_and_numbers() {
+        let amounts: Vec<Money> = serde_json::from_str(r#"["1.5", 2, 0.1]"#).unwrap();
+        assert_eq!(amounts, vec![money!(1.5), money!(2), money!(0.1)]);
+        assert!(serde_json::from_str::<Money>(r#""abc""#).is_err());
+        assert_eq!(serde_json::to_string(&money!(1.5)).unwrap(), r#""1.5""#);
+    }
+}
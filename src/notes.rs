@@ -0,0 +1,86 @@
+//! Operators' notes on accounts and their transactions, e.g. the context of
+//! an investigation, kept with the ledger rather than in a separate ticket
+//! system.
+//!
+//! Notes are stored on the account (so are part of snapshots), raise an
+//! [`crate::event::EngineEvent::NoteAdded`] event for the audit log, and are
+//! included in statement bundles (see [`crate::export`]).
+
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Read;
+
+/// A note to attach, as read from a notes file.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Note {
+    pub client: u16,
+    /// The transaction the note is about, or `None` for the account as a
+    /// whole.
+    #[serde(default)]
+    pub tx: Option<u32>,
+    /// Who wrote the note.
+    pub author: String,
+    pub note: String,
+}
+
+/// A note attached to an account.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountNote {
+    /// The transaction the note is about, or `None` for the account as a
+    /// whole.
+    pub tx: Option<u32>,
+    /// Sequence number of the last transaction applied when the note was
+    /// attached, placing it in the account's history.
+    pub after_sequence: u64,
+    pub author: String,
+    pub text: String,
+}
+
+/// Reads a notes file: a CSV with `client`, `tx`, `author` and `note`
+/// columns. `tx` may be empty for notes on the account as a whole.
+pub fn read_notes<R: Read>(reader: R) -> Result<Vec<Note>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut notes = vec![];
+    for note in csv_reader.deserialize() {
+        let note: Note = note?;
+        if note.note.is_empty() {
+            return Err(format!("Note for client {} must not be empty", note.client).into());
+        }
+        notes.push(note);
+    }
+    Ok(notes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read() {
+        let notes = read_notes(
+            "client, tx, author, note\n1, 2, alice,\"Chargeback, see case 7\"\n3, , bob, KYC refreshed\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(
+            notes,
+            vec![
+                Note {
+                    client: 1,
+                    tx: Some(2),
+                    author: "alice".into(),
+                    note: "Chargeback, see case 7".into(),
+                },
+                Note {
+                    client: 3,
+                    tx: None,
+                    author: "bob".into(),
+                    note: "KYC refreshed".into(),
+                },
+            ]
+        );
+        assert!(read_notes("client,tx,author,note\n1,,alice,\n".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Opening balances, e.g. migrated from a legacy ledger, established before
+//! the transaction feed is processed.
+
+use crate::money::Money;
+use serde::{Deserialize, Deserializer};
+use std::error::Error;
+use std::io::Read;
+
+/// A deposit making up part of a client's opening balance.
+///
+/// Each entry is applied as a synthetic deposit with the given transaction
+/// ID, so the feed can go on to dispute it, and already-disputed entries as a
+/// deposit followed by a dispute.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct OpeningEntry {
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Money,
+    /// Whether the deposit was already disputed in the legacy ledger.
+    #[serde(default, deserialize_with = "empty_as_false")]
+    pub disputed: bool,
+}
+
+/// Reads an empty `disputed` field as not disputed.
+fn empty_as_false<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(Option::<bool>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Reads an opening balances file: a CSV with `client`, `tx` and `amount`
+/// columns, and an optional `disputed` column.
+///
+/// Opening balances are configuration rather than input, so any invalid
+/// entry is an error.
+pub fn read_entries<R: Read>(reader: R) -> Result<Vec<OpeningEntry>, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+    let mut entries = vec![];
+    for entry in csv_reader.deserialize() {
+        let entry: OpeningEntry = entry?;
+        if entry.amount <= Money::zero() {
+            return Err(format!(
+                "Opening balance for client {} tx {} must be positive",
+                entry.client, entry.tx
+            )
+            .into());
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn read_opening_entries() {
+        let entries =
+            read_entries("client, tx, amount, disputed\n1, 10, 5.5, true\n2, 11, 3,\n".as_bytes())
+                .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                OpeningEntry {
+                    client: 1,
+                    tx: 10,
+                    amount: money!(5.5),
+                    disputed: true,
+                },
+                OpeningEntry {
+                    client: 2,
+                    tx: 11,
+                    amount: money!(3),
+                    disputed: false,
+                },
+            ]
+        );
+        let err = read_entries("client,tx,amount\n1,10,-5\n".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Opening balance for client 1 tx 10 must be positive"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Reliable publishing of engine events from a persistent store, with the
+//! transactional outbox pattern.
+//!
+//! Publishing an event after the account it concerns is saved loses the
+//! event if the process stops in between, and downstream systems (risk,
+//! webhooks, a Kafka topic) silently fall out of step with the accounts. An
+//! [`OutboxStore`] instead saves the events a transaction raised to an
+//! outbox in the same commit as the account (see
+//! [`AsyncTxEngine::handle_to_outbox`](crate::async_store::AsyncTxEngine::handle_to_outbox)),
+//! and a separate publisher drains the outbox with [`drain_outbox`].
+//!
+//! Delivery is at least once: an event published just before the publisher
+//! stops is published again by the next, so consumers should skip entries
+//! by ID they've already seen. Only one publisher should drain a store's
+//! outbox at a time, so events are published in order.
+//!
+//! IDs are given out as entries are added, but instances sharing a store
+//! can commit them in a different order, so an entry may turn up after
+//! entries with higher IDs were published. Publishers therefore only remove
+//! the entries they published, never everything up to an ID, and consumers
+//! should keep the IDs they've seen rather than only the highest.
+
+use crate::account::Account;
+use crate::async_store::{AsyncAccountStore, StoreError};
+use crate::event::EngineEvent;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+
+/// An event in the outbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    /// Increases with each entry, so entries are published in ID order.
+    pub id: u64,
+    pub event: EngineEvent,
+}
+
+/// An [`AsyncAccountStore`] with an outbox of events to publish.
+pub trait OutboxStore: AsyncAccountStore {
+    /// As [`AsyncAccountStore::update`], where `f` also returns the events
+    /// raised, which are added to the outbox in the same commit as the
+    /// account is saved: either both are saved, or neither.
+    fn update_with_events<R: Send>(
+        &self,
+        client_id: u16,
+        f: impl FnOnce(&mut Account) -> (R, Vec<EngineEvent>) + Send,
+    ) -> impl Future<Output = Result<R, StoreError>> + Send;
+
+    /// Up to `limit` entries not yet published, oldest first.
+    fn pending(
+        &self,
+        limit: usize,
+    ) -> impl Future<Output = Result<Vec<OutboxEntry>, StoreError>> + Send;
+
+    /// Removes the entries with `ids` from the outbox, once they've been
+    /// published.
+    fn published(&self, ids: &[u64]) -> impl Future<Output = Result<(), StoreError>> + Send;
+}
+
+/// Publishes the entries in the outbox, in order, `batch` at a time, until
+/// it's empty. Returns the number published.
+///
+/// Entries are removed once each batch is published. If `publish` fails,
+/// the entries published before it are still removed, and draining stops
+/// with its error, leaving the failed entry to be published next time.
+pub async fn drain_outbox<S: OutboxStore>(
+    store: &S,
+    batch: usize,
+    mut publish: impl FnMut(&OutboxEntry) -> Result<(), StoreError>,
+) -> Result<usize, StoreError> {
+    let mut drained = 0;
+    loop {
+        let entries = store.pending(batch.max(1)).await?;
+        if entries.is_empty() {
+            return Ok(drained);
+        }
+        let mut published = Vec::with_capacity(entries.len());
+        for entry in &entries {
+            if let Err(err) = publish(entry) {
+                if !published.is_empty() {
+                    store.published(&published).await?;
+                }
+                return Err(err);
+            }
+            published.push(entry.id);
+            drained += 1;
+        }
+        store.published(&published).await?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::AccountStatement;
+    use crate::async_store::{AsyncTxEngine, InMemoryAsyncStore};
+    use crate::money::money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::EngineConfig;
+    use std::collections::BTreeMap;
+    use std::pin::pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll, Waker};
+
+    /// Runs a future that never waits, as the in-memory store's don't.
+    fn ready<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("Future waited"),
+        }
+    }
+
+    #[test]
+    fn events_published_from_outbox() {
+        let mut engine = AsyncTxEngine::new(InMemoryAsyncStore::new(), EngineConfig::default());
+        for (client_id, transaction_id, info) in [
+            (1, 1, TransactionInfo::Deposit(money!(10))),
+            (1, 2, TransactionInfo::Withdrawal(money!(8))),
+            (1, 1, TransactionInfo::Dispute(None)),
+            (2, 3, TransactionInfo::Deposit(money!(4))),
+            (2, 4, TransactionInfo::Withdrawal(money!(4))),
+            (2, 3, TransactionInfo::Dispute(None)),
+        ] {
+            let transaction = Transaction {
+                client_id,
+                transaction_id,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            };
+            ready(engine.handle_to_outbox(&transaction))
+                .unwrap()
+                .unwrap();
+        }
+        // Raised in the outbox rather than by the engine.
+        assert_eq!(engine.drain_events().count(), 0);
+
+        let store = engine.store();
+        let mut published = vec![];
+        let err = ready(drain_outbox(store, 1, |entry| {
+            if !published.is_empty() {
+                return Err("Broker unavailable".into());
+            }
+            published.push(entry.clone());
+            Ok(())
+        }))
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Broker unavailable");
+        assert!(matches!(
+            published[0].event,
+            EngineEvent::DisputeShortfall { client: 1, .. }
+        ));
+
+        // The failed entry is published next time, and nothing else.
+        let drained = ready(drain_outbox(store, 10, |entry| {
+            published.push(entry.clone());
+            Ok(())
+        }))
+        .unwrap();
+        assert_eq!(drained, 1);
+        let ids: Vec<u64> = published.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert!(matches!(
+            published[1].event,
+            EngineEvent::DisputeShortfall { client: 2, .. }
+        ));
+        assert!(ready(store.pending(10)).unwrap().is_empty());
+    }
+
+    /// An outbox whose entries are committed by the test, in any ID order,
+    /// as entries from instances sharing a database can be.
+    #[derive(Default)]
+    struct SharedOutbox {
+        accounts: InMemoryAsyncStore,
+        entries: Mutex<BTreeMap<u64, OutboxEntry>>,
+    }
+
+    impl SharedOutbox {
+        fn commit(&self, id: u64) {
+            let event = EngineEvent::NoteAdded {
+                after_sequence: id,
+                client: 1,
+                tx: None,
+                author: "ops".into(),
+                note: format!("Entry {}", id),
+            };
+            self.entries
+                .lock()
+                .unwrap()
+                .insert(id, OutboxEntry { id, event });
+        }
+    }
+
+    impl AsyncAccountStore for SharedOutbox {
+        async fn update<R: Send>(
+            &self,
+            client_id: u16,
+            f: impl FnOnce(&mut Account) -> R + Send,
+        ) -> Result<R, StoreError> {
+            self.accounts.update(client_id, f).await
+        }
+
+        async fn account_statements(&self) -> Result<Vec<AccountStatement>, StoreError> {
+            self.accounts.account_statements().await
+        }
+    }
+
+    impl OutboxStore for SharedOutbox {
+        async fn update_with_events<R: Send>(
+            &self,
+            client_id: u16,
+            f: impl FnOnce(&mut Account) -> (R, Vec<EngineEvent>) + Send,
+        ) -> Result<R, StoreError> {
+            self.accounts.update_with_events(client_id, f).await
+        }
+
+        async fn pending(&self, limit: usize) -> Result<Vec<OutboxEntry>, StoreError> {
+            let entries = self.entries.lock().unwrap();
+            Ok(entries.values().take(limit).cloned().collect())
+        }
+
+        async fn published(&self, ids: &[u64]) -> Result<(), StoreError> {
+            let mut entries = self.entries.lock().unwrap();
+            for id in ids {
+                entries.remove(id);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn late_entries_kept() {
+        let store = SharedOutbox::default();
+        store.commit(2);
+        store.commit(3);
+        let mut published = vec![];
+        let mut publish = |entry: &OutboxEntry| {
+            published.push(entry.id);
+            // Entry 1 was inserted first, but committed by another instance
+            // only now.
+            if entry.id == 3 {
+                store.commit(1);
+            }
+            Ok(())
+        };
+        assert_eq!(ready(drain_outbox(&store, 2, &mut publish)).unwrap(), 3);
+        assert_eq!(published, [2, 3, 1]);
+        assert!(ready(store.pending(10)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,328 @@
+//! Ledger periods, so books can be closed per business day.
+//!
+//! In a run, each applied transaction is assigned to a business date (see
+//! [`BusinessDates`]), and each client's movements are reported per date.
+//!
+//! A long-running [`crate::shared_engine::SharedTxEngine`] closes periods
+//! with a cutover instead, without stopping the engine. A cutover briefly
+//! freezes every shard, so the period's statements are a consistent cut
+//! across all clients, then processing carries on into the next period with
+//! the same state. Scheduling cutovers is up to the host, e.g. a timer
+//! calling [`SharedTxEngine::cutover`] at the end of each day, as
+//! `payments-engine serve --cutover-every` does.
+//!
+//! [`SharedTxEngine::cutover`]: crate::shared_engine::SharedTxEngine::cutover
+
+use crate::account::{AccountStatement, StatementOptions};
+use crate::clock::{self, SECONDS_PER_DAY};
+use crate::event::EngineEvent;
+use crate::money::{Money, OUTPUT_SCALE};
+use crate::system_accounts::SystemAccounts;
+use crate::transaction::TransactionInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Options for assigning a run's transactions to business dates.
+#[derive(Debug, Clone)]
+pub struct BusinessDateOptions {
+    /// The business date the run opens on, as `YYYY-MM-DD`.
+    pub opening_date: String,
+    /// Write a CSV of each client's movements per business date to this
+    /// path.
+    pub movements: Option<PathBuf>,
+    /// Write the statements at the close of each business date into this
+    /// directory, as `statements_<date>.csv`. Must already exist.
+    pub statements_dir: Option<PathBuf>,
+}
+
+/// A client's movements on one business date.
+#[derive(Debug, Default)]
+struct Movement {
+    transactions: u64,
+    deposited: Money,
+    withdrawn: Money,
+    total_change: Money,
+    held_change: Money,
+}
+
+/// A client's movements on one business date, as written to the movements
+/// report.
+#[derive(Debug, Serialize)]
+struct MovementRow<'a> {
+    date: &'a str,
+    client: u16,
+    /// Applied transactions.
+    transactions: u64,
+    deposited: Money,
+    withdrawn: Money,
+    /// Net change in the client's total funds, including chargebacks and
+    /// fees.
+    total_change: Money,
+    held_change: Money,
+}
+
+/// Assigns applied transactions to business dates, accumulating each
+/// client's movements per date.
+///
+/// The current date only moves forward. Transactions dated earlier than it
+/// are booked to it, as earlier dates are already closed.
+#[derive(Debug)]
+pub struct BusinessDates {
+    current: String,
+    /// Movements by date and client.
+    movements: BTreeMap<(String, u16), Movement>,
+}
+
+impl BusinessDates {
+    pub fn new(opening_date: &str) -> Result<Self, String> {
+        check_date(opening_date)?;
+        Ok(Self {
+            current: opening_date.to_owned(),
+            movements: BTreeMap::new(),
+        })
+    }
+
+    /// The date transactions are currently assigned to.
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// Moves on to `date` if it's later than the current date. Returns the
+    /// date that was closed, if any.
+    pub fn advance(&mut self, date: &str) -> Result<Option<String>, String> {
+        check_date(date)?;
+        if date <= self.current.as_str() {
+            return Ok(None);
+        }
+        Ok(Some(std::mem::replace(&mut self.current, date.to_owned())))
+    }
+
+    /// As [`advance`](Self::advance), to the date (in UTC) of `timestamp`,
+    /// in seconds since the Unix epoch.
+    pub fn advance_to_time(&mut self, timestamp: u64) -> Result<Option<String>, String> {
+        let days = i64::try_from(timestamp / SECONDS_PER_DAY)
+            .map_err(|_| format!("Timestamp {} is out of range", timestamp))?;
+        self.advance(&clock::format_date(days))
+    }
+
+    /// Records a transaction applied to `client` on the current date, with
+    /// the client's total and held funds before and after it.
+    pub fn record(
+        &mut self,
+        client: u16,
+        info: &TransactionInfo,
+        before: (Money, Money),
+        after: (Money, Money),
+    ) {
+        let movement = self
+            .movements
+            .entry((self.current.clone(), client))
+            .or_default();
+        movement.transactions += 1;
+        match info {
+            TransactionInfo::Deposit(amount) => {
+                movement.deposited = &movement.deposited + amount;
+            }
+            TransactionInfo::Withdrawal(amount) => {
+                movement.withdrawn = &movement.withdrawn + amount;
+            }
+            _ => {}
+        }
+        movement.total_change = &movement.total_change + &(&after.0 - &before.0);
+        movement.held_change = &movement.held_change + &(&after.1 - &before.1);
+    }
+
+    /// Writes the movements report as CSV, ordered by date then client.
+    /// Dates and clients without applied transactions are left out.
+    pub fn write_movements<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        // Write the header explicitly, as there may be no movements.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        csv_writer.write_record([
+            "date",
+            "client",
+            "transactions",
+            "deposited",
+            "withdrawn",
+            "total_change",
+            "held_change",
+        ])?;
+        for ((date, client), movement) in &self.movements {
+            csv_writer.serialize(MovementRow {
+                date,
+                client: *client,
+                transactions: movement.transactions,
+                deposited: movement.deposited.round_dp(OUTPUT_SCALE),
+                withdrawn: movement.withdrawn.round_dp(OUTPUT_SCALE),
+                total_change: movement.total_change.round_dp(OUTPUT_SCALE),
+                held_change: movement.held_change.round_dp(OUTPUT_SCALE),
+            })?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Checks a business date is a real date written as `YYYY-MM-DD`, so dates
+/// order as strings.
+fn check_date(date: &str) -> Result<(), String> {
+    let valid = date.len() == 10
+        && date.char_indices().all(|(index, c)| match index {
+            4 | 7 => c == '-',
+            _ => c.is_ascii_digit(),
+        })
+        && clock::parse_date(date).is_some();
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("Business date {:?} isn't a YYYY-MM-DD date", date))
+    }
+}
+
+/// Counts for a closed period, as written to its summary.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PeriodSummary {
+    /// Periods are numbered from one, in the order they're closed.
+    pub period: u32,
+    /// Sequence number of the first transaction applied in the period, if
+    /// any were.
+    pub first_sequence: Option<u64>,
+    /// Sequence number of the last transaction applied by the cutover, in
+    /// this period or an earlier one.
+    pub last_sequence: u64,
+    pub applied: u64,
+    /// Transactions handled in the period but not applied.
+    pub rejected: u64,
+}
+
+/// Everything a cutover captured of the period it closed.
+#[derive(Debug)]
+pub struct PeriodClose {
+    pub summary: PeriodSummary,
+    /// Statements for all accounts at the close, ordered by client.
+    pub statements: Vec<AccountStatement>,
+    /// The engine's own accounts at the close.
+    pub system: SystemAccounts,
+    /// Events raised in the period and not yet drained.
+    pub events: Vec<EngineEvent>,
+}
+
+impl PeriodClose {
+    /// Writes the period's statements, system statement, events and summary
+    /// into `dir`, as `period_<n>_statements.csv`, `period_<n>_system.csv`,
+    /// `period_<n>_events.jsonl` and `period_<n>_summary.json`. Returns the
+    /// paths written.
+    pub fn write_reports(
+        &self,
+        dir: &Path,
+        options: &StatementOptions,
+    ) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let path = |name: &str| dir.join(format!("period_{}_{}", self.summary.period, name));
+
+        let statements = path("statements.csv");
+        // Write the header explicitly, as there may be no accounts.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(BufWriter::new(File::create(&statements)?));
+        csv_writer.write_record(AccountStatement::header(options))?;
+        for statement in &self.statements {
+            csv_writer.serialize(statement.clone().with_options(options))?;
+        }
+        csv_writer.flush()?;
+
+        let system = path("system.csv");
+        self.system
+            .write_statement(BufWriter::new(File::create(&system)?))?;
+
+        let events = path("events.jsonl");
+        let mut writer = BufWriter::new(File::create(&events)?);
+        for event in &self.events {
+            serde_json::to_writer(&mut writer, event)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
+        let summary = path("summary.json");
+        let mut writer = BufWriter::new(File::create(&summary)?);
+        serde_json::to_writer_pretty(&mut writer, &self.summary)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        Ok(vec![statements, system, events, summary])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn movements_per_date() {
+        let mut dates = BusinessDates::new("2024-03-01").unwrap();
+        let deposit = TransactionInfo::Deposit(money!(10));
+        dates.record(1, &deposit, (money!(0), money!(0)), (money!(10), money!(0)));
+        assert_eq!(dates.advance("2024-03-01"), Ok(None));
+        let dispute = TransactionInfo::Dispute(None);
+        dates.record(
+            1,
+            &dispute,
+            (money!(10), money!(0)),
+            (money!(10), money!(10)),
+        );
+        assert_eq!(dates.advance("2024-03-02"), Ok(Some("2024-03-01".into())));
+        // A late transaction is booked to the current date.
+        assert_eq!(dates.advance("2024-03-01"), Ok(None));
+        assert_eq!(dates.current(), "2024-03-02");
+        let chargeback = TransactionInfo::Chargeback;
+        dates.record(
+            1,
+            &chargeback,
+            (money!(10), money!(10)),
+            (money!(0), money!(0)),
+        );
+        let withdrawal = TransactionInfo::Withdrawal(money!(2.5));
+        dates.record(
+            2,
+            &withdrawal,
+            (money!(5), money!(0)),
+            (money!(2.5), money!(0)),
+        );
+
+        assert!(dates.advance("2024-3-3").is_err());
+        assert!(dates.advance("2024-99-99").is_err());
+        assert!(dates.advance("2023-02-29").is_err());
+        assert!(BusinessDates::new("yesterday").is_err());
+
+        let mut output = vec![];
+        dates.write_movements(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "date,client,transactions,deposited,withdrawn,total_change,held_change\n\
+             2024-03-01,1,2,10,0,10,10\n\
+             2024-03-02,1,1,0,0,-10,-10\n\
+             2024-03-02,2,1,0,2.5,-2.5,0\n"
+        );
+    }
+
+    #[test]
+    fn dates_from_timestamps() {
+        let mut dates = BusinessDates::new("2024-02-28").unwrap();
+        // 2024-02-29T23:59:59Z, then the next day.
+        assert_eq!(
+            dates.advance_to_time(1_709_251_199),
+            Ok(Some("2024-02-28".into()))
+        );
+        assert_eq!(dates.current(), "2024-02-29");
+        assert_eq!(
+            dates.advance_to_time(1_709_251_200),
+            Ok(Some("2024-02-29".into()))
+        );
+        assert_eq!(dates.current(), "2024-03-01");
+    }
+}
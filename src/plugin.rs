@@ -0,0 +1,305 @@
+use crate::account::Account;
+use crate::money::Money;
+use crate::transaction::Transaction;
+
+/// Reasons a plugin may decline to let a transaction through.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginError {
+    /// The plugin's logic rejected the transaction, e.g. a risk rule fired.
+    Rejected(String),
+    /// The plugin itself failed to run (trapped, ran out of fuel, bad
+    /// return value...). The transaction should be treated as failed rather
+    /// than rejected.
+    Failed(String),
+}
+
+/// Hook for custom validation and fee logic, called by the
+/// [`crate::transaction_engine::TxEngine`] for every transaction.
+///
+/// Plugins may be native (implemented directly in Rust by an embedder) or
+/// loaded from a WebAssembly module with the `wasm` feature, see
+/// [`wasm::WasmPlugin`].
+pub trait TransactionPlugin: Send {
+    /// Checks whether `tx` may be applied to `account`.
+    ///
+    /// Called after the engine's own account checks (e.g. locked accounts)
+    /// but before any state is modified.
+    fn validate(&mut self, tx: &Transaction, account: &Account) -> Result<(), PluginError>;
+
+    /// Returns the fee to charge for `tx`.
+    ///
+    /// Only called for deposits and withdrawals. The fee is debited from the
+    /// account when the transaction is applied, though a deposit's fees, with
+    /// the engine's own, are capped at the deposit. Defaults to no fee.
+    fn fee(&mut self, _tx: &Transaction) -> Result<Money, PluginError> {
+        Ok(Money::zero())
+    }
+}
+
+#[cfg(feature = "wasm")]
+pub mod wasm {
+    //! WebAssembly plugin host.
+    //!
+    //! A plugin module must export:
+    //!
+    //! * `validate(kind: i32, client: i32, tx: i64, amount: i64, available: i64) -> i32`
+    //!   returning `0` to accept the transaction, or any other value as a
+    //!   rejection code.
+    //!
+    //! And may optionally export:
+    //!
+    //! * `fee(kind: i32, client: i32, amount: i64) -> i64` returning the fee
+    //!   to charge for a deposit or withdrawal.
+    //!
+    //! `kind` is one of 0 (deposit), 1 (withdrawal), 2 (dispute),
+    //! 3 (resolve), 4 (chargeback), 5 (unlock), 6 (convert) or 7 (reversal).
+    //! All amounts are fixed-point integers in units of 0.0001, with `amount`
+    //! zero for transactions without one. A conversion's `amount` is the
+    //! amount taken, in the transaction's currency.
+    //!
+    //! Modules are instantiated without any imports, so have no access to the
+    //! host beyond the values passed in. Each call is bounded by a fuel limit
+    //! and the module's memory is capped.
+    use super::{PluginError, TransactionPlugin};
+    use crate::account::Account;
+    use crate::money::Money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use std::error::Error;
+    use std::path::Path;
+    use wasmtime::{
+        Config, Engine, Instance, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+    };
+
+    /// Fuel available to each plugin call. Roughly one unit per instruction.
+    const FUEL_PER_CALL: u64 = 1_000_000;
+    /// Maximum linear memory a plugin may grow to.
+    const MAX_MEMORY_BYTES: usize = 16 * 1024 * 1024;
+    /// Scale of the fixed-point amounts passed across the plugin boundary.
+    const AMOUNT_SCALE: u32 = 4;
+
+    /// A [`TransactionPlugin`] implemented by a sandboxed WebAssembly module.
+    pub struct WasmPlugin {
+        store: Store<StoreLimits>,
+        validate: TypedFunc<(i32, i32, i64, i64, i64), i32>,
+        fee: Option<TypedFunc<(i32, i32, i64), i64>>,
+    }
+
+    impl WasmPlugin {
+        /// Loads a plugin from a `.wasm` (or `.wat`) file.
+        pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+            let engine = Self::engine()?;
+            let module = Module::from_file(&engine, path)?;
+            Self::instantiate(&engine, &module)
+        }
+
+        /// Loads a plugin from an in-memory `.wasm` binary (or `.wat` text).
+        pub fn from_bytes(bytes: impl AsRef<[u8]>) -> Result<Self, Box<dyn Error>> {
+            let engine = Self::engine()?;
+            let module = Module::new(&engine, bytes)?;
+            Self::instantiate(&engine, &module)
+        }
+
+        fn engine() -> Result<Engine, Box<dyn Error>> {
+            let mut config = Config::new();
+            config.consume_fuel(true);
+            Ok(Engine::new(&config)?)
+        }
+
+        fn instantiate(engine: &Engine, module: &Module) -> Result<Self, Box<dyn Error>> {
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(MAX_MEMORY_BYTES)
+                .build();
+            let mut store = Store::new(engine, limits);
+            store.limiter(|limits| limits);
+            store.set_fuel(FUEL_PER_CALL)?;
+            // No imports: the module can't reach anything outside itself.
+            let instance = Instance::new(&mut store, module, &[])?;
+            let validate = instance.get_typed_func(&mut store, "validate")?;
+            let fee = instance.get_typed_func(&mut store, "fee").ok();
+            Ok(Self {
+                store,
+                validate,
+                fee,
+            })
+        }
+
+        fn refuel(&mut self) -> Result<(), PluginError> {
+            self.store
+                .set_fuel(FUEL_PER_CALL)
+                .map_err(|err| PluginError::Failed(err.to_string()))
+        }
+    }
+
+    fn kind(info: &TransactionInfo) -> i32 {
+        match info {
+            TransactionInfo::Deposit(_) => 0,
+            TransactionInfo::Withdrawal(_) => 1,
+            TransactionInfo::Dispute(_) => 2,
+            TransactionInfo::Resolve => 3,
+            TransactionInfo::Chargeback => 4,
+            TransactionInfo::Unlock(_) => 5,
+            TransactionInfo::Convert(_) => 6,
+            TransactionInfo::Reversal => 7,
+        }
+    }
+
+    fn to_fixed(amount: Option<&Money>) -> Result<i64, PluginError> {
+        let Some(amount) = amount else {
+            return Ok(0);
+        };
+        amount
+            .to_scaled_i64(AMOUNT_SCALE)
+            .ok_or_else(|| PluginError::Failed(format!("Amount {} out of range", amount)))
+    }
+
+    impl TransactionPlugin for WasmPlugin {
+        fn validate(&mut self, tx: &Transaction, account: &Account) -> Result<(), PluginError> {
+            let args = (
+                kind(&tx.info),
+                i32::from(tx.client_id),
+                i64::from(tx.transaction_id),
+                to_fixed(tx.info.amount())?,
+                to_fixed(Some(&account.available_funds()))?,
+            );
+            self.refuel()?;
+            match self.validate.call(&mut self.store, args) {
+                Ok(0) => Ok(()),
+                Ok(code) => Err(PluginError::Rejected(format!(
+                    "Plugin rejection code {}",
+                    code
+                ))),
+                Err(err) => Err(PluginError::Failed(err.to_string())),
+            }
+        }
+
+        fn fee(&mut self, tx: &Transaction) -> Result<Money, PluginError> {
+            let Some(fee) = self.fee else {
+                return Ok(Money::zero());
+            };
+            let args = (
+                kind(&tx.info),
+                i32::from(tx.client_id),
+                to_fixed(tx.info.amount())?,
+            );
+            self.refuel()?;
+            match fee.call(&mut self.store, args) {
+                Ok(fee) if fee >= 0 => Ok(Money::from_scaled(fee, AMOUNT_SCALE)),
+                Ok(fee) => Err(PluginError::Failed(format!("Negative fee {}", fee))),
+                Err(err) => Err(PluginError::Failed(err.to_string())),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use super::*;
+        use crate::intern::Interned;
+        use crate::money::money;
+        use crate::transaction::Conversion;
+
+        // Rejects withdrawals over 50.0000 and charges a flat 0.5 on deposits.
+        const PLUGIN_WAT: &str = r#"
+            (module
+              (func (export "validate")
+                    (param $kind i32) (param $client i32) (param $tx i64)
+                    (param $amount i64) (param $available i64) (result i32)
+                (if (result i32)
+                  (i32.and (i32.eq (local.get $kind) (i32.const 1))
+                           (i64.gt_s (local.get $amount) (i64.const 500000)))
+                  (then (i32.const 7))
+                  (else (i32.const 0))))
+              (func (export "fee")
+                    (param $kind i32) (param $client i32) (param $amount i64) (result i64)
+                (if (result i64) (i32.eqz (local.get $kind))
+                  (then (i64.const 5000))
+                  (else (i64.const 0)))))
+        "#;
+
+        fn tx(info: TransactionInfo) -> Transaction {
+            Transaction {
+                client_id: 1,
+                transaction_id: 1,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            }
+        }
+
+        #[test]
+        fn validate_and_fee() {
+            let mut plugin = WasmPlugin::from_bytes(PLUGIN_WAT).unwrap();
+            let account = Account::new(1);
+            assert!(plugin
+                .validate(&tx(TransactionInfo::Withdrawal(money!(50))), &account)
+                .is_ok());
+            assert_eq!(
+                plugin.validate(&tx(TransactionInfo::Withdrawal(money!(50.0001))), &account),
+                Err(PluginError::Rejected("Plugin rejection code 7".into()))
+            );
+            assert_eq!(
+                plugin
+                    .fee(&tx(TransactionInfo::Deposit(money!(10))))
+                    .unwrap(),
+                money!(0.5)
+            );
+            assert_eq!(
+                plugin
+                    .fee(&tx(TransactionInfo::Withdrawal(money!(10))))
+                    .unwrap(),
+                money!(0)
+            );
+        }
+
+        #[test]
+        fn later_kinds() {
+            // Rejects everything, with the transaction's kind as the code.
+            let mut plugin = WasmPlugin::from_bytes(
+                r#"(module
+                     (func (export "validate")
+                           (param $kind i32) (param i32 i64 i64 i64) (result i32)
+                       (i32.add (local.get $kind) (i32.const 100))))"#,
+            )
+            .unwrap();
+            let account = Account::new(1);
+            let conversion = TransactionInfo::Convert(Conversion {
+                amount: money!(10),
+                to: Interned::from("EUR"),
+                rate: None,
+            });
+            for (info, code) in [
+                (TransactionInfo::Unlock("ops".into()), 105),
+                (conversion, 106),
+                (TransactionInfo::Reversal, 107),
+            ] {
+                assert_eq!(
+                    plugin.validate(&tx(info), &account),
+                    Err(PluginError::Rejected(format!(
+                        "Plugin rejection code {}",
+                        code
+                    )))
+                );
+            }
+        }
+
+        #[test]
+        fn runaway_plugin_fails() {
+            let mut plugin = WasmPlugin::from_bytes(
+                r#"(module
+                     (func (export "validate")
+                           (param i32 i32 i64 i64 i64) (result i32)
+                       (loop (br 0))
+                       (i32.const 0)))"#,
+            )
+            .unwrap();
+            let res = plugin.validate(&tx(TransactionInfo::Deposit(money!(1))), &Account::new(1));
+            assert!(matches!(res, Err(PluginError::Failed(_))));
+        }
+
+        #[test]
+        fn missing_validate_export() {
+            assert!(WasmPlugin::from_bytes("(module)").is_err());
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Per-client processing profiles, summarising what each client did in a
+//! run.
+
+use crate::metrics::Outcome;
+use crate::money::{Money, OUTPUT_SCALE};
+use crate::transaction::TransactionInfo;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+/// Counts and totals for a single client.
+#[derive(Debug, Default)]
+struct ClientCounts {
+    deposits: u64,
+    withdrawals: u64,
+    disputes: u64,
+    resolves: u64,
+    chargebacks: u64,
+    deposited: Money,
+    withdrawn: Money,
+    rejected: u64,
+    failed: u64,
+}
+
+/// A client's profile, as written to the profile report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientProfile {
+    pub client: u16,
+    /// Applied transactions by type.
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub disputes: u64,
+    pub resolves: u64,
+    pub chargebacks: u64,
+    /// Average applied deposit and withdrawal.
+    pub average_deposit: Money,
+    pub average_withdrawal: Money,
+    pub rejected: u64,
+    pub failed: u64,
+    /// Proportion of the client's transactions rejected or failed.
+    pub rejection_rate: f64,
+    /// Disputes per applied deposit.
+    pub dispute_rate: f64,
+}
+
+/// Accumulates per-client profiles while processing.
+#[derive(Debug, Default)]
+pub struct ClientProfiles {
+    clients: BTreeMap<u16, ClientCounts>,
+}
+
+impl ClientProfiles {
+    /// Records a transaction for `client`. `info` is `None` for rows that
+    /// couldn't be parsed into a transaction.
+    pub fn record(&mut self, client: u16, info: Option<&TransactionInfo>, outcome: Outcome) {
+        let counts = self.clients.entry(client).or_default();
+        match (outcome, info) {
+            (Outcome::Rejected, _) => counts.rejected += 1,
+            (Outcome::Failed, _) => counts.failed += 1,
+            // Not yet known to be applied or not.
+            (Outcome::Quarantined, _) => {}
+            (Outcome::Applied, Some(TransactionInfo::Deposit(amount))) => {
+                counts.deposits += 1;
+                counts.deposited = &counts.deposited + amount;
+            }
+            (Outcome::Applied, Some(TransactionInfo::Withdrawal(amount))) => {
+                counts.withdrawals += 1;
+                counts.withdrawn = &counts.withdrawn + amount;
+            }
+            (Outcome::Applied, Some(TransactionInfo::Dispute(_))) => counts.disputes += 1,
+            (Outcome::Applied, Some(TransactionInfo::Resolve)) => counts.resolves += 1,
+            (Outcome::Applied, Some(TransactionInfo::Chargeback)) => counts.chargebacks += 1,
+            // An operator's or the bank's action rather than the client's.
+            (Outcome::Applied, Some(TransactionInfo::Unlock(_) | TransactionInfo::Reversal)) => {}
+            // Moves funds between the client's own balances.
+            (Outcome::Applied, Some(TransactionInfo::Convert(_))) => {}
+            (Outcome::Applied, None) => {}
+        }
+    }
+
+    /// Profiles for every client seen, ordered by client.
+    pub fn profiles(&self) -> Vec<ClientProfile> {
+        self.clients
+            .iter()
+            .map(|(client, counts)| {
+                let applied = counts.deposits
+                    + counts.withdrawals
+                    + counts.disputes
+                    + counts.resolves
+                    + counts.chargebacks;
+                let not_applied = counts.rejected + counts.failed;
+                ClientProfile {
+                    client: *client,
+                    deposits: counts.deposits,
+                    withdrawals: counts.withdrawals,
+                    disputes: counts.disputes,
+                    resolves: counts.resolves,
+                    chargebacks: counts.chargebacks,
+                    average_deposit: counts
+                        .deposited
+                        .div_count(counts.deposits)
+                        .round_dp(OUTPUT_SCALE),
+                    average_withdrawal: counts
+                        .withdrawn
+                        .div_count(counts.withdrawals)
+                        .round_dp(OUTPUT_SCALE),
+                    rejected: counts.rejected,
+                    failed: counts.failed,
+                    rejection_rate: ratio(not_applied, applied + not_applied),
+                    dispute_rate: ratio(counts.disputes, counts.deposits),
+                }
+            })
+            .collect()
+    }
+
+    /// Writes the profile report as CSV.
+    pub fn write_report<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        for profile in self.profiles() {
+            csv_writer.serialize(profile)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// `numerator / denominator`, rounded to 4 decimal places. Zero if the
+/// denominator is.
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        return 0.0;
+    }
+    (numerator as f64 / denominator as f64 * 10_000.0).round() / 10_000.0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn profile_report() {
+        let mut profiles = ClientProfiles::default();
+        for amount in [money!(10), money!(20), money!(30)] {
+            profiles.record(1, Some(&TransactionInfo::Deposit(amount)), Outcome::Applied);
+        }
+        let withdrawal = TransactionInfo::Withdrawal(money!(100));
+        profiles.record(1, Some(&withdrawal), Outcome::Rejected);
+        profiles.record(1, Some(&TransactionInfo::Dispute(None)), Outcome::Applied);
+        profiles.record(1, Some(&TransactionInfo::Resolve), Outcome::Failed);
+        profiles.record(2, None, Outcome::Rejected);
+
+        let mut output = vec![];
+        profiles.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,deposits,withdrawals,disputes,resolves,chargebacks,\
+             average_deposit,average_withdrawal,rejected,failed,\
+             rejection_rate,dispute_rate\n\
+             1,3,0,1,0,0,20,0,1,1,0.3333,0.3333\n\
+             2,0,0,0,0,0,0,0,1,0,1.0,0.0\n"
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Pseudonymized client IDs, so outputs can be shared (e.g. with external
+//! analysts) without revealing which customer each row is about.
+//!
+//! Each client ID is replaced by a pseudonym derived from it with a secret
+//! key. Pseudonyms are client IDs themselves, and distinct clients always get
+//! distinct pseudonyms, so outputs keep their format and can still be joined
+//! on the client column. Without the key, the only way back is the mapping
+//! written separately (see [`Pseudonymizer::write_mapping`]).
+
+use crate::account::AccountStatement;
+use crate::encryption::Key;
+use crate::event::EngineEvent;
+use crate::sha256::hmac;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+/// Rounds of the Feistel network mixing a client ID with the key.
+const ROUNDS: u8 = 4;
+
+pub struct Pseudonymizer {
+    key: Key,
+    /// Pseudonyms handed out so far, by client, for the mapping.
+    issued: RefCell<BTreeMap<u16, u16>>,
+}
+
+impl Pseudonymizer {
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            issued: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// The client's pseudonym.
+    ///
+    /// A Feistel network keyed with HMAC-SHA256 permutes the 16 bit IDs, so
+    /// no two clients share a pseudonym.
+    pub fn pseudonym(&self, client: u16) -> u16 {
+        let [mut left, mut right] = client.to_be_bytes();
+        for round in 0..ROUNDS {
+            let mixed = hmac(self.key.as_bytes(), &[round, right])[0];
+            (left, right) = (right, left ^ mixed);
+        }
+        let pseudonym = u16::from_be_bytes([left, right]);
+        self.issued.borrow_mut().insert(client, pseudonym);
+        pseudonym
+    }
+
+    /// The statement, with its client pseudonymized.
+    pub fn statement(&self, statement: AccountStatement) -> AccountStatement {
+        let pseudonym = self.pseudonym(statement.client());
+        statement.with_client(pseudonym)
+    }
+
+    /// The event, with its client pseudonymized.
+    pub fn event(&self, event: EngineEvent) -> EngineEvent {
+        match event {
+            EngineEvent::DisputeShortfall {
+                sequence,
+                client,
+                tx,
+                shortfall,
+                account_shortfall,
+            } => EngineEvent::DisputeShortfall {
+                sequence,
+                client: self.pseudonym(client),
+                tx,
+                shortfall,
+                account_shortfall,
+            },
+            EngineEvent::NoteAdded {
+                after_sequence,
+                client,
+                tx,
+                author,
+                note,
+            } => EngineEvent::NoteAdded {
+                after_sequence,
+                client: self.pseudonym(client),
+                tx,
+                author,
+                note,
+            },
+            EngineEvent::DisputeReferred {
+                after_sequence,
+                client,
+                tx,
+                held,
+                max_held,
+            } => EngineEvent::DisputeReferred {
+                after_sequence,
+                client: self.pseudonym(client),
+                tx,
+                held,
+                max_held,
+            },
+            EngineEvent::AccountUnlocked {
+                sequence,
+                client,
+                tx,
+                operator,
+                scope,
+            } => EngineEvent::AccountUnlocked {
+                sequence,
+                client: self.pseudonym(client),
+                tx,
+                operator,
+                scope,
+            },
+            EngineEvent::BalanceThresholdCrossed {
+                sequence,
+                client,
+                currency,
+                threshold,
+                limit,
+                available,
+            } => EngineEvent::BalanceThresholdCrossed {
+                sequence,
+                client: self.pseudonym(client),
+                currency,
+                threshold,
+                limit,
+                available,
+            },
+        }
+    }
+
+    /// Writes every pseudonym handed out so far, as CSV with `client` and
+    /// `pseudonym` columns, in client order.
+    pub fn write_mapping<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        // Write the header explicitly, as there may be no clients.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        csv_writer.write_record(["client", "pseudonym"])?;
+        for (client, pseudonym) in self.issued.borrow().iter() {
+            csv_writer.serialize((client, pseudonym))?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Passes CSV through to `inner`, pseudonymizing the `client` column of each
+/// table. Passes everything through as it is without a [`Pseudonymizer`].
+///
+/// A table starts with its header, and ends at a blank line. Its rows are
+/// written out ordered by pseudonym (otherwise keeping their order) when it
+/// ends or the writer is flushed, as tables ordered by client ID would
+/// otherwise reveal how the clients' real IDs are ordered. Records must not
+/// contain line breaks.
+pub struct ClientColumnWriter<'a, W: Write> {
+    inner: W,
+    pseudonyms: Option<&'a Pseudonymizer>,
+    /// The line written so far, not yet passed through.
+    line: Vec<u8>,
+    /// Index of the client column in the current table, once its header has
+    /// been written.
+    client_column: Option<Option<usize>>,
+    /// Pseudonymized rows of the current table, with their pseudonyms.
+    rows: Vec<(u16, Vec<u8>)>,
+}
+
+impl<'a, W: Write> ClientColumnWriter<'a, W> {
+    pub fn new(inner: W, pseudonyms: Option<&'a Pseudonymizer>) -> Self {
+        Self {
+            inner,
+            pseudonyms,
+            line: vec![],
+            client_column: None,
+            rows: vec![],
+        }
+    }
+
+    fn pass_line(&mut self, pseudonyms: &Pseudonymizer) -> std::io::Result<()> {
+        let mut line = std::mem::take(&mut self.line);
+        line.push(b'\n');
+        if line == b"\n" {
+            self.write_rows()?;
+            self.client_column = None;
+            return self.inner.write_all(&line);
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader(line.as_slice());
+        let mut record = csv::StringRecord::new();
+        reader.read_record(&mut record)?;
+        let Some(client_column) = self.client_column else {
+            self.client_column = Some(record.iter().position(|field| field == "client"));
+            return self.inner.write_all(&line);
+        };
+        let Some(client) = client_column
+            .and_then(|column| record.get(column))
+            .and_then(|field| field.parse::<u16>().ok())
+        else {
+            return self.inner.write_all(&line);
+        };
+        let pseudonym = pseudonyms.pseudonym(client);
+        let pseudonym_field = pseudonym.to_string();
+        let fields = record.iter().enumerate().map(|(index, field)| {
+            if Some(index) == client_column {
+                pseudonym_field.as_str()
+            } else {
+                field
+            }
+        });
+        let mut csv_writer = csv::Writer::from_writer(vec![]);
+        csv_writer.write_record(fields)?;
+        let row = csv_writer.into_inner().map_err(|err| err.into_error())?;
+        self.rows.push((pseudonym, row));
+        Ok(())
+    }
+
+    fn write_rows(&mut self) -> std::io::Result<()> {
+        self.rows.sort_by_key(|(pseudonym, _)| *pseudonym);
+        for (_, row) in self.rows.drain(..) {
+            self.inner.write_all(&row)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ClientColumnWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some(pseudonyms) = self.pseudonyms else {
+            return self.inner.write(buf);
+        };
+        for byte in buf {
+            if *byte == b'\n' {
+                self.pass_line(pseudonyms)?;
+            } else {
+                self.line.push(*byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_rows()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    fn pseudonymizer() -> Pseudonymizer {
+        Pseudonymizer::new(Key::from_bytes([7; 32]))
+    }
+
+    #[test]
+    fn pseudonyms_are_distinct() {
+        let pseudonyms = pseudonymizer();
+        let mut seen = vec![false; 1 << 16];
+        for client in 0..=u16::MAX {
+            let pseudonym = usize::from(pseudonyms.pseudonym(client));
+            assert!(!seen[pseudonym]);
+            seen[pseudonym] = true;
+        }
+        // Another key gives different pseudonyms.
+        let other = Pseudonymizer::new(Key::from_bytes([8; 32]));
+        assert_ne!(
+            (1..=10)
+                .map(|client| pseudonyms.pseudonym(client))
+                .collect::<Vec<_>>(),
+            (1..=10)
+                .map(|client| other.pseudonym(client))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rewrites_client_columns() {
+        let pseudonyms = pseudonymizer();
+        let (one, two) = (pseudonyms.pseudonym(1), pseudonyms.pseudonym(2));
+        let pseudonyms = pseudonymizer();
+        let mut output = vec![];
+        let mut writer = ClientColumnWriter::new(&mut output, Some(&pseudonyms));
+        writer
+            .write_all(b"tx,client,reason\n5,1,\"a, b\"\n6,2,\n7,1,\n\nseq,tx\n1,2\n")
+            .unwrap();
+        writer.flush().unwrap();
+        let mut rows = vec![
+            (one, format!("5,{},\"a, b\"\n", one)),
+            (two, format!("6,{},\n", two)),
+            (one, format!("7,{},\n", one)),
+        ];
+        rows.sort_by_key(|(pseudonym, _)| *pseudonym);
+        let rows: String = rows.into_iter().map(|(_, row)| row).collect();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            format!("tx,client,reason\n{}\nseq,tx\n1,2\n", rows)
+        );
+
+        let mut mapping = vec![];
+        pseudonyms.write_mapping(&mut mapping).unwrap();
+        assert_eq!(
+            String::from_utf8(mapping).unwrap(),
+            format!("client,pseudonym\n1,{}\n2,{}\n", one, two)
+        );
+
+        let event = pseudonyms.event(EngineEvent::DisputeShortfall {
+            sequence: 1,
+            client: 1,
+            tx: 2,
+            shortfall: money!(1),
+            account_shortfall: money!(1),
+        });
+        assert!(matches!(event, EngineEvent::DisputeShortfall { client, .. } if client == one));
+    }
+
+    #[test]
+    fn passes_through_without_pseudonyms() {
+        let mut output = vec![];
+        let mut writer = ClientColumnWriter::new(&mut output, None);
+        writer.write_all(b"client\n1\n").unwrap();
+        assert_eq!(output, b"client\n1\n");
+    }
+}
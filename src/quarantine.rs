@@ -0,0 +1,85 @@
+//! Reports on transactions queued for quarantined accounts (see
+//! [`crate::TxEngine::quarantine`]).
+
+use crate::money::Money;
+use crate::transaction::Transaction;
+use crate::transaction_engine::TransactionNotApplied;
+use serde::Serialize;
+use std::error::Error;
+use std::io::Write;
+
+#[derive(Debug, Serialize)]
+struct QueuedTransaction<'a> {
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    amount: Option<&'a Money>,
+}
+
+/// Outcome of a released transaction.
+#[derive(Debug, Serialize)]
+struct ReleasedTransaction {
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// `applied`, or the name of the reason it wasn't.
+    outcome: &'static str,
+    /// Sequence number applied with, if applied.
+    sequence: Option<u64>,
+    reason: Option<String>,
+}
+
+/// Writes the transactions queued for each quarantined client as CSV, in the
+/// order given.
+pub fn write_queue<'a, W: Write>(
+    writer: W,
+    quarantined: impl Iterator<Item = (u16, &'a [Transaction])>,
+) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be nothing queued.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["client", "tx", "type", "amount"])?;
+    for (client, queued) in quarantined {
+        for transaction in queued {
+            csv_writer.serialize(QueuedTransaction {
+                client,
+                tx: transaction.transaction_id,
+                kind: transaction.info.kind(),
+                amount: transaction.amount(),
+            })?;
+        }
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+/// Writes the outcome of each transaction released from quarantine as CSV,
+/// in the order they were applied.
+pub fn write_released<W: Write>(
+    writer: W,
+    released: &[(Transaction, Result<u64, TransactionNotApplied>)],
+) -> Result<(), Box<dyn Error>> {
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["client", "tx", "type", "outcome", "sequence", "reason"])?;
+    for (transaction, result) in released {
+        let (outcome, sequence, reason) = match result {
+            Ok(sequence) => ("applied", Some(*sequence), None),
+            Err(err) => (err.name(), None, Some(err.to_string())),
+        };
+        csv_writer.serialize(ReleasedTransaction {
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            kind: transaction.info.kind(),
+            outcome,
+            sequence,
+            reason,
+        })?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
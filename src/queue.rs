@@ -0,0 +1,335 @@
+//! Bounded queues for handing work between threads, e.g. between a reader
+//! and the engine, or the engine and a slow output sink, so a consumer that
+//! falls behind can't grow a queue until the process runs out of memory.
+//!
+//! Each queue has a capacity and a defined behaviour when it's full (see
+//! [`Overflow`]), and reports its depth for metrics (see [`QueueStats`]).
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// What [`BoundedQueue::push`] does when the queue is full.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Overflow {
+    /// Wait for the consumer to make room, slowing the producer to its pace.
+    Block,
+    /// Hand the item back to the producer, to report as not accepted.
+    Shed,
+    /// Queue further items in a file at this path, as NDJSON, until the
+    /// consumer catches up. The file is created, or truncated, with the
+    /// queue.
+    Spill(PathBuf),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    /// Items held in memory.
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+/// The outcome of pushing an item.
+#[derive(Debug, PartialEq)]
+pub enum Pushed<T> {
+    /// Queued in memory.
+    Queued,
+    /// Queued in the spill file.
+    Spilled,
+    /// Not queued, as the queue was full (with [`Overflow::Shed`]) or
+    /// closed.
+    Shed(T),
+}
+
+/// Counts for a queue, for metrics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct QueueStats {
+    /// Items currently queued, in memory or spilled.
+    pub depth: usize,
+    /// The deepest the queue has been.
+    pub max_depth: usize,
+    pub pushed: u64,
+    pub shed: u64,
+    pub spilled: u64,
+}
+
+/// Items spilled to disk, oldest first.
+struct SpillFile {
+    writer: BufWriter<File>,
+    reader: BufReader<File>,
+    /// Items written and not yet read back.
+    pending: usize,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    spill: Option<SpillFile>,
+    closed: bool,
+    stats: QueueStats,
+}
+
+/// A first-in, first-out queue with a bounded number of items in memory,
+/// safe to share between producer and consumer threads.
+pub struct BoundedQueue<T> {
+    capacity: usize,
+    overflow: Overflow,
+    state: Mutex<State<T>>,
+    not_full: Condvar,
+    not_empty: Condvar,
+}
+
+impl<T: Serialize + DeserializeOwned> BoundedQueue<T> {
+    pub fn new(config: QueueConfig) -> io::Result<Self> {
+        let spill = match &config.overflow {
+            Overflow::Spill(path) => Some(SpillFile {
+                writer: BufWriter::new(File::create(path)?),
+                reader: BufReader::new(OpenOptions::new().read(true).open(path)?),
+                pending: 0,
+            }),
+            Overflow::Block | Overflow::Shed => None,
+        };
+        Ok(Self {
+            // A queue that can't hold anything would block forever.
+            capacity: config.capacity.max(1),
+            overflow: config.overflow,
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                spill,
+                closed: false,
+                stats: QueueStats::default(),
+            }),
+            not_full: Condvar::new(),
+            not_empty: Condvar::new(),
+        })
+    }
+
+    /// Adds an item to the back of the queue, or handles it as the queue's
+    /// [`Overflow`] says if the queue is full.
+    pub fn push(&self, item: T) -> io::Result<Pushed<T>> {
+        let mut state = self.lock();
+        if self.overflow == Overflow::Block {
+            while state.items.len() >= self.capacity && !state.closed {
+                state = self.not_full.wait(state).expect("Queue lock poisoned");
+            }
+        }
+        if state.closed {
+            state.stats.shed += 1;
+            return Ok(Pushed::Shed(item));
+        }
+        // Once spilling, later items follow the spilled ones, to stay in
+        // order.
+        let spilling = state.spill.as_ref().is_some_and(|spill| spill.pending > 0);
+        let pushed = if state.items.len() < self.capacity && !spilling {
+            state.items.push_back(item);
+            Pushed::Queued
+        } else if let Some(spill) = &mut state.spill {
+            serde_json::to_writer(&mut spill.writer, &item)?;
+            spill.writer.write_all(b"\n")?;
+            spill.pending += 1;
+            state.stats.spilled += 1;
+            Pushed::Spilled
+        } else {
+            state.stats.shed += 1;
+            return Ok(Pushed::Shed(item));
+        };
+        state.stats.pushed += 1;
+        state.stats.depth += 1;
+        state.stats.max_depth = state.stats.max_depth.max(state.stats.depth);
+        self.not_empty.notify_one();
+        Ok(pushed)
+    }
+
+    /// Takes the item at the front of the queue, waiting for one if it's
+    /// empty. Returns `None` once the queue is closed and empty.
+    pub fn pop(&self) -> io::Result<Option<T>> {
+        let mut state = self.lock();
+        while state.stats.depth == 0 && !state.closed {
+            state = self.not_empty.wait(state).expect("Queue lock poisoned");
+        }
+        self.take(&mut state)
+    }
+
+    /// Takes the item at the front of the queue, if there is one.
+    pub fn try_pop(&self) -> io::Result<Option<T>> {
+        let mut state = self.lock();
+        self.take(&mut state)
+    }
+
+    /// Stops the queue accepting items. Items already queued can still be
+    /// taken, and anyone waiting is woken.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.not_full.notify_all();
+        self.not_empty.notify_all();
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.lock().stats.clone()
+    }
+
+    fn take(&self, state: &mut State<T>) -> io::Result<Option<T>> {
+        let item = match state.items.pop_front() {
+            Some(item) => item,
+            None => match &mut state.spill {
+                Some(spill) if spill.pending > 0 => {
+                    spill.writer.flush()?;
+                    let mut line = String::new();
+                    spill.reader.read_line(&mut line)?;
+                    let item = serde_json::from_str(&line)?;
+                    spill.pending -= 1;
+                    if spill.pending == 0 {
+                        // Caught up, so start the file afresh rather than
+                        // let it grow for the life of the queue.
+                        spill.writer.get_ref().set_len(0)?;
+                        spill.writer.seek(SeekFrom::Start(0))?;
+                        spill.reader.seek(SeekFrom::Start(0))?;
+                    }
+                    item
+                }
+                _ => return Ok(None),
+            },
+        };
+        state.stats.depth -= 1;
+        self.not_full.notify_one();
+        Ok(Some(item))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<T>> {
+        self.state.lock().expect("Queue lock poisoned")
+    }
+}
+
+/// Writes the stats of named queues in the Prometheus text exposition
+/// format.
+pub fn write_prometheus<W: Write>(mut writer: W, queues: &[(&str, QueueStats)]) -> io::Result<()> {
+    let mut metric = |name: &str, kind: &str, help: &str, value: fn(&QueueStats) -> u64| {
+        let name = format!("payments_engine_queue_{}", name);
+        writeln!(writer, "# HELP {} {}", name, help)?;
+        writeln!(writer, "# TYPE {} {}", name, kind)?;
+        for (queue, stats) in queues {
+            writeln!(writer, "{}{{queue=\"{}\"}} {}", name, queue, value(stats))?;
+        }
+        io::Result::Ok(())
+    };
+    metric("depth", "gauge", "Items queued.", |stats| {
+        stats.depth as u64
+    })?;
+    metric(
+        "max_depth",
+        "gauge",
+        "Most items queued at once.",
+        |stats| stats.max_depth as u64,
+    )?;
+    metric("pushed_total", "counter", "Items accepted.", |stats| {
+        stats.pushed
+    })?;
+    metric("shed_total", "counter", "Items not accepted.", |stats| {
+        stats.shed
+    })?;
+    metric(
+        "spilled_total",
+        "counter",
+        "Items spilled to disk.",
+        |stats| stats.spilled,
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    fn queue(overflow: Overflow) -> BoundedQueue<u32> {
+        BoundedQueue::new(QueueConfig {
+            capacity: 2,
+            overflow,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn shed_when_full() {
+        let queue = queue(Overflow::Shed);
+        assert_eq!(queue.push(1).unwrap(), Pushed::Queued);
+        assert_eq!(queue.push(2).unwrap(), Pushed::Queued);
+        assert_eq!(queue.push(3).unwrap(), Pushed::Shed(3));
+        assert_eq!(queue.pop().unwrap(), Some(1));
+        assert_eq!(queue.push(4).unwrap(), Pushed::Queued);
+        queue.close();
+        assert_eq!(queue.push(5).unwrap(), Pushed::Shed(5));
+        assert_eq!(queue.pop().unwrap(), Some(2));
+        assert_eq!(queue.pop().unwrap(), Some(4));
+        assert_eq!(queue.pop().unwrap(), None);
+        assert_eq!(
+            queue.stats(),
+            QueueStats {
+                depth: 0,
+                max_depth: 2,
+                pushed: 3,
+                shed: 2,
+                spilled: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn spill_keeps_order() {
+        let path =
+            std::env::temp_dir().join(format!("pe-queue-spill-{}.ndjson", std::process::id()));
+        let queue = queue(Overflow::Spill(path.clone()));
+        for item in 1..=5 {
+            queue.push(item).unwrap();
+        }
+        assert_eq!(queue.stats().depth, 5);
+        assert_eq!(queue.stats().spilled, 3);
+        // Room in memory again, but 4 is still behind spilled items.
+        assert_eq!(queue.pop().unwrap(), Some(1));
+        assert_eq!(queue.push(6).unwrap(), Pushed::Spilled);
+        let items: Vec<_> = std::iter::from_fn(|| queue.try_pop().unwrap()).collect();
+        assert_eq!(items, [2, 3, 4, 5, 6]);
+        // Caught up, so the spill file was emptied.
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(queue.push(7).unwrap(), Pushed::Queued);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn block_until_consumed() {
+        let queue = Arc::new(queue(Overflow::Block));
+        let producer = {
+            let queue = Arc::clone(&queue);
+            std::thread::spawn(move || {
+                for item in 0..100 {
+                    assert_eq!(queue.push(item).unwrap(), Pushed::Queued);
+                }
+                queue.close();
+            })
+        };
+        let items: Vec<_> = std::iter::from_fn(|| queue.pop().unwrap()).collect();
+        producer.join().unwrap();
+        assert_eq!(items, (0..100).collect::<Vec<_>>());
+        assert_eq!(queue.stats().max_depth, 2);
+    }
+
+    #[test]
+    fn prometheus_exposition() {
+        let mut output = vec![];
+        let stats = QueueStats {
+            depth: 1,
+            max_depth: 3,
+            pushed: 4,
+            shed: 0,
+            spilled: 2,
+        };
+        write_prometheus(&mut output, &[("events", stats)]).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("# TYPE payments_engine_queue_depth gauge\n"));
+        assert!(output.contains("payments_engine_queue_depth{queue=\"events\"} 1\n"));
+        assert!(output.contains("payments_engine_queue_spilled_total{queue=\"events\"} 2\n"));
+    }
+}
@@ -0,0 +1,214 @@
+//! Signed receipts for transactions applied by the service (see
+//! [`crate::server::PaymentsService::with_receipts`]), as proof for partners
+//! that a transaction was settled.
+//!
+//! A receipt records the transaction, the balance it left the account with,
+//! its sequence number and a digest of the account's whole state just after
+//! it was applied. It's signed with Ed25519, as statements are (see
+//! [`crate::signing`]), so partners verify receipts with the engine's public
+//! key and can't issue receipts themselves. The signature covers the
+//! receipt's other fields, as the JSON [`ReceiptBody`] serializes to (fields
+//! in declaration order, no whitespace). Issuing and verifying receipts
+//! require the `signing` feature.
+
+use crate::account::{Account, AccountStatement};
+use crate::encryption::{self, Key};
+use crate::intern::Interned;
+use crate::money::Money;
+use crate::sha256;
+use crate::signing;
+use crate::transaction::{Transaction, TransactionInfo};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// The signed part of a [`Receipt`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReceiptBody {
+    pub sequence: u64,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: String,
+    /// For deposits and withdrawals; dispute steps act on the disputed
+    /// transaction's amount.
+    pub amount: Option<Money>,
+    /// Currency of the balance below, if the account's transactions have
+    /// named one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Interned>,
+    /// The resulting balance the transaction was applied to, as in its
+    /// statement.
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+    pub locked: bool,
+    /// SHA-256 of the account's serialized state just after the
+    /// transaction was applied, in hex.
+    pub state_digest: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    #[serde(flatten)]
+    pub body: ReceiptBody,
+    /// Ed25519 signature of the body, in hex.
+    pub signature: String,
+}
+
+/// Signs receipts with an Ed25519 key.
+pub struct ReceiptSigner {
+    key: Key,
+    public_key: String,
+}
+
+impl ReceiptSigner {
+    /// Fails without the `signing` feature.
+    pub fn new(key: Key) -> Result<Self, Box<dyn Error>> {
+        let public_key = sha256::to_hex(&signing::public_key(&key)?);
+        Ok(Self { key, public_key })
+    }
+
+    /// The public key verifying the signer's receipts, in hex.
+    pub fn public_key(&self) -> &str {
+        &self.public_key
+    }
+
+    /// The receipt for `transaction`, applied with `sequence`, leaving
+    /// `account` as given.
+    pub fn issue(&self, transaction: &Transaction, sequence: u64, account: &Account) -> Receipt {
+        // The balance in the transaction's currency, or the disputed
+        // transaction's.
+        let balance = account
+            .balance_with(transaction.transaction_id)
+            .or_else(|| {
+                let currency = transaction.currency.as_ref()?;
+                account
+                    .balances()
+                    .find(|balance| balance.currency() == Some(currency))
+            })
+            .unwrap_or(account);
+        let statement = AccountStatement::from(balance);
+        let state = serde_json::to_vec(account).expect("Accounts always serialize");
+        let amount = match &transaction.info {
+            TransactionInfo::Deposit(amount) | TransactionInfo::Withdrawal(amount) => {
+                Some(amount.clone())
+            }
+            _ => None,
+        };
+        self.sign(ReceiptBody {
+            sequence,
+            client: transaction.client_id,
+            tx: transaction.transaction_id,
+            transaction_type: transaction.info.kind().to_owned(),
+            amount,
+            currency: statement.currency().cloned(),
+            available: statement.available().clone(),
+            held: statement.held().clone(),
+            total: statement.total().clone(),
+            locked: account.locked(),
+            state_digest: sha256::to_hex(&Sha256::digest(&state)),
+        })
+    }
+
+    pub fn sign(&self, body: ReceiptBody) -> Receipt {
+        let message = serde_json::to_vec(&body).expect("Receipts always serialize");
+        // Signing can only fail without the feature, when `new` would have.
+        let signature = signing::sign(&self.key, &message).expect("Receipt signing is enabled");
+        Receipt {
+            body,
+            signature: sha256::to_hex(&signature),
+        }
+    }
+}
+
+/// Checks the receipt's signature is over its body, by the key with
+/// `public_key` in hex.
+pub fn verify(public_key: &str, receipt: &Receipt) -> Result<(), Box<dyn Error>> {
+    let message = serde_json::to_vec(&receipt.body)?;
+    signing::verify(public_key, &message, &receipt.signature)
+}
+
+/// Receipts appended to a file as JSON Lines, each written before the
+/// receipt is returned.
+pub struct ReceiptLog {
+    file: Mutex<File>,
+}
+
+impl ReceiptLog {
+    /// Opens the log at `path`, appending to any receipts already there.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends the receipt, encrypted with `key` if given (see
+    /// [`encryption::encrypt_line`]).
+    pub fn append(&self, receipt: &Receipt, key: Option<&Key>) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_vec(receipt)?;
+        if let Some(key) = key {
+            line = encryption::encrypt_line(key, &line)?.into_bytes();
+        }
+        line.push(b'\n');
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // One write per receipt, so concurrent appends don't interleave.
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod test {
+    use super::*;
+    use crate::money::money;
+    use crate::system_accounts::SystemAccounts;
+
+    #[test]
+    fn receipts_verify() {
+        let mut account = Account::new(3);
+        account
+            .credit(
+                7,
+                &money!(12.5),
+                &Money::zero(),
+                &mut SystemAccounts::default(),
+                || 1,
+            )
+            .unwrap();
+        let transaction = Transaction {
+            client_id: 3,
+            transaction_id: 7,
+            info: TransactionInfo::Deposit(money!(12.5)),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let signer = ReceiptSigner::new(Key::from_bytes([1; 32])).unwrap();
+        let receipt = signer.issue(&transaction, 1, &account);
+        assert_eq!(receipt.body.total, money!(12.5));
+        assert_eq!(receipt.body.transaction_type, "deposit");
+        assert_eq!(receipt.signature.len(), 128);
+        verify(signer.public_key(), &receipt).unwrap();
+
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert!(json.starts_with(r#"{"sequence":1,"client":3,"tx":7,"type":"deposit","#));
+        assert_eq!(serde_json::from_str::<Receipt>(&json).unwrap(), receipt);
+
+        let mut altered = receipt.clone();
+        altered.body.total = money!(125);
+        assert!(verify(signer.public_key(), &altered).is_err());
+        let other = ReceiptSigner::new(Key::from_bytes([2; 32])).unwrap();
+        assert!(verify(other.public_key(), &receipt).is_err());
+    }
+}
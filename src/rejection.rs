@@ -0,0 +1,265 @@
+//! Structured responses for transactions that weren't applied, giving
+//! integrators a typed error contract rather than display strings.
+//!
+//! A server responds to a transaction that wasn't applied with the
+//! [`Rejection`]'s status and its JSON body (or, over gRPC, its
+//! [`ErrorInfo`]; see [`crate::server`]), e.g.
+//!
+//! ```json
+//! {"code":"InsufficientFunds","message":"Insufficient Funds: short by 2.5","tx":2,"client":1}
+//! ```
+//!
+//! Codes are the names of the [`TransactionNotApplied`] variants (see
+//! [`TransactionNotApplied::name`]), plus [`MALFORMED`] for requests that
+//! aren't a valid transaction, and are stable. Messages are for people and
+//! may change.
+//!
+//! A run reports the transactions it rejected as [`RejectedTransaction`]s,
+//! which can be written as CSV with [`write_rejected`].
+
+use crate::transaction::Transaction;
+use crate::transaction_engine::TransactionNotApplied;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+/// Code of a request that isn't a valid transaction.
+pub const MALFORMED: &str = "MalformedTransaction";
+
+/// Domain of the [`ErrorInfo`] in gRPC error details.
+pub const ERROR_DOMAIN: &str = "payments-engine";
+
+/// Structured error details, as gRPC's `google.rpc.ErrorInfo`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorInfo {
+    /// The rejection's code.
+    pub reason: &'static str,
+    pub domain: &'static str,
+    /// `tx`, and `client` if known.
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// A transaction that wasn't applied, as reported to the caller.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Rejection {
+    /// HTTP status to respond with. Not part of the body.
+    #[serde(skip)]
+    pub http_status: u16,
+    /// gRPC status code to respond with. Not part of the body.
+    #[serde(skip)]
+    pub grpc_code: u8,
+    pub code: &'static str,
+    pub message: String,
+    pub tx: u32,
+    /// `None` only for malformed requests without a readable client.
+    pub client: Option<u16>,
+}
+
+impl Rejection {
+    pub fn new(transaction: &Transaction, err: &TransactionNotApplied) -> Self {
+        Self {
+            http_status: err.http_status(),
+            grpc_code: err.grpc_code(),
+            code: err.name(),
+            message: err.to_string(),
+            tx: transaction.transaction_id,
+            client: Some(transaction.client_id),
+        }
+    }
+
+    /// A request that couldn't be parsed as a transaction.
+    pub fn malformed(tx: u32, client: Option<u16>, message: String) -> Self {
+        Self {
+            http_status: 400,
+            // INVALID_ARGUMENT
+            grpc_code: 3,
+            code: MALFORMED,
+            message,
+            tx,
+            client,
+        }
+    }
+
+    /// The response body, as JSON.
+    pub fn body(&self) -> String {
+        serde_json::to_string(self).expect("Rejections always serialize")
+    }
+
+    /// The gRPC error details.
+    pub fn error_info(&self) -> ErrorInfo {
+        let mut metadata = BTreeMap::from([("tx".to_owned(), self.tx.to_string())]);
+        if let Some(client) = self.client {
+            metadata.insert("client".to_owned(), client.to_string());
+        }
+        ErrorInfo {
+            reason: self.code,
+            domain: ERROR_DOMAIN,
+            metadata,
+        }
+    }
+}
+
+/// A transaction a run rejected, due to account state or invalid input.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedTransaction {
+    pub tx: u32,
+    pub client: u16,
+    /// Transaction type, as written in the input.
+    #[serde(rename = "type")]
+    pub kind: String,
+    /// Description of the rejection cause.
+    pub reason: String,
+}
+
+impl RejectedTransaction {
+    pub fn new(transaction: &Transaction, err: &TransactionNotApplied) -> Self {
+        Self {
+            tx: transaction.transaction_id,
+            client: transaction.client_id,
+            kind: transaction.info.kind().to_owned(),
+            reason: err.to_string(),
+        }
+    }
+
+    /// A row that couldn't be parsed as a transaction.
+    pub fn malformed(tx: u32, client: u16, kind: String) -> Self {
+        Self {
+            tx,
+            client,
+            kind,
+            reason: "Malformed Transaction".to_owned(),
+        }
+    }
+}
+
+/// Writes rejected transactions as CSV, in the order given.
+pub fn write_rejected<W: Write>(
+    rejected: &[RejectedTransaction],
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be no rejections.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["tx", "client", "type", "reason"])?;
+    for rejection in rejected {
+        csv_writer.serialize(rejection)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+    use crate::risk::Limit;
+    use crate::transaction::TransactionInfo;
+
+    #[test]
+    fn every_variant_mapped() {
+        let transaction = Transaction {
+            client_id: 1,
+            transaction_id: 2,
+            info: TransactionInfo::Chargeback,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        for (err, http_status, grpc_code) in [
+            (TransactionNotApplied::AlreadyApplied(2), 200, 0),
+            (TransactionNotApplied::Quarantined, 202, 0),
+            (
+                TransactionNotApplied::DisputedTransactionNotFound(2),
+                404,
+                5,
+            ),
+            (TransactionNotApplied::RepeatTransaction(2), 409, 6),
+            (
+                TransactionNotApplied::InvalidDisputeState("".into()),
+                409,
+                10,
+            ),
+            (TransactionNotApplied::InsufficientFunds(money!(1)), 422, 9),
+            (TransactionNotApplied::DisputeWindowExpired(2), 422, 9),
+            (TransactionNotApplied::RateUnavailable(2), 422, 9),
+            (TransactionNotApplied::RejectedByPlugin("".into()), 422, 9),
+            (TransactionNotApplied::ArithmeticOverflow, 422, 11),
+            (TransactionNotApplied::AccountLocked, 423, 9),
+            (TransactionNotApplied::AccountDormant, 423, 9),
+            (TransactionNotApplied::DestinationBlocked, 403, 7),
+            (TransactionNotApplied::ClientMismatch(2), 403, 7),
+            (TransactionNotApplied::DestinationLimitExceeded, 422, 9),
+            (
+                TransactionNotApplied::LimitExceeded(Limit::Withdrawal),
+                422,
+                9,
+            ),
+            (TransactionNotApplied::UnexpectedError("".into()), 500, 13),
+            (TransactionNotApplied::PluginFailure("".into()), 503, 14),
+        ] {
+            let rejection = Rejection::new(&transaction, &err);
+            assert_eq!(rejection.http_status, http_status, "{:?}", err);
+            assert_eq!(rejection.grpc_code, grpc_code, "{:?}", err);
+            assert_eq!(rejection.code, err.name());
+        }
+    }
+
+    #[test]
+    fn body() {
+        let transaction = Transaction {
+            client_id: 1,
+            transaction_id: 2,
+            info: TransactionInfo::Chargeback,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let rejection = Rejection::new(
+            &transaction,
+            &TransactionNotApplied::InsufficientFunds(money!(2.5)),
+        );
+        assert_eq!(
+            rejection.body(),
+            r#"{"code":"InsufficientFunds","message":"Insufficient Funds: short by 2.5","tx":2,"client":1}"#
+        );
+        let rejection = Rejection::malformed(3, None, "Missing amount".into());
+        assert_eq!(rejection.http_status, 400);
+        assert_eq!(
+            rejection.body(),
+            r#"{"code":"MalformedTransaction","message":"Missing amount","tx":3,"client":null}"#
+        );
+        let info = rejection.error_info();
+        assert_eq!(info.reason, MALFORMED);
+        assert_eq!(info.domain, ERROR_DOMAIN);
+        assert_eq!(info.metadata, BTreeMap::from([("tx".into(), "3".into())]));
+    }
+
+    #[test]
+    fn rejected_written_as_csv() {
+        let transaction = Transaction {
+            client_id: 1,
+            transaction_id: 2,
+            info: TransactionInfo::Withdrawal(money!(5)),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let rejected = [
+            RejectedTransaction::new(&transaction, &TransactionNotApplied::AccountLocked),
+            RejectedTransaction::malformed(3, 4, "refund".into()),
+        ];
+        let mut output = vec![];
+        write_rejected(&rejected, &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "tx,client,type,reason\n\
+             2,1,withdrawal,Account Locked\n\
+             3,4,refund,Malformed Transaction\n"
+        );
+    }
+}
@@ -0,0 +1,520 @@
+use crate::account::{Account, AccountStatement};
+use crate::intern::Interned;
+use crate::localize::OutputLocale;
+use crate::money::Money;
+use crate::transaction::Transaction;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+
+/// Number of entries shown in each "top N" table of a report.
+const TOP_N: usize = 10;
+
+/// An applied transaction that moved funds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Movement {
+    pub client: u16,
+    pub tx: u32,
+    pub kind: &'static str,
+    pub amount: Money,
+}
+
+/// Summary of a run, accumulated while processing.
+#[derive(Debug, Default)]
+pub struct RunSummary {
+    /// Input rows that could not be read at all.
+    pub unreadable_rows: usize,
+    /// Transactions successfully applied.
+    pub applied: usize,
+    /// Transactions queued for quarantined accounts.
+    pub quarantined: usize,
+    /// Transactions skipped as exact duplicates of one in an earlier input.
+    pub duplicates_skipped: usize,
+    /// Count of rejected transactions by reason.
+    pub rejected: BTreeMap<&'static str, usize>,
+    /// Count of failed transactions by reason.
+    pub failed: BTreeMap<&'static str, usize>,
+    /// The largest applied movements, largest first.
+    pub largest_movements: Vec<Movement>,
+    /// Client funds by currency, as of the end of the run (see
+    /// [`RunSummary::record_totals`]).
+    pub currency_totals: Vec<CurrencyTotals>,
+}
+
+/// Client funds in one currency, summed across the balances in it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CurrencyTotals {
+    /// Empty for balances that don't name a currency.
+    pub currency: Interned,
+    /// Number of client balances in the currency.
+    pub balances: usize,
+    pub available: Money,
+    pub held: Money,
+    pub total: Money,
+}
+
+/// Sums statements by currency, in currency order, so balances in different
+/// currencies are never added together.
+pub fn currency_totals<'a>(
+    statements: impl IntoIterator<Item = &'a AccountStatement>,
+) -> Vec<CurrencyTotals> {
+    let mut totals: BTreeMap<Interned, CurrencyTotals> = BTreeMap::new();
+    for statement in statements {
+        let currency = statement.currency().cloned().unwrap_or_else(|| "".into());
+        let entry = totals
+            .entry(currency.clone())
+            .or_insert_with(|| CurrencyTotals {
+                currency,
+                balances: 0,
+                available: Money::zero(),
+                held: Money::zero(),
+                total: Money::zero(),
+            });
+        entry.balances += 1;
+        entry.available = &entry.available + statement.available();
+        entry.held = &entry.held + statement.held();
+        entry.total = &entry.total + statement.total();
+    }
+    totals.into_values().collect()
+}
+
+impl RunSummary {
+    /// Records a successfully applied transaction.
+    pub fn record_applied(&mut self, tx: &Transaction) {
+        self.applied += 1;
+        let Some(amount) = tx.info.amount() else {
+            return;
+        };
+        if self.largest_movements.len() == TOP_N
+            && self.largest_movements[TOP_N - 1].amount >= *amount
+        {
+            return;
+        }
+        let movement = Movement {
+            client: tx.client_id,
+            tx: tx.transaction_id,
+            kind: tx.info.kind(),
+            amount: amount.clone(),
+        };
+        // Keep the list sorted, earliest transaction first amongst equals.
+        let pos = self
+            .largest_movements
+            .partition_point(|existing| existing.amount >= movement.amount);
+        self.largest_movements.insert(pos, movement);
+        self.largest_movements.truncate(TOP_N);
+    }
+
+    /// Records a rejected transaction.
+    pub fn record_rejected(&mut self, reason: &'static str) {
+        *self.rejected.entry(reason).or_default() += 1;
+    }
+
+    /// Records a failed transaction.
+    pub fn record_failed(&mut self, reason: &'static str) {
+        *self.failed.entry(reason).or_default() += 1;
+    }
+
+    /// Records client funds by currency from the statements at the end of
+    /// the run.
+    pub fn record_totals<'a>(
+        &mut self,
+        statements: impl IntoIterator<Item = &'a AccountStatement>,
+    ) {
+        self.currency_totals = currency_totals(statements);
+    }
+}
+
+/// Renders a self-contained HTML report for a run, localized for `locale`.
+pub fn render_html<W: Write>(
+    mut writer: W,
+    summary: &RunSummary,
+    statements: &[AccountStatement],
+    locale: &OutputLocale,
+) -> std::io::Result<()> {
+    let label = |label| locale.label(label);
+    let rejected: usize = summary.rejected.values().sum();
+    let failed: usize = summary.failed.values().sum();
+    let mut locked: Vec<&AccountStatement> = statements.iter().filter(|s| s.locked()).collect();
+    let locked_count = locked.len();
+    // Largest balances (positive or overdrawn) first.
+    locked.sort_by(|a, b| {
+        b.total()
+            .abs()
+            .cmp(&a.total().abs())
+            .then(a.client().cmp(&b.client()))
+    });
+    locked.truncate(TOP_N);
+
+    writeln!(writer, "<!DOCTYPE html>")?;
+    writeln!(writer, "<html lang=\"{}\">", escape_html(locale.tag()))?;
+    writeln!(writer, "<head>")?;
+    writeln!(writer, "<meta charset=\"utf-8\">")?;
+    let title = label("Payments engine run report");
+    writeln!(writer, "<title>{}</title>", title)?;
+    writeln!(
+        writer,
+        "<style>body{{font-family:sans-serif;margin:2em}}\
+         table{{border-collapse:collapse;margin-bottom:2em}}\
+         th,td{{border:1px solid #ccc;padding:4px 8px;text-align:right}}\
+         th{{background:#eee}}</style>"
+    )?;
+    writeln!(writer, "</head>")?;
+    writeln!(writer, "<body>")?;
+    writeln!(writer, "<h1>{}</h1>", title)?;
+
+    writeln!(writer, "<h2>{}</h2>", label("Summary"))?;
+    writeln!(writer, "<table>")?;
+    for (name, value) in [
+        ("Accounts", statements.len()),
+        ("Locked accounts", locked_count),
+        ("Applied transactions", summary.applied),
+        ("Rejected transactions", rejected),
+        ("Failed transactions", failed),
+        ("Unreadable rows", summary.unreadable_rows),
+        ("Duplicates skipped", summary.duplicates_skipped),
+    ] {
+        writeln!(
+            writer,
+            "<tr><th>{}</th><td>{}</td></tr>",
+            label(name),
+            locale.count(value)
+        )?;
+    }
+    writeln!(writer, "</table>")?;
+
+    // Single-currency reports are as they've always been.
+    if summary.currency_totals.len() > 1 {
+        writeln!(writer, "<h2>{}</h2>", label("Totals by currency"))?;
+        writeln!(writer, "<table>")?;
+        write_header(
+            &mut writer,
+            ["Currency", "Balances", "Available", "Held", "Total"].map(label),
+        )?;
+        for totals in &summary.currency_totals {
+            writeln!(
+                writer,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                escape_html(&totals.currency),
+                locale.count(totals.balances),
+                locale.amount(&totals.available),
+                locale.amount(&totals.held),
+                locale.amount(&totals.total)
+            )?;
+        }
+        writeln!(writer, "</table>")?;
+    }
+
+    writeln!(writer, "<h2>{}</h2>", label("Top locked accounts"))?;
+    if locked.is_empty() {
+        writeln!(writer, "<p>{}</p>", label("No locked accounts."))?;
+    } else {
+        writeln!(writer, "<table>")?;
+        write_header(
+            &mut writer,
+            ["Client", "Available", "Held", "Total"].map(label),
+        )?;
+        for statement in locked {
+            writeln!(
+                writer,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                statement.client(),
+                locale.amount(statement.available()),
+                locale.amount(statement.held()),
+                locale.amount(statement.total())
+            )?;
+        }
+        writeln!(writer, "</table>")?;
+    }
+
+    writeln!(writer, "<h2>{}</h2>", label("Largest movements"))?;
+    if summary.largest_movements.is_empty() {
+        writeln!(writer, "<p>{}</p>", label("No movements."))?;
+    } else {
+        writeln!(writer, "<table>")?;
+        write_header(
+            &mut writer,
+            ["Client", "Transaction", "Type", "Amount"].map(label),
+        )?;
+        for movement in &summary.largest_movements {
+            writeln!(
+                writer,
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                movement.client,
+                movement.tx,
+                label(movement.kind),
+                locale.amount(&movement.amount)
+            )?;
+        }
+        writeln!(writer, "</table>")?;
+    }
+
+    for (title, breakdown) in [
+        ("Rejections", &summary.rejected),
+        ("Failures", &summary.failed),
+    ] {
+        writeln!(writer, "<h2>{}</h2>", label(title))?;
+        if breakdown.is_empty() {
+            writeln!(writer, "<p>{}</p>", label("None."))?;
+            continue;
+        }
+        writeln!(writer, "<table>")?;
+        write_header(&mut writer, ["Reason", "Count"].map(label))?;
+        for (reason, count) in breakdown {
+            writeln!(
+                writer,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape_html(reason),
+                locale.count(*count)
+            )?;
+        }
+        writeln!(writer, "</table>")?;
+    }
+
+    writeln!(writer, "</body>")?;
+    writeln!(writer, "</html>")?;
+    writer.flush()
+}
+
+fn write_header<W: Write, const N: usize>(
+    writer: &mut W,
+    columns: [&str; N],
+) -> std::io::Result<()> {
+    write!(writer, "<tr>")?;
+    for column in columns {
+        write!(writer, "<th>{}</th>", column)?;
+    }
+    writeln!(writer, "</tr>")
+}
+
+/// An account with no activity for at least the dormancy period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DormantAccount {
+    pub client: u16,
+    /// Sequence number of the account's last applied transaction.
+    pub last_activity: u64,
+    /// Transactions applied to other accounts since.
+    pub idle: u64,
+    pub total: Money,
+    pub locked: bool,
+}
+
+/// Finds the accounts dormant as of sequence number `now`, having been idle
+/// for at least `after` transactions. Ordered by client.
+pub fn dormant_accounts<'a>(
+    accounts: impl Iterator<Item = &'a Account>,
+    now: u64,
+    after: u64,
+) -> Vec<DormantAccount> {
+    let mut dormant: Vec<DormantAccount> = accounts
+        .filter(|account| account.dormant(now, after))
+        .map(|account| {
+            let statement = AccountStatement::from(account);
+            DormantAccount {
+                client: account.client(),
+                last_activity: account.last_activity(),
+                idle: now - account.last_activity(),
+                total: statement.total().clone(),
+                locked: statement.locked(),
+            }
+        })
+        .collect();
+    dormant.sort_by_key(|account| account.client);
+    dormant
+}
+
+/// Writes a dormancy report as CSV, one row per dormant account.
+pub fn write_dormancy_report<W: Write>(
+    writer: W,
+    dormant: &[DormantAccount],
+) -> Result<(), Box<dyn Error>> {
+    // Write the header explicitly, as there may be no dormant accounts.
+    let mut csv_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    csv_writer.write_record(["client", "last_activity", "idle", "total", "locked"])?;
+    for account in dormant {
+        csv_writer.serialize(account)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
+
+fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::{Account, LockScope};
+    use crate::money::money;
+    use crate::transaction::TransactionInfo;
+
+    fn deposit(tx: u32, amount: Money) -> Transaction {
+        Transaction {
+            client_id: 1,
+            transaction_id: tx,
+            info: TransactionInfo::Deposit(amount),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn largest_movements_kept_in_order() {
+        let mut summary = RunSummary::default();
+        for tx in 1..=20 {
+            summary.record_applied(&deposit(tx, Money::from(i64::from(tx % 7))));
+        }
+        summary.record_applied(&Transaction {
+            client_id: 1,
+            transaction_id: 1,
+            info: TransactionInfo::Dispute(None),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        });
+        assert_eq!(summary.applied, 21);
+        let amounts: Vec<Money> = summary
+            .largest_movements
+            .iter()
+            .map(|m| m.amount.clone())
+            .collect();
+        assert_eq!(
+            amounts,
+            [6, 6, 6, 5, 5, 5, 4, 4, 4, 3].map(Money::from).to_vec()
+        );
+        // Ties keep the earlier transaction first.
+        assert_eq!(summary.largest_movements[0].tx, 6);
+        assert_eq!(summary.largest_movements[1].tx, 13);
+    }
+
+    #[test]
+    fn html_report_contents() {
+        let mut summary = RunSummary::default();
+        summary.record_applied(&deposit(1, money!(12.5)));
+        summary.record_rejected("InsufficientFunds");
+        summary.record_rejected("InsufficientFunds");
+        summary.record_failed("<script>");
+        let mut locked = Account::new(7);
+        locked.set_funds(money!(-3), Money::zero());
+        locked.lock(LockScope::BlockAll);
+        let statements = vec![(&Account::new(1)).into(), (&locked).into()];
+
+        let mut output = vec![];
+        render_html(&mut output, &summary, &statements, &OutputLocale::default()).unwrap();
+        let html = String::from_utf8(output).unwrap();
+        assert!(html.contains("<tr><th>Locked accounts</th><td>1</td></tr>"));
+        assert!(html.contains("<tr><td>7</td><td>0</td><td>0</td><td>-3</td></tr>"));
+        assert!(html.contains("<tr><td>1</td><td>1</td><td>deposit</td><td>12.5</td></tr>"));
+        assert!(html.contains("<tr><td>InsufficientFunds</td><td>2</td></tr>"));
+        assert!(html.contains("&lt;script&gt;"));
+        assert!(!html.contains("<script>"));
+
+        let mut output = vec![];
+        let german = "de-DE".parse().unwrap();
+        render_html(&mut output, &summary, &statements, &german).unwrap();
+        let html = String::from_utf8(output).unwrap();
+        assert!(html.contains("<html lang=\"de-DE\">"));
+        assert!(html.contains("<tr><th>Gesperrte Konten</th><td>1</td></tr>"));
+        assert!(html.contains("<tr><td>1</td><td>1</td><td>Einzahlung</td><td>12,5</td></tr>"));
+        // Codes are stable, so aren't translated.
+        assert!(html.contains("<tr><td>InsufficientFunds</td><td>2</td></tr>"));
+    }
+
+    #[test]
+    fn totals_by_currency() {
+        let mut account = Account::new(1);
+        account.set_funds(money!(10), money!(2));
+        account.set_last_activity(1);
+        let euros = account.balance_mut(Some(&"EUR".into()));
+        euros.set_funds(money!(4), Money::zero());
+        euros.set_last_activity(2);
+        let mut other = Account::new(2);
+        other.set_funds(money!(1), Money::zero());
+        let statements: Vec<AccountStatement> = [account, other]
+            .iter()
+            .flat_map(Account::statements)
+            .collect();
+        let mut summary = RunSummary::default();
+        summary.record_totals(&statements);
+        assert_eq!(
+            summary.currency_totals,
+            [
+                CurrencyTotals {
+                    currency: "".into(),
+                    balances: 2,
+                    available: money!(9),
+                    held: money!(2),
+                    total: money!(11),
+                },
+                CurrencyTotals {
+                    currency: "EUR".into(),
+                    balances: 1,
+                    available: money!(4),
+                    held: money!(0),
+                    total: money!(4),
+                },
+            ]
+        );
+
+        let mut output = vec![];
+        render_html(&mut output, &summary, &statements, &OutputLocale::default()).unwrap();
+        let html = String::from_utf8(output).unwrap();
+        assert!(html.contains("<tr><td>EUR</td><td>1</td><td>4</td><td>0</td><td>4</td></tr>"));
+
+        // Only shown if there's more than one currency.
+        summary.record_totals(&statements[..1]);
+        let mut output = vec![];
+        render_html(&mut output, &summary, &statements, &OutputLocale::default()).unwrap();
+        assert!(!String::from_utf8(output)
+            .unwrap()
+            .contains("Totals by currency"));
+    }
+
+    #[test]
+    fn dormancy_report() {
+        let mut never_active = Account::new(1);
+        never_active.set_funds(money!(5), Money::zero());
+        let mut idle = Account::new(2);
+        idle.set_funds(money!(7.5), Money::zero());
+        idle.set_last_activity(3);
+        let mut recent = Account::new(3);
+        recent.set_last_activity(8);
+        let accounts = [recent, never_active, idle];
+
+        let dormant = dormant_accounts(accounts.iter(), 10, 5);
+        assert_eq!(
+            dormant,
+            vec![DormantAccount {
+                client: 2,
+                last_activity: 3,
+                idle: 7,
+                total: money!(7.5),
+                locked: false,
+            }]
+        );
+
+        let mut output = vec![];
+        write_dormancy_report(&mut output, &dormant).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,last_activity,idle,total,locked\n2,3,7,7.5,false\n"
+        );
+    }
+}
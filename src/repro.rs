@@ -0,0 +1,165 @@
+//! Repro bundles: failed transactions, with what's needed to replay them.
+//!
+//! A transaction that fails (see
+//! [`TransactionNotApplied::is_failure`]) is put in the dead letter queue,
+//! and working out why used to mean rebuilding the state it failed against
+//! by hand. With [`crate::RunOptions::repro_dir`], a run also writes a
+//! bundle for each, as JSON: the row as the engine read it, the failure,
+//! and a snapshot of the engine holding just the client's account, with the
+//! engine's config, sequence number and system accounts. `payments-engine
+//! repro <bundle>` replays the row against the snapshot.
+//!
+//! Plugins and rate providers aren't part of the snapshot, so failures
+//! coming from them may not reproduce.
+
+use crate::account_store::InMemoryStore;
+use crate::transaction::{Transaction, TransactionRaw};
+use crate::transaction_engine::{TransactionNotApplied, TxEngine};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// Version of the bundle layout, increased whenever it changes
+/// incompatibly. The engine snapshot is versioned separately (see
+/// [`crate::STATE_VERSION`]).
+pub const REPRO_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub struct ReproBundle {
+    pub version: u32,
+    /// The row as the engine read it, after any ingestion profile (see
+    /// [`crate::ingestion`]) was applied.
+    pub row: TransactionRaw,
+    /// Why the transaction failed, as reported in the dead letter queue.
+    pub failure: String,
+    /// The engine as the transaction found it, holding only the client's
+    /// account (see [`TxEngine::extract`]).
+    pub engine: TxEngine<InMemoryStore>,
+}
+
+impl ReproBundle {
+    /// A bundle for `row`, which just failed against `engine` with
+    /// `failure`. Failed transactions change nothing, so the engine is as
+    /// the transaction found it.
+    pub fn capture(
+        row: TransactionRaw,
+        failure: &TransactionNotApplied,
+        engine: &TxEngine<InMemoryStore>,
+    ) -> Self {
+        Self {
+            version: REPRO_VERSION,
+            failure: failure.to_string(),
+            engine: engine.extract(row.client),
+            row,
+        }
+    }
+
+    pub fn write<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Reads a bundle written with [`ReproBundle::write`], failing if it's
+    /// from another version.
+    pub fn read(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let bundle: Self = serde_json::from_slice(bytes)?;
+        if bundle.version != REPRO_VERSION {
+            return Err(format!(
+                "Repro bundle version {} isn't supported (expected {})",
+                bundle.version, REPRO_VERSION
+            )
+            .into());
+        }
+        Ok(bundle)
+    }
+
+    /// Applies the row to the bundled engine again.
+    pub fn replay(mut self) -> Replay {
+        let outcome = match Transaction::try_from(self.row) {
+            Ok(transaction) => self
+                .engine
+                .handle(&transaction)
+                .map_err(|err| err.to_string()),
+            Err(_) => Err("Malformed Transaction".to_owned()),
+        };
+        Replay {
+            recorded: self.failure,
+            outcome,
+        }
+    }
+}
+
+/// What replaying a bundle came to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    /// The failure recorded in the bundle.
+    pub recorded: String,
+    /// The sequence number the row was applied with, or why it wasn't.
+    pub outcome: Result<u64, String>,
+}
+
+impl Replay {
+    /// Whether the row failed again, the same way.
+    pub fn reproduced(&self) -> bool {
+        self.outcome.as_ref().err() == Some(&self.recorded)
+    }
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Err(err) if self.reproduced() => write!(f, "Reproduced: {}", err),
+            Err(err) => write!(f, "Not reproduced: {} (recorded: {})", err, self.recorded),
+            Ok(sequence) => write!(
+                f,
+                "Not reproduced: applied with sequence {} (recorded: {})",
+                sequence, self.recorded
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::AccountStore;
+    use crate::input::parse_json;
+
+    #[test]
+    fn replays_failures() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        // The bundle for the row, if it failed.
+        let mut apply = |row: &str| {
+            let row = parse_json(row.as_bytes()).unwrap();
+            let transaction = Transaction::try_from(row.clone()).unwrap();
+            engine
+                .handle(&transaction)
+                .err()
+                .map(|err| ReproBundle::capture(row, &err, &engine))
+        };
+        assert!(apply(r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}"#).is_none());
+        assert!(apply(r#"{"type":"deposit","client":2,"tx":2,"amount":"5"}"#).is_none());
+        let Some(bundle) = apply(r#"{"type":"deposit","client":1,"tx":1,"amount":"3"}"#) else {
+            panic!("Repeated deposit applied");
+        };
+        assert_eq!(bundle.failure, "Repeat Transaction: 1");
+        // Only the client's account is kept.
+        assert_eq!(bundle.engine.store().accounts().count(), 1);
+        assert_eq!(bundle.engine.last_sequence(), 2);
+
+        let mut bytes = vec![];
+        bundle.write(&mut bytes).unwrap();
+        let replay = ReproBundle::read(&bytes).unwrap().replay();
+        assert!(replay.reproduced());
+        assert_eq!(replay.to_string(), "Reproduced: Repeat Transaction: 1");
+
+        // Against an engine without the original deposit, it applies.
+        let mut bundle = ReproBundle::read(&bytes).unwrap();
+        bundle.engine = TxEngine::new(InMemoryStore::new());
+        assert_eq!(
+            bundle.replay().to_string(),
+            "Not reproduced: applied with sequence 1 (recorded: Repeat Transaction: 1)"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Resources a run used, for capacity planning and spotting regressions
+//! between runs: peak memory, CPU time per stage of the run, bytes read and
+//! written and, with the `alloc-stats` feature, allocator statistics.
+//!
+//! Peak memory and CPU time are read from `/proc`, so are only measured on
+//! Linux. CPU time is the whole process's, so includes any threads the
+//! stage started, at the kernel's tick resolution (usually 10ms).
+
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// Clock ticks per second `/proc` reports CPU times in. Fixed by the kernel's
+/// ABI, whatever the kernel's own tick rate.
+const USER_HZ: f64 = 100.0;
+
+/// What a run used.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ResourceUsage {
+    /// Peak resident set size of the process at the end of the run, if it
+    /// could be read (see [`crate::bench::peak_memory_bytes`]). Includes
+    /// anything the process did before the run.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak_memory_bytes: Option<u64>,
+    /// Bytes read from the run's inputs: transactions, and any opening
+    /// balances.
+    pub bytes_read: u64,
+    /// Bytes written to the run's outputs: statements, and any files written
+    /// alongside them.
+    pub bytes_written: u64,
+    /// Each stage of the run, in the order they ran.
+    pub stages: Vec<StageUsage>,
+    /// Allocations made by the process by the end of the run, with the
+    /// `alloc-stats` feature and its `TrackingAllocator` installed as the
+    /// global allocator.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allocator: Option<AllocatorStats>,
+}
+
+impl ResourceUsage {
+    /// Usage of a run that's just finished, given its `stages` and I/O.
+    pub fn measure(stages: Stages, bytes_read: u64, bytes_written: u64) -> Self {
+        Self {
+            peak_memory_bytes: crate::bench::peak_memory_bytes(),
+            bytes_read,
+            bytes_written,
+            stages: stages.finish(),
+            allocator: allocator_stats(),
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for stage in &self.stages {
+            write!(
+                f,
+                "{:<17}{:.3}s",
+                format!("{}:", stage.stage),
+                stage.wall_seconds
+            )?;
+            match stage.cpu_seconds {
+                Some(cpu) => writeln!(f, " ({:.2}s CPU)", cpu)?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(f, "bytes read:      {}", self.bytes_read)?;
+        writeln!(f, "bytes written:   {}", self.bytes_written)?;
+        match self.peak_memory_bytes {
+            Some(bytes) => writeln!(f, "peak memory:     {:.1} MiB", bytes as f64 / 1048576.0)?,
+            None => writeln!(f, "peak memory:     unavailable")?,
+        }
+        if let Some(allocator) = &self.allocator {
+            writeln!(f, "allocations:     {}", allocator.allocations)?;
+            writeln!(
+                f,
+                "allocated:       {:.1} MiB",
+                allocator.allocated_bytes as f64 / 1048576.0
+            )?;
+            writeln!(
+                f,
+                "peak allocated:  {:.1} MiB",
+                allocator.peak_bytes as f64 / 1048576.0
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Time spent in a stage of a run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StageUsage {
+    pub stage: &'static str,
+    pub wall_seconds: f64,
+    /// Process CPU time (user and system), if it could be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<f64>,
+}
+
+/// Allocator activity, as counted by the `alloc-stats` feature's
+/// `TrackingAllocator`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct AllocatorStats {
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    /// Most bytes allocated at once.
+    pub peak_bytes: u64,
+}
+
+/// Times the stages of a run, each running until the next starts.
+#[derive(Debug, Default)]
+pub struct Stages {
+    finished: Vec<StageUsage>,
+    current: Option<(&'static str, Instant, Option<Duration>)>,
+}
+
+impl Stages {
+    /// Finishes the current stage, if any, and starts `stage`.
+    pub fn start(&mut self, stage: &'static str) {
+        self.finish_current();
+        self.current = Some((stage, Instant::now(), cpu_time()));
+    }
+
+    /// Finishes the current stage, returning every stage's usage.
+    pub fn finish(mut self) -> Vec<StageUsage> {
+        self.finish_current();
+        self.finished
+    }
+
+    fn finish_current(&mut self) {
+        let Some((stage, started, cpu_started)) = self.current.take() else {
+            return;
+        };
+        let cpu_seconds = cpu_started
+            .zip(cpu_time())
+            .map(|(started, now)| now.saturating_sub(started).as_secs_f64());
+        self.finished.push(StageUsage {
+            stage,
+            wall_seconds: started.elapsed().as_secs_f64(),
+            cpu_seconds,
+        });
+    }
+}
+
+/// CPU time used by the process so far, user and system, on Linux.
+pub fn cpu_time() -> Option<Duration> {
+    parse_cpu_time(&std::fs::read_to_string("/proc/self/stat").ok()?)
+}
+
+/// CPU time from `/proc/<pid>/stat`: its `utime` and `stime` fields, the
+/// 14th and 15th.
+fn parse_cpu_time(stat: &str) -> Option<Duration> {
+    // The command name (the 2nd field) may contain spaces, so fields are
+    // counted from the parenthesis closing it, the state being the 3rd.
+    let (_, fields) = stat.rsplit_once(')')?;
+    let mut fields = fields.split_whitespace().skip(11);
+    let user: u64 = fields.next()?.parse().ok()?;
+    let system: u64 = fields.next()?.parse().ok()?;
+    Some(Duration::from_secs_f64((user + system) as f64 / USER_HZ))
+}
+
+#[cfg(feature = "alloc-stats")]
+pub use tracking::TrackingAllocator;
+
+/// Allocator statistics so far, if [`TrackingAllocator`] is installed.
+#[cfg(feature = "alloc-stats")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    tracking::stats()
+}
+
+/// Allocator statistics so far, only counted with the `alloc-stats` feature.
+#[cfg(not(feature = "alloc-stats"))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    None
+}
+
+#[cfg(feature = "alloc-stats")]
+mod tracking {
+    use super::AllocatorStats;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+    static ALLOCATED: AtomicU64 = AtomicU64::new(0);
+    static IN_USE: AtomicU64 = AtomicU64::new(0);
+    static PEAK: AtomicU64 = AtomicU64::new(0);
+
+    /// The system allocator, counting allocations for
+    /// [`super::allocator_stats`]. Install it in the binary with
+    /// `#[global_allocator]`.
+    pub struct TrackingAllocator;
+
+    unsafe impl GlobalAlloc for TrackingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let ptr = System.alloc(layout);
+            if !ptr.is_null() {
+                let size = layout.size() as u64;
+                ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+                ALLOCATED.fetch_add(size, Ordering::Relaxed);
+                let in_use = IN_USE.fetch_add(size, Ordering::Relaxed) + size;
+                PEAK.fetch_max(in_use, Ordering::Relaxed);
+            }
+            ptr
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+            IN_USE.fetch_sub(layout.size() as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// `None` until something's been allocated, i.e. if the allocator isn't
+    /// installed.
+    pub(super) fn stats() -> Option<AllocatorStats> {
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+        (allocations != 0).then(|| AllocatorStats {
+            allocations,
+            allocated_bytes: ALLOCATED.load(Ordering::Relaxed),
+            peak_bytes: PEAK.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpu_time_parsed() {
+        let stat = "4242 (payments engine) R 1 4242 4242 0 -1 4194304 120 0 0 0 \
+                    250 31 0 0 20 0 1 0 100 1000 200";
+        assert_eq!(parse_cpu_time(stat), Some(Duration::from_millis(2810)));
+        assert_eq!(parse_cpu_time("4242 (truncated"), None);
+    }
+
+    #[test]
+    fn stages_in_order() {
+        let mut stages = Stages::default();
+        stages.start("input");
+        stages.start("output");
+        let stages = stages.finish();
+        let names: Vec<_> = stages.iter().map(|stage| stage.stage).collect();
+        assert_eq!(names, ["input", "output"]);
+        assert!(Stages::default().finish().is_empty());
+    }
+}
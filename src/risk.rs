@@ -0,0 +1,187 @@
+//! Risk limits on what each client may do, e.g. in an engine config file:
+//!
+//! ```json
+//! {"risk": {"max_withdrawal": "5000", "max_daily_withdrawals": "10000", "max_transactions": 500}}
+//! ```
+//!
+//! A transaction over a limit is rejected with
+//! [`TransactionNotApplied::LimitExceeded`](crate::TransactionNotApplied::LimitExceeded),
+//! naming the limit. Daily totals are of withdrawals' amounts (not their
+//! fees) in any currency, by the UTC day they happened on (see
+//! [`crate::clock`]), so untimed withdrawals aren't counted towards them.
+//! Only deposits, withdrawals and conversions, which clients make, count
+//! towards and are held to the cap on transactions; disputes and their
+//! steps come from elsewhere, and aren't limited.
+//!
+//! What clients have done is counted from when the engine is created or
+//! restored from a snapshot, i.e. per run.
+
+use crate::clock::SECONDS_PER_DAY;
+use crate::money::Money;
+use crate::transaction::TransactionInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Limits applying to every client (see [`crate::EngineConfig::risk`]).
+/// None by default.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RiskLimits {
+    /// Largest single withdrawal.
+    pub max_withdrawal: Option<Money>,
+    /// Most a client may withdraw in a day.
+    pub max_daily_withdrawals: Option<Money>,
+    /// Most deposits, withdrawals and conversions a client may make.
+    pub max_transactions: Option<u64>,
+}
+
+impl RiskLimits {
+    pub fn is_set(&self) -> bool {
+        self.max_withdrawal.is_some()
+            || self.max_daily_withdrawals.is_some()
+            || self.max_transactions.is_some()
+    }
+}
+
+/// Which of the [`RiskLimits`] a transaction would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Withdrawal,
+    DailyWithdrawals,
+    Transactions,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Withdrawal => "max_withdrawal",
+            Limit::DailyWithdrawals => "max_daily_withdrawals",
+            Limit::Transactions => "max_transactions",
+        })
+    }
+}
+
+/// What a client has done so far, as limited by [`RiskLimits`].
+#[derive(Debug, Clone, Default)]
+pub struct Velocity {
+    transactions: u64,
+    /// Amount withdrawn on each day, in days since 1970-01-01.
+    withdrawn: BTreeMap<u64, Money>,
+}
+
+impl Velocity {
+    /// Whether a transaction at `time` would keep the client within
+    /// `limits`, or the limit it would exceed.
+    pub fn check(
+        &self,
+        limits: &RiskLimits,
+        info: &TransactionInfo,
+        time: Option<u64>,
+    ) -> Result<(), Limit> {
+        if !counted(info) {
+            return Ok(());
+        }
+        if limits
+            .max_transactions
+            .is_some_and(|max| self.transactions >= max)
+        {
+            return Err(Limit::Transactions);
+        }
+        let TransactionInfo::Withdrawal(amount) = info else {
+            return Ok(());
+        };
+        if limits
+            .max_withdrawal
+            .as_ref()
+            .is_some_and(|max| amount > max)
+        {
+            return Err(Limit::Withdrawal);
+        }
+        if let (Some(max), Some(time)) = (&limits.max_daily_withdrawals, time) {
+            let withdrawn = self.withdrawn.get(&(time / SECONDS_PER_DAY));
+            // An overflowing total is over any limit.
+            if withdrawn
+                .map_or(Some(amount.clone()), |withdrawn| {
+                    withdrawn.checked_add(amount)
+                })
+                .is_none_or(|total| &total > max)
+            {
+                return Err(Limit::DailyWithdrawals);
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a transaction at `time`, just applied.
+    pub fn record(&mut self, info: &TransactionInfo, time: Option<u64>) {
+        if !counted(info) {
+            return;
+        }
+        self.transactions += 1;
+        if let (TransactionInfo::Withdrawal(amount), Some(time)) = (info, time) {
+            let withdrawn = self.withdrawn.entry(time / SECONDS_PER_DAY).or_default();
+            // Only overflows without a daily limit, when totals don't matter.
+            if let Some(total) = withdrawn.checked_add(amount) {
+                *withdrawn = total;
+            }
+        }
+    }
+}
+
+fn counted(info: &TransactionInfo) -> bool {
+    matches!(
+        info,
+        TransactionInfo::Deposit(_) | TransactionInfo::Withdrawal(_) | TransactionInfo::Convert(_)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn limits_checked() {
+        let limits: RiskLimits = serde_json::from_str(
+            r#"{"max_withdrawal": "50", "max_daily_withdrawals": 80, "max_transactions": 4}"#,
+        )
+        .unwrap();
+        let mut velocity = Velocity::default();
+        let withdrawal = |amount| TransactionInfo::Withdrawal(amount);
+        let day = Some(SECONDS_PER_DAY);
+        assert_eq!(
+            velocity.check(&limits, &withdrawal(money!(50.01)), None),
+            Err(Limit::Withdrawal)
+        );
+        velocity.record(&withdrawal(money!(50)), day);
+        assert_eq!(
+            velocity.check(&limits, &withdrawal(money!(30.01)), day),
+            Err(Limit::DailyWithdrawals)
+        );
+        // Untimed, or on another day.
+        assert_eq!(
+            velocity.check(&limits, &withdrawal(money!(40)), None),
+            Ok(())
+        );
+        let next_day = Some(2 * SECONDS_PER_DAY + 1);
+        assert_eq!(
+            velocity.check(&limits, &withdrawal(money!(40)), next_day),
+            Ok(())
+        );
+        velocity.record(&withdrawal(money!(30)), day);
+        velocity.record(&TransactionInfo::Deposit(money!(1)), None);
+        velocity.record(&TransactionInfo::Resolve, None);
+        velocity.record(&TransactionInfo::Deposit(money!(1)), None);
+        assert_eq!(
+            velocity.check(&limits, &TransactionInfo::Deposit(money!(1)), None),
+            Err(Limit::Transactions)
+        );
+        // Dispute steps aren't limited.
+        assert_eq!(
+            velocity.check(&limits, &TransactionInfo::Chargeback, None),
+            Ok(())
+        );
+        assert_eq!(Limit::DailyWithdrawals.to_string(), "max_daily_withdrawals");
+    }
+}
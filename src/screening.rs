@@ -0,0 +1,300 @@
+//! Screening of applied transactions for suspicious patterns, such as
+//! structuring deposits to stay under a reporting threshold.
+//!
+//! Transactions carry no timestamps, so "rapid" is measured in each client's
+//! own transactions rather than in time.
+
+use crate::money::Money;
+use crate::transaction::{Transaction, TransactionInfo};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// Thresholds for each screening.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScreeningConfig {
+    /// The reporting threshold structured deposits stay under.
+    pub threshold: Money,
+    /// How far under the threshold a deposit counts as "just under".
+    pub margin: Money,
+    /// Deposits just under the threshold before a client is flagged.
+    pub min_near_threshold: usize,
+    /// A withdrawal of at least this fraction of a deposit, within
+    /// `cycle_window` of the client's transactions, completes a cycle.
+    pub cycle_fraction: Money,
+    /// How many of the client's transactions a cycle may span.
+    pub cycle_window: usize,
+    /// Deposit-withdrawal cycles before a client is flagged.
+    pub min_cycles: usize,
+    /// Disputes per deposit before a client is flagged.
+    pub max_dispute_rate: f64,
+    /// Disputes before the dispute rate is considered.
+    pub min_disputes: usize,
+}
+
+impl Default for ScreeningConfig {
+    fn default() -> Self {
+        Self {
+            threshold: Money::from(10_000),
+            margin: Money::from(1_000),
+            min_near_threshold: 3,
+            cycle_fraction: Money::from_scaled(9, 1),
+            cycle_window: 3,
+            min_cycles: 3,
+            max_dispute_rate: 0.5,
+            min_disputes: 3,
+        }
+    }
+}
+
+/// Where to write the flags report, and the thresholds to screen with.
+#[derive(Debug, Clone)]
+pub struct ScreeningOptions {
+    pub path: PathBuf,
+    pub config: ScreeningConfig,
+}
+
+/// A pattern a client was flagged for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlagKind {
+    /// Many deposits just under the threshold.
+    Structuring,
+    /// Deposits quickly withdrawn again.
+    RapidCycling,
+    /// An unusually high proportion of deposits disputed.
+    DisputeRate,
+}
+
+/// A client flagged by screening, as written to the flags report.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Flag {
+    pub client: u16,
+    pub flag: FlagKind,
+    /// Occurrences of the pattern.
+    pub count: usize,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+struct ClientActivity {
+    /// Applied transactions, used to measure cycle windows.
+    transactions: usize,
+    deposits: usize,
+    near_threshold: usize,
+    disputes: usize,
+    cycles: usize,
+    /// Deposits within the cycle window, with the index of the client
+    /// transaction they were.
+    recent_deposits: VecDeque<(usize, Money)>,
+}
+
+/// Screens applied transactions as they're processed.
+#[derive(Debug)]
+pub struct Screening {
+    config: ScreeningConfig,
+    clients: BTreeMap<u16, ClientActivity>,
+}
+
+impl Screening {
+    pub fn new(config: ScreeningConfig) -> Self {
+        Self {
+            config,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Records an applied transaction.
+    pub fn record(&mut self, tx: &Transaction) {
+        let config = &self.config;
+        let activity = self.clients.entry(tx.client_id).or_default();
+        activity.transactions += 1;
+        let now = activity.transactions;
+        while activity
+            .recent_deposits
+            .front()
+            .is_some_and(|(at, _)| now - at > config.cycle_window)
+        {
+            activity.recent_deposits.pop_front();
+        }
+        match &tx.info {
+            TransactionInfo::Deposit(amount) => {
+                activity.deposits += 1;
+                if *amount < config.threshold && *amount >= &config.threshold - &config.margin {
+                    activity.near_threshold += 1;
+                }
+                activity.recent_deposits.push_back((now, amount.clone()));
+            }
+            TransactionInfo::Withdrawal(amount) => {
+                let cycled = activity.recent_deposits.iter().position(|(_, deposit)| {
+                    deposit
+                        .checked_mul(&config.cycle_fraction)
+                        .is_some_and(|least| *amount >= least)
+                });
+                if let Some(idx) = cycled {
+                    activity.recent_deposits.remove(idx);
+                    activity.cycles += 1;
+                }
+            }
+            TransactionInfo::Dispute(_) => activity.disputes += 1,
+            TransactionInfo::Resolve
+            | TransactionInfo::Chargeback
+            | TransactionInfo::Unlock(_)
+            | TransactionInfo::Convert(_)
+            | TransactionInfo::Reversal => {}
+        }
+    }
+
+    /// The clients flagged so far, ordered by client then flag.
+    pub fn flags(&self) -> Vec<Flag> {
+        let config = &self.config;
+        let mut flags = vec![];
+        for (client, activity) in &self.clients {
+            if activity.near_threshold >= config.min_near_threshold {
+                flags.push(Flag {
+                    client: *client,
+                    flag: FlagKind::Structuring,
+                    count: activity.near_threshold,
+                    detail: format!(
+                        "{} of {} deposits within {} under {}",
+                        activity.near_threshold, activity.deposits, config.margin, config.threshold
+                    ),
+                });
+            }
+            if activity.cycles >= config.min_cycles {
+                flags.push(Flag {
+                    client: *client,
+                    flag: FlagKind::RapidCycling,
+                    count: activity.cycles,
+                    detail: format!(
+                        "{} deposits withdrawn within {} transactions",
+                        activity.cycles, config.cycle_window
+                    ),
+                });
+            }
+            // Withdrawals can be disputed too, so a client may have
+            // disputes without deposits, which aren't a rate to flag.
+            if activity.disputes >= config.min_disputes
+                && activity.deposits > 0
+                && activity.disputes as f64 >= activity.deposits as f64 * config.max_dispute_rate
+            {
+                flags.push(Flag {
+                    client: *client,
+                    flag: FlagKind::DisputeRate,
+                    count: activity.disputes,
+                    detail: format!(
+                        "{} disputes for {} deposits",
+                        activity.disputes, activity.deposits
+                    ),
+                });
+            }
+        }
+        flags
+    }
+
+    /// Writes the flags report as CSV.
+    pub fn write_report<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        // Write the header explicitly, as there may be no flags.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        csv_writer.write_record(["client", "flag", "count", "detail"])?;
+        for flag in self.flags() {
+            csv_writer.serialize(flag)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    fn tx(client_id: u16, info: TransactionInfo) -> Transaction {
+        Transaction {
+            client_id,
+            transaction_id: 1,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn structuring_flagged() {
+        let mut screening = Screening::new(ScreeningConfig::default());
+        for amount in [money!(9500), money!(9999.99), money!(10000), money!(8999)] {
+            screening.record(&tx(1, TransactionInfo::Deposit(amount)));
+        }
+        assert!(screening.flags().is_empty());
+        screening.record(&tx(1, TransactionInfo::Deposit(money!(9000))));
+        assert_eq!(
+            screening.flags(),
+            vec![Flag {
+                client: 1,
+                flag: FlagKind::Structuring,
+                count: 3,
+                detail: "3 of 5 deposits within 1000 under 10000".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rapid_cycling_flagged() {
+        let mut screening = Screening::new(ScreeningConfig::default());
+        for _ in 0..2 {
+            screening.record(&tx(2, TransactionInfo::Deposit(money!(100))));
+            screening.record(&tx(2, TransactionInfo::Withdrawal(money!(95))));
+        }
+        // Exactly the fraction, which f64 would make slightly more.
+        screening.record(&tx(2, TransactionInfo::Deposit(money!(0.1))));
+        screening.record(&tx(2, TransactionInfo::Withdrawal(money!(0.09))));
+        // Too small a withdrawal, and too late.
+        screening.record(&tx(3, TransactionInfo::Deposit(money!(100))));
+        screening.record(&tx(3, TransactionInfo::Withdrawal(money!(50))));
+        screening.record(&tx(3, TransactionInfo::Withdrawal(money!(10))));
+        screening.record(&tx(3, TransactionInfo::Withdrawal(money!(10))));
+        screening.record(&tx(3, TransactionInfo::Withdrawal(money!(100))));
+        let flags = screening.flags();
+        assert_eq!(flags.len(), 1);
+        assert_eq!(
+            (flags[0].client, flags[0].flag),
+            (2, FlagKind::RapidCycling)
+        );
+        assert_eq!(flags[0].count, 3);
+    }
+
+    #[test]
+    fn dispute_rate_flagged() {
+        let mut screening = Screening::new(ScreeningConfig::default());
+        for client in [4, 5] {
+            for _ in 0..4 {
+                screening.record(&tx(client, TransactionInfo::Deposit(money!(1))));
+            }
+        }
+        for _ in 0..2 {
+            screening.record(&tx(4, TransactionInfo::Dispute(None)));
+        }
+        for _ in 0..3 {
+            screening.record(&tx(5, TransactionInfo::Dispute(None)));
+        }
+        // Only withdrawals disputed, so no rate of deposits disputed.
+        screening.record(&tx(6, TransactionInfo::Withdrawal(money!(1))));
+        for _ in 0..3 {
+            screening.record(&tx(6, TransactionInfo::Dispute(None)));
+        }
+        let mut output = vec![];
+        screening.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,flag,count,detail\n\
+             5,dispute_rate,3,3 disputes for 4 deposits\n"
+        );
+    }
+}
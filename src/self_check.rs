@@ -0,0 +1,428 @@
+//! Checks run on startup, before any input is processed or requests are
+//! served, so a misconfiguration fails fast with an actionable message
+//! rather than partway through a run.
+//!
+//! Each check names what it checked (the config file, a snapshot to be
+//! restored, an output to be written, ...) and either passes, with a short
+//! description of what it found, or fails saying what's wrong and how to
+//! fix it. The [`SelfCheck`] report lists them all, so every problem is
+//! reported at once rather than one per attempt.
+//!
+//! Outputs are checked by writing, syncing and removing a probe file of
+//! [`PROBE_BYTES`] next to them, which fails if the directory is missing,
+//! isn't writable or its disk is (nearly) full. The standard library can't
+//! query free space directly.
+
+use crate::account_store::AccountStore;
+use crate::checkpoint;
+use crate::encryption::{self, Key};
+use crate::snapshot;
+use crate::transaction_engine::EngineConfig;
+use crate::RunOptions;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Size of the probe written next to each output.
+pub const PROBE_BYTES: usize = 1 << 20;
+
+/// The outcome of each check, in the order they were run.
+#[derive(Debug, Default)]
+pub struct SelfCheck {
+    checks: Vec<Check>,
+}
+
+#[derive(Debug)]
+struct Check {
+    name: String,
+    /// What was found if the check passed, or what's wrong if it failed.
+    result: Result<String, String>,
+}
+
+impl SelfCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs a check, recording its outcome.
+    pub fn check(
+        &mut self,
+        name: impl Into<String>,
+        check: impl FnOnce() -> Result<String, String>,
+    ) {
+        self.checks.push(Check {
+            name: name.into(),
+            result: check(),
+        });
+    }
+
+    /// Whether every check passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.result.is_ok())
+    }
+
+    /// Fails, listing the failed checks, unless every check passed.
+    pub fn into_result(self) -> Result<(), Box<dyn Error>> {
+        if self.passed() {
+            return Ok(());
+        }
+        let failures: Vec<String> = self
+            .checks
+            .into_iter()
+            .filter_map(|check| {
+                let error = check.result.err()?;
+                Some(format!("{}: {}", check.name, error))
+            })
+            .collect();
+        Err(format!("Self-check failed:\n  {}", failures.join("\n  ")).into())
+    }
+
+    /// Checks the inputs and outputs of a run of `input` with `options`.
+    /// Everything given is checked; the store is in memory, so always
+    /// available.
+    pub fn for_run(input: &Path, options: &RunOptions) -> Self {
+        let mut self_check = Self::new();
+        self_check.check("store", || Ok("in memory".to_owned()));
+        self_check.input(input);
+        for path in &options.extra_inputs {
+            self_check.input(path);
+        }
+        let optional_inputs = [
+            options.opening_balances.as_deref(),
+            options.notes.as_deref(),
+            options
+                .bulk_disputes
+                .as_ref()
+                .map(|bulk| bulk.input.as_path()),
+        ];
+        for path in optional_inputs.into_iter().flatten() {
+            self_check.input(path);
+        }
+        let key = match &options.encryption {
+            Some(provider) => match provider.key() {
+                Ok(key) => Some(key),
+                Err(err) => {
+                    self_check.check("encryption key", || Err(err.to_string()));
+                    None
+                }
+            },
+            None => None,
+        };
+        if let Some(delta) = &options.delta {
+            self_check.snapshot(&delta.previous, key.as_ref());
+        }
+        if let Some(path) = &options.resume {
+            self_check.checkpoint(path);
+        }
+        let outputs = [
+            options.html_report.as_deref(),
+            options.metrics.as_deref(),
+            options.events.as_deref(),
+            options
+                .manifest
+                .as_ref()
+                .map(|manifest| manifest.path.as_path()),
+            options.dormancy_report.as_deref(),
+            options.trial_balance.as_deref(),
+            options.system_statement.as_deref(),
+            options
+                .bulk_disputes
+                .as_ref()
+                .map(|bulk| bulk.results.as_path()),
+            options
+                .business_dates
+                .as_ref()
+                .and_then(|dates| dates.movements.as_deref()),
+            options
+                .screening
+                .as_ref()
+                .map(|screening| screening.path.as_path()),
+            options.profile_report.as_deref(),
+            options.snapshot.as_deref(),
+            options
+                .pseudonymize
+                .as_ref()
+                .map(|pseudonymize| pseudonymize.mapping.as_path()),
+            options.quarantine_report.as_deref(),
+            options
+                .delta
+                .as_ref()
+                .and_then(|delta| delta.tombstones.as_deref()),
+            options
+                .checkpoint
+                .as_ref()
+                .map(|checkpoint| checkpoint.path.as_path()),
+        ];
+        for path in outputs.into_iter().flatten() {
+            self_check.output(path);
+        }
+        let output_dirs = [
+            options.export.as_ref().map(|export| export.dir.as_path()),
+            options
+                .business_dates
+                .as_ref()
+                .and_then(|dates| dates.statements_dir.as_deref()),
+            options.repro_dir.as_deref(),
+        ];
+        for dir in output_dirs.into_iter().flatten() {
+            self_check.output_dir(dir);
+        }
+        self_check
+    }
+
+    /// Checks the store can be read, with `read` reading a page of its
+    /// statements and returning how many it read.
+    pub fn store<E: fmt::Display>(&mut self, read: impl FnOnce() -> Result<usize, E>) {
+        self.check("store", || {
+            let statements =
+                read().map_err(|err| format!("can't be read ({}); check it's reachable", err))?;
+            Ok(format!(
+                "readable, {} statements on the first page",
+                statements
+            ))
+        });
+    }
+
+    /// Checks `path` can be read.
+    pub fn input(&mut self, path: &Path) {
+        self.check(format!("input {}", path.display()), || {
+            let metadata = File::open(path)
+                .and_then(|file| file.metadata())
+                .map_err(|err| format!("can't be read ({}); check the path", err))?;
+            if metadata.is_dir() {
+                return Err("is a directory; give a file".to_owned());
+            }
+            Ok(format!("{} bytes", metadata.len()))
+        });
+    }
+
+    /// Checks `path` can be written, with room on its disk.
+    pub fn output(&mut self, path: &Path) {
+        self.check(format!("output {}", path.display()), || {
+            if path.is_dir() {
+                return Err("is a directory; give a file".to_owned());
+            }
+            let dir = match path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            probe(dir)
+        });
+    }
+
+    /// Checks files can be written in the existing directory `dir`, with
+    /// room on its disk.
+    pub fn output_dir(&mut self, dir: &Path) {
+        self.check(format!("output directory {}", dir.display()), || probe(dir));
+    }
+
+    /// Checks the snapshot at `path` is intact, decrypting it with `key` if
+    /// one is given.
+    pub fn snapshot(&mut self, path: &Path, key: Option<&Key>) {
+        self.check(format!("snapshot {}", path.display()), || {
+            let engine = encryption::read_file(path, key)
+                .and_then(|bytes| snapshot::read_snapshot(bytes.as_slice()))
+                .map_err(|err| format!("can't be restored ({}); use an earlier snapshot", err))?;
+            Ok(format!(
+                "sequence {}, {} accounts",
+                engine.last_sequence(),
+                engine.store().accounts().count()
+            ))
+        });
+    }
+
+    /// Checks the checkpoint at `path` is intact.
+    pub fn checkpoint(&mut self, path: &Path) {
+        self.check(format!("checkpoint {}", path.display()), || {
+            let checkpoint = checkpoint::read_checkpoint(path).map_err(|err| {
+                format!("can't be resumed from ({}); rerun without --resume", err)
+            })?;
+            Ok(format!("{} rows in", checkpoint.position.rows))
+        });
+    }
+
+    /// Checks the engine config file at `path`, returning the config if it's
+    /// valid.
+    pub fn config(&mut self, path: &Path) -> Option<EngineConfig> {
+        let mut config = None;
+        self.check(format!("config {}", path.display()), || {
+            config = Some(read_config(path)?);
+            Ok("valid".to_owned())
+        });
+        config
+    }
+}
+
+impl fmt::Display for SelfCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.result {
+                Ok(found) => writeln!(f, "ok      {}: {}", check.name, found)?,
+                Err(error) => writeln!(f, "FAILED  {}: {}", check.name, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes, syncs and removes a probe file in `dir`.
+fn probe(dir: &Path) -> Result<String, String> {
+    if !dir.is_dir() {
+        return Err(format!("{} doesn't exist; create it first", dir.display()));
+    }
+    let path = dir.join(format!(
+        ".payments-engine-self-check-{}",
+        std::process::id()
+    ));
+    let written = File::create(&path).and_then(|mut file| {
+        file.write_all(&vec![0; PROBE_BYTES])?;
+        file.sync_all()
+    });
+    // Removed even if writing failed part way.
+    let _ = std::fs::remove_file(&path);
+    written.map_err(|err| {
+        format!(
+            "can't write to {} ({}); check its permissions and free space",
+            dir.display(),
+            err
+        )
+    })?;
+    Ok("writable".to_owned())
+}
+
+/// Reads an engine config file, with the line and column of any error.
+/// Unknown fields are errors, rather than silently ignored as when
+/// deserializing, as they're most likely misspelt policies.
+pub fn read_config(path: &Path) -> Result<EngineConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let located = |err: serde_json::Error| {
+        // The message without serde_json's own " at line .. column ..".
+        let message = err.to_string();
+        let message = match message.rsplit_once(" at line ") {
+            Some((message, _)) => message.to_owned(),
+            None => message,
+        };
+        format!("line {} column {}: {}", err.line(), err.column(), message)
+    };
+    let value: Value = serde_json::from_str(&text).map_err(located)?;
+    let defaults = serde_json::to_value(EngineConfig::default()).expect("Configs always serialize");
+    if let Some((field, key)) = unknown_field(&value, &defaults) {
+        let (line, column) = position_of_key(&text, key);
+        return Err(format!(
+            "line {} column {}: unknown field {:?}",
+            line, column, field
+        ));
+    }
+    serde_json::from_str(&text).map_err(located)
+}
+
+/// The first field of `value` not in `known`, as a dotted path, and its
+/// key. Only objects known to be structs (the defaults are objects with
+/// fields) are checked, not maps keyed by, e.g., destinations.
+fn unknown_field<'a>(value: &'a Value, known: &Value) -> Option<(String, &'a str)> {
+    let (Value::Object(fields), Value::Object(known)) = (value, known) else {
+        return None;
+    };
+    if known.is_empty() {
+        return None;
+    }
+    for (key, value) in fields {
+        let Some(known) = known.get(key) else {
+            return Some((key.clone(), key));
+        };
+        if let Some((field, inner)) = unknown_field(value, known) {
+            return Some((format!("{}.{}", key, field), inner));
+        }
+    }
+    None
+}
+
+/// The line and column (both from 1) of the first use of `key` as a key in
+/// the JSON `text`.
+fn position_of_key(text: &str, key: &str) -> (usize, usize) {
+    let quoted = format!("{:?}", key);
+    let offset = text
+        .match_indices(&quoted)
+        .find(|(at, _)| text[at + quoted.len()..].trim_start().starts_with(':'))
+        .map_or(0, |(at, _)| at);
+    let before = &text[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_every_check() {
+        let dir = std::env::temp_dir().join(format!("self_check_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("engine.json");
+        std::fs::write(
+            &config,
+            "{\n  \"hold_policy\": \"capped\",\n  \"destinations\": {\n    \"limitz\": \"5\"\n  }\n}",
+        )
+        .unwrap();
+
+        let mut self_check = SelfCheck::new();
+        assert!(self_check.config(&config).is_none());
+        self_check.input(&config);
+        self_check.output(&dir.join("report.csv"));
+        self_check.output(&dir.join("missing").join("report.csv"));
+        self_check.snapshot(&config, None);
+        assert!(!self_check.passed());
+        let report = self_check.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with(r#"line 4 column 5: unknown field "destinations.limitz""#));
+        assert!(lines[1].starts_with("ok      input"));
+        assert!(lines[2].ends_with("report.csv: writable"));
+        assert!(lines[3].contains("doesn't exist; create it first"));
+        assert!(lines[4].starts_with("FAILED  snapshot"));
+        let err = self_check.into_result().unwrap_err().to_string();
+        assert_eq!(err.lines().count(), 4);
+
+        std::fs::write(&config, "{\n  \"hold_policy\": \"held\"\n}").unwrap();
+        let err = read_config(&config).unwrap_err();
+        assert!(
+            err.starts_with("line 2 column 23: unknown variant `held`"),
+            "{}",
+            err
+        );
+        std::fs::write(
+            &config,
+            r#"{"max_held": "50", "destinations": {"limits": {"x": "1"}}}"#,
+        )
+        .unwrap();
+        let mut self_check = SelfCheck::new();
+        let config = self_check.config(&config).unwrap();
+        assert_eq!(config.destinations.limits.len(), 1);
+        assert!(self_check.into_result().is_ok());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn store_unreadable() {
+        let mut self_check = SelfCheck::new();
+        self_check.store(|| Ok::<_, String>(1));
+        assert!(self_check.passed());
+        self_check.store(|| Err("connection refused"));
+        assert!(!self_check.passed());
+        let report = self_check.to_string();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "ok      store: readable, 1 statements on the first page",
+                "FAILED  store: can't be read (connection refused); check it's reachable",
+            ]
+        );
+        let err = self_check.into_result().unwrap_err().to_string();
+        assert!(err.ends_with("store: can't be read (connection refused); check it's reachable"));
+    }
+}
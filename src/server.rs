@@ -0,0 +1,1711 @@
+//! The engine as a service: handlers for submitting transactions and
+//! reading accounts, independent of transport, over a [`SharedTxEngine`].
+//!
+//! With the `grpc` feature, [`grpc::serve`] exposes them as the `Payments`
+//! gRPC service defined in `proto/payments.proto`:
+//!
+//! * `SubmitTransaction` applies a transaction, optionally under an
+//!   idempotency key (see [`SharedTxEngine::handle_keyed`]).
+//! * `GetAccount` returns a client's statements, one per currency.
+//! * `ListStatements` returns a page of statements, filtered and ordered as
+//!   requested, with the cursor for the next page (see
+//!   [`PaymentsService::list_statements`]).
+//!
+//! A transaction that isn't applied fails with its [`Rejection`]'s gRPC code
+//! and an `ErrorInfo` detail (see [`Rejection::error_info`]). Outcomes that
+//! aren't errors, such as a transaction queued for a quarantined client,
+//! succeed with their code in the response.
+//!
+//! With the `http` feature, [`http::serve`] exposes them as a JSON API:
+//!
+//! * `POST /transactions` applies a transaction, written as in JSON Lines
+//!   input, optionally under the key in an `Idempotency-Key` header.
+//! * `GET /accounts/{client}` returns a client's statements, one per
+//!   currency, as a JSON array.
+//! * `GET /accounts?after=&limit=&client=&locked=&disputed=&order=` returns
+//!   a page of statements, as `{"statements": [...], "next": <client>}`.
+//!
+//! Failures respond with the [`Rejection`]'s HTTP status and body.
+//!
+//! * `GET /commitment` returns the latest commitment to every account's
+//!   balance (see [`PaymentsService::commit_state`]).
+//! * `GET /accounts/{client}/proof` returns a proof of the client's balance
+//!   against it.
+//! * `GET /subscribe?clients=1,2` upgrades to a WebSocket streaming the
+//!   clients' ledger events, as JSON text messages, with the bearer token
+//!   as the principal (see [`PaymentsService::with_subscriptions`]).
+//!
+//! With [`PaymentsService::with_receipts`], responses to applied
+//! transactions carry a signed [`Receipt`], in JSON.
+//!
+//! Operators manage the service through an admin API, which
+//! [`http::serve_admin`] serves on an address of its own so it needn't be
+//! reachable by clients (see [`crate::admin`] for a client):
+//!
+//! * `POST /admin/accounts/{client}/lock` locks an account, with the scope
+//!   in the body as `{"scope": "block-all"}`.
+//! * `POST /admin/accounts/{client}/unlock` unlocks it, with the operator in
+//!   the body as `{"operator": "..."}`.
+//! * `GET /admin/accounts/{client}` returns a client's statements.
+//! * `POST /admin/snapshot` writes a snapshot of the engine (see
+//!   [`PaymentsService::with_snapshots`]).
+//! * `POST /admin/dlq/flush` returns and clears the dead letter queue (see
+//!   [`PaymentsService::flush_dead_letters`]).
+//! * `POST /admin/config/reload` re-reads the engine config (see
+//!   [`PaymentsService::with_config_file`]).
+//!
+//! With [`PaymentsService::with_subscriptions`], callers can follow the
+//! ledger events of the clients they're authorized for, through
+//! [`PaymentsService::subscribe`] or the HTTP server's `/subscribe` stream.
+
+use crate::account::{Account, AccountStatement, LockScope, StatementOrder};
+use crate::account_store::AccountStore;
+use crate::encryption::{Key, KeyProvider, OutputFile};
+use crate::inspect::{self, InspectQuery, Page};
+use crate::merkle::{BalanceProof, Commitment, StateCommitment};
+use crate::queue::QueueStats;
+use crate::receipt::{Receipt, ReceiptLog, ReceiptSigner};
+use crate::rejection::{ErrorInfo, Rejection, ERROR_DOMAIN};
+use crate::shared_engine::{Ack, Retained, SharedTxEngine};
+use crate::snapshot::{write_snapshot, Compression};
+use crate::subscriptions::{Authorizer, SubscribeError, Subscription, Subscriptions};
+use crate::transaction::{Transaction, TransactionInfo, TransactionRaw};
+use crate::transaction_engine::{EngineConfig, TransactionNotApplied};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Code of a successfully applied transaction.
+pub const APPLIED: &str = "Applied";
+
+/// A transaction submitted to the service, with the same fields as in JSON
+/// Lines input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubmitRequest {
+    pub transaction_type: String,
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Option<String>,
+    pub reason: Option<String>,
+    pub destination: Option<String>,
+    /// When the transaction happened, as seconds since the Unix epoch or
+    /// RFC 3339.
+    pub timestamp: Option<String>,
+    /// Currency of the amount; the account's primary currency if not given.
+    pub currency: Option<String>,
+    /// Currency converted into, for conversions.
+    pub to_currency: Option<String>,
+    /// Rate to convert at, for conversions.
+    pub rate: Option<String>,
+    /// Key chosen by the client, so the submission can be safely retried.
+    pub idempotency_key: Option<String>,
+}
+
+/// Which statements to list, a page at a time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListRequest {
+    pub query: InspectQuery,
+    pub order: StatementOrder,
+    /// The previous page's `next`, or `None` for the first page.
+    pub after: Option<u16>,
+    /// Most accounts on the page, capped at [`inspect::MAX_PAGE_SIZE`].
+    pub limit: usize,
+}
+
+impl Default for ListRequest {
+    fn default() -> Self {
+        Self {
+            query: InspectQuery::default(),
+            order: StatementOrder::Client,
+            after: None,
+            limit: inspect::MAX_PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubmitResponse {
+    /// HTTP status to respond with. Not part of the body.
+    #[serde(skip)]
+    pub http_status: u16,
+    /// [`APPLIED`], or the code of an outcome that isn't an error (e.g.
+    /// `Quarantined`).
+    pub code: &'static str,
+    /// Sequence number the transaction was applied with.
+    pub sequence: Option<u64>,
+    /// Whether this was a retry of a submission already handled under the
+    /// same idempotency key, so nothing changed.
+    pub replayed: bool,
+    /// The transaction's receipt, if it was applied and the service issues
+    /// them. Retries return the original receipt.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<Receipt>,
+}
+
+/// A failed call, as a gRPC status or an HTTP response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    /// gRPC status code.
+    pub code: u8,
+    pub message: String,
+    pub details: Option<ErrorInfo>,
+    pub http_status: u16,
+    /// HTTP response body, as JSON with the same `code` as the details'
+    /// reason.
+    pub body: String,
+}
+
+impl Status {
+    fn new(
+        (code, http_status): (u8, u16),
+        reason: &'static str,
+        message: String,
+        client: u16,
+    ) -> Self {
+        let body = serde_json::json!({"code": reason, "message": message, "client": client});
+        Self {
+            code,
+            message,
+            details: Some(ErrorInfo {
+                reason,
+                domain: ERROR_DOMAIN,
+                metadata: BTreeMap::from([("client".to_owned(), client.to_string())]),
+            }),
+            http_status,
+            body: body.to_string(),
+        }
+    }
+}
+
+impl Status {
+    /// A failed call not about any one client, e.g. an admin call.
+    fn unscoped((code, http_status): (u8, u16), reason: &'static str, message: String) -> Self {
+        let body = serde_json::json!({"code": reason, "message": message});
+        Self {
+            code,
+            message,
+            details: Some(ErrorInfo {
+                reason,
+                domain: ERROR_DOMAIN,
+                metadata: BTreeMap::new(),
+            }),
+            http_status,
+            body: body.to_string(),
+        }
+    }
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        Self {
+            code: rejection.grpc_code,
+            details: Some(rejection.error_info()),
+            http_status: rejection.http_status,
+            body: rejection.body(),
+            message: rejection.message,
+        }
+    }
+}
+
+pub struct PaymentsService {
+    engine: SharedTxEngine,
+    receipts: Option<Receipts>,
+    /// The latest commitment to account balances, if one's been made.
+    commitment: Mutex<Option<Arc<StateCommitment>>>,
+    /// Where snapshots are written when an operator asks for one.
+    snapshot_path: Option<PathBuf>,
+    /// Encrypts snapshots and the receipt log, if set.
+    encryption: Option<Key>,
+    /// The engine config file, re-read when an operator asks.
+    config_path: Option<PathBuf>,
+    dead_letters: Mutex<Vec<DeadLetter>>,
+    subscriptions: Option<Subscriptions>,
+    ingestion: Ingestion,
+}
+
+/// Submissions being handled, shed beyond a limit if one's set.
+#[derive(Default)]
+struct Ingestion {
+    limit: Option<usize>,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    accepted: AtomicU64,
+    shed: AtomicU64,
+}
+
+/// Counts a submission in flight until dropped.
+struct InFlight<'a>(&'a AtomicUsize);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A submission that failed (see [`TransactionNotApplied::is_failure`]),
+/// kept for operators to investigate.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DeadLetter {
+    pub client: u16,
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub transaction_type: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// A snapshot written at an operator's request.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SnapshotWritten {
+    pub path: PathBuf,
+    /// Sequence number of the last transaction in the snapshot.
+    pub sequence: u64,
+    pub accounts: usize,
+}
+
+struct Receipts {
+    signer: ReceiptSigner,
+    log: Option<ReceiptLog>,
+    /// Receipts for submissions with an idempotency key, by sequence
+    /// number, for retries. Kept as long as the engine keeps their results.
+    keyed: Mutex<Retained<u64, Receipt>>,
+}
+
+impl PaymentsService {
+    pub fn new(engine: SharedTxEngine) -> Self {
+        Self {
+            engine,
+            receipts: None,
+            commitment: Mutex::default(),
+            snapshot_path: None,
+            encryption: None,
+            config_path: None,
+            dead_letters: Mutex::default(),
+            subscriptions: None,
+            ingestion: Ingestion::default(),
+        }
+    }
+
+    /// Handles at most `limit` submissions at once, refusing further ones
+    /// with `Overloaded` (`UNAVAILABLE`, 503) for their clients to retry,
+    /// rather than letting them wait on the engine without limit.
+    pub fn with_max_in_flight(mut self, limit: usize) -> Self {
+        self.ingestion.limit = Some(limit.max(1));
+        self
+    }
+
+    /// Counts for the submissions handled, as for a queue: submissions in
+    /// flight are its depth, and those refused for being over the limit
+    /// (see [`PaymentsService::with_max_in_flight`]) were shed.
+    pub fn ingestion_stats(&self) -> QueueStats {
+        QueueStats {
+            depth: self.ingestion.in_flight.load(Ordering::SeqCst),
+            max_depth: self.ingestion.max_in_flight.load(Ordering::Relaxed),
+            pushed: self.ingestion.accepted.load(Ordering::Relaxed),
+            shed: self.ingestion.shed.load(Ordering::Relaxed),
+            spilled: 0,
+        }
+    }
+
+    /// Counts a submission in flight, unless that takes it over the limit.
+    fn admit(&self, client: u16) -> Result<InFlight<'_>, Status> {
+        let ingestion = &self.ingestion;
+        let in_flight = ingestion.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        let guard = InFlight(&ingestion.in_flight);
+        if let Some(limit) = ingestion.limit.filter(|&limit| in_flight > limit) {
+            ingestion.shed.fetch_add(1, Ordering::Relaxed);
+            return Err(Status::new(
+                // UNAVAILABLE, Service Unavailable
+                (14, 503),
+                "Overloaded",
+                format!("Over {} submissions in flight, retry later", limit),
+                client,
+            ));
+        }
+        ingestion.accepted.fetch_add(1, Ordering::Relaxed);
+        ingestion
+            .max_in_flight
+            .fetch_max(in_flight, Ordering::Relaxed);
+        Ok(guard)
+    }
+
+    /// Writes snapshots to `path` when an operator asks for one, replacing
+    /// the previous snapshot.
+    pub fn with_snapshots(mut self, path: PathBuf) -> Self {
+        self.snapshot_path = Some(path);
+        self
+    }
+
+    /// Encrypts the snapshots and receipt log written, which hold account
+    /// histories, with the key from `provider` (see [`crate::encryption`]).
+    /// The key is taken up front, so a missing key fails before serving
+    /// rather than at the first write.
+    pub fn with_encryption(mut self, provider: &dyn KeyProvider) -> Result<Self, Box<dyn Error>> {
+        self.encryption = Some(provider.key()?);
+        Ok(self)
+    }
+
+    /// Re-reads the engine config from `path` when an operator asks, so
+    /// policies can be changed without a restart.
+    pub fn with_config_file(mut self, path: PathBuf) -> Self {
+        self.config_path = Some(path);
+        self
+    }
+
+    /// Issues a receipt signed by `signer` for each applied transaction,
+    /// appending it to `log` if given before responding. If it can't be
+    /// appended the call fails, though the transaction was applied.
+    pub fn with_receipts(mut self, signer: ReceiptSigner, log: Option<ReceiptLog>) -> Self {
+        self.receipts = Some(Receipts {
+            signer,
+            log,
+            keyed: Mutex::new(Retained::new(self.engine.ack_retention())),
+        });
+        self
+    }
+
+    /// Lets callers subscribe to the ledger events of the clients
+    /// `authorizer` allows them to follow (see [`crate::subscriptions`]).
+    pub fn with_subscriptions(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        let subscriptions = Subscriptions::new(authorizer);
+        self.engine.add_event_sinks(|| subscriptions.sink());
+        self.subscriptions = Some(subscriptions);
+        self
+    }
+
+    pub fn engine(&self) -> &SharedTxEngine {
+        &self.engine
+    }
+
+    pub fn submit_transaction(&self, request: SubmitRequest) -> Result<SubmitResponse, Status> {
+        let (client, tx) = (request.client, request.tx);
+        let _in_flight = self.admit(client)?;
+        if request.transaction_type == "unlock" {
+            // Operators unlock accounts through their own tools, not the
+            // service clients submit to.
+            return Err(Rejection::malformed(
+                tx,
+                Some(client),
+                "Unlocks aren't accepted by the service".to_owned(),
+            )
+            .into());
+        }
+        let transaction = Transaction::try_from(TransactionRaw {
+            transaction_type: request.transaction_type,
+            client,
+            tx,
+            amount: request.amount,
+            reason: request.reason,
+            date: None,
+            destination: request.destination,
+            operator: None,
+            timestamp: request.timestamp,
+            currency: request.currency,
+            to_currency: request.to_currency,
+            rate: request.rate,
+        })
+        .map_err(|(_, message)| Rejection::malformed(tx, Some(client), message))?;
+        let key = request.idempotency_key.as_deref();
+        let (ack, receipt) =
+            self.engine
+                .handle_inspecting(key, &transaction, |sequence, account: &Account| {
+                    self.receipts
+                        .as_ref()
+                        .map(|receipts| receipts.signer.issue(&transaction, sequence, account))
+                });
+        let (result, replayed) = match ack {
+            Ack::Handled(result) => (result, false),
+            Ack::Replayed(result) => (result, true),
+            Ack::KeyConflict => {
+                return Err(Status::new(
+                    // ALREADY_EXISTS, Conflict
+                    (6, 409),
+                    "IdempotencyKeyConflict",
+                    format!(
+                        "Key {:?} was already used for another transaction",
+                        key.unwrap_or_default()
+                    ),
+                    client,
+                ));
+            }
+        };
+        if let (Err(err), false) = (&result, replayed) {
+            if err.is_failure() {
+                self.dead_letters
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .push(DeadLetter {
+                        client,
+                        tx,
+                        transaction_type: transaction.info.kind(),
+                        code: err.name(),
+                        message: err.to_string(),
+                    });
+            }
+        }
+        let receipt = match (&self.receipts, receipt.flatten(), &result) {
+            (Some(receipts), Some(receipt), _) => {
+                receipts.record(&receipt, key.is_some(), self.encryption.as_ref(), client)?;
+                Some(receipt)
+            }
+            (Some(receipts), None, Ok(sequence)) if replayed => receipts
+                .keyed
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .get(sequence)
+                .cloned(),
+            _ => None,
+        };
+        respond(&transaction, result, replayed, receipt)
+    }
+
+    /// Subscribes `principal`, as established by the transport, to the
+    /// ledger events of `clients`. Fails unless it may follow every one.
+    pub fn subscribe(&self, principal: &str, clients: &[u16]) -> Result<Subscription, Status> {
+        let subscriptions = self.subscriptions.as_ref().ok_or_else(|| {
+            Status::unscoped(
+                // UNIMPLEMENTED, Not Implemented
+                (12, 501),
+                "SubscriptionsNotEnabled",
+                "The service doesn't offer event subscriptions".to_owned(),
+            )
+        })?;
+        subscriptions
+            .subscribe(principal, clients)
+            .map_err(|err| match err {
+                SubscribeError::NoClients => Status::unscoped(
+                    // INVALID_ARGUMENT, Bad Request
+                    (3, 400),
+                    "NoClients",
+                    err.to_string(),
+                ),
+                SubscribeError::Forbidden(client) => Status::new(
+                    // PERMISSION_DENIED, Forbidden
+                    (7, 403),
+                    "SubscriptionForbidden",
+                    err.to_string(),
+                    client,
+                ),
+            })
+    }
+
+    /// The client's statements, one per currency they've used, the
+    /// primary balance first.
+    pub fn get_account(&self, client: u16) -> Result<Vec<AccountStatement>, Status> {
+        let statements = self.engine.client_statements(client);
+        if statements.is_empty() {
+            return Err(account_not_found(client));
+        }
+        Ok(statements)
+    }
+
+    /// Locks a client's account (see [`SharedTxEngine::lock_account`]),
+    /// returning its statements.
+    pub fn lock_account(
+        &self,
+        client: u16,
+        scope: LockScope,
+    ) -> Result<Vec<AccountStatement>, Status> {
+        self.engine
+            .lock_account(client, scope)
+            .ok_or_else(|| account_not_found(client))?;
+        self.get_account(client)
+    }
+
+    /// Unlocks a client's account, as an
+    /// [`Unlock`](TransactionInfo::Unlock) by `operator` with transaction
+    /// ID zero. Unlocking an account that isn't locked succeeds with the
+    /// `AlreadyApplied` code.
+    pub fn unlock_account(&self, client: u16, operator: &str) -> Result<SubmitResponse, Status> {
+        if operator.trim().is_empty() {
+            return Err(
+                Rejection::malformed(0, Some(client), "Missing operator".to_owned()).into(),
+            );
+        }
+        self.get_account(client)?;
+        let transaction = Transaction {
+            client_id: client,
+            transaction_id: 0,
+            info: TransactionInfo::Unlock(operator.trim().to_owned()),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let result = self.engine.handle(&transaction);
+        respond(&transaction, result, false, None)
+    }
+
+    /// Writes a snapshot of the engine to the path given to
+    /// [`with_snapshots`](Self::with_snapshots), encrypted if the service
+    /// has a key. It's written alongside first, then moved into place, so a
+    /// failed write leaves the previous snapshot as it was.
+    pub fn write_snapshot(&self) -> Result<SnapshotWritten, Status> {
+        let path = self.snapshot_path.as_ref().ok_or_else(|| {
+            Status::unscoped(
+                // FAILED_PRECONDITION, Conflict
+                (9, 409),
+                "SnapshotsNotConfigured",
+                "The service wasn't given a snapshot path".to_owned(),
+            )
+        })?;
+        let engine = self.engine.snapshot();
+        let partial = path.with_extension("partial");
+        let written = OutputFile::create(&partial, self.encryption.as_ref())
+            .map_err(Into::into)
+            .and_then(|mut file| {
+                write_snapshot(&mut file, &engine, Compression::None)?;
+                file.finish()
+            })
+            .and_then(|()| Ok(std::fs::rename(&partial, path)?));
+        written.map_err(|err| {
+            Status::unscoped(
+                // INTERNAL, Internal Server Error
+                (13, 500),
+                "SnapshotFailed",
+                format!("Couldn't write snapshot to {}: {}", path.display(), err),
+            )
+        })?;
+        Ok(SnapshotWritten {
+            path: path.clone(),
+            sequence: engine.last_sequence(),
+            accounts: engine.store().accounts().count(),
+        })
+    }
+
+    /// Takes the submissions that failed since the last call, in the order
+    /// they were handled. Rejections that aren't failures (e.g. for
+    /// insufficient funds) aren't kept.
+    pub fn flush_dead_letters(&self) -> Vec<DeadLetter> {
+        std::mem::take(
+            &mut *self
+                .dead_letters
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+
+    /// Re-reads the engine config from the file given to
+    /// [`with_config_file`](Self::with_config_file), applying it to later
+    /// transactions, and returns it. An invalid file leaves the current
+    /// config in place.
+    pub fn reload_config(&self) -> Result<EngineConfig, Status> {
+        let path = self.config_path.as_ref().ok_or_else(|| {
+            Status::unscoped(
+                // FAILED_PRECONDITION, Conflict
+                (9, 409),
+                "ConfigNotConfigured",
+                "The service wasn't started with a config file".to_owned(),
+            )
+        })?;
+        let config = crate::self_check::read_config(path).map_err(|err| {
+            Status::unscoped(
+                // FAILED_PRECONDITION, Unprocessable Entity
+                (9, 422),
+                "ConfigInvalid",
+                format!("Couldn't read config from {}: {}", path.display(), err),
+            )
+        })?;
+        self.engine.set_config(config.clone());
+        Ok(config)
+    }
+
+    /// A page of the statements `request` selects (see
+    /// [`SharedTxEngine::statement_page`]), with the client to list the
+    /// next page after. Fails with `InvalidCursor` if the page's order
+    /// isn't by client and the cursor's client no longer matches.
+    pub fn list_statements(&self, request: &ListRequest) -> Result<Page, Status> {
+        self.engine
+            .statement_page(&request.query, request.order, request.after, request.limit)
+            .map_err(|message| {
+                Status::unscoped(
+                    // INVALID_ARGUMENT, Bad Request
+                    (3, 400),
+                    "InvalidCursor",
+                    message,
+                )
+            })
+    }
+
+    /// Commits to every account's current balance (see [`crate::merkle`]),
+    /// replacing the previous commitment for proofs, and returns it for
+    /// publishing. Typically called periodically.
+    ///
+    /// Shards are read one at a time, so balances are each as of some point
+    /// after the commitment's sequence number.
+    pub fn commit_state(&self) -> Commitment {
+        let sequence = self.engine.last_sequence();
+        let commitment = StateCommitment::new(&self.engine.statement_view(), sequence);
+        let summary = commitment.summary();
+        *self
+            .commitment
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(commitment));
+        summary
+    }
+
+    /// The latest commitment, if one's been made.
+    pub fn commitment(&self) -> Option<Commitment> {
+        self.latest_commitment()
+            .map(|commitment| commitment.summary())
+    }
+
+    /// Proof of the client's balance in the latest commitment.
+    pub fn balance_proof(&self, client: u16) -> Result<BalanceProof, Status> {
+        let commitment = self.latest_commitment().ok_or_else(|| {
+            Status::new(
+                // NOT_FOUND, Not Found
+                (5, 404),
+                "NoCommitment",
+                "No commitment has been made yet".to_owned(),
+                client,
+            )
+        })?;
+        commitment.proof(client).ok_or_else(|| {
+            Status::new(
+                // NOT_FOUND, Not Found
+                (5, 404),
+                "AccountNotFound",
+                format!("No account for client {} in the commitment", client),
+                client,
+            )
+        })
+    }
+
+    fn latest_commitment(&self) -> Option<Arc<StateCommitment>> {
+        self.commitment
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+impl Receipts {
+    /// Keeps a newly issued receipt for retries if `keyed`, and appends it
+    /// to the log, encrypted if there's a key.
+    fn record(
+        &self,
+        receipt: &Receipt,
+        keyed: bool,
+        encryption: Option<&Key>,
+        client: u16,
+    ) -> Result<(), Status> {
+        if keyed {
+            self.keyed
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .insert(receipt.body.sequence, receipt.clone());
+        }
+        let Some(log) = &self.log else {
+            return Ok(());
+        };
+        log.append(receipt, encryption).map_err(|err| {
+            Status::new(
+                // INTERNAL, Internal Server Error
+                (13, 500),
+                "ReceiptNotPersisted",
+                format!(
+                    "Transaction applied with sequence number {}, but its receipt wasn't \
+                     persisted: {}",
+                    receipt.body.sequence, err
+                ),
+                client,
+            )
+        })
+    }
+}
+
+fn account_not_found(client: u16) -> Status {
+    Status::new(
+        // NOT_FOUND, Not Found
+        (5, 404),
+        "AccountNotFound",
+        format!("No account for client {}", client),
+        client,
+    )
+}
+
+fn respond(
+    transaction: &Transaction,
+    result: Result<u64, TransactionNotApplied>,
+    replayed: bool,
+    receipt: Option<Receipt>,
+) -> Result<SubmitResponse, Status> {
+    match result {
+        Ok(sequence) => Ok(SubmitResponse {
+            http_status: 200,
+            code: APPLIED,
+            sequence: Some(sequence),
+            replayed,
+            receipt,
+        }),
+        Err(err) => {
+            let rejection = Rejection::new(transaction, &err);
+            // OK: not an error, e.g. a retry already applied.
+            if rejection.grpc_code == 0 {
+                return Ok(SubmitResponse {
+                    http_status: rejection.http_status,
+                    code: rejection.code,
+                    sequence: None,
+                    replayed,
+                    receipt: None,
+                });
+            }
+            Err(rejection.into())
+        }
+    }
+}
+
+#[cfg(feature = "grpc")]
+pub mod grpc {
+    //! gRPC server for a [`PaymentsService`].
+    use super::{ListRequest, PaymentsService, Status, SubmitRequest};
+    use crate::account::AccountStatement;
+    use crate::inspect::{self, InspectQuery};
+    use crate::rejection::Rejection;
+    use std::collections::HashMap;
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tonic::{Request, Response};
+    use tonic_types::{ErrorDetails, StatusExt};
+
+    /// Generated from `proto/payments.proto`.
+    pub mod proto {
+        tonic::include_proto!("payments.v1");
+    }
+
+    use proto::payments_server::{Payments, PaymentsServer};
+
+    impl From<Status> for tonic::Status {
+        fn from(status: Status) -> Self {
+            let code = tonic::Code::from_i32(i32::from(status.code));
+            match status.details {
+                Some(info) => tonic::Status::with_error_details(
+                    code,
+                    status.message,
+                    ErrorDetails::with_error_info(
+                        info.reason,
+                        info.domain,
+                        info.metadata.into_iter().collect::<HashMap<_, _>>(),
+                    ),
+                ),
+                None => tonic::Status::new(code, status.message),
+            }
+        }
+    }
+
+    fn account(statement: &AccountStatement) -> proto::Account {
+        proto::Account {
+            client: u32::from(statement.client()),
+            available: statement.available().to_string(),
+            held: statement.held().to_string(),
+            total: statement.total().to_string(),
+            locked: statement.locked(),
+            currency: statement.currency().map(ToString::to_string),
+        }
+    }
+
+    /// Client IDs are 16 bit, but protobuf has no 16 bit integers.
+    fn client(client: u32, tx: u32) -> Result<u16, tonic::Status> {
+        u16::try_from(client).map_err(|_| {
+            Status::from(Rejection::malformed(
+                tx,
+                None,
+                format!("Client {} out of range", client),
+            ))
+            .into()
+        })
+    }
+
+    struct GrpcPayments(Arc<PaymentsService>);
+
+    #[tonic::async_trait]
+    impl Payments for GrpcPayments {
+        async fn submit_transaction(
+            &self,
+            request: Request<proto::SubmitTransactionRequest>,
+        ) -> Result<Response<proto::SubmitTransactionResponse>, tonic::Status> {
+            let request = request.into_inner();
+            let response = self.0.submit_transaction(SubmitRequest {
+                transaction_type: request.r#type,
+                client: client(request.client, request.tx)?,
+                tx: request.tx,
+                amount: request.amount,
+                reason: request.reason,
+                destination: request.destination,
+                timestamp: request.timestamp,
+                currency: request.currency,
+                to_currency: request.to_currency,
+                rate: request.rate,
+                idempotency_key: request.idempotency_key,
+            })?;
+            Ok(Response::new(proto::SubmitTransactionResponse {
+                code: response.code.to_owned(),
+                sequence: response.sequence,
+                replayed: response.replayed,
+                receipt: response.receipt.map(|receipt| {
+                    serde_json::to_string(&receipt).expect("Receipts always serialize")
+                }),
+            }))
+        }
+
+        async fn get_account(
+            &self,
+            request: Request<proto::GetAccountRequest>,
+        ) -> Result<Response<proto::GetAccountResponse>, tonic::Status> {
+            let client = client(request.into_inner().client, 0)?;
+            let statements = self.0.get_account(client)?;
+            Ok(Response::new(proto::GetAccountResponse {
+                balances: statements.iter().map(account).collect(),
+            }))
+        }
+
+        async fn list_statements(
+            &self,
+            request: Request<proto::ListStatementsRequest>,
+        ) -> Result<Response<proto::ListStatementsResponse>, tonic::Status> {
+            let request = request.into_inner();
+            let order = match request.order.as_deref() {
+                Some(order) => order.parse().map_err(|message| {
+                    Status::unscoped(
+                        // INVALID_ARGUMENT, Bad Request
+                        (3, 400),
+                        "MalformedRequest",
+                        message,
+                    )
+                })?,
+                None => ListRequest::default().order,
+            };
+            let page = self.0.list_statements(&ListRequest {
+                query: InspectQuery {
+                    client: request
+                        .client
+                        .map(|client_id| client(client_id, 0))
+                        .transpose()?,
+                    locked: request.locked,
+                    disputed: request.disputed,
+                },
+                order,
+                after: request.after.map(|after| client(after, 0)).transpose()?,
+                limit: request.limit.map_or(inspect::MAX_PAGE_SIZE, |limit| {
+                    usize::try_from(limit).unwrap_or(usize::MAX)
+                }),
+            })?;
+            Ok(Response::new(proto::ListStatementsResponse {
+                accounts: page.statements.iter().map(account).collect(),
+                next: page.next.map(u32::from),
+            }))
+        }
+    }
+
+    /// Serves `service` on `addr` until the server fails. Must be run
+    /// within a Tokio runtime.
+    pub async fn serve(
+        service: Arc<PaymentsService>,
+        addr: SocketAddr,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        tonic::transport::Server::builder()
+            .add_service(PaymentsServer::new(GrpcPayments(service)))
+            .serve(addr)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "http")]
+pub mod http {
+    //! HTTP server for a [`PaymentsService`], with JSON bodies.
+    use super::{ListRequest, PaymentsService, Status, SubmitRequest};
+    use crate::account::{LockScope, StatementOrder};
+    use crate::input;
+    use crate::inspect::{self, InspectQuery};
+    use crate::ledger::LedgerEvent;
+    use crate::rejection::Rejection;
+    use crate::subscriptions::{NoEvent, Subscription};
+    use axum::body::Bytes;
+    use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+    use axum::extract::{Path, Query, State};
+    use axum::http::{header, HeaderMap, StatusCode};
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, post};
+    use axum::Router;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+    use std::error::Error;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+    /// How long an event stream waits for an event before pinging, to find
+    /// out whether the connection is still open.
+    const IDLE_PING: Duration = Duration::from_secs(15);
+
+    /// Events handed from a subscription to its connection at once. Beyond
+    /// this, the subscription's own queue fills (see
+    /// [`crate::subscriptions`]).
+    const STREAM_BUFFER: usize = 16;
+
+    /// WebSocket close code for "Try Again Later", sent to subscribers
+    /// dropped for lagging.
+    const TRY_AGAIN_LATER: u16 = 1013;
+
+    fn json(status: u16, body: String) -> Response {
+        let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        (status, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+    }
+
+    impl IntoResponse for Status {
+        fn into_response(self) -> Response {
+            json(self.http_status, self.body)
+        }
+    }
+
+    async fn submit_transaction(
+        State(service): State<Arc<PaymentsService>>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response, Status> {
+        // The transaction ID isn't known until the body is parsed.
+        let malformed = |message: String| Status::from(Rejection::malformed(0, None, message));
+        let raw = input::parse_json(&body).map_err(|err| malformed(err.to_string()))?;
+        let idempotency_key = match headers.get(IDEMPOTENCY_KEY) {
+            Some(key) => Some(
+                key.to_str()
+                    .map_err(|_| malformed("Idempotency key isn't text".to_owned()))?
+                    .to_owned(),
+            ),
+            None => None,
+        };
+        let response = service.submit_transaction(SubmitRequest {
+            transaction_type: raw.transaction_type,
+            client: raw.client,
+            tx: raw.tx,
+            amount: raw.amount,
+            reason: raw.reason,
+            destination: raw.destination,
+            timestamp: raw.timestamp,
+            currency: raw.currency,
+            to_currency: raw.to_currency,
+            rate: raw.rate,
+            idempotency_key,
+        })?;
+        let body = serde_json::to_string(&response).expect("Responses always serialize");
+        Ok(json(response.http_status, body))
+    }
+
+    async fn get_account(
+        State(service): State<Arc<PaymentsService>>,
+        Path(client): Path<u16>,
+    ) -> Result<Response, Status> {
+        let statements = service.get_account(client)?;
+        let body = serde_json::to_string(&statements).expect("Statements always serialize");
+        Ok(json(200, body))
+    }
+
+    #[derive(Deserialize)]
+    struct ListQuery {
+        after: Option<u16>,
+        limit: Option<usize>,
+        client: Option<u16>,
+        #[serde(default)]
+        locked: bool,
+        #[serde(default)]
+        disputed: bool,
+        order: Option<String>,
+    }
+
+    async fn list_statements(
+        State(service): State<Arc<PaymentsService>>,
+        Query(query): Query<ListQuery>,
+    ) -> Result<Response, Status> {
+        let order = match query.order.as_deref() {
+            Some(order) => order.parse().map_err(|message| {
+                Status::unscoped(
+                    // INVALID_ARGUMENT, Bad Request
+                    (3, 400),
+                    "MalformedRequest",
+                    message,
+                )
+            })?,
+            None => StatementOrder::Client,
+        };
+        let page = service.list_statements(&ListRequest {
+            query: InspectQuery {
+                client: query.client,
+                locked: query.locked,
+                disputed: query.disputed,
+            },
+            order,
+            after: query.after,
+            limit: query.limit.unwrap_or(inspect::MAX_PAGE_SIZE),
+        })?;
+        Ok(json(
+            200,
+            serde_json::to_string(&page).expect("Statements always serialize"),
+        ))
+    }
+
+    async fn get_commitment(State(service): State<Arc<PaymentsService>>) -> Response {
+        match service.commitment() {
+            Some(commitment) => json(
+                200,
+                serde_json::to_string(&commitment).expect("Commitments always serialize"),
+            ),
+            None => json(
+                404,
+                r#"{"code":"NoCommitment","message":"No commitment has been made yet"}"#.to_owned(),
+            ),
+        }
+    }
+
+    async fn get_proof(
+        State(service): State<Arc<PaymentsService>>,
+        Path(client): Path<u16>,
+    ) -> Result<Response, Status> {
+        let proof = service.balance_proof(client)?;
+        let body = serde_json::to_string(&proof).expect("Proofs always serialize");
+        Ok(json(200, body))
+    }
+
+    #[derive(Deserialize)]
+    struct SubscribeQuery {
+        /// Client IDs, comma separated.
+        clients: Option<String>,
+    }
+
+    /// What a subscription's thread hands to its connection.
+    enum Forwarded {
+        Event(LedgerEvent),
+        /// No event within [`IDLE_PING`].
+        Idle,
+        Lagged,
+    }
+
+    async fn subscribe(
+        State(service): State<Arc<PaymentsService>>,
+        headers: HeaderMap,
+        Query(query): Query<SubscribeQuery>,
+        upgrade: WebSocketUpgrade,
+    ) -> Result<Response, Status> {
+        let clients = query.clients.unwrap_or_default();
+        let clients = clients
+            .split(',')
+            .filter(|client| !client.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<u16>, _>>()
+            .map_err(|_| {
+                Status::unscoped(
+                    // INVALID_ARGUMENT, Bad Request
+                    (3, 400),
+                    "MalformedRequest",
+                    format!("{:?} isn't a list of client IDs", clients),
+                )
+            })?;
+        // The bearer token is the principal, for the service's authorizer to
+        // verify.
+        let principal = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        let subscription = service.subscribe(principal, &clients)?;
+        Ok(upgrade.on_upgrade(move |socket| stream_events(socket, subscription)))
+    }
+
+    /// Sends a subscription's events over `socket` as JSON text messages,
+    /// until the connection closes or the subscription lags.
+    async fn stream_events(mut socket: WebSocket, subscription: Subscription) {
+        // Waiting for an event blocks, so wait on a thread of its own.
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(STREAM_BUFFER);
+        tokio::task::spawn_blocking(move || loop {
+            let forwarded = match subscription.next_event(IDLE_PING) {
+                Ok(event) => Forwarded::Event(event),
+                Err(NoEvent::Timeout) => Forwarded::Idle,
+                Err(NoEvent::Lagged) => Forwarded::Lagged,
+            };
+            let lagged = matches!(forwarded, Forwarded::Lagged);
+            // Fails once the connection's closed, ending the subscription.
+            if sender.blocking_send(forwarded).is_err() || lagged {
+                break;
+            }
+        });
+        while let Some(forwarded) = receiver.recv().await {
+            let message = match forwarded {
+                Forwarded::Event(event) => Message::Text(
+                    serde_json::to_string(&event)
+                        .expect("Events always serialize")
+                        .into(),
+                ),
+                Forwarded::Idle => Message::Ping(Bytes::new()),
+                Forwarded::Lagged => Message::Close(Some(CloseFrame {
+                    code: TRY_AGAIN_LATER,
+                    reason: "Lagged".into(),
+                })),
+            };
+            if socket.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct LockRequest {
+        scope: LockScope,
+    }
+
+    #[derive(Deserialize)]
+    struct UnlockRequest {
+        operator: String,
+    }
+
+    /// Parses an admin request's JSON body.
+    fn admin_body<T: DeserializeOwned>(body: &[u8]) -> Result<T, Status> {
+        serde_json::from_slice(body).map_err(|err| {
+            Status::unscoped(
+                // INVALID_ARGUMENT, Bad Request
+                (3, 400),
+                "MalformedRequest",
+                err.to_string(),
+            )
+        })
+    }
+
+    fn ok<T: Serialize>(body: &T) -> Response {
+        json(
+            200,
+            serde_json::to_string(body).expect("Admin responses always serialize"),
+        )
+    }
+
+    async fn lock_account(
+        State(service): State<Arc<PaymentsService>>,
+        Path(client): Path<u16>,
+        body: Bytes,
+    ) -> Result<Response, Status> {
+        let request: LockRequest = admin_body(&body)?;
+        Ok(ok(&service.lock_account(client, request.scope)?))
+    }
+
+    async fn unlock_account(
+        State(service): State<Arc<PaymentsService>>,
+        Path(client): Path<u16>,
+        body: Bytes,
+    ) -> Result<Response, Status> {
+        let request: UnlockRequest = admin_body(&body)?;
+        let response = service.unlock_account(client, &request.operator)?;
+        let body = serde_json::to_string(&response).expect("Responses always serialize");
+        Ok(json(response.http_status, body))
+    }
+
+    async fn write_snapshot(
+        State(service): State<Arc<PaymentsService>>,
+    ) -> Result<Response, Status> {
+        Ok(ok(&service.write_snapshot()?))
+    }
+
+    async fn flush_dead_letters(State(service): State<Arc<PaymentsService>>) -> Response {
+        ok(&service.flush_dead_letters())
+    }
+
+    async fn reload_config(
+        State(service): State<Arc<PaymentsService>>,
+    ) -> Result<Response, Status> {
+        Ok(ok(&service.reload_config()?))
+    }
+
+    /// Serves the admin API for `service` on `addr` until the server fails.
+    /// Must be run within a Tokio runtime.
+    pub async fn serve_admin(
+        service: Arc<PaymentsService>,
+        addr: SocketAddr,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let app = Router::new()
+            .route("/admin/accounts/{client}", get(get_account))
+            .route("/admin/accounts/{client}/lock", post(lock_account))
+            .route("/admin/accounts/{client}/unlock", post(unlock_account))
+            .route("/admin/snapshot", post(write_snapshot))
+            .route("/admin/dlq/flush", post(flush_dead_letters))
+            .route("/admin/config/reload", post(reload_config))
+            .with_state(service);
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, app).await?;
+        Ok(())
+    }
+
+    /// Serves `service` on `addr` until the server fails. Must be run
+    /// within a Tokio runtime.
+    pub async fn serve(
+        service: Arc<PaymentsService>,
+        addr: SocketAddr,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let app = Router::new()
+            .route("/transactions", post(submit_transaction))
+            .route("/accounts", get(list_statements))
+            .route("/accounts/{client}", get(get_account))
+            .route("/accounts/{client}/proof", get(get_proof))
+            .route("/commitment", get(get_commitment))
+            .route("/subscribe", get(subscribe))
+            .with_state(service);
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+        axum::serve(listener, app).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transaction_engine::EngineConfig;
+    use std::fs::File;
+
+    fn request(
+        transaction_type: &str,
+        client: u16,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> SubmitRequest {
+        SubmitRequest {
+            transaction_type: transaction_type.into(),
+            client,
+            tx,
+            amount: amount.map(Into::into),
+            ..SubmitRequest::default()
+        }
+    }
+
+    #[test]
+    fn submit_and_read() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4));
+        let applied = service
+            .submit_transaction(SubmitRequest {
+                idempotency_key: Some("a".into()),
+                ..request("deposit", 1, 1, Some("10"))
+            })
+            .unwrap();
+        assert_eq!((applied.code, applied.sequence), (APPLIED, Some(1)));
+        let retried = service
+            .submit_transaction(SubmitRequest {
+                idempotency_key: Some("a".into()),
+                ..request("deposit", 1, 1, Some("10"))
+            })
+            .unwrap();
+        assert!(retried.replayed);
+
+        let conflict = service
+            .submit_transaction(SubmitRequest {
+                idempotency_key: Some("a".into()),
+                ..request("deposit", 1, 2, Some("10"))
+            })
+            .unwrap_err();
+        assert_eq!((conflict.code, conflict.http_status), (6, 409));
+        assert_eq!(conflict.details.unwrap().reason, "IdempotencyKeyConflict");
+
+        let rejected = service
+            .submit_transaction(request("withdrawal", 1, 3, Some("20")))
+            .unwrap_err();
+        assert_eq!((rejected.code, rejected.http_status), (9, 422));
+        assert_eq!(
+            rejected.body,
+            r#"{"code":"InsufficientFunds","message":"Insufficient Funds: short by 10","tx":3,"client":1}"#
+        );
+        let details = rejected.details.unwrap();
+        assert_eq!(details.reason, "InsufficientFunds");
+        assert_eq!(details.metadata["tx"], "3");
+        assert_eq!(details.metadata["client"], "1");
+
+        let malformed = service
+            .submit_transaction(request("deposit", 1, 4, None))
+            .unwrap_err();
+        assert_eq!(malformed.code, 3);
+        assert_eq!(malformed.details.unwrap().reason, "MalformedTransaction");
+
+        assert_eq!(service.get_account(1).unwrap()[0].total().to_string(), "10");
+        let missing = service.get_account(2).unwrap_err();
+        assert_eq!((missing.code, missing.http_status), (5, 404));
+        assert_eq!(
+            missing.body,
+            r#"{"client":2,"code":"AccountNotFound","message":"No account for client 2"}"#
+        );
+        service
+            .submit_transaction(request("deposit", 2, 5, Some("1")))
+            .unwrap();
+        let clients: Vec<u16> = service
+            .list_statements(&ListRequest::default())
+            .unwrap()
+            .statements
+            .iter()
+            .map(AccountStatement::client)
+            .collect();
+        assert_eq!(clients, [1, 2]);
+    }
+
+    #[test]
+    fn statements_listed_by_page() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4));
+        for client in 1..=5 {
+            let amount = client.to_string();
+            service
+                .submit_transaction(request("deposit", client, u32::from(client), Some(&amount)))
+                .unwrap();
+        }
+        let listed = |request: &ListRequest| -> (Vec<u16>, Option<u16>) {
+            let page = service.list_statements(request).unwrap();
+            let clients = page.statements.iter().map(AccountStatement::client);
+            (clients.collect(), page.next)
+        };
+        let first = ListRequest {
+            order: StatementOrder::TotalDesc,
+            limit: 2,
+            ..ListRequest::default()
+        };
+        assert_eq!(listed(&first), (vec![5, 4], Some(4)));
+        let second = ListRequest {
+            after: Some(4),
+            ..first.clone()
+        };
+        assert_eq!(listed(&second), (vec![3, 2], Some(2)));
+        let only_three = ListRequest {
+            query: InspectQuery {
+                client: Some(3),
+                ..InspectQuery::default()
+            },
+            ..ListRequest::default()
+        };
+        assert_eq!(listed(&only_three), (vec![3], None));
+
+        // Client 1 isn't selected, so can't be the cursor.
+        let stale = service
+            .list_statements(&ListRequest {
+                order: StatementOrder::TotalDesc,
+                after: Some(1),
+                ..only_three
+            })
+            .unwrap_err();
+        assert_eq!((stale.code, stale.http_status), (3, 400));
+        assert_eq!(stale.details.unwrap().reason, "InvalidCursor");
+    }
+
+    #[test]
+    fn currencies_carried() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4));
+        let in_currency = |tx, transaction_type, amount, currency: &str| SubmitRequest {
+            currency: Some(currency.into()),
+            ..request(transaction_type, 1, tx, Some(amount))
+        };
+        for submission in [
+            in_currency(1, "deposit", "10", "USD"),
+            in_currency(2, "deposit", "5", "eur"),
+            SubmitRequest {
+                to_currency: Some("EUR".into()),
+                rate: Some("0.5".into()),
+                ..in_currency(3, "convert", "2", "USD")
+            },
+        ] {
+            service.submit_transaction(submission).unwrap();
+        }
+        let balances: Vec<(String, String)> = service
+            .get_account(1)
+            .unwrap()
+            .iter()
+            .map(|statement| {
+                (
+                    statement.currency().unwrap().to_string(),
+                    statement.total().to_string(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            balances,
+            [("USD".into(), "8".into()), ("EUR".into(), "6".into())]
+        );
+    }
+
+    #[test]
+    fn balances_proven_against_commitment() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4));
+        assert!(service.commitment().is_none());
+        assert_eq!(
+            service
+                .balance_proof(1)
+                .unwrap_err()
+                .details
+                .unwrap()
+                .reason,
+            "NoCommitment"
+        );
+        for client in 1..=3 {
+            service
+                .submit_transaction(request("deposit", client, u32::from(client), Some("5")))
+                .unwrap();
+        }
+        let commitment = service.commit_state();
+        assert_eq!((commitment.sequence, commitment.accounts), (3, 3));
+        service
+            .submit_transaction(request("deposit", 4, 4, Some("5")))
+            .unwrap();
+
+        // Proofs are against the commitment, not the current balances.
+        let proof = service.balance_proof(2).unwrap();
+        assert!(proof.verify());
+        assert_eq!(proof.root, commitment.root);
+        assert_eq!(service.balance_proof(4).unwrap_err().http_status, 404);
+        assert_eq!(service.commitment(), Some(commitment));
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn receipts_issued() {
+        let signer = ReceiptSigner::new(crate::encryption::Key::from_bytes([9; 32])).unwrap();
+        let public_key = signer.public_key().to_owned();
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4))
+            .with_receipts(signer, None);
+        let keyed = || SubmitRequest {
+            idempotency_key: Some("a".into()),
+            ..request("deposit", 1, 1, Some("10"))
+        };
+        let receipt = service
+            .submit_transaction(keyed())
+            .unwrap()
+            .receipt
+            .unwrap();
+        assert_eq!(
+            (receipt.body.sequence, receipt.body.client, receipt.body.tx),
+            (1, 1, 1)
+        );
+        crate::receipt::verify(&public_key, &receipt).unwrap();
+        // A retry returns the original receipt.
+        let retried = service.submit_transaction(keyed()).unwrap();
+        assert_eq!(retried.receipt.as_ref(), Some(&receipt));
+
+        let withdrawn = service
+            .submit_transaction(request("withdrawal", 1, 2, Some("4")))
+            .unwrap()
+            .receipt
+            .unwrap();
+        assert_eq!(withdrawn.body.available.to_string(), "6");
+        assert_ne!(withdrawn.body.state_digest, receipt.body.state_digest);
+        assert!(service
+            .submit_transaction(request("withdrawal", 1, 3, Some("40")))
+            .is_err());
+        // Receipts are for the balance in the transaction's currency.
+        let in_euros = service
+            .submit_transaction(SubmitRequest {
+                currency: Some("EUR".into()),
+                ..request("deposit", 1, 4, Some("3"))
+            })
+            .unwrap()
+            .receipt
+            .unwrap();
+        assert_eq!(in_euros.body.currency.as_deref(), Some("EUR"));
+        assert_eq!(in_euros.body.total.to_string(), "3");
+    }
+
+    #[cfg(all(feature = "signing", feature = "encryption"))]
+    #[test]
+    fn encrypted_at_rest() {
+        struct Fixed;
+        impl KeyProvider for Fixed {
+            fn key(&self) -> Result<Key, Box<dyn Error>> {
+                Ok(Key::from_bytes([7; 32]))
+            }
+        }
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("pe-encrypted-snapshot-{}", std::process::id()));
+        let log_path = dir.join(format!("pe-encrypted-receipts-{}", std::process::id()));
+        let signer = ReceiptSigner::new(Key::from_bytes([9; 32])).unwrap();
+        let public_key = signer.public_key().to_owned();
+        let log = ReceiptLog::open(&log_path).unwrap();
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4))
+            .with_receipts(signer, Some(log))
+            .with_snapshots(snapshot_path.clone())
+            .with_encryption(&Fixed)
+            .unwrap();
+        for tx in 1..=2 {
+            service
+                .submit_transaction(request("deposit", 1, tx, Some("10")))
+                .unwrap();
+        }
+        service.write_snapshot().unwrap();
+
+        let key = Fixed.key().unwrap();
+        for path in [&snapshot_path, &log_path] {
+            let written = std::fs::read(path).unwrap();
+            assert!(!written.windows(7).any(|window| window == b"deposit"));
+            assert!(crate::encryption::read_file(path, None).is_err());
+        }
+        let snapshot = crate::encryption::read_file(&snapshot_path, Some(&key)).unwrap();
+        let restored = crate::snapshot::read_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.store().get_account(1).unwrap().total_funds(),
+            &crate::money::Money::from(20)
+        );
+        let log = crate::encryption::read_file(&log_path, Some(&key)).unwrap();
+        let receipts: Vec<Receipt> = log
+            .split(|&byte| byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(receipts.len(), 2);
+        for receipt in &receipts {
+            crate::receipt::verify(&public_key, receipt).unwrap();
+        }
+        for path in [snapshot_path, log_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn admin_operations() {
+        let dir = std::env::temp_dir();
+        let snapshot_path = dir.join(format!("pe-admin-snapshot-{}", std::process::id()));
+        let config_path = dir.join(format!("pe-admin-config-{}.json", std::process::id()));
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4))
+            .with_snapshots(snapshot_path.clone())
+            .with_config_file(config_path.clone());
+        service
+            .submit_transaction(request("deposit", 1, 1, Some("10")))
+            .unwrap();
+
+        let locked = service.lock_account(1, LockScope::BlockDebits).unwrap();
+        assert!(locked[0].locked());
+        assert!(service
+            .submit_transaction(request("withdrawal", 1, 2, Some("1")))
+            .is_err());
+        assert_eq!(
+            service
+                .lock_account(2, LockScope::BlockAll)
+                .unwrap_err()
+                .http_status,
+            404
+        );
+        let unlocked = service.unlock_account(1, "alice").unwrap();
+        assert_eq!((unlocked.code, unlocked.sequence), (APPLIED, Some(2)));
+        assert_eq!(
+            service.unlock_account(1, "alice").unwrap().code,
+            "AlreadyApplied"
+        );
+        assert_eq!(service.unlock_account(1, " ").unwrap_err().http_status, 400);
+
+        // Only failures are dead letters, not rejections.
+        service
+            .submit_transaction(request("withdrawal", 1, 3, Some("100")))
+            .unwrap_err();
+        service
+            .submit_transaction(request("dispute", 1, 9, None))
+            .unwrap_err();
+        let dead = service.flush_dead_letters();
+        assert_eq!(
+            dead.iter()
+                .map(|letter| (letter.tx, letter.code))
+                .collect::<Vec<_>>(),
+            [(9, "DisputedTransactionNotFound")]
+        );
+        assert!(service.flush_dead_letters().is_empty());
+
+        let written = service.write_snapshot().unwrap();
+        assert_eq!((written.sequence, written.accounts), (2, 1));
+        let restored = crate::snapshot::read_snapshot(File::open(&snapshot_path).unwrap()).unwrap();
+        assert_eq!(
+            restored.store().get_account(1).unwrap().total_funds(),
+            &crate::money::Money::from(10)
+        );
+
+        std::fs::write(&config_path, "{\"max_held\": ").unwrap();
+        assert_eq!(service.reload_config().unwrap_err().http_status, 422);
+        std::fs::write(&config_path, r#"{"chargeback_lock_scope": "block-debits"}"#).unwrap();
+        let config = service.reload_config().unwrap();
+        assert_eq!(config.chargeback_lock_scope, LockScope::BlockDebits);
+        for (transaction_type, tx) in [("dispute", 1), ("chargeback", 1)] {
+            service
+                .submit_transaction(request(transaction_type, 1, tx, None))
+                .unwrap();
+        }
+        // Deposits are still allowed under the reloaded lock scope.
+        service
+            .submit_transaction(request("deposit", 1, 4, Some("1")))
+            .unwrap();
+
+        let unconfigured =
+            PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 1));
+        assert_eq!(unconfigured.write_snapshot().unwrap_err().http_status, 409);
+        assert_eq!(unconfigured.reload_config().unwrap_err().http_status, 409);
+        for path in [snapshot_path, config_path] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn submissions_over_limit_shed() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 1))
+            .with_max_in_flight(1);
+        // As if another submission were being handled.
+        let in_flight = service.admit(2).unwrap();
+        let shed = service
+            .submit_transaction(request("deposit", 1, 1, Some("10")))
+            .unwrap_err();
+        assert_eq!((shed.code, shed.http_status), (14, 503));
+        assert_eq!(shed.details.unwrap().reason, "Overloaded");
+        drop(in_flight);
+        service
+            .submit_transaction(request("deposit", 1, 1, Some("10")))
+            .unwrap();
+        assert_eq!(
+            service.ingestion_stats(),
+            QueueStats {
+                depth: 0,
+                max_depth: 1,
+                pushed: 2,
+                shed: 1,
+                spilled: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn subscriptions_filtered() {
+        let service = PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 4))
+            .with_subscriptions(|principal: &str, client: u16| principal == "ops" || client == 1);
+        let forbidden = service.subscribe("team-a", &[1, 5]).err().unwrap();
+        assert_eq!((forbidden.code, forbidden.http_status), (7, 403));
+        assert_eq!(forbidden.details.unwrap().metadata["client"], "5");
+        assert_eq!(
+            service.subscribe("ops", &[]).err().unwrap().http_status,
+            400
+        );
+
+        let team_a = service.subscribe("team-a", &[1]).unwrap();
+        let ops = service.subscribe("ops", &[2, 5]).unwrap();
+        // Clients 1 and 5 are on the same shard.
+        for (client, tx) in [(1, 1), (5, 2), (2, 3), (1, 4)] {
+            service
+                .submit_transaction(request("deposit", client, tx, Some("1")))
+                .unwrap();
+        }
+        service.lock_account(5, LockScope::BlockAll).unwrap();
+        let followed = |subscription: &Subscription| -> Vec<(u16, u64)> {
+            subscription
+                .pending_events()
+                .map(|event| (event.client(), event.sequence()))
+                .collect()
+        };
+        assert_eq!(followed(&team_a), [(1, 1), (1, 4)]);
+        assert_eq!(followed(&ops), [(5, 2), (2, 3), (5, 4)]);
+
+        let unconfigured =
+            PaymentsService::new(SharedTxEngine::with_shards(EngineConfig::default(), 1));
+        assert_eq!(
+            unconfigured
+                .subscribe("ops", &[1])
+                .err()
+                .unwrap()
+                .http_status,
+            501
+        );
+    }
+}
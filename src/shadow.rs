@@ -0,0 +1,336 @@
+//! Shadow runs, replaying the same input through two engines side by side,
+//! e.g. a new store backend or engine configuration against the one it's
+//! replacing, and reporting where they diverge.
+//!
+//! Outcomes are compared per transaction, by whether it was applied and
+//! otherwise the reason it wasn't. Sequence numbers aren't compared, as
+//! engines may number transactions differently. Final states are compared
+//! per client, and by digest: the SHA-256 of the statements in the
+//! canonical format, as recorded in a run manifest's `state_digest`.
+
+use crate::account::{AccountStatement, StatementOptions};
+use crate::account_store::{AccountStore, InMemoryStore};
+use crate::sha256;
+use crate::shared_engine::SharedTxEngine;
+use crate::tiered_store::{DirBackend, TieredStore};
+use crate::transaction::{Transaction, TransactionRaw};
+use crate::transaction_engine::{EngineConfig, TransactionNotApplied, TxEngine};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Accounts a [`EngineKind::Tiered`] engine keeps in memory. Deliberately
+/// small, so even modest inputs page accounts out.
+const TIERED_HOT_CAPACITY: usize = 64;
+
+/// The engines a shadow run can compare.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EngineKind {
+    /// A [`TxEngine`] with an [`InMemoryStore`].
+    Single,
+    /// A [`TxEngine`] with a [`TieredStore`] paging to a temporary
+    /// directory.
+    Tiered,
+    /// A [`SharedTxEngine`].
+    Shared,
+}
+
+impl std::str::FromStr for EngineKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(EngineKind::Single),
+            "tiered" => Ok(EngineKind::Tiered),
+            "shared" => Ok(EngineKind::Shared),
+            _ => Err(format!("Unknown engine {:?}", s)),
+        }
+    }
+}
+
+/// One side of a shadow run.
+#[derive(Debug, Clone)]
+pub struct ShadowSide {
+    pub engine: EngineKind,
+    pub config: EngineConfig,
+}
+
+/// A transaction the two engines handled differently.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Divergence {
+    /// Position of the transaction in the input, counting from one.
+    pub row: usize,
+    pub client: u16,
+    pub tx: u32,
+    /// `applied`, or the name of the reason it wasn't, for each engine.
+    pub primary: &'static str,
+    pub shadow: &'static str,
+}
+
+#[derive(Debug)]
+pub struct ShadowReport {
+    /// Transactions handled by both engines.
+    pub transactions: usize,
+    /// Rows that couldn't be parsed, so were given to neither engine.
+    pub malformed: usize,
+    pub divergences: Vec<Divergence>,
+    /// Clients whose final statements differ, in order.
+    pub differing_clients: Vec<u16>,
+    pub primary_digest: String,
+    pub shadow_digest: String,
+}
+
+impl ShadowReport {
+    /// Whether the engines agreed on every transaction and final state.
+    pub fn agrees(&self) -> bool {
+        self.divergences.is_empty() && self.primary_digest == self.shadow_digest
+    }
+
+    /// Writes the divergent transactions as CSV, in input order.
+    pub fn write_divergences<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        // Write the header explicitly, as there are usually no divergences.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        csv_writer.write_record(["row", "client", "tx", "primary", "shadow"])?;
+        for divergence in &self.divergences {
+            csv_writer.serialize(divergence)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for ShadowReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "transactions:      {}", self.transactions)?;
+        writeln!(f, "malformed:         {}", self.malformed)?;
+        writeln!(f, "divergences:       {}", self.divergences.len())?;
+        writeln!(f, "differing clients: {}", self.differing_clients.len())?;
+        writeln!(f, "primary digest:    {}", self.primary_digest)?;
+        writeln!(f, "shadow digest:     {}", self.shadow_digest)
+    }
+}
+
+/// An engine under comparison.
+trait Replica {
+    fn handle(&mut self, transaction: &Transaction) -> Result<u64, TransactionNotApplied>;
+
+    /// Statements for all accounts, ordered by client.
+    fn statements(&self) -> Vec<AccountStatement>;
+}
+
+impl<S: AccountStore> Replica for TxEngine<S> {
+    fn handle(&mut self, transaction: &Transaction) -> Result<u64, TransactionNotApplied> {
+        let result = TxEngine::handle(self, transaction);
+        // Nothing consumes events, but they'd otherwise accumulate.
+        self.drain_events().for_each(drop);
+        result
+    }
+
+    fn statements(&self) -> Vec<AccountStatement> {
+        let mut statements: Vec<_> = self.store().account_statements().collect();
+        statements.sort_by_key(AccountStatement::client);
+        statements
+    }
+}
+
+impl Replica for SharedTxEngine {
+    fn handle(&mut self, transaction: &Transaction) -> Result<u64, TransactionNotApplied> {
+        let result = SharedTxEngine::handle(self, transaction);
+        self.drain_events();
+        result
+    }
+
+    fn statements(&self) -> Vec<AccountStatement> {
+        self.account_statements()
+    }
+}
+
+/// Creates the engine for one side. A tiered store pages to `dir`.
+fn replica(side: &ShadowSide, dir: &Path) -> Result<Box<dyn Replica>, Box<dyn Error>> {
+    let config = side.config.clone();
+    Ok(match side.engine {
+        EngineKind::Single => Box::new(TxEngine::with_config(InMemoryStore::new(), config)),
+        EngineKind::Tiered => {
+            let store = TieredStore::new(DirBackend::new(dir)?, TIERED_HOT_CAPACITY);
+            Box::new(TxEngine::with_config(store, config))
+        }
+        EngineKind::Shared => Box::new(SharedTxEngine::new(config)),
+    })
+}
+
+/// Replays a transactions CSV through both engines, comparing each
+/// transaction's outcome and the final states.
+pub fn run<R: Read>(
+    input: R,
+    primary: &ShadowSide,
+    shadow: &ShadowSide,
+) -> Result<ShadowReport, Box<dyn Error>> {
+    let dir = |name| {
+        std::env::temp_dir().join(format!(
+            "payments_engine_shadow_{}_{}",
+            std::process::id(),
+            name
+        ))
+    };
+    let dirs = [dir("primary"), dir("shadow")];
+    let result = replica(primary, &dirs[0]).and_then(|mut primary| {
+        let mut shadow = replica(shadow, &dirs[1])?;
+        compare(input, primary.as_mut(), shadow.as_mut())
+    });
+    // Best effort, the directories are only scratch space, if created at all.
+    for dir in dirs {
+        let _ = std::fs::remove_dir_all(dir);
+    }
+    result
+}
+
+fn compare<R: Read>(
+    input: R,
+    primary: &mut dyn Replica,
+    shadow: &mut dyn Replica,
+) -> Result<ShadowReport, Box<dyn Error>> {
+    let mut csv_reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let mut transactions = 0;
+    let mut malformed = 0;
+    let mut divergences = vec![];
+    for (index, transaction) in csv_reader.deserialize::<TransactionRaw>().enumerate() {
+        let transaction = match transaction.ok().map(Transaction::try_from) {
+            Some(Ok(transaction)) => transaction,
+            _ => {
+                malformed += 1;
+                continue;
+            }
+        };
+        transactions += 1;
+        let primary_outcome = outcome(&primary.handle(&transaction));
+        let shadow_outcome = outcome(&shadow.handle(&transaction));
+        if primary_outcome != shadow_outcome {
+            divergences.push(Divergence {
+                row: index + 1,
+                client: transaction.client_id,
+                tx: transaction.transaction_id,
+                primary: primary_outcome,
+                shadow: shadow_outcome,
+            });
+        }
+    }
+
+    let primary_statements = primary.statements();
+    let shadow_statements = shadow.statements();
+    let differing_clients = differing_clients(&primary_statements, &shadow_statements);
+    Ok(ShadowReport {
+        transactions,
+        malformed,
+        divergences,
+        differing_clients,
+        primary_digest: digest(primary_statements)?,
+        shadow_digest: digest(shadow_statements)?,
+    })
+}
+
+fn outcome(result: &Result<u64, TransactionNotApplied>) -> &'static str {
+    match result {
+        Ok(_) => "applied",
+        Err(err) => err.name(),
+    }
+}
+
+/// Clients with a statement from only one engine, or different statements
+/// from each.
+fn differing_clients(primary: &[AccountStatement], shadow: &[AccountStatement]) -> Vec<u16> {
+    let clients: BTreeSet<u16> = primary
+        .iter()
+        .chain(shadow)
+        .map(AccountStatement::client)
+        .collect();
+    let find = |statements: &[AccountStatement], client| {
+        statements
+            .binary_search_by_key(&client, AccountStatement::client)
+            .ok()
+            .map(|index| statements[index].clone().to_fixed_scale())
+    };
+    clients
+        .into_iter()
+        .filter(|&client| find(primary, client) != find(shadow, client))
+        .collect()
+}
+
+/// SHA-256 of statements, ordered by client, in the canonical format.
+fn digest(statements: Vec<AccountStatement>) -> Result<String, Box<dyn Error>> {
+    let options = StatementOptions::default();
+    let statements = statements
+        .into_iter()
+        .map(|statement| statement.with_options(&options))
+        .collect();
+    let mut state = vec![];
+    crate::write_canonical_rows(&mut state, statements, &options)?;
+    Ok(sha256::to_hex(&Sha256::digest(&state)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::HoldPolicy;
+
+    const INPUT: &str = "type,client,tx,amount\n\
+                         deposit,1,1,10\n\
+                         withdrawal,1,2,8\n\
+                         dispute,1,1,\n\
+                         deposit,2,3,5.5\n\
+                         deposit,2,x,1\n";
+
+    fn side(engine: EngineKind) -> ShadowSide {
+        ShadowSide {
+            engine,
+            config: EngineConfig::default(),
+        }
+    }
+
+    #[test]
+    fn backends_agree() {
+        for engine in [EngineKind::Tiered, EngineKind::Shared] {
+            let report = run(INPUT.as_bytes(), &side(EngineKind::Single), &side(engine))
+                .unwrap_or_else(|err| panic!("{}", err));
+            assert!(report.agrees(), "{:?}: {}", engine, report);
+            assert_eq!(report.transactions, 4);
+            assert_eq!(report.malformed, 1);
+            assert!(report.differing_clients.is_empty());
+        }
+    }
+
+    #[test]
+    fn config_divergence_reported() {
+        let input = format!("{}resolve,1,1,\nresolve,1,1,\n", INPUT);
+        let mut shadow = side(EngineKind::Single);
+        shadow.config.idempotent_settlement = true;
+        let report = run(input.as_bytes(), &side(EngineKind::Single), &shadow).unwrap();
+        assert!(!report.agrees());
+        // Only the outcome of the repeated resolve differs.
+        assert_eq!(report.primary_digest, report.shadow_digest);
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!(report.divergences[0].row, 7);
+        assert_eq!(report.divergences[0].shadow, "AlreadyApplied");
+
+        shadow.config.hold_policy = HoldPolicy::NegativeAvailable;
+        let report = run(INPUT.as_bytes(), &side(EngineKind::Single), &shadow).unwrap();
+        assert!(!report.agrees());
+        assert!(report.divergences.is_empty());
+        assert_eq!(report.differing_clients, [1]);
+
+        let mut output = vec![];
+        report.write_divergences(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "row,client,tx,primary,shadow\n"
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Multi-threaded engine for batch runs, applying each client's transactions
+//! on one of several worker threads.
+
+use crate::account::AccountStatement;
+use crate::account_store::{AccountStore, InMemoryStore};
+use crate::event::EngineEvent;
+use crate::queue::{BoundedQueue, Overflow, Pushed, QueueConfig, QueueStats};
+use crate::system_accounts::SystemAccounts;
+use crate::transaction::{Transaction, TransactionInfo};
+use crate::transaction_engine::{
+    DestinationTotals, EngineConfig, SharedTxIds, TransactionNotApplied, TxEngine,
+};
+use crate::tx_index::TxIndex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Transactions handed to a worker at a time.
+const BATCH_SIZE: usize = 1024;
+
+/// Batches queued per worker by default before [`ShardedTxEngine::submit`]
+/// waits for it to catch up, so a slow worker doesn't buffer the whole
+/// input.
+const QUEUED_BATCHES: usize = 4;
+
+/// Transactions handed to a worker at once.
+#[derive(Serialize, Deserialize)]
+struct Batch {
+    /// Numbered in the order they were submitted.
+    transactions: Vec<(usize, Transaction)>,
+    /// How many transactions had been submitted when the batch was queued:
+    /// the worker's transactions before then are all in this batch or an
+    /// earlier one.
+    upto: usize,
+    /// Whether the batch's transaction waits for every worker to handle the
+    /// transactions submitted before it (see [`ShardedTxEngine::submit`]).
+    barrier: bool,
+}
+
+/// A [`TxEngine`] spread over worker threads, for processing large inputs.
+///
+/// Each transaction only touches its client's account, so clients are
+/// partitioned across workers by ID, each worker with its own engine and
+/// store. A client's transactions are all applied by the same worker, in the
+/// order they were submitted; transactions for different clients may be
+/// applied in any order.
+///
+/// As with [`crate::shared_engine::SharedTxEngine`], sequence numbers,
+/// withdrawal destination totals and the transaction IDs taken are shared by
+/// all workers, and plugins aren't supported. Transaction IDs are unique
+/// across clients, so a transaction taking or naming an ID that a client on
+/// another worker took first is only applied once every worker has caught
+/// up to it, for the same result as applying the transactions in order.
+pub struct ShardedTxEngine {
+    workers: Vec<Worker>,
+    submitted: usize,
+    /// The client that first took each transaction ID submitted.
+    claims: TxIndex,
+    /// IDs taken by clients on more than one worker.
+    contested: HashSet<u32>,
+}
+
+struct Worker {
+    queue: Arc<BoundedQueue<Batch>>,
+    batch: Vec<(usize, Transaction)>,
+    thread: JoinHandle<io::Result<ShardOutput>>,
+}
+
+/// Marks a worker as having handled everything once it stops, however it
+/// stops, so no other worker waits on it.
+struct Progress {
+    done: Arc<[AtomicUsize]>,
+    index: usize,
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.done[self.index].store(usize::MAX, Ordering::Release);
+    }
+}
+
+/// What a worker leaves behind once its input is exhausted.
+struct ShardOutput {
+    engine: TxEngine<InMemoryStore>,
+    applied: u64,
+    not_applied: Vec<(usize, Transaction, TransactionNotApplied)>,
+    events: Vec<EngineEvent>,
+}
+
+/// The merged results of a [`ShardedTxEngine`] run.
+#[derive(Debug)]
+pub struct ShardedRun {
+    /// Number of transactions applied.
+    pub applied: u64,
+    /// Transactions not applied and why, each with its position in the order
+    /// they were submitted, sorted by position.
+    pub not_applied: Vec<(usize, Transaction, TransactionNotApplied)>,
+    /// Statements for all accounts, ordered by client.
+    pub statements: Vec<AccountStatement>,
+    /// The system accounts, totalled over all workers.
+    pub system: SystemAccounts,
+    /// Events raised by the engine, worker by worker. Each client's events
+    /// are in the order they were raised.
+    pub events: Vec<EngineEvent>,
+    /// Counts for each worker's queue of batches, e.g. how deep it got.
+    pub queues: Vec<QueueStats>,
+}
+
+impl ShardedTxEngine {
+    /// Starts `workers` worker threads (at least one), each queueing a few
+    /// batches before [`ShardedTxEngine::submit`] waits for it.
+    pub fn new(config: EngineConfig, workers: usize) -> Self {
+        let queue = QueueConfig {
+            capacity: QUEUED_BATCHES,
+            overflow: Overflow::Block,
+        };
+        Self::with_queues(config, workers, queue).expect("Queues without spill files can't fail")
+    }
+
+    /// As [`ShardedTxEngine::new`], with each worker's queue of batches
+    /// configured by `queue`. With [`Overflow::Spill`], each worker spills
+    /// to the path with its index appended (e.g. `spill.0`). Shedding isn't
+    /// supported, as every transaction submitted must be applied.
+    pub fn with_queues(
+        config: EngineConfig,
+        workers: usize,
+        queue: QueueConfig,
+    ) -> io::Result<Self> {
+        if queue.overflow == Overflow::Shed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Shard queues can't shed transactions",
+            ));
+        }
+        let sequence = Arc::new(AtomicU64::new(0));
+        let destination_totals = DestinationTotals::default();
+        let tx_ids = SharedTxIds::default();
+        let workers = workers.max(1);
+        // How many transactions each worker has handled those of, as for
+        // `Batch::upto`.
+        let done: Arc<[AtomicUsize]> = (0..workers).map(|_| AtomicUsize::new(0)).collect();
+        let workers = (0..workers)
+            .map(|index| {
+                let engine = TxEngine::with_sequence(
+                    InMemoryStore::new(),
+                    config.clone(),
+                    Arc::clone(&sequence),
+                    Arc::clone(&destination_totals),
+                    Some(Arc::clone(&tx_ids)),
+                );
+                let overflow = match &queue.overflow {
+                    Overflow::Spill(path) => {
+                        let mut path = path.clone().into_os_string();
+                        path.push(format!(".{}", index));
+                        Overflow::Spill(path.into())
+                    }
+                    overflow => overflow.clone(),
+                };
+                let queue = Arc::new(BoundedQueue::<Batch>::new(QueueConfig {
+                    capacity: queue.capacity,
+                    overflow,
+                })?);
+                let batches = Arc::clone(&queue);
+                let progress = Progress {
+                    done: Arc::clone(&done),
+                    index,
+                };
+                let thread = thread::spawn(move || {
+                    let mut output = ShardOutput {
+                        engine,
+                        applied: 0,
+                        not_applied: vec![],
+                        events: vec![],
+                    };
+                    while let Some(batch) = batches.pop()? {
+                        if let (true, Some((position, _))) =
+                            (batch.barrier, batch.transactions.first())
+                        {
+                            while progress
+                                .done
+                                .iter()
+                                .any(|done| done.load(Ordering::Acquire) < *position)
+                            {
+                                thread::yield_now();
+                            }
+                        }
+                        for (position, transaction) in batch.transactions {
+                            match output.engine.handle(&transaction) {
+                                Ok(_) => output.applied += 1,
+                                Err(err) => output.not_applied.push((position, transaction, err)),
+                            }
+                        }
+                        output.events.extend(output.engine.drain_events());
+                        progress.done[index].store(batch.upto, Ordering::Release);
+                    }
+                    Ok(output)
+                });
+                Ok(Worker {
+                    queue,
+                    batch: Vec::with_capacity(BATCH_SIZE),
+                    thread,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self {
+            workers,
+            submitted: 0,
+            claims: TxIndex::new(),
+            contested: HashSet::new(),
+        })
+    }
+
+    /// Queues a transaction for the worker its client is on. Waits if that
+    /// worker is too far behind, unless its queue spills to disk. Fails if
+    /// the batch can't be spilled.
+    ///
+    /// A transaction whose result depends on another worker's transactions,
+    /// as it takes or names a transaction ID a client on another worker
+    /// took, is queued on its own once every worker's earlier transactions
+    /// are, and waits for them to be handled.
+    pub fn submit(&mut self, transaction: Transaction) -> io::Result<()> {
+        let shard = usize::from(transaction.client_id) % self.workers.len();
+        let position = self.submitted;
+        self.submitted += 1;
+        if self.crosses_workers(&transaction, shard) {
+            for worker in &mut self.workers {
+                let transactions = std::mem::take(&mut worker.batch);
+                worker.push(Batch {
+                    transactions,
+                    upto: position,
+                    barrier: false,
+                })?;
+            }
+            return self.workers[shard].push(Batch {
+                transactions: vec![(position, transaction)],
+                upto: self.submitted,
+                barrier: true,
+            });
+        }
+        let worker = &mut self.workers[shard];
+        worker.batch.push((position, transaction));
+        if worker.batch.len() == BATCH_SIZE {
+            let transactions = std::mem::replace(&mut worker.batch, Vec::with_capacity(BATCH_SIZE));
+            worker.push(Batch {
+                transactions,
+                upto: self.submitted,
+                barrier: false,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Whether the transaction, for a client on worker `shard`, takes or
+    /// names a transaction ID that a client on another worker took, noting
+    /// the IDs it takes.
+    fn crosses_workers(&mut self, transaction: &Transaction, shard: usize) -> bool {
+        let tx = transaction.transaction_id;
+        let takes_id = matches!(
+            transaction.info,
+            TransactionInfo::Deposit(_)
+                | TransactionInfo::Withdrawal(_)
+                | TransactionInfo::Convert(_)
+        );
+        let names_id = matches!(
+            transaction.info,
+            TransactionInfo::Dispute(_)
+                | TransactionInfo::Resolve
+                | TransactionInfo::Chargeback
+                | TransactionInfo::Reversal
+        );
+        if !takes_id && !names_id {
+            return false;
+        }
+        let Some(owner) = self.claims.owner(tx) else {
+            if takes_id {
+                self.claims.insert(tx, transaction.client_id);
+            }
+            return false;
+        };
+        let crosses =
+            self.contested.contains(&tx) || usize::from(owner) % self.workers.len() != shard;
+        if crosses && takes_id {
+            self.contested.insert(tx);
+        }
+        crosses
+    }
+
+    /// Number of transactions submitted so far, i.e. the position the next
+    /// one will be submitted at.
+    pub fn submitted(&self) -> usize {
+        self.submitted
+    }
+
+    /// Waits for the workers to apply everything submitted, and merges their
+    /// results.
+    pub fn finish(self) -> Result<ShardedRun, Box<dyn Error>> {
+        let mut run = ShardedRun {
+            applied: 0,
+            not_applied: vec![],
+            statements: vec![],
+            system: SystemAccounts::default(),
+            events: vec![],
+            queues: vec![],
+        };
+        for mut worker in self.workers {
+            let transactions = std::mem::take(&mut worker.batch);
+            if !transactions.is_empty() {
+                worker.push(Batch {
+                    transactions,
+                    upto: self.submitted,
+                    barrier: false,
+                })?;
+            }
+            worker.queue.close();
+            let output = worker
+                .thread
+                .join()
+                .map_err(|_| "Shard worker panicked.")??;
+            run.queues.push(worker.queue.stats());
+            run.applied += output.applied;
+            run.not_applied.extend(output.not_applied);
+            run.statements
+                .extend(output.engine.store().account_statements());
+            run.system = run.system + output.engine.system_accounts().clone();
+            run.events.extend(output.events);
+        }
+        run.not_applied.sort_by_key(|(position, _, _)| *position);
+        run.statements.sort_by_key(AccountStatement::client);
+        Ok(run)
+    }
+}
+
+impl Worker {
+    fn push(&self, batch: Batch) -> io::Result<()> {
+        match self.queue.push(batch)? {
+            Pushed::Queued | Pushed::Spilled => Ok(()),
+            // Queues don't shed, so only once closed, if the worker's ended.
+            Pushed::Shed(_) => Err(io::Error::other("Shard worker stopped early")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::generator::{generate, WorkloadConfig};
+    use crate::money::Money;
+
+    #[test]
+    fn sharded_matches_single_engine() {
+        let transactions = generate(&WorkloadConfig {
+            transactions: 20_000,
+            clients: 100,
+            seed: 7,
+        });
+        let mut single = TxEngine::with_config(InMemoryStore::new(), EngineConfig::default());
+        let mut single_not_applied = vec![];
+        for (position, transaction) in transactions.iter().enumerate() {
+            if let Err(err) = single.handle(transaction) {
+                single_not_applied.push((position, transaction.clone(), err));
+            }
+        }
+        let mut single_statements: Vec<AccountStatement> =
+            single.store().account_statements().collect();
+        single_statements.sort_by_key(AccountStatement::client);
+
+        let mut sharded = ShardedTxEngine::new(EngineConfig::default(), 4);
+        for transaction in transactions.iter().cloned() {
+            sharded.submit(transaction).unwrap();
+        }
+        let run = sharded.finish().unwrap();
+        assert_eq!(run.queues.len(), 4);
+        assert!(run
+            .queues
+            .iter()
+            .all(|queue| queue.max_depth <= QUEUED_BATCHES));
+        assert_eq!(run.statements, single_statements);
+        assert_eq!(&run.system, single.system_accounts());
+        assert_eq!(run.not_applied, single_not_applied);
+        assert_eq!(
+            run.applied as usize,
+            transactions.len() - single_not_applied.len()
+        );
+    }
+
+    #[test]
+    fn transaction_ids_unique_across_workers() {
+        let transaction = |client_id, info| Transaction {
+            client_id,
+            transaction_id: 1,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        // Clients 1 to 8 are spread over the workers, all depositing under
+        // the same ID, with other clients' disputes of it in between.
+        let mut transactions = vec![];
+        for client_id in 1..=8 {
+            transactions.push(transaction(
+                client_id,
+                TransactionInfo::Deposit(Money::from(1)),
+            ));
+            transactions.push(transaction(client_id + 1, TransactionInfo::Dispute(None)));
+        }
+        let mut single = TxEngine::with_config(InMemoryStore::new(), EngineConfig::default());
+        let single_not_applied: Vec<_> = transactions
+            .iter()
+            .enumerate()
+            .filter_map(|(position, transaction)| {
+                let err = single.handle(transaction).err()?;
+                Some((position, transaction.clone(), err))
+            })
+            .collect();
+
+        let mut sharded = ShardedTxEngine::new(EngineConfig::default(), 4);
+        for transaction in transactions.iter().cloned() {
+            sharded.submit(transaction).unwrap();
+        }
+        let run = sharded.finish().unwrap();
+        // Client 1's deposit came first, so only it's applied, and only
+        // client 1 can dispute it.
+        assert_eq!(run.applied, 1);
+        assert_eq!(run.not_applied, single_not_applied);
+        for (i, (_, _, err)) in run.not_applied.iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(err, &TransactionNotApplied::ClientMismatch(1));
+            } else {
+                assert_eq!(err, &TransactionNotApplied::RepeatTransaction(1));
+            }
+        }
+    }
+}
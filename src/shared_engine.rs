@@ -0,0 +1,794 @@
+//! Thread-safe engine for handling transactions from many callers at once.
+
+use crate::account::{Account, AccountStatement, HistoryEntry, LockScope, StatementOrder};
+use crate::account_store::{AccountStore, InMemoryStore};
+use crate::clock::Clock;
+use crate::event::EngineEvent;
+use crate::fx::RateProvider;
+use crate::inspect::{self, InspectQuery, Page};
+use crate::ledger::EventSink;
+use crate::period::{PeriodClose, PeriodSummary};
+use crate::statements::{self, StatementView};
+use crate::system_accounts::SystemAccounts;
+use crate::transaction::Transaction;
+use crate::transaction_engine::{
+    DestinationTotals, EngineConfig, SharedTxIds, TransactionNotApplied, TxEngine,
+};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Default number of lock shards. Comfortably more than the cores we'd
+/// expect to run on, so concurrent callers rarely land on the same shard.
+pub const DEFAULT_SHARDS: usize = 256;
+
+/// Default time the results of keyed submissions are kept for retries (see
+/// [`SharedTxEngine::handle_keyed`]). Long enough to cover any producer's
+/// retries, short enough that a long-running server's keys don't pile up.
+pub const DEFAULT_ACK_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// A [`TxEngine`] that can be shared between threads (or async tasks), with
+/// `handle` taking `&self`.
+///
+/// Clients are partitioned across independently locked shards, each with its
+/// own engine and store, so transactions for clients on different shards
+/// never contend. A shard's lock is only held for the duration of a single
+/// transaction and never across an `.await`.
+///
+/// Transactions for a given client are applied in the order `handle` is
+/// called for them. Callers submitting from several tasks must route each
+/// client's transactions through one task to keep them in order.
+///
+/// Sequence numbers are shared by all shards, so are unique across the
+/// engine and follow the order transactions were applied in.
+///
+/// Processing is divided into ledger periods, closed by
+/// [`cutover`](Self::cutover) without stopping the engine.
+///
+/// Plugins aren't supported, as each shard would need its own instance.
+pub struct SharedTxEngine {
+    shards: Vec<Mutex<TxEngine<InMemoryStore>>>,
+    /// Results of transactions submitted with an idempotency key, per shard,
+    /// by client and key. Always locked before the shard's engine.
+    acks: Vec<Mutex<AckLog>>,
+    ack_retention: Duration,
+    /// The current period, numbered from one.
+    period: AtomicU32,
+    /// Last sequence number of the previous period.
+    period_start: AtomicU64,
+    /// Transactions handled in the current period but not applied.
+    period_rejected: AtomicU64,
+}
+
+type AckLog = Retained<(u16, String), (Transaction, Result<u64, TransactionNotApplied>)>;
+
+/// Entries kept for a fixed time after they're inserted, and dropped once
+/// it's passed.
+pub(crate) struct Retained<K, V> {
+    retention: Duration,
+    entries: HashMap<K, V>,
+    /// Keys in the order they were inserted, with when.
+    inserted: VecDeque<(Instant, K)>,
+}
+
+impl<K: Eq + Hash + Clone, V> Retained<K, V> {
+    pub(crate) fn new(retention: Duration) -> Self {
+        Self {
+            retention,
+            entries: HashMap::new(),
+            inserted: VecDeque::new(),
+        }
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        self.expire();
+        self.entries.get(key)
+    }
+
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        self.expire();
+        if self.entries.insert(key.clone(), value).is_none() {
+            self.inserted.push_back((Instant::now(), key));
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn expire(&mut self) {
+        let now = Instant::now();
+        while let Some((inserted, _)) = self.inserted.front() {
+            if now.duration_since(*inserted) < self.retention {
+                break;
+            }
+            if let Some((_, key)) = self.inserted.pop_front() {
+                self.entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// The response to a transaction submitted with an idempotency key (see
+/// [`SharedTxEngine::handle_keyed`]).
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ack {
+    /// The transaction was handled now, with this result.
+    Handled(Result<u64, TransactionNotApplied>),
+    /// A retry of a transaction already handled under this key. Nothing was
+    /// changed; this is the result from when it was handled.
+    Replayed(Result<u64, TransactionNotApplied>),
+    /// The client already used this key for a different transaction, so this
+    /// one wasn't handled.
+    KeyConflict,
+}
+
+impl SharedTxEngine {
+    /// Creates an engine with [`DEFAULT_SHARDS`] shards.
+    pub fn new(config: EngineConfig) -> Self {
+        Self::with_shards(config, DEFAULT_SHARDS)
+    }
+
+    /// Creates an engine with the given number of shards (at least one).
+    pub fn with_shards(config: EngineConfig, shards: usize) -> Self {
+        let sequence = Arc::new(AtomicU64::new(0));
+        let destination_totals = DestinationTotals::default();
+        let tx_ids = SharedTxIds::default();
+        let shards = shards.max(1);
+        Self {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(TxEngine::with_sequence(
+                        InMemoryStore::new(),
+                        config.clone(),
+                        Arc::clone(&sequence),
+                        Arc::clone(&destination_totals),
+                        Some(Arc::clone(&tx_ids)),
+                    ))
+                })
+                .collect(),
+            acks: (0..shards)
+                .map(|_| Mutex::new(Retained::new(DEFAULT_ACK_RETENTION)))
+                .collect(),
+            ack_retention: DEFAULT_ACK_RETENTION,
+            period: AtomicU32::new(1),
+            period_start: AtomicU64::new(0),
+            period_rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Keeps the results of keyed submissions for `retention` rather than
+    /// [`DEFAULT_ACK_RETENTION`].
+    pub fn with_ack_retention(mut self, retention: Duration) -> Self {
+        self.acks = (0..self.shards.len())
+            .map(|_| Mutex::new(Retained::new(retention)))
+            .collect();
+        self.ack_retention = retention;
+        self
+    }
+
+    /// How long the results of keyed submissions are kept for retries.
+    pub fn ack_retention(&self) -> Duration {
+        self.ack_retention
+    }
+
+    /// As [`TxEngine::handle`], locking only the shard the client is on.
+    pub fn handle(&self, transaction: &Transaction) -> Result<u64, TransactionNotApplied> {
+        let mut shard = self.shard(transaction.client_id);
+        let result = shard.handle(transaction);
+        self.count(&result);
+        result
+    }
+
+    /// As [`handle`](Self::handle), with a key chosen by the client to
+    /// identify the submission, so a producer can safely retry after losing
+    /// the response (e.g. when a connection drops).
+    ///
+    /// The first submission under a key is handled, and its result kept;
+    /// submitting the same transaction under that key again replays the
+    /// result rather than applying it twice. Keys are scoped to the client
+    /// and kept for the engine's [`ack_retention`](Self::ack_retention),
+    /// after which a retry is handled as a new submission.
+    pub fn handle_keyed(&self, key: &str, transaction: &Transaction) -> Ack {
+        self.handle_inspecting(Some(key), transaction, |_, _| ()).0
+    }
+
+    /// As [`handle_keyed`](Self::handle_keyed), or [`handle`](Self::handle)
+    /// without a key, calling `inspect` with the sequence number and the
+    /// client's account if the transaction is applied now. The account is
+    /// inspected under the same lock, so as the transaction left it.
+    pub fn handle_inspecting<R>(
+        &self,
+        key: Option<&str>,
+        transaction: &Transaction,
+        inspect: impl FnOnce(u64, &Account) -> R,
+    ) -> (Ack, Option<R>) {
+        let shard = self.shard_index(transaction.client_id);
+        let Some(key) = key else {
+            let (result, inspected) = self.apply(shard, transaction, inspect);
+            return (Ack::Handled(result), inspected);
+        };
+        let mut acks = self.acks[shard]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((original, result)) = acks.get(&(transaction.client_id, key.to_owned())) {
+            let ack = if original == transaction {
+                Ack::Replayed(result.clone())
+            } else {
+                Ack::KeyConflict
+            };
+            return (ack, None);
+        }
+        let (result, inspected) = self.apply(shard, transaction, inspect);
+        acks.insert(
+            (transaction.client_id, key.to_owned()),
+            (transaction.clone(), result.clone()),
+        );
+        (Ack::Handled(result), inspected)
+    }
+
+    fn apply<R>(
+        &self,
+        shard: usize,
+        transaction: &Transaction,
+        inspect: impl FnOnce(u64, &Account) -> R,
+    ) -> (Result<u64, TransactionNotApplied>, Option<R>) {
+        let mut engine = self.lock(shard);
+        let result = engine.handle(transaction);
+        self.count(&result);
+        let inspected = match &result {
+            Ok(sequence) => engine
+                .store()
+                .get_account(transaction.client_id)
+                .map(|account| inspect(*sequence, account)),
+            Err(_) => None,
+        };
+        (result, inspected)
+    }
+
+    /// The sequence number of the most recently applied transaction, on any
+    /// shard.
+    pub fn last_sequence(&self) -> u64 {
+        self.lock(0).last_sequence()
+    }
+
+    /// The engine's own accounts, summed across shards.
+    pub fn system_accounts(&self) -> SystemAccounts {
+        (0..self.shards.len())
+            .map(|shard| self.lock(shard).system_accounts().clone())
+            .fold(SystemAccounts::default(), |sum, shard| sum + shard)
+    }
+
+    /// Registers a sink made by `make_sink` with every shard (see
+    /// [`TxEngine::add_event_sink`]), so it receives a
+    /// [`LedgerEvent`](crate::ledger::LedgerEvent) for
+    /// every subsequent change to an account on that shard.
+    pub fn add_event_sinks(&self, mut make_sink: impl FnMut() -> Box<dyn EventSink>) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).add_event_sink(make_sink());
+        }
+    }
+
+    /// Takes the events raised on every shard since the last call.
+    pub fn drain_events(&self) -> Vec<EngineEvent> {
+        let mut events = vec![];
+        for shard in 0..self.shards.len() {
+            events.extend(self.lock(shard).drain_events());
+        }
+        events
+    }
+
+    /// Generates statements for all accounts, ordered by client.
+    ///
+    /// Shards are locked one at a time, so this is only a consistent
+    /// snapshot across clients once all callers have stopped handling
+    /// transactions.
+    pub fn account_statements(&self) -> Vec<AccountStatement> {
+        self.statement_view().statements().to_vec()
+    }
+
+    /// The client's statements, one per currency they've used (see
+    /// [`Account::statements`]), locking only the shard they're on. Empty if
+    /// they have no account.
+    pub fn client_statements(&self, client_id: u16) -> Vec<AccountStatement> {
+        self.shard(client_id)
+            .store()
+            .get_account(client_id)
+            .map_or_else(Vec::new, |account| account.statements().collect())
+    }
+
+    /// As [`inspect::page`], across every shard, locking one at a time.
+    /// Each account on the page has a statement per currency it's used, so
+    /// a page may hold more than `limit` statements.
+    ///
+    /// In client order, each shard's accounts are only read up to the end
+    /// of the page. Other orders sort every matching account.
+    pub fn statement_page(
+        &self,
+        query: &InspectQuery,
+        order: StatementOrder,
+        after: Option<u16>,
+        limit: usize,
+    ) -> Result<Page, String> {
+        let limit = limit.clamp(1, inspect::MAX_PAGE_SIZE);
+        let mut clients: Vec<u16> = match order {
+            StatementOrder::Client => {
+                // The page's accounts are among each shard's first after the
+                // cursor.
+                let mut clients: Vec<u16> = (0..self.shards.len())
+                    .flat_map(|shard| {
+                        self.lock(shard)
+                            .store()
+                            .accounts_after(after)
+                            .filter(|account| query.matches(account))
+                            .take(limit + 1)
+                            .map(Account::client)
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                clients.sort_unstable();
+                clients
+            }
+            _ => {
+                let mut statements: Vec<AccountStatement> = (0..self.shards.len())
+                    .flat_map(|shard| inspect::select(self.lock(shard).store(), query))
+                    .collect();
+                inspect::skip_to_cursor(&mut statements, order, after)?;
+                statements.iter().map(AccountStatement::client).collect()
+            }
+        };
+        clients.truncate(limit + 1);
+        let next = if clients.len() > limit {
+            clients.truncate(limit);
+            clients.last().copied()
+        } else {
+            None
+        };
+        let statements = clients
+            .into_iter()
+            .flat_map(|client| self.client_statements(client))
+            .collect();
+        Ok(Page { statements, next })
+    }
+
+    /// As [`TxEngine::account_history`], locking only the shard the client
+    /// is on.
+    pub fn account_history(&self, client_id: u16) -> Vec<HistoryEntry> {
+        self.shard(client_id).account_history(client_id)
+    }
+
+    /// As [`account_statements`](Self::account_statements), as a view that
+    /// can be shared and read while transactions are handled. Shards are
+    /// read on several threads, each holding one shard's lock at a time.
+    pub fn statement_view(&self) -> StatementView {
+        let shards: Vec<usize> = (0..self.shards.len()).collect();
+        statements::map_chunks(&shards, 1, |_, shards| {
+            shards
+                .iter()
+                .flat_map(|shard| {
+                    self.lock(*shard)
+                        .store()
+                        .account_statements()
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        })
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .into()
+    }
+
+    /// Replaces the policies every shard applies to later transactions (see
+    /// [`TxEngine::set_config`]).
+    pub fn set_config(&self, config: EngineConfig) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).set_config(config.clone());
+        }
+    }
+
+    /// Sets where every shard gets the time transactions happened (see
+    /// [`TxEngine::set_clock`]).
+    pub fn set_clock<C: Clock + Clone + 'static>(&self, clock: C) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).set_clock(clock.clone());
+        }
+    }
+
+    /// Sets where every shard looks up conversion rates (see
+    /// [`TxEngine::set_rate_provider`]).
+    pub fn set_rate_provider<R: RateProvider + Clone + 'static>(&self, rates: R) {
+        for shard in 0..self.shards.len() {
+            self.lock(shard).set_rate_provider(rates.clone());
+        }
+    }
+
+    /// As [`TxEngine::lock_account`], locking only the shard the client is
+    /// on.
+    pub fn lock_account(&self, client_id: u16, scope: LockScope) -> Option<LockScope> {
+        self.shard(client_id).lock_account(client_id, scope)
+    }
+
+    /// A copy of the whole engine's state as one [`TxEngine`], e.g. to write
+    /// a snapshot with [`crate::snapshot::write_snapshot`]. Every shard is
+    /// locked while it's copied, so it's a consistent cut, as with
+    /// [`cutover`](Self::cutover).
+    pub fn snapshot(&self) -> TxEngine<InMemoryStore> {
+        let shards: Vec<_> = (0..self.shards.len())
+            .map(|shard| self.lock(shard))
+            .collect();
+        let shards: Vec<&TxEngine<InMemoryStore>> = shards.iter().map(|shard| &**shard).collect();
+        TxEngine::merge(&shards)
+    }
+
+    /// Closes the current ledger period, returning its statements, system
+    /// accounts, events and counts, and starts the next one. Accounts carry
+    /// over into the next period unchanged.
+    ///
+    /// Every shard is locked for the duration, so the close is a consistent
+    /// cut: each transaction is either in this period or the next.
+    pub fn cutover(&self) -> PeriodClose {
+        let mut shards: Vec<_> = (0..self.shards.len())
+            .map(|shard| self.lock(shard))
+            .collect();
+        let last_sequence = shards[0].last_sequence();
+        let first_sequence = self.period_start.swap(last_sequence, Ordering::Relaxed) + 1;
+        let mut statements = vec![];
+        let mut system = SystemAccounts::default();
+        let mut events = vec![];
+        for shard in &mut shards {
+            statements.extend(shard.store().account_statements());
+            system = system + shard.system_accounts().clone();
+            events.extend(shard.drain_events());
+        }
+        statements.sort_by_key(|statement| statement.client());
+        PeriodClose {
+            summary: PeriodSummary {
+                period: self.period.fetch_add(1, Ordering::Relaxed),
+                first_sequence: (first_sequence <= last_sequence).then_some(first_sequence),
+                last_sequence,
+                applied: last_sequence + 1 - first_sequence,
+                rejected: self.period_rejected.swap(0, Ordering::Relaxed),
+            },
+            statements,
+            system,
+            events,
+        }
+    }
+
+    /// Counts a result towards the current period. Only called with the
+    /// transaction's shard locked, so no cutover is in progress.
+    fn count(&self, result: &Result<u64, TransactionNotApplied>) {
+        if result.is_err() {
+            self.period_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn shard(&self, client_id: u16) -> MutexGuard<'_, TxEngine<InMemoryStore>> {
+        self.lock(self.shard_index(client_id))
+    }
+
+    fn shard_index(&self, client_id: u16) -> usize {
+        usize::from(client_id) % self.shards.len()
+    }
+
+    fn lock(&self, shard: usize) -> MutexGuard<'_, TxEngine<InMemoryStore>> {
+        // A panic while handling a transaction leaves the shard's accounts
+        // as they were at the point of the panic. There's nothing better to
+        // recover to, so carry on rather than poisoning every later call.
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::{money, Money};
+    use crate::transaction::TransactionInfo;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn tx(client_id: u16, transaction_id: u32, info: TransactionInfo) -> Transaction {
+        Transaction {
+            client_id,
+            transaction_id,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn per_client_ordering_preserved_across_threads() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 4);
+        let sequences: Vec<Vec<(u16, u64)>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..8u16)
+                .map(|worker| {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        // Each worker owns a distinct set of clients, taking
+                        // them in descending order and interleaving their
+                        // transactions, so accounts are opened out of client
+                        // order and every shard is contended. Every
+                        // withdrawal only succeeds if the preceding deposit
+                        // was applied first.
+                        let clients: Vec<u16> = (1..=64u16)
+                            .rev()
+                            .filter(|client| client % 8 == worker)
+                            .collect();
+                        let mut sequences = vec![];
+                        for round in 0..50u32 {
+                            for &client in &clients {
+                                let id = u32::from(client) * 1_000 + round * 2;
+                                let amount = money!(10) + Money::from(i64::from(round));
+                                let deposit = TransactionInfo::Deposit(amount.clone());
+                                let deposited = engine.handle(&tx(client, id, deposit)).unwrap();
+                                let withdrawal = TransactionInfo::Withdrawal(amount);
+                                let withdrawn =
+                                    engine.handle(&tx(client, id + 1, withdrawal)).unwrap();
+                                sequences.extend([(client, deposited), (client, withdrawn)]);
+                            }
+                        }
+                        for &client in &clients {
+                            let deposit = TransactionInfo::Deposit(Money::from(i64::from(client)));
+                            let id = u32::from(client) * 1_000 + 999;
+                            let sequence = engine.handle(&tx(client, id, deposit)).unwrap();
+                            sequences.push((client, sequence));
+                        }
+                        sequences
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        // Every applied transaction got its own sequence number, and each
+        // client's were applied in the order they were submitted.
+        assert_eq!(engine.last_sequence(), 64 * 101);
+        let mut last = HashMap::new();
+        for (client, sequence) in sequences.into_iter().flatten() {
+            let previous = last.insert(client, sequence).unwrap_or(0);
+            assert!(sequence > previous, "client {} out of order", client);
+        }
+        let deposited: i64 = (1..=64).sum();
+        assert_eq!(engine.system_accounts().escrow, -Money::from(deposited));
+
+        // Statements come out in client order, whatever order the accounts
+        // were opened in.
+        let statements = engine.account_statements();
+        let clients: Vec<u16> = statements
+            .iter()
+            .map(|statement| statement.client())
+            .collect();
+        assert_eq!(clients, (1..=64).collect::<Vec<u16>>());
+        for statement in &statements {
+            assert_eq!(
+                statement.total(),
+                &Money::from(i64::from(statement.client()))
+            );
+        }
+    }
+
+    #[test]
+    fn clients_on_other_shards_do_not_contend() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 2);
+        let (done, finished) = mpsc::channel();
+        thread::scope(|scope| {
+            // Hold client 1's shard for the duration of the test.
+            let _guard = engine.shard(1);
+            let engine = &engine;
+            scope.spawn(move || {
+                let deposit = TransactionInfo::Deposit(money!(5));
+                engine.handle(&tx(2, 1, deposit)).unwrap();
+                done.send(()).unwrap();
+            });
+            finished
+                .recv_timeout(Duration::from_secs(10))
+                .expect("client 2 blocked by client 1's shard");
+        });
+    }
+
+    #[test]
+    fn keyed_retries_replay_result() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 2);
+        let deposit = tx(1, 1, TransactionInfo::Deposit(money!(10)));
+        assert_eq!(engine.handle_keyed("a", &deposit), Ack::Handled(Ok(1)));
+        // The connection dropped before the producer saw the ack.
+        assert_eq!(engine.handle_keyed("a", &deposit), Ack::Replayed(Ok(1)));
+        assert_eq!(engine.last_sequence(), 1);
+
+        // Failures are replayed too, rather than retried.
+        let withdrawal = tx(1, 2, TransactionInfo::Withdrawal(money!(20)));
+        let insufficient = Err(TransactionNotApplied::InsufficientFunds(money!(10)));
+        assert_eq!(
+            engine.handle_keyed("b", &withdrawal),
+            Ack::Handled(insufficient.clone())
+        );
+        assert_eq!(
+            engine.handle_keyed("b", &withdrawal),
+            Ack::Replayed(insufficient)
+        );
+
+        // A key reused for another transaction is refused.
+        let other = tx(1, 3, TransactionInfo::Deposit(money!(5)));
+        assert_eq!(engine.handle_keyed("a", &other), Ack::KeyConflict);
+        // Keys are per client.
+        let other_client = tx(2, 4, TransactionInfo::Deposit(money!(5)));
+        assert_eq!(engine.handle_keyed("a", &other_client), Ack::Handled(Ok(2)));
+
+        let statements = engine.account_statements();
+        assert_eq!(statements[0].total(), &money!(10));
+    }
+
+    #[test]
+    fn keyed_results_expire() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 1)
+            .with_ack_retention(Duration::ZERO);
+        let deposit = tx(1, 1, TransactionInfo::Deposit(money!(10)));
+        assert_eq!(engine.handle_keyed("a", &deposit), Ack::Handled(Ok(1)));
+        // Past retention the key is forgotten, so the retry is handled again
+        // (and refused by the engine's own duplicate check).
+        assert!(matches!(
+            engine.handle_keyed("a", &deposit),
+            Ack::Handled(Err(_))
+        ));
+        assert_eq!(engine.acks[0].lock().unwrap().len(), 1);
+
+        let kept = SharedTxEngine::with_shards(EngineConfig::default(), 1);
+        assert_eq!(kept.ack_retention(), DEFAULT_ACK_RETENTION);
+        kept.handle_keyed("a", &deposit);
+        assert_eq!(kept.handle_keyed("a", &deposit), Ack::Replayed(Ok(1)));
+    }
+
+    #[test]
+    fn cutover_closes_period_and_carries_on() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 2);
+        engine
+            .handle(&tx(1, 1, TransactionInfo::Deposit(money!(10))))
+            .unwrap();
+        engine
+            .handle(&tx(2, 2, TransactionInfo::Deposit(money!(5))))
+            .unwrap();
+        engine
+            .handle(&tx(2, 3, TransactionInfo::Withdrawal(money!(50))))
+            .unwrap_err();
+
+        let close = engine.cutover();
+        assert_eq!(
+            close.summary,
+            PeriodSummary {
+                period: 1,
+                first_sequence: Some(1),
+                last_sequence: 2,
+                applied: 2,
+                rejected: 1,
+            }
+        );
+        assert_eq!(close.statements.len(), 2);
+        assert_eq!(close.system.escrow, money!(-15));
+
+        // Nothing happened in the second period.
+        let close = engine.cutover();
+        assert_eq!(close.summary.period, 2);
+        assert_eq!(close.summary.first_sequence, None);
+        assert_eq!((close.summary.applied, close.summary.rejected), (0, 0));
+        assert_eq!(close.statements.len(), 2);
+
+        // Balances carry over, and sequence numbers keep increasing.
+        engine
+            .handle(&tx(1, 4, TransactionInfo::Withdrawal(money!(4))))
+            .unwrap();
+        let close = engine.cutover();
+        assert_eq!(close.summary.first_sequence, Some(3));
+        assert_eq!(close.summary.applied, 1);
+        assert_eq!(close.statements[0].total(), &money!(6));
+
+        let dir = std::env::temp_dir().join(format!("pe-period-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let written = close
+            .write_reports(&dir, &crate::account::StatementOptions::default())
+            .unwrap();
+        assert_eq!(written.len(), 4);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("period_3_statements.csv")).unwrap(),
+            "client,available,held,total,locked\n1,6,0,6,false\n2,5,0,5,false\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn statements_paged_across_shards() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 3);
+        for client in 1..=7 {
+            let amount = Money::from_scaled(i64::from(client % 4), 0);
+            engine
+                .handle(&tx(
+                    client,
+                    u32::from(client),
+                    TransactionInfo::Deposit(amount),
+                ))
+                .unwrap();
+        }
+        engine
+            .handle(&Transaction {
+                currency: Some("EUR".into()),
+                ..tx(2, 8, TransactionInfo::Deposit(money!(1)))
+            })
+            .unwrap();
+        engine.lock_account(6, LockScope::BlockAll).unwrap();
+        let pages = |query: &InspectQuery, order: StatementOrder| {
+            let mut pages = vec![];
+            let mut after = None;
+            loop {
+                let page = engine.statement_page(query, order, after, 3).unwrap();
+                pages.push(
+                    page.statements
+                        .iter()
+                        .map(AccountStatement::client)
+                        .collect::<Vec<_>>(),
+                );
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => return pages,
+                }
+            }
+        };
+        let all = InspectQuery::default();
+        // Client 2 has a statement per currency.
+        assert_eq!(
+            pages(&all, StatementOrder::Client),
+            [vec![1, 2, 2, 3], vec![4, 5, 6], vec![7]]
+        );
+        assert_eq!(
+            pages(&all, StatementOrder::TotalDesc),
+            [vec![3, 7, 2, 2], vec![6, 1, 5], vec![4]]
+        );
+        let locked = InspectQuery {
+            locked: true,
+            ..all.clone()
+        };
+        assert_eq!(pages(&locked, StatementOrder::Client), [vec![6]]);
+        assert!(engine
+            .statement_page(&locked, StatementOrder::HeldDesc, Some(1), 3)
+            .is_err());
+    }
+
+    #[test]
+    fn transaction_ids_unique_across_shards() {
+        let engine = SharedTxEngine::with_shards(EngineConfig::default(), 2);
+        // Clients 1 and 2 are on different shards.
+        engine
+            .handle(&tx(1, 1, TransactionInfo::Deposit(money!(10))))
+            .unwrap();
+        assert_eq!(
+            engine.handle(&tx(2, 1, TransactionInfo::Deposit(money!(5)))),
+            Err(TransactionNotApplied::RepeatTransaction(1))
+        );
+        assert_eq!(
+            engine.handle(&tx(2, 1, TransactionInfo::Dispute(None))),
+            Err(TransactionNotApplied::ClientMismatch(1))
+        );
+        // An ID is only taken once its transaction is applied.
+        assert!(engine
+            .handle(&tx(2, 2, TransactionInfo::Withdrawal(money!(5))))
+            .is_err());
+        engine
+            .handle(&tx(1, 2, TransactionInfo::Deposit(money!(1))))
+            .unwrap();
+        assert_eq!(
+            engine.handle(&tx(2, 2, TransactionInfo::Deposit(money!(1)))),
+            Err(TransactionNotApplied::RepeatTransaction(2))
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Detached signatures over statement files (see
+//! [`crate::RunOptions::sign_statements`]), so consumers can check the
+//! statements they ingest are exactly those the engine wrote.
+//!
+//! Statements are signed with Ed25519, the signing key's 32 bytes being the
+//! key seed, taken from a [`KeyProvider`] (a key file, or a key management
+//! service). The signature is over the statement file's bytes exactly as
+//! written, and is written alongside it as 128 hex digits; consumers verify
+//! it with the public key, 64 hex digits, which can be shared freely.
+//! Signing and verifying require the `signing` feature.
+
+use crate::encryption::{Key, KeyProvider};
+use crate::sha256::to_hex;
+use std::error::Error;
+use std::path::PathBuf;
+
+/// Options for signing the statements a run writes.
+pub struct SigningOptions {
+    /// Provides the signing key.
+    pub key: Box<dyn KeyProvider>,
+    /// Write the signature to this path.
+    pub signature: PathBuf,
+}
+
+/// Signs statement files with an Ed25519 key.
+pub struct StatementSigner {
+    key: Key,
+}
+
+impl StatementSigner {
+    pub fn new(key: Key) -> Self {
+        Self { key }
+    }
+
+    /// Signature over `statements`, in hex.
+    pub fn sign(&self, statements: &[u8]) -> Result<String, Box<dyn Error>> {
+        Ok(to_hex(&sign(&self.key, statements)?))
+    }
+
+    /// The public key verifying the signer's signatures, in hex.
+    pub fn public_key(&self) -> Result<String, Box<dyn Error>> {
+        Ok(to_hex(&public_key(&self.key)?))
+    }
+}
+
+/// Checks `signature` is the signature over `statements` by the key with
+/// `public_key`, both in hex, ignoring surrounding whitespace.
+pub fn verify(public_key: &str, statements: &[u8], signature: &str) -> Result<(), Box<dyn Error>> {
+    let public_key = from_hex(public_key).ok_or("A public key must be 64 hex digits.")?;
+    let signature = from_hex(signature).ok_or("A signature must be 128 hex digits.")?;
+    verify_bytes(&public_key, statements, &signature)
+}
+
+#[cfg(feature = "signing")]
+pub(crate) fn sign(key: &Key, message: &[u8]) -> Result<[u8; 64], Box<dyn Error>> {
+    use ed25519_dalek::Signer;
+    let key = ed25519_dalek::SigningKey::from_bytes(key.as_bytes());
+    Ok(key.sign(message).to_bytes())
+}
+
+#[cfg(feature = "signing")]
+pub(crate) fn public_key(key: &Key) -> Result<[u8; 32], Box<dyn Error>> {
+    let key = ed25519_dalek::SigningKey::from_bytes(key.as_bytes());
+    Ok(key.verifying_key().to_bytes())
+}
+
+#[cfg(feature = "signing")]
+fn verify_bytes(
+    public_key: &[u8; 32],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), Box<dyn Error>> {
+    let public_key = ed25519_dalek::VerifyingKey::from_bytes(public_key)
+        .map_err(|_| "Not a valid Ed25519 public key.")?;
+    let signature = ed25519_dalek::Signature::from_bytes(signature);
+    public_key.verify_strict(message, &signature).map_err(|_| {
+        "Signature doesn't match: the statements were modified, or signed with another key.".into()
+    })
+}
+
+#[cfg(not(feature = "signing"))]
+pub(crate) fn sign(_key: &Key, _message: &[u8]) -> Result<[u8; 64], Box<dyn Error>> {
+    Err("Signing requires the `signing` feature.".into())
+}
+
+#[cfg(not(feature = "signing"))]
+pub(crate) fn public_key(_key: &Key) -> Result<[u8; 32], Box<dyn Error>> {
+    Err("Signing requires the `signing` feature.".into())
+}
+
+#[cfg(not(feature = "signing"))]
+fn verify_bytes(
+    _public_key: &[u8; 32],
+    _message: &[u8],
+    _signature: &[u8; 64],
+) -> Result<(), Box<dyn Error>> {
+    Err("Verifying signatures requires the `signing` feature.".into())
+}
+
+/// Parses exactly `N` bytes written as hex digits, ignoring surrounding
+/// whitespace.
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let hex = hex.trim();
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hex_parsed() {
+        assert_eq!(from_hex::<2>(" 0aff\n"), Some([0x0a, 0xff]));
+        assert_eq!(from_hex::<2>("0aff00"), None);
+        assert_eq!(from_hex::<2>("0afg"), None);
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signatures_verified() {
+        // RFC 8032's first test vector, signing an empty message.
+        let key = Key::from_hex("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+            .unwrap();
+        let signer = StatementSigner::new(key);
+        let public_key = signer.public_key().unwrap();
+        assert_eq!(
+            public_key,
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+        assert_eq!(
+            signer.sign(b"").unwrap(),
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e06522490155\
+             5fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        );
+
+        let statements = b"client,available,held,total,locked\n1,1.5,0,1.5,false\n";
+        let signature = signer.sign(statements).unwrap();
+        verify(&public_key, statements, &signature).unwrap();
+        let modified = b"client,available,held,total,locked\n1,9.5,0,9.5,false\n";
+        assert!(verify(&public_key, modified, &signature).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Saved engine state snapshots, e.g. for day-over-day diffs (see
+//! [`crate::diff`]) or offline backfills.
+//!
+//! A snapshot is the engine serialized as versioned JSON (see
+//! [`crate::STATE_VERSION`]), optionally compressed, after a header line
+//! recording its encoding, length and SHA-256. The length and checksum are
+//! verified before anything is restored, so a truncated or damaged snapshot
+//! is refused rather than silently restored with accounts missing.
+
+use crate::account_store::InMemoryStore;
+use crate::sha256::to_hex;
+use crate::transaction_engine::TxEngine;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::io::{Read, Write};
+
+/// Starts the header line of every snapshot.
+const MAGIC: &str = "payments-engine-snapshot";
+
+/// How a snapshot's state is compressed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard, only available with the `zstd` feature.
+    Zstd,
+}
+
+impl Compression {
+    fn name(self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+        }
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown snapshot compression {:?}", s)),
+        }
+    }
+}
+
+/// Writes a snapshot of the engine's state.
+pub fn write_snapshot<W: Write>(
+    mut writer: W,
+    engine: &TxEngine<InMemoryStore>,
+    compression: Compression,
+) -> Result<(), Box<dyn Error>> {
+    let payload = compress(serde_json::to_vec(engine)?, compression)?;
+    writeln!(
+        writer,
+        "{} {} {} {}",
+        MAGIC,
+        compression.name(),
+        payload.len(),
+        to_hex(&Sha256::digest(&payload))
+    )?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads a snapshot written with [`write_snapshot`], failing if it's corrupt.
+///
+/// Snapshots from before they had a header (plain JSON, starting with `{`)
+/// are still read, though there's nothing to verify them against.
+pub fn read_snapshot<R: Read>(mut reader: R) -> Result<TxEngine<InMemoryStore>, Box<dyn Error>> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    if bytes.first() == Some(&b'{') {
+        return Ok(serde_json::from_slice(&bytes)?);
+    }
+    let header_end = bytes
+        .iter()
+        .position(|byte| *byte == b'\n')
+        .ok_or_else(|| corrupt("no header"))?;
+    let header = std::str::from_utf8(&bytes[..header_end]).map_err(|_| corrupt("bad header"))?;
+    let payload = &bytes[header_end + 1..];
+    let [magic, compression, length, checksum] =
+        <[&str; 4]>::try_from(header.split(' ').collect::<Vec<_>>())
+            .map_err(|_| corrupt("bad header"))?;
+    if magic != MAGIC {
+        return Err("Not a snapshot.".into());
+    }
+    let compression: Compression = compression.parse()?;
+    let length: usize = length.parse().map_err(|_| corrupt("bad header"))?;
+    if payload.len() != length {
+        return Err(corrupt(&format!(
+            "expected {} bytes of state, found {}",
+            length,
+            payload.len()
+        )));
+    }
+    if to_hex(&Sha256::digest(payload)) != checksum {
+        return Err(corrupt("checksum mismatch"));
+    }
+    Ok(serde_json::from_slice(&decompress(payload, compression)?)?)
+}
+
+fn corrupt(detail: &str) -> Box<dyn Error> {
+    format!("Corrupt snapshot: {}.", detail).into()
+}
+
+fn compress(state: Vec<u8>, compression: Compression) -> Result<Vec<u8>, Box<dyn Error>> {
+    match compression {
+        Compression::None => Ok(state),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(zstd::encode_all(state.as_slice(), 0)?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(zstd_unavailable()),
+    }
+}
+
+fn decompress(payload: &[u8], compression: Compression) -> Result<Vec<u8>, Box<dyn Error>> {
+    match compression {
+        Compression::None => Ok(payload.to_vec()),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Ok(zstd::decode_all(payload)?),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => Err(zstd_unavailable()),
+    }
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_unavailable() -> Box<dyn Error> {
+    "Compressed snapshots require the `zstd` feature.".into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::AccountStore;
+    use crate::money::money;
+    use crate::transaction::{Transaction, TransactionInfo};
+
+    fn engine() -> TxEngine<InMemoryStore> {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        for client_id in 1..=3 {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    info: TransactionInfo::Deposit(money!(10)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        engine
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &engine(), Compression::None).unwrap();
+        let restored = read_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.store().accounts().count(), 3);
+
+        // Snapshots written before the header was added.
+        let legacy = serde_json::to_vec(&engine()).unwrap();
+        let restored = read_snapshot(legacy.as_slice()).unwrap();
+        assert_eq!(restored.store().accounts().count(), 3);
+    }
+
+    #[test]
+    fn corruption_detected() {
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &engine(), Compression::None).unwrap();
+
+        let truncated = &snapshot[..snapshot.len() - 10];
+        let err = read_snapshot(truncated).err().unwrap();
+        assert!(err.to_string().starts_with("Corrupt snapshot: expected"));
+
+        // Flip a digit in a balance, keeping the JSON valid.
+        let mut damaged = snapshot.clone();
+        let header_end = damaged.iter().position(|byte| *byte == b'\n').unwrap();
+        let digit = header_end
+            + damaged[header_end..]
+                .windows(2)
+                .position(|window| window == b"10")
+                .unwrap();
+        damaged[digit] = b'9';
+        let err = read_snapshot(damaged.as_slice()).err().unwrap();
+        assert_eq!(err.to_string(), "Corrupt snapshot: checksum mismatch.");
+
+        let err = read_snapshot(&b"something else\n{}"[..]).err().unwrap();
+        assert_eq!(err.to_string(), "Corrupt snapshot: bad header.");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        let mut snapshot = vec![];
+        write_snapshot(&mut snapshot, &engine(), Compression::Zstd).unwrap();
+        let restored = read_snapshot(snapshot.as_slice()).unwrap();
+        assert_eq!(restored.store().accounts().count(), 3);
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn zstd_unavailable() {
+        let err = write_snapshot(vec![], &engine(), Compression::Zstd).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Compressed snapshots require the `zstd` feature."
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! Generating and writing statements for large stores on several threads.
+//!
+//! Work is split into ordered chunks, each handled on a scoped thread, so
+//! results come out in the same order as they would on one thread. Small
+//! inputs are handled on the calling thread, as spawning threads would cost
+//! more than it saves.
+
+use crate::account::{Account, AccountStatement};
+use crate::account_store::AccountStore;
+use crate::intern::Interned;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::thread;
+
+/// Fewest accounts (or rows) worth handing to a thread of their own.
+const MIN_CHUNK: usize = 4096;
+
+/// Calls `f` with consecutive chunks of `items` of at least `min_chunk`
+/// items, and the index of each chunk's first item, on a thread per chunk if
+/// there's more than one. Returns the results in order.
+pub(crate) fn map_chunks<T: Sync, R: Send>(
+    items: &[T],
+    min_chunk: usize,
+    f: impl Fn(usize, &[T]) -> R + Sync,
+) -> Vec<R> {
+    let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let size = items.len().div_ceil(threads).max(min_chunk).max(1);
+    if items.len() <= size {
+        return vec![f(0, items)];
+    }
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(size)
+            .enumerate()
+            .map(|(index, chunk)| scope.spawn(move || f(index * size, chunk)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Statement worker panicked"))
+            .collect()
+    })
+}
+
+/// Labels statements with their currency if more than one is named, so a
+/// client's balances can be told apart, and otherwise drops the labels, so
+/// single-currency output is as it's always been. Returns whether they're
+/// labelled.
+pub fn label_currencies(statements: &mut [AccountStatement]) -> bool {
+    let currencies: BTreeSet<&Interned> = statements
+        .iter()
+        .filter_map(AccountStatement::currency)
+        .collect();
+    let labelled = currencies.len() > 1;
+    for statement in statements.iter_mut() {
+        let currency = match labelled {
+            true => Some(statement.currency().cloned().unwrap_or_else(|| "".into())),
+            false => None,
+        };
+        statement.set_currency(currency);
+    }
+    labelled
+}
+
+/// As [`AccountStore::account_statements`], on several threads for large
+/// stores. Statements are unordered.
+pub fn account_statements<T: AccountStore>(store: &T) -> Vec<AccountStatement> {
+    let accounts: Vec<&Account> = store.accounts().collect();
+    map_chunks(&accounts, MIN_CHUNK, |_, chunk| {
+        chunk
+            .iter()
+            .flat_map(|account| account.statements())
+            .collect::<Vec<_>>()
+    })
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Serializes `rows` as CSV with writers from `builder`, on several threads
+/// for many rows, and writes them out in order. Only the first chunk's
+/// writer writes headers, if `builder` asks for them.
+pub(crate) fn write_rows<W: Write, S: Serialize + Sync>(
+    mut writer: W,
+    rows: &[S],
+    builder: impl Fn() -> csv::WriterBuilder + Sync,
+) -> Result<(), Box<dyn Error>> {
+    let chunks = map_chunks(rows, MIN_CHUNK, |start, chunk| {
+        let mut builder = builder();
+        if start > 0 {
+            builder.has_headers(false);
+        }
+        let mut csv_writer = builder.from_writer(vec![]);
+        for row in chunk {
+            csv_writer.serialize(row)?;
+        }
+        csv_writer
+            .into_inner()
+            .map_err(|err| csv::Error::from(err.into_error()))
+    });
+    for chunk in chunks {
+        writer.write_all(&chunk?)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// An immutable view of every account's statement at one point, ordered by
+/// client.
+///
+/// Taking a view is the only time accounts are read, so it can be shared
+/// (it's cheap to clone) and read from any thread while the engine carries
+/// on applying transactions.
+#[derive(Debug, Clone, Default)]
+pub struct StatementView {
+    statements: Arc<[AccountStatement]>,
+}
+
+impl StatementView {
+    /// Takes a view of a store's accounts, on several threads for large
+    /// stores.
+    pub fn of<T: AccountStore>(store: &T) -> Self {
+        account_statements(store).into()
+    }
+
+    /// The client's statement, if they have an account. For clients with
+    /// balances in several currencies, the first in currency order.
+    pub fn get(&self, client_id: u16) -> Option<&AccountStatement> {
+        let index = self
+            .statements
+            .partition_point(|statement| statement.client() < client_id);
+        self.statements
+            .get(index)
+            .filter(|statement| statement.client() == client_id)
+    }
+
+    pub fn statements(&self) -> &[AccountStatement] {
+        &self.statements
+    }
+}
+
+impl From<Vec<AccountStatement>> for StatementView {
+    fn from(mut statements: Vec<AccountStatement>) -> Self {
+        statements.sort_by(|a, b| (a.client(), a.currency()).cmp(&(b.client(), b.currency())));
+        Self {
+            statements: statements.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::Money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+
+    #[test]
+    fn parallel_matches_serial() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        for client_id in 0..20_000u16 {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    info: TransactionInfo::Deposit(Money::from(i64::from(client_id) + 1)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+        let mut serial: Vec<_> = engine.store().account_statements().collect();
+        serial.sort_by_key(AccountStatement::client);
+        let view = StatementView::of(engine.store());
+        assert_eq!(view.statements(), serial.as_slice());
+        assert_eq!(view.get(1234).unwrap().total(), &Money::from(1235));
+        assert!(view.get(20_000).is_none());
+
+        let mut expected = csv::Writer::from_writer(vec![]);
+        for statement in &serial {
+            expected.serialize(statement).unwrap();
+        }
+        let mut written = vec![];
+        write_rows(&mut written, view.statements(), csv::WriterBuilder::new).unwrap();
+        assert_eq!(written, expected.into_inner().unwrap());
+    }
+
+    #[test]
+    fn currencies_labelled_only_when_mixed() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        let deposit = |client_id, transaction_id, currency: Option<&str>| Transaction {
+            client_id,
+            transaction_id,
+            info: TransactionInfo::Deposit(Money::from(1)),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: currency.map(Into::into),
+        };
+        engine.handle(&deposit(1, 1, Some("USD"))).unwrap();
+        engine.handle(&deposit(2, 2, None)).unwrap();
+        let mut statements = account_statements(engine.store());
+        assert!(!label_currencies(&mut statements));
+        assert!(statements.iter().all(|s| s.currency().is_none()));
+
+        engine.handle(&deposit(1, 3, Some("EUR"))).unwrap();
+        let view = StatementView::of(engine.store());
+        let mut statements = view.statements().to_vec();
+        assert!(label_currencies(&mut statements));
+        let labels: Vec<_> = statements
+            .iter()
+            .map(|s| (s.client(), s.currency().unwrap().to_string()))
+            .collect();
+        assert_eq!(
+            labels,
+            [(1, "EUR".into()), (1, "USD".into()), (2, String::new())]
+        );
+        assert_eq!(view.get(1).unwrap().currency().unwrap().to_string(), "EUR");
+    }
+}
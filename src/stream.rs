@@ -0,0 +1,267 @@
+//! Streaming sources of transactions, for running the engine as a
+//! long-lived processor rather than over a file.
+//!
+//! A source hands out messages, each a transaction as a JSON object (as in
+//! JSON Lines input, see [`crate::input`]), and is told once each has been
+//! processed. [`consume`] applies them through a [`TxEngine`] and only marks
+//! a message processed after the engine has handled it and its outcome has
+//! been recorded, so a consumer that stops part way picks up from the first
+//! message not fully handled.
+//!
+//! Sources may deliver a message again after a restart. Repeated deposits
+//! are rejected by the engine, but the engine's state must be restored (e.g.
+//! from a snapshot taken when the source was last committed) for that to
+//! hold across restarts.
+//!
+//! With the `kafka` feature, [`kafka::KafkaSource`] consumes a Kafka topic.
+
+use crate::account_store::AccountStore;
+use crate::input;
+use crate::transaction::Transaction;
+use crate::transaction_engine::{TransactionNotApplied, TxEngine};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A message from a [`TransactionSource`].
+pub struct SourceMessage<P> {
+    pub payload: Vec<u8>,
+    /// Identifies the message, to mark it processed.
+    pub position: P,
+}
+
+pub trait TransactionSource {
+    type Position;
+
+    /// The next message, or `None` if none arrived for a while.
+    fn next(&mut self) -> Result<Option<SourceMessage<Self::Position>>, Box<dyn Error>>;
+
+    /// Marks the message at `position`, and all before it, as processed.
+    fn processed(&mut self, position: Self::Position) -> Result<(), Box<dyn Error>>;
+
+    /// Makes sure everything marked processed is recorded, e.g. before
+    /// shutting down.
+    fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// What became of a message.
+#[derive(Debug, PartialEq)]
+pub enum Handled {
+    /// The message was handled by the engine, with this result. Boxed, as
+    /// transactions are much larger than malformed payloads' vectors.
+    Transaction(Box<Transaction>, Result<u64, TransactionNotApplied>),
+    /// The message isn't a valid transaction, so was skipped.
+    Malformed(Vec<u8>),
+}
+
+/// Applies transactions from `source` until `stop` is set, passing each
+/// outcome to `on_handled`, e.g. to respond to its producer or dead-letter
+/// it.
+///
+/// A message is only marked processed once `on_handled` returns. If it, or
+/// the source, fails, consuming stops with that error, leaving the message
+/// to be delivered again.
+pub fn consume<T: AccountStore, S: TransactionSource>(
+    engine: &mut TxEngine<T>,
+    source: &mut S,
+    stop: &AtomicBool,
+    mut on_handled: impl FnMut(Handled) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    while !stop.load(Ordering::Relaxed) {
+        let Some(SourceMessage { payload, position }) = source.next()? else {
+            continue;
+        };
+        let parsed = input::parse_json(&payload)
+            .ok()
+            .and_then(|raw| Transaction::try_from(raw).ok());
+        let handled = match parsed {
+            Some(transaction) => {
+                let result = engine.handle(&transaction);
+                Handled::Transaction(Box::new(transaction), result)
+            }
+            None => Handled::Malformed(payload),
+        };
+        on_handled(handled)?;
+        source.processed(position)?;
+    }
+    source.commit()
+}
+
+#[cfg(feature = "kafka")]
+pub mod kafka {
+    //! Kafka topic source.
+    //!
+    //! Offsets are stored as messages are processed and committed in the
+    //! background by the consumer (`enable.auto.offset.store` is off, so
+    //! only processed messages are ever committed), then synchronously by
+    //! [`TransactionSource::commit`].
+    use super::{SourceMessage, TransactionSource};
+    use rdkafka::config::ClientConfig;
+    use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer};
+    use rdkafka::Message;
+    use std::error::Error;
+    use std::time::Duration;
+
+    /// How long [`KafkaSource::next`] waits for a message.
+    const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+
+    pub struct KafkaSource {
+        consumer: BaseConsumer,
+    }
+
+    /// A message's topic, partition and offset.
+    pub struct KafkaPosition {
+        topic: String,
+        partition: i32,
+        offset: i64,
+    }
+
+    impl KafkaSource {
+        /// Joins consumer group `group` on the brokers (a comma separated
+        /// list of `host:port`), subscribed to `topic`. A group with no
+        /// committed offsets starts from the beginning of the topic.
+        pub fn new(brokers: &str, group: &str, topic: &str) -> Result<Self, Box<dyn Error>> {
+            let consumer: BaseConsumer = ClientConfig::new()
+                .set("bootstrap.servers", brokers)
+                .set("group.id", group)
+                .set("enable.auto.commit", "true")
+                .set("enable.auto.offset.store", "false")
+                .set("auto.offset.reset", "earliest")
+                .create()?;
+            consumer.subscribe(&[topic])?;
+            Ok(Self { consumer })
+        }
+    }
+
+    impl TransactionSource for KafkaSource {
+        type Position = KafkaPosition;
+
+        fn next(&mut self) -> Result<Option<SourceMessage<KafkaPosition>>, Box<dyn Error>> {
+            let Some(message) = self.consumer.poll(POLL_TIMEOUT) else {
+                return Ok(None);
+            };
+            let message = message?;
+            let position = KafkaPosition {
+                topic: message.topic().to_owned(),
+                partition: message.partition(),
+                offset: message.offset(),
+            };
+            Ok(Some(SourceMessage {
+                payload: message.payload().unwrap_or_default().to_vec(),
+                position,
+            }))
+        }
+
+        fn processed(&mut self, position: KafkaPosition) -> Result<(), Box<dyn Error>> {
+            // The committed offset is that of the next message to consume.
+            self.consumer
+                .store_offset(&position.topic, position.partition, position.offset + 1)?;
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), Box<dyn Error>> {
+            self.consumer.commit_consumer_state(CommitMode::Sync)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+    use crate::transaction::TransactionInfo;
+    use std::collections::VecDeque;
+
+    /// Messages in memory, numbered from zero, stopping the consumer once
+    /// they run out.
+    struct Messages<'a> {
+        messages: VecDeque<&'static str>,
+        next: usize,
+        processed: Option<usize>,
+        stop: &'a AtomicBool,
+    }
+
+    impl TransactionSource for Messages<'_> {
+        type Position = usize;
+
+        fn next(&mut self) -> Result<Option<SourceMessage<usize>>, Box<dyn Error>> {
+            let Some(message) = self.messages.pop_front() else {
+                self.stop.store(true, Ordering::Relaxed);
+                return Ok(None);
+            };
+            self.next += 1;
+            Ok(Some(SourceMessage {
+                payload: message.as_bytes().to_vec(),
+                position: self.next - 1,
+            }))
+        }
+
+        fn processed(&mut self, position: usize) -> Result<(), Box<dyn Error>> {
+            self.processed = Some(position);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn processed_only_once_handled() {
+        let stop = AtomicBool::new(false);
+        let mut source = Messages {
+            messages: VecDeque::from([
+                r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#,
+                r#"{"type":"withdrawal","client":1,"tx":2,"amount":"5"}"#,
+                "not json",
+                r#"{"type":"deposit","client":2,"tx":3,"amount":"1"}"#,
+            ]),
+            next: 0,
+            processed: None,
+            stop: &stop,
+        };
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        let mut handled = vec![];
+        let err = consume(&mut engine, &mut source, &stop, |outcome| {
+            if handled.len() == 3 {
+                return Err("Dead letter queue unavailable".into());
+            }
+            handled.push(outcome);
+            Ok(())
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Dead letter queue unavailable");
+        // The last deposit was applied, but not marked processed, so would
+        // be delivered again.
+        assert_eq!(source.processed, Some(2));
+        assert_eq!(
+            handled,
+            [
+                Handled::Transaction(
+                    Box::new(Transaction {
+                        client_id: 1,
+                        transaction_id: 1,
+                        info: TransactionInfo::Deposit(money!(2.5)),
+                        destination: None,
+                        dispute_amount: None,
+                        timestamp: None,
+                        currency: None,
+                    }),
+                    Ok(1)
+                ),
+                Handled::Transaction(
+                    Box::new(Transaction {
+                        client_id: 1,
+                        transaction_id: 2,
+                        info: TransactionInfo::Withdrawal(money!(5)),
+                        destination: None,
+                        dispute_amount: None,
+                        timestamp: None,
+                        currency: None,
+                    }),
+                    Err(TransactionNotApplied::InsufficientFunds(money!(2.5)))
+                ),
+                Handled::Malformed(b"not json".to_vec()),
+            ]
+        );
+    }
+}
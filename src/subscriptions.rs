@@ -0,0 +1,461 @@
+//! Per-account subscriptions to the ledger (see [`crate::ledger`]), so a
+//! streaming frontend sends each subscriber only the events for the clients
+//! it's allowed to follow, rather than broadcasting every account's events
+//! to everyone.
+//!
+//! Events are routed on the server: each is handed to the subscribers of
+//! its client alone, found by client ID, so the cost of an event doesn't
+//! grow with subscribers to other clients. Which clients a caller may
+//! follow is decided by an [`Authorizer`], given the caller's principal as
+//! established by the transport (e.g. a token's subject).
+//!
+//! Each subscription queues at most a fixed number of events (see
+//! [`Subscriptions::with_capacity`]). A subscriber that falls that far
+//! behind is dropped rather than slowing the engine or queueing without
+//! limit: its subscription ends, reporting [`Subscription::lagged`], and it
+//! can subscribe again and catch up from the ledger by sequence number.
+
+use crate::ledger::{EventSink, LedgerEvent};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+/// Decides which clients' events a principal may follow.
+pub trait Authorizer: Send + Sync {
+    fn may_follow(&self, principal: &str, client: u16) -> bool;
+}
+
+impl<F: Fn(&str, u16) -> bool + Send + Sync> Authorizer for F {
+    fn may_follow(&self, principal: &str, client: u16) -> bool {
+        self(principal, client)
+    }
+}
+
+/// Lets every principal follow every client, e.g. behind a gateway that
+/// already authorizes subscriptions.
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn may_follow(&self, _principal: &str, _client: u16) -> bool {
+        true
+    }
+}
+
+/// Lets each principal follow the clients it's been granted, e.g. as read
+/// from a file with [`Grants::read_csv`]. Principals without a grant may
+/// follow no one.
+#[derive(Debug, Default)]
+pub struct Grants(HashMap<String, Granted>);
+
+#[derive(Debug)]
+enum Granted {
+    Every,
+    Clients(HashSet<u16>),
+}
+
+impl Grants {
+    /// Grants `principal` the events of `client`, or of every client if
+    /// `None`.
+    pub fn grant(&mut self, principal: &str, client: Option<u16>) {
+        let granted = self
+            .0
+            .entry(principal.to_owned())
+            .or_insert_with(|| Granted::Clients(HashSet::new()));
+        match (granted, client) {
+            (Granted::Clients(clients), Some(client)) => {
+                clients.insert(client);
+            }
+            (granted, None) => *granted = Granted::Every,
+            (Granted::Every, Some(_)) => {}
+        }
+    }
+
+    /// Reads grants from a CSV of `principal,client` rows, where the client
+    /// is an ID, or `*` for every client.
+    pub fn read_csv<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct Row {
+            principal: String,
+            client: String,
+        }
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        let mut grants = Self::default();
+        for row in csv_reader.deserialize() {
+            let Row { principal, client } = row?;
+            let client = match client.as_str() {
+                "*" => None,
+                _ => Some(client.parse().map_err(|_| {
+                    format!(
+                        "{:?} granted {:?}, which isn't a client ID",
+                        principal, client
+                    )
+                })?),
+            };
+            grants.grant(&principal, client);
+        }
+        Ok(grants)
+    }
+}
+
+impl Authorizer for Grants {
+    fn may_follow(&self, principal: &str, client: u16) -> bool {
+        match self.0.get(principal) {
+            Some(Granted::Every) => true,
+            Some(Granted::Clients(clients)) => clients.contains(&client),
+            None => false,
+        }
+    }
+}
+
+/// Why a subscription wasn't made.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubscribeError {
+    /// No clients were named. Subscribing to every client isn't supported.
+    NoClients,
+    /// The principal may not follow this client.
+    Forbidden(u16),
+}
+
+impl std::fmt::Display for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SubscribeError::NoClients => write!(f, "A subscription must name at least one client"),
+            SubscribeError::Forbidden(client) => {
+                write!(f, "Not authorized to follow client {}", client)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SubscribeError {}
+
+/// Events queued for each subscription by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// The subscribers to each client's events.
+pub struct Subscriptions {
+    authorizer: Box<dyn Authorizer>,
+    capacity: usize,
+    registry: Arc<Registry>,
+}
+
+/// A subscription's end of its queue, as registered for each client it
+/// follows.
+#[derive(Clone)]
+struct Follower {
+    id: u64,
+    sender: SyncSender<LedgerEvent>,
+    lagged: Arc<AtomicBool>,
+}
+
+/// Subscribers by client.
+type Followers = HashMap<u16, Vec<Follower>>;
+
+#[derive(Default)]
+struct Registry {
+    subscribers: RwLock<Followers>,
+    next_id: AtomicU64,
+}
+
+impl Registry {
+    fn read(&self) -> RwLockReadGuard<'_, Followers> {
+        // Senders are only ever added or removed whole, so a panic elsewhere
+        // can't leave the map inconsistent.
+        self.subscribers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, Followers> {
+        self.subscribers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Removes a subscription from every client it follows.
+    fn remove(&self, id: u64, clients: &[u16]) {
+        let mut subscribers = self.write();
+        for client in clients {
+            if let Some(followers) = subscribers.get_mut(client) {
+                followers.retain(|follower| follower.id != id);
+                if followers.is_empty() {
+                    subscribers.remove(client);
+                }
+            }
+        }
+    }
+}
+
+impl Subscriptions {
+    pub fn new(authorizer: impl Authorizer + 'static) -> Self {
+        Self {
+            authorizer: Box::new(authorizer),
+            capacity: DEFAULT_CAPACITY,
+            registry: Arc::default(),
+        }
+    }
+
+    /// Queues at most `capacity` events for each subscription, dropping
+    /// subscribers that fall further behind.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        // A subscription that can't queue anything would lag on every event.
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Subscribes `principal` to the events of `clients`, if it may follow
+    /// every one of them.
+    pub fn subscribe(
+        &self,
+        principal: &str,
+        clients: &[u16],
+    ) -> Result<Subscription, SubscribeError> {
+        if clients.is_empty() {
+            return Err(SubscribeError::NoClients);
+        }
+        if let Some(&client) = clients
+            .iter()
+            .find(|&&client| !self.authorizer.may_follow(principal, client))
+        {
+            return Err(SubscribeError::Forbidden(client));
+        }
+        let mut clients = clients.to_vec();
+        clients.sort_unstable();
+        clients.dedup();
+        let id = self.registry.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::sync_channel(self.capacity);
+        let follower = Follower {
+            id,
+            sender,
+            lagged: Arc::default(),
+        };
+        let lagged = Arc::clone(&follower.lagged);
+        let mut subscribers = self.registry.write();
+        for &client in &clients {
+            subscribers
+                .entry(client)
+                .or_default()
+                .push(follower.clone());
+        }
+        Ok(Subscription {
+            id,
+            clients,
+            receiver,
+            lagged,
+            registry: Arc::clone(&self.registry),
+        })
+    }
+
+    /// Subscriptions currently following `client`.
+    pub fn subscribers(&self, client: u16) -> usize {
+        self.registry.read().get(&client).map_or(0, Vec::len)
+    }
+
+    /// A sink routing the events an engine raises to their clients'
+    /// subscribers. Add one to every engine (or shard) whose events should
+    /// reach subscribers.
+    pub fn sink(&self) -> Box<dyn EventSink> {
+        Box::new(SubscriptionSink(Arc::clone(&self.registry)))
+    }
+}
+
+struct SubscriptionSink(Arc<Registry>);
+
+impl EventSink for SubscriptionSink {
+    fn emit(&mut self, event: &LedgerEvent) {
+        let mut lagging = vec![];
+        if let Some(subscribers) = self.0.read().get(&event.client()) {
+            for follower in subscribers {
+                match follower.sender.try_send(event.clone()) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => lagging.push(follower.clone()),
+                    // Subscriptions remove themselves when dropped, so this
+                    // only happens in the moment before.
+                    Err(TrySendError::Disconnected(_)) => {}
+                }
+            }
+        }
+        for follower in lagging {
+            follower.lagged.store(true, Ordering::Relaxed);
+            let mut subscribers = self.0.write();
+            for followers in subscribers.values_mut() {
+                followers.retain(|other| other.id != follower.id);
+            }
+            subscribers.retain(|_, followers| !followers.is_empty());
+        }
+    }
+}
+
+/// A subscription to some clients' events, ended when dropped, or when it
+/// falls too far behind (see [`Subscription::lagged`]).
+pub struct Subscription {
+    id: u64,
+    clients: Vec<u16>,
+    receiver: Receiver<LedgerEvent>,
+    lagged: Arc<AtomicBool>,
+    registry: Arc<Registry>,
+}
+
+/// Why [`Subscription::next_event`] returned no event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoEvent {
+    /// None was raised within the timeout.
+    Timeout,
+    /// The subscription fell too far behind and was dropped. The events
+    /// queued before it was have all been returned.
+    Lagged,
+}
+
+impl Subscription {
+    /// The clients followed, in order.
+    pub fn clients(&self) -> &[u16] {
+        &self.clients
+    }
+
+    /// The next event, waiting up to `timeout` for one, e.g. so a frontend
+    /// can check its connection is still open in between.
+    pub fn next_event(&self, timeout: Duration) -> Result<LedgerEvent, NoEvent> {
+        self.receiver
+            .recv_timeout(timeout)
+            .map_err(|err| match err {
+                RecvTimeoutError::Timeout => NoEvent::Timeout,
+                // Only the registry holds senders, so they're only all dropped
+                // once the subscription's been dropped for lagging.
+                RecvTimeoutError::Disconnected => NoEvent::Lagged,
+            })
+    }
+
+    /// Whether the subscription fell too far behind and was dropped, so
+    /// receives no further events. The subscriber should subscribe again
+    /// and catch up from the ledger.
+    pub fn lagged(&self) -> bool {
+        self.lagged.load(Ordering::Relaxed)
+    }
+
+    /// The events raised since the last call, without waiting.
+    pub fn pending_events(&self) -> impl Iterator<Item = LedgerEvent> + '_ {
+        self.receiver.try_iter()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.registry.remove(self.id, &self.clients);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+
+    fn deposit(client_id: u16, transaction_id: u32) -> Transaction {
+        Transaction {
+            client_id,
+            transaction_id,
+            info: TransactionInfo::Deposit(money!(1)),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn grants_read() {
+        let grants =
+            Grants::read_csv("principal,client\nteam-a,1\nteam-a,2\nops,*\nops,3\n".as_bytes())
+                .unwrap();
+        assert!(grants.may_follow("team-a", 2));
+        assert!(!grants.may_follow("team-a", 3));
+        assert!(grants.may_follow("ops", 40));
+        assert!(!grants.may_follow("team-b", 1));
+        assert!(!grants.may_follow("", 1));
+        let err = Grants::read_csv("principal,client\nteam-a,all\n".as_bytes()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "\"team-a\" granted \"all\", which isn't a client ID"
+        );
+    }
+
+    #[test]
+    fn events_routed_by_client() {
+        // Each team may only follow its own range of clients.
+        let subscriptions = Subscriptions::new(|principal: &str, client: u16| {
+            matches!((principal, client), ("team-a", 1..=9) | ("team-b", 10..=19))
+        });
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        engine.add_event_sink(subscriptions.sink());
+
+        assert_eq!(
+            subscriptions.subscribe("team-a", &[1, 10]).err(),
+            Some(SubscribeError::Forbidden(10))
+        );
+        assert_eq!(
+            subscriptions.subscribe("team-a", &[]).err(),
+            Some(SubscribeError::NoClients)
+        );
+        let team_a = subscriptions.subscribe("team-a", &[2, 1, 2]).unwrap();
+        assert_eq!(team_a.clients(), [1, 2]);
+        let team_b = subscriptions.subscribe("team-b", &[10]).unwrap();
+
+        for (client, tx) in [(1, 1), (10, 2), (3, 3), (2, 4)] {
+            engine.handle(&deposit(client, tx)).unwrap();
+        }
+        let clients = |subscription: &Subscription| -> Vec<u16> {
+            subscription
+                .pending_events()
+                .map(|event| event.client())
+                .collect()
+        };
+        assert_eq!(clients(&team_a), [1, 2]);
+        assert_eq!(clients(&team_b), [10]);
+        assert_eq!(team_b.next_event(Duration::ZERO), Err(NoEvent::Timeout));
+
+        // Dropped subscriptions stop receiving events.
+        assert_eq!(subscriptions.subscribers(1), 1);
+        drop(team_a);
+        assert_eq!(subscriptions.subscribers(1), 0);
+        engine.handle(&deposit(1, 5)).unwrap();
+        assert_eq!(subscriptions.subscribers(10), 1);
+    }
+
+    #[test]
+    fn lagging_subscribers_dropped() {
+        let subscriptions = Subscriptions::new(AllowAll).with_capacity(2);
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        engine.add_event_sink(subscriptions.sink());
+        let slow = subscriptions.subscribe("slow", &[1, 2]).unwrap();
+        let fast = subscriptions.subscribe("fast", &[1]).unwrap();
+
+        for tx in 1..=2 {
+            engine.handle(&deposit(1, tx)).unwrap();
+            assert!(fast.next_event(Duration::ZERO).is_ok());
+        }
+        assert!(!slow.lagged());
+        // A third event doesn't fit in the slow subscriber's queue.
+        engine.handle(&deposit(1, 3)).unwrap();
+        assert!(slow.lagged());
+        assert_eq!(subscriptions.subscribers(1), 1);
+        assert_eq!(subscriptions.subscribers(2), 0);
+
+        // The events queued before are still returned, then it ends.
+        let sequences: Vec<u64> = slow
+            .pending_events()
+            .map(|event| event.sequence())
+            .collect();
+        assert_eq!(sequences, [1, 2]);
+        assert_eq!(slow.next_event(Duration::ZERO), Err(NoEvent::Lagged));
+        assert_eq!(fast.next_event(Duration::ZERO).unwrap().sequence(), 3);
+    }
+}
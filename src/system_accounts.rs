@@ -0,0 +1,335 @@
+//! Accounts maintained by the engine itself, holding the other side of every
+//! client movement.
+//!
+//! Balances follow the same sign convention as client accounts: credits
+//! (what the system owes or has earned) are positive, debits are negative.
+//! Every movement is posted twice, so client totals and system balances
+//! always sum to zero.
+//!
+//! Once balances name currencies, the accounts are also kept for each
+//! currency, so the books can be checked to balance in each one.
+
+use crate::intern::Interned;
+use crate::money::{Money, OUTPUT_SCALE};
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Write;
+use std::ops::Add;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SystemAccounts {
+    /// Funds held in escrow for clients: debited with deposits, credited
+    /// with withdrawals and chargebacks paid back out.
+    pub escrow: Money,
+    /// Fees charged to clients.
+    pub fee_income: Money,
+    /// Client overdrafts written off, in practice always from chargebacks
+    /// for deposits that were already withdrawn. Credited back as overdrawn
+    /// clients pay in again.
+    pub chargeback_loss: Money,
+    /// The recoverable side of [`SystemAccounts::chargeback_loss`]: client
+    /// overdrafts awaiting recovery.
+    pub suspense: Money,
+    /// Interest paid to clients (see [`crate::interest`]).
+    #[serde(default)]
+    pub interest_expense: Money,
+    /// The same accounts for each currency named, by currency. The balances
+    /// above also include postings for balances that don't name one, and
+    /// sum amounts in every currency as they are.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub currencies: BTreeMap<Interned, SystemAccounts>,
+}
+
+/// A single row of the system statement.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SystemStatement {
+    pub account: &'static str,
+    /// Only labelled if more than one currency is named.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Interned>,
+    pub balance: Money,
+}
+
+impl SystemAccounts {
+    /// Returns the balances after a client's total in `currency` moves from
+    /// `before` to `after`, with `external` funds moving into (positive) or
+    /// out of (negative) the system and `fee` charged. `None` if a balance
+    /// would overflow.
+    pub(crate) fn checked_post(
+        &self,
+        currency: Option<&Interned>,
+        before: &Money,
+        after: &Money,
+        external: &Money,
+        fee: &Money,
+    ) -> Option<Self> {
+        self.checked_post_with(currency, |system| {
+            let written_off = overdraft(after).checked_sub(&overdraft(before))?;
+            Some(Self {
+                escrow: system.escrow.checked_sub(external)?,
+                fee_income: system.fee_income.checked_add(fee)?,
+                chargeback_loss: system.chargeback_loss.checked_sub(&written_off)?,
+                suspense: system.suspense.checked_add(&written_off)?,
+                interest_expense: system.interest_expense.clone(),
+                currencies: BTreeMap::new(),
+            })
+        })
+    }
+
+    /// As [`SystemAccounts::checked_post`], for `interest` paid to a client.
+    pub(crate) fn checked_pay_interest(
+        &self,
+        currency: Option<&Interned>,
+        before: &Money,
+        after: &Money,
+        interest: &Money,
+    ) -> Option<Self> {
+        let system = self.checked_post(currency, before, after, &Money::zero(), &Money::zero())?;
+        system.checked_post_with(currency, |system| {
+            Some(Self {
+                escrow: system.escrow.clone(),
+                fee_income: system.fee_income.clone(),
+                chargeback_loss: system.chargeback_loss.clone(),
+                suspense: system.suspense.clone(),
+                interest_expense: system.interest_expense.checked_sub(interest)?,
+                currencies: BTreeMap::new(),
+            })
+        })
+    }
+
+    /// Returns the balances with `post` applied to the totals, and to the
+    /// accounts for `currency` if it's named.
+    fn checked_post_with(
+        &self,
+        currency: Option<&Interned>,
+        post: impl Fn(&Self) -> Option<Self>,
+    ) -> Option<Self> {
+        let mut currencies = self.currencies.clone();
+        if let Some(currency) = currency {
+            let in_currency = currencies.entry(currency.clone()).or_default();
+            *in_currency = post(in_currency)?;
+        }
+        Some(Self {
+            currencies,
+            ..post(self)?
+        })
+    }
+
+    /// The accounts in each currency, by currency, with postings for
+    /// balances that don't name one under the empty currency (only if there
+    /// are any).
+    pub fn by_currency(&self) -> BTreeMap<Interned, SystemAccounts> {
+        let mut by_currency = self.currencies.clone();
+        let named = by_currency
+            .values()
+            .cloned()
+            .fold(SystemAccounts::default(), |sum, system| sum + system);
+        let unnamed = SystemAccounts {
+            escrow: &self.escrow - &named.escrow,
+            fee_income: &self.fee_income - &named.fee_income,
+            chargeback_loss: &self.chargeback_loss - &named.chargeback_loss,
+            suspense: &self.suspense - &named.suspense,
+            interest_expense: &self.interest_expense - &named.interest_expense,
+            currencies: BTreeMap::new(),
+        };
+        if unnamed != SystemAccounts::default() {
+            by_currency.insert("".into(), unnamed);
+        }
+        by_currency
+    }
+
+    /// Sum of the balances. Zero with client totals added, if the books
+    /// balance.
+    pub fn total(&self) -> Money {
+        [
+            &self.escrow,
+            &self.fee_income,
+            &self.chargeback_loss,
+            &self.suspense,
+            &self.interest_expense,
+        ]
+        .into_iter()
+        .sum()
+    }
+
+    /// Statement rows for each system account, rounded as client statements
+    /// are. If more than one currency is named, there's a row per account
+    /// and currency, labelled with the currency, rather than one summing
+    /// them.
+    pub fn statements(&self) -> Vec<SystemStatement> {
+        let by_currency = self.by_currency();
+        let named: Vec<_> = match by_currency.len() > 1 {
+            true => by_currency
+                .iter()
+                .map(|(currency, system)| (Some(currency), system.named()))
+                .collect(),
+            false => vec![(None, self.named())],
+        };
+        (0..5)
+            .flat_map(|account| {
+                named.iter().map(move |(currency, named)| {
+                    let (account, balance) = named[account];
+                    SystemStatement {
+                        account,
+                        currency: currency.cloned(),
+                        balance: balance.round_dp(OUTPUT_SCALE),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Each account's balance, by name, in statement order.
+    fn named(&self) -> [(&'static str, &Money); 5] {
+        [
+            ("escrow", &self.escrow),
+            ("fee_income", &self.fee_income),
+            ("chargeback_loss", &self.chargeback_loss),
+            ("suspense", &self.suspense),
+            ("interest_expense", &self.interest_expense),
+        ]
+    }
+
+    /// Writes the system statement as CSV.
+    pub fn write_statement<W: Write>(&self, writer: W) -> Result<(), Box<dyn Error>> {
+        let mut csv_writer = csv::Writer::from_writer(writer);
+        for statement in self.statements() {
+            csv_writer.serialize(statement)?;
+        }
+        csv_writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Add for SystemAccounts {
+    type Output = SystemAccounts;
+
+    fn add(self, other: SystemAccounts) -> SystemAccounts {
+        let mut currencies = self.currencies;
+        for (currency, system) in other.currencies {
+            let sum = currencies.remove(&currency).unwrap_or_default() + system;
+            currencies.insert(currency, sum);
+        }
+        SystemAccounts {
+            escrow: self.escrow + other.escrow,
+            fee_income: self.fee_income + other.fee_income,
+            chargeback_loss: self.chargeback_loss + other.chargeback_loss,
+            suspense: self.suspense + other.suspense,
+            interest_expense: self.interest_expense + other.interest_expense,
+            currencies,
+        }
+    }
+}
+
+/// The amount by which a total is below zero.
+fn overdraft(total: &Money) -> Money {
+    max(-total.clone(), Money::zero())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn overdrafts_written_off_and_recovered() {
+        let system = SystemAccounts::default();
+        // Deposit 10 with a fee of 1.
+        let system = system
+            .checked_post(None, &money!(0), &money!(9), &money!(10), &money!(1))
+            .unwrap();
+        assert_eq!(system.escrow, money!(-10));
+        assert_eq!(system.fee_income, money!(1));
+        // Chargeback of 12, leaving the client 3 overdrawn.
+        let system = system
+            .checked_post(None, &money!(9), &money!(-3), &money!(-12), &money!(0))
+            .unwrap();
+        assert_eq!(system.escrow, money!(2));
+        assert_eq!(system.chargeback_loss, money!(-3));
+        assert_eq!(system.suspense, money!(3));
+        // Deposit 5, recovering the overdraft.
+        let system = system
+            .checked_post(None, &money!(-3), &money!(2), &money!(5), &money!(0))
+            .unwrap();
+        assert_eq!(
+            system,
+            SystemAccounts {
+                escrow: money!(-3),
+                fee_income: money!(1),
+                chargeback_loss: money!(0),
+                suspense: money!(0),
+                interest_expense: money!(0),
+                currencies: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn kept_by_currency() {
+        let (usd, eur): (Interned, Interned) = ("USD".into(), "EUR".into());
+        // Deposits of 10 USD and 4 EUR, then 3 in a balance naming neither.
+        let system = SystemAccounts::default()
+            .checked_post(Some(&usd), &money!(0), &money!(10), &money!(10), &money!(0))
+            .and_then(|system| {
+                system.checked_post(Some(&eur), &money!(0), &money!(4), &money!(4), &money!(0))
+            })
+            .and_then(|system| {
+                system.checked_post(None, &money!(0), &money!(3), &money!(3), &money!(0))
+            })
+            .unwrap();
+        assert_eq!(system.escrow, money!(-17));
+        let by_currency = system.by_currency();
+        let escrow: Vec<_> = by_currency
+            .iter()
+            .map(|(currency, system)| (currency.to_string(), system.escrow.clone()))
+            .collect();
+        assert_eq!(
+            escrow,
+            [
+                ("".to_string(), money!(-3)),
+                ("EUR".to_string(), money!(-4)),
+                ("USD".to_string(), money!(-10))
+            ]
+        );
+        // Merging shards adds the accounts in each currency.
+        let merged = system.clone() + system;
+        assert_eq!(merged.currencies[&usd].escrow, money!(-20));
+
+        let mut output = vec![];
+        merged.write_statement(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.starts_with(
+                "account,currency,balance\n\
+                 escrow,,-6\n\
+                 escrow,EUR,-8\n\
+                 escrow,USD,-20\n\
+                 fee_income,,0\n"
+            ),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn statement_layout() {
+        let system = SystemAccounts {
+            escrow: money!(-12.34567),
+            fee_income: money!(2),
+            ..SystemAccounts::default()
+        };
+        let mut output = vec![];
+        system.write_statement(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "account,balance\n\
+             escrow,-12.3457\n\
+             fee_income,2\n\
+             chargeback_loss,0\n\
+             suspense,0\n\
+             interest_expense,0\n"
+        );
+    }
+}
@@ -0,0 +1,584 @@
+//! Several tenants sharing one process, each a namespace with its own
+//! engine and accounts, scheduled fairly so one tenant's backlog can't
+//! starve the others.
+//!
+//! Producers [`submit`](Tenants::submit) transactions to a tenant's queue
+//! from any thread, and a worker applies them with [`run`](Tenants::run),
+//! taking from the queues in weighted round-robin: each tenant with work in
+//! turn, up to its weight in transactions per turn. A tenant with a large
+//! backfill queued therefore only delays another's next transaction by the
+//! other tenants' weights, not by its whole backlog.
+//!
+//! Each tenant has a quota of transactions it may have queued at once;
+//! submissions over it are refused, for the producer to retry later, rather
+//! than let one tenant's queue grow without bound.
+//!
+//! Tenants can be configured from a [`TenantsFile`], where each tenant may
+//! override any of the engine policies shared by the others, so business
+//! lines with different limits or lock behaviour needn't be deployed
+//! separately.
+
+use crate::account_store::InMemoryStore;
+use crate::transaction::Transaction;
+use crate::transaction_engine::{EngineConfig, TransactionNotApplied, TxEngine};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Transactions taken from the tenant's queue per turn. At least one.
+    pub weight: u32,
+    /// Most transactions the tenant may have queued at once.
+    pub quota: usize,
+    pub engine: EngineConfig,
+}
+
+/// Tenants as configured in a file, e.g.
+///
+/// ```json
+/// {
+///   "defaults": {"chargeback_lock_scope": "block-all"},
+///   "tenants": [
+///     {"name": "retail", "weight": 2, "quota": 1000},
+///     {
+///       "name": "wholesale",
+///       "quota": 100,
+///       "overrides": {"max_held": "50000", "chargeback_lock_scope": "block-debits"}
+///     }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantsFile {
+    /// [`EngineConfig`] fields every tenant shares, as in an engine config
+    /// file. Fields not given take their usual defaults.
+    #[serde(default)]
+    pub defaults: Map<String, Value>,
+    pub tenants: Vec<TenantEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantEntry {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    pub quota: usize,
+    /// [`EngineConfig`] fields this tenant has its own values for. Objects
+    /// are merged into the defaults field by field, so e.g. one reason's
+    /// policy can be overridden without repeating the others; anything else
+    /// replaces the default outright.
+    #[serde(default)]
+    pub overrides: Map<String, Value>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl TenantsFile {
+    pub fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+
+    /// Each tenant's config, with its overrides applied over the defaults.
+    /// Fails on a field [`EngineConfig`] doesn't have, so a misspelt policy
+    /// isn't silently left at its default.
+    pub fn resolve(&self) -> Result<Vec<TenantConfig>, String> {
+        let Value::Object(known) =
+            serde_json::to_value(EngineConfig::default()).expect("Configs always serialize")
+        else {
+            unreachable!("Configs serialize to objects");
+        };
+        let check = |fields: &Map<String, Value>, what: &str| match fields
+            .keys()
+            .find(|field| !known.contains_key(*field))
+        {
+            Some(field) => Err(format!(
+                "Unknown engine config field {:?} in {}",
+                field, what
+            )),
+            None => Ok(()),
+        };
+        check(&self.defaults, "defaults")?;
+        self.tenants
+            .iter()
+            .map(|tenant| {
+                let what = format!("tenant {:?}", tenant.name);
+                check(&tenant.overrides, &what)?;
+                let mut fields = self.defaults.clone();
+                merge(&mut fields, &tenant.overrides);
+                let engine = serde_json::from_value(Value::Object(fields))
+                    .map_err(|err| format!("Invalid engine config for {}: {}", what, err))?;
+                Ok(TenantConfig {
+                    name: tenant.name.clone(),
+                    weight: tenant.weight,
+                    quota: tenant.quota,
+                    engine,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Merges `overrides` into `fields`, recursing into objects present in both.
+fn merge(fields: &mut Map<String, Value>, overrides: &Map<String, Value>) {
+    for (name, value) in overrides {
+        match (fields.get_mut(name), value) {
+            (Some(Value::Object(field)), Value::Object(value)) => merge(field, value),
+            _ => {
+                fields.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Why a submission was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    UnknownTenant,
+    /// The tenant already has its quota of transactions queued.
+    OverQuota,
+    /// The tenants were closed to further submissions.
+    Closed,
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SubmitError::UnknownTenant => write!(f, "Unknown tenant"),
+            SubmitError::OverQuota => write!(f, "Tenant over quota"),
+            SubmitError::Closed => write!(f, "Closed to submissions"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// Counts for a tenant, for metrics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct TenantStats {
+    /// Transactions currently queued.
+    pub depth: usize,
+    /// The deepest the queue has been.
+    pub max_depth: usize,
+    pub submitted: u64,
+    /// Submissions refused for being over quota.
+    pub over_quota: u64,
+    pub applied: u64,
+    pub not_applied: u64,
+    /// Total and longest time transactions waited in the queue, in seconds.
+    pub wait_seconds: f64,
+    pub max_wait_seconds: f64,
+}
+
+struct TenantQueue {
+    weight: u32,
+    quota: usize,
+    items: VecDeque<(Instant, Transaction)>,
+    stats: TenantStats,
+}
+
+struct State {
+    queues: Vec<TenantQueue>,
+    /// The tenant whose turn it is, and how many more transactions it may
+    /// take this turn.
+    turn: usize,
+    credit: u32,
+    closed: bool,
+}
+
+impl State {
+    /// The tenant to take the next transaction from, in weighted
+    /// round-robin, if any have transactions queued.
+    fn pick(&mut self) -> Option<usize> {
+        if self.credit > 0 && !self.queues[self.turn].items.is_empty() {
+            self.credit -= 1;
+            return Some(self.turn);
+        }
+        let count = self.queues.len();
+        // Ends back at the current tenant, whose turn starts again only if
+        // no one else has work.
+        let next = (1..=count)
+            .map(|offset| (self.turn + offset) % count)
+            .find(|tenant| !self.queues[*tenant].items.is_empty())?;
+        self.turn = next;
+        self.credit = self.queues[next].weight - 1;
+        Some(next)
+    }
+}
+
+pub struct Tenants {
+    names: Vec<String>,
+    indexes: HashMap<String, usize>,
+    engines: Vec<Mutex<TxEngine<InMemoryStore>>>,
+    state: Mutex<State>,
+    ready: Condvar,
+}
+
+impl Tenants {
+    /// Fails if two tenants have the same name.
+    pub fn new(configs: Vec<TenantConfig>) -> Result<Self, String> {
+        let mut indexes = HashMap::new();
+        let mut names = vec![];
+        let mut engines = vec![];
+        let mut queues = vec![];
+        for config in configs {
+            if indexes.insert(config.name.clone(), names.len()).is_some() {
+                return Err(format!("Tenant {:?} configured twice", config.name));
+            }
+            names.push(config.name);
+            engines.push(Mutex::new(TxEngine::with_config(
+                InMemoryStore::new(),
+                config.engine,
+            )));
+            queues.push(TenantQueue {
+                weight: config.weight.max(1),
+                quota: config.quota,
+                items: VecDeque::new(),
+                stats: TenantStats::default(),
+            });
+        }
+        Ok(Self {
+            names,
+            indexes,
+            engines,
+            state: Mutex::new(State {
+                // So the first tenant has the first turn.
+                turn: queues.len().saturating_sub(1),
+                queues,
+                credit: 0,
+                closed: false,
+            }),
+            ready: Condvar::new(),
+        })
+    }
+
+    /// Queues a transaction for the tenant, unless it's over quota.
+    pub fn submit(&self, tenant: &str, transaction: Transaction) -> Result<(), SubmitError> {
+        let index = *self.indexes.get(tenant).ok_or(SubmitError::UnknownTenant)?;
+        let mut state = self.lock();
+        if state.closed {
+            return Err(SubmitError::Closed);
+        }
+        let queue = &mut state.queues[index];
+        if queue.items.len() >= queue.quota {
+            queue.stats.over_quota += 1;
+            return Err(SubmitError::OverQuota);
+        }
+        queue.items.push_back((Instant::now(), transaction));
+        queue.stats.submitted += 1;
+        queue.stats.depth = queue.items.len();
+        queue.stats.max_depth = queue.stats.max_depth.max(queue.stats.depth);
+        self.ready.notify_one();
+        Ok(())
+    }
+
+    /// Stops accepting submissions. Transactions already queued are still
+    /// applied, and [`run`](Self::run) returns once they have been.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+
+    /// Applies queued transactions as they're submitted, calling
+    /// `on_handled` with each one's tenant and result, until closed and
+    /// every queue is empty.
+    ///
+    /// Run on one thread only, as each tenant's transactions must be
+    /// applied in the order they were submitted.
+    pub fn run(
+        &self,
+        mut on_handled: impl FnMut(&str, &Transaction, &Result<u64, TransactionNotApplied>),
+    ) {
+        while let Some((tenant, transaction)) = self.next() {
+            let result = self.engines[tenant]
+                .lock()
+                .expect("Tenant engine lock poisoned")
+                .handle(&transaction);
+            let mut state = self.lock();
+            let stats = &mut state.queues[tenant].stats;
+            match result {
+                Ok(_) => stats.applied += 1,
+                Err(_) => stats.not_applied += 1,
+            }
+            drop(state);
+            on_handled(&self.names[tenant], &transaction, &result);
+        }
+    }
+
+    /// Calls `f` with the tenant's engine, if there's such a tenant.
+    pub fn with_engine<R>(
+        &self,
+        tenant: &str,
+        f: impl FnOnce(&TxEngine<InMemoryStore>) -> R,
+    ) -> Option<R> {
+        let index = *self.indexes.get(tenant)?;
+        Some(f(&self.engines[index]
+            .lock()
+            .expect("Tenant engine lock poisoned")))
+    }
+
+    /// Every tenant's stats, in the order they were configured.
+    pub fn stats(&self) -> Vec<(String, TenantStats)> {
+        let state = self.lock();
+        self.names
+            .iter()
+            .cloned()
+            .zip(state.queues.iter().map(|queue| queue.stats.clone()))
+            .collect()
+    }
+
+    /// Takes the next transaction to apply, waiting for one if none are
+    /// queued. `None` once closed and every queue is empty.
+    fn next(&self) -> Option<(usize, Transaction)> {
+        let mut state = self.lock();
+        loop {
+            if let Some(tenant) = state.pick() {
+                let queue = &mut state.queues[tenant];
+                let (queued_at, transaction) =
+                    queue.items.pop_front().expect("Picked tenants have work");
+                let waited = queued_at.elapsed().as_secs_f64();
+                queue.stats.depth = queue.items.len();
+                queue.stats.wait_seconds += waited;
+                queue.stats.max_wait_seconds = queue.stats.max_wait_seconds.max(waited);
+                return Some((tenant, transaction));
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.ready.wait(state).expect("Tenants lock poisoned");
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("Tenants lock poisoned")
+    }
+}
+
+/// Writes tenants' stats in the Prometheus text exposition format.
+pub fn write_prometheus<W: Write>(
+    mut writer: W,
+    tenants: &[(String, TenantStats)],
+) -> io::Result<()> {
+    let mut metric = |name: &str, kind: &str, help: &str, value: fn(&TenantStats) -> f64| {
+        let name = format!("payments_engine_tenant_{}", name);
+        writeln!(writer, "# HELP {} {}", name, help)?;
+        writeln!(writer, "# TYPE {} {}", name, kind)?;
+        for (tenant, stats) in tenants {
+            writeln!(writer, "{}{{tenant=\"{}\"}} {}", name, tenant, value(stats))?;
+        }
+        io::Result::Ok(())
+    };
+    metric("depth", "gauge", "Transactions queued.", |stats| {
+        stats.depth as f64
+    })?;
+    metric(
+        "max_depth",
+        "gauge",
+        "Most transactions queued at once.",
+        |stats| stats.max_depth as f64,
+    )?;
+    metric(
+        "submitted_total",
+        "counter",
+        "Transactions accepted.",
+        |stats| stats.submitted as f64,
+    )?;
+    metric(
+        "over_quota_total",
+        "counter",
+        "Submissions refused for being over quota.",
+        |stats| stats.over_quota as f64,
+    )?;
+    metric(
+        "applied_total",
+        "counter",
+        "Transactions applied.",
+        |stats| stats.applied as f64,
+    )?;
+    metric(
+        "not_applied_total",
+        "counter",
+        "Transactions not applied.",
+        |stats| stats.not_applied as f64,
+    )?;
+    metric(
+        "wait_seconds_total",
+        "counter",
+        "Time transactions waited in the queue.",
+        |stats| stats.wait_seconds,
+    )?;
+    metric(
+        "max_wait_seconds",
+        "gauge",
+        "Longest a transaction waited in the queue.",
+        |stats| stats.max_wait_seconds,
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::LockScope;
+    use crate::account_store::AccountStore;
+    use crate::money::Money;
+    use crate::transaction::TransactionInfo;
+
+    fn deposit(tx: u32) -> Transaction {
+        Transaction {
+            client_id: 1,
+            transaction_id: tx,
+            info: TransactionInfo::Deposit(Money::from(1)),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        }
+    }
+
+    fn tenant(name: &str, weight: u32, quota: usize) -> TenantConfig {
+        TenantConfig {
+            name: name.into(),
+            weight,
+            quota,
+            engine: EngineConfig::default(),
+        }
+    }
+
+    #[test]
+    fn backlog_does_not_starve_others() {
+        let tenants =
+            Tenants::new(vec![tenant("backfill", 1, 100), tenant("live", 2, 10)]).unwrap();
+        for tx in 0..100 {
+            tenants.submit("backfill", deposit(tx)).unwrap();
+        }
+        assert_eq!(
+            tenants.submit("backfill", deposit(100)),
+            Err(SubmitError::OverQuota)
+        );
+        for tx in 0..4 {
+            tenants.submit("live", deposit(tx)).unwrap();
+        }
+        assert_eq!(
+            tenants.submit("other", deposit(0)),
+            Err(SubmitError::UnknownTenant)
+        );
+        tenants.close();
+
+        let mut order = vec![];
+        tenants.run(|tenant, _, result| {
+            assert!(result.is_ok());
+            order.push(tenant.to_owned());
+        });
+        assert_eq!(
+            order[..7],
+            ["backfill", "live", "live", "backfill", "live", "live", "backfill"]
+        );
+        assert_eq!(order.len(), 104);
+
+        // Each tenant's accounts are its own.
+        let total = |tenant| {
+            tenants
+                .with_engine(tenant, |engine| {
+                    engine.store().get_account(1).unwrap().total_funds().clone()
+                })
+                .unwrap()
+        };
+        assert_eq!(total("backfill"), Money::from(100));
+        assert_eq!(total("live"), Money::from(4));
+
+        let stats = tenants.stats();
+        assert_eq!(stats[0].0, "backfill");
+        let backfill = &stats[0].1;
+        assert_eq!(
+            (backfill.submitted, backfill.over_quota, backfill.applied),
+            (100, 1, 100)
+        );
+        assert_eq!((backfill.depth, backfill.max_depth), (0, 100));
+        let mut metrics = vec![];
+        write_prometheus(&mut metrics, &stats).unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(metrics.contains("payments_engine_tenant_applied_total{tenant=\"live\"} 4\n"));
+    }
+
+    #[test]
+    fn overrides_resolved_per_tenant() {
+        let file: TenantsFile = serde_json::from_str(
+            r#"{
+                "defaults": {
+                    "reason_policies": {
+                        "fraud": {"chargeback_lock": "block-all"},
+                        "duplicate": {"chargeback_lock": null}
+                    }
+                },
+                "tenants": [
+                    {"name": "retail", "quota": 10},
+                    {
+                        "name": "wholesale",
+                        "weight": 3,
+                        "quota": 10,
+                        "overrides": {
+                            "chargeback_lock_scope": "block-debits",
+                            "reason_policies": {"fraud": {"chargeback_lock": "allow-disputes"}}
+                        }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let configs = file.resolve().unwrap();
+        assert_eq!(configs[1].weight, 3);
+        // The override replaced one reason's policy, keeping the other.
+        assert_eq!(configs[1].engine.reason_policies.len(), 2);
+
+        let tenants = Tenants::new(configs).unwrap();
+        for tenant in ["retail", "wholesale"] {
+            for (transaction_id, info) in [
+                (1, TransactionInfo::Deposit(Money::from(5))),
+                (1, TransactionInfo::Dispute(None)),
+                (1, TransactionInfo::Chargeback),
+            ] {
+                let transaction = Transaction {
+                    client_id: 1,
+                    transaction_id,
+                    info,
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                };
+                tenants.submit(tenant, transaction).unwrap();
+            }
+        }
+        tenants.close();
+        tenants.run(|_, _, result| assert!(result.is_ok()));
+        let scope = |tenant| {
+            tenants
+                .with_engine(tenant, |engine| {
+                    engine.store().get_account(1).unwrap().lock_scope()
+                })
+                .unwrap()
+        };
+        assert_eq!(scope("retail"), Some(LockScope::BlockAll));
+        assert_eq!(scope("wholesale"), Some(LockScope::BlockDebits));
+
+        let mut misspelt = file.clone();
+        misspelt.tenants[0]
+            .overrides
+            .insert("max_hold".into(), Value::Null);
+        assert_eq!(
+            misspelt.resolve().unwrap_err(),
+            "Unknown engine config field \"max_hold\" in tenant \"retail\""
+        );
+    }
+}
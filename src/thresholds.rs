@@ -0,0 +1,148 @@
+//! Balance thresholds, for notifying clients as their balance crosses a
+//! level they care about.
+//!
+//! A [`ThresholdPolicy`] (see [`crate::EngineConfig::thresholds`]) sets a
+//! low and a high threshold on each client's available funds, with defaults
+//! for clients it doesn't list. When an applied transaction takes a balance
+//! below its low threshold, or above its high one, the engine raises an
+//! [`EngineEvent::BalanceThresholdCrossed`](crate::event::EngineEvent::BalanceThresholdCrossed)
+//! event with the transaction's sequence number, for a notification service
+//! to pick up from the event log or an outbox (see [`crate::outbox`]).
+//!
+//! Thresholds apply to each of a client's balances in its own currency. A
+//! balance crossing back raises no event, so a client is only notified
+//! again once their balance has recovered and crossed again.
+
+use crate::money::Money;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+
+/// Which of a client's thresholds was crossed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdKind {
+    /// Available funds fell below the low threshold.
+    Low,
+    /// Available funds rose above the high threshold.
+    High,
+}
+
+/// A client's thresholds. Either may be left unset.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Thresholds {
+    pub low: Option<Money>,
+    pub high: Option<Money>,
+}
+
+impl Thresholds {
+    /// The thresholds crossed as available funds move from `before` to
+    /// `after`: the low one crossed downwards, the high one upwards. Funds
+    /// at a threshold haven't crossed it.
+    pub fn crossed(&self, before: &Money, after: &Money) -> Vec<(ThresholdKind, &Money)> {
+        let mut crossed = vec![];
+        if let Some(low) = &self.low {
+            if before >= low && after < low {
+                crossed.push((ThresholdKind::Low, low));
+            }
+        }
+        if let Some(high) = &self.high {
+            if before <= high && after > high {
+                crossed.push((ThresholdKind::High, high));
+            }
+        }
+        crossed
+    }
+
+    fn is_set(&self) -> bool {
+        self.low.is_some() || self.high.is_some()
+    }
+}
+
+/// Thresholds by client (see [`crate::EngineConfig::thresholds`]).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThresholdPolicy {
+    /// Thresholds for clients not in `clients`.
+    pub default: Thresholds,
+    /// Clients' own thresholds, replacing the defaults.
+    pub clients: BTreeMap<u16, Thresholds>,
+}
+
+impl ThresholdPolicy {
+    /// The client's thresholds, or `None` if neither is set.
+    pub fn for_client(&self, client: u16) -> Option<&Thresholds> {
+        Some(self.clients.get(&client).unwrap_or(&self.default)).filter(|t| t.is_set())
+    }
+
+    /// Reads clients' thresholds from a CSV with `client`, `low` and `high`
+    /// columns, where an empty cell leaves that threshold unset. Thresholds
+    /// are configuration rather than input, so any invalid row is an error.
+    pub fn read_csv<R: Read>(&mut self, reader: R) -> Result<(), Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct Row {
+            client: u16,
+            low: Option<Money>,
+            high: Option<Money>,
+        }
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let Row { client, low, high } = row?;
+            if let (Some(low), Some(high)) = (&low, &high) {
+                if low > high {
+                    return Err(format!(
+                        "Client {}'s low threshold is above their high threshold",
+                        client
+                    )
+                    .into());
+                }
+            }
+            self.clients.insert(client, Thresholds { low, high });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn thresholds_crossed() {
+        let mut policy = ThresholdPolicy {
+            default: Thresholds {
+                low: Some(money!(10)),
+                high: None,
+            },
+            ..ThresholdPolicy::default()
+        };
+        policy
+            .read_csv("client,low,high\n2, , 100\n3,,\n".as_bytes())
+            .unwrap();
+        assert_eq!(policy.for_client(1), Some(&policy.default));
+        assert_eq!(policy.for_client(2).unwrap().high, Some(money!(100)));
+        assert_eq!(policy.for_client(3), None);
+        assert!(policy
+            .read_csv("client,low,high\n4,5,1\n".as_bytes())
+            .is_err());
+
+        let default = &policy.default;
+        assert_eq!(
+            default.crossed(&money!(10), &money!(9.99)),
+            [(ThresholdKind::Low, &money!(10))]
+        );
+        assert!(default.crossed(&money!(9.99), &money!(5)).is_empty());
+        assert!(default.crossed(&money!(5), &money!(20)).is_empty());
+        let client = policy.for_client(2).unwrap();
+        assert_eq!(
+            client.crossed(&money!(100), &money!(100.5)),
+            [(ThresholdKind::High, &money!(100))]
+        );
+        assert!(client.crossed(&money!(50), &money!(100)).is_empty());
+    }
+}
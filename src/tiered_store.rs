@@ -0,0 +1,527 @@
+//! Account store keeping recently active accounts in memory, and paging the
+//! rest out to a slower backend (e.g. disk).
+
+use crate::account::{Account, AccountStatement};
+use crate::account_store::{taken_ids, AccountStore};
+use crate::tx_index::TxIndex;
+use std::cell::{Cell, OnceCell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+/// Where a [`TieredStore`] keeps inactive accounts.
+pub trait ColdBackend {
+    /// Reads a client's account, if one was written.
+    fn read(&self, client_id: u16) -> io::Result<Option<Account>>;
+
+    /// Writes a client's account, replacing any previously written.
+    fn write(&mut self, account: &Account) -> io::Result<()>;
+}
+
+/// A [`ColdBackend`] keeping each account as a JSON file in a directory.
+pub struct DirBackend {
+    dir: PathBuf,
+}
+
+impl DirBackend {
+    /// Uses `dir`, creating it if necessary. Accounts already in it are
+    /// ignored unless preloaded (see [`TieredStore`]), so it should otherwise
+    /// be empty.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, client_id: u16) -> PathBuf {
+        self.dir.join(format!("{}.json", client_id))
+    }
+}
+
+impl ColdBackend for DirBackend {
+    fn read(&self, client_id: u16) -> io::Result<Option<Account>> {
+        match std::fs::read(self.path(client_id)) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn write(&mut self, account: &Account) -> io::Result<()> {
+        std::fs::write(self.path(account.client()), serde_json::to_vec(account)?)
+    }
+}
+
+/// An account from [`TieredStore::try_accounts`]: either held by the store,
+/// or read from its backend for the caller.
+pub enum AccountRef<'a> {
+    Held(&'a Account),
+    Read(Box<Account>),
+}
+
+impl Deref for AccountRef<'_> {
+    type Target = Account;
+
+    fn deref(&self) -> &Account {
+        match self {
+            AccountRef::Held(account) => account,
+            AccountRef::Read(account) => account,
+        }
+    }
+}
+
+/// An [`AccountStore`] holding at most a fixed number of accounts in memory.
+///
+/// Accounts are promoted to memory whenever they're used, and the least
+/// recently used account is written to the backend to make room. Only a
+/// small fraction of clients are typically active at once, so most
+/// transactions are for accounts already in memory.
+///
+/// Updating an account promotes it at once. Reading one with
+/// [`AccountStore::get_account`] loads a copy if it's paged out, which can't
+/// be moved into memory through `&self`, so it's promoted the next time the
+/// store is updated. Copies are kept until the account is promoted or
+/// [`TieredStore::drop_cold_copies`] is called.
+///
+/// Statements ([`AccountStore::account_statements`]) and
+/// [`TieredStore::try_accounts`] read paged out accounts one at a time,
+/// without keeping them or promoting them, so memory stays bounded however
+/// many are paged out. [`AccountStore::accounts`] lends references, so has to
+/// keep a copy of every account it reads.
+///
+/// [`AccountStore::preload`] promotes paged out accounts into free room in
+/// memory, and adopts accounts the backend held before the store was created
+/// (e.g. by a previous instance). Accounts already in memory are never paged
+/// out to make room, so preloading stops once memory is full.
+///
+/// [`AccountStore`] has no way to report errors, so a backend failing to read
+/// or write an account panics. [`TieredStore::try_accounts`] and
+/// [`TieredStore::try_account_statements`] return them instead.
+pub struct TieredStore<B: ColdBackend> {
+    backend: B,
+    hot_capacity: usize,
+    /// In-memory accounts, with when each was last used.
+    hot: HashMap<u16, (Account, u64)>,
+    /// Clients of the in-memory accounts, by when they were last used.
+    recency: BTreeMap<u64, u16>,
+    /// Clients whose accounts are paged out, with any copy loaded for reading.
+    cold: HashMap<u16, OnceCell<Account>>,
+    /// Clients whose accounts were read since the store was last updated,
+    /// with when, to promote on the next update.
+    read: RefCell<HashMap<u16, u64>>,
+    clock: Cell<u64>,
+    /// Transaction IDs taken by any account, hot or cold, so checking one
+    /// doesn't load every paged out account.
+    tx_ids: TxIndex,
+}
+
+impl<B: ColdBackend> TieredStore<B> {
+    /// Returns an empty store keeping up to `hot_capacity` (at least one)
+    /// accounts in memory.
+    pub fn new(backend: B, hot_capacity: usize) -> Self {
+        Self {
+            backend,
+            hot_capacity: hot_capacity.max(1),
+            hot: HashMap::new(),
+            recency: BTreeMap::new(),
+            cold: HashMap::new(),
+            read: RefCell::default(),
+            clock: Cell::new(0),
+            tx_ids: TxIndex::new(),
+        }
+    }
+
+    /// Number of accounts currently held in memory, excluding copies loaded
+    /// for reading.
+    pub fn hot_len(&self) -> usize {
+        self.hot.len()
+    }
+
+    /// Frees the copies of paged out accounts loaded for reading, along with
+    /// the storage pooled for deposit records (see [`crate::arena`]).
+    pub fn drop_cold_copies(&mut self) {
+        for copy in self.cold.values_mut() {
+            copy.take();
+        }
+        crate::arena::reclaim();
+    }
+
+    /// Iterates over all accounts, reading paged out ones from the backend
+    /// as they're reached and dropping them once passed, rather than keeping
+    /// copies. Copies already loaded for reading are used as they are.
+    pub fn try_accounts(&self) -> impl Iterator<Item = io::Result<AccountRef<'_>>> {
+        let hot = self
+            .hot
+            .values()
+            .map(|(account, _)| Ok(AccountRef::Held(account)));
+        let cold = self.cold.iter().map(|(client_id, copy)| match copy.get() {
+            Some(account) => Ok(AccountRef::Held(account)),
+            None => self
+                .read_cold(*client_id)
+                .map(|account| AccountRef::Read(Box::new(account))),
+        });
+        hot.chain(cold)
+    }
+
+    /// Statements for all accounts, as [`AccountStore::account_statements`],
+    /// returning an error for each account the backend fails to read.
+    pub fn try_account_statements(
+        &self,
+    ) -> impl Iterator<Item = io::Result<AccountStatement>> + '_ {
+        self.try_accounts().flat_map(|account| match account {
+            Ok(account) => account.statements().map(Ok).collect(),
+            Err(err) => vec![Err(err)],
+        })
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.set(self.clock.get() + 1);
+        self.clock.get()
+    }
+
+    /// Promotes the accounts read since the last update, in the order they
+    /// were read, or marks them used if they're already in memory. Copies
+    /// dropped since they were read are left paged out.
+    fn promote_read(&mut self) {
+        let mut read: Vec<_> = self.read.take().into_iter().collect();
+        read.sort_by_key(|(_, at)| *at);
+        for (client_id, at) in read {
+            if let Some((_, used)) = self.hot.get_mut(&client_id) {
+                self.recency.remove(used);
+                *used = at;
+                self.recency.insert(at, client_id);
+                continue;
+            }
+            let loaded = self.cold.get(&client_id).and_then(OnceCell::get);
+            if loaded.is_none() {
+                continue;
+            }
+            if self.hot.len() >= self.hot_capacity {
+                self.evict();
+            }
+            let account = self
+                .cold
+                .remove(&client_id)
+                .and_then(OnceCell::into_inner)
+                .expect("loaded copy");
+            self.hot.insert(client_id, (account, at));
+            self.recency.insert(at, client_id);
+        }
+    }
+
+    /// Reads a paged out account from the backend, which should have it.
+    fn read_cold(&self, client_id: u16) -> io::Result<Account> {
+        let account = self.backend.read(client_id).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to read account {}: {}", client_id, err),
+            )
+        })?;
+        account.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Account {} missing from backend", client_id),
+            )
+        })
+    }
+
+    fn load(&self, client_id: u16) -> Account {
+        self.read_cold(client_id)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Writes the least recently used in-memory account to the backend.
+    fn evict(&mut self) {
+        let Some((_, client_id)) = self.recency.pop_first() else {
+            return;
+        };
+        let (account, _) = self.hot.remove(&client_id).expect("hot account");
+        self.backend
+            .write(&account)
+            .unwrap_or_else(|err| panic!("Failed to write account {}: {}", client_id, err));
+        self.cold.insert(client_id, OnceCell::new());
+    }
+}
+
+impl<B: ColdBackend> AccountStore for TieredStore<B> {
+    fn get_account(&self, client_id: u16) -> Option<&Account> {
+        let account = match self.hot.get(&client_id) {
+            Some((account, _)) => account,
+            None => self
+                .cold
+                .get(&client_id)?
+                .get_or_init(|| self.load(client_id)),
+        };
+        self.read.borrow_mut().insert(client_id, self.tick());
+        Some(account)
+    }
+
+    fn get_account_mut(&mut self, client_id: u16) -> &mut Account {
+        self.promote_read();
+        let now = self.tick();
+        if let Some((_, updated)) = self.hot.get_mut(&client_id) {
+            self.recency.remove(updated);
+            *updated = now;
+        } else {
+            if self.hot.len() >= self.hot_capacity {
+                self.evict();
+            }
+            let account = match self.cold.remove(&client_id) {
+                Some(copy) => copy.into_inner().unwrap_or_else(|| self.load(client_id)),
+                None => Account::new(client_id),
+            };
+            self.hot.insert(client_id, (account, now));
+        }
+        self.recency.insert(now, client_id);
+        &mut self.hot.get_mut(&client_id).expect("hot account").0
+    }
+
+    fn accounts(&self) -> impl Iterator<Item = &Account> {
+        self.hot.values().map(|(account, _)| account).chain(
+            self.cold
+                .iter()
+                .map(|(client_id, copy)| copy.get_or_init(|| self.load(*client_id))),
+        )
+    }
+
+    fn account_statements(&self) -> impl Iterator<Item = AccountStatement> {
+        self.try_account_statements()
+            .map(|statement| statement.unwrap_or_else(|err| panic!("{}", err)))
+    }
+
+    fn preload(&mut self, clients: &[u16]) -> usize {
+        self.promote_read();
+        let mut loaded = 0;
+        for &client_id in clients {
+            if self.hot.len() >= self.hot_capacity {
+                break;
+            }
+            if self.hot.contains_key(&client_id) {
+                continue;
+            }
+            let account = match self.cold.remove(&client_id) {
+                Some(copy) => copy.into_inner().unwrap_or_else(|| self.load(client_id)),
+                None => match self.backend.read(client_id) {
+                    Ok(Some(account)) => {
+                        self.tx_ids.extend(taken_ids(&account));
+                        account
+                    }
+                    Ok(None) => continue,
+                    Err(err) => panic!("Failed to read account {}: {}", client_id, err),
+                },
+            };
+            let now = self.tick();
+            self.hot.insert(client_id, (account, now));
+            self.recency.insert(now, client_id);
+            loaded += 1;
+        }
+        loaded
+    }
+
+    fn tx_owner(&self, tx: u32) -> Option<u16> {
+        self.tx_ids.owner(tx)
+    }
+
+    fn take_tx(&mut self, tx: u32, client: u16) {
+        self.tx_ids.insert(tx, client);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::money::money;
+    use crate::transaction::{Transaction, TransactionInfo};
+    use crate::transaction_engine::TxEngine;
+    use crate::InMemoryStore;
+
+    /// Keeps accounts in memory, serialized as on disk, counting reads.
+    /// Reads fail while `failing` is set.
+    #[derive(Default)]
+    struct CountingBackend {
+        accounts: HashMap<u16, Vec<u8>>,
+        reads: std::cell::Cell<usize>,
+        failing: bool,
+    }
+
+    impl ColdBackend for &mut CountingBackend {
+        fn read(&self, client_id: u16) -> io::Result<Option<Account>> {
+            if self.failing {
+                return Err(io::Error::other("disk unavailable"));
+            }
+            self.reads.set(self.reads.get() + 1);
+            Ok(self
+                .accounts
+                .get(&client_id)
+                .map(|bytes| serde_json::from_slice(bytes).unwrap()))
+        }
+
+        fn write(&mut self, account: &Account) -> io::Result<()> {
+            let bytes = serde_json::to_vec(account).unwrap();
+            self.accounts.insert(account.client(), bytes);
+            Ok(())
+        }
+    }
+
+    fn statements<S: AccountStore>(store: &S) -> String {
+        let mut statements: Vec<_> = store.account_statements().collect();
+        statements.sort_by_key(|statement| statement.client());
+        serde_json::to_string(&statements).unwrap()
+    }
+
+    #[test]
+    fn matches_in_memory_store() {
+        let transactions: Vec<_> = (1..=200u32)
+            .map(|tx| {
+                let client_id = (tx * 7 % 13) as u16;
+                let info = match tx % 4 {
+                    0 => TransactionInfo::Withdrawal(money!(3)),
+                    1 if tx > 20 => TransactionInfo::Dispute(None),
+                    _ => TransactionInfo::Deposit(money!(5)),
+                };
+                let transaction_id = match info {
+                    TransactionInfo::Dispute(_) => tx - 13,
+                    _ => tx,
+                };
+                Transaction {
+                    client_id,
+                    transaction_id,
+                    info,
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                }
+            })
+            .collect();
+        let mut backend = CountingBackend::default();
+        let mut tiered = TxEngine::new(TieredStore::new(&mut backend, 3));
+        let mut in_memory = TxEngine::new(InMemoryStore::new());
+        for transaction in &transactions {
+            assert_eq!(tiered.handle(transaction), in_memory.handle(transaction));
+        }
+        assert_eq!(tiered.store().hot_len(), 3);
+        assert_eq!(statements(tiered.store()), statements(in_memory.store()));
+    }
+
+    #[test]
+    fn promotes_least_recently_used() {
+        let mut backend = CountingBackend::default();
+        let mut store = TieredStore::new(&mut backend, 2);
+        store.get_account_mut(1);
+        store.get_account_mut(2);
+        store.get_account_mut(1);
+        // Client 2 is the least recently used, so is paged out.
+        store.get_account_mut(3);
+        assert!(store.hot.contains_key(&1));
+        assert!(store.cold.contains_key(&2));
+
+        // Reading loads a copy once, and promotes it at the next update,
+        // paging out client 1.
+        assert_eq!(store.get_account(2).unwrap().client(), 2);
+        assert_eq!(store.get_account(2).unwrap().client(), 2);
+        assert!(store.cold.contains_key(&2));
+        assert_eq!(store.backend.reads.get(), 1);
+        store.get_account_mut(3);
+        assert!(store.hot.contains_key(&2));
+        assert!(store.cold.contains_key(&1));
+        assert_eq!(store.backend.reads.get(), 1);
+
+        // Reading an account in memory keeps it there: client 3 was read
+        // since client 2, so 2 is paged out for 4.
+        store.get_account(3);
+        store.get_account_mut(4);
+        assert!(store.hot.contains_key(&3));
+        assert!(store.cold.contains_key(&2));
+
+        // Iterating loads copies without promoting them.
+        assert_eq!(store.accounts().count(), 4);
+        assert_eq!(store.backend.reads.get(), 3);
+        store.get_account_mut(4);
+        assert!(store.cold.contains_key(&1));
+        store.get_account(1);
+        store.drop_cold_copies();
+        store.get_account(1);
+        assert_eq!(store.backend.reads.get(), 4);
+    }
+
+    #[test]
+    fn streams_paged_out_accounts() {
+        let mut backend = CountingBackend::default();
+        let mut store = TieredStore::new(&mut backend, 1);
+        for client_id in 1..=3 {
+            store.get_account_mut(client_id);
+        }
+
+        // Paged out accounts are read once each, and not kept.
+        let clients: Vec<u16> = store
+            .try_accounts()
+            .map(|account| account.unwrap().client())
+            .collect();
+        assert_eq!(clients.len(), 3);
+        assert_eq!(store.backend.reads.get(), 2);
+        assert!(store.cold.values().all(|copy| copy.get().is_none()));
+        assert_eq!(store.account_statements().count(), 3);
+        assert_eq!(store.backend.reads.get(), 4);
+        assert!(store.cold.values().all(|copy| copy.get().is_none()));
+
+        // Failures are returned for each account that can't be read.
+        store.backend.failing = true;
+        let errors: Vec<String> = store
+            .try_account_statements()
+            .filter_map(|statement| Some(statement.err()?.to_string()))
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].ends_with(": disk unavailable"), "{}", errors[0]);
+        store.backend.failing = false;
+        store.backend.accounts.remove(&1);
+        let err = store.try_accounts().find_map(Result::err).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(err.to_string(), "Account 1 missing from backend");
+    }
+
+    #[test]
+    fn preloads_into_free_room() {
+        let mut backend = CountingBackend::default();
+        let mut account = Account::new(7);
+        account
+            .credit(1, &money!(5), &money!(0), &mut Default::default(), || 1)
+            .unwrap();
+        // Left by a previous instance.
+        (&mut backend).write(&account).unwrap();
+        let mut store = TieredStore::new(&mut backend, 3);
+        store.get_account_mut(1);
+
+        // Clients 9 and 5 are unknown, and 1 is already in memory.
+        assert_eq!(store.preload(&[9, 7, 1, 5]), 1);
+        assert_eq!(store.get_account(7).unwrap().total_funds(), &money!(5));
+        assert_eq!(store.hot_len(), 2);
+
+        // Preloaded accounts are paged out like any other.
+        store.get_account_mut(2);
+        store.get_account_mut(3);
+        store.get_account_mut(4);
+        assert!(store.cold.contains_key(&1));
+        assert!(store.cold.contains_key(&7));
+        // Nothing is paged out to make room.
+        assert_eq!(store.preload(&[1, 7]), 0);
+        assert!(store.cold.contains_key(&1));
+    }
+
+    #[test]
+    fn dir_backend_round_trip() {
+        let dir = std::env::temp_dir().join(format!("pe-tiered-{}", std::process::id()));
+        let mut backend = DirBackend::new(&dir).unwrap();
+        assert!(backend.read(1).unwrap().is_none());
+        let mut account = Account::new(1);
+        account
+            .credit(1, &money!(5), &money!(0), &mut Default::default(), || 1)
+            .unwrap();
+        backend.write(&account).unwrap();
+        let read = backend.read(1).unwrap().unwrap();
+        assert_eq!(read.total_funds(), &money!(5));
+        assert!(read.transaction(1).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,51 +1,203 @@
-use rust_decimal::Decimal;
-use serde::Deserialize;
+use crate::clock::parse_timestamp;
+use crate::intern::Interned;
+use crate::money::Money;
+use serde::{Deserialize, Serialize};
 
 /// Basic flat datastructure used to deserialize transactions
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TransactionRaw {
     #[serde(rename = "type")]
     pub transaction_type: String,
     pub client: u16,
     pub tx: u32,
-    pub amount: Option<Decimal>,
+    /// Amount as written in the input. Parsed into [`Money`] when converting
+    /// to a [`Transaction`], so the configured money backend sees the exact
+    /// input digits.
+    pub amount: Option<String>,
+    /// Reason code, for disputes. Optional, and ignored for other types.
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Business date, as `YYYY-MM-DD`. Optional, and only used to assign
+    /// transactions to business dates (see [`crate::period::BusinessDates`]).
+    #[serde(default)]
+    pub date: Option<String>,
+    /// Destination tag, e.g. a bank reference or wallet ID. Optional, and
+    /// ignored for types other than withdrawals.
+    #[serde(default)]
+    pub destination: Option<String>,
+    /// Operator responsible, for unlocks, where it's required. Ignored for
+    /// other types.
+    #[serde(default)]
+    pub operator: Option<String>,
+    /// When the transaction happened, as seconds since the Unix epoch or
+    /// RFC 3339 (see [`crate::clock`]). Optional.
+    #[serde(default)]
+    pub timestamp: Option<String>,
+    /// Currency of the amount, e.g. `USD`. Optional; see [`Transaction::currency`].
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Currency converted into, for conversions, where it's required.
+    /// Ignored for other types.
+    #[serde(default)]
+    pub to_currency: Option<String>,
+    /// Rate to convert at, for conversions. Optional, and ignored for other
+    /// types.
+    #[serde(default)]
+    pub rate: Option<String>,
 }
 
 /// Representation of a transaction
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Transaction {
     pub client_id: u16,
     pub transaction_id: u32,
     pub info: TransactionInfo,
+    /// Where a withdrawal is paid to, if tagged. Always `None` for other
+    /// types.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<Interned>,
+    /// Portion of the deposit disputed, for partial disputes. Always `None`
+    /// for other types, and for disputes of whatever's left to dispute.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dispute_amount: Option<Money>,
+    /// When the transaction happened, in seconds since the Unix epoch, if
+    /// the input says.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Currency the transaction is in, upper-cased, if the input names one.
+    /// Transactions without one are in their account's primary currency
+    /// (see [`crate::Account::balance_mut`]).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Interned>,
+}
+
+impl Transaction {
+    /// Amount as written in input files: the transaction's amount, or the
+    /// portion disputed by a partial dispute.
+    pub fn amount(&self) -> Option<&Money> {
+        self.info.amount().or(self.dispute_amount.as_ref())
+    }
+}
+
+/// Standardized reason a deposit was disputed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeReason {
+    Fraud,
+    ProductNotReceived,
+    Duplicate,
+}
+
+impl std::str::FromStr for DisputeReason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fraud" => Ok(DisputeReason::Fraud),
+            "product-not-received" => Ok(DisputeReason::ProductNotReceived),
+            "duplicate" => Ok(DisputeReason::Duplicate),
+            _ => Err(format!("Unknown dispute reason {:?}", s)),
+        }
+    }
 }
 
 /// Transaction type and, where relevant, the associated amount.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum TransactionInfo {
-    Deposit(Decimal),
-    Withdrawal(Decimal),
-    Dispute,
+    Deposit(Money),
+    Withdrawal(Money),
+    /// A dispute, with its reason code if one was given.
+    Dispute(Option<DisputeReason>),
     Resolve,
     Chargeback,
+    /// An operator unlocking the account, e.g. once a chargeback is
+    /// settled, naming the operator for the audit log.
+    Unlock(String),
+    /// A conversion of funds from the transaction's currency into another
+    /// (see [`crate::fx`]).
+    Convert(Conversion),
+    /// A reversal of the deposit or withdrawal with the same transaction ID,
+    /// e.g. a bank-initiated correction (see [`crate::Account::reverse`]).
+    Reversal,
+}
+
+/// What a conversion converts, and into what.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conversion {
+    /// Amount taken, in the transaction's currency.
+    pub amount: Money,
+    /// Currency converted into.
+    pub to: Interned,
+    /// Units of `to` per unit of the transaction's currency, if supplied.
+    /// Otherwise the engine looks the rate up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate: Option<Money>,
+}
+
+impl TransactionInfo {
+    /// Name of the transaction type, as used in input files.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransactionInfo::Deposit(_) => "deposit",
+            TransactionInfo::Withdrawal(_) => "withdrawal",
+            TransactionInfo::Dispute(_) => "dispute",
+            TransactionInfo::Resolve => "resolve",
+            TransactionInfo::Chargeback => "chargeback",
+            TransactionInfo::Unlock(_) => "unlock",
+            TransactionInfo::Convert(_) => "convert",
+            TransactionInfo::Reversal => "reversal",
+        }
+    }
+
+    /// Amount of the transaction, for types that carry one.
+    pub fn amount(&self) -> Option<&Money> {
+        match self {
+            TransactionInfo::Deposit(amount) | TransactionInfo::Withdrawal(amount) => Some(amount),
+            TransactionInfo::Convert(conversion) => Some(&conversion.amount),
+            _ => None,
+        }
+    }
 }
 
 impl std::convert::TryFrom<TransactionRaw> for Transaction {
     type Error = (u32, String);
 
     fn try_from(value: TransactionRaw) -> Result<Transaction, Self::Error> {
-        let info = match (value.transaction_type.as_str(), value.amount) {
-            // Round on input. The engine only supports 4 DP, so we need to
-            // avoid compounding rounding errors on output. E.g. erroneous
-            // deposits of 1.00003 + 1.00003 => 2.0000, not 2.0001
-            ("deposit", Some(amount)) if amount > Decimal::ZERO => {
-                TransactionInfo::Deposit(amount.round_dp(4))
+        let amount = match value.amount.as_deref().map(str::parse::<Money>) {
+            Some(Ok(amount)) => Some(amount),
+            Some(Err(err)) => return Err((value.tx, err)),
+            None => None,
+        };
+        let info = match (value.transaction_type.as_str(), amount.clone()) {
+            // Round on input where the money backend requires it, see
+            // [`Money::round_input`].
+            ("deposit", Some(amount)) if amount.is_positive() => {
+                TransactionInfo::Deposit(amount.round_input())
+            }
+            ("withdrawal", Some(amount)) if amount.is_positive() => {
+                TransactionInfo::Withdrawal(amount.round_input())
             }
-            ("withdrawal", Some(amount)) if amount > Decimal::ZERO => {
-                TransactionInfo::Withdrawal(amount.round_dp(4))
+            // A dispute's amount, if given, is the portion disputed.
+            ("dispute", amount) if amount.as_ref().is_none_or(|amount| amount.is_positive()) => {
+                match value.reason.as_deref().map(str::parse) {
+                    Some(Ok(reason)) => TransactionInfo::Dispute(Some(reason)),
+                    Some(Err(err)) => return Err((value.tx, err)),
+                    None => TransactionInfo::Dispute(None),
+                }
             }
-            ("dispute", None) => TransactionInfo::Dispute,
             ("resolve", None) => TransactionInfo::Resolve,
             ("chargeback", None) => TransactionInfo::Chargeback,
+            ("reversal", None) => TransactionInfo::Reversal,
+            ("unlock", None) => match value.operator.as_deref().map(str::trim) {
+                Some(operator) if !operator.is_empty() => {
+                    TransactionInfo::Unlock(operator.to_owned())
+                }
+                _ => return Err((value.tx, "Unlocks must name an operator".to_owned())),
+            },
+            ("convert", Some(amount)) if amount.is_positive() => {
+                TransactionInfo::Convert(conversion(&value, amount)?)
+            }
             _ => {
                 return Err((
                     value.tx,
@@ -53,44 +205,116 @@ impl std::convert::TryFrom<TransactionRaw> for Transaction {
                 ));
             }
         };
+        let destination = match info {
+            TransactionInfo::Withdrawal(_) => value
+                .destination
+                .filter(|tag| !tag.is_empty())
+                .map(Interned::from),
+            _ => None,
+        };
+        let dispute_amount = match info {
+            TransactionInfo::Dispute(_) => amount.map(Money::round_input),
+            _ => None,
+        };
+        let timestamp = match value.timestamp.as_deref().map(str::trim) {
+            Some(timestamp) if !timestamp.is_empty() => {
+                Some(parse_timestamp(timestamp).map_err(|err| (value.tx, err))?)
+            }
+            _ => None,
+        };
+        let currency = parse_currency(value.currency.as_deref());
         Ok(Self {
             client_id: value.client,
             transaction_id: value.tx,
             info,
+            destination,
+            dispute_amount,
+            timestamp,
+            currency,
         })
     }
 }
 
+/// A currency as named in input files, upper-cased. `None` if blank.
+fn parse_currency(currency: Option<&str>) -> Option<Interned> {
+    currency
+        .map(str::trim)
+        .filter(|currency| !currency.is_empty())
+        .map(|currency| Interned::from(currency.to_ascii_uppercase()))
+}
+
+/// The conversion of `amount` a `convert` row describes. Conversions must
+/// name both currencies, and they must differ.
+fn conversion(value: &TransactionRaw, amount: Money) -> Result<Conversion, (u32, String)> {
+    let invalid = |reason: &str| (value.tx, reason.to_owned());
+    let from = parse_currency(value.currency.as_deref())
+        .ok_or_else(|| invalid("Conversions must name the currency converted from"))?;
+    let to = parse_currency(value.to_currency.as_deref())
+        .ok_or_else(|| invalid("Conversions must name the currency converted into"))?;
+    if from == to {
+        return Err(invalid("Conversions must be between different currencies"));
+    }
+    let rate = match value.rate.as_deref().map(str::trim) {
+        Some(rate) if !rate.is_empty() => match rate.parse::<Money>() {
+            Ok(rate) if rate.is_positive() => Some(rate),
+            Ok(_) => return Err(invalid("Conversion rates must be positive")),
+            Err(err) => return Err((value.tx, err)),
+        },
+        _ => None,
+    };
+    Ok(Conversion {
+        amount: amount.round_input(),
+        to,
+        rate,
+    })
+}
+
 #[cfg(test)]
 mod transaction_deserialization {
     use super::*;
-    use rust_decimal_macros::dec;
+    use crate::money::money;
 
-    fn tx_raw(typ: &str, amount: Option<Decimal>) -> TransactionRaw {
+    fn tx_raw(typ: &str, amount: Option<&str>) -> TransactionRaw {
         TransactionRaw {
             transaction_type: typ.to_string(),
             client: 1,
             tx: 1,
-            amount,
+            amount: amount.map(String::from),
+            reason: None,
+            date: None,
+            destination: None,
+            operator: None,
+            timestamp: None,
+            currency: None,
+            to_currency: None,
+            rate: None,
         }
     }
 
     #[test]
     fn parse_transaction_raw_ok_cases() {
         assert_eq!(
-            Transaction::try_from(tx_raw("deposit", Some(dec!(1)))).unwrap(),
+            Transaction::try_from(tx_raw("deposit", Some("1"))).unwrap(),
             Transaction {
                 client_id: 1,
                 transaction_id: 1,
-                info: TransactionInfo::Deposit(dec!(1)),
+                info: TransactionInfo::Deposit(money!(1)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         );
         assert_eq!(
-            Transaction::try_from(tx_raw("withdrawal", Some(dec!(1)))).unwrap(),
+            Transaction::try_from(tx_raw("withdrawal", Some("1"))).unwrap(),
             Transaction {
                 client_id: 1,
                 transaction_id: 1,
-                info: TransactionInfo::Withdrawal(dec!(1)),
+                info: TransactionInfo::Withdrawal(money!(1)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         );
         assert_eq!(
@@ -98,7 +322,31 @@ mod transaction_deserialization {
             Transaction {
                 client_id: 1,
                 transaction_id: 1,
-                info: TransactionInfo::Dispute,
+                info: TransactionInfo::Dispute(None),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            }
+        );
+        let raw = TransactionRaw {
+            reason: Some("product-not-received".into()),
+            ..tx_raw("dispute", None)
+        };
+        assert_eq!(
+            Transaction::try_from(raw).unwrap().info,
+            TransactionInfo::Dispute(Some(DisputeReason::ProductNotReceived))
+        );
+        assert_eq!(
+            Transaction::try_from(tx_raw("dispute", Some("0.5"))).unwrap(),
+            Transaction {
+                client_id: 1,
+                transaction_id: 1,
+                info: TransactionInfo::Dispute(None),
+                destination: None,
+                dispute_amount: Some(money!(0.5)),
+                timestamp: None,
+                currency: None,
             }
         );
         assert_eq!(
@@ -107,6 +355,10 @@ mod transaction_deserialization {
                 client_id: 1,
                 transaction_id: 1,
                 info: TransactionInfo::Resolve,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         );
         assert_eq!(
@@ -115,8 +367,48 @@ mod transaction_deserialization {
                 client_id: 1,
                 transaction_id: 1,
                 info: TransactionInfo::Chargeback,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         );
+        assert_eq!(
+            Transaction::try_from(tx_raw("reversal", None))
+                .unwrap()
+                .info,
+            TransactionInfo::Reversal
+        );
+        let raw = TransactionRaw {
+            timestamp: Some("2024-03-01T12:30:00Z".into()),
+            ..tx_raw("resolve", None)
+        };
+        assert_eq!(
+            Transaction::try_from(raw).unwrap().timestamp,
+            Some(1_709_296_200)
+        );
+        for (currency, expected) in [(" usd ", Some("USD")), ("", None)] {
+            let raw = TransactionRaw {
+                currency: Some(currency.into()),
+                ..tx_raw("deposit", Some("1"))
+            };
+            let parsed = Transaction::try_from(raw).unwrap().currency;
+            assert_eq!(parsed.as_deref(), expected);
+        }
+        let raw = TransactionRaw {
+            currency: Some("usd".into()),
+            to_currency: Some("eur".into()),
+            rate: Some("0.9".into()),
+            ..tx_raw("convert", Some("10"))
+        };
+        assert_eq!(
+            Transaction::try_from(raw).unwrap().info,
+            TransactionInfo::Convert(Conversion {
+                amount: money!(10),
+                to: "EUR".into(),
+                rate: Some(money!(0.9)),
+            })
+        );
     }
 
     #[test]
@@ -125,15 +417,49 @@ mod transaction_deserialization {
         assert!(Transaction::try_from(tx_raw("deposit", None)).is_err());
         assert!(Transaction::try_from(tx_raw("withdrawal", None)).is_err());
         // Transactions that shouldn't have amounts
-        assert!(Transaction::try_from(tx_raw("dispute", Some(dec!(1)))).is_err());
-        assert!(Transaction::try_from(tx_raw("resolve", Some(dec!(1)))).is_err());
-        assert!(Transaction::try_from(tx_raw("chargeback", Some(dec!(1)))).is_err());
+        assert!(Transaction::try_from(tx_raw("resolve", Some("1"))).is_err());
+        assert!(Transaction::try_from(tx_raw("chargeback", Some("1"))).is_err());
+        assert!(Transaction::try_from(tx_raw("reversal", Some("1"))).is_err());
+        // Unlocks not naming an operator
+        assert!(Transaction::try_from(tx_raw("unlock", None)).is_err());
+        let raw = TransactionRaw {
+            operator: Some(" ".into()),
+            ..tx_raw("unlock", None)
+        };
+        assert!(Transaction::try_from(raw).is_err());
+        // Unrecognized dispute reason
+        let raw = TransactionRaw {
+            reason: Some("changed-my-mind".into()),
+            ..tx_raw("dispute", None)
+        };
+        assert!(Transaction::try_from(raw).is_err());
+        // Malformed timestamp
+        let raw = TransactionRaw {
+            timestamp: Some("last tuesday".into()),
+            ..tx_raw("deposit", Some("1"))
+        };
+        assert!(Transaction::try_from(raw).is_err());
+        // Conversions not naming both currencies, naming the same one twice,
+        // or at a rate that isn't positive
+        let convert = |currency: &str, to_currency: &str, rate: &str| TransactionRaw {
+            currency: Some(currency.into()),
+            to_currency: Some(to_currency.into()),
+            rate: Some(rate.into()),
+            ..tx_raw("convert", Some("1"))
+        };
+        assert!(Transaction::try_from(convert("USD", "", "")).is_err());
+        assert!(Transaction::try_from(convert("", "EUR", "")).is_err());
+        assert!(Transaction::try_from(convert("usd", "USD", "")).is_err());
+        assert!(Transaction::try_from(convert("USD", "EUR", "0")).is_err());
+        assert!(Transaction::try_from(convert("USD", "EUR", "fair")).is_err());
         // Unrecognized transaction type
         assert!(Transaction::try_from(tx_raw("not a real type", None)).is_err());
-        assert!(Transaction::try_from(tx_raw("not a real type", Some(dec!(1)))).is_err());
+        assert!(Transaction::try_from(tx_raw("not a real type", Some("1"))).is_err());
 
         // Invalid transaction amount
-        assert!(Transaction::try_from(tx_raw("deposit", Some(dec!(0)))).is_err());
-        assert!(Transaction::try_from(tx_raw("deposit", Some(dec!(-1)))).is_err());
+        assert!(Transaction::try_from(tx_raw("deposit", Some("0"))).is_err());
+        assert!(Transaction::try_from(tx_raw("deposit", Some("-1"))).is_err());
+        assert!(Transaction::try_from(tx_raw("deposit", Some("one"))).is_err());
+        assert!(Transaction::try_from(tx_raw("dispute", Some("0"))).is_err());
     }
 }
@@ -1,6 +1,33 @@
-use crate::account::DepositRecord;
-use crate::account_store::AccountStore;
-use crate::transaction::{Transaction, TransactionInfo};
+use crate::account::{
+    Account, DepositRecord, DisputeStatus, HistoryEntry, HoldPolicy, LockScope, WithdrawalRecord,
+};
+use crate::account_store::{AccountStore, InMemoryStore};
+use crate::backfill::Correction;
+use crate::bulk::{DisputeAction, DisputeItem};
+use crate::clock::{Clock, InputClock, SECONDS_PER_DAY};
+use crate::event::EngineEvent;
+use crate::fees::FeePolicy;
+use crate::fx::{FxPolicy, RateProvider};
+use crate::interest::InterestPolicy;
+use crate::intern::Interned;
+use crate::ledger::{EventSink, LedgerEvent};
+use crate::money::Money;
+use crate::notes::{AccountNote, Note};
+use crate::opening::OpeningEntry;
+use crate::plugin::{PluginError, TransactionPlugin};
+use crate::risk::{Limit, RiskLimits, Velocity};
+use crate::system_accounts::SystemAccounts;
+use crate::thresholds::ThresholdPolicy;
+use crate::transaction::{DisputeReason, Transaction, TransactionInfo};
+use crate::tx_index::TxIndex;
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Enum covering reasons why a transaction was not applied.
 /// These may be for expected, valid reasons (e.g. insufficient funds)
@@ -11,63 +38,593 @@ use crate::transaction::{Transaction, TransactionInfo};
 pub enum TransactionNotApplied {
     /// Account is locked so transaction could not be applied.
     AccountLocked,
+    /// Account is dormant, so can't be debited until reactivated.
+    AccountDormant,
+    /// Account is quarantined, so the transaction was queued until the
+    /// quarantine is released (see [`TxEngine::release_quarantine`]).
+    Quarantined,
+    /// A resolve or chargeback repeated for a dispute it already settled
+    /// (only raised with [`EngineConfig::idempotent_settlement`]), or an
+    /// unlock of an account that isn't locked. Nothing was changed, but the
+    /// outcome requested already holds.
+    AlreadyApplied(u32),
     /// Transaction with ID has already been applied.
     RepeatTransaction(u32),
-    /// Account could not be debited due to insufficient funds.
-    InsufficientFunds,
-    /// Dispute process failed due to unknwon transaction for this customer
+    /// Account could not be debited due to insufficient funds, short by
+    /// the amount given.
+    InsufficientFunds(Money),
+    /// The withdrawal's destination is blocklisted (see
+    /// [`DestinationPolicy::blocklist`]).
+    DestinationBlocked,
+    /// The withdrawal would take the total withdrawn to its destination over
+    /// the destination's limit.
+    DestinationLimitExceeded,
+    /// The transaction would take the client over one of the engine's risk
+    /// limits (see [`EngineConfig::risk`]).
+    LimitExceeded(Limit),
+    /// Dispute process or reversal failed due to unknwon transaction for
+    /// this customer
     DisputedTransactionNotFound(u32),
+    /// Dispute process or reversal named a transaction belonging to another
+    /// client.
+    ClientMismatch(u32),
     /// Dispute process failed to progress due to invalid dispute state
     InvalidDisputeState(String),
+    /// The dispute was raised too long after the transaction disputed (see
+    /// [`EngineConfig::dispute_window_days`]).
+    DisputeWindowExpired(u32),
+    /// The conversion didn't supply a rate, and the engine has none for its
+    /// currencies (see [`crate::fx`]).
+    RateUnavailable(u32),
+    /// A [`TransactionPlugin`] rejected the transaction
+    RejectedByPlugin(String),
+    /// A [`TransactionPlugin`] failed while checking the transaction
+    PluginFailure(String),
+    /// Applying the transaction would overflow an account balance
+    ArithmeticOverflow,
     /// Unexpected error
     UnexpectedError(String),
 }
 
 impl TransactionNotApplied {
+    /// Short, stable name of the variant, for grouping in reports.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionNotApplied::AccountLocked => "AccountLocked",
+            TransactionNotApplied::AccountDormant => "AccountDormant",
+            TransactionNotApplied::Quarantined => "Quarantined",
+            TransactionNotApplied::AlreadyApplied(_) => "AlreadyApplied",
+            TransactionNotApplied::RepeatTransaction(_) => "RepeatTransaction",
+            TransactionNotApplied::InsufficientFunds(_) => "InsufficientFunds",
+            TransactionNotApplied::DestinationBlocked => "DestinationBlocked",
+            TransactionNotApplied::DestinationLimitExceeded => "DestinationLimitExceeded",
+            TransactionNotApplied::LimitExceeded(_) => "LimitExceeded",
+            TransactionNotApplied::DisputedTransactionNotFound(_) => "DisputedTransactionNotFound",
+            TransactionNotApplied::ClientMismatch(_) => "ClientMismatch",
+            TransactionNotApplied::InvalidDisputeState(_) => "InvalidDisputeState",
+            TransactionNotApplied::DisputeWindowExpired(_) => "DisputeWindowExpired",
+            TransactionNotApplied::RateUnavailable(_) => "RateUnavailable",
+            TransactionNotApplied::RejectedByPlugin(_) => "RejectedByPlugin",
+            TransactionNotApplied::PluginFailure(_) => "PluginFailure",
+            TransactionNotApplied::ArithmeticOverflow => "ArithmeticOverflow",
+            TransactionNotApplied::UnexpectedError(_) => "UnexpectedError",
+        }
+    }
+
+    /// HTTP status code for the variant, for servers responding to a
+    /// transaction that wasn't applied (see [`crate::rejection`]).
+    pub fn http_status(&self) -> u16 {
+        match self {
+            // Nothing was applied, but the outcome the caller wants holds.
+            TransactionNotApplied::AlreadyApplied(_) => 200,
+            // Queued, and applied once the quarantine is released.
+            TransactionNotApplied::Quarantined => 202,
+            TransactionNotApplied::DisputedTransactionNotFound(_) => 404,
+            TransactionNotApplied::RepeatTransaction(_) => 409,
+            TransactionNotApplied::InvalidDisputeState(_) => 409,
+            TransactionNotApplied::InsufficientFunds(_) => 422,
+            TransactionNotApplied::DisputeWindowExpired(_) => 422,
+            TransactionNotApplied::RateUnavailable(_) => 422,
+            TransactionNotApplied::DestinationBlocked => 403,
+            TransactionNotApplied::ClientMismatch(_) => 403,
+            TransactionNotApplied::DestinationLimitExceeded => 422,
+            TransactionNotApplied::LimitExceeded(_) => 422,
+            TransactionNotApplied::RejectedByPlugin(_) => 422,
+            TransactionNotApplied::ArithmeticOverflow => 422,
+            TransactionNotApplied::AccountLocked => 423,
+            TransactionNotApplied::AccountDormant => 423,
+            TransactionNotApplied::UnexpectedError(_) => 500,
+            TransactionNotApplied::PluginFailure(_) => 503,
+        }
+    }
+
+    /// gRPC status code for the variant, the counterpart of
+    /// [`http_status`](Self::http_status).
+    pub fn grpc_code(&self) -> u8 {
+        const OK: u8 = 0;
+        const NOT_FOUND: u8 = 5;
+        const ALREADY_EXISTS: u8 = 6;
+        const PERMISSION_DENIED: u8 = 7;
+        const FAILED_PRECONDITION: u8 = 9;
+        const ABORTED: u8 = 10;
+        const OUT_OF_RANGE: u8 = 11;
+        const INTERNAL: u8 = 13;
+        const UNAVAILABLE: u8 = 14;
+        match self {
+            TransactionNotApplied::AlreadyApplied(_) => OK,
+            TransactionNotApplied::Quarantined => OK,
+            TransactionNotApplied::DisputedTransactionNotFound(_) => NOT_FOUND,
+            TransactionNotApplied::RepeatTransaction(_) => ALREADY_EXISTS,
+            TransactionNotApplied::InvalidDisputeState(_) => ABORTED,
+            TransactionNotApplied::InsufficientFunds(_) => FAILED_PRECONDITION,
+            TransactionNotApplied::DisputeWindowExpired(_) => FAILED_PRECONDITION,
+            TransactionNotApplied::RateUnavailable(_) => FAILED_PRECONDITION,
+            TransactionNotApplied::DestinationBlocked => PERMISSION_DENIED,
+            TransactionNotApplied::ClientMismatch(_) => PERMISSION_DENIED,
+            TransactionNotApplied::DestinationLimitExceeded => FAILED_PRECONDITION,
+            TransactionNotApplied::LimitExceeded(_) => FAILED_PRECONDITION,
+            TransactionNotApplied::RejectedByPlugin(_) => FAILED_PRECONDITION,
+            TransactionNotApplied::ArithmeticOverflow => OUT_OF_RANGE,
+            TransactionNotApplied::AccountLocked => FAILED_PRECONDITION,
+            TransactionNotApplied::AccountDormant => FAILED_PRECONDITION,
+            TransactionNotApplied::UnexpectedError(_) => INTERNAL,
+            TransactionNotApplied::PluginFailure(_) => UNAVAILABLE,
+        }
+    }
+
     /// Checks whether the current variant of `self` represents a system
     /// failure (`true`) or a valid rejection of a transaction (`false`).
     pub fn is_failure(&self) -> bool {
         match self {
             TransactionNotApplied::AccountLocked => false,
-            TransactionNotApplied::InsufficientFunds => false,
+            TransactionNotApplied::AccountDormant => false,
+            TransactionNotApplied::Quarantined => false,
+            TransactionNotApplied::AlreadyApplied(_) => false,
+            TransactionNotApplied::InsufficientFunds(_) => false,
+            TransactionNotApplied::DisputeWindowExpired(_) => false,
+            TransactionNotApplied::RateUnavailable(_) => false,
+            TransactionNotApplied::DestinationBlocked => false,
+            TransactionNotApplied::DestinationLimitExceeded => false,
+            TransactionNotApplied::LimitExceeded(_) => false,
+            TransactionNotApplied::RejectedByPlugin(_) => false,
             // If we've seen this transaction before, something has gone wrong.
             TransactionNotApplied::RepeatTransaction(_) => true,
             // Either invalid input or a previously lost transaction.
             TransactionNotApplied::DisputedTransactionNotFound(_) => true,
+            // Invalid input, or a message routed to the wrong client.
+            TransactionNotApplied::ClientMismatch(_) => true,
             // Either invalid input or a previously lost dispute-related msg.
             TransactionNotApplied::InvalidDisputeState(_) => true,
+            TransactionNotApplied::PluginFailure(_) => true,
+            // No legitimate balance gets this large, so the input is corrupt
+            // or hostile.
+            TransactionNotApplied::ArithmeticOverflow => true,
             TransactionNotApplied::UnexpectedError(_) => true,
         }
     }
 }
 
+impl From<PluginError> for TransactionNotApplied {
+    fn from(err: PluginError) -> Self {
+        match err {
+            PluginError::Rejected(reason) => TransactionNotApplied::RejectedByPlugin(reason),
+            PluginError::Failed(reason) => TransactionNotApplied::PluginFailure(reason),
+        }
+    }
+}
+
 impl std::fmt::Display for TransactionNotApplied {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             TransactionNotApplied::AccountLocked => write!(f, "Account Locked"),
-            TransactionNotApplied::InsufficientFunds => write!(f, "Insufficient Funds"),
+            TransactionNotApplied::AccountDormant => write!(f, "Account Dormant"),
+            TransactionNotApplied::Quarantined => write!(f, "Account Quarantined"),
+            TransactionNotApplied::AlreadyApplied(id) => write!(f, "Already Applied: {}", id),
+            TransactionNotApplied::InsufficientFunds(shortfall) => {
+                write!(f, "Insufficient Funds: short by {}", shortfall)
+            }
+            TransactionNotApplied::DestinationBlocked => write!(f, "Destination Blocked"),
+            TransactionNotApplied::DestinationLimitExceeded => {
+                write!(f, "Destination Limit Exceeded")
+            }
+            TransactionNotApplied::LimitExceeded(limit) => write!(f, "Limit Exceeded: {}", limit),
             TransactionNotApplied::RepeatTransaction(id) => write!(f, "Repeat Transaction: {}", id),
             TransactionNotApplied::DisputedTransactionNotFound(id) => {
                 write!(f, "Transaction Not Found: {}", id)
             }
+            TransactionNotApplied::ClientMismatch(id) => write!(f, "Client Mismatch: {}", id),
             TransactionNotApplied::InvalidDisputeState(err) => {
                 write!(f, "Invalid state for disputed transaction: {}", err)
             }
+            TransactionNotApplied::DisputeWindowExpired(id) => {
+                write!(f, "Dispute Window Expired: {}", id)
+            }
+            TransactionNotApplied::RateUnavailable(id) => write!(f, "Rate Unavailable: {}", id),
+            TransactionNotApplied::RejectedByPlugin(err) => {
+                write!(f, "Rejected by plugin: {}", err)
+            }
+            TransactionNotApplied::PluginFailure(err) => write!(f, "Plugin failure: {}", err),
+            TransactionNotApplied::ArithmeticOverflow => write!(f, "Arithmetic Overflow"),
             TransactionNotApplied::UnexpectedError(err) => write!(f, "Unexpected Error: {}", err),
         }
     }
 }
 
+/// Policies controlling how the [`TxEngine`] applies transactions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineConfig {
+    /// How funds are held against disputed deposits.
+    pub hold_policy: HoldPolicy,
+    /// What an account locked by a chargeback may still do.
+    pub chargeback_lock_scope: LockScope,
+    /// Let disputes, resolves and chargebacks through on locked accounts,
+    /// whatever their lock scope, so disputes raised after an account is
+    /// locked are still reflected in its liabilities.
+    pub disputes_on_locked_accounts: bool,
+    /// When accounts become dormant, if ever.
+    pub dormancy: Option<DormancyPolicy>,
+    /// Treat a resolve or chargeback repeated for a dispute it already
+    /// settled as [`TransactionNotApplied::AlreadyApplied`], rather than a
+    /// failure, e.g. when upstream retries messages.
+    pub idempotent_settlement: bool,
+    /// Policies for disputes with a given reason code, overriding the
+    /// defaults above.
+    pub reason_policies: BTreeMap<DisputeReason, ReasonPolicy>,
+    /// Limits and blocklists for tagged withdrawals' destinations.
+    pub destinations: DestinationPolicy,
+    /// Most that may be held for any one account's disputes, or `None` for
+    /// no limit. A dispute that would hold more quarantines the account,
+    /// queuing the dispute for review, rather than holding the funds.
+    pub max_held: Option<Money>,
+    /// Days after a deposit or withdrawal within which it may be disputed,
+    /// or `None` for no limit. Disputes raised later are rejected with
+    /// [`TransactionNotApplied::DisputeWindowExpired`]. Only enforced where
+    /// both times are known (see [`crate::clock`]).
+    pub dispute_window_days: Option<u64>,
+    /// Rates and rounding rules for conversions between currencies.
+    pub fx: FxPolicy,
+    /// Fees charged on deposits and withdrawals, on top of any plugins'.
+    pub fees: FeePolicy,
+    /// Interest paid on balances, if any.
+    pub interest: Option<InterestPolicy>,
+    /// Low and high balances at which clients are notified, with an
+    /// [`EngineEvent::BalanceThresholdCrossed`] event.
+    pub thresholds: ThresholdPolicy,
+    /// How far withdrawals may overdraw each client's balances.
+    pub overdraft: OverdraftPolicy,
+    /// Limits on what each client may withdraw, and how often they may
+    /// transact.
+    pub risk: RiskLimits,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            hold_policy: HoldPolicy::default(),
+            chargeback_lock_scope: LockScope::BlockAll,
+            disputes_on_locked_accounts: false,
+            dormancy: None,
+            idempotent_settlement: false,
+            reason_policies: BTreeMap::new(),
+            destinations: DestinationPolicy::default(),
+            max_held: None,
+            dispute_window_days: None,
+            fx: FxPolicy::default(),
+            fees: FeePolicy::default(),
+            interest: None,
+            thresholds: ThresholdPolicy::default(),
+            overdraft: OverdraftPolicy::default(),
+            risk: RiskLimits::default(),
+        }
+    }
+}
+
+/// Policies for the destinations withdrawals are tagged with. Untagged
+/// withdrawals aren't subject to them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DestinationPolicy {
+    /// Destinations withdrawals may not be paid to.
+    pub blocklist: BTreeSet<String>,
+    /// Most that may be withdrawn to any one destination, across all
+    /// clients, or `None` for no limit.
+    pub limit: Option<Money>,
+    /// Limits for particular destinations, overriding `limit`.
+    pub limits: BTreeMap<String, Money>,
+}
+
+impl DestinationPolicy {
+    fn limit(&self, destination: &str) -> Option<&Money> {
+        self.limits.get(destination).or(self.limit.as_ref())
+    }
+}
+
+/// Arranged overdrafts: how far below zero withdrawals may take a balance's
+/// available funds. None by default.
+///
+/// Limits apply to each of a client's balances, in its own currency. Only
+/// withdrawals may overdraw a balance; conversions are limited to the funds
+/// available.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OverdraftPolicy {
+    /// Limit for clients not in `clients`.
+    pub limit: Money,
+    /// Clients' own limits, replacing `limit`.
+    pub clients: BTreeMap<u16, Money>,
+}
+
+impl OverdraftPolicy {
+    /// The client's overdraft limit.
+    pub fn limit_for(&self, client: u16) -> &Money {
+        self.clients.get(&client).unwrap_or(&self.limit)
+    }
+
+    /// Reads clients' limits from a CSV with `client` and `limit` columns.
+    /// Limits are configuration rather than input, so any invalid limit is
+    /// an error.
+    pub fn read_csv<R: std::io::Read>(&mut self, reader: R) -> Result<(), Box<dyn Error>> {
+        #[derive(Deserialize)]
+        struct Row {
+            client: u16,
+            limit: Money,
+        }
+        let mut csv_reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(reader);
+        for row in csv_reader.deserialize() {
+            let Row { client, limit } = row?;
+            if limit.is_negative() {
+                return Err(format!("Client {}'s overdraft limit is negative", client).into());
+            }
+            self.clients.insert(client, limit);
+        }
+        Ok(())
+    }
+}
+
+/// How disputes with a particular reason code are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReasonPolicy {
+    /// The lock applied when the dispute is charged back, or `None` to leave
+    /// the account unlocked. Replaces [`EngineConfig::chargeback_lock_scope`].
+    pub chargeback_lock: Option<LockScope>,
+}
+
+impl std::str::FromStr for ReasonPolicy {
+    type Err = String;
+
+    /// Parses the chargeback lock scope, or `none`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let chargeback_lock = match s {
+            "none" => None,
+            scope => Some(scope.parse()?),
+        };
+        Ok(Self { chargeback_lock })
+    }
+}
+
+/// When an account is considered dormant, and what that prevents.
+///
+/// Transactions needn't have timestamps, so inactivity is measured in
+/// transactions applied to other accounts (see [`crate::Account::dormant`]).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DormancyPolicy {
+    /// Number of transactions applied without any for an account before it
+    /// becomes dormant.
+    pub after: u64,
+    /// Reject withdrawals from dormant accounts. Any other applied
+    /// transaction (e.g. a deposit) reactivates the account.
+    pub block_withdrawals: bool,
+}
+
 /// Transaction Engine, applies transactions to accounts.
 pub struct TxEngine<T> {
     state: T,
+    config: EngineConfig,
+    plugins: Vec<Box<dyn TransactionPlugin>>,
+    events: Vec<EngineEvent>,
+    sinks: Vec<Box<dyn EventSink>>,
+    system: SystemAccounts,
+    /// Transactions queued for each quarantined client, in the order they
+    /// arrived. A client is quarantined for as long as it has an entry.
+    quarantine: BTreeMap<u16, Vec<Transaction>>,
+    /// Last sequence number assigned. Shared between the shards of a
+    /// [`crate::shared_engine::SharedTxEngine`].
+    sequence: Arc<AtomicU64>,
+    /// Total withdrawn to each destination, across all clients. Shared
+    /// like `sequence`, and always locked last.
+    destination_totals: DestinationTotals,
+    /// Transaction IDs taken on every shard, for engines sharing IDs with
+    /// other shards, whose stores only know their own. Shared like
+    /// `sequence`.
+    shared_ids: Option<SharedTxIds>,
+    /// When transactions happened.
+    clock: Box<dyn Clock>,
+    /// Where rates are looked up, if not in the config's table.
+    rates: Option<Box<dyn RateProvider>>,
+    /// Latest time of any transaction handled, if any were timed.
+    latest_time: Option<u64>,
+    /// What each client has done this run, for the risk limits.
+    velocity: HashMap<u16, Velocity>,
 }
 
+pub(crate) type DestinationTotals = Arc<Mutex<HashMap<Interned, Money>>>;
+pub(crate) type SharedTxIds = Arc<Mutex<TxIndex>>;
+
 impl<T: AccountStore> TxEngine<T> {
     /// Creates a new instance of Transaction Engine wrapping the provided
     /// account store.
     pub fn new(state: T) -> Self {
-        Self { state }
+        Self::with_config(state, EngineConfig::default())
+    }
+
+    /// As [`TxEngine::new`], applying transactions according to `config`.
+    pub fn with_config(state: T, config: EngineConfig) -> Self {
+        Self::with_sequence(state, config, Arc::default(), Arc::default(), None)
+    }
+
+    /// The policies the engine applies transactions with.
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Replaces the policies applied to later transactions, e.g. when an
+    /// operator reloads the config. Accounts are left as they are.
+    pub fn set_config(&mut self, config: EngineConfig) {
+        self.config = config;
+    }
+
+    /// As [`TxEngine::with_config`], assigning sequence numbers from a
+    /// counter, and totalling withdrawals per destination, in state that may
+    /// be shared with other engines. Shards of one engine also share the
+    /// transaction IDs taken, so IDs are unique across every shard.
+    pub(crate) fn with_sequence(
+        state: T,
+        config: EngineConfig,
+        sequence: Arc<AtomicU64>,
+        destination_totals: DestinationTotals,
+        shared_ids: Option<SharedTxIds>,
+    ) -> Self {
+        Self {
+            state,
+            config,
+            plugins: vec![],
+            events: vec![],
+            sinks: vec![],
+            system: SystemAccounts::default(),
+            quarantine: BTreeMap::new(),
+            sequence,
+            destination_totals,
+            shared_ids,
+            clock: Box::new(InputClock),
+            rates: None,
+            latest_time: None,
+            velocity: HashMap::new(),
+        }
+    }
+
+    /// Replaces where the engine gets the time transactions happened, by
+    /// default the input's timestamps (see [`InputClock`]).
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Looks up rates for conversions that don't supply one with `rates`
+    /// rather than the config's table (see [`FxPolicy::rates`]), e.g. to
+    /// follow a live feed.
+    pub fn set_rate_provider(&mut self, rates: impl RateProvider + 'static) {
+        self.rates = Some(Box::new(rates));
+    }
+
+    /// Registers a plugin to validate, and charge fees for, every subsequent
+    /// transaction. Plugins are called in the order they were added.
+    pub fn add_plugin(&mut self, plugin: Box<dyn TransactionPlugin>) {
+        self.plugins.push(plugin);
+    }
+
+    /// Registers a sink to receive a [`LedgerEvent`] for every subsequent
+    /// change to an account. Sinks are called in the order they were added.
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Takes the events raised since the last call. Events accumulate until
+    /// drained, so long-running callers should drain them regularly.
+    pub fn drain_events(&mut self) -> impl Iterator<Item = EngineEvent> + '_ {
+        self.events.drain(..)
+    }
+
+    /// The sequence number of the most recently applied transaction, or zero
+    /// if none have been applied.
+    pub fn last_sequence(&self) -> u64 {
+        self.sequence.load(Ordering::Relaxed)
+    }
+
+    /// The latest time of any transaction the engine has handled, applied
+    /// or not, if any were timed (see [`crate::clock`]).
+    pub fn latest_time(&self) -> Option<u64> {
+        self.latest_time
+    }
+
+    /// The engine's own accounts, holding the other side of every client
+    /// movement.
+    pub fn system_accounts(&self) -> &SystemAccounts {
+        &self.system
+    }
+
+    /// Quarantines a client's account, pausing it while it's investigated.
+    /// Unlike a lock, the client's transactions aren't rejected but queued,
+    /// to be applied if the quarantine is released.
+    pub fn quarantine(&mut self, client_id: u16) {
+        self.quarantine.entry(client_id).or_default();
+    }
+
+    /// Whether a client's account is quarantined.
+    pub fn is_quarantined(&self, client_id: u16) -> bool {
+        self.quarantine.contains_key(&client_id)
+    }
+
+    /// The transactions queued for each quarantined client, ordered by
+    /// client.
+    pub fn quarantined(&self) -> impl Iterator<Item = (u16, &[Transaction])> {
+        self.quarantine
+            .iter()
+            .map(|(client, queued)| (*client, queued.as_slice()))
+    }
+
+    /// Lifts a client's quarantine, applying the transactions queued for it
+    /// in the order they arrived. Returns each transaction with its result.
+    ///
+    /// The queued transactions are taken to have been reviewed, so disputes
+    /// among them aren't held to [`EngineConfig::max_held`].
+    pub fn release_quarantine(
+        &mut self,
+        client_id: u16,
+    ) -> Vec<(Transaction, Result<u64, TransactionNotApplied>)> {
+        let queued = self.quarantine.remove(&client_id).unwrap_or_default();
+        queued
+            .into_iter()
+            .map(|transaction| {
+                let result = self.apply(&transaction, false);
+                (transaction, result)
+            })
+            .collect()
+    }
+
+    /// Locks a client's account with the given scope, at an operator's
+    /// request, as a chargeback would. An account already locked more
+    /// restrictively stays that way. Returns the account's resulting scope,
+    /// or `None` if the client has no account.
+    pub fn lock_account(&mut self, client_id: u16, scope: LockScope) -> Option<LockScope> {
+        self.state.get_account(client_id)?;
+        let account = self.state.get_account_mut(client_id);
+        let before = account.lock_scope();
+        account.lock(scope);
+        let after = account.lock_scope();
+        if after != before {
+            // Locks aren't transactions, so aren't numbered; the event
+            // follows the last transaction applied.
+            let event = LedgerEvent::AccountLocked {
+                sequence: self.last_sequence(),
+                client: client_id,
+                scope: after.expect("Just locked"),
+            };
+            for sink in self.sinks.iter_mut() {
+                sink.emit(&event);
+            }
+        }
+        after
+    }
+
+    /// The deposits, withdrawals and conversions applied to the client's
+    /// account, in the order they were applied (see [`Account::history`]).
+    /// Empty if they have no account.
+    pub fn account_history(&self, client_id: u16) -> Vec<HistoryEntry> {
+        self.state
+            .get_account(client_id)
+            .map_or_else(Vec::new, Account::history)
     }
 
     /// Accesses the underlying account store directly
@@ -75,108 +632,1100 @@ impl<T: AccountStore> TxEngine<T> {
         &self.state
     }
 
+    /// As [`AccountStore::preload`].
+    pub fn preload(&mut self, clients: &[u16]) -> usize {
+        self.state.preload(clients)
+    }
+
     #[cfg(test)]
     fn store_mut(&mut self) -> &mut T {
         &mut self.state
     }
 
+    /// Applies the same dispute step to each of `items`, as if each were a
+    /// separate transaction. Returns the result for each item, in order.
+    pub fn handle_bulk_disputes(
+        &mut self,
+        action: DisputeAction,
+        items: &[DisputeItem],
+    ) -> Vec<Result<u64, TransactionNotApplied>> {
+        items
+            .iter()
+            .map(|item| {
+                self.handle(&Transaction {
+                    client_id: item.client,
+                    transaction_id: item.tx,
+                    info: action.info(item.reason),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+            })
+            .collect()
+    }
+
+    /// Establishes part of a client's opening balance, as a synthetic
+    /// deposit, followed by a synthetic dispute if the entry was already
+    /// disputed. Returns the synthetic transactions, with their sequence
+    /// numbers.
+    ///
+    /// Opening balances predate the feed, so bypass plugins, locks and
+    /// dormancy, but still take their transaction IDs.
+    pub fn open_balance(
+        &mut self,
+        entry: &OpeningEntry,
+    ) -> Result<Vec<(u64, Transaction)>, TransactionNotApplied> {
+        if self.tx_owner(entry.tx).is_some() {
+            return Err(TransactionNotApplied::RepeatTransaction(entry.tx));
+        }
+        let next_sequence = || self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let account = self.state.get_account_mut(entry.client);
+        account.set_hold_policy(self.config.hold_policy);
+        let synthetic = |info| Transaction {
+            client_id: entry.client,
+            transaction_id: entry.tx,
+            info,
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        let sequence = account.credit(
+            entry.tx,
+            &entry.amount,
+            &Money::zero(),
+            &mut self.system,
+            next_sequence,
+        )?;
+        let mut applied = vec![(
+            sequence,
+            synthetic(TransactionInfo::Deposit(entry.amount.clone())),
+        )];
+        self.take_tx(entry.tx, entry.client);
+        if entry.disputed {
+            let next_sequence = || self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+            let account = self.state.get_account_mut(entry.client);
+            let sequence = account.hold(entry.tx, None, None, &mut self.system, next_sequence)?;
+            applied.push((sequence, synthetic(TransactionInfo::Dispute(None))));
+        }
+        Ok(applied)
+    }
+
+    /// Applies a backfilled correction, posting a compensating entry (see
+    /// [`crate::Account::correct`]). Returns its sequence number and the
+    /// amount posted.
+    ///
+    /// Corrections come from the back office rather than the client, so
+    /// bypass plugins, locks and dormancy.
+    pub fn correct(
+        &mut self,
+        correction: &Correction,
+    ) -> Result<(u64, Money), TransactionNotApplied> {
+        // A correction to a deposit the account has no record of is applied
+        // as a late deposit, so takes its transaction ID.
+        let late = self
+            .state
+            .get_account(correction.client)
+            .is_none_or(|account| account.transaction(correction.tx).is_none());
+        if late && self.tx_owner(correction.tx).is_some() {
+            return Err(TransactionNotApplied::RepeatTransaction(correction.tx));
+        }
+        let next_sequence = || self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let account = self.state.get_account_mut(correction.client);
+        account.set_hold_policy(self.config.hold_policy);
+        let (sequence, posted) = account.balance_of_mut(correction.tx).correct(
+            correction.tx,
+            &correction.amount,
+            &mut self.system,
+            next_sequence,
+        )?;
+        account.touch(sequence);
+        if late {
+            self.take_tx(correction.tx, correction.client);
+        }
+        Ok((sequence, posted))
+    }
+
+    /// Attaches an operator's note to an account, raising a
+    /// [`EngineEvent::NoteAdded`] event for the audit log. Fails if the
+    /// client has no account, or if the note is on a transaction that isn't
+    /// one of the account's deposits, withdrawals or conversions.
+    pub fn annotate(&mut self, note: &Note) -> Result<(), String> {
+        let Some(account) = self.state.get_account(note.client) else {
+            return Err(format!("Client {} has no account to note.", note.client));
+        };
+        if let Some(tx) = note.tx {
+            if account.balance_with(tx).is_none() && account.conversion(tx).is_none() {
+                return Err(format!(
+                    "Transaction {} isn't one of client {}'s to note.",
+                    tx, note.client
+                ));
+            }
+        }
+        let after_sequence = self.last_sequence();
+        self.state
+            .get_account_mut(note.client)
+            .add_note(AccountNote {
+                tx: note.tx,
+                after_sequence,
+                author: note.author.clone(),
+                text: note.note.clone(),
+            });
+        self.events.push(EngineEvent::NoteAdded {
+            after_sequence,
+            client: note.client,
+            tx: note.tx,
+            author: note.author.clone(),
+            note: note.note.clone(),
+        });
+        Ok(())
+    }
+
+    /// Brings every account's interest up to `time` (see
+    /// [`crate::interest`]), e.g. at the end of a run, posting the interest
+    /// of any periods that ended by then. Returns the number of postings.
+    /// Does nothing without an [`EngineConfig::interest`] policy.
+    ///
+    /// Quarantined accounts are paused, so are left until released.
+    pub fn accrue_interest(&mut self, time: u64) -> Result<usize, TransactionNotApplied> {
+        if self.config.interest.is_none() {
+            return Ok(0);
+        }
+        let clients: Vec<u16> = self
+            .state
+            .accounts()
+            .map(Account::client)
+            .filter(|client| !self.quarantine.contains_key(client))
+            .collect();
+        let mut posted = 0;
+        for client in clients {
+            posted += self.accrue(client, time)?;
+        }
+        Ok(posted)
+    }
+
+    /// Brings `client`'s interest up to `time`, raising a
+    /// [`LedgerEvent::InterestPosted`] for each posting. Returns the number
+    /// of postings.
+    fn accrue(&mut self, client: u16, time: u64) -> Result<usize, TransactionNotApplied> {
+        let Some(policy) = &self.config.interest else {
+            return Ok(0);
+        };
+        let next_sequence = || self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let day = (time / SECONDS_PER_DAY) as i64;
+        let posted = self.state.get_account_mut(client).accrue_interest(
+            policy,
+            day,
+            &mut self.system,
+            next_sequence,
+        )?;
+        for record in &posted {
+            let event = LedgerEvent::InterestPosted {
+                sequence: record.sequence,
+                client,
+                period_end: record.period_end.clone(),
+                amount: record.amount.clone(),
+            };
+            for sink in self.sinks.iter_mut() {
+                sink.emit(&event);
+            }
+        }
+        Ok(posted.len())
+    }
+
     /// Apply a given transaction to the account store.
     ///
+    /// Returns the sequence number assigned to the transaction. Sequence
+    /// numbers start at one and increase by one with every applied
+    /// transaction, so give the order transactions were applied in
+    /// regardless of where they came from. Transactions that aren't applied
+    /// don't consume one.
+    ///
     /// Note: Returns an error for all cases where the requested transaction
     /// was not successfully applied. Some reasons may be valid and require no
     /// additional handling (i.e. not constituting a runtime "error").
     /// See [`TransactionNotApplied`] for more details.
-    pub fn handle(
+    pub fn handle(&mut self, transaction: &Transaction) -> Result<u64, TransactionNotApplied> {
+        self.apply(transaction, true)
+    }
+
+    /// As [`TxEngine::handle`], holding disputes to
+    /// [`EngineConfig::max_held`] only if `cap_held`.
+    fn apply(
+        &mut self,
+        transaction: &Transaction,
+        cap_held: bool,
+    ) -> Result<u64, TransactionNotApplied> {
+        let client = transaction.client_id;
+        let before = self
+            .config
+            .thresholds
+            .for_client(client)
+            .map(|_| self.available_balances(client));
+        let sequence = self.apply_logged(transaction, cap_held)?;
+        if self.config.risk.is_set() {
+            let time = self.clock.time_of(transaction);
+            self.velocity
+                .entry(client)
+                .or_default()
+                .record(&transaction.info, time);
+        }
+        let Some(before) = before else {
+            return Ok(sequence);
+        };
+        let thresholds = self
+            .config
+            .thresholds
+            .for_client(client)
+            .expect("Thresholds checked above");
+        for (currency, available) in self.available_balances(client) {
+            let available_before = before
+                .iter()
+                .find(|(before_currency, _)| before_currency == &currency)
+                .map_or_else(Money::zero, |(_, available)| available.clone());
+            for (threshold, limit) in thresholds.crossed(&available_before, &available) {
+                self.events.push(EngineEvent::BalanceThresholdCrossed {
+                    sequence,
+                    client,
+                    currency: currency.clone(),
+                    threshold,
+                    limit: limit.clone(),
+                    available: available.clone(),
+                });
+            }
+        }
+        Ok(sequence)
+    }
+
+    /// Available funds in each of the client's balances, by currency.
+    fn available_balances(&self, client: u16) -> Vec<(Option<Interned>, Money)> {
+        self.state
+            .get_account(client)
+            .into_iter()
+            .flat_map(Account::balances)
+            .map(|balance| (balance.currency().cloned(), balance.available_funds()))
+            .collect()
+    }
+
+    /// As [`TxEngine::apply`], raising ledger events for any sinks.
+    fn apply_logged(
+        &mut self,
+        transaction: &Transaction,
+        cap_held: bool,
+    ) -> Result<u64, TransactionNotApplied> {
+        if self.sinks.is_empty() {
+            return self.apply_owned(transaction, cap_held);
+        }
+        let before = self.state.get_account(transaction.client_id);
+        let lock_before = before.and_then(Account::lock_scope);
+        let held_before = before.and_then(|account| account.held_for(transaction.transaction_id));
+        let disputed_before = before
+            .and_then(|account| account.transaction(transaction.transaction_id))
+            .map(DepositRecord::disputed_amount);
+        let sequence = self.apply_owned(transaction, cap_held)?;
+        let events = self.ledger_events(
+            transaction,
+            sequence,
+            lock_before,
+            held_before,
+            disputed_before,
+        );
+        for sink in self.sinks.iter_mut() {
+            for event in &events {
+                sink.emit(event);
+            }
+        }
+        Ok(sequence)
+    }
+
+    /// The ledger events for `transaction`, just applied with `sequence`,
+    /// given its account's lock, and the funds held for and amount disputed
+    /// of the transaction, beforehand.
+    fn ledger_events(
+        &self,
+        transaction: &Transaction,
+        sequence: u64,
+        lock_before: Option<LockScope>,
+        held_before: Option<Money>,
+        disputed_before: Option<Money>,
+    ) -> Vec<LedgerEvent> {
+        let client = transaction.client_id;
+        let tx = transaction.transaction_id;
+        let account = self
+            .state
+            .get_account(client)
+            .expect("Account of an applied transaction");
+        let record = || {
+            account
+                .transaction(tx)
+                .expect("Record of an applied dispute step")
+        };
+        if let Some(withdrawal) = account.withdrawal(tx) {
+            let event = match &transaction.info {
+                TransactionInfo::Dispute(_) => Some(LedgerEvent::WithdrawalDisputeOpened {
+                    sequence,
+                    client,
+                    tx,
+                    held: withdrawal.held(),
+                }),
+                TransactionInfo::Resolve => Some(LedgerEvent::WithdrawalDisputeResolved {
+                    sequence,
+                    client,
+                    tx,
+                    released: held_before.clone().unwrap_or_default(),
+                }),
+                TransactionInfo::Chargeback => Some(LedgerEvent::WithdrawalChargebackApplied {
+                    sequence,
+                    client,
+                    tx,
+                    amount: withdrawal.amount.clone(),
+                }),
+                TransactionInfo::Reversal => Some(LedgerEvent::WithdrawalReversed {
+                    sequence,
+                    client,
+                    tx,
+                    amount: withdrawal.amount.clone(),
+                }),
+                _ => None,
+            };
+            if let Some(event) = event {
+                return vec![event];
+            }
+        }
+        let event = match &transaction.info {
+            TransactionInfo::Deposit(amount) => LedgerEvent::DepositApplied {
+                sequence,
+                client,
+                tx,
+                amount: amount.clone(),
+            },
+            TransactionInfo::Withdrawal(amount) => LedgerEvent::WithdrawalApplied {
+                sequence,
+                client,
+                tx,
+                amount: amount.clone(),
+            },
+            TransactionInfo::Dispute(_) => LedgerEvent::DisputeOpened {
+                sequence,
+                client,
+                tx,
+                held: record().held(),
+            },
+            TransactionInfo::Resolve => LedgerEvent::DisputeResolved {
+                sequence,
+                client,
+                tx,
+                released: held_before.unwrap_or_default(),
+            },
+            TransactionInfo::Chargeback => LedgerEvent::ChargebackApplied {
+                sequence,
+                client,
+                tx,
+                amount: disputed_before.unwrap_or_default(),
+            },
+            TransactionInfo::Unlock(operator) => LedgerEvent::AccountUnlocked {
+                sequence,
+                client,
+                operator: operator.clone(),
+            },
+            TransactionInfo::Reversal => LedgerEvent::DepositReversed {
+                sequence,
+                client,
+                tx,
+                amount: record().net_amount(),
+            },
+            TransactionInfo::Convert(_) => {
+                let record = account
+                    .conversion(tx)
+                    .expect("Record of an applied conversion");
+                LedgerEvent::ConversionApplied {
+                    sequence,
+                    client,
+                    tx,
+                    from: record.from.clone(),
+                    to: record.to.clone(),
+                    amount: record.amount.clone(),
+                    converted: record.converted.clone(),
+                }
+            }
+        };
+        let mut events = vec![event];
+        if let (TransactionInfo::Deposit(_) | TransactionInfo::Withdrawal(_), Some(fee)) =
+            (&transaction.info, account.fee(tx))
+        {
+            events.push(LedgerEvent::FeeCharged {
+                sequence,
+                client,
+                tx,
+                fee: fee.clone(),
+            });
+        }
+        if let Some(scope) = account
+            .lock_scope()
+            .filter(|scope| lock_before != Some(*scope))
+        {
+            events.push(LedgerEvent::AccountLocked {
+                sequence,
+                client,
+                scope,
+            });
+        }
+        events
+    }
+
+    /// As [`TxEngine::apply_to_account`], telling a dispute step or reversal
+    /// naming another client's transaction apart from one naming no
+    /// transaction at all. Only checked once the transaction isn't found, so
+    /// stores without an index only look through their accounts then.
+    fn apply_owned(
+        &mut self,
+        transaction: &Transaction,
+        cap_held: bool,
+    ) -> Result<u64, TransactionNotApplied> {
+        let result = self.apply_to_account(transaction, cap_held);
+        if result.is_err() {
+            self.release_tx(transaction.transaction_id, transaction.client_id);
+        }
+        result.map_err(|err| match err {
+            TransactionNotApplied::DisputedTransactionNotFound(tx)
+                if self
+                    .tx_owner(tx)
+                    .is_some_and(|owner| owner != transaction.client_id) =>
+            {
+                TransactionNotApplied::ClientMismatch(tx)
+            }
+            err => err,
+        })
+    }
+
+    /// The client whose account took transaction ID `tx`, on any shard.
+    fn tx_owner(&self, tx: u32) -> Option<u16> {
+        self.state.tx_owner(tx).or_else(|| {
+            self.shared_ids
+                .as_ref()?
+                .lock()
+                .expect("Transaction ID lock poisoned")
+                .owner(tx)
+        })
+    }
+
+    /// Takes transaction ID `tx` for the client's account, failing with
+    /// [`TransactionNotApplied::RepeatTransaction`] if it's already taken.
+    /// IDs shared with other shards are reserved at once, so two shards
+    /// can't both take one; a reservation is released if the transaction
+    /// then isn't applied (see [`TxEngine::release_tx`]).
+    fn reserve_tx(&mut self, tx: u32, client: u16) -> Result<(), TransactionNotApplied> {
+        let taken = self.state.tx_owner(tx).is_some()
+            || self.shared_ids.as_ref().is_some_and(|ids| {
+                !ids.lock()
+                    .expect("Transaction ID lock poisoned")
+                    .insert(tx, client)
+            });
+        match taken {
+            true => Err(TransactionNotApplied::RepeatTransaction(tx)),
+            false => Ok(()),
+        }
+    }
+
+    /// Records that the client's account took transaction ID `tx`.
+    fn take_tx(&mut self, tx: u32, client: u16) {
+        self.state.take_tx(tx, client);
+        if let Some(ids) = &self.shared_ids {
+            ids.lock()
+                .expect("Transaction ID lock poisoned")
+                .insert(tx, client);
+        }
+    }
+
+    /// Releases the client's reservation of transaction ID `tx`, if it has
+    /// one: an ID shared with other shards, taken for the client, but not by
+    /// their account. Clients are only ever handled on one shard, one
+    /// transaction at a time, so that's a reservation by the transaction
+    /// just handled.
+    fn release_tx(&mut self, tx: u32, client: u16) {
+        let Some(ids) = &self.shared_ids else {
+            return;
+        };
+        if self.state.tx_owner(tx) == Some(client) {
+            return;
+        }
+        let mut ids = ids.lock().expect("Transaction ID lock poisoned");
+        if ids.owner(tx) == Some(client) {
+            ids.remove(tx);
+        }
+    }
+
+    fn apply_to_account(
         &mut self,
-        Transaction {
+        transaction: &Transaction,
+        cap_held: bool,
+    ) -> Result<u64, TransactionNotApplied> {
+        let Transaction {
             client_id,
             transaction_id,
             info,
-        }: &Transaction,
-    ) -> Result<(), TransactionNotApplied> {
+            destination,
+            dispute_amount,
+            timestamp: _,
+            currency,
+        } = transaction;
+        let time = self.clock.time_of(transaction);
+        self.latest_time = self.latest_time.max(time);
+        if let Some(queued) = self.quarantine.get_mut(client_id) {
+            queued.push(transaction.clone());
+            return Err(TransactionNotApplied::Quarantined);
+        }
+        // Up to the transaction's day, whether or not it's then applied.
+        if let Some(time) = time {
+            self.accrue(*client_id, time)?;
+        }
+        // Transaction IDs are unique across clients, not just within each
+        // account's records.
+        let takes_id = matches!(
+            info,
+            TransactionInfo::Deposit(_)
+                | TransactionInfo::Withdrawal(_)
+                | TransactionInfo::Convert(_)
+        );
+        if takes_id {
+            self.reserve_tx(*transaction_id, *client_id)?;
+        }
         let account = self.state.get_account_mut(*client_id);
-        if account.locked {
-            return Err(TransactionNotApplied::AccountLocked);
+        account.set_hold_policy(self.config.hold_policy);
+        if let Some(mut scope) = account.lock_scope() {
+            if self.config.disputes_on_locked_accounts {
+                scope = scope.min(LockScope::AllowDisputes);
+            }
+            if !scope.allows(info) {
+                return Err(TransactionNotApplied::AccountLocked);
+            }
         }
-        match info {
-            TransactionInfo::Deposit(amount) => {
-                if account.transactions.contains_key(transaction_id) {
+        if let Some(dormancy) = self.config.dormancy {
+            if dormancy.block_withdrawals
+                && matches!(info, TransactionInfo::Withdrawal(_))
+                && account.dormant(self.sequence.load(Ordering::Relaxed), dormancy.after)
+            {
+                return Err(TransactionNotApplied::AccountDormant);
+            }
+        }
+        if self.config.risk.is_set() {
+            self.velocity
+                .entry(*client_id)
+                .or_default()
+                .check(&self.config.risk, info, time)
+                .map_err(TransactionNotApplied::LimitExceeded)?;
+        }
+        for plugin in self.plugins.iter_mut() {
+            plugin.validate(transaction, account)?;
+        }
+        // Only taken once nothing more can fail, so applied transactions are
+        // numbered without gaps.
+        let next_sequence = || self.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        let status = account.dispute_status(*transaction_id);
+        // Deposits and withdrawals apply to the balance in their currency,
+        // dispute steps to the balance of the transaction they dispute.
+        let account = match info {
+            TransactionInfo::Deposit(_) | TransactionInfo::Withdrawal(_) => {
+                if status.is_some() || account.conversion(*transaction_id).is_some() {
                     return Err(TransactionNotApplied::RepeatTransaction(*transaction_id));
                 }
-                account.total_funds += *amount;
-                account
-                    .transactions
-                    .insert(*transaction_id, DepositRecord::new(*amount));
+                account.balance_mut(currency.as_ref())
             }
-            TransactionInfo::Withdrawal(amount) => {
-                if account.available_funds() < *amount {
-                    return Err(TransactionNotApplied::InsufficientFunds);
-                } else {
-                    account.total_funds -= *amount;
+            // Conversions span balances, so are kept on the primary one.
+            TransactionInfo::Unlock(_) | TransactionInfo::Convert(_) => account,
+            _ => {
+                let balance = account.balance_of_mut(*transaction_id);
+                if status.is_some() && currency.is_some() && balance.currency() != currency.as_ref()
+                {
+                    return Err(TransactionNotApplied::DisputedTransactionNotFound(
+                        *transaction_id,
+                    ));
                 }
+                balance
             }
-            TransactionInfo::Dispute => {
-                let tx_record = account.transactions.get_mut(transaction_id).ok_or(
-                    TransactionNotApplied::DisputedTransactionNotFound(*transaction_id),
+        };
+        let mut lock = None;
+        let sequence = match info {
+            TransactionInfo::Deposit(amount) => {
+                let fee = transaction_fees(&mut self.plugins, &self.config.fees, transaction)?;
+                let sequence = account.credit(
+                    *transaction_id,
+                    amount,
+                    &fee,
+                    &mut self.system,
+                    next_sequence,
                 )?;
-                if let Err(err) = tx_record.disputed() {
-                    return Err(TransactionNotApplied::InvalidDisputeState(err));
+                account.record_time(*transaction_id, time);
+                sequence
+            }
+            TransactionInfo::Withdrawal(amount) => {
+                let Some(destination) = destination else {
+                    let fee = transaction_fees(&mut self.plugins, &self.config.fees, transaction)?;
+                    let sequence = account.debit(
+                        *transaction_id,
+                        amount,
+                        &fee,
+                        self.config.overdraft.limit_for(*client_id),
+                        &mut self.system,
+                        next_sequence,
+                    )?;
+                    account.record_time(*transaction_id, time);
+                    self.state.get_account_mut(*client_id).touch(sequence);
+                    self.take_tx(*transaction_id, *client_id);
+                    return Ok(sequence);
+                };
+                let policy = &self.config.destinations;
+                if policy.blocklist.contains(destination.as_str()) {
+                    return Err(TransactionNotApplied::DestinationBlocked);
                 }
-                account.active_dispute_total += tx_record.amount;
+                // Held until the withdrawal is applied, so concurrent
+                // withdrawals to the same destination can't both fit under
+                // its limit.
+                let mut totals = self
+                    .destination_totals
+                    .lock()
+                    .expect("Destination totals lock poisoned");
+                let total = totals
+                    .get(destination)
+                    .unwrap_or(&Money::zero())
+                    .checked_add(amount)
+                    .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+                if policy
+                    .limit(destination)
+                    .is_some_and(|limit| &total > limit)
+                {
+                    return Err(TransactionNotApplied::DestinationLimitExceeded);
+                }
+                let fee = transaction_fees(&mut self.plugins, &self.config.fees, transaction)?;
+                let sequence = account.debit(
+                    *transaction_id,
+                    amount,
+                    &fee,
+                    self.config.overdraft.limit_for(*client_id),
+                    &mut self.system,
+                    next_sequence,
+                )?;
+                totals.insert(destination.clone(), total);
+                account.record_time(*transaction_id, time);
+                account.record_withdrawal(WithdrawalRecord {
+                    tx: *transaction_id,
+                    destination: destination.clone(),
+                    amount: amount.clone(),
+                    sequence,
+                });
+                sequence
             }
-            TransactionInfo::Resolve => {
-                let tx_record = account.transactions.get_mut(transaction_id).ok_or(
-                    TransactionNotApplied::DisputedTransactionNotFound(*transaction_id),
+            TransactionInfo::Dispute(reason) => {
+                if let (Some(days), Some(time), Some(recorded)) = (
+                    self.config.dispute_window_days,
+                    time,
+                    account.time_of(*transaction_id),
+                ) {
+                    if time > recorded.saturating_add(days.saturating_mul(SECONDS_PER_DAY)) {
+                        return Err(TransactionNotApplied::DisputeWindowExpired(*transaction_id));
+                    }
+                }
+                let max_held = self.config.max_held.as_ref().filter(|_| cap_held);
+                let to_hold = match account.transaction(*transaction_id) {
+                    Some(record) => {
+                        let disputed = dispute_amount
+                            .clone()
+                            .unwrap_or_else(|| record.disputable());
+                        Some(account.hold_for_dispute(&disputed))
+                    }
+                    None => account
+                        .withdrawal(*transaction_id)
+                        .map(|record| record.amount.clone()),
+                };
+                if let (Some(max_held), Some(held)) = (max_held, to_hold) {
+                    // Disputes that can't begin are left to fail as usual.
+                    let can_begin = matches!(
+                        status,
+                        Some(
+                            DisputeStatus::NotDisputed
+                                | DisputeStatus::Resolved
+                                | DisputeStatus::PartiallyRefunded
+                        )
+                    );
+                    if can_begin && &(account.active_dispute_total() + &held) > max_held {
+                        self.events.push(EngineEvent::DisputeReferred {
+                            after_sequence: self.sequence.load(Ordering::Relaxed),
+                            client: *client_id,
+                            tx: *transaction_id,
+                            held,
+                            max_held: max_held.clone(),
+                        });
+                        self.quarantine
+                            .insert(*client_id, vec![transaction.clone()]);
+                        return Err(TransactionNotApplied::Quarantined);
+                    }
+                }
+                let shortfall_before = account.dispute_shortfall();
+                let sequence = account.hold(
+                    *transaction_id,
+                    *reason,
+                    dispute_amount.as_ref(),
+                    &mut self.system,
+                    next_sequence,
                 )?;
-                if let Err(err) = tx_record.resolved() {
-                    return Err(TransactionNotApplied::InvalidDisputeState(err));
+                let account_shortfall = account.dispute_shortfall();
+                if account_shortfall > shortfall_before {
+                    self.events.push(EngineEvent::DisputeShortfall {
+                        sequence,
+                        client: *client_id,
+                        tx: *transaction_id,
+                        shortfall: &account_shortfall - &shortfall_before,
+                        account_shortfall,
+                    });
                 }
-                let resolved_amount = tx_record.amount;
-                // If this transaction ammount > current disputed funds,
-                // then something has gone wrong and we may have failed to
-                // hold sufficient funds for any remaining disputes. This
-                // doesn't directly affect our ability to resolve _this_
-                // dispute, but may indicate past or future bad handling,
-                // so drop an error log.
-                if account.free_disputed_amount(&resolved_amount) {
-                    // TODO log it
+                sequence
+            }
+            TransactionInfo::Resolve => {
+                if self.config.idempotent_settlement && status == Some(DisputeStatus::Resolved) {
+                    return Err(TransactionNotApplied::AlreadyApplied(*transaction_id));
                 }
+                account.release(*transaction_id, &mut self.system, next_sequence)?
             }
             TransactionInfo::Chargeback => {
-                let tx_record = account.transactions.get_mut(transaction_id).ok_or(
-                    TransactionNotApplied::DisputedTransactionNotFound(*transaction_id),
-                )?;
-                if let Err(err) = tx_record.refunded() {
-                    return Err(TransactionNotApplied::InvalidDisputeState(err));
+                let refunded = matches!(
+                    status,
+                    Some(DisputeStatus::Refunded | DisputeStatus::PartiallyRefunded)
+                );
+                if self.config.idempotent_settlement && refunded {
+                    return Err(TransactionNotApplied::AlreadyApplied(*transaction_id));
                 }
-                let cb_amount = tx_record.amount;
-                if account.free_disputed_amount(&cb_amount) {
-                    // TODO log it
-                }
-                account.total_funds -= cb_amount;
-                account.locked = true;
+                lock = match account.transaction(*transaction_id) {
+                    Some(record) => match record
+                        .reason
+                        .and_then(|reason| self.config.reason_policies.get(&reason))
+                    {
+                        Some(policy) => policy.chargeback_lock,
+                        None => Some(self.config.chargeback_lock_scope),
+                    },
+                    // Charging back a withdrawal credits the client, so is
+                    // no reason to lock their account.
+                    None => None,
+                };
+                account.charge_back(*transaction_id, &mut self.system, next_sequence)?
+            }
+            TransactionInfo::Reversal => {
+                let (sequence, _) =
+                    account.reverse(*transaction_id, &mut self.system, next_sequence)?;
+                sequence
+            }
+            TransactionInfo::Unlock(operator) => {
+                let Some(scope) = account.lock_scope() else {
+                    return Err(TransactionNotApplied::AlreadyApplied(*transaction_id));
+                };
+                let sequence = account.unlock(next_sequence);
+                self.events.push(EngineEvent::AccountUnlocked {
+                    sequence,
+                    client: *client_id,
+                    tx: *transaction_id,
+                    operator: operator.clone(),
+                    scope,
+                });
+                sequence
+            }
+            TransactionInfo::Convert(conversion) => {
+                let unavailable = TransactionNotApplied::RateUnavailable(*transaction_id);
+                let Some(from) = currency else {
+                    return Err(unavailable);
+                };
+                let rate = match (&conversion.rate, &self.rates) {
+                    (Some(rate), _) => rate.clone(),
+                    (None, Some(rates)) => rates.rate(from, &conversion.to).ok_or(unavailable)?,
+                    (None, None) => self
+                        .config
+                        .fx
+                        .rates
+                        .rate(from, &conversion.to)
+                        .ok_or(unavailable)?,
+                };
+                let converted = self
+                    .config
+                    .fx
+                    .convert(&conversion.amount, &rate, &conversion.to)
+                    .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+                account.convert(
+                    *transaction_id,
+                    from,
+                    &conversion.to,
+                    &conversion.amount,
+                    &converted,
+                    &rate,
+                    &mut self.system,
+                    next_sequence,
+                )?
             }
         };
+        // Locks and activity are the client's, whichever balance the
+        // transaction applied to.
+        let account = self.state.get_account_mut(*client_id);
+        account.touch(sequence);
+        if let Some(scope) = lock {
+            account.lock(scope);
+        }
+        if takes_id {
+            self.take_tx(*transaction_id, *client_id);
+        }
+        Ok(sequence)
+    }
+}
+
+impl TxEngine<InMemoryStore> {
+    /// As [`TxEngine::handle`], applying the transaction to `account` rather
+    /// than an account in the engine's store, for stores that lend out one
+    /// account at a time (see [`crate::async_store`]).
+    pub(crate) fn handle_on(
+        &mut self,
+        account: &mut Account,
+        transaction: &Transaction,
+    ) -> Result<u64, TransactionNotApplied> {
+        self.state.lend(std::mem::take(account));
+        let result = self.handle(transaction);
+        *account = self
+            .state
+            .remove(transaction.client_id)
+            .expect("Account inserted above");
+        result
+    }
+}
+
+impl TxEngine<InMemoryStore> {
+    /// One engine holding the state of every one of `shards`, e.g. to
+    /// snapshot an engine split into shards (see
+    /// [`crate::SharedTxEngine::snapshot`]). The shards are left as they
+    /// are. Takes the first shard's config.
+    pub(crate) fn merge(shards: &[&TxEngine<InMemoryStore>]) -> Self {
+        let mut accounts = InMemoryStore::new();
+        let mut system = SystemAccounts::default();
+        let mut events = vec![];
+        let mut quarantine = BTreeMap::new();
+        for shard in shards {
+            for account in shard.state.accounts() {
+                // Accounts aren't `Clone`, as their records are pooled (see
+                // `crate::arena`), so are copied through their serialized
+                // form.
+                let value = serde_json::to_value(account).expect("Accounts always serialize");
+                accounts.insert(serde_json::from_value(value).expect("Accounts round trip"));
+            }
+            system = system + shard.system.clone();
+            events.extend(shard.events.iter().cloned());
+            quarantine.extend(
+                shard
+                    .quarantine
+                    .iter()
+                    .map(|(client, queued)| (*client, queued.clone())),
+            );
+        }
+        EngineVisitor::restore(EngineState {
+            config: shards
+                .first()
+                .map_or_else(EngineConfig::default, |shard| shard.config.clone()),
+            sequence: shards
+                .iter()
+                .map(|shard| shard.last_sequence())
+                .max()
+                .unwrap_or(0),
+            system,
+            accounts,
+            events,
+            quarantine,
+        })
+    }
+
+    /// A copy of the engine holding only `client`'s account (if it has
+    /// one), with the same config, sequence number and system accounts,
+    /// e.g. to replay a transaction for the client against (see
+    /// [`crate::repro`]). Events and quarantined transactions aren't
+    /// copied.
+    pub fn extract(&self, client: u16) -> Self {
+        let mut accounts = InMemoryStore::new();
+        if let Some(account) = self.state.get_account(client) {
+            // Copied through its serialized form, as in `merge`.
+            let value = serde_json::to_value(account).expect("Accounts always serialize");
+            accounts.insert(serde_json::from_value(value).expect("Accounts round trip"));
+        }
+        EngineVisitor::restore(EngineState {
+            config: self.config.clone(),
+            sequence: self.last_sequence(),
+            system: self.system.clone(),
+            accounts,
+            events: vec![],
+            quarantine: BTreeMap::new(),
+        })
+    }
+}
+
+/// Version of the serialized engine state, increased whenever its layout
+/// changes incompatibly.
+pub const STATE_VERSION: u32 = 1;
+
+/// Everything in a serialized [`TxEngine`] other than its version.
+#[derive(Serialize)]
+struct EngineStateRef<'a> {
+    config: &'a EngineConfig,
+    sequence: u64,
+    system: &'a SystemAccounts,
+    accounts: &'a InMemoryStore,
+    events: &'a [EngineEvent],
+    quarantine: &'a BTreeMap<u16, Vec<Transaction>>,
+}
+
+#[derive(Deserialize)]
+struct EngineState {
+    config: EngineConfig,
+    sequence: u64,
+    system: SystemAccounts,
+    accounts: InMemoryStore,
+    events: Vec<EngineEvent>,
+    #[serde(default)]
+    quarantine: BTreeMap<u16, Vec<Transaction>>,
+}
+
+/// Serializes the engine's whole state: its config, accounts, system
+/// accounts, sequence number, undrained events and quarantined
+/// transactions, tagged with
+/// [`STATE_VERSION`]. Plugins aren't part of the state, so must be added
+/// again once restored.
+impl Serialize for TxEngine<InMemoryStore> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("TxEngine", 2)?;
+        state.serialize_field("version", &STATE_VERSION)?;
+        state.serialize_field(
+            "state",
+            &EngineStateRef {
+                config: &self.config,
+                sequence: self.last_sequence(),
+                system: &self.system,
+                accounts: &self.state,
+                events: &self.events,
+                quarantine: &self.quarantine,
+            },
+        )?;
+        state.end()
+    }
+}
+
+/// Restores an engine serialized by the same [`STATE_VERSION`]. The version
+/// must come first, so state from other versions is rejected before its
+/// layout is relied on.
+impl<'de> Deserialize<'de> for TxEngine<InMemoryStore> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("TxEngine", &["version", "state"], EngineVisitor)
+    }
+}
+
+struct EngineVisitor;
+
+impl EngineVisitor {
+    fn check_version<E: de::Error>(version: u32) -> Result<(), E> {
+        if version != STATE_VERSION {
+            return Err(E::custom(format!(
+                "unsupported engine state version {}, expected {}",
+                version, STATE_VERSION
+            )));
+        }
         Ok(())
     }
+
+    fn restore(state: EngineState) -> TxEngine<InMemoryStore> {
+        // Rebuilt rather than stored, as the accounts record every tagged
+        // withdrawal.
+        let mut destination_totals: HashMap<Interned, Money> = HashMap::new();
+        for record in state
+            .accounts
+            .accounts()
+            .flat_map(|account| account.tagged_withdrawals())
+        {
+            let total = destination_totals
+                .entry(record.destination.clone())
+                .or_default();
+            *total = &*total + &record.amount;
+        }
+        let mut engine = TxEngine::with_sequence(
+            state.accounts,
+            state.config,
+            Arc::new(AtomicU64::new(state.sequence)),
+            Arc::new(Mutex::new(destination_totals)),
+            None,
+        );
+        engine.system = state.system;
+        engine.events = state.events;
+        engine.quarantine = state.quarantine;
+        engine
+    }
+}
+
+impl<'de> Visitor<'de> for EngineVisitor {
+    type Value = TxEngine<InMemoryStore>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a versioned engine state")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let version = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+        Self::check_version(version)?;
+        let state = seq
+            .next_element()?
+            .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+        Ok(Self::restore(state))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        match map.next_key::<String>()?.as_deref() {
+            Some("version") => Self::check_version(map.next_value()?)?,
+            _ => return Err(de::Error::missing_field("version")),
+        }
+        match map.next_key::<String>()?.as_deref() {
+            Some("state") => Ok(Self::restore(map.next_value()?)),
+            _ => Err(de::Error::missing_field("state")),
+        }
+    }
+}
+
+/// Sums the fees charged for a transaction by the engine's fee policy and
+/// all plugins. A deposit's fees are capped at the deposit, so they can't
+/// take more than was paid in.
+fn transaction_fees(
+    plugins: &mut [Box<dyn TransactionPlugin>],
+    policy: &FeePolicy,
+    transaction: &Transaction,
+) -> Result<Money, TransactionNotApplied> {
+    let mut total = policy
+        .fee(&transaction.info)
+        .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+    for plugin in plugins.iter_mut() {
+        total = total
+            .checked_add(&plugin.fee(transaction)?)
+            .ok_or(TransactionNotApplied::ArithmeticOverflow)?;
+    }
+    if let TransactionInfo::Deposit(amount) = &transaction.info {
+        total = total.min(amount.clone());
+    }
+    Ok(total)
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::account::{Account, DisputeStatus};
+    use crate::account::DisputeStatus;
     use crate::account_store::{AccountStore, InMemoryStore};
-    use rust_decimal_macros::dec;
+    use crate::money::money;
+    use crate::thresholds::{ThresholdKind, Thresholds};
+    use crate::transaction::Conversion;
+    use crate::trial_balance::{Flows, TrialBalance};
 
     const CLIENT_ID_DEFAULT: u16 = 123;
     const TX_ID_DEFAULT: u32 = 1;
 
     fn engine_with_def_account() -> TxEngine<InMemoryStore> {
-        let stub_store = InMemoryStore::new_with_data(vec![Account {
-            client: CLIENT_ID_DEFAULT,
-            locked: false,
-            ..Account::default()
-        }]);
+        let stub_store = InMemoryStore::new_with_data(vec![Account::new(CLIENT_ID_DEFAULT)]);
         TxEngine::new(stub_store)
     }
 
@@ -191,21 +1740,40 @@ mod test {
         (Withdrawal, $amount:expr) => {
             txn!(Withdrawal, $amount, TX_ID_DEFAULT)
         };
-        ($txn_typ:ident, $txn_id:expr) => {
-            txn!($txn_typ, None, $txn_id)
+        (Dispute, $txn_id:expr) => {
+            Transaction {
+                client_id: CLIENT_ID_DEFAULT,
+                transaction_id: $txn_id,
+                info: TransactionInfo::Dispute(None),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            }
+        };
+        ($txn_typ:ident, $txn_id:expr) => {
+            txn!($txn_typ, None, $txn_id)
         };
         ($txn_typ:ident, None, $txn_id:expr) => {
             Transaction {
                 client_id: CLIENT_ID_DEFAULT,
                 transaction_id: $txn_id,
                 info: TransactionInfo::$txn_typ,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         };
         ($txn_typ:ident, $amount:expr, $txn_id:expr) => {
             Transaction {
                 client_id: CLIENT_ID_DEFAULT,
                 transaction_id: $txn_id,
-                info: TransactionInfo::$txn_typ(dec!($amount)),
+                info: TransactionInfo::$txn_typ(money!($amount)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
             }
         };
     }
@@ -215,16 +1783,16 @@ mod test {
         let mut engine = engine_with_def_account();
         {
             let acc = engine.store_mut().get_account_mut(123);
-            acc.locked = true;
+            acc.lock(LockScope::BlockAll);
         }
         let resp = engine.handle(&txn!(Deposit, 1)).unwrap_err();
         assert_eq!(resp, TransactionNotApplied::AccountLocked);
 
         // Nothing changed since not applied.
         let acc = engine.store().get_account(123).unwrap();
-        assert_eq!(acc.available_funds(), dec!(0));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert!(!acc.transactions.contains_key(&1));
+        assert_eq!(acc.available_funds(), money!(0));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert!(acc.transaction(1).is_none());
     }
 
     #[test]
@@ -233,9 +1801,9 @@ mod test {
         engine.handle(&txn!(Deposit, 1)).unwrap();
 
         let acc = engine.store().get_account(123).unwrap();
-        assert_eq!(acc.available_funds(), dec!(1));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert!(acc.transactions.contains_key(&1));
+        assert_eq!(acc.available_funds(), money!(1));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert!(acc.transaction(1).is_some());
     }
 
     #[test]
@@ -245,9 +1813,9 @@ mod test {
         engine.handle(&txn!(Withdrawal, 50, 2)).unwrap();
 
         let acc = engine.store().get_account(123).unwrap();
-        assert_eq!(acc.available_funds(), dec!(50));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert!(!acc.transactions.contains_key(&2));
+        assert_eq!(acc.available_funds(), money!(50));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert!(acc.transaction(2).is_none());
     }
 
     #[test]
@@ -255,12 +1823,53 @@ mod test {
         let mut engine = engine_with_def_account();
         engine.handle(&txn!(Deposit, 100)).unwrap();
         let resp = engine.handle(&txn!(Withdrawal, 150, 2)).unwrap_err();
-        assert_eq!(resp, TransactionNotApplied::InsufficientFunds);
+        assert_eq!(resp, TransactionNotApplied::InsufficientFunds(money!(50)));
+
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(100));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert!(acc.transaction(2).is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigdecimal"))]
+    fn deposit_overflow() {
+        let mut engine = engine_with_def_account();
+        let max = Money::from(rust_decimal::Decimal::MAX);
+        engine.handle(&txn!(Deposit, 1, 1)).unwrap();
+        engine
+            .store_mut()
+            .get_account_mut(123)
+            .set_funds(max.clone(), Money::zero());
+        let resp = engine.handle(&txn!(Deposit, 1, 2)).unwrap_err();
+        assert_eq!(resp, TransactionNotApplied::ArithmeticOverflow);
+        assert!(resp.is_failure());
+
+        // Nothing changed since not applied.
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &max);
+        assert!(acc.transaction(2).is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "bigdecimal"))]
+    fn chargeback_overflow_leaves_dispute_open() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 1, 1)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine
+            .store_mut()
+            .get_account_mut(123)
+            .set_funds(Money::from(rust_decimal::Decimal::MIN), money!(1));
+        let resp = engine.handle(&txn!(Chargeback, 1)).unwrap_err();
+        assert_eq!(resp, TransactionNotApplied::ArithmeticOverflow);
 
         let acc = engine.store().get_account(123).unwrap();
-        assert_eq!(acc.available_funds(), dec!(100));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert!(!acc.transactions.contains_key(&2));
+        assert!(!acc.locked());
+        assert_eq!(
+            acc.transaction(1).unwrap().dispute_status(),
+            DisputeStatus::Disputed
+        );
     }
 
     #[test]
@@ -269,6 +1878,41 @@ mod test {
         engine.handle(&txn!(Deposit, 100, 1)).unwrap();
         let resp = engine.handle(&txn!(Deposit, 150, 1)).unwrap_err();
         assert!(matches!(resp, TransactionNotApplied::RepeatTransaction(_)));
+
+        // IDs are unique across clients. Transactions that fail don't take
+        // their IDs.
+        let other_client = |info| Transaction {
+            client_id: 7,
+            info,
+            ..txn!(Resolve, 1)
+        };
+        assert_eq!(
+            engine.handle(&other_client(TransactionInfo::Deposit(money!(5)))),
+            Err(TransactionNotApplied::RepeatTransaction(1))
+        );
+        assert_eq!(
+            engine.handle(&other_client(TransactionInfo::Withdrawal(money!(5)))),
+            Err(TransactionNotApplied::RepeatTransaction(1))
+        );
+        assert!(engine
+            .store()
+            .get_account(7)
+            .is_none_or(|acc| acc.total_funds() == &money!(0)));
+        engine.handle(&txn!(Withdrawal, 500, 2)).unwrap_err();
+        engine
+            .handle(&Transaction {
+                transaction_id: 2,
+                ..other_client(TransactionInfo::Deposit(money!(5)))
+            })
+            .unwrap();
+
+        // Restored engines keep the index.
+        let json = serde_json::to_string(&engine).unwrap();
+        let mut restored: TxEngine<InMemoryStore> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            restored.handle(&txn!(Deposit, 1, 2)),
+            Err(TransactionNotApplied::RepeatTransaction(2))
+        );
     }
 
     #[test]
@@ -285,6 +1929,32 @@ mod test {
             resp,
             TransactionNotApplied::DisputedTransactionNotFound(_)
         ));
+
+        // Another client's transaction is told apart from none at all.
+        engine
+            .handle(&Transaction {
+                client_id: 7,
+                ..txn!(Deposit, 5, 3)
+            })
+            .unwrap();
+        for info in [
+            TransactionInfo::Dispute(None),
+            TransactionInfo::Resolve,
+            TransactionInfo::Chargeback,
+            TransactionInfo::Reversal,
+        ] {
+            assert_eq!(
+                engine.handle(&Transaction {
+                    info,
+                    ..txn!(Resolve, 3)
+                }),
+                Err(TransactionNotApplied::ClientMismatch(3))
+            );
+        }
+        assert_eq!(
+            engine.store().get_account(7).unwrap().total_funds(),
+            &money!(5)
+        );
     }
 
     #[test]
@@ -339,35 +2009,1431 @@ mod test {
         engine.handle(&txn!(Dispute, 1)).unwrap();
         {
             let acc = engine.store().get_account(123).unwrap();
-            assert_eq!(acc.available_funds(), dec!(50));
-            assert_eq!(acc.held_funds(), dec!(100));
-            assert!(acc.transactions.get(&1).unwrap().dispute_status() == DisputeStatus::Disputed);
+            assert_eq!(acc.available_funds(), money!(50));
+            assert_eq!(acc.held_funds(), money!(100));
+            assert!(acc.transaction(1).unwrap().dispute_status() == DisputeStatus::Disputed);
         }
 
         // Resolve it
         engine.handle(&txn!(Resolve, 1)).unwrap();
         {
             let acc = engine.store().get_account(123).unwrap();
-            assert_eq!(acc.available_funds(), dec!(150));
-            assert_eq!(acc.held_funds(), dec!(0));
-            assert!(acc.transactions.get(&1).unwrap().dispute_status() == DisputeStatus::Resolved);
+            assert_eq!(acc.available_funds(), money!(150));
+            assert_eq!(acc.held_funds(), money!(0));
+            assert!(acc.transaction(1).unwrap().dispute_status() == DisputeStatus::Resolved);
         }
 
         // Re-initialize the dispute.
         engine.handle(&txn!(Dispute, 1)).unwrap();
         {
             let acc = engine.store().get_account(123).unwrap();
-            assert_eq!(acc.available_funds(), dec!(50));
-            assert_eq!(acc.held_funds(), dec!(100));
-            assert!(acc.transactions.get(&1).unwrap().dispute_status() == DisputeStatus::Disputed);
+            assert_eq!(acc.available_funds(), money!(50));
+            assert_eq!(acc.held_funds(), money!(100));
+            assert!(acc.transaction(1).unwrap().dispute_status() == DisputeStatus::Disputed);
         }
 
         // Now chargeback.
         engine.handle(&txn!(Chargeback, 1)).unwrap();
         let acc = engine.store().get_account(123).unwrap();
-        assert_eq!(acc.available_funds(), dec!(50));
-        assert_eq!(acc.held_funds(), dec!(0));
-        assert!(acc.transactions.get(&1).unwrap().dispute_status() == DisputeStatus::Refunded);
-        assert!(acc.locked);
+        assert_eq!(acc.available_funds(), money!(50));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert!(acc.transaction(1).unwrap().dispute_status() == DisputeStatus::Refunded);
+        assert!(acc.locked());
+    }
+
+    #[test]
+    fn withdrawal_dispute_transitions() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 30, 2)).unwrap();
+        assert_eq!(
+            engine.handle(&txn!(Deposit, 5, 2)),
+            Err(TransactionNotApplied::RepeatTransaction(2))
+        );
+        let balances = |engine: &TxEngine<InMemoryStore>| {
+            let acc = engine.store().get_account(123).unwrap();
+            (
+                acc.available_funds(),
+                acc.held_funds(),
+                acc.total_funds().clone(),
+                acc.dispute_status(2).unwrap(),
+            )
+        };
+
+        // The withdrawn funds are returned, but held.
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(70), money!(30), money!(100), DisputeStatus::Disputed)
+        );
+
+        // Resolving takes them back out.
+        engine.handle(&txn!(Resolve, 2)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(70), money!(0), money!(70), DisputeStatus::Resolved)
+        );
+
+        // A chargeback credits them to the client, without locking the
+        // account.
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        engine.handle(&txn!(Chargeback, 2)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(100), money!(0), money!(100), DisputeStatus::Refunded)
+        );
+        assert!(!engine.store().get_account(123).unwrap().locked());
+        let system = engine.system_accounts();
+        assert_eq!(system.escrow, money!(-100));
+    }
+
+    #[test]
+    fn account_history() {
+        let mut engine = engine_with_def_account();
+        let eur = |transaction: Transaction| Transaction {
+            currency: Some(Interned::from("EUR")),
+            ..transaction
+        };
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&eur(txn!(Deposit, 20, 2))).unwrap();
+        engine.handle(&txn!(Withdrawal, 30, 3)).unwrap();
+        engine.handle(&txn!(Withdrawal, 500, 4)).unwrap_err();
+        engine.handle(&eur(txn!(Withdrawal, 5, 5))).unwrap();
+        engine.handle(&txn!(Dispute, 3)).unwrap();
+
+        // Withdrawals are recorded alongside deposits, in every currency,
+        // in applied order. Failed transactions aren't.
+        let history = engine.account_history(123);
+        let entries: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.tx, entry.kind, entry.amount.clone()))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (1, "deposit", money!(100)),
+                (2, "deposit", money!(20)),
+                (3, "withdrawal", money!(30)),
+                (5, "withdrawal", money!(5)),
+            ]
+        );
+        assert_eq!(history[1].currency, Some(Interned::from("EUR")));
+        assert_eq!(history[2].dispute_status, Some(DisputeStatus::Disputed));
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].sequence < pair[1].sequence));
+        assert!(engine.account_history(7).is_empty());
+    }
+
+    #[test]
+    fn reversals() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 30, 2)).unwrap();
+        engine.handle(&txn!(Deposit, 10, 3)).unwrap();
+        engine.handle(&txn!(Dispute, 3)).unwrap();
+        let balances = |engine: &TxEngine<InMemoryStore>| {
+            let acc = engine.store().get_account(123).unwrap();
+            (
+                acc.available_funds(),
+                acc.held_funds(),
+                acc.total_funds().clone(),
+            )
+        };
+
+        // Reversing a withdrawal credits it back, without holding anything.
+        engine.handle(&txn!(Reversal, 2)).unwrap();
+        assert_eq!(balances(&engine), (money!(100), money!(10), money!(110)));
+
+        // Reversing a deposit debits it, without locking the account.
+        engine.handle(&txn!(Reversal, 1)).unwrap();
+        assert_eq!(balances(&engine), (money!(0), money!(10), money!(10)));
+        let acc = engine.store().get_account(123).unwrap();
+        assert!(!acc.locked());
+        assert_eq!(acc.dispute_status(1), Some(DisputeStatus::Reversed));
+        assert_eq!(acc.dispute_status(2), Some(DisputeStatus::Reversed));
+
+        // Reversed transactions are final, and disputed ones can't be
+        // reversed until settled.
+        for (info, tx) in [
+            (TransactionInfo::Reversal, 1),
+            (TransactionInfo::Dispute(None), 2),
+            (TransactionInfo::Reversal, 3),
+        ] {
+            assert!(matches!(
+                engine.handle(&Transaction {
+                    info,
+                    ..txn!(Resolve, tx)
+                }),
+                Err(TransactionNotApplied::InvalidDisputeState(_))
+            ));
+        }
+        assert_eq!(
+            engine.handle(&txn!(Reversal, 4)),
+            Err(TransactionNotApplied::DisputedTransactionNotFound(4))
+        );
+        engine.handle(&txn!(Resolve, 3)).unwrap();
+        engine.handle(&txn!(Reversal, 3)).unwrap();
+        assert_eq!(balances(&engine), (money!(0), money!(0), money!(0)));
+        assert_eq!(engine.system_accounts().escrow, money!(0));
+    }
+
+    #[test]
+    fn partial_dispute_transitions() {
+        let config = EngineConfig {
+            chargeback_lock_scope: LockScope::AllowDisputes,
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        let dispute = |amount: Option<Money>| Transaction {
+            dispute_amount: amount,
+            ..txn!(Dispute, 1)
+        };
+        let balances = |engine: &TxEngine<InMemoryStore>| {
+            let acc = engine.store().get_account(123).unwrap();
+            (
+                acc.available_funds(),
+                acc.held_funds(),
+                acc.total_funds().clone(),
+                acc.dispute_status(1).unwrap(),
+            )
+        };
+
+        assert!(matches!(
+            engine.handle(&dispute(Some(money!(120)))),
+            Err(TransactionNotApplied::InvalidDisputeState(_))
+        ));
+        engine.handle(&dispute(Some(money!(40)))).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(60), money!(40), money!(100), DisputeStatus::Disputed)
+        );
+        engine.handle(&txn!(Resolve, 1)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(100), money!(0), money!(100), DisputeStatus::Resolved)
+        );
+
+        // Only the portion disputed is charged back, leaving the rest to
+        // dispute.
+        engine.handle(&dispute(Some(money!(40)))).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (
+                money!(60),
+                money!(0),
+                money!(60),
+                DisputeStatus::PartiallyRefunded
+            )
+        );
+        let record = engine.store().get_account(123).unwrap().transaction(1);
+        assert_eq!(record.unwrap().disputable(), money!(60));
+        assert!(matches!(
+            engine.handle(&dispute(Some(money!(70)))),
+            Err(TransactionNotApplied::InvalidDisputeState(_))
+        ));
+
+        // Without an amount, whatever's left is disputed.
+        engine.handle(&dispute(None)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(0), money!(60), money!(60), DisputeStatus::Disputed)
+        );
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        assert_eq!(
+            balances(&engine),
+            (money!(0), money!(0), money!(0), DisputeStatus::Refunded)
+        );
+        let record = engine.store().get_account(123).unwrap().transaction(1);
+        assert_eq!(record.unwrap().charged_back(), money!(100));
+        assert!(engine.handle(&dispute(Some(money!(1)))).is_err());
+    }
+
+    #[test]
+    fn chargeback_lock_scope_allows_further_disputes() {
+        let config = EngineConfig {
+            chargeback_lock_scope: LockScope::AllowDisputes,
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 50, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+
+        for tx in [txn!(Deposit, 1, 3), txn!(Withdrawal, 1, 4)] {
+            let resp = engine.handle(&tx).unwrap_err();
+            assert_eq!(resp, TransactionNotApplied::AccountLocked);
+        }
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        engine.handle(&txn!(Chargeback, 2)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(0));
+        assert_eq!(acc.lock_scope(), Some(LockScope::AllowDisputes));
+    }
+
+    #[test]
+    fn unlock_after_chargeback() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 10, 1)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        let unlock = Transaction {
+            info: TransactionInfo::Unlock("ops-1".into()),
+            ..txn!(Resolve, 2)
+        };
+        let sequence = engine.handle(&unlock).unwrap();
+        assert!(!engine.store().get_account(123).unwrap().locked());
+        engine.handle(&txn!(Deposit, 5, 3)).unwrap();
+        assert_eq!(
+            engine.drain_events().collect::<Vec<_>>(),
+            [EngineEvent::AccountUnlocked {
+                sequence,
+                client: 123,
+                tx: 2,
+                operator: "ops-1".into(),
+                scope: LockScope::BlockAll,
+            }]
+        );
+
+        // Nothing to unlock.
+        assert_eq!(
+            engine.handle(&unlock),
+            Err(TransactionNotApplied::AlreadyApplied(2))
+        );
+    }
+
+    #[test]
+    fn chargeback_lock_by_dispute_reason() {
+        let config = EngineConfig {
+            chargeback_lock_scope: LockScope::BlockDebits,
+            reason_policies: [
+                (
+                    DisputeReason::Fraud,
+                    ReasonPolicy {
+                        chargeback_lock: Some(LockScope::BlockAll),
+                    },
+                ),
+                (
+                    DisputeReason::Duplicate,
+                    ReasonPolicy {
+                        chargeback_lock: None,
+                    },
+                ),
+            ]
+            .into(),
+            ..EngineConfig::default()
+        };
+        let dispute = |tx, reason| Transaction {
+            client_id: CLIENT_ID_DEFAULT,
+            transaction_id: tx,
+            info: TransactionInfo::Dispute(reason),
+            destination: None,
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        for (reason, lock) in [
+            (Some(DisputeReason::Duplicate), None),
+            (Some(DisputeReason::Fraud), Some(LockScope::BlockAll)),
+            (
+                Some(DisputeReason::ProductNotReceived),
+                Some(LockScope::BlockDebits),
+            ),
+            (None, Some(LockScope::BlockDebits)),
+        ] {
+            let mut engine = TxEngine::with_config(InMemoryStore::new(), config.clone());
+            engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+            engine.handle(&dispute(1, reason)).unwrap();
+            let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+            assert_eq!(acc.transaction(1).unwrap().reason, reason);
+            engine.handle(&txn!(Chargeback, 1)).unwrap();
+            let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+            assert_eq!(acc.lock_scope(), lock, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn idempotent_settlement() {
+        for idempotent_settlement in [false, true] {
+            let config = EngineConfig {
+                idempotent_settlement,
+                chargeback_lock_scope: LockScope::AllowDisputes,
+                ..EngineConfig::default()
+            };
+            let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+            engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+            engine.handle(&txn!(Deposit, 50, 2)).unwrap();
+            engine.handle(&txn!(Dispute, 1)).unwrap();
+            engine.handle(&txn!(Resolve, 1)).unwrap();
+            engine.handle(&txn!(Dispute, 2)).unwrap();
+            engine.handle(&txn!(Chargeback, 2)).unwrap();
+            let sequence = engine.last_sequence();
+
+            let repeats = [
+                engine.handle(&txn!(Resolve, 1)).unwrap_err(),
+                engine.handle(&txn!(Chargeback, 2)).unwrap_err(),
+            ];
+            if idempotent_settlement {
+                assert_eq!(
+                    repeats,
+                    [
+                        TransactionNotApplied::AlreadyApplied(1),
+                        TransactionNotApplied::AlreadyApplied(2)
+                    ]
+                );
+                assert!(!repeats[0].is_failure());
+            } else {
+                assert!(repeats.iter().all(TransactionNotApplied::is_failure));
+            }
+            // A different settlement is still invalid.
+            let resp = engine.handle(&txn!(Chargeback, 1)).unwrap_err();
+            assert!(matches!(
+                resp,
+                TransactionNotApplied::InvalidDisputeState(_)
+            ));
+            assert_eq!(engine.last_sequence(), sequence);
+            let acc = engine.store().get_account(123).unwrap();
+            assert_eq!(acc.total_funds(), &money!(100));
+        }
+    }
+
+    #[test]
+    fn disputes_on_locked_accounts() {
+        let config = EngineConfig {
+            disputes_on_locked_accounts: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 50, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        assert_eq!(
+            engine.store().get_account(123).unwrap().lock_scope(),
+            Some(LockScope::BlockAll)
+        );
+
+        let resp = engine.handle(&txn!(Deposit, 1, 3)).unwrap_err();
+        assert_eq!(resp, TransactionNotApplied::AccountLocked);
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        engine.handle(&txn!(Resolve, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        engine.handle(&txn!(Chargeback, 2)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(0));
+    }
+
+    #[test]
+    fn sequence_numbers_assigned_in_applied_order() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        assert_eq!(engine.last_sequence(), 0);
+        assert_eq!(engine.handle(&txn!(Deposit, 100, 1)), Ok(1));
+        // Transactions that aren't applied don't consume a sequence number.
+        assert!(engine.handle(&txn!(Withdrawal, 500, 2)).is_err());
+        assert!(engine.handle(&txn!(Deposit, 5, 1)).is_err());
+        assert_eq!(engine.handle(&txn!(Withdrawal, 10, 3)), Ok(2));
+        assert_eq!(engine.handle(&txn!(Dispute, 1)), Ok(3));
+        assert_eq!(engine.handle(&txn!(Resolve, 1)), Ok(4));
+        assert_eq!(engine.handle(&txn!(Deposit, 5, 4)), Ok(5));
+        assert_eq!(engine.last_sequence(), 5);
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.transaction(1).unwrap().sequence, 1);
+        assert_eq!(acc.transaction(4).unwrap().sequence, 5);
+    }
+
+    #[test]
+    fn dormant_account_withdrawals_blocked_until_reactivated() {
+        let config = EngineConfig {
+            dormancy: Some(DormancyPolicy {
+                after: 3,
+                block_withdrawals: true,
+            }),
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        for tx in 2..5 {
+            let other = Transaction {
+                client_id: 7,
+                transaction_id: tx,
+                info: TransactionInfo::Deposit(Money::from(1)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            };
+            engine.handle(&other).unwrap();
+        }
+        assert_eq!(
+            engine.handle(&txn!(Withdrawal, 10, 5)),
+            Err(TransactionNotApplied::AccountDormant)
+        );
+        // Disputes still go through, and reactivate the account.
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Resolve, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 10, 5)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.last_activity(), engine.last_sequence());
+    }
+
+    #[test]
+    fn system_accounts_balance_client_movements() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        engine.add_plugin(Box::new(LimitPlugin));
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 20, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        let system = engine.system_accounts();
+        assert_eq!(system.escrow, money!(20));
+        assert_eq!(system.fee_income, money!(2));
+        assert_eq!(system.chargeback_loss, money!(-22));
+        assert_eq!(system.suspense, money!(22));
+        let client_total = engine.store().get_account(123).unwrap().total_funds();
+        assert_eq!(client_total, &money!(-22));
+        let system_total = [
+            &system.escrow,
+            &system.fee_income,
+            &system.chargeback_loss,
+            &system.suspense,
+        ]
+        .into_iter()
+        .fold(Money::zero(), |sum, balance| sum + balance);
+        assert_eq!(client_total + &system_total, money!(0));
+    }
+
+    #[test]
+    fn bulk_disputes() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 10, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 20, 2)).unwrap();
+        let items = [1, 3, 2].map(|tx| DisputeItem {
+            client: CLIENT_ID_DEFAULT,
+            tx,
+            reason: None,
+        });
+        let results = engine.handle_bulk_disputes(DisputeAction::Open, &items);
+        assert_eq!(
+            results,
+            vec![
+                Ok(3),
+                Err(TransactionNotApplied::DisputedTransactionNotFound(3)),
+                Ok(4)
+            ]
+        );
+        let results = engine.handle_bulk_disputes(DisputeAction::Resolve, &items[..1]);
+        assert_eq!(results, vec![Ok(5)]);
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.held_funds(), money!(20));
+    }
+
+    #[test]
+    fn dispute_shortfall_event() {
+        for hold_policy in [
+            HoldPolicy::Capped,
+            HoldPolicy::NegativeAvailable,
+            HoldPolicy::HeldBucket,
+        ] {
+            let mut engine = engine_after_dispute_with(hold_policy);
+            let events: Vec<_> = engine.drain_events().collect();
+            assert_eq!(
+                events,
+                vec![EngineEvent::DisputeShortfall {
+                    sequence: 3,
+                    client: 123,
+                    tx: 1,
+                    shortfall: money!(80),
+                    account_shortfall: money!(80),
+                }],
+                "{:?}",
+                hold_policy
+            );
+            // The later deposit covers some of the shortfall.
+            let acc = engine.store().get_account(123).unwrap();
+            assert_eq!(acc.dispute_shortfall(), money!(30));
+            assert_eq!(engine.drain_events().count(), 0);
+        }
+    }
+
+    /// Deposits 100, withdraws 80, then disputes the deposit and deposits
+    /// 50 more, using the given hold policy.
+    fn engine_after_dispute_with(hold_policy: HoldPolicy) -> TxEngine<InMemoryStore> {
+        let config = EngineConfig {
+            hold_policy,
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 80, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 50, 3)).unwrap();
+        engine
+    }
+
+    #[test]
+    fn hold_policy_capped() {
+        let mut engine = engine_after_dispute_with(HoldPolicy::Capped);
+        let acc = engine.store().get_account(123).unwrap();
+        // The later deposit is held to cover the dispute.
+        assert_eq!(acc.available_funds(), money!(0));
+        assert_eq!(acc.held_funds(), money!(70));
+        engine.handle(&txn!(Resolve, 1)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(70));
+        assert_eq!(acc.held_funds(), money!(0));
+    }
+
+    #[test]
+    fn hold_policy_negative_available() {
+        let mut engine = engine_after_dispute_with(HoldPolicy::NegativeAvailable);
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(-30));
+        assert_eq!(acc.held_funds(), money!(100));
+        let resp = engine.handle(&txn!(Withdrawal, 1, 4)).unwrap_err();
+        assert_eq!(resp, TransactionNotApplied::InsufficientFunds(money!(31)));
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(-30));
+        assert_eq!(acc.held_funds(), money!(0));
+    }
+
+    #[test]
+    fn hold_policy_held_bucket() {
+        let mut engine = engine_after_dispute_with(HoldPolicy::HeldBucket);
+        let acc = engine.store().get_account(123).unwrap();
+        // Only the 20 available at dispute time is held.
+        assert_eq!(acc.available_funds(), money!(50));
+        assert_eq!(acc.held_funds(), money!(20));
+        assert_eq!(acc.transaction(1).unwrap().held(), money!(20));
+        engine.handle(&txn!(Withdrawal, 50, 4)).unwrap();
+        engine.handle(&txn!(Chargeback, 1)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(-80));
+        assert_eq!(acc.held_funds(), money!(0));
+        assert_eq!(acc.transaction(1).unwrap().held(), money!(0));
+    }
+
+    /// Rejects withdrawals from client 123 above a limit, and charges a flat
+    /// fee of 1 on every deposit/withdrawal.
+    struct LimitPlugin;
+
+    impl TransactionPlugin for LimitPlugin {
+        fn validate(&mut self, tx: &Transaction, _account: &Account) -> Result<(), PluginError> {
+            match tx.info {
+                TransactionInfo::Withdrawal(ref amount) if *amount > money!(20) => {
+                    Err(PluginError::Rejected("Over limit".into()))
+                }
+                _ => Ok(()),
+            }
+        }
+
+        fn fee(&mut self, _tx: &Transaction) -> Result<Money, PluginError> {
+            Ok(money!(1))
+        }
+    }
+
+    #[test]
+    fn plugin_validation_and_fees() {
+        let mut engine = engine_with_def_account();
+        engine.add_plugin(Box::new(LimitPlugin));
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        let resp = engine.handle(&txn!(Withdrawal, 50, 2)).unwrap_err();
+        assert_eq!(
+            resp,
+            TransactionNotApplied::RejectedByPlugin("Over limit".into())
+        );
+        assert!(!resp.is_failure());
+        engine.handle(&txn!(Withdrawal, 20, 3)).unwrap();
+
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(78));
+        // Deposit record holds the full amount for disputes.
+        assert_eq!(acc.transaction(1).unwrap().amount, money!(100));
+
+        // Fee counts towards the funds required for a withdrawal.
+        engine.handle(&txn!(Withdrawal, 20, 4)).unwrap();
+        engine.handle(&txn!(Withdrawal, 20, 5)).unwrap();
+        engine.handle(&txn!(Withdrawal, 20, 6)).unwrap();
+        let resp = engine.handle(&txn!(Withdrawal, 15, 7)).unwrap_err();
+        assert_eq!(resp, TransactionNotApplied::InsufficientFunds(money!(1)));
+
+        // A deposit smaller than the fee is taken whole, not overdrawn.
+        engine.handle(&txn!(Deposit, 0.5, 8)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.fee(8), Some(&money!(0.5)));
+        assert_eq!(acc.available_funds(), money!(15));
+    }
+
+    #[test]
+    fn fee_policy_charged_and_recorded() {
+        let config = EngineConfig {
+            fees: serde_json::from_value(serde_json::json!({
+                "deposit": {"flat": "0.5"},
+                "withdrawal": {"flat": "1", "percent": "2"}
+            }))
+            .unwrap(),
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(64);
+        engine.add_event_sink(Box::new(sender));
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 50, 2)).unwrap();
+        // The fee takes it over what's available.
+        assert_eq!(
+            engine.handle(&txn!(Withdrawal, 47, 3)),
+            Err(TransactionNotApplied::InsufficientFunds(money!(1.44)))
+        );
+
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.total_funds(), &money!(47.5));
+        assert_eq!(acc.fee(1), Some(&money!(0.5)));
+        assert_eq!(acc.fee(2), Some(&money!(2)));
+        assert_eq!(acc.fees_charged(), money!(2.5));
+        assert_eq!(engine.system_accounts().fee_income, money!(2.5));
+        drop(engine);
+        let fees: Vec<LedgerEvent> = receiver
+            .iter()
+            .filter(|event| matches!(event, LedgerEvent::FeeCharged { .. }))
+            .collect();
+        assert_eq!(
+            fees,
+            [
+                LedgerEvent::FeeCharged {
+                    sequence: 1,
+                    client: CLIENT_ID_DEFAULT,
+                    tx: 1,
+                    fee: money!(0.5),
+                },
+                LedgerEvent::FeeCharged {
+                    sequence: 2,
+                    client: CLIENT_ID_DEFAULT,
+                    tx: 2,
+                    fee: money!(2),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn interest_accrued_and_posted() {
+        let config = EngineConfig {
+            interest: Some("3.65:actual/365:monthly".parse().unwrap()),
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(64);
+        engine.add_event_sink(Box::new(sender));
+        let mut flows = Flows::default();
+        let day = SECONDS_PER_DAY;
+        for (transaction, time) in [
+            (txn!(Deposit, 1000, 1), 0),
+            // 1 accrued over 1970-01-01 to 01-10, on 1000.
+            (txn!(Withdrawal, 500, 2), 10 * day),
+            // Untimed, so accrues nothing.
+            (txn!(Deposit, 0.01, 3), 0),
+            // 1.050021 more by the end of January, on 500.01, so 2.05
+            // posted for 1970-01-31; then 0.451854 over February 1 to 9,
+            // on 502.06.
+            (txn!(Deposit, 100, 4), 40 * day + 1),
+        ] {
+            let transaction = Transaction {
+                timestamp: Some(time).filter(|_| transaction.transaction_id != 3),
+                ..transaction
+            };
+            engine.handle(&transaction).unwrap();
+            flows.record(&transaction, engine.store());
+        }
+        assert_eq!(engine.latest_time(), Some(40 * day + 1));
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.total_funds(), &money!(602.06));
+        assert_eq!(acc.interest_history()[0].period_end, "1970-01-31");
+        assert_eq!(acc.interest_history()[0].amount, money!(2.05));
+        assert_eq!(acc.interest_history()[0].sequence, 4);
+
+        // 1.143914 more by the end of February, on 602.06.
+        assert_eq!(engine.accrue_interest(59 * day), Ok(1));
+        assert_eq!(engine.accrue_interest(59 * day), Ok(0));
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.interest_history()[1].period_end, "1970-02-28");
+        assert_eq!(acc.interest_paid(), money!(3.6458));
+        assert_eq!(acc.total_funds(), &money!(603.6558));
+        assert_eq!(engine.system_accounts().interest_expense, money!(-3.6458));
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        assert!(trial.balances(), "{:?}", trial);
+
+        drop(engine);
+        let interest: Vec<LedgerEvent> = receiver
+            .iter()
+            .filter(|event| matches!(event, LedgerEvent::InterestPosted { .. }))
+            .collect();
+        assert_eq!(
+            interest,
+            [
+                LedgerEvent::InterestPosted {
+                    sequence: 4,
+                    client: CLIENT_ID_DEFAULT,
+                    period_end: "1970-01-31".to_owned(),
+                    amount: money!(2.05),
+                },
+                LedgerEvent::InterestPosted {
+                    sequence: 6,
+                    client: CLIENT_ID_DEFAULT,
+                    period_end: "1970-02-28".to_owned(),
+                    amount: money!(1.5958),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn balance_thresholds_raise_events() {
+        let mut config = EngineConfig::default();
+        config.thresholds.default.low = Some(money!(10));
+        config.thresholds.clients.insert(
+            2,
+            Thresholds {
+                low: None,
+                high: Some(money!(50)),
+            },
+        );
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let eur = |transaction: Transaction| Transaction {
+            currency: Some(Interned::from("EUR")),
+            ..transaction
+        };
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 95, 2)).unwrap();
+        // Already below, so not crossed again.
+        engine.handle(&txn!(Withdrawal, 1, 3)).unwrap();
+        engine.handle(&eur(txn!(Deposit, 20, 4))).unwrap();
+        engine.handle(&eur(txn!(Withdrawal, 15, 5))).unwrap();
+        // Up to the high threshold, then over it.
+        for transaction in [txn!(Deposit, 50, 6), txn!(Deposit, 1, 7)] {
+            let transaction = Transaction {
+                client_id: 2,
+                ..transaction
+            };
+            engine.handle(&transaction).unwrap();
+        }
+        let events: Vec<EngineEvent> = engine.drain_events().collect();
+        assert_eq!(
+            events,
+            [
+                EngineEvent::BalanceThresholdCrossed {
+                    sequence: 2,
+                    client: CLIENT_ID_DEFAULT,
+                    currency: None,
+                    threshold: ThresholdKind::Low,
+                    limit: money!(10),
+                    available: money!(5),
+                },
+                EngineEvent::BalanceThresholdCrossed {
+                    sequence: 5,
+                    client: CLIENT_ID_DEFAULT,
+                    currency: Some(Interned::from("EUR")),
+                    threshold: ThresholdKind::Low,
+                    limit: money!(10),
+                    available: money!(5),
+                },
+                EngineEvent::BalanceThresholdCrossed {
+                    sequence: 7,
+                    client: 2,
+                    currency: None,
+                    threshold: ThresholdKind::High,
+                    limit: money!(50),
+                    available: money!(51),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn risk_limits() {
+        let config = EngineConfig {
+            risk: RiskLimits {
+                max_withdrawal: Some(money!(50)),
+                max_daily_withdrawals: Some(money!(60)),
+                max_transactions: Some(5),
+            },
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        // Transactions 1 to 9 on the first day, later ones on the next.
+        engine.set_clock(|transaction: &Transaction| {
+            Some(transaction.transaction_id as u64 / 10 * SECONDS_PER_DAY)
+        });
+        engine.handle(&txn!(Deposit, 200, 1)).unwrap();
+        let limit_exceeded = |limit| Err(TransactionNotApplied::LimitExceeded(limit));
+        assert_eq!(
+            engine.handle(&txn!(Withdrawal, 51, 2)),
+            limit_exceeded(Limit::Withdrawal)
+        );
+        engine.handle(&txn!(Withdrawal, 50, 3)).unwrap();
+        assert_eq!(
+            engine.handle(&txn!(Withdrawal, 11, 4)),
+            limit_exceeded(Limit::DailyWithdrawals)
+        );
+        engine.handle(&txn!(Withdrawal, 10, 5)).unwrap();
+        engine.handle(&txn!(Withdrawal, 50, 10)).unwrap();
+        // Disputes don't count, and aren't limited.
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Resolve, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 1, 11)).unwrap();
+        assert_eq!(
+            engine.handle(&txn!(Deposit, 1, 12)),
+            limit_exceeded(Limit::Transactions)
+        );
+        assert_eq!(engine.handle(&txn!(Dispute, 11)), Ok(8));
+        assert_eq!(
+            TransactionNotApplied::LimitExceeded(Limit::Transactions).to_string(),
+            "Limit Exceeded: max_transactions"
+        );
+        // Other clients have their own counts.
+        let other = Transaction {
+            client_id: 2,
+            ..txn!(Deposit, 1, 13)
+        };
+        engine.handle(&other).unwrap();
+    }
+
+    #[test]
+    fn overdraft_limits() {
+        let mut config = EngineConfig::default();
+        config.overdraft.limit = money!(50);
+        config
+            .overdraft
+            .read_csv("client,limit\n2,0\n".as_bytes())
+            .unwrap();
+        assert!(config
+            .overdraft
+            .read_csv("client,limit\n3,-1\n".as_bytes())
+            .is_err());
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 120, 2)).unwrap();
+        assert_eq!(
+            engine.handle(&txn!(Withdrawal, 40, 3)),
+            Err(TransactionNotApplied::InsufficientFunds(money!(10)))
+        );
+        engine.handle(&txn!(Withdrawal, 30, 4)).unwrap();
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.total_funds(), &money!(-50));
+        assert_eq!(acc.available_funds(), money!(0));
+
+        // Client 2 has no overdraft.
+        let client_2 = |transaction: Transaction| Transaction {
+            client_id: 2,
+            ..transaction
+        };
+        engine.handle(&client_2(txn!(Deposit, 10, 5))).unwrap();
+        assert_eq!(
+            engine.handle(&client_2(txn!(Withdrawal, 12.5, 6))),
+            Err(TransactionNotApplied::InsufficientFunds(money!(2.5)))
+        );
+    }
+
+    #[test]
+    fn opening_balances_bypass_plugins() {
+        let mut engine = engine_with_def_account();
+        engine.add_plugin(Box::new(LimitPlugin));
+        let entry = OpeningEntry {
+            client: 123,
+            tx: 1,
+            amount: money!(100),
+            disputed: true,
+        };
+        let applied = engine.open_balance(&entry).unwrap();
+        assert_eq!(
+            applied,
+            vec![(1, txn!(Deposit, 100, 1)), (2, txn!(Dispute, 1))]
+        );
+        // No fee was charged.
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(100));
+        assert_eq!(acc.held_funds(), money!(100));
+        assert_eq!(engine.system_accounts().escrow, money!(-100));
+        assert_eq!(
+            engine.open_balance(&entry),
+            Err(TransactionNotApplied::RepeatTransaction(1))
+        );
+
+        engine.handle(&txn!(Resolve, 1)).unwrap();
+        assert_eq!(engine.last_sequence(), 3);
+    }
+
+    #[test]
+    fn quarantine_queues_until_released() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.quarantine(123);
+        assert!(engine.is_quarantined(123));
+        for transaction in [txn!(Withdrawal, 30, 2), txn!(Withdrawal, 90, 3)] {
+            assert_eq!(
+                engine.handle(&transaction),
+                Err(TransactionNotApplied::Quarantined)
+            );
+        }
+        assert!(!TransactionNotApplied::Quarantined.is_failure());
+        // Nothing applied, or numbered, while quarantined.
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(100));
+        assert_eq!(engine.last_sequence(), 1);
+        let queued: Vec<_> = engine
+            .quarantined()
+            .map(|(client, queued)| (client, queued.len()))
+            .collect();
+        assert_eq!(queued, [(123, 2)]);
+
+        // Survives a snapshot.
+        let state = serde_json::to_string(&engine).unwrap();
+        let mut engine: TxEngine<InMemoryStore> = serde_json::from_str(&state).unwrap();
+        let released = engine.release_quarantine(123);
+        assert_eq!(
+            released,
+            [
+                (txn!(Withdrawal, 30, 2), Ok(2)),
+                (
+                    txn!(Withdrawal, 90, 3),
+                    Err(TransactionNotApplied::InsufficientFunds(money!(20)))
+                ),
+            ]
+        );
+        assert!(!engine.is_quarantined(123));
+        assert_eq!(engine.quarantined().count(), 0);
+        engine.handle(&txn!(Deposit, 5, 4)).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(75));
+    }
+
+    #[test]
+    fn disputes_over_max_held_referred() {
+        let config = EngineConfig {
+            max_held: Some(money!(100)),
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 60, 1)).unwrap();
+        engine.handle(&txn!(Deposit, 30, 2)).unwrap();
+        engine.handle(&txn!(Deposit, 20, 3)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        // Would hold 110, so the account is quarantined instead.
+        assert_eq!(
+            engine.handle(&txn!(Dispute, 3)),
+            Err(TransactionNotApplied::Quarantined)
+        );
+        assert!(engine.is_quarantined(CLIENT_ID_DEFAULT));
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.active_dispute_total(), &money!(90));
+        assert_eq!(
+            engine.drain_events().collect::<Vec<_>>(),
+            [EngineEvent::DisputeReferred {
+                after_sequence: 5,
+                client: CLIENT_ID_DEFAULT,
+                tx: 3,
+                held: money!(20),
+                max_held: money!(100),
+            }]
+        );
+
+        // Once reviewed, the dispute is held in full.
+        let released = engine.release_quarantine(CLIENT_ID_DEFAULT);
+        assert_eq!(released, [(txn!(Dispute, 3), Ok(6))]);
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.active_dispute_total(), &money!(110));
+        // Disputes that can't begin aren't referred.
+        assert_eq!(
+            engine.handle(&txn!(Dispute, 3)).unwrap_err().name(),
+            "InvalidDisputeState"
+        );
+        assert!(!engine.is_quarantined(CLIENT_ID_DEFAULT));
+    }
+
+    #[test]
+    fn disputes_outside_window_rejected() {
+        let config = EngineConfig {
+            dispute_window_days: Some(30),
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let at = |transaction: Transaction, time| Transaction {
+            timestamp: Some(time),
+            ..transaction
+        };
+        let day = SECONDS_PER_DAY;
+        engine.handle(&at(txn!(Deposit, 10, 1), day)).unwrap();
+        engine.handle(&at(txn!(Withdrawal, 5, 2), day)).unwrap();
+        engine.handle(&txn!(Deposit, 10, 3)).unwrap();
+        let acc = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        assert_eq!(acc.time_of(1), Some(day));
+        assert_eq!(acc.time_of(2), Some(day));
+        assert_eq!(acc.time_of(3), None);
+
+        // The last day of the window is still inside it.
+        engine.handle(&at(txn!(Dispute, 1), 31 * day)).unwrap();
+        assert_eq!(
+            engine.handle(&at(txn!(Dispute, 2), 31 * day + 1)),
+            Err(TransactionNotApplied::DisputeWindowExpired(2))
+        );
+        // Without both times, the window can't be checked.
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        engine.handle(&at(txn!(Dispute, 3), 100 * day)).unwrap();
+
+        // The clock decides the times, e.g. stamping untimed transactions.
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), engine.config().clone());
+        engine.set_clock(|transaction: &Transaction| match transaction.info {
+            TransactionInfo::Deposit(_) => Some(SECONDS_PER_DAY),
+            _ => Some(40 * SECONDS_PER_DAY),
+        });
+        engine.handle(&txn!(Deposit, 10, 1)).unwrap();
+        assert_eq!(
+            engine.handle(&txn!(Dispute, 1)),
+            Err(TransactionNotApplied::DisputeWindowExpired(1))
+        );
+    }
+
+    #[test]
+    fn balances_kept_per_currency() {
+        let mut engine = engine_with_def_account();
+        let tagged = |transaction: Transaction, currency: &str| Transaction {
+            currency: Some(currency.into()),
+            ..transaction
+        };
+        // The first currency seen becomes the account's primary one.
+        engine.handle(&tagged(txn!(Deposit, 10, 1), "USD")).unwrap();
+        engine.handle(&tagged(txn!(Deposit, 5, 2), "EUR")).unwrap();
+        engine.handle(&txn!(Deposit, 1, 3)).unwrap();
+        assert_eq!(
+            engine.handle(&tagged(txn!(Withdrawal, 6, 4), "EUR")),
+            Err(TransactionNotApplied::InsufficientFunds(money!(1)))
+        );
+        engine
+            .handle(&tagged(txn!(Withdrawal, 6, 5), "USD"))
+            .unwrap();
+
+        // Disputes apply to the balance the transaction went to.
+        engine.handle(&txn!(Dispute, 2)).unwrap();
+        assert_eq!(
+            engine.handle(&tagged(txn!(Resolve, 2), "USD")),
+            Err(TransactionNotApplied::DisputedTransactionNotFound(2))
+        );
+        engine.handle(&tagged(txn!(Chargeback, 2), "EUR")).unwrap();
+
+        let account = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        let statements: Vec<_> = account.statements().collect();
+        let rows: Vec<_> = statements
+            .iter()
+            .map(|s| {
+                (
+                    s.currency().map(|c| c.to_string()),
+                    s.total().clone(),
+                    s.locked(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (Some("USD".to_string()), Money::from(5), true),
+                (Some("EUR".to_string()), Money::from(0), true),
+            ]
+        );
+        assert_eq!(
+            engine.handle(&tagged(txn!(Deposit, 1, 6), "EUR")),
+            Err(TransactionNotApplied::AccountLocked)
+        );
+    }
+
+    #[test]
+    fn conversions_between_balances() {
+        let mut config = EngineConfig::default();
+        config.fx.rates.insert("USD", "JPY", money!(151.237));
+        config
+            .fx
+            .rounding
+            .insert("JPY".into(), "0".parse().unwrap());
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let convert = |tx, amount, from: &str, to: &str, rate| Transaction {
+            info: TransactionInfo::Convert(Conversion {
+                amount,
+                to: to.into(),
+                rate,
+            }),
+            currency: Some(from.into()),
+            ..txn!(Deposit, 0, tx)
+        };
+        let usd = Some("USD".into());
+        engine
+            .handle(&Transaction {
+                currency: usd.clone(),
+                ..txn!(Deposit, 100, 1)
+            })
+            .unwrap();
+        // At the configured rate, rounded for the currency converted into.
+        engine
+            .handle(&convert(2, money!(10.5), "USD", "JPY", None))
+            .unwrap();
+        // At the rate supplied.
+        engine
+            .handle(&convert(3, money!(10), "USD", "EUR", Some(money!(0.9))))
+            .unwrap();
+        for (transaction, err) in [
+            (
+                convert(4, money!(1), "USD", "GBP", None),
+                TransactionNotApplied::RateUnavailable(4),
+            ),
+            (
+                convert(4, money!(80), "USD", "EUR", Some(money!(1))),
+                TransactionNotApplied::InsufficientFunds(money!(0.5)),
+            ),
+            (
+                convert(4, money!(1), "GBP", "USD", Some(money!(1))),
+                TransactionNotApplied::InsufficientFunds(money!(1)),
+            ),
+            (
+                convert(2, money!(1), "USD", "EUR", Some(money!(1))),
+                TransactionNotApplied::RepeatTransaction(2),
+            ),
+            (
+                txn!(Deposit, 1, 3),
+                TransactionNotApplied::RepeatTransaction(3),
+            ),
+            (
+                txn!(Dispute, 3),
+                TransactionNotApplied::DisputedTransactionNotFound(3),
+            ),
+        ] {
+            assert_eq!(engine.handle(&transaction), Err(err));
+        }
+        let account = engine.store().get_account(CLIENT_ID_DEFAULT).unwrap();
+        let totals: Vec<_> = account
+            .balances()
+            .map(|balance| {
+                (
+                    balance.currency().unwrap().to_string(),
+                    balance.total_funds().clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                ("USD".into(), money!(79.5)),
+                ("EUR".into(), money!(9)),
+                ("JPY".into(), money!(1588)),
+            ]
+        );
+        assert_eq!(account.conversion(2).unwrap().converted, money!(1588));
+
+        let mut flows = Flows::default();
+        let deposit = Transaction {
+            currency: usd.clone(),
+            ..txn!(Deposit, 100, 1)
+        };
+        flows.record(&deposit, engine.store());
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        // Conversions balance in each currency.
+        assert!(trial.balances(), "{:?}", trial);
+        assert_eq!(trial.currencies.len(), 3);
+
+        // A rate provider replaces the configured rates.
+        engine.set_rate_provider(|_: &str, to: &str| (to == "GBP").then(|| money!(0.5)));
+        engine
+            .handle(&convert(5, money!(10), "USD", "GBP", None))
+            .unwrap();
+        assert_eq!(
+            engine.handle(&convert(6, money!(1), "USD", "JPY", None)),
+            Err(TransactionNotApplied::RateUnavailable(6))
+        );
+    }
+
+    #[test]
+    fn notes_attached_and_audited() {
+        let mut engine = engine_with_def_account();
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        let note = Note {
+            client: 123,
+            tx: Some(1),
+            author: "alice".into(),
+            note: "Source of funds checked".into(),
+        };
+        engine.annotate(&note).unwrap();
+        assert_eq!(
+            engine.drain_events().collect::<Vec<_>>(),
+            [EngineEvent::NoteAdded {
+                after_sequence: 1,
+                client: 123,
+                tx: Some(1),
+                author: "alice".into(),
+                note: "Source of funds checked".into(),
+            }]
+        );
+        // Notes aren't activity, and don't number anything.
+        assert_eq!(engine.last_sequence(), 1);
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(acc.last_activity(), 1);
+
+        // Survives a snapshot.
+        let state = serde_json::to_string(&engine).unwrap();
+        let engine: TxEngine<InMemoryStore> = serde_json::from_str(&state).unwrap();
+        let acc = engine.store().get_account(123).unwrap();
+        assert_eq!(
+            acc.notes(),
+            [AccountNote {
+                tx: Some(1),
+                after_sequence: 1,
+                author: "alice".into(),
+                text: "Source of funds checked".into(),
+            }]
+        );
+
+        let mut engine = engine;
+        let unknown = Note {
+            client: 9,
+            ..note.clone()
+        };
+        assert!(engine.annotate(&unknown).is_err());
+        assert!(engine.store().get_account(9).is_none());
+        assert_eq!(engine.drain_events().count(), 0);
+
+        // Withdrawals are recorded, so can be noted, but not transactions the
+        // account doesn't have.
+        engine.handle(&txn!(Withdrawal, 30, 2)).unwrap();
+        let withdrawal = Note {
+            tx: Some(2),
+            ..note.clone()
+        };
+        engine.annotate(&withdrawal).unwrap();
+        assert_eq!(engine.drain_events().count(), 1);
+        let missing = Note {
+            tx: Some(3),
+            ..note
+        };
+        assert_eq!(
+            engine.annotate(&missing),
+            Err("Transaction 3 isn't one of client 123's to note.".to_owned())
+        );
+        assert_eq!(engine.store().get_account(123).unwrap().notes().len(), 2);
+    }
+
+    #[test]
+    fn destination_policies() {
+        let mut config = EngineConfig::default();
+        config.destinations.blocklist.insert("mule".into());
+        config.destinations.limit = Some(money!(10));
+        config
+            .destinations
+            .limits
+            .insert("payroll".into(), money!(100));
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        let withdrawal = |client_id, transaction_id, amount, destination: &str| Transaction {
+            client_id,
+            transaction_id,
+            info: TransactionInfo::Withdrawal(amount),
+            destination: Some(destination.into()),
+            dispute_amount: None,
+            timestamp: None,
+            currency: None,
+        };
+        for client_id in [1, 2] {
+            engine
+                .handle(&Transaction {
+                    client_id,
+                    transaction_id: u32::from(client_id),
+                    info: TransactionInfo::Deposit(money!(50)),
+                    destination: None,
+                    dispute_amount: None,
+                    timestamp: None,
+                    currency: None,
+                })
+                .unwrap();
+        }
+
+        assert_eq!(
+            engine.handle(&withdrawal(1, 3, money!(1), "mule")),
+            Err(TransactionNotApplied::DestinationBlocked)
+        );
+        // The limit is across clients.
+        engine
+            .handle(&withdrawal(1, 4, money!(6), "wallet"))
+            .unwrap();
+        assert_eq!(
+            engine.handle(&withdrawal(2, 5, money!(5), "wallet")),
+            Err(TransactionNotApplied::DestinationLimitExceeded)
+        );
+        engine
+            .handle(&withdrawal(2, 6, money!(4), "wallet"))
+            .unwrap();
+        engine
+            .handle(&withdrawal(2, 7, money!(40), "payroll"))
+            .unwrap();
+        // Untagged withdrawals aren't limited.
+        engine
+            .handle(&Transaction {
+                destination: None,
+                ..withdrawal(1, 8, money!(20), "")
+            })
+            .unwrap();
+
+        let account = engine.store().get_account(2).unwrap();
+        let tagged: Vec<_> = account
+            .tagged_withdrawals()
+            .iter()
+            .map(|record| (record.tx, record.destination.as_str()))
+            .collect();
+        assert_eq!(tagged, [(6, "wallet"), (7, "payroll")]);
+
+        // Totals are rebuilt from the accounts when restored.
+        let mut restored: TxEngine<InMemoryStore> =
+            serde_json::from_str(&serde_json::to_string(&engine).unwrap()).unwrap();
+        assert_eq!(
+            restored.handle(&withdrawal(1, 9, money!(0.5), "wallet")),
+            Err(TransactionNotApplied::DestinationLimitExceeded)
+        );
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let config = EngineConfig {
+            hold_policy: HoldPolicy::NegativeAvailable,
+            ..EngineConfig::default()
+        };
+        let mut engine = TxEngine::with_config(InMemoryStore::new(), config);
+        engine.handle(&txn!(Deposit, 100, 1)).unwrap();
+        engine.handle(&txn!(Withdrawal, 80, 2)).unwrap();
+        engine.handle(&txn!(Dispute, 1)).unwrap();
+        let state = serde_json::to_string(&engine).unwrap();
+        assert!(state.starts_with(r#"{"version":1,"state":{"config":"#));
+        // Deterministic, whatever the maps' iteration order.
+        assert_eq!(serde_json::to_string(&engine).unwrap(), state);
+
+        let mut restored: TxEngine<InMemoryStore> = serde_json::from_str(&state).unwrap();
+        assert_eq!(serde_json::to_string(&restored).unwrap(), state);
+        assert_eq!(restored.last_sequence(), 3);
+        assert_eq!(restored.system_accounts(), engine.system_accounts());
+        assert_eq!(restored.drain_events().count(), 1);
+        let acc = restored.store().get_account(123).unwrap();
+        assert_eq!(acc.available_funds(), money!(-80));
+        assert_eq!(acc.held_funds(), money!(100));
+
+        // Carries on where the original left off.
+        assert_eq!(restored.handle(&txn!(Chargeback, 1)), Ok(4));
+        let acc = restored.store().get_account(123).unwrap();
+        assert_eq!(acc.total_funds(), &money!(-80));
+        assert!(acc.locked());
+    }
+
+    #[test]
+    fn unsupported_state_version() {
+        let err = serde_json::from_str::<TxEngine<InMemoryStore>>(r#"{"version":2,"state":{}}"#)
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("unsupported engine state version 2, expected 1"));
     }
 }
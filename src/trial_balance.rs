@@ -0,0 +1,678 @@
+//! Trial balance of a run, verifying the engine's books add up.
+//!
+//! Two identities are checked, each against figures the engine keeps
+//! separately:
+//!
+//! * Double entry: client totals and system balances (see
+//!   [`crate::system_accounts`]) sum to zero.
+//! * Funds flows: deposits less withdrawals, chargebacks, fees and reversed
+//!   deposits, plus withdrawals returned by disputes or reversals, what
+//!   conversions gained and interest paid, equals the sum of client totals.
+//!   Deposits and withdrawals are tallied from the transactions applied, and
+//!   chargebacks, returned withdrawals, reversals, conversions and interest
+//!   from the accounts' records, rather than from any balance.
+//!
+//! Each account's held funds are also checked against its disputed deposits
+//! and withdrawals.
+//!
+//! Both identities are checked in each currency, against the system accounts
+//! kept for it. The trial balance's lines sum amounts in every currency as
+//! they are, so where balances name more than one currency, client totals
+//! and the differences are also reported by currency, as a mixed sum means
+//! nothing on its own.
+//!
+//! Embedders can check their own runs with [`verify_conservation`].
+
+use crate::account::{Account, DisputeStatus};
+use crate::account_store::AccountStore;
+use crate::intern::Interned;
+use crate::money::{Money, OUTPUT_SCALE};
+use crate::system_accounts::SystemAccounts;
+use crate::transaction::{Transaction, TransactionInfo};
+use crate::transaction_engine::{TransactionNotApplied, TxEngine};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// Funds moved by the deposits and withdrawals applied, tallied as they're
+/// applied.
+#[derive(Debug, Default)]
+pub struct Flows {
+    /// By the currency of the balance each was applied to (empty where it
+    /// doesn't name one).
+    currencies: BTreeMap<Interned, Lines>,
+}
+
+impl Flows {
+    /// Records an applied transaction, in the currency of the balance in
+    /// `store` it was applied to.
+    pub fn record<S: AccountStore>(&mut self, transaction: &Transaction, store: &S) {
+        let currency = || {
+            store
+                .get_account(transaction.client_id)
+                .and_then(|account| account.balance_with(transaction.transaction_id))
+                .and_then(Account::currency)
+                .cloned()
+                .unwrap_or_else(|| "".into())
+        };
+        match &transaction.info {
+            TransactionInfo::Deposit(amount) => {
+                let lines = self.currencies.entry(currency()).or_default();
+                lines.deposited = &lines.deposited + amount;
+            }
+            TransactionInfo::Withdrawal(amount) => {
+                let lines = self.currencies.entry(currency()).or_default();
+                lines.withdrawn = &lines.withdrawn + amount;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The lines for `currency`, or for balances that don't name one.
+fn lines_in<'a>(
+    currencies: &'a mut BTreeMap<Interned, Lines>,
+    currency: Option<&Interned>,
+) -> &'a mut Lines {
+    currencies
+        .entry(currency.cloned().unwrap_or_else(|| "".into()))
+        .or_default()
+}
+
+/// The amounts the identities are checked with, in one currency or summed
+/// across them.
+#[derive(Debug, Clone, Default)]
+struct Lines {
+    deposited: Money,
+    withdrawn: Money,
+    charged_back: Money,
+    withdrawals_returned: Money,
+    deposits_reversed: Money,
+    withdrawals_reversed: Money,
+    converted: Money,
+    interest: Money,
+    client_totals: Money,
+}
+
+impl Lines {
+    fn add(&mut self, other: &Lines) {
+        for (sum, amount) in [
+            (&mut self.deposited, &other.deposited),
+            (&mut self.withdrawn, &other.withdrawn),
+            (&mut self.charged_back, &other.charged_back),
+            (&mut self.withdrawals_returned, &other.withdrawals_returned),
+            (&mut self.deposits_reversed, &other.deposits_reversed),
+            (&mut self.withdrawals_reversed, &other.withdrawals_reversed),
+            (&mut self.converted, &other.converted),
+            (&mut self.interest, &other.interest),
+            (&mut self.client_totals, &other.client_totals),
+        ] {
+            *sum = &*sum + amount;
+        }
+    }
+
+    /// Deposits less withdrawals, chargebacks, `fees` and reversed deposits,
+    /// plus withdrawals returned or reversed, conversions and interest, less
+    /// client totals.
+    fn flow_difference(&self, fees: &Money) -> Money {
+        let returned = &self.withdrawals_returned + &self.withdrawals_reversed;
+        let net_flows = &(&(&self.deposited - &self.withdrawn)
+            + &(&returned + &(&self.converted + &self.interest)))
+            - &(&(&self.charged_back + &self.deposits_reversed) + fees);
+        &net_flows - &self.client_totals
+    }
+}
+
+/// An account whose disputed deposits don't account for its held funds.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HeldMismatch {
+    pub client: u16,
+    /// Total held for the account's disputes, as it records it.
+    pub held: Money,
+    /// Sum of the amounts held for each of its disputed deposits and
+    /// withdrawals.
+    pub disputed: Money,
+}
+
+#[derive(Debug)]
+pub struct TrialBalance {
+    pub deposited: Money,
+    pub withdrawn: Money,
+    /// Net amounts of the deposits charged back.
+    pub charged_back: Money,
+    /// Withdrawals returned to their accounts, as they're disputed or once
+    /// charged back.
+    pub withdrawals_returned: Money,
+    /// Net amounts of the deposits reversed.
+    pub deposits_reversed: Money,
+    /// Withdrawals returned to their accounts by reversals.
+    pub withdrawals_reversed: Money,
+    /// Amounts converted into, less amounts converted from, summed across
+    /// currencies as they are.
+    pub converted: Money,
+    /// Interest posted to accounts (see [`crate::interest`]).
+    pub interest: Money,
+    pub system: SystemAccounts,
+    pub client_totals: Money,
+    /// Client totals and the identities checked in each currency, by
+    /// currency (empty where balances don't name one).
+    pub currencies: BTreeMap<Interned, CurrencyBalance>,
+    /// Sum of client totals and system balances. Zero if the books balance.
+    pub double_entry_difference: Money,
+    /// Deposits less withdrawals, chargebacks, fees and reversed deposits,
+    /// plus withdrawals returned or reversed, conversions and interest, less
+    /// the sum of client totals.
+    /// Zero if the books balance.
+    pub flow_difference: Money,
+    pub held_mismatches: Vec<HeldMismatch>,
+}
+
+/// A trial balance's client totals and checks in one currency, against the
+/// system accounts kept for it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CurrencyBalance {
+    pub client_totals: Money,
+    /// Sum of client totals and system balances in the currency.
+    pub double_entry_difference: Money,
+    /// As [`TrialBalance::flow_difference`], in the currency.
+    pub flow_difference: Money,
+}
+
+#[derive(Debug, Serialize)]
+struct Line {
+    line: &'static str,
+    amount: Money,
+}
+
+impl TrialBalance {
+    pub fn new<S: AccountStore>(store: &S, system: &SystemAccounts, flows: &Flows) -> Self {
+        let mut currencies = flows.currencies.clone();
+        let mut held_mismatches = vec![];
+        for account in store.accounts() {
+            for (_, record) in account.conversion_history() {
+                let from = lines_in(&mut currencies, Some(&record.from));
+                from.converted = &from.converted - &record.amount;
+                let to = lines_in(&mut currencies, Some(&record.to));
+                to.converted = &to.converted + &record.converted;
+            }
+        }
+        for account in store.accounts().flat_map(Account::balances) {
+            let lines = lines_in(&mut currencies, account.currency());
+            lines.client_totals = &lines.client_totals + account.total_funds();
+            lines.interest = &lines.interest + &account.interest_paid();
+            let mut disputed = Money::zero();
+            for (_, record) in account.transaction_history() {
+                // Partially charged back deposits may be disputed again.
+                lines.charged_back = &lines.charged_back + &record.charged_back();
+                match record.dispute_status() {
+                    DisputeStatus::Disputed => disputed = &disputed + &record.held(),
+                    DisputeStatus::Reversed => {
+                        lines.deposits_reversed = &lines.deposits_reversed + &record.net_amount()
+                    }
+                    _ => {}
+                }
+            }
+            for (_, record) in account.withdrawal_history() {
+                match record.dispute_status() {
+                    DisputeStatus::Disputed | DisputeStatus::Refunded => {
+                        lines.withdrawals_returned = &lines.withdrawals_returned + &record.amount;
+                        disputed = &disputed + &record.held();
+                    }
+                    DisputeStatus::Reversed => {
+                        lines.withdrawals_reversed = &lines.withdrawals_reversed + &record.amount
+                    }
+                    DisputeStatus::NotDisputed
+                    | DisputeStatus::Resolved
+                    | DisputeStatus::PartiallyRefunded => {}
+                }
+            }
+            if &disputed != account.active_dispute_total() {
+                held_mismatches.push(HeldMismatch {
+                    client: account.client(),
+                    held: account.active_dispute_total().clone(),
+                    disputed,
+                });
+            }
+        }
+        held_mismatches.sort_by_key(|mismatch| mismatch.client);
+        let mut system_by_currency = system.by_currency();
+        for currency in system_by_currency.keys() {
+            currencies.entry(currency.clone()).or_default();
+        }
+        // The lines sum amounts in different currencies as they are, on
+        // both sides, so still balance if each currency does.
+        let mut total = Lines::default();
+        let currencies = currencies
+            .into_iter()
+            .map(|(currency, lines)| {
+                total.add(&lines);
+                let system = system_by_currency.remove(&currency).unwrap_or_default();
+                let checked = CurrencyBalance {
+                    double_entry_difference: &lines.client_totals + &system.total(),
+                    flow_difference: lines.flow_difference(&system.fee_income),
+                    client_totals: lines.client_totals,
+                };
+                (currency, checked)
+            })
+            .collect();
+        Self {
+            double_entry_difference: &total.client_totals + &system.total(),
+            flow_difference: total.flow_difference(&system.fee_income),
+            deposited: total.deposited,
+            withdrawn: total.withdrawn,
+            charged_back: total.charged_back,
+            withdrawals_returned: total.withdrawals_returned,
+            deposits_reversed: total.deposits_reversed,
+            withdrawals_reversed: total.withdrawals_reversed,
+            converted: total.converted,
+            interest: total.interest,
+            system: system.clone(),
+            client_totals: total.client_totals,
+            currencies,
+            held_mismatches,
+        }
+    }
+
+    /// Whether both identities hold in every currency, and every account's
+    /// held funds are accounted for.
+    pub fn balances(&self) -> bool {
+        self.double_entry_difference == Money::zero()
+            && self.flow_difference == Money::zero()
+            && self.currencies.values().all(|checked| {
+                checked.double_entry_difference == Money::zero()
+                    && checked.flow_difference == Money::zero()
+            })
+            && self.held_mismatches.is_empty()
+    }
+
+    /// Writes the trial balance as CSV: a table of its lines, with amounts to
+    /// exactly 4 decimal places, then a blank line and a table of the
+    /// accounts whose held funds don't match their disputes. If balances are
+    /// in more than one currency, a blank line and a table of client totals
+    /// and differences by currency follow.
+    pub fn write_report<W: Write>(&self, mut writer: W) -> Result<(), Box<dyn Error>> {
+        let lines = [
+            ("deposited", &self.deposited),
+            ("withdrawn", &self.withdrawn),
+            ("charged_back", &self.charged_back),
+            ("withdrawals_returned", &self.withdrawals_returned),
+            ("deposits_reversed", &self.deposits_reversed),
+            ("withdrawals_reversed", &self.withdrawals_reversed),
+            ("converted", &self.converted),
+            ("interest", &self.interest),
+            ("fee_income", &self.system.fee_income),
+            ("client_totals", &self.client_totals),
+            ("escrow", &self.system.escrow),
+            ("chargeback_loss", &self.system.chargeback_loss),
+            ("suspense", &self.system.suspense),
+            ("interest_expense", &self.system.interest_expense),
+            ("double_entry_difference", &self.double_entry_difference),
+            ("flow_difference", &self.flow_difference),
+        ];
+        let mut csv_writer = csv::Writer::from_writer(&mut writer);
+        for (line, amount) in lines {
+            csv_writer.serialize(Line {
+                line,
+                amount: amount.to_fixed_scale(OUTPUT_SCALE),
+            })?;
+        }
+        csv_writer.flush()?;
+        drop(csv_writer);
+
+        writer.write_all(b"\n")?;
+
+        // Write the header explicitly, as there are usually no mismatches.
+        let mut csv_writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(&mut writer);
+        csv_writer.write_record(["client", "held", "disputed"])?;
+        for mismatch in &self.held_mismatches {
+            csv_writer.serialize(mismatch)?;
+        }
+        csv_writer.flush()?;
+        drop(csv_writer);
+
+        // Single-currency trial balances are as they've always been.
+        if self.currencies.len() > 1 {
+            writer.write_all(b"\n")?;
+            let mut csv_writer = csv::Writer::from_writer(&mut writer);
+            csv_writer.write_record([
+                "currency",
+                "client_totals",
+                "double_entry_difference",
+                "flow_difference",
+            ])?;
+            for (currency, checked) in &self.currencies {
+                let [client_totals, double_entry_difference, flow_difference] = [
+                    &checked.client_totals,
+                    &checked.double_entry_difference,
+                    &checked.flow_difference,
+                ]
+                .map(|amount| amount.to_fixed_scale(OUTPUT_SCALE).to_string());
+                csv_writer.write_record([
+                    &**currency,
+                    &client_totals,
+                    &double_entry_difference,
+                    &flow_difference,
+                ])?;
+            }
+            csv_writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that funds were conserved over a run, given every transaction
+/// handled by `engine` along with its result: that deposits equal
+/// withdrawals, chargebacks, fees and client balances, and the other checks
+/// of a [`TrialBalance`]. Returns the trial balance if they hold.
+///
+/// Deposits are only counted if their result says they were applied, so
+/// transactions queued for a quarantined client and applied later (see
+/// [`crate::quarantine`]) aren't counted.
+pub fn verify_conservation<'a, T: AccountStore>(
+    outcomes: impl IntoIterator<Item = &'a (Transaction, Result<u64, TransactionNotApplied>)>,
+    engine: &TxEngine<T>,
+) -> Result<TrialBalance, ConservationError> {
+    let mut flows = Flows::default();
+    for (transaction, result) in outcomes {
+        if result.is_ok() {
+            flows.record(transaction, engine.store());
+        }
+    }
+    let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+    if trial.balances() {
+        Ok(trial)
+    } else {
+        Err(ConservationError(Box::new(trial)))
+    }
+}
+
+/// Funds weren't conserved, with the trial balance showing where.
+#[derive(Debug)]
+pub struct ConservationError(pub Box<TrialBalance>);
+
+impl fmt::Display for ConservationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let trial = &self.0;
+        let mut problems = vec![];
+        // Differences in a single currency are as they've always been.
+        let checked: Vec<_> = match trial.currencies.len() > 1 {
+            true => trial
+                .currencies
+                .iter()
+                .map(|(currency, checked)| {
+                    (
+                        format!(" in {:?}", &**currency),
+                        &checked.flow_difference,
+                        &checked.double_entry_difference,
+                    )
+                })
+                .collect(),
+            false => vec![(
+                String::new(),
+                &trial.flow_difference,
+                &trial.double_entry_difference,
+            )],
+        };
+        for (currency, flow_difference, double_entry_difference) in checked {
+            if flow_difference != &Money::zero() {
+                problems.push(format!(
+                    "funds in less funds out{} differ from client balances by {}",
+                    currency, flow_difference
+                ));
+            }
+            if double_entry_difference != &Money::zero() {
+                problems.push(format!(
+                    "client and system balances{} sum to {}",
+                    currency, double_entry_difference
+                ));
+            }
+        }
+        for mismatch in &trial.held_mismatches {
+            problems.push(format!(
+                "client {} holds {} for disputes of {}",
+                mismatch.client, mismatch.held, mismatch.disputed
+            ));
+        }
+        write!(f, "Funds not conserved: {}", problems.join("; "))
+    }
+}
+
+impl Error for ConservationError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::account::Account;
+    use crate::account_store::InMemoryStore;
+    use crate::money::money;
+
+    fn engine() -> (TxEngine<InMemoryStore>, Flows) {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        let mut flows = Flows::default();
+        for (client_id, transaction_id, info) in [
+            (1, 1, TransactionInfo::Deposit(money!(100))),
+            (1, 2, TransactionInfo::Withdrawal(money!(80))),
+            (1, 1, TransactionInfo::Dispute(None)),
+            (1, 1, TransactionInfo::Chargeback),
+            (2, 3, TransactionInfo::Deposit(money!(5))),
+            (2, 4, TransactionInfo::Withdrawal(money!(2))),
+            (2, 4, TransactionInfo::Dispute(None)),
+            (2, 4, TransactionInfo::Chargeback),
+            (2, 3, TransactionInfo::Dispute(None)),
+            (3, 5, TransactionInfo::Deposit(money!(50))),
+            (3, 6, TransactionInfo::Withdrawal(money!(20))),
+            (3, 6, TransactionInfo::Reversal),
+            (3, 7, TransactionInfo::Deposit(money!(10))),
+            (3, 7, TransactionInfo::Reversal),
+        ] {
+            let transaction = Transaction {
+                client_id,
+                transaction_id,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            };
+            engine.handle(&transaction).unwrap();
+            flows.record(&transaction, engine.store());
+        }
+        (engine, flows)
+    }
+
+    #[test]
+    fn books_balance() {
+        let (engine, flows) = engine();
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        assert!(trial.balances(), "{:?}", trial);
+        assert_eq!(trial.charged_back, money!(100));
+        assert_eq!(trial.withdrawals_returned, money!(2));
+        assert_eq!(trial.deposits_reversed, money!(10));
+        assert_eq!(trial.withdrawals_reversed, money!(20));
+        assert_eq!(trial.client_totals, money!(-25));
+
+        let mut output = vec![];
+        trial.write_report(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "line,amount\n\
+             deposited,165.0000\n\
+             withdrawn,102.0000\n\
+             charged_back,100.0000\n\
+             withdrawals_returned,2.0000\n\
+             deposits_reversed,10.0000\n\
+             withdrawals_reversed,20.0000\n\
+             converted,0.0000\n\
+             interest,0.0000\n\
+             fee_income,0.0000\n\
+             client_totals,-25.0000\n\
+             escrow,25.0000\n\
+             chargeback_loss,-80.0000\n\
+             suspense,80.0000\n\
+             interest_expense,0.0000\n\
+             double_entry_difference,0.0000\n\
+             flow_difference,0.0000\n\
+             \n\
+             client,held,disputed\n"
+        );
+    }
+
+    #[test]
+    fn checked_by_currency() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        let mut transactions = vec![];
+        for (client_id, transaction_id, info, currency) in [
+            (1, 1, TransactionInfo::Deposit(money!(100)), "USD"),
+            (1, 2, TransactionInfo::Deposit(money!(30)), "EUR"),
+            (2, 3, TransactionInfo::Deposit(money!(5)), "USD"),
+            (2, 4, TransactionInfo::Deposit(money!(30)), "USD"),
+            (1, 5, TransactionInfo::Withdrawal(money!(25)), "EUR"),
+            (1, 2, TransactionInfo::Dispute(None), "EUR"),
+            (1, 2, TransactionInfo::Chargeback, "EUR"),
+        ] {
+            let transaction = Transaction {
+                client_id,
+                transaction_id,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: Some(currency.into()),
+            };
+            engine.handle(&transaction).unwrap();
+            transactions.push(transaction);
+        }
+        let mut flows = Flows::default();
+        for transaction in &transactions {
+            flows.record(transaction, engine.store());
+        }
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        assert!(trial.balances(), "{:?}", trial);
+        let totals: Vec<_> = trial
+            .currencies
+            .iter()
+            .map(|(currency, checked)| (currency.to_string(), checked.client_totals.clone()))
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                ("EUR".to_string(), money!(-25)),
+                ("USD".to_string(), money!(135))
+            ]
+        );
+        // The EUR account was overdrawn by the chargeback, so the loss is
+        // only on EUR's books.
+        let system = engine.system_accounts().by_currency();
+        assert_eq!(system[&Interned::from("EUR")].chargeback_loss, money!(-25));
+        assert_eq!(system[&Interned::from("USD")].chargeback_loss, money!(0));
+
+        let mut output = vec![];
+        trial.write_report(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(
+            output.ends_with(
+                "client,held,disputed\n\n\
+                 currency,client_totals,double_entry_difference,flow_difference\n\
+                 EUR,-25.0000,0.0000,0.0000\n\
+                 USD,135.0000,0.0000,0.0000\n"
+            ),
+            "{}",
+            output
+        );
+
+        // Missing a USD deposit and counting an EUR one twice still sums
+        // the same across currencies, but not in either currency.
+        let mut flows = Flows::default();
+        for transaction in [&transactions[..3], &transactions[1..2], &transactions[4..]].concat() {
+            flows.record(&transaction, engine.store());
+        }
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        assert_eq!(trial.flow_difference, money!(0));
+        assert!(!trial.balances());
+        let err = ConservationError(Box::new(trial));
+        assert_eq!(
+            err.to_string(),
+            "Funds not conserved: \
+             funds in less funds out in \"EUR\" differ from client balances by 30; \
+             funds in less funds out in \"USD\" differ from client balances by -30"
+        );
+    }
+
+    #[test]
+    fn discrepancies_found() {
+        let (engine, mut flows) = engine();
+        // A deposit the engine never saw.
+        flows.record(
+            &Transaction {
+                client_id: 4,
+                transaction_id: 8,
+                info: TransactionInfo::Deposit(money!(1)),
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            },
+            engine.store(),
+        );
+        let trial = TrialBalance::new(engine.store(), engine.system_accounts(), &flows);
+        assert!(!trial.balances());
+        assert_eq!(trial.flow_difference, money!(1));
+        assert_eq!(trial.double_entry_difference, money!(0));
+
+        // An account holding funds for disputes it doesn't have.
+        let mut account = Account::new(3);
+        account.set_funds(money!(10), money!(4));
+        let store = InMemoryStore::new_with_data(vec![account]);
+        let trial = TrialBalance::new(&store, &SystemAccounts::default(), &Flows::default());
+        assert_eq!(trial.double_entry_difference, money!(10));
+        assert_eq!(
+            trial.held_mismatches,
+            [HeldMismatch {
+                client: 3,
+                held: money!(4),
+                disputed: money!(0),
+            }]
+        );
+    }
+
+    #[test]
+    fn conservation_verified() {
+        let mut engine = TxEngine::new(InMemoryStore::new());
+        let outcomes: Vec<_> = [
+            (1, 1, TransactionInfo::Deposit(money!(10))),
+            (1, 2, TransactionInfo::Withdrawal(money!(20))),
+            (1, 2, TransactionInfo::Withdrawal(money!(4))),
+            (2, 3, TransactionInfo::Deposit(money!(5))),
+            (2, 3, TransactionInfo::Dispute(None)),
+            (2, 3, TransactionInfo::Chargeback),
+        ]
+        .into_iter()
+        .map(|(client_id, transaction_id, info)| {
+            let transaction = Transaction {
+                client_id,
+                transaction_id,
+                info,
+                destination: None,
+                dispute_amount: None,
+                timestamp: None,
+                currency: None,
+            };
+            let result = engine.handle(&transaction);
+            (transaction, result)
+        })
+        .collect();
+        let trial = verify_conservation(&outcomes, &engine).unwrap();
+        assert_eq!(trial.withdrawn, money!(4));
+
+        // Missing the first deposit.
+        let err = verify_conservation(&outcomes[1..], &engine).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Funds not conserved: funds in less funds out differ from client balances by -10"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+//! Compact index of the transaction IDs taken and the client taking each, so
+//! account stores can enforce that IDs are unique across clients (see
+//! [`crate::AccountStore::tx_owner`]).
+
+use std::collections::HashMap;
+
+/// Transaction IDs in a page.
+const PAGE_IDS: usize = 1 << 16;
+/// Words of bits in a page.
+const PAGE_WORDS: usize = PAGE_IDS / 64;
+
+/// A map from transaction ID to the client that took it, paged by the upper
+/// 16 bits of each ID.
+///
+/// Feeds usually number transactions densely, so this takes a bit marking
+/// each ID taken and two bytes for its client, in 136KiB pages allocated as
+/// IDs reach them, rather than a hash entry per ID.
+#[derive(Debug, Default, Clone)]
+pub struct TxIndex {
+    pages: HashMap<u16, Page>,
+    len: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Page {
+    taken: Box<[u64]>,
+    clients: Box<[u16]>,
+}
+
+impl Page {
+    fn new() -> Self {
+        // Built on the heap, as pages are too large to go through the stack.
+        Self {
+            taken: vec![0; PAGE_WORDS].into_boxed_slice(),
+            clients: vec![0; PAGE_IDS].into_boxed_slice(),
+        }
+    }
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `tx` is in the index.
+    pub fn contains(&self, tx: u32) -> bool {
+        self.owner(tx).is_some()
+    }
+
+    /// The client that took `tx`, if it's in the index.
+    pub fn owner(&self, tx: u32) -> Option<u16> {
+        let (page, offset) = Self::position(tx);
+        let page = self.pages.get(&page)?;
+        (page.taken[offset / 64] & (1 << (offset % 64)) != 0).then(|| page.clients[offset])
+    }
+
+    /// Adds `tx`, taken by `client`, to the index. Returns whether it was
+    /// newly added; an ID already in the index keeps its first client.
+    pub fn insert(&mut self, tx: u32, client: u16) -> bool {
+        let (page, offset) = Self::position(tx);
+        let page = self.pages.entry(page).or_insert_with(Page::new);
+        let word = &mut page.taken[offset / 64];
+        let bit = 1 << (offset % 64);
+        if *word & bit != 0 {
+            return false;
+        }
+        *word |= bit;
+        page.clients[offset] = client;
+        self.len += 1;
+        true
+    }
+
+    /// Removes `tx` from the index, e.g. an ID reserved for a transaction
+    /// that wasn't applied. Returns whether it was in the index.
+    pub fn remove(&mut self, tx: u32) -> bool {
+        let (page, offset) = Self::position(tx);
+        let Some(page) = self.pages.get_mut(&page) else {
+            return false;
+        };
+        let word = &mut page.taken[offset / 64];
+        let bit = 1 << (offset % 64);
+        if *word & bit == 0 {
+            return false;
+        }
+        *word &= !bit;
+        self.len -= 1;
+        true
+    }
+
+    /// Number of IDs in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Page holding `tx` and its offset within the page.
+    fn position(tx: u32) -> (u16, usize) {
+        ((tx >> 16) as u16, (tx & 0xffff) as usize)
+    }
+}
+
+impl FromIterator<(u32, u16)> for TxIndex {
+    fn from_iter<I: IntoIterator<Item = (u32, u16)>>(iter: I) -> Self {
+        let mut index = TxIndex::new();
+        index.extend(iter);
+        index
+    }
+}
+
+impl Extend<(u32, u16)> for TxIndex {
+    fn extend<I: IntoIterator<Item = (u32, u16)>>(&mut self, iter: I) {
+        for (tx, client) in iter {
+            self.insert(tx, client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ids_across_pages() {
+        let mut index = TxIndex::new();
+        for (client, tx) in [0, 63, 64, 65_535, 65_536, u32::MAX]
+            .into_iter()
+            .enumerate()
+        {
+            assert!(!index.contains(tx));
+            assert!(index.insert(tx, client as u16));
+            assert_eq!(index.owner(tx), Some(client as u16));
+        }
+        // The first client to take an ID keeps it.
+        assert!(!index.insert(64, 9));
+        assert_eq!(index.owner(64), Some(2));
+        assert_eq!(index.len(), 6);
+        assert!(!index.contains(1));
+        assert_eq!(index.owner(u32::MAX - 1), None);
+        assert!(index.remove(63));
+        assert!(!index.remove(63));
+        assert_eq!((index.owner(63), index.len()), (None, 5));
+        assert!(index.insert(63, 9));
+
+        let index: TxIndex = (1..=100_000).map(|tx| (tx, 1)).collect();
+        assert_eq!(index.len(), 100_000);
+        assert_eq!(index.pages.len(), 2);
+    }
+}
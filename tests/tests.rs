@@ -1,4 +1,20 @@
-use payments_engine::run_with_csv;
+use payments_engine::bulk::{BulkDisputeOptions, DisputeAction};
+use payments_engine::checkpoint::CheckpointOptions;
+use payments_engine::compat::v1::{run_with_csv, run_with_json_lines};
+use payments_engine::delta::DeltaOptions;
+use payments_engine::diff;
+use payments_engine::encryption::KeyFile;
+use payments_engine::export::{ExportFormat, ExportOptions};
+use payments_engine::input::InputFormat;
+use payments_engine::manifest::ManifestOptions;
+use payments_engine::period::BusinessDateOptions;
+use payments_engine::screening::{ScreeningConfig, ScreeningOptions};
+use payments_engine::shadow::{self, EngineKind, ShadowSide};
+use payments_engine::snapshot;
+use payments_engine::{
+    process, run_with_options, DisputeReason, DormancyPolicy, EngineConfig, PseudonymOptions,
+    ReasonPolicy, RejectedTransaction, RunOptions, StatementOptions, StatementOrder, TotalPolicy,
+};
 
 // Split a string by newline and sort lines based on first csv value
 // Hacky way to compare CSV output that isn't deterministically ordered.
@@ -154,3 +170,1433 @@ chargeback, 1, 1
     assert_eq!(rejects.len(), 0);
     assert_eq!(fails.len(), 0);
 }
+
+#[test]
+fn export_bundles_for_requested_clients() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 10
+withdrawal, 2, 3, 4
+withdrawal, 2, 4, 40
+";
+    let dir = std::env::temp_dir().join(format!("pe-export-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        export: Some(ExportOptions {
+            dir: dir.clone(),
+            format: ExportFormat::Csv,
+            clients: Some([2].into()),
+        }),
+        ..RunOptions::default()
+    };
+
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    assert!(!dir.join("client_1.csv").exists());
+    // Only applied transactions appear in the history.
+    let bundle = std::fs::read_to_string(dir.join("client_2.csv")).unwrap();
+    assert_eq!(
+        bundle,
+        "client,available,held,total,locked\n\
+         2,6,0,6,false\n\
+         \n\
+         seq,type,tx,amount\n\
+         2,deposit,2,10\n\
+         3,withdrawal,3,4\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+#[cfg(not(feature = "bigdecimal"))]
+fn overflowing_deposit_fails_without_panic() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 79228162514264337593543950335
+deposit,    1, 2, 1
+deposit,    2, 3, 1
+";
+    let expected_output = r"client,available,held,total,locked
+1,79228162514264337593543950335,0,79228162514264337593543950335,false
+2,0,0,0,false
+"
+    .to_string();
+
+    let mut output: Vec<u8> = vec![];
+    let (rejects, fails) = run_with_csv(input.as_bytes(), &mut output).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+    assert_eq!(rejects.len(), 0);
+    // Client 2's deposit would overflow the escrow system account.
+    assert_eq!(fails.len(), 2);
+    assert_eq!(fails[0].0.transaction_id, 2);
+    assert_eq!(fails[0].1, "Arithmetic Overflow");
+    assert_eq!(fails[1].0.transaction_id, 3);
+    assert_eq!(fails[1].1, "Arithmetic Overflow");
+}
+
+#[test]
+fn overdrawn_total_clamped_with_owed() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+withdrawal, 1, 2, 7.5
+dispute,    1, 1,
+chargeback, 1, 1,
+deposit,    2, 3, 4
+";
+    let expected_output = r"client,available,held,total,owed,locked
+1,0,0,0,7.5,true
+2,4,0,4,0,false
+"
+    .to_string();
+
+    let options = RunOptions {
+        statement: StatementOptions {
+            total_policy: TotalPolicy::ClampWithOwed,
+            ..StatementOptions::default()
+        },
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+}
+
+#[test]
+fn statements_localized() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 1234.5
+deposit,    2, 2, 10
+dispute,    2, 2,
+";
+    let options = || RunOptions {
+        statement: StatementOptions {
+            order: Some(StatementOrder::Client),
+            ..StatementOptions::default()
+        },
+        locale: "de-DE".parse().unwrap(),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options()).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Kunde;verfügbar;einbehalten;gesamt;gesperrt\n\
+         1;1.234,5;0;1.234,5;nein\n\
+         2;0;10;10;nein\n"
+    );
+
+    // Canonical statements aren't localized.
+    let options = RunOptions {
+        canonical: true,
+        ..options()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert!(String::from_utf8(output)
+        .unwrap()
+        .starts_with("client,available,held,total,locked\n1,1234.5000,"));
+}
+
+#[test]
+fn disputes_allowed_on_locked_account() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    1, 2, 5
+dispute,    1, 1,
+chargeback, 1, 1,
+deposit,    1, 3, 20
+dispute,    1, 2,
+";
+    let expected_output = r"client,available,held,total,locked
+1,0,5,5,true
+"
+    .to_string();
+
+    let mut options = RunOptions::default();
+    options.engine.disputes_on_locked_accounts = true;
+    let mut output: Vec<u8> = vec![];
+    let (rejects, fails) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+    assert_eq!(
+        rejects,
+        vec![RejectedTransaction {
+            tx: 3,
+            client: 1,
+            kind: "deposit".into(),
+            reason: "Account Locked".into(),
+        }]
+    );
+    assert_eq!(fails.len(), 0);
+}
+
+#[test]
+fn dispute_shortfall_reported() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+withdrawal, 1, 2, 8
+dispute,    1, 1,
+";
+    let expected_output = r"client,available,held,total,shortfall,locked
+1,0,2,2,8,false
+"
+    .to_string();
+
+    let events_path =
+        std::env::temp_dir().join(format!("pe-shortfall-events-{}.ndjson", std::process::id()));
+    let options = RunOptions {
+        statement: StatementOptions {
+            shortfall: true,
+            ..StatementOptions::default()
+        },
+        events: Some(events_path.clone()),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+    let events = std::fs::read_to_string(&events_path).unwrap();
+    assert_eq!(
+        events,
+        "{\"event\":\"dispute_shortfall\",\"sequence\":3,\"client\":1,\"tx\":1,\"shortfall\":\"8\",\"account_shortfall\":\"8\"}\n"
+    );
+    std::fs::remove_file(&events_path).unwrap();
+}
+
+#[test]
+fn canonical_output() {
+    let input = r"type, client, tx, amount
+deposit,    3, 1, 10
+deposit,    1, 2, 2.5
+deposit,    2, 3, 7
+dispute,    2, 3,
+withdrawal, 3, 4, 0.0001
+dispute,    1, 2,
+chargeback, 1, 2,
+";
+    let options = RunOptions {
+        canonical: true,
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    // Byte-for-byte, without sorting.
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         1,0.0000,0.0000,0.0000,true\n\
+         2,0.0000,7.0000,7.0000,false\n\
+         3,9.9999,0.0000,9.9999,false\n"
+    );
+}
+
+#[test]
+fn canonical_output_without_accounts() {
+    let input = "type, client, tx, amount\n";
+    let options = RunOptions {
+        canonical: true,
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n"
+    );
+}
+
+#[test]
+fn run_manifest() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+withdrawal, 1, 2, 20
+";
+    let dir = std::env::temp_dir().join(format!("pe-manifest-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        metrics: Some(dir.join("metrics.prom")),
+        manifest: Some(ManifestOptions {
+            path: dir.join("manifest.json"),
+            input_name: "input.csv".into(),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    let inputs = &manifest["inputs"][0];
+    assert_eq!(inputs["name"], "input.csv");
+    assert_eq!(inputs["bytes"], input.len());
+    assert_eq!(inputs["processed"]["end"], input.len());
+    assert_eq!(inputs["sha256"].as_str().unwrap().len(), 64);
+    assert_eq!(manifest["checkpoints"][0]["rows"], 3);
+    assert_eq!(manifest["config"]["engine"]["hold_policy"], "capped");
+    assert_eq!(manifest["counts"]["applied"], 1);
+    assert_eq!(manifest["counts"]["rejected"], 1);
+    assert_eq!(manifest["outputs"][0]["bytes"], output.len());
+    assert!(manifest["outputs"][1]["name"]
+        .as_str()
+        .unwrap()
+        .ends_with("metrics.prom"));
+    assert_eq!(manifest["metrics"]["withdrawal"]["rejected"], 1);
+
+    // The state digest only depends on the final state.
+    let digest = manifest["state_digest"].clone();
+    let options = RunOptions {
+        canonical: true,
+        manifest: Some(ManifestOptions {
+            path: dir.join("manifest.json"),
+            input_name: "input.csv".into(),
+        }),
+        ..RunOptions::default()
+    };
+    let input = "type,client,tx,amount\ndeposit,1,5,10.0\n";
+    run_with_options(input.as_bytes(), &mut vec![], options).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["state_digest"], digest);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn resource_usage() {
+    let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.0\n";
+    let dir = std::env::temp_dir().join(format!("pe-resources-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        metrics: Some(dir.join("metrics.prom")),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let report = process(input.as_bytes(), &mut output, options).unwrap();
+    let resources = report.resources;
+    let stages: Vec<_> = resources.stages.iter().map(|stage| stage.stage).collect();
+    assert_eq!(stages, ["setup", "input", "post-processing", "output"]);
+    assert_eq!(resources.bytes_read, input.len() as u64);
+    let metrics = std::fs::metadata(dir.join("metrics.prom")).unwrap().len();
+    assert_eq!(resources.bytes_written, output.len() as u64 + metrics);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // Other run modes are a single stage.
+    let options = RunOptions {
+        workers: 2,
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let resources = process(input.as_bytes(), &mut output, options)
+        .unwrap()
+        .resources;
+    assert_eq!(resources.stages.len(), 1);
+    assert_eq!(resources.stages[0].stage, "sharded");
+    assert_eq!(resources.bytes_read, input.len() as u64);
+    assert_eq!(resources.bytes_written, output.len() as u64);
+}
+
+#[test]
+fn statements_sorted_by_total() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 5
+deposit,    2, 2, 50
+deposit,    3, 3, 20
+";
+    let options = RunOptions {
+        statement: StatementOptions {
+            order: Some(StatementOrder::TotalDesc),
+            ..StatementOptions::default()
+        },
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         2,50,0,50,false\n\
+         3,20,0,20,false\n\
+         1,5,0,5,false\n"
+    );
+}
+
+#[test]
+fn dormancy_report_lists_idle_accounts() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 10
+deposit,    2, 3, 10
+deposit,    2, 4, 10
+withdrawal, 1, 5, 5
+";
+    let report_path = std::env::temp_dir().join(format!("pe-dormancy-{}.csv", std::process::id()));
+    let mut options = RunOptions {
+        dormancy_report: Some(report_path.clone()),
+        ..RunOptions::default()
+    };
+    options.engine.dormancy = Some(DormancyPolicy {
+        after: 3,
+        block_withdrawals: true,
+    });
+    let mut output: Vec<u8> = vec![];
+    let (rejected, _) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    assert_eq!(
+        rejected,
+        vec![RejectedTransaction {
+            tx: 5,
+            client: 1,
+            kind: "withdrawal".into(),
+            reason: "Account Dormant".into(),
+        }]
+    );
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "client,last_activity,idle,total,locked\n1,1,3,10,false\n"
+    );
+    std::fs::remove_file(&report_path).unwrap();
+}
+
+#[test]
+fn system_statement_balances_clients() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+withdrawal, 1, 3, 8
+dispute,    1, 1,
+chargeback, 1, 1,
+";
+    let statement_path =
+        std::env::temp_dir().join(format!("pe-system-statement-{}.csv", std::process::id()));
+    let options = RunOptions {
+        system_statement: Some(statement_path.clone()),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let statement = std::fs::read_to_string(&statement_path).unwrap();
+    assert_eq!(
+        statement,
+        "account,balance\n\
+         escrow,3\n\
+         fee_income,0\n\
+         chargeback_loss,-8\n\
+         suspense,8\n\
+         interest_expense,0\n"
+    );
+    std::fs::remove_file(&statement_path).unwrap();
+}
+
+#[test]
+fn bulk_disputes_applied_after_input() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+";
+    let dir = std::env::temp_dir();
+    let disputes_path = dir.join(format!("pe-bulk-disputes-{}.csv", std::process::id()));
+    let results_path = dir.join(format!("pe-bulk-results-{}.csv", std::process::id()));
+    std::fs::write(&disputes_path, "client,tx\n1,1\n2,1\n2,2\n").unwrap();
+    let options = RunOptions {
+        bulk_disputes: Some(BulkDisputeOptions {
+            input: disputes_path.clone(),
+            action: DisputeAction::Open,
+            results: results_path.clone(),
+        }),
+        ..RunOptions::default()
+    };
+    let expected_output = r"client,available,held,total,locked
+1,0,10,10,false
+2,0,5,5,false
+"
+    .to_string();
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+    let results = std::fs::read_to_string(&results_path).unwrap();
+    assert_eq!(
+        results,
+        "client,tx,outcome,sequence,reason\n\
+         1,1,applied,3,\n\
+         2,1,ClientMismatch,,Client Mismatch: 1\n\
+         2,2,applied,4,\n"
+    );
+    std::fs::remove_file(&disputes_path).unwrap();
+    std::fs::remove_file(&results_path).unwrap();
+}
+
+#[test]
+fn dispute_reason_policies() {
+    let input = r"type, client, tx, amount, reason
+deposit,    1, 1, 10,
+deposit,    2, 2, 10,
+dispute,    1, 1,   , duplicate
+dispute,    2, 2,   , fraud
+chargeback, 1, 1,   ,
+chargeback, 2, 2,   ,
+";
+    let expected_output = r"client,available,held,total,locked
+1,0,0,0,false
+2,0,0,0,true
+"
+    .to_string();
+    let mut options = RunOptions::default();
+    options.engine.reason_policies.insert(
+        DisputeReason::Duplicate,
+        ReasonPolicy {
+            chargeback_lock: None,
+        },
+    );
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let output = split_and_sort(String::from_utf8(output).unwrap());
+    assert_eq!(output, split_and_sort(expected_output));
+}
+
+#[test]
+fn repeated_settlement_not_a_failure() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+dispute,    1, 1,
+resolve,    1, 1,
+resolve,    1, 1,
+";
+    let mut options = RunOptions::default();
+    options.engine.idempotent_settlement = true;
+    let mut output: Vec<u8> = vec![];
+    let (rejects, fails) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    assert!(fails.is_empty());
+    assert_eq!(
+        rejects,
+        vec![RejectedTransaction {
+            tx: 1,
+            client: 1,
+            kind: "resolve".into(),
+            reason: "Already Applied: 1".into(),
+        }]
+    );
+}
+
+#[test]
+fn flags_report() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 9900
+deposit,    1, 2, 9800
+deposit,    1, 3, 9950
+deposit,    2, 4, 9900
+withdrawal, 1, 5, 100000
+";
+    let report_path = std::env::temp_dir().join(format!("pe-flags-{}.csv", std::process::id()));
+    let options = RunOptions {
+        screening: Some(ScreeningOptions {
+            path: report_path.clone(),
+            config: ScreeningConfig::default(),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "client,flag,count,detail\n\
+         1,structuring,3,3 of 3 deposits within 1000 under 10000\n"
+    );
+    std::fs::remove_file(&report_path).unwrap();
+}
+
+#[test]
+fn client_profile_report() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    1, 2, 5
+withdrawal, 1, 3, 100
+dispute,    1, 2,
+deposit,    2, 4, -1
+";
+    let report_path = std::env::temp_dir().join(format!("pe-profiles-{}.csv", std::process::id()));
+    let options = RunOptions {
+        profile_report: Some(report_path.clone()),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let report = std::fs::read_to_string(&report_path).unwrap();
+    assert_eq!(
+        report,
+        "client,deposits,withdrawals,disputes,resolves,chargebacks,\
+         average_deposit,average_withdrawal,rejected,failed,\
+         rejection_rate,dispute_rate\n\
+         1,2,0,1,0,0,7.5,0,1,0,0.25,0.5\n\
+         2,0,0,0,0,0,0,0,1,0,1.0,0.0\n"
+    );
+    std::fs::remove_file(&report_path).unwrap();
+}
+
+#[test]
+fn opening_balances_established_before_input() {
+    let input = r"type, client, tx, amount
+withdrawal, 1, 3, 4
+resolve,    1, 1,
+dispute,    2, 4,
+chargeback, 2, 4,
+";
+    let opening_path =
+        std::env::temp_dir().join(format!("pe-opening-balances-{}.csv", std::process::id()));
+    std::fs::write(
+        &opening_path,
+        "client,tx,amount,disputed\n1,1,10,true\n1,2,5,\n2,4,7,false\n",
+    )
+    .unwrap();
+    let options = RunOptions {
+        opening_balances: Some(opening_path.clone()),
+        canonical: true,
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let (rejected, failed) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert!(rejected.is_empty());
+    assert!(failed.is_empty());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         1,11.0000,0.0000,11.0000,false\n\
+         2,0.0000,0.0000,0.0000,true\n"
+    );
+
+    // Opening balances are configuration, so a bad entry fails the run.
+    std::fs::write(&opening_path, "client,tx,amount\n1,1,10\n1,1,5\n").unwrap();
+    let options = RunOptions {
+        opening_balances: Some(opening_path.clone()),
+        ..RunOptions::default()
+    };
+    let err = run_with_options(input.as_bytes(), vec![], options).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Opening balance for client 1 tx 1 not applied: Repeat Transaction: 1"
+    );
+    std::fs::remove_file(&opening_path).unwrap();
+}
+
+#[test]
+fn snapshot_diff() {
+    let dir = std::env::temp_dir();
+    let day_one_path = dir.join(format!("pe-snapshot-day-one-{}.json", std::process::id()));
+    let day_two_path = dir.join(format!("pe-snapshot-day-two-{}.json", std::process::id()));
+    let day_one = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+";
+    let day_two = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+dispute,    2, 2,
+chargeback, 2, 2,
+";
+    for (input, path) in [(day_one, &day_one_path), (day_two, &day_two_path)] {
+        let options = RunOptions {
+            snapshot: Some(path.clone()),
+            ..RunOptions::default()
+        };
+        run_with_options(input.as_bytes(), vec![], options).unwrap();
+    }
+
+    let read = |path| snapshot::read_snapshot(std::fs::File::open(path).unwrap()).unwrap();
+    let (before, after) = (read(&day_one_path), read(&day_two_path));
+    let mut output = vec![];
+    diff::write_diff(&mut output, &diff::diff(before.store(), after.store())).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,newly_locked,dispute_changes\n\
+         2,-5,0,-5,true,2:not-disputed->refunded\n"
+    );
+    std::fs::remove_file(&day_one_path).unwrap();
+    std::fs::remove_file(&day_two_path).unwrap();
+}
+
+#[test]
+fn quarantined_transactions_queued() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+withdrawal, 2, 3, 20
+dispute,    2, 2,
+";
+    let report_path =
+        std::env::temp_dir().join(format!("pe-quarantine-{}.csv", std::process::id()));
+    let options = RunOptions {
+        quarantine: vec![2],
+        quarantine_report: Some(report_path.clone()),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let (rejected, failed) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert!(rejected.is_empty());
+    assert!(failed.is_empty());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&report_path).unwrap(),
+        "client,tx,type,amount\n\
+         2,2,deposit,5\n\
+         2,3,withdrawal,20\n\
+         2,2,dispute,\n"
+    );
+    std::fs::remove_file(&report_path).unwrap();
+}
+
+#[test]
+fn redisputes_across_inputs_applied() {
+    let first = r"type, client, tx, amount
+deposit, 1, 1, 10
+dispute, 1, 1,
+resolve, 1, 1,
+";
+    // Overlaps the first input by its resolve, then disputes the deposit
+    // again, which isn't a repeat of the first input's dispute.
+    let second = r"type, client, tx, amount
+resolve, 1, 1,
+dispute, 1, 1,
+";
+    let dir = std::env::temp_dir().join(format!("pe-redispute-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let second_path = dir.join("second.csv");
+    std::fs::write(&second_path, second).unwrap();
+    let options = RunOptions {
+        extra_inputs: vec![second_path],
+        manifest: Some(ManifestOptions {
+            path: dir.join("manifest.json"),
+            input_name: "first.csv".into(),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let (rejected, failed) = run_with_options(first.as_bytes(), &mut output, options).unwrap();
+    assert!(rejected.is_empty());
+    assert!(failed.is_empty());
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,0,10,10,false\n"
+    );
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["counts"]["duplicates_skipped"], 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn overlapping_inputs_deduplicated() {
+    let first = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    1, 2, 5
+";
+    // Overlaps the first input by one transaction, then repeats a
+    // transaction ID with a different amount, which is still a failure.
+    let second = r"type, client, tx, amount
+deposit,    1, 2, 5
+withdrawal, 1, 3, 4
+deposit,    1, 1, 7
+";
+    let dir = std::env::temp_dir().join(format!("pe-overlap-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let second_path = dir.join("second.csv");
+    std::fs::write(&second_path, second).unwrap();
+    let options = RunOptions {
+        extra_inputs: vec![second_path.clone()],
+        manifest: Some(ManifestOptions {
+            path: dir.join("manifest.json"),
+            input_name: "first.csv".into(),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let (rejected, failed) = run_with_options(first.as_bytes(), &mut output, options).unwrap();
+    assert!(rejected.is_empty());
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].0.transaction_id, 1);
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n1,11,0,11,false\n"
+    );
+
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["counts"]["applied"], 3);
+    assert_eq!(manifest["counts"]["duplicates_skipped"], 1);
+    assert_eq!(
+        manifest["inputs"][1]["name"],
+        second_path.display().to_string()
+    );
+    assert_eq!(manifest["inputs"][1]["processed"]["end"], second.len());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn pseudonymized_outputs() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 5
+withdrawal, 2, 3, 1
+withdrawal, 2, 4, 100
+";
+    let dir = std::env::temp_dir().join(format!("pe-pseudonym-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key_path = dir.join("key");
+    std::fs::write(&key_path, "42".repeat(32)).unwrap();
+    let options = RunOptions {
+        pseudonymize: Some(PseudonymOptions {
+            key: Box::new(KeyFile(key_path)),
+            mapping: dir.join("mapping.csv"),
+        }),
+        canonical: true,
+        profile_report: Some(dir.join("profile.csv")),
+        rejects: Some(dir.join("rejects.csv")),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let mapping = std::fs::read_to_string(dir.join("mapping.csv")).unwrap();
+    let pseudonyms: Vec<(u16, u16)> = mapping
+        .lines()
+        .skip(1)
+        .map(|line| {
+            let (client, pseudonym) = line.split_once(',').unwrap();
+            (client.parse().unwrap(), pseudonym.parse().unwrap())
+        })
+        .collect();
+    assert_eq!(pseudonyms.len(), 2);
+    assert_eq!((pseudonyms[0].0, pseudonyms[1].0), (1, 2));
+    let (one, two) = (pseudonyms[0].1, pseudonyms[1].1);
+    assert_ne!(one, two);
+
+    // Canonical statements are ordered by pseudonym.
+    let mut rows = vec![
+        (one, format!("{},10.0000,0.0000,10.0000,false\n", one)),
+        (two, format!("{},4.0000,0.0000,4.0000,false\n", two)),
+    ];
+    rows.sort();
+    let rows: String = rows.into_iter().map(|(_, row)| row).collect();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        format!("client,available,held,total,locked\n{}", rows)
+    );
+    let profile = std::fs::read_to_string(dir.join("profile.csv")).unwrap();
+    let clients: Vec<_> = profile
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().parse::<u16>().unwrap())
+        .collect();
+    assert_eq!(clients, [one.min(two), one.max(two)]);
+    let rejects = std::fs::read_to_string(dir.join("rejects.csv")).unwrap();
+    assert_eq!(
+        rejects,
+        format!(
+            "tx,client,type,reason\n4,{},withdrawal,Insufficient Funds: short by 96\n",
+            two
+        )
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn notes_exported_and_logged() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+withdrawal, 1, 2, 4
+";
+    let dir = std::env::temp_dir().join(format!("pe-notes-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let notes_path = dir.join("notes.csv");
+    let events_path = dir.join("events.jsonl");
+    std::fs::write(
+        &notes_path,
+        "client,tx,author,note\n1,2,alice,Withdrawal confirmed by phone\n1,,bob,Case 7 closed\n",
+    )
+    .unwrap();
+    let options = RunOptions {
+        notes: Some(notes_path.clone()),
+        events: Some(events_path.clone()),
+        export: Some(ExportOptions {
+            dir: dir.clone(),
+            format: ExportFormat::Csv,
+            clients: None,
+        }),
+        ..RunOptions::default()
+    };
+
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let bundle = std::fs::read_to_string(dir.join("client_1.csv")).unwrap();
+    assert!(bundle.ends_with(
+        "\n\
+         after_seq,tx,author,note\n\
+         2,2,alice,Withdrawal confirmed by phone\n\
+         2,,bob,Case 7 closed\n"
+    ));
+    let events = std::fs::read_to_string(&events_path).unwrap();
+    assert_eq!(
+        events.lines().next().unwrap(),
+        r#"{"event":"note_added","after_sequence":2,"client":1,"tx":2,"author":"alice","note":"Withdrawal confirmed by phone"}"#
+    );
+    assert_eq!(events.lines().count(), 2);
+
+    // Clients without an account can't be noted.
+    std::fs::write(&notes_path, "client,tx,author,note\n2,,bob,Unknown\n").unwrap();
+    let options = RunOptions {
+        notes: Some(notes_path.clone()),
+        ..RunOptions::default()
+    };
+    let err = run_with_options(input.as_bytes(), vec![], options).unwrap_err();
+    assert_eq!(err.to_string(), "Client 2 has no account to note.");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn movements_and_statements_per_business_date() {
+    let input = r"type, client, tx, amount, reason, date
+deposit,    1, 1, 10,  ,
+deposit,    2, 2, 5,   , 2024-03-01
+withdrawal, 1, 3, 4,   , 2024-03-02
+deposit,    2, 4, 1,   , 2024-03-01
+dispute,    2, 2,   ,  , 2024-3-2
+";
+    let dir = std::env::temp_dir().join(format!("pe-dates-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let movements_path = dir.join("movements.csv");
+    let options = RunOptions {
+        business_dates: Some(BusinessDateOptions {
+            opening_date: "2024-02-29".into(),
+            movements: Some(movements_path.clone()),
+            statements_dir: Some(dir.clone()),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    let (rejected, _) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    // The malformed date rejects its row.
+    assert_eq!(
+        rejected,
+        [RejectedTransaction {
+            tx: 2,
+            client: 2,
+            kind: "dispute".into(),
+            reason: "Malformed Transaction".into(),
+        }]
+    );
+    // Undated rows are on the current date, and late ones on the next open
+    // date.
+    assert_eq!(
+        std::fs::read_to_string(&movements_path).unwrap(),
+        "date,client,transactions,deposited,withdrawn,total_change,held_change\n\
+         2024-02-29,1,1,10,0,10,0\n\
+         2024-03-01,2,1,5,0,5,0\n\
+         2024-03-02,1,1,0,4,-4,0\n\
+         2024-03-02,2,1,1,0,1,0\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("statements_2024-03-01.csv")).unwrap(),
+        "client,available,held,total,locked\n\
+         1,10.0000,0.0000,10.0000,false\n\
+         2,5.0000,0.0000,5.0000,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("statements_2024-03-02.csv")).unwrap(),
+        "client,available,held,total,locked\n\
+         1,6.0000,0.0000,6.0000,false\n\
+         2,6.0000,0.0000,6.0000,false\n"
+    );
+    assert!(dir.join("statements_2024-02-29.csv").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn business_dates_from_timestamps() {
+    let input = r"type, client, tx, amount, timestamp, date
+deposit,    1, 1, 10, 2024-03-01T09:00:00Z,
+deposit,    1, 2, 5,  2024-03-01T23:30:00-01:00,
+withdrawal, 1, 3, 4,  , 2024-99-99
+withdrawal, 1, 4, 1,  ,
+";
+    let dir = std::env::temp_dir().join(format!("pe-timestamp-dates-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let movements_path = dir.join("movements.csv");
+    let options = RunOptions {
+        business_dates: Some(BusinessDateOptions {
+            opening_date: "2024-02-29".into(),
+            movements: Some(movements_path.clone()),
+            statements_dir: None,
+        }),
+        ..RunOptions::default()
+    };
+    let (rejected, _) = run_with_options(input.as_bytes(), vec![], options).unwrap();
+
+    // Not a real date, so malformed.
+    assert_eq!(rejected.len(), 1);
+    assert_eq!(rejected[0].tx, 3);
+    // Rows without a date are on the day (in UTC) of their timestamp.
+    assert_eq!(
+        std::fs::read_to_string(&movements_path).unwrap(),
+        "date,client,transactions,deposited,withdrawn,total_change,held_change\n\
+         2024-03-01,1,1,10,0,10,0\n\
+         2024-03-02,1,2,5,1,4,0\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn trial_balance_verified() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 100
+withdrawal, 1, 2, 80
+dispute,    1, 1,
+chargeback, 1, 1,
+deposit,    2, 3, 5
+deposit,    2, 4, 7.5
+dispute,    2, 4,
+";
+    let dir = std::env::temp_dir();
+    let disputes_path = dir.join(format!("pe-trial-disputes-{}.csv", std::process::id()));
+    let results_path = dir.join(format!("pe-trial-results-{}.csv", std::process::id()));
+    let trial_path = dir.join(format!("pe-trial-balance-{}.csv", std::process::id()));
+    std::fs::write(&disputes_path, "client,tx\n2,4\n").unwrap();
+    let options = RunOptions {
+        bulk_disputes: Some(BulkDisputeOptions {
+            input: disputes_path.clone(),
+            action: DisputeAction::Chargeback,
+            results: results_path.clone(),
+        }),
+        trial_balance: Some(trial_path.clone()),
+        ..RunOptions::default()
+    };
+    let mut output: Vec<u8> = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+
+    let trial = std::fs::read_to_string(&trial_path).unwrap();
+    assert!(trial.starts_with(
+        "line,amount\n\
+         deposited,112.5000\n\
+         withdrawn,80.0000\n\
+         charged_back,107.5000\n"
+    ));
+    assert!(trial.contains("\ndouble_entry_difference,0.0000\nflow_difference,0.0000\n"));
+    for path in [disputes_path, results_path, trial_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn shadow_digest_matches_manifest() {
+    let input = r"type, client, tx, amount
+deposit,    1, 1, 10
+deposit,    2, 2, 4
+withdrawal, 1, 3, 2.5
+dispute,    2, 2,
+chargeback, 2, 2,
+";
+    let side = |engine| ShadowSide {
+        engine,
+        config: EngineConfig::default(),
+    };
+    let report = shadow::run(
+        input.as_bytes(),
+        &side(EngineKind::Single),
+        &side(EngineKind::Shared),
+    )
+    .unwrap();
+    assert!(report.agrees(), "{}", report);
+    assert_eq!(report.transactions, 5);
+
+    let dir = std::env::temp_dir().join(format!("pe-shadow-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        manifest: Some(ManifestOptions {
+            path: dir.join("manifest.json"),
+            input_name: "input.csv".into(),
+        }),
+        ..RunOptions::default()
+    };
+    run_with_options(input.as_bytes(), &mut vec![], options).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(manifest["state_digest"], report.primary_digest.as_str());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn delta_statements_since_snapshot() {
+    let dir = std::env::temp_dir().join(format!("pe-delta-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let options = RunOptions {
+        snapshot: Some(dir.join("previous.json")),
+        ..RunOptions::default()
+    };
+    let input = r"type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+deposit, 3, 3, 1
+";
+    run_with_options(input.as_bytes(), &mut vec![], options).unwrap();
+
+    // Client 3's account is gone from this run, and client 4's is new.
+    let input = r"type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 2, 4, 1
+deposit, 4, 5, 2
+";
+    let options = RunOptions {
+        canonical: true,
+        delta: Some(DeltaOptions {
+            previous: dir.join("previous.json"),
+            tombstones: Some(dir.join("tombstones.csv")),
+        }),
+        ..RunOptions::default()
+    };
+    let mut output = vec![];
+    run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "client,available,held,total,locked\n\
+         2,4.0000,0.0000,4.0000,false\n\
+         4,2.0000,0.0000,2.0000,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("tombstones.csv")).unwrap(),
+        "client\n3\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn json_lines_input() {
+    let csv = r"type, client, tx, amount
+deposit, 1, 1, 10.25
+withdrawal, 1, 2, 0.25
+dispute, 1, 1,
+deposit, 2, 3, 4
+";
+    let json_lines = r#"{"type":"deposit","client":1,"tx":1,"amount":"10.25"}
+{"type":"withdrawal","client":1,"tx":2,"amount":0.25}
+{"type":"dispute","client":1,"tx":1}
+
+{"type":"deposit","client":2,"tx":3,"amount":4}
+{"type":"deposit","client":2}
+"#;
+    let mut expected = vec![];
+    run_with_csv(csv.as_bytes(), &mut expected).unwrap();
+    let mut output = vec![];
+    let (rejected, _) = run_with_json_lines(json_lines.as_bytes(), &mut output).unwrap();
+    assert!(rejected.is_empty());
+    assert_eq!(
+        split_and_sort(String::from_utf8(output).unwrap()),
+        split_and_sort(String::from_utf8(expected).unwrap())
+    );
+}
+
+#[test]
+fn partner_feed_with_profile() {
+    let profiles: payments_engine::ingestion::Profiles = serde_json::from_str(
+        r#"{"partner-x": {
+            "columns": {"kind": "type", "customer": "client", "ref": "tx", "value": "amount"},
+            "locale": "de-DE",
+            "currency": "EUR",
+            "strictness": "strict",
+            "dedup": "exact"
+        }}"#,
+    )
+    .unwrap();
+    let profile = &profiles["partner-x"];
+    let run = |input: &str| {
+        let options = RunOptions {
+            input_format: InputFormat::Csv { delimiter: b';' },
+            ingestion: Some(profile.clone()),
+            ..RunOptions::default()
+        };
+        let mut output: Vec<u8> = vec![];
+        run_with_options(input.as_bytes(), &mut output, options)
+            .map(|_| String::from_utf8(output).unwrap())
+    };
+    // The feed resends the deposit, which is skipped.
+    let input = "kind;customer;ref;value
+deposit;1;1;1.234,5
+deposit;1;1;1.234,5
+withdrawal;1;2;0,25
+";
+    assert_eq!(
+        run(input).unwrap(),
+        "client,available,held,total,locked\n1,1234.25,0,1234.25,false\n"
+    );
+    // Strictly, an amount not in the locale fails the run, as does a
+    // column that isn't read.
+    assert!(run("kind;customer;ref;value\ndeposit;1;1;1.5\n").is_err());
+    assert!(run("kind;customer;ref;value;memo\ndeposit;1;1;1,5;x\n").is_err());
+}
+
+#[test]
+fn workers_match_single_thread() {
+    let input = r"type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 20
+deposit, 3, 4, oops
+dispute, 2, 2,
+withdrawal, 3, 5, 1
+chargeback, 2, 2,
+deposit, 2, 6, 1
+";
+    let mut expected = vec![];
+    let canonical = || RunOptions {
+        canonical: true,
+        ..RunOptions::default()
+    };
+    let expected_rejected = run_with_options(input.as_bytes(), &mut expected, canonical())
+        .unwrap()
+        .0;
+    let mut output = vec![];
+    let options = RunOptions {
+        workers: 3,
+        ..canonical()
+    };
+    let (rejected, _) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert_eq!(output, expected);
+    assert_eq!(rejected, expected_rejected);
+    assert_eq!(rejected.len(), 4);
+
+    let options = RunOptions {
+        workers: 3,
+        metrics: Some("metrics.prom".into()),
+        quarantine: vec![1],
+        ..RunOptions::default()
+    };
+    let err = run_with_options(input.as_bytes(), vec![], options).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Options not supported with more than one worker: metrics, quarantine."
+    );
+}
+
+#[test]
+fn resume_from_checkpoint() {
+    let input = r"type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+withdrawal, 1, 3, 20
+deposit, 3, 4, oops
+dispute, 2, 2,
+withdrawal, 3, 5, 1
+chargeback, 2, 2,
+deposit, 2, 6, 1
+";
+    let path = std::env::temp_dir().join(format!("pe-checkpoint-{}.json", std::process::id()));
+    let mut expected = vec![];
+    let options = RunOptions {
+        canonical: true,
+        checkpoint: Some(CheckpointOptions {
+            interval: 3,
+            ..CheckpointOptions::new(&path)
+        }),
+        ..RunOptions::default()
+    };
+    let (expected_rejected, _) =
+        run_with_options(input.as_bytes(), &mut expected, options).unwrap();
+    assert_eq!(expected_rejected.len(), 4);
+
+    // The last checkpoint was taken after the sixth row, so the rest of the
+    // input is applied again on resuming.
+    let mut output = vec![];
+    let options = RunOptions {
+        canonical: true,
+        resume: Some(path.clone()),
+        ..RunOptions::default()
+    };
+    let (rejected, _) = run_with_options(input.as_bytes(), &mut output, options).unwrap();
+    assert_eq!(String::from_utf8(output), String::from_utf8(expected));
+    assert_eq!(rejected, expected_rejected);
+
+    let options = RunOptions {
+        resume: Some(path.clone()),
+        ..RunOptions::default()
+    };
+    let truncated = &input[..input.find("dispute").unwrap()];
+    let err = run_with_options(truncated.as_bytes(), vec![], options).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "The input doesn't match the checkpoint being resumed."
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+/// Serves the binary's HTTP API on a free port until dropped.
+#[cfg(feature = "http")]
+struct Server {
+    child: std::process::Child,
+    addr: std::net::SocketAddr,
+}
+
+#[cfg(feature = "http")]
+impl Server {
+    fn start(args: &[&str]) -> Self {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let child = std::process::Command::new(env!("CARGO_BIN_EXE_payments-engine"))
+            .args(["serve", "--addr", &addr.to_string()])
+            .args(args)
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, addr };
+        for _ in 0..100 {
+            if std::net::TcpStream::connect(addr).is_ok() {
+                return server;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        panic!("Server didn't start on {}", addr);
+    }
+
+    /// Sends an HTTP request, returning the connection with the response's
+    /// status line and headers read.
+    fn request(&self, request: &str) -> (String, std::io::BufReader<std::net::TcpStream>) {
+        use std::io::{BufRead, Write};
+        let mut stream = std::net::TcpStream::connect(self.addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(10)))
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reader = std::io::BufReader::new(stream);
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut header = String::new();
+        while reader.read_line(&mut header).unwrap() > 2 {
+            header.clear();
+        }
+        (status, reader)
+    }
+
+    fn subscribe(
+        &self,
+        token: &str,
+        clients: &str,
+    ) -> (String, std::io::BufReader<std::net::TcpStream>) {
+        self.request(&format!(
+            "GET /subscribe?clients={} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Authorization: Bearer {}\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            clients, self.addr, token
+        ))
+    }
+}
+
+#[cfg(feature = "http")]
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn serve_subscriptions() {
+    use std::io::Read;
+    let grants = std::env::temp_dir().join(format!("pe-grants-{}.csv", std::process::id()));
+    std::fs::write(&grants, "principal,client\nteam-a,1\nops,*\n").unwrap();
+    let server = Server::start(&["--subscribers", grants.to_str().unwrap()]);
+
+    let (status, _) = server.subscribe("team-a", "1,2");
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+    let (status, _) = server.subscribe("team-b", "1");
+    assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+    let (status, mut events) = server.subscribe("team-a", "1");
+    assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+
+    for (client, tx) in [(2, 1), (1, 2)] {
+        let body = format!(
+            r#"{{"type":"deposit","client":{},"tx":{},"amount":"10"}}"#,
+            client, tx
+        );
+        let (status, _) = server.request(&format!(
+            "POST /transactions HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            server.addr,
+            body.len(),
+            body
+        ));
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+    }
+    // Only client 1's deposit reaches the subscriber, as an unmasked text
+    // frame.
+    let mut header = [0; 2];
+    events.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0x81);
+    let len = match header[1] {
+        126 => {
+            let mut len = [0; 2];
+            events.read_exact(&mut len).unwrap();
+            usize::from(u16::from_be_bytes(len))
+        }
+        len => usize::from(len),
+    };
+    let mut event = vec![0; len];
+    events.read_exact(&mut event).unwrap();
+    let event: serde_json::Value = serde_json::from_slice(&event).unwrap();
+    assert_eq!((&event["client"], &event["tx"]), (&1.into(), &2.into()));
+    std::fs::remove_file(grants).unwrap();
+}
+
+#[cfg(feature = "http")]
+#[test]
+fn serve_without_subscribers() {
+    let server = Server::start(&[]);
+    let (status, _) = server.subscribe("team-a", "1");
+    assert!(status.starts_with("HTTP/1.1 501"), "{}", status);
+}
+
+#[test]
+fn missing_flag_values_are_usage_errors() {
+    for args in [
+        &["transactions.csv", "--sort"][..],
+        &["serve", "--key-file"],
+        &["serve", "--addr", "127.0.0.1:0", "--subscribers"],
+    ] {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_payments-engine"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        let flag = args.last().unwrap();
+        assert!(
+            stderr.contains(&format!("{} requires a value.", flag)),
+            "{}",
+            stderr
+        );
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+    }
+}